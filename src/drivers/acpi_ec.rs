@@ -0,0 +1,356 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! ACPI Embedded Controller
+//!
+//! The EC of laptops holds the battery, AC adapter, fan and thermal registers in a 256-byte
+//! address space, reached through a command/status and a data port. Every byte of a command
+//! waits for the input buffer (IBF) to empty, every byte of a response for the output buffer
+//! (OBF) to fill. Burst mode keeps the EC at our disposal for a series of accesses.
+//!
+//! The ports come from the ECDT, or from the \_CRS of the PNP0C09 device.
+//!
+//! https://uefi.org/specs/ACPI/6.5/12_ACPI_Embedded_Controller_Interface_Specification.html
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{Dependency, Driver, DriverError};
+use crate::fox_acpi::{self, Table, ecdt};
+use crate::fox_aml::{self, NodeKind, PathDisplay, Resource, Value};
+use crate::fox_io::{self, Space};
+use crate::fox_port::Pio;
+use crate::fox_time::poll_timeout;
+
+/// Hardware ID of the EC device
+const HARDWARE_ID: &str = "PNP0C09";
+
+/// Legacy ports, the usual values of the ECDT and \_CRS
+const PORT_DATA_DEFAULT: u16 = 0x62;
+const PORT_COMMAND_DEFAULT: u16 = 0x66;
+
+/// One handshake of a command, the EC may be busy with SMM firmware
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+// commands
+const RD_EC: u8 = 0x80;
+const WR_EC: u8 = 0x81;
+const BE_EC: u8 = 0x82;
+const BD_EC: u8 = 0x83;
+const QR_EC: u8 = 0x84;
+
+/// Answer to BE_EC
+const BURST_ACK: u8 = 0x90;
+
+// status register bits
+const STATUS_OBF: usize = 0;
+const STATUS_IBF: usize = 1;
+const STATUS_BURST: usize = 4;
+const STATUS_SCI_EVT: usize = 5;
+
+/// Set by [`Driver::init`], 0 while no EC is in use
+static PORT_DATA: AtomicU16 = AtomicU16::new(0);
+static PORT_COMMAND: AtomicU16 = AtomicU16::new(0);
+/// The EC device has \_GLK = 1, accesses take the ACPI global lock
+static USES_GLOBAL_LOCK: AtomicBool = AtomicBool::new(false);
+
+/// ACPI Embedded Controller
+#[derive(Default, Debug)]
+pub struct AcpiEc {
+    data_port: u16,
+    command_port: u16,
+    /// GPE bit of the SCI, from the ECDT or \_GPE
+    gpe: Option<u8>,
+}
+
+impl AcpiEc {
+    /// Size of the EC address space
+    pub const SPACE_SIZE: usize = 0x100;
+
+    /// Status register: OBF, IBF, CMD, BURST, SCI_EVT, SMI_EVT
+    pub fn status() -> Result<u8, DriverError> {
+        let (_, command) = ports()?;
+        Ok(read_status(command))
+    }
+
+    /// Byte `address` of the EC space (RD_EC)
+    pub fn read(address: u8) -> Result<u8, DriverError> {
+        // log::trace!("AcpiEc::read({:#x})", address);
+
+        let (data, command) = ports()?;
+        with_global_lock(|| {
+            send_command(command, RD_EC)?;
+            write_data(data, command, address)?;
+            read_data(data, command)
+        })
+    }
+
+    /// Write byte `address` of the EC space (WR_EC)
+    pub fn write(address: u8, value: u8) -> Result<(), DriverError> {
+        // log::trace!("AcpiEc::write({:#x}, {:#x})", address, value);
+
+        let (data, command) = ports()?;
+        with_global_lock(|| {
+            send_command(command, WR_EC)?;
+            write_data(data, command, address)?;
+            write_data(data, command, value)?;
+            wait_input_empty(command)
+        })
+    }
+
+    /// Read `buf.len()` bytes from `address` on, in burst mode (BE_EC, BD_EC)
+    ///
+    /// Multi-byte fields such as the battery registers are read without the EC updating them in
+    /// between. Falls back to single reads if the EC does not grant burst mode.
+    pub fn read_block(address: u8, buf: &mut [u8]) -> Result<(), DriverError> {
+        // log::trace!("AcpiEc::read_block({:#x}, {})", address, buf.len());
+
+        if address as usize + buf.len() > Self::SPACE_SIZE {
+            return Err(DriverError::InvalidRequest);
+        }
+        let (data, command) = ports()?;
+        with_global_lock(|| {
+            let is_burst = enable_burst(data, command)?;
+            // checked above: the last address is at most 0xFF
+            let result = (address..=u8::MAX)
+                .zip(buf.iter_mut())
+                .try_for_each(|(address, byte)| {
+                    send_command(command, RD_EC)?;
+                    write_data(data, command, address)?;
+                    *byte = read_data(data, command)?;
+                    Ok(())
+                });
+            if is_burst {
+                send_command(command, BD_EC)?;
+                wait_input_empty(command)?;
+            }
+            result
+        })
+    }
+
+    /// Pending event number (QR_EC), `None` if there is none
+    ///
+    /// The event names the \_Qxx method that handles it. Only asked while SCI_EVT is set.
+    pub fn query() -> Result<Option<u8>, DriverError> {
+        // log::trace!("AcpiEc::query()");
+
+        let (data, command) = ports()?;
+        if !read_status(command).get_bit(STATUS_SCI_EVT) {
+            return Ok(None);
+        }
+        with_global_lock(|| {
+            send_command(command, QR_EC)?;
+            let event = read_data(data, command)?;
+            Ok((event != 0).then_some(event))
+        })
+    }
+}
+
+impl Driver for AcpiEc {
+    const DRIVER_NAME: &str = "acpi_ec";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Table(Table::Rsdp)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("AcpiEc::probe()");
+
+        if ecdt().is_ok() || ec_device().is_some() {
+            return Ok(());
+        }
+        log::info!("{}: No ECDT or {} device", Self::DRIVER_NAME, HARDWARE_ID);
+        Err(DriverError::NoHardware)
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("AcpiEc::init()");
+
+        let device = ec_device();
+        match ecdt() {
+            Ok(ecdt) if ecdt.data_port != 0 && ecdt.command_port != 0 => {
+                log::info!(
+                    "{}: ECDT, {} (UID {})",
+                    Self::DRIVER_NAME,
+                    ecdt.id,
+                    ecdt.uid
+                );
+                self.data_port = ecdt.data_port;
+                self.command_port = ecdt.command_port;
+                self.gpe = Some(ecdt.gpe);
+            }
+            Ok(_) => log::debug!("{}: ECDT without ports", Self::DRIVER_NAME),
+            Err(fox_acpi::Error::NoTable) => {}
+            Err(err) => log::warn!("{}: ECDT: {}", Self::DRIVER_NAME, err),
+        }
+        if let Some(device) = &device
+            && self.data_port == 0
+        {
+            log::info!("{}: {}", Self::DRIVER_NAME, PathDisplay(device));
+            (self.data_port, self.command_port) = device_ports(device);
+            self.gpe = match namespace_value(device, b"_GPE") {
+                Some(Value::Integer(gpe)) => u8::try_from(gpe).ok(),
+                _ => None,
+            };
+        }
+        if self.data_port == 0 {
+            return Err(DriverError::NoHardware);
+        }
+        let uses_global_lock = device.as_ref().is_some_and(|device| {
+            matches!(namespace_value(device, b"_GLK"), Some(Value::Integer(1)))
+        });
+
+        // without an EC the status port floats to 0xFF
+        let status = read_status(self.command_port);
+        if status == 0xFF {
+            log::warn!("{}: No EC at {:#x}", Self::DRIVER_NAME, self.command_port);
+            return Err(DriverError::NoHardware);
+        }
+        if status.get_bit(STATUS_BURST) {
+            // left in burst mode by the firmware
+            send_command(self.command_port, BD_EC)?;
+        }
+        // a stale byte would be taken as the answer to the next command
+        if status.get_bit(STATUS_OBF) {
+            let port = Pio::<u8>::new(self.data_port);
+            // SAFETY: the EC data port, the byte is not expected by anyone
+            let byte = unsafe { port.read() };
+            log::debug!("{}: Dropped output byte {:#04X}", Self::DRIVER_NAME, byte);
+        }
+
+        fox_io::reserve(Space::Port, self.data_port.into(), 1, Self::DRIVER_NAME);
+        fox_io::reserve(Space::Port, self.command_port.into(), 1, Self::DRIVER_NAME);
+        USES_GLOBAL_LOCK.store(uses_global_lock, Ordering::Relaxed);
+        PORT_COMMAND.store(self.command_port, Ordering::Relaxed);
+        PORT_DATA.store(self.data_port, Ordering::Release);
+
+        log::info!(
+            "{}: Ports {:#x}/{:#x}, GPE {:?}, global lock {}",
+            Self::DRIVER_NAME,
+            self.data_port,
+            self.command_port,
+            self.gpe,
+            uses_global_lock
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("AcpiEc::remove()");
+
+        PORT_DATA.store(0, Ordering::Release);
+        PORT_COMMAND.store(0, Ordering::Relaxed);
+        fox_io::release(Self::DRIVER_NAME);
+    }
+}
+
+/// Data and command port, [`DriverError::NoHardware`] without [`Driver::init`]
+fn ports() -> Result<(u16, u16), DriverError> {
+    let data = PORT_DATA.load(Ordering::Acquire);
+    if data == 0 {
+        return Err(DriverError::NoHardware);
+    }
+    Ok((data, PORT_COMMAND.load(Ordering::Relaxed)))
+}
+
+/// Run a transaction under the ACPI global lock, if the EC asks for it
+///
+/// Without a usable FACS the transaction runs unlocked.
+fn with_global_lock<R>(f: impl FnOnce() -> Result<R, DriverError>) -> Result<R, DriverError> {
+    if !USES_GLOBAL_LOCK.load(Ordering::Relaxed) {
+        return f();
+    }
+    match fox_acpi::global_lock() {
+        Ok(_lock) => f(),
+        Err(fox_acpi::Error::Timeout) => Err(DriverError::Timeout),
+        Err(err) => {
+            log::debug!("{}: No global lock: {}", AcpiEc::DRIVER_NAME, err);
+            f()
+        }
+    }
+}
+
+/// The first PNP0C09 device of the namespace
+fn ec_device() -> Option<fox_aml::Path> {
+    let namespace = fox_aml::namespace()?;
+    namespace.find_devices(HARDWARE_ID).next().cloned()
+}
+
+fn namespace_value(device: &[fox_aml::NameSeg], name: &fox_aml::NameSeg) -> Option<Value> {
+    match fox_aml::namespace()?.child(device, name)? {
+        NodeKind::Name(value) => Some(*value),
+        _ => None,
+    }
+}
+
+/// Data and command port from the \_CRS: the data port first, the legacy ports without one
+fn device_ports(device: &[fox_aml::NameSeg]) -> (u16, u16) {
+    let resources = fox_aml::namespace().and_then(|i| i.current_resources(device));
+    let mut ports = resources
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|i| match i {
+            Resource::Io { base, .. } => Some(base),
+            _ => None,
+        });
+    match (ports.next(), ports.next()) {
+        (Some(data), Some(command)) => (data, command),
+        _ => {
+            log::debug!(
+                "{}: No ports in _CRS, using the legacy ones",
+                AcpiEc::DRIVER_NAME
+            );
+            (PORT_DATA_DEFAULT, PORT_COMMAND_DEFAULT)
+        }
+    }
+}
+
+fn read_status(command: u16) -> u8 {
+    let port = Pio::<u8>::new(command);
+    // SAFETY: the EC status register, reading has no side effects
+    unsafe { port.read() }
+}
+
+/// Wait for the EC to take the last byte
+fn wait_input_empty(command: u16) -> Result<(), DriverError> {
+    poll_timeout(TIMEOUT, || {
+        (!read_status(command).get_bit(STATUS_IBF)).then_some(())
+    })
+    .ok_or(DriverError::Timeout)
+}
+
+fn send_command(command: u16, byte: u8) -> Result<(), DriverError> {
+    wait_input_empty(command)?;
+    let mut port = Pio::<u8>::new(command);
+    // SAFETY: the EC command register, the input buffer is empty
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn write_data(data: u16, command: u16, byte: u8) -> Result<(), DriverError> {
+    wait_input_empty(command)?;
+    let mut port = Pio::<u8>::new(data);
+    // SAFETY: the EC data register, the input buffer is empty
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn read_data(data: u16, command: u16) -> Result<u8, DriverError> {
+    poll_timeout(TIMEOUT, || {
+        read_status(command).get_bit(STATUS_OBF).then_some(())
+    })
+    .ok_or(DriverError::Timeout)?;
+    let port = Pio::<u8>::new(data);
+    // SAFETY: the EC data register, the output buffer is full
+    Ok(unsafe { port.read() })
+}
+
+/// Ask for burst mode, `false` if the EC declined
+fn enable_burst(data: u16, command: u16) -> Result<bool, DriverError> {
+    send_command(command, BE_EC)?;
+    match read_data(data, command)? {
+        BURST_ACK => Ok(true),
+        byte => {
+            log::debug!("{}: Burst refused ({:#04X})", AcpiEc::DRIVER_NAME, byte);
+            Ok(false)
+        }
+    }
+}
@@ -0,0 +1,642 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! Advanced Host Controller Interface (SATA)
+//!
+//! Commands are polled, one at a time, from command slot 0. Memory is identity-mapped, so
+//! addresses of statics and caller buffers are used for DMA as they are.
+//!
+//! https://wiki.osdev.org/AHCI
+
+use core::sync::atomic::{Ordering, compiler_fence};
+use core::time::Duration;
+use core::{fmt, str};
+
+use bit_field::BitField;
+
+use super::pci::{Bar, Pci, PciDevice, PowerState};
+use super::{Dependency, Driver, DriverError};
+use crate::fox_block::{BlockDevice, check_range};
+use crate::fox_mem::map_mmio;
+use crate::fox_port::MmioBlock;
+use crate::fox_time::poll_timeout;
+use crate::fox_vec::FixedVec;
+use crate::{bitfields, mapped_registers, registers};
+
+const MAX_PORTS: usize = 32;
+
+/// Sectors per command, keeps one PRDT entry under its 4 MiB limit for 4K sectors
+const MAX_SECTORS_PER_COMMAND: usize = 128;
+
+const TIMEOUT_COMMAND: Duration = Duration::from_secs(5);
+const TIMEOUT_PORT: Duration = Duration::from_millis(500);
+const TIMEOUT_HANDOFF: Duration = Duration::from_secs(2);
+
+/// Command list, received FIS and command table of every port, used by DMA
+static mut PORT_MEMORY: [PortMemory; MAX_PORTS] = [const { PortMemory::new() }; MAX_PORTS];
+
+/// AHCI host bus adapter
+#[derive(Default, Debug)]
+pub struct Ahci {
+    /// HBA memory registers (BAR5)
+    abar: MmioBlock,
+    disks: FixedVec<AhciDisk, MAX_PORTS>,
+}
+
+/// ATA disk attached to a port
+#[derive(Copy, Clone)]
+pub struct AhciDisk {
+    abar: MmioBlock,
+    port: u8,
+    block_size: usize,
+    block_count: u64,
+    model: [u8; 40],
+}
+
+impl Ahci {
+    pub fn disks(&self) -> &[AhciDisk] {
+        &self.disks
+    }
+}
+
+impl Driver for Ahci {
+    const DRIVER_NAME: &str = "ahci";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Driver(Pci::DRIVER_NAME)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Ahci::probe()");
+
+        if controller().is_none() {
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Ahci::init()");
+
+        let (dev, abar, size) = controller().ok_or(DriverError::NoHardware)?;
+        // uncached, whatever the firmware chose
+        let address = map_mmio(abar, size as usize).map_err(DriverError::Mmio)?;
+        self.abar = MmioBlock::new(address);
+        log::info!("{}: {} ABAR at {:#x}", Self::DRIVER_NAME, dev.address, abar);
+
+        if let Some(pm) = dev.power_management()
+            && pm.state() != PowerState::D0
+        {
+            pm.set_state(PowerState::D0);
+        }
+        dev.enable_bus_master();
+
+        let hba = self.hba();
+        let version = read_register(hba.vs());
+        let cap = read_register(hba.cap());
+        log::debug!(
+            "{}: version {}.{}, {} ports, {} command slots{}",
+            Self::DRIVER_NAME,
+            version.get_bits(16..32),
+            version.get_bits(0..16),
+            cap.port_count() + 1,
+            cap.command_slots() + 1,
+            if cap.has_64_bit_addressing() {
+                ", 64-bit"
+            } else {
+                ""
+            }
+        );
+        if !cap.has_64_bit_addressing() && PortMemory::address(0) > u32::MAX as u64 {
+            log::error!("{}: no 64-bit DMA", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+
+        self.bios_handoff()?;
+
+        // AHCI mode, polled
+        let mut ghc = read_register(hba.ghc());
+        ghc.set_bit(31, true);
+        ghc.set_bit(1, false);
+        write_register(hba.ghc(), ghc);
+
+        let implemented = read_register(hba.pi());
+        for port in 0..MAX_PORTS as u8 {
+            if !implemented.get_bit(port as usize) {
+                continue;
+            }
+            match self.init_port(port) {
+                Ok(Some(disk)) => {
+                    log::info!(
+                        "{}: port {}: {} ({} blocks of {} bytes)",
+                        Self::DRIVER_NAME,
+                        port,
+                        disk,
+                        disk.block_count,
+                        disk.block_size
+                    );
+                    let _ = self.disks.push(disk);
+                }
+                Ok(None) => {}
+                Err(err) => log::warn!("{}: port {}: {}", Self::DRIVER_NAME, port, err),
+            }
+        }
+
+        if self.disks.is_empty() {
+            log::info!("{}: No disks", Self::DRIVER_NAME);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Ahci::remove()");
+
+        for disk in self.disks.iter() {
+            if let Err(err) = disk.stop() {
+                log::warn!("{}: port {}: {}", Self::DRIVER_NAME, disk.port, err);
+            }
+        }
+        self.disks = FixedVec::new();
+    }
+}
+
+impl Ahci {
+    /// Take the HBA from the firmware if it supports BIOS/OS handoff
+    fn bios_handoff(&self) -> Result<(), DriverError> {
+        // log::trace!("Ahci::bios_handoff()");
+
+        let hba = self.hba();
+        if !read_register(hba.cap2()).get_bit(0) {
+            return Ok(());
+        }
+        // OS Owned Semaphore
+        let mut bohc = read_register(hba.bohc());
+        bohc.set_bit(1, true);
+        write_register(hba.bohc(), bohc);
+        // BIOS Owned Semaphore, then BIOS Busy
+        poll_timeout(TIMEOUT_HANDOFF, || {
+            let bohc = read_register(hba.bohc());
+            (!bohc.get_bit(0) && !bohc.get_bit(4)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+    }
+
+    fn init_port(&self, port: u8) -> Result<Option<AhciDisk>, DriverError> {
+        // log::trace!("Ahci::init_port()");
+
+        let mut disk = AhciDisk {
+            abar: self.abar,
+            port,
+            block_size: 512,
+            block_count: 0,
+            model: [b' '; 40],
+        };
+
+        // Device Detection: present and communication established, Interface Power: active
+        let registers = disk.registers();
+        let ssts = read_register(registers.ssts());
+        if ssts.get_bits(0..4) != 3 || ssts.get_bits(8..12) != 1 {
+            return Ok(None);
+        }
+        let signature = read_register(registers.sig());
+        if signature != SIGNATURE_ATA {
+            log::debug!(
+                "{}: port {}: skip signature {:#010x}",
+                Self::DRIVER_NAME,
+                port,
+                signature
+            );
+            return Ok(None);
+        }
+
+        disk.stop()?;
+
+        let command_list = PortMemory::address(port);
+        let received_fis = command_list + PortMemory::OFFSET_RECEIVED_FIS;
+        write_register64(registers.clb(), command_list);
+        write_register64(registers.fb(), received_fis);
+        write_register(registers.serr(), u32::MAX);
+        write_register(registers.is(), u32::MAX);
+        write_register(registers.ie(), 0);
+
+        disk.start()?;
+
+        let mut identify = [0u16; 256];
+        // SAFETY: u16 has no invalid bit patterns
+        let buffer = unsafe { identify.align_to_mut::<u8>().1 };
+        disk.issue(COMMAND_IDENTIFY_DEVICE, 0, 1, Transfer::Read(buffer))?;
+
+        // Word 83 bit 10: 48-bit Address feature set supported
+        disk.block_count = if identify[83].get_bit(10) {
+            (identify[100] as u64)
+                | (identify[101] as u64) << 16
+                | (identify[102] as u64) << 32
+                | (identify[103] as u64) << 48
+        } else {
+            (identify[60] as u64) | (identify[61] as u64) << 16
+        };
+        // Word 106: valid, logical sector longer than 256 words
+        let sector_info = identify[106];
+        if sector_info.get_bits(14..16) == 0b01 && sector_info.get_bit(12) {
+            let words = (identify[117] as usize) | (identify[118] as usize) << 16;
+            disk.block_size = words * 2;
+        }
+        // Words 27..46: model number, two characters per word, first one in the high byte
+        for (i, word) in identify[27..47].iter().enumerate() {
+            disk.model[i * 2..i * 2 + 2].copy_from_slice(&word.to_be_bytes());
+        }
+
+        Ok(Some(disk))
+    }
+
+    fn hba(&self) -> HbaRegisters {
+        HbaRegisters(self.abar)
+    }
+}
+
+impl AhciDisk {
+    /// Model number from IDENTIFY DEVICE
+    pub fn model(&self) -> &str {
+        str::from_utf8(&self.model).map_or("?", |model| model.trim())
+    }
+
+    /// Clear ST and FRE and wait for the DMA engines to stop
+    fn stop(&self) -> Result<(), DriverError> {
+        let registers = self.registers();
+        modify_register(registers.cmd(), |mut value| {
+            value.set_is_started(false);
+            value
+        });
+        poll_timeout(TIMEOUT_PORT, || {
+            (!read_register(registers.cmd()).is_command_list_running()).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+
+        modify_register(registers.cmd(), |mut value| {
+            value.set_is_fis_receive_enabled(false);
+            value
+        });
+        poll_timeout(TIMEOUT_PORT, || {
+            (!read_register(registers.cmd()).is_fis_receive_running()).then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+    }
+
+    /// Set FRE, wait for the device to become ready and set ST
+    fn start(&self) -> Result<(), DriverError> {
+        let registers = self.registers();
+        modify_register(registers.cmd(), |mut value| {
+            value.set_is_fis_receive_enabled(true);
+            value
+        });
+
+        self.wait_ready()?;
+
+        modify_register(registers.cmd(), |mut value| {
+            value.set_is_started(true);
+            value
+        });
+        Ok(())
+    }
+
+    /// Wait until the device is neither busy (BSY) nor expecting data (DRQ)
+    fn wait_ready(&self) -> Result<(), DriverError> {
+        poll_timeout(TIMEOUT_COMMAND, || {
+            let status = read_register(self.registers().tfd());
+            (!status.get_bit(7) && !status.get_bit(3)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+    }
+
+    /// Issue a command from slot 0 and wait for its completion
+    fn issue(
+        &self,
+        command: u8,
+        lba: u64,
+        count: u16,
+        transfer: Transfer,
+    ) -> Result<(), DriverError> {
+        // log::trace!("AhciDisk::issue()");
+
+        let (data, length, is_write) = match transfer {
+            Transfer::None => (0, 0, false),
+            Transfer::Read(buffer) => (buffer.as_mut_ptr() as u64, buffer.len(), false),
+            Transfer::Write(buffer) => (buffer.as_ptr() as u64, buffer.len(), true),
+        };
+        // PRDT Data Base Address bit 0 is reserved
+        if !data.is_multiple_of(2) {
+            return Err(DriverError::InvalidRequest);
+        }
+        self.wait_ready()?;
+
+        let memory = &raw mut PORT_MEMORY;
+        // SAFETY: the slot is idle, only this port's memory is touched
+        let memory = unsafe { &mut (*memory)[self.port as usize] };
+        let table_address = &raw const memory.command_table as u64;
+
+        let mut fis = [0u8; 20];
+        fis[0] = FIS_TYPE_REG_H2D;
+        // Command, not Control
+        fis[1] = 0x80;
+        fis[2] = command;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[0..3]);
+        // LBA mode
+        fis[7] = 1 << 6;
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&count.to_le_bytes());
+
+        let table = &mut memory.command_table;
+        table.cfis = [0; 64];
+        table.cfis[..fis.len()].copy_from_slice(&fis);
+        table.prdt[0] = PrdtEntry {
+            data_address: data as u32,
+            data_address_upper: (data >> 32) as u32,
+            reserved: 0,
+            byte_count: length.saturating_sub(1) as u32,
+        };
+
+        // Command FIS Length in dwords, Write, at most one PRDT entry
+        let mut flags = (fis.len() / 4) as u16;
+        flags.set_bit(6, is_write);
+        let header = CommandHeader {
+            flags,
+            prdt_length: (length != 0) as u16,
+            prd_byte_count: 0,
+            table_address: table_address as u32,
+            table_address_upper: (table_address >> 32) as u32,
+            reserved: [0; 4],
+        };
+        // SAFETY: the command list is only read by the HBA after CI is set
+        unsafe { (&raw mut memory.command_list[0]).write_volatile(header) };
+
+        compiler_fence(Ordering::SeqCst);
+        let registers = self.registers();
+        write_register(registers.is(), u32::MAX);
+        write_register(registers.ci(), 1);
+
+        let result = poll_timeout(TIMEOUT_COMMAND, || {
+            // Task File Error Status
+            if read_register(registers.is()).get_bit(30) {
+                return Some(Err(()));
+            }
+            (!read_register(registers.ci()).get_bit(0)).then_some(Ok(()))
+        });
+        compiler_fence(Ordering::SeqCst);
+
+        let tfd = read_register(registers.tfd());
+        match result {
+            Some(Ok(())) if !tfd.get_bit(0) => Ok(()),
+            Some(_) => Err(DriverError::DeviceError {
+                status: tfd.get_bits(0..8) as u8,
+                error: tfd.get_bits(8..16) as u8,
+            }),
+            None => {
+                // the slot stays busy until the port is restarted
+                let _ = self.stop().and_then(|()| self.start());
+                Err(DriverError::Timeout)
+            }
+        }
+    }
+
+    fn registers(&self) -> PortRegisters {
+        PortRegisters(self.abar.at(0x100 + self.port as u64 * 0x80))
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), DriverError> {
+        // log::trace!("AhciDisk::read_blocks()");
+
+        check_range(self, lba, buffer.len())?;
+
+        let mut lba = lba;
+        for chunk in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            self.issue(
+                COMMAND_READ_DMA_EXT,
+                lba,
+                count as u16,
+                Transfer::Read(chunk),
+            )?;
+            lba += count as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), DriverError> {
+        // log::trace!("AhciDisk::write_blocks()");
+
+        check_range(self, lba, buffer.len())?;
+
+        let mut lba = lba;
+        for chunk in buffer.chunks(MAX_SECTORS_PER_COMMAND * self.block_size) {
+            let count = chunk.len() / self.block_size;
+            self.issue(
+                COMMAND_WRITE_DMA_EXT,
+                lba,
+                count as u16,
+                Transfer::Write(chunk),
+            )?;
+            lba += count as u64;
+        }
+        self.issue(COMMAND_FLUSH_CACHE_EXT, 0, 0, Transfer::None)
+    }
+}
+
+impl fmt::Debug for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AhciDisk")
+            .field("port", &self.port)
+            .field("model", &self.model())
+            .field("block_size", &self.block_size)
+            .field("block_count", &self.block_count)
+            .finish()
+    }
+}
+
+impl fmt::Display for AhciDisk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.model())
+    }
+}
+
+/// First SATA controller in AHCI mode, its ABAR and the ABAR size
+fn controller() -> Option<(&'static PciDevice, u64, u64)> {
+    // prog-if 01: AHCI 1.0
+    Pci::find_class(0x01, 0x06)
+        .filter(|i| i.prog_if == 0x01)
+        .find_map(|dev| match dev.bars[5] {
+            Bar::Memory { address, size, .. } if address != 0 => Some((dev, address, size)),
+            _ => None,
+        })
+}
+
+// all registers of the HBA are in ABAR, mapped uncached by `Ahci::init`
+mapped_registers!();
+
+registers! {
+    /// Generic Host Control
+    struct HbaRegisters {
+        /// Host Capabilities
+        cap: 0x00 => HbaCapabilities, ReadOnlyAccess;
+        /// Global Host Control
+        ghc: 0x04 => u32, ReadWriteAccess;
+        /// Ports Implemented
+        pi: 0x0C => u32, ReadOnlyAccess;
+        /// Version
+        vs: 0x10 => u32, ReadOnlyAccess;
+        /// Host Capabilities Extended
+        cap2: 0x24 => u32, ReadOnlyAccess;
+        /// BIOS/OS Handoff Control and Status
+        bohc: 0x28 => u32, ReadWriteAccess;
+    }
+}
+
+registers! {
+    /// Of a port, at 0x100 + port * 0x80
+    struct PortRegisters {
+        /// Command List Base Address
+        clb: 0x00 => u64, ReadWriteAccess;
+        /// FIS Base Address
+        fb: 0x08 => u64, ReadWriteAccess;
+        /// Interrupt Status
+        is: 0x10 => u32, ReadWriteAccess;
+        /// Interrupt Enable
+        ie: 0x14 => u32, ReadWriteAccess;
+        /// Command and Status
+        cmd: 0x18 => PortCommand, ReadWriteAccess;
+        /// Task File Data
+        tfd: 0x20 => u32, ReadOnlyAccess;
+        /// Signature
+        sig: 0x24 => u32, ReadOnlyAccess;
+        /// SATA Status
+        ssts: 0x28 => u32, ReadOnlyAccess;
+        /// SATA Error
+        serr: 0x30 => u32, ReadWriteAccess;
+        /// Command Issue
+        ci: 0x38 => u32, ReadWriteAccess;
+    }
+}
+
+bitfields! {
+    /// CAP
+    struct HbaCapabilities(u32) {
+        /// Number of Ports, minus one
+        port_count: 0..5;
+        /// Number of Command Slots, minus one
+        command_slots: 8..13;
+        /// Supports 64-bit Addressing
+        has_64_bit_addressing: 31;
+    }
+}
+
+bitfields! {
+    /// PxCMD
+    struct PortCommand(u32) {
+        /// Start
+        is_started, set_is_started: 0;
+        /// FIS Receive Enable
+        is_fis_receive_enabled, set_is_fis_receive_enabled: 4;
+        /// FIS Receive Running
+        is_fis_receive_running: 14;
+        /// Command List Running
+        is_command_list_running: 15;
+    }
+}
+
+const SIGNATURE_ATA: u32 = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+
+const COMMAND_IDENTIFY_DEVICE: u8 = 0xEC;
+const COMMAND_READ_DMA_EXT: u8 = 0x25;
+const COMMAND_WRITE_DMA_EXT: u8 = 0x35;
+const COMMAND_FLUSH_CACHE_EXT: u8 = 0xEA;
+
+/// Data phase of a command
+enum Transfer<'a> {
+    None,
+    Read(&'a mut [u8]),
+    Write(&'a [u8]),
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct CommandHeader {
+    /// CFL, A, W, P, R, B, C, PMP
+    flags: u16,
+    prdt_length: u16,
+    /// Written by the HBA
+    prd_byte_count: u32,
+    table_address: u32,
+    table_address_upper: u32,
+    reserved: [u32; 4],
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct PrdtEntry {
+    data_address: u32,
+    data_address_upper: u32,
+    reserved: u32,
+    /// Byte count minus one, bit 31: interrupt on completion
+    byte_count: u32,
+}
+
+#[repr(C, align(128))]
+struct CommandTable {
+    cfis: [u8; 64],
+    acmd: [u8; 16],
+    reserved: [u8; 48],
+    prdt: [PrdtEntry; 1],
+}
+
+#[repr(C, align(1024))]
+struct PortMemory {
+    command_list: [CommandHeader; 32],
+    /// 256-byte aligned by its offset
+    received_fis: [u8; 256],
+    /// 128-byte aligned by its offset
+    command_table: CommandTable,
+}
+
+impl PortMemory {
+    const OFFSET_RECEIVED_FIS: u64 = 32 * size_of::<CommandHeader>() as u64;
+
+    const fn new() -> Self {
+        const HEADER: CommandHeader = CommandHeader {
+            flags: 0,
+            prdt_length: 0,
+            prd_byte_count: 0,
+            table_address: 0,
+            table_address_upper: 0,
+            reserved: [0; 4],
+        };
+        Self {
+            command_list: [HEADER; 32],
+            received_fis: [0; 256],
+            command_table: CommandTable {
+                cfis: [0; 64],
+                acmd: [0; 16],
+                reserved: [0; 48],
+                prdt: [PrdtEntry {
+                    data_address: 0,
+                    data_address_upper: 0,
+                    reserved: 0,
+                    byte_count: 0,
+                }],
+            },
+        }
+    }
+
+    /// Physical address of the memory of `port`
+    fn address(port: u8) -> u64 {
+        let memory = &raw const PORT_MEMORY;
+        // SAFETY: only the address is taken
+        unsafe { &raw const (*memory)[port as usize] as u64 }
+    }
+}
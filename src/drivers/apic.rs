@@ -0,0 +1,249 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! Local APIC and I/O APIC
+//!
+//! https://wiki.osdev.org/APIC
+//! https://wiki.osdev.org/IOAPIC
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use bit_field::BitField;
+
+use super::{Dependency, Driver, DriverError};
+use crate::fox_acpi::{IoApic, Table, madt};
+use crate::fox_cpu::msr::{self, IA32_APIC_BASE};
+use crate::fox_cpu::{Feature, has_feature};
+use crate::fox_mem::{PAGE_SIZE, map_mmio};
+use crate::fox_port::MmioBlock;
+
+/// Base of the local APIC registers, 0 while the driver is not initialized
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Local APIC of the bootstrap processor and the I/O APICs
+#[derive(Default, Debug)]
+pub struct Apic {
+    /// IA32_APIC_BASE set by the firmware, restored by [`Driver::remove`]
+    saved_apic_base: Option<u64>,
+    /// Spurious Interrupt Vector Register set by the firmware
+    saved_svr: u32,
+}
+
+impl Apic {
+    /// Vector of spurious interrupts (bits 0-3 are hardwired to 1 on old CPUs)
+    pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+    /// The local APIC is enabled and I/O APIC routing is in use
+    pub fn is_active() -> bool {
+        LAPIC_BASE.load(Ordering::Acquire) != 0
+    }
+
+    /// Route an ISA IRQ to `vector` of the bootstrap processor and unmask it
+    pub fn route_irq(irq: u8, vector: u8) {
+        let Some((io_apic, gsi, flags)) = isa_irq_to_gsi(irq) else {
+            log::warn!("apic: No I/O APIC for IRQ {}", irq);
+            return;
+        };
+
+        let mut entry = vector as u64;
+        // delivery mode fixed, destination mode physical
+        // MPS INTI flags: polarity (bits 0-1), trigger mode (bits 2-3)
+        entry.set_bit(13, flags.get_bits(0..2) == 0b11);
+        entry.set_bit(15, flags.get_bits(2..4) == 0b11);
+        entry.set_bits(56..64, lapic_id() as u64);
+
+        io_apic_write_redirection(&io_apic, gsi, entry);
+    }
+
+    /// Disable the I/O APIC input of an ISA IRQ
+    pub fn mask_irq(irq: u8) {
+        let Some((io_apic, gsi, _)) = isa_irq_to_gsi(irq) else {
+            return;
+        };
+        let mut entry = io_apic_read_redirection(&io_apic, gsi);
+        entry.set_bit(16, true);
+        io_apic_write_redirection(&io_apic, gsi, entry);
+    }
+
+    /// Must be sent at the end of every IRQ handler
+    pub fn send_eoi() {
+        lapic_write(LAPIC_EOI, 0);
+    }
+}
+
+impl Driver for Apic {
+    const DRIVER_NAME: &str = "apic";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Table(Table::Madt)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Apic::probe()");
+
+        if !has_feature(Feature::Apic) {
+            log::warn!("{}: No local APIC found", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        if madt().is_none_or(|madt| madt.io_apics.is_empty()) {
+            log::warn!("{}: No I/O APIC found", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Apic::init()");
+
+        let madt = madt().ok_or(DriverError::NoHardware)?;
+
+        // SAFETY: the MSR exists, checked by `probe`
+        let apic_base = unsafe { msr::read(IA32_APIC_BASE) };
+        if apic_base.get_bit(10) {
+            // MMIO registers are not available in x2APIC mode
+            log::warn!("{}: x2APIC mode is not supported", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        self.saved_apic_base = Some(apic_base);
+        let mut value = apic_base;
+        value.set_bit(11, true);
+        // SAFETY: global enable, the base stays the same
+        unsafe { msr::write(IA32_APIC_BASE, value) };
+
+        let lapic_base =
+            map_mmio(madt.local_apic_address, PAGE_SIZE as usize).map_err(DriverError::Mmio)?;
+        for io_apic in madt.io_apics.iter() {
+            map_mmio(io_apic.address as u64, IOAPIC_SIZE).map_err(DriverError::Mmio)?;
+        }
+        LAPIC_BASE.store(lapic_base, Ordering::Release);
+
+        // drivers route what they handle
+        mask_all(madt.io_apics.as_slice());
+
+        // accept all priorities, software enable with the spurious vector
+        lapic_write(LAPIC_TPR, 0);
+        self.saved_svr = lapic_read(LAPIC_SVR);
+        lapic_write(LAPIC_SVR, 1 << 8 | Self::SPURIOUS_VECTOR as u32);
+
+        log::info!(
+            "{}: Local APIC {} at {:#X}",
+            Self::DRIVER_NAME,
+            lapic_id(),
+            madt.local_apic_address
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Apic::remove()");
+
+        if let Some(madt) = madt() {
+            mask_all(madt.io_apics.as_slice());
+        }
+
+        lapic_write(LAPIC_SVR, self.saved_svr);
+        LAPIC_BASE.store(0, Ordering::Release);
+
+        if let Some(apic_base) = self.saved_apic_base.take() {
+            // SAFETY: the value was set by the firmware
+            unsafe { msr::write(IA32_APIC_BASE, apic_base) };
+        }
+    }
+}
+
+/// Local APIC ID Register
+const LAPIC_ID: u64 = 0x20;
+/// Task Priority Register
+const LAPIC_TPR: u64 = 0x80;
+/// EOI Register
+const LAPIC_EOI: u64 = 0xB0;
+/// Spurious Interrupt Vector Register
+const LAPIC_SVR: u64 = 0xF0;
+
+/// IOREGSEL and IOWIN
+const IOAPIC_SIZE: usize = 0x20;
+/// I/O Register Select, the index of the register IOWIN accesses
+const IOREGSEL: u64 = 0x00;
+/// I/O Window
+const IOWIN: u64 = 0x10;
+
+/// I/O APIC Version Register
+const IOAPICVER: u32 = 0x01;
+/// First I/O APIC Redirection Table register, two per entry
+const IOREDTBL: u32 = 0x10;
+
+fn lapic_id() -> u8 {
+    lapic_read(LAPIC_ID).get_bits(24..32) as u8
+}
+
+fn lapic() -> MmioBlock {
+    let base = LAPIC_BASE.load(Ordering::Acquire);
+    assert_ne!(base, 0);
+    MmioBlock::new(base)
+}
+
+fn lapic_read(register: u64) -> u32 {
+    // SAFETY: mapped by `init`
+    unsafe { lapic().read(register) }
+}
+
+fn lapic_write(register: u64, value: u32) {
+    // SAFETY: mapped by `init`
+    unsafe { lapic().write(register, value) };
+}
+
+/// I/O APIC, GSI and MPS INTI flags of an ISA IRQ
+fn isa_irq_to_gsi(irq: u8) -> Option<(IoApic, u32, u16)> {
+    let madt = madt()?;
+    // identity mapped unless the firmware says otherwise
+    let (gsi, flags) = match madt.isa_override(irq) {
+        Some(i) => (i.gsi, i.flags),
+        None => (irq as u32, 0),
+    };
+    let io_apic = madt
+        .io_apics
+        .iter()
+        .filter(|i| i.gsi_base <= gsi)
+        .max_by_key(|i| i.gsi_base)?;
+    Some((*io_apic, gsi, flags))
+}
+
+/// Mask every redirection entry of every I/O APIC
+fn mask_all(io_apics: &[IoApic]) {
+    for io_apic in io_apics {
+        // Maximum Redirection Entry (bits 16-23)
+        let count = io_apic_read(io_apic, IOAPICVER).get_bits(16..24) + 1;
+        for i in 0..count {
+            io_apic_write_redirection(io_apic, io_apic.gsi_base + i, 1 << 16);
+        }
+    }
+}
+
+fn io_apic_read_redirection(io_apic: &IoApic, gsi: u32) -> u64 {
+    let register = IOREDTBL + (gsi - io_apic.gsi_base) * 2;
+    let lo = io_apic_read(io_apic, register) as u64;
+    let hi = io_apic_read(io_apic, register + 1) as u64;
+    hi << 32 | lo
+}
+
+fn io_apic_write_redirection(io_apic: &IoApic, gsi: u32, entry: u64) {
+    let register = IOREDTBL + (gsi - io_apic.gsi_base) * 2;
+    // mask first so a half-written entry never fires
+    io_apic_write(io_apic, register, 1 << 16);
+    io_apic_write(io_apic, register + 1, (entry >> 32) as u32);
+    io_apic_write(io_apic, register, entry as u32);
+}
+
+fn io_apic_read(io_apic: &IoApic, register: u32) -> u32 {
+    let registers = MmioBlock::new(io_apic.address as u64);
+    // SAFETY: mapped by `init`
+    unsafe {
+        registers.write(IOREGSEL, register);
+        registers.read(IOWIN)
+    }
+}
+
+fn io_apic_write(io_apic: &IoApic, register: u32, value: u32) {
+    let registers = MmioBlock::new(io_apic.address as u64);
+    // SAFETY: mapped by `init`
+    unsafe {
+        registers.write(IOREGSEL, register);
+        registers.write(IOWIN, value);
+    }
+}
@@ -0,0 +1,188 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! CMOS Real-Time Clock
+//!
+//! https://wiki.osdev.org/CMOS
+//! https://wiki.osdev.org/RTC
+
+use core::fmt;
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{Driver, DriverError};
+use crate::fox_acpi::fadt;
+use crate::fox_port::{Pio, WriteOnlyAccess};
+use crate::fox_sync::without_interrupts;
+use crate::fox_time::poll_timeout;
+
+/// Update cycle of the RTC (at most 1984 µs)
+const TIMEOUT_UPDATE: Duration = Duration::from_millis(10);
+
+/// CMOS Real-Time Clock
+#[derive(Default, Debug)]
+pub struct CmosRtc {
+    /// CMOS register of the century from the FADT, 0 if there is none
+    century_register: u8,
+}
+
+/// Wall-clock time as kept by the RTC (usually local time)
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl CmosRtc {
+    /// Read the current date and time
+    pub fn now(&self) -> Result<DateTime, DriverError> {
+        // log::trace!("CmosRtc::now()");
+
+        // read until two reads in a row agree, an update may happen in between
+        let mut last = self.read_raw()?;
+        loop {
+            let raw = self.read_raw()?;
+            if raw == last {
+                break;
+            }
+            last = raw;
+        }
+
+        let status_b = read_register(REGISTER_STATUS_B);
+        let is_binary = status_b.get_bit(2);
+        let is_24_hour = status_b.get_bit(1);
+        let decode = |value: u8| {
+            if is_binary {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0x0F)
+            }
+        };
+
+        // 12-hour mode: bit 7 of the hour is PM
+        let mut hour = decode(last.hour & 0x7F);
+        if !is_24_hour {
+            let is_pm = last.hour.get_bit(7);
+            hour %= 12;
+            if is_pm {
+                hour += 12;
+            }
+        }
+
+        let year = decode(last.year) as u16;
+        let year = match last.century {
+            Some(century) => decode(century) as u16 * 100 + year,
+            // no century register, assume the 21st century
+            None => 2000 + year,
+        };
+
+        Ok(DateTime {
+            year,
+            month: decode(last.month),
+            day: decode(last.day),
+            hour,
+            minute: decode(last.minute),
+            second: decode(last.second),
+        })
+    }
+
+    /// Registers as stored, read outside of an update cycle
+    fn read_raw(&self) -> Result<RawDateTime, DriverError> {
+        poll_timeout(TIMEOUT_UPDATE, || {
+            (!read_register(REGISTER_STATUS_A).get_bit(7)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+
+        Ok(RawDateTime {
+            second: read_register(REGISTER_SECONDS),
+            minute: read_register(REGISTER_MINUTES),
+            hour: read_register(REGISTER_HOURS),
+            day: read_register(REGISTER_DAY),
+            month: read_register(REGISTER_MONTH),
+            year: read_register(REGISTER_YEAR),
+            century: (self.century_register != 0).then(|| read_register(self.century_register)),
+        })
+    }
+}
+
+impl Driver for CmosRtc {
+    const DRIVER_NAME: &str = "cmos_rtc";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("CmosRtc::probe()");
+
+        // CMOS RTC Not Present
+        let flags = fadt().map(|fadt| fadt.iapc_boot_arch);
+        if flags.is_some_and(|flags| flags.use_time_and_alarm_namespace_for_rtc()) {
+            log::warn!("{}: No RTC found", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("CmosRtc::init()");
+
+        if let Some(fadt) = fadt() {
+            self.century_register = fadt.century;
+        }
+
+        let now = self.now()?;
+        log::info!("{}: {}", Self::DRIVER_NAME, now);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("CmosRtc::remove()");
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: Option<u8>,
+}
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+
+const PORT_CMOS_ADDRESS: Pio<u8, WriteOnlyAccess> = Pio::new(0x0070);
+const PORT_CMOS_DATA: Pio<u8> = Pio::new(0x0071);
+
+fn read_register(register: u8) -> u8 {
+    let mut port_address = PORT_CMOS_ADDRESS;
+    let port_data = PORT_CMOS_DATA;
+    // an interrupt between selecting and reading could select another register
+    without_interrupts(|| {
+        // SAFETY: trust me
+        unsafe {
+            port_address.write(register);
+            port_data.read()
+        }
+    })
+}
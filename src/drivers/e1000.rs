@@ -0,0 +1,462 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! Intel 8254x (e1000) and 82574 (e1000e) Ethernet controllers
+//!
+//! Legacy descriptors, which both families support, one receive and one transmit ring, polled
+//! without interrupts. Memory is identity-mapped, so the buffers in a static are used for DMA
+//! as they are. [`E1000::nic`] hands out the [`Nic`] for [`crate::fox_net`].
+//!
+//! https://wiki.osdev.org/Intel_Ethernet_i217
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering, compiler_fence};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::pci::{Bar, Pci, PciDevice, PowerState};
+use super::{Dependency, Driver, DriverError};
+use crate::fox_mem::map_mmio;
+use crate::fox_net::{MacAddress, Nic};
+use crate::fox_port::MmioBlock;
+use crate::fox_time::{delay, poll_timeout};
+
+const RX_RING_SIZE: usize = 32;
+const TX_RING_SIZE: usize = 8;
+/// Per descriptor, RCTL.BSIZE 0 selects it
+const BUFFER_SIZE: usize = 2048;
+
+const TIMEOUT_RESET: Duration = Duration::from_millis(100);
+const TIMEOUT_EEPROM: Duration = Duration::from_millis(10);
+/// Until a transmit descriptor is free again
+const TIMEOUT_TRANSMIT: Duration = Duration::from_millis(100);
+
+/// Set by [`Driver::init`], the [`E1000Nic`] fails without it
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Descriptor rings and packet buffers, used by DMA
+static mut MEMORY: E1000Memory = E1000Memory::new();
+
+/// Supported devices, the 82540EM and 82574L are the ones QEMU emulates
+const DEVICES: &[(u16, Model)] = &[
+    (0x100E, Model::E1000),
+    (0x100F, Model::E1000),
+    (0x10D3, Model::E1000e),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Model {
+    /// 8254x
+    E1000,
+    /// 82574
+    E1000e,
+}
+
+/// e1000 or e1000e controller
+#[derive(Default)]
+pub struct E1000 {
+    /// Controller registers (BAR0)
+    bar: MmioBlock,
+    mac: MacAddress,
+}
+
+/// Frames through an initialized [`E1000`], the ring positions are kept in its registers
+pub struct E1000Nic {
+    bar: MmioBlock,
+    mac: MacAddress,
+}
+
+impl E1000 {
+    /// STATUS.LU
+    pub fn is_link_up(&self) -> bool {
+        read(self.bar, REG_STATUS).get_bit(1)
+    }
+
+    /// The controller for [`crate::fox_net::set_interface`], fails once the driver is removed
+    pub fn nic(&self) -> E1000Nic {
+        E1000Nic {
+            bar: self.bar,
+            mac: self.mac,
+        }
+    }
+
+    /// Word of the EEPROM through EERD, the fields moved on the 82574
+    fn read_eeprom(&self, model: Model, address: u8) -> Result<u16, DriverError> {
+        let (shift, done) = match model {
+            Model::E1000 => (8, 4),
+            Model::E1000e => (2, 1),
+        };
+        write(self.bar, REG_EERD, 1 | (address as u32) << shift);
+        let value = poll_timeout(TIMEOUT_EEPROM, || {
+            let value = read(self.bar, REG_EERD);
+            value.get_bit(done).then_some(value)
+        });
+        let value = value.ok_or(DriverError::Timeout)?;
+        Ok(value.get_bits(16..32) as u16)
+    }
+}
+
+impl Driver for E1000 {
+    const DRIVER_NAME: &str = "e1000";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Driver(Pci::DRIVER_NAME)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("E1000::probe()");
+
+        if controller().is_none() {
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("E1000::init()");
+
+        let (dev, model, bar, size) = controller().ok_or(DriverError::NoHardware)?;
+        // uncached, whatever the firmware chose
+        let address = map_mmio(bar, size as usize).map_err(DriverError::Mmio)?;
+        self.bar = MmioBlock::new(address);
+        log::info!(
+            "{}: {:04x} at {} BAR0 at {:#x}",
+            Self::DRIVER_NAME,
+            dev.device_id,
+            dev.address,
+            bar
+        );
+
+        if let Some(pm) = dev.power_management()
+            && pm.state() != PowerState::D0
+        {
+            pm.set_state(PowerState::D0);
+        }
+        dev.enable_bus_master();
+
+        // device reset, the MAC address is reloaded from the EEPROM
+        write(self.bar, REG_IMC, u32::MAX);
+        let ctrl = read(self.bar, REG_CTRL);
+        write(self.bar, REG_CTRL, ctrl | CTRL_RST);
+        delay(Duration::from_millis(1));
+        poll_timeout(TIMEOUT_RESET, || {
+            (!read(self.bar, REG_CTRL).get_bit(26)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+        // no interrupts, everything is polled
+        write(self.bar, REG_IMC, u32::MAX);
+        read(self.bar, REG_ICR);
+
+        let ral = read(self.bar, REG_RAL0);
+        let rah = read(self.bar, REG_RAH0);
+        // Address Valid
+        if rah.get_bit(31) {
+            let [a, b, c, d] = ral.to_le_bytes();
+            let [e, f, ..] = rah.to_le_bytes();
+            self.mac = MacAddress([a, b, c, d, e, f]);
+        } else {
+            for i in 0..3 {
+                let word = self.read_eeprom(model, i)?.to_le_bytes();
+                self.mac.0[i as usize * 2..][..2].copy_from_slice(&word);
+            }
+        }
+
+        // Set Link Up, Auto-Speed Detection Enable
+        let ctrl = read(self.bar, REG_CTRL);
+        write(self.bar, REG_CTRL, ctrl | CTRL_SLU | CTRL_ASDE);
+        // only broadcast and our address
+        for i in 0..128 {
+            write(self.bar, REG_MTA + i * 4, 0);
+        }
+
+        let memory = &raw mut MEMORY;
+        // SAFETY: the controller is reset and not using the memory
+        let memory = unsafe { &mut *memory };
+        for (descriptor, buffer) in memory.rx.0.iter_mut().zip(&memory.rx_buffers) {
+            *descriptor = RxDescriptor {
+                address: buffer.as_ptr() as u64,
+                ..RxDescriptor::EMPTY
+            };
+        }
+        for (descriptor, buffer) in memory.tx.0.iter_mut().zip(&memory.tx_buffers) {
+            *descriptor = TxDescriptor {
+                address: buffer.as_ptr() as u64,
+                ..TxDescriptor::EMPTY
+            };
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        let rx = memory.rx.0.as_ptr() as u64;
+        write(self.bar, REG_RDBAL, rx as u32);
+        write(self.bar, REG_RDBAH, (rx >> 32) as u32);
+        write(self.bar, REG_RDLEN, size_of_val(&memory.rx.0) as u32);
+        write(self.bar, REG_RDH, 0);
+        // all but one descriptor belong to the controller
+        write(self.bar, REG_RDT, RX_RING_SIZE as u32 - 1);
+        write(self.bar, REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx = memory.tx.0.as_ptr() as u64;
+        write(self.bar, REG_TDBAL, tx as u32);
+        write(self.bar, REG_TDBAH, (tx >> 32) as u32);
+        write(self.bar, REG_TDLEN, size_of_val(&memory.tx.0) as u32);
+        write(self.bar, REG_TDH, 0);
+        write(self.bar, REG_TDT, 0);
+        // Collision Threshold 15, Collision Distance 64 bytes, as recommended for full duplex
+        write(
+            self.bar,
+            REG_TCTL,
+            TCTL_EN | TCTL_PSP | 0x0F << 4 | 0x40 << 12,
+        );
+        // IPGT 10, IPGR1 8, IPGR2 6
+        write(self.bar, REG_TIPG, 10 | 8 << 10 | 6 << 20);
+
+        IS_ACTIVE.store(true, Ordering::Release);
+        if self.is_link_up() {
+            let speed = match read(self.bar, REG_STATUS).get_bits(6..8) {
+                0b00 => 10,
+                0b01 => 100,
+                _ => 1000,
+            };
+            log::info!(
+                "{}: {}, link up at {} Mb/s",
+                Self::DRIVER_NAME,
+                self.mac,
+                speed
+            );
+        } else {
+            log::info!("{}: {}, no link", Self::DRIVER_NAME, self.mac);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("E1000::remove()");
+
+        IS_ACTIVE.store(false, Ordering::Release);
+        write(self.bar, REG_RCTL, 0);
+        write(self.bar, REG_TCTL, 0);
+        write(self.bar, REG_IMC, u32::MAX);
+    }
+}
+
+impl Nic for E1000Nic {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), DriverError> {
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        if frame.len() > BUFFER_SIZE {
+            return Err(DriverError::InvalidRequest);
+        }
+        let tail = read(self.bar, REG_TDT) as usize;
+        let memory = &raw mut MEMORY;
+        // SAFETY: the controller is done with descriptors it wrote back or never got
+        let (descriptor, buffer) = unsafe {
+            (
+                &raw mut (*memory).tx.0[tail],
+                &mut (*memory).tx_buffers[tail],
+            )
+        };
+        poll_timeout(TIMEOUT_TRANSMIT, || {
+            // SAFETY: see above
+            let descriptor = unsafe { descriptor.read_volatile() };
+            (descriptor.command == 0 || descriptor.status.get_bit(0)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+
+        buffer[..frame.len()].copy_from_slice(frame);
+        let value = TxDescriptor {
+            address: buffer.as_ptr() as u64,
+            length: frame.len() as u16,
+            command: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+            ..TxDescriptor::EMPTY
+        };
+        // SAFETY: see above
+        unsafe { descriptor.write_volatile(value) };
+        compiler_fence(Ordering::SeqCst);
+        write(self.bar, REG_TDT, ((tail + 1) % TX_RING_SIZE) as u32);
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, DriverError> {
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        let next = (read(self.bar, REG_RDT) as usize + 1) % RX_RING_SIZE;
+        let memory = &raw mut MEMORY;
+        // SAFETY: descriptors with DD set belong to us until RDT passes them
+        let (descriptor, rx_buffer) =
+            unsafe { (&raw mut (*memory).rx.0[next], &(*memory).rx_buffers[next]) };
+        // SAFETY: see above
+        let mut value = unsafe { descriptor.read_volatile() };
+        // Descriptor Done
+        if !value.status.get_bit(0) {
+            return Ok(None);
+        }
+        compiler_fence(Ordering::SeqCst);
+
+        // frames spanning descriptors (no End Of Packet) or with errors are dropped
+        let length = value.length as usize;
+        let is_valid = value.status.get_bit(1) && value.errors == 0 && length <= buffer.len();
+        if is_valid {
+            buffer[..length].copy_from_slice(&rx_buffer[..length]);
+        }
+        value.status = 0;
+        // SAFETY: see above
+        unsafe { descriptor.write_volatile(value) };
+        compiler_fence(Ordering::SeqCst);
+        write(self.bar, REG_RDT, next as u32);
+        Ok(is_valid.then_some(length))
+    }
+}
+
+impl fmt::Debug for E1000 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("E1000")
+            .field("bar", &format_args!("{:#x}", self.bar.base()))
+            .field("mac", &format_args!("{}", self.mac))
+            .finish()
+    }
+}
+
+fn controller() -> Option<(&'static PciDevice, Model, u64, u64)> {
+    Pci::devices()
+        .iter()
+        .filter(|i| i.vendor_id == 0x8086)
+        .find_map(|dev| {
+            let &(_, model) = DEVICES.iter().find(|i| i.0 == dev.device_id)?;
+            match dev.bars[0] {
+                Bar::Memory { address, size, .. } if address != 0 => {
+                    Some((dev, model, address, size))
+                }
+                _ => None,
+            }
+        })
+}
+
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00C0;
+const REG_IMC: u64 = 0x00D8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+/// Multicast Table Array, 128 dwords
+const REG_MTA: u64 = 0x5200;
+const REG_RAL0: u64 = 0x5400;
+const REG_RAH0: u64 = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+
+const RCTL_EN: u32 = 1 << 1;
+/// Broadcast Accept Mode
+const RCTL_BAM: u32 = 1 << 15;
+/// Strip Ethernet CRC
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// Pad Short Packets
+const TCTL_PSP: u32 = 1 << 3;
+
+/// End Of Packet
+const TX_CMD_EOP: u8 = 1 << 0;
+/// Insert FCS
+const TX_CMD_IFCS: u8 = 1 << 1;
+/// Report Status, sets DD when done
+const TX_CMD_RS: u8 = 1 << 3;
+
+/// Legacy receive descriptor
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    /// DD, EOP, ...
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+impl RxDescriptor {
+    const EMPTY: Self = Self {
+        address: 0,
+        length: 0,
+        checksum: 0,
+        status: 0,
+        errors: 0,
+        special: 0,
+    };
+}
+
+/// Legacy transmit descriptor
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    checksum_offset: u8,
+    command: u8,
+    /// DD
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+impl TxDescriptor {
+    const EMPTY: Self = Self {
+        address: 0,
+        length: 0,
+        checksum_offset: 0,
+        command: 0,
+        status: 0,
+        checksum_start: 0,
+        special: 0,
+    };
+}
+
+/// Ring bases must be 128-byte aligned, pages are
+#[repr(C, align(4096))]
+struct Page<T>(T);
+
+#[repr(C)]
+struct E1000Memory {
+    rx: Page<[RxDescriptor; RX_RING_SIZE]>,
+    tx: Page<[TxDescriptor; TX_RING_SIZE]>,
+    rx_buffers: [[u8; BUFFER_SIZE]; RX_RING_SIZE],
+    tx_buffers: [[u8; BUFFER_SIZE]; TX_RING_SIZE],
+}
+
+impl E1000Memory {
+    const fn new() -> Self {
+        Self {
+            rx: Page([RxDescriptor::EMPTY; RX_RING_SIZE]),
+            tx: Page([TxDescriptor::EMPTY; TX_RING_SIZE]),
+            rx_buffers: [[0; BUFFER_SIZE]; RX_RING_SIZE],
+            tx_buffers: [[0; BUFFER_SIZE]; TX_RING_SIZE],
+        }
+    }
+}
+
+fn read(bar: MmioBlock, offset: u64) -> u32 {
+    // SAFETY: BAR0 is mapped by `init`
+    unsafe { bar.read(offset) }
+}
+
+fn write(bar: MmioBlock, offset: u64, value: u32) {
+    // SAFETY: BAR0 is mapped by `init`
+    unsafe { bar.write(offset, value) }
+}
@@ -3,291 +3,1361 @@
 //! I8042 PS/2 Controller
 //!
 //! https://wiki.osdev.org/I8042_PS/2_Controller
-//!
-//! TODO:
-//! - [`port::PortDataPort::read`] - spinlock
 
-use core::fmt;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::time::Duration;
 
+use acpi::fadt::{Fadt, IaPcBootArchFlags};
 use bit_field::BitField;
-use x86_64::instructions::port::{PortGeneric, ReadOnlyAccess, ReadWriteAccess, WriteOnlyAccess};
-
-use super::Driver;
-use crate::fox_acpi::fadt_raw;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{Driver, DriverError};
+use crate::fox_acpi::fadt;
+use crate::fox_aml::{self, PathDisplay, Resource};
+use crate::fox_event::wait_irq;
+use crate::fox_interrupts::{clear_irq_handler, end_of_interrupt, set_irq_handler};
+use crate::fox_io::{self, Space};
+use crate::fox_port::{Pio, ReadOnlyAccess, ReadWriteAccess, WriteOnlyAccess};
+use crate::fox_ring::RingBuffer;
+use crate::fox_sync::without_interrupts;
+use crate::fox_time::poll_timeout;
+use crate::fox_watchdog;
+pub use dto::{InputPort, OutputPort};
+use trace::{Access, Trace};
+
+mod trace;
+
+/// Legacy IRQs, taken from the \_CRS of the ACPI devices by [`Driver::init`]
+static IRQ_PORT1: AtomicU8 = AtomicU8::new(1);
+static IRQ_PORT2: AtomicU8 = AtomicU8::new(12);
+
+/// Set by [`Driver::init`], the controller RAM is only accessed with the driver in charge
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+/// Last configuration byte written, to mask the IRQs around commands from outside the driver
+static CONFIG: AtomicU8 = AtomicU8::new(0);
+
+const BUFFER_SIZE: usize = 64;
+
+/// Controller responses and free input buffer
+const TIMEOUT: Duration = Duration::from_millis(10);
+/// Device responses to commands
+const TIMEOUT_DEVICE: Duration = Duration::from_millis(100);
+/// Device self-test (BAT) after reset
+const TIMEOUT_RESET: Duration = Duration::from_millis(1000);
+/// Resends (0xFE) of one byte before a device command is given up
+const RESEND_ATTEMPTS: usize = 3;
+
+/// Bytes received by [`port1_interrupt_handler`]
+static PORT1_BUFFER: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
+/// Bytes received by [`port2_interrupt_handler`]
+static PORT2_BUFFER: RingBuffer<BUFFER_SIZE> = RingBuffer::new();
 
 /// I8042 PS/2 Controller
-#[derive(Default, Debug)]
-pub struct I8042 {
+///
+/// The registers are reached through `P`, the hardware [`Ports`] unless a host test drives the
+/// state machine with a mock controller.
+#[derive(Debug)]
+pub struct I8042<P: PortIo = Ports> {
+    io: P,
     port1: Option<DeviceType>,
     port2: Option<DeviceType>,
     is_exists_port2: bool,
     config: dto::ControllerConfigurationByte,
+    /// As the firmware left it, restored by [`Driver::remove`]
+    firmware_config: Option<dto::ControllerConfigurationByte>,
+    /// In cells, so the keyboard and mouse tasks can share the driver
+    mouse_packet: Cell<[u8; 4]>,
+    mouse_packet_len: Cell<usize>,
+    /// 3, or 4 with a wheel enabled by [`I8042::enable_wheel`]
+    mouse_packet_size: usize,
+    /// Scancodes the keyboard path delivers (after controller translation)
+    scancode_set: Option<ScancodeSet>,
 }
 
-#[derive(Debug)]
+/// Keyboard scancode set
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// IBM PC XT
+    Set1 = 1,
+    /// IBM PC AT, the only set every keyboard must support
+    Set2 = 2,
+    /// IBM 3270 PC
+    Set3 = 3,
+}
+
+/// Movement reported by a PS/2 mouse packet
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MouseEvent {
+    pub dx: i16,
+    /// Positive is up
+    pub dy: i16,
+    /// Wheel clicks, positive is up, always 0 without [`I8042::enable_wheel`]
+    pub dz: i8,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// Device by its reply to Identify (0xF2)
+///
+/// https://wiki.osdev.org/PS/2_Keyboard#Commands
+#[derive(Debug, PartialEq, Eq)]
 pub enum DeviceType {
-    /// Standard PS/2 mouse
+    /// Standard PS/2 mouse (0x00)
     StandardMouse,
-    /// MF2 keyboard
+    /// Mouse with scroll wheel (0x03)
+    ScrollWheelMouse,
+    /// 5-button mouse (0x04)
+    FiveButtonMouse,
+    /// MF2 keyboard (0xAB 0x83, 0xAB 0xC1 with translation)
     StandardKeyboard,
+    /// IBM ThinkPads, Spacesaver and many other short keyboards (0xAB 0x84)
+    ShortKeyboard,
+    /// NCD N-97 or 122-key host connect keyboard (0xAB 0x85)
+    NcdKeyboard,
+    /// 122-key keyboard (0xAB 0x86)
+    Keyboard122,
+    /// Neither mouse nor keyboard, the raw ID bytes
+    Unknown(u8, Option<u8>),
+}
+
+impl<P: PortIo + Default> Default for I8042<P> {
+    fn default() -> Self {
+        Self::with_io(P::default())
+    }
 }
 
 impl DeviceType {
+    fn from_id(id: u8, id2: Option<u8>) -> Self {
+        match (id, id2) {
+            (0x00, None) => Self::StandardMouse,
+            (0x03, None) => Self::ScrollWheelMouse,
+            (0x04, None) => Self::FiveButtonMouse,
+            (0xAB, Some(0x83 | 0xC1)) => Self::StandardKeyboard,
+            (0xAB, Some(0x84)) => Self::ShortKeyboard,
+            (0xAB, Some(0x85)) => Self::NcdKeyboard,
+            (0xAB, Some(0x86)) => Self::Keyboard122,
+            (id, id2) => Self::Unknown(id, id2),
+        }
+    }
+
+    pub fn is_keyboard(&self) -> bool {
+        matches!(
+            self,
+            Self::StandardKeyboard | Self::ShortKeyboard | Self::NcdKeyboard | Self::Keyboard122
+        )
+    }
+
+    pub fn is_mouse(&self) -> bool {
+        matches!(
+            self,
+            Self::StandardMouse | Self::ScrollWheelMouse | Self::FiveButtonMouse
+        )
+    }
+
     pub fn log(&self) {
-        match self {
-            Self::StandardMouse => {
-                log::info!("{}: Found standard PS/2 mouse", I8042::DRIVER_NAME)
-            }
-            Self::StandardKeyboard => {
-                log::info!("{}: Found standard PS/2 keyboard", I8042::DRIVER_NAME)
+        let name = match self {
+            Self::StandardMouse => "standard PS/2 mouse",
+            Self::ScrollWheelMouse => "PS/2 mouse with scroll wheel",
+            Self::FiveButtonMouse => "5-button PS/2 mouse",
+            Self::StandardKeyboard => "standard PS/2 keyboard",
+            Self::ShortKeyboard => "short PS/2 keyboard",
+            Self::NcdKeyboard => "NCD N-97 or 122-key host connect keyboard",
+            Self::Keyboard122 => "122-key PS/2 keyboard",
+            Self::Unknown(id, id2) => {
+                log::warn!(
+                    "{}: Found unknown device {:#04X}, {:02X?}",
+                    I8042::DRIVER_NAME,
+                    id,
+                    id2
+                );
+                return;
             }
-        }
+        };
+        log::info!("{}: Found {}", I8042::DRIVER_NAME, name);
     }
 }
 
 impl Driver for I8042 {
     const DRIVER_NAME: &str = "i8042";
 
-    fn probe() -> Result<(), ()> {
+    fn probe() -> Result<(), DriverError> {
         // log::trace!("I8042::probe()");
 
-        let fadt = fadt_raw().expect("no init FADT");
-        let fadt = unsafe { fadt.as_ref() };
-
-        // Step 1: Initialize USB Controllers
+        // Step 1: Initialize USB Controllers, left to the xhci driver after exiting boot
+        // services, the firmware still reads its keyboard through them
 
         // Step 2: Determine if the PS/2 Controller Exists
-        let flags = fadt.iapc_boot_arch;
-        if !flags.motherboard_implements_8042() {
-            log::warn!("{}: No controller found", I8042::DRIVER_NAME);
-            Err(())
-        } else {
-            log::info!("{}: Found PS/2 controller", I8042::DRIVER_NAME);
-            Ok(())
+        let flags = fadt().and_then(iapc_boot_arch);
+        match flags {
+            Some(flags) if flags.motherboard_implements_8042() => {
+                log::info!("{}: Found PS/2 controller", I8042::DRIVER_NAME);
+                Ok(())
+            }
+            // many firmwares clear the flag although there is a controller
+            Some(_) => {
+                log::info!(
+                    "{}: IAPC_BOOT_ARCH reports no controller, probing anyway",
+                    I8042::DRIVER_NAME
+                );
+                probe_controller(&mut Ports)
+            }
+            None => {
+                log::info!(
+                    "{}: IAPC_BOOT_ARCH unavailable, probing the controller",
+                    I8042::DRIVER_NAME
+                );
+                probe_controller(&mut Ports)
+            }
         }
     }
 
-    fn init(&mut self) {
+    fn init(&mut self) -> Result<(), DriverError> {
         // log::trace!("I8042::init()");
 
+        // the driver tracks the controller state and buffers the output
+        fox_io::reserve(Space::Port, PORT_DATA_ADDRESS.into(), 1, Self::DRIVER_NAME);
+        fox_io::reserve(Space::Port, PORT_CMD_ADDRESS.into(), 1, Self::DRIVER_NAME);
+        check_acpi_resources();
+
+        // a failed init is not removed, release the ports for the firmware and other drivers
+        self.init_controller()
+            .inspect_err(|_| fox_io::release(Self::DRIVER_NAME))?;
+
+        if self.keyboard_port().is_some() {
+            match self.detect_scancode_set() {
+                Ok(set) => log::info!("{}: Keyboard uses {:?}", I8042::DRIVER_NAME, set),
+                Err(err) => log::warn!("{}: Scancode set unknown: {}", I8042::DRIVER_NAME, err),
+            }
+        }
+
+        IS_ACTIVE.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("I8042::remove()");
+
+        IS_ACTIVE.store(false, Ordering::Release);
+        if let Some(config) = self.firmware_config.take() {
+            let result = without_interrupts(|| self.restore_firmware_state(config));
+            match result {
+                Ok(()) => log::info!("{}: Firmware state restored", Self::DRIVER_NAME),
+                Err(err) => log::warn!(
+                    "{}: Firmware state not restored: {}",
+                    Self::DRIVER_NAME,
+                    err
+                ),
+            }
+        }
+        fox_io::release(Self::DRIVER_NAME);
+    }
+}
+
+impl I8042 {
+    /// Bytes of controller RAM reachable with commands 0x20-0x3F and 0x60-0x7F
+    pub const RAM_SIZE: usize = 0x20;
+
+    /// Pulse the CPU reset line (controller command 0xFE)
+    ///
+    /// Works without [`Driver::init`], only returns if the controller is stuck or ignores it.
+    pub fn cpu_reset() -> Result<(), DriverError> {
+        // log::trace!("I8042::cpu_reset()");
+
+        // the command is lost if the input buffer is still full
+        poll_timeout(TIMEOUT, || {
+            (!port_status_read(&mut Ports).input_buffer_is_full()).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+        interrupts::disable();
+        port_cmd_write(&mut Ports, dto::ControllerCommands::PulseResetLine);
+        Ok(())
+    }
+
+    /// Byte `index` of the controller RAM (commands 0x20-0x3F), byte 0 is the configuration
+    pub fn read_ram(index: u8) -> Result<u8, DriverError> {
+        // log::trace!("I8042::read_ram({:#x})", index);
+
+        if index as usize >= Self::RAM_SIZE {
+            return Err(DriverError::InvalidRequest);
+        }
+        without_controller_interrupts(|io| {
+            port_cmd_write(io, u8::from(dto::ControllerCommands::ReadByte0) + index);
+            port_data_read(io, TIMEOUT)
+        })
+    }
+
+    /// Write byte `index` of the controller RAM (commands 0x61-0x7F)
+    ///
+    /// Byte 0 is the configuration, it belongs to the driver.
+    pub fn write_ram(index: u8, value: u8) -> Result<(), DriverError> {
+        // log::trace!("I8042::write_ram({:#x}, {:#x})", index, value);
+
+        if index == 0 || index as usize >= Self::RAM_SIZE {
+            return Err(DriverError::InvalidRequest);
+        }
+        without_controller_interrupts(|io| {
+            port_cmd_write(io, u8::from(dto::ControllerCommands::WriteByte0) + index);
+            port_data_write(io, value, TIMEOUT)
+            // Response Byte: None
+        })
+    }
+
+    /// Diagnostic dump (command 0xAC), whatever the controller sends until it stops
+    ///
+    /// The format is vendor specific, many controllers send nothing.
+    pub fn diagnostic_dump() -> Result<Vec<u8>, DriverError> {
+        // log::trace!("I8042::diagnostic_dump()");
+
+        const LIMIT: usize = 64;
+        without_controller_interrupts(|io| {
+            port_cmd_write(io, dto::ControllerCommands::DiagnosticDump);
+            let mut bytes = Vec::new();
+            while bytes.len() < LIMIT {
+                match port_data_read(io, TIMEOUT) {
+                    Ok(byte) => bytes.push(byte),
+                    Err(_) => break,
+                }
+            }
+            Ok(bytes)
+        })
+    }
+
+    /// Controller input port (command 0xC0)
+    pub fn input_port() -> Result<InputPort, DriverError> {
+        // log::trace!("I8042::input_port()");
+        without_controller_interrupts(read_input_port)
+    }
+
+    /// Controller output port (command 0xD0)
+    pub fn output_port() -> Result<OutputPort, DriverError> {
+        // log::trace!("I8042::output_port()");
+        without_controller_interrupts(read_output_port)
+    }
+
+    /// Write the controller output port (command 0xD1)
+    ///
+    /// # Safety
+    /// [`OutputPort::system_reset`] low holds the CPU in reset, the A20 gate is as dangerous as
+    /// with [`Self::disable_a20`].
+    pub unsafe fn set_output_port(value: OutputPort) -> Result<(), DriverError> {
+        // log::trace!("I8042::set_output_port({:?})", value);
+        without_controller_interrupts(|io| write_output_port(io, value))
+    }
+
+    /// Make `value` look like it came from a device (commands 0xD2 and 0xD3)
+    ///
+    /// The byte lands in the ring buffer of the port once interrupts are enabled again.
+    pub fn fake_input(is_port2: bool, value: u8) -> Result<(), DriverError> {
+        // log::trace!("I8042::fake_input(is_port2={}, {:#x})", is_port2, value);
+
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        without_interrupts(|| {
+            port_cmd_write(
+                &mut Ports,
+                if is_port2 {
+                    dto::ControllerCommands::WriteOutputBufferPort2
+                } else {
+                    dto::ControllerCommands::WriteOutputBufferPort1
+                },
+            );
+            port_data_write(&mut Ports, value, TIMEOUT)
+            // Response Byte: the value, from the port
+        })
+    }
+
+    /// A20 gate, open if fast A20 (port 0x92) or the controller output port (command 0xD0) has it
+    pub fn is_a20_enabled() -> Result<bool, DriverError> {
+        // log::trace!("I8042::is_a20_enabled()");
+
+        let port = PORT_SYSTEM_CONTROL_A;
+        // SAFETY: only reads
+        if unsafe { port.read() }.get_bit(SYSTEM_CONTROL_A_A20) {
+            return Ok(true);
+        }
+        let output = without_controller_interrupts(read_output_port)?;
+        Ok(output.a20_gate())
+    }
+
+    /// Open the A20 gate through the output port, or fast A20 (port 0x92) if that fails
+    pub fn enable_a20() -> Result<(), DriverError> {
+        // log::trace!("I8042::enable_a20()");
+        set_a20(true)
+    }
+
+    /// Close the A20 gate, the way [`Self::enable_a20`] opens it
+    ///
+    /// # Safety
+    /// Bit 20 of every physical address is forced to 0 where the CPU still honors A20M#: the
+    /// odd megabytes alias the even ones below them.
+    pub unsafe fn disable_a20() -> Result<(), DriverError> {
+        // log::trace!("I8042::disable_a20()");
+        set_a20(false)
+    }
+
+    /// Record every port access from now on, or stop, see [`trace`]
+    pub fn set_trace(is_enabled: bool) {
+        // log::trace!("I8042::set_trace({})", is_enabled);
+
+        trace::set_enabled(is_enabled);
+    }
+
+    pub fn is_tracing() -> bool {
+        trace::is_enabled()
+    }
+
+    /// Forget the recorded accesses
+    pub fn clear_trace() {
+        // log::trace!("I8042::clear_trace()");
+
+        trace::with_trace(Trace::clear);
+    }
+
+    /// The recorded accesses, one per line
+    pub fn trace_dump() -> String {
+        // log::trace!("I8042::trace_dump()");
+
+        trace::with_trace(|trace| trace.to_string())
+    }
+
+    /// Switch from polling to IRQ1/IRQ12
+    ///
+    /// Requires [`crate::fox_interrupts::init`].
+    pub fn enable_interrupts(&mut self) -> Result<(), DriverError> {
+        // log::trace!("I8042::enable_interrupts()");
+
+        if self.port1.is_some() {
+            set_irq_handler(IRQ_PORT1.load(Ordering::Relaxed), port1_interrupt_handler);
+        }
+        if self.port2.is_some() {
+            set_irq_handler(IRQ_PORT2.load(Ordering::Relaxed), port2_interrupt_handler);
+        }
+
+        // the config byte must not be read back here: the handler would steal the response
+        without_interrupts(|| {
+            self.config.set_is_enable_interrupt1(self.port1.is_some());
+            self.config.set_is_enable_interrupt2(self.port2.is_some());
+            set_controller_configuration_byte(&mut self.io, self.config)
+        })?;
+
+        log::info!("{}: Interrupts enabled", I8042::DRIVER_NAME);
+        Ok(())
+    }
+
+    /// Switch back to polling
+    pub fn disable_interrupts(&mut self) -> Result<(), DriverError> {
+        // log::trace!("I8042::disable_interrupts()");
+
+        without_interrupts(|| {
+            self.config.set_is_enable_interrupt1(false);
+            self.config.set_is_enable_interrupt2(false);
+            set_controller_configuration_byte(&mut self.io, self.config)
+        })?;
+
+        clear_irq_handler(IRQ_PORT1.load(Ordering::Relaxed));
+        clear_irq_handler(IRQ_PORT2.load(Ordering::Relaxed));
+        Ok(())
+    }
+}
+
+impl<P: PortIo> I8042<P> {
+    /// Driver state for the controller behind `io`, [`Self::init_controller`] comes next
+    pub fn with_io(io: P) -> Self {
+        Self {
+            io,
+            port1: None,
+            port2: None,
+            is_exists_port2: false,
+            config: dto::ControllerConfigurationByte::default(),
+            firmware_config: None,
+            mouse_packet: Cell::new([0; 4]),
+            mouse_packet_len: Cell::new(0),
+            mouse_packet_size: 3,
+            scancode_set: None,
+        }
+    }
+
+    /// The controller part of [`Driver::init`]: steps 3 to 10 of the OSDev sequence, then the
+    /// device types
+    fn init_controller(&mut self) -> Result<(), DriverError> {
+        // log::trace!("I8042::init_controller()");
+
+        let io = &mut self.io;
+
+        // disabling the ports below changes the clock bits
+        flush(io);
+        self.firmware_config = Some(get_controller_configuration_byte(io)?);
+
         // Step 3: Disable Devices
         // log::trace!("step 3");
-        disable_port1();
-        disable_port2();
+        disable_port1(io);
+        disable_port2(io);
 
         // Step 4: Flush The Output Buffer
         // log::trace!("step 4");
-        while port_data_try_read().is_some() {}
+        while port_data_try_read(io).is_some() && !fox_watchdog::is_expired() {}
 
         // Step 5: Set the Controller Configuration Byte
         // log::trace!("step 5");
-        self.config = get_controller_configuration_byte();
+        self.config = get_controller_configuration_byte(io)?;
         // log::debug!("{:?}", self.config);
-        assert!(self.config.system_flag());
+        // set by the firmware once POST passed, nothing here depends on it
+        if !self.config.system_flag() {
+            log::warn!("{}: System flag clear", I8042::DRIVER_NAME);
+        }
         self.config.set_is_enable_interrupt1(false);
         self.config.set_is_enable_interrupt2(false);
         self.config.set_is_disabled_clock1(true);
         self.config.set_is_disabled_clock2(true);
         self.config.set_is_enabled_translation1(false);
-        set_controller_configuration_byte(self.config);
+        set_controller_configuration_byte(io, self.config)?;
 
         // Step 6: Perform Controller Self Test
         // log::trace!("step 6");
-        if test_controller().is_err() {
-            log::warn!("{}: Test controller failed", I8042::DRIVER_NAME);
-            return;
+        if let Err(err) = test_controller(io) {
+            log::warn!("{}: Test controller failed: {}", I8042::DRIVER_NAME, err);
+            return Err(err);
         }
         // This can reset the PS/2 controller on some hardware (tested on a 2016 laptop).
-        set_controller_configuration_byte(self.config);
+        set_controller_configuration_byte(io, self.config)?;
 
         // Step 7: Determine If There Are 2 Channels
         // log::trace!("step 7");
         // пробуем включить порт 2
-        enable_port2();
-        let cfg = get_controller_configuration_byte();
+        enable_port2(io);
+        let cfg = get_controller_configuration_byte(io)?;
         if !cfg.is_disabled_clock2() {
             self.is_exists_port2 = true;
             // выключаем обратно
-            disable_port2();
-            set_controller_configuration_byte(self.config);
+            disable_port2(io);
+            set_controller_configuration_byte(io, self.config)?;
         }
 
         // Step 8: Perform Interface Tests
         // log::trace!("step 8");
         // At this stage, check to see how many PS/2 ports are left.
-        test_port1().expect("test failed");
+        test_port1(io)?;
         if self.is_exists_port2 {
-            test_port2().expect("test failed");
+            test_port2(io)?;
         }
 
         // Step 9: Enable Devices
         // log::trace!("step 9");
-        enable_port1();
+        enable_port1(io);
+        self.config.set_is_disabled_clock1(false);
         if self.is_exists_port2 {
-            enable_port2();
+            enable_port2(io);
+            self.config.set_is_disabled_clock2(false);
         }
 
         // Step 10: Reset Devices
         // log::trace!("step 10");
-        reset_dev(false).expect("reset failed");
+        reset_dev(io, false)?;
         if self.is_exists_port2 {
-            reset_dev(true).expect("reset failed");
+            reset_dev(io, true)?;
         }
 
         // Detecting PS/2 Device Types
         // log::trace!("step 11");
-        self.port1 = get_dev_type(false);
+        self.port1 = get_dev_type(io, false)?;
         if let Some(dev) = &self.port1 {
             dev.log();
         }
 
         if self.is_exists_port2 {
-            self.port2 = get_dev_type(true);
+            self.port2 = get_dev_type(io, true)?;
             if let Some(dev) = &self.port2 {
                 dev.log();
             }
         }
+        Ok(())
     }
 
-    fn remove(&mut self) {
-        // log::trace!("I8042::remove()");
+    /// Hand the controller back with the configuration byte read by [`Driver::init`]
+    ///
+    /// The firmware keyboard driver expects translation, its IRQs and a scanning keyboard.
+    fn restore_firmware_state(
+        &mut self,
+        config: dto::ControllerConfigurationByte,
+    ) -> Result<(), DriverError> {
+        // log::trace!("I8042::restore_firmware_state({:?})", config);
+
+        let io = &mut self.io;
+        let ports = [(self.port1.is_some(), false), (self.port2.is_some(), true)];
+        for (is_present, is_port2) in ports {
+            if is_present
+                && let Err(err) = send_device_command_with_retry(
+                    io,
+                    is_port2,
+                    dto::DeviceCommands::DisableScanning.into(),
+                )
+            {
+                log::warn!("{}: Disable scanning failed: {}", I8042::DRIVER_NAME, err);
+            }
+        }
+        disable_port1(io);
+        if self.is_exists_port2 {
+            disable_port2(io);
+        }
+        flush(io);
+
+        // the firmware clocks and translation, its IRQs only once the ACKs are read
+        let mut masked = config;
+        masked.set_is_enable_interrupt1(false);
+        masked.set_is_enable_interrupt2(false);
+        set_controller_configuration_byte(io, masked)?;
+
+        let clocks = [!config.is_disabled_clock1(), !config.is_disabled_clock2()];
+        for ((is_present, is_port2), is_clocked) in ports.into_iter().zip(clocks) {
+            if is_present && is_clocked {
+                send_device_command_with_retry(
+                    io,
+                    is_port2,
+                    dto::DeviceCommands::EnableScanning.into(),
+                )?;
+            }
+        }
+        flush(io);
+        set_controller_configuration_byte(io, config)?;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Keyboard LEDs (command 0xED)
+    pub fn set_leds(
+        &mut self,
+        scroll_lock: bool,
+        num_lock: bool,
+        caps_lock: bool,
+    ) -> Result<(), DriverError> {
+        // log::trace!("I8042::set_leds()");
+
+        let is_port2 = self.keyboard_port().ok_or(DriverError::NoHardware)?;
+        let mut leds = 0u8;
+        leds.set_bit(0, scroll_lock);
+        leds.set_bit(1, num_lock);
+        leds.set_bit(2, caps_lock);
+        self.without_device_interrupts(|io| {
+            send_device_command_with_retry(io, is_port2, dto::DeviceCommands::SetLeds.into())?;
+            send_device_command_with_retry(io, is_port2, leds)
+        })
+    }
 
-        // todo!()
+    /// Keyboard repeat rate and delay (command 0xF3)
+    ///
+    /// `rate`: 0x00 = 30 Hz ... 0x1F = 2 Hz, `delay`: 0 = 250 ms ... 3 = 1000 ms
+    pub fn set_typematic(&mut self, rate: u8, delay: u8) -> Result<(), DriverError> {
+        // log::trace!("I8042::set_typematic(rate={}, delay={})", rate, delay);
+
+        let is_port2 = self.keyboard_port().ok_or(DriverError::NoHardware)?;
+        let mut value = 0u8;
+        value.set_bits(0..5, rate.min(0x1F));
+        value.set_bits(5..7, delay.min(3));
+        self.without_device_interrupts(|io| {
+            send_device_command_with_retry(io, is_port2, dto::DeviceCommands::SetTypematic.into())?;
+            send_device_command_with_retry(io, is_port2, value)
+        })
+    }
+
+    /// Mouse sample rate (command 0xF3)
+    ///
+    /// `rate` in Hz: 10, 20, 40, 60, 80, 100 or 200, the mouse rejects other values.
+    pub fn set_sample_rate(&mut self, rate: u8) -> Result<(), DriverError> {
+        // log::trace!("I8042::set_sample_rate({})", rate);
+
+        let is_port2 = self.mouse_port().ok_or(DriverError::NoHardware)?;
+        self.without_device_interrupts(|io| {
+            send_device_command_with_retry(
+                io,
+                is_port2,
+                dto::DeviceCommands::SET_SAMPLE_RATE.into(),
+            )?;
+            send_device_command_with_retry(io, is_port2, rate)
+        })
+    }
+
+    /// Turn on the wheel of an IntelliMouse, returns whether the mouse has one
+    ///
+    /// Sample rates 200, 100 and 80 in a row make it identify as a wheel mouse and add a fourth
+    /// byte to its packets. The rate is left at 80.
+    pub fn enable_wheel(&mut self) -> Result<bool, DriverError> {
+        // log::trace!("I8042::enable_wheel()");
+
+        let is_port2 = self.mouse_port().ok_or(DriverError::NoHardware)?;
+        for rate in [200, 100, 80] {
+            self.set_sample_rate(rate)?;
+        }
+        let device = self.without_device_interrupts(|io| get_dev_type(io, is_port2))?;
+        let Some(device @ (DeviceType::ScrollWheelMouse | DeviceType::FiveButtonMouse)) = device
+        else {
+            return Ok(false);
+        };
+        device.log();
+        if is_port2 {
+            self.port2 = Some(device);
+        } else {
+            self.port1 = Some(device);
+        }
+        // a packet in flight keeps its old size, the sync bit finds the next one
+        self.mouse_packet_len.set(0);
+        self.mouse_packet_size = 4;
+        Ok(true)
+    }
+
+    /// Scancode set of the bytes returned by [`Self::read_key`]
+    pub fn scancode_set(&self) -> Option<ScancodeSet> {
+        self.scancode_set
+    }
+
+    /// Query the active scancode set (command 0xF0, sub-command 0)
+    pub fn detect_scancode_set(&mut self) -> Result<ScancodeSet, DriverError> {
+        // log::trace!("I8042::detect_scancode_set()");
+
+        let is_port2 = self.keyboard_port().ok_or(DriverError::NoHardware)?;
+        let set = self.without_device_interrupts(|io| {
+            send_device_command_with_retry(io, is_port2, dto::DeviceCommands::ScancodeSet.into())?;
+            send_device_command_with_retry(io, is_port2, 0)?;
+            // the response is translated too when translation is enabled
+            match port_data_read(io, TIMEOUT_DEVICE)? {
+                0x01 | 0x43 => Ok(ScancodeSet::Set1),
+                0x02 | 0x41 => Ok(ScancodeSet::Set2),
+                0x03 | 0x3F => Ok(ScancodeSet::Set3),
+                byte => Err(DriverError::UnexpectedResponse { byte }),
+            }
+        })?;
+
+        // the controller turns set 2 into set 1 on port 1
+        let is_translated = !is_port2 && self.config.is_enabled_translation1();
+        self.scancode_set = Some(if is_translated && set == ScancodeSet::Set2 {
+            ScancodeSet::Set1
+        } else {
+            set
+        });
+        Ok(set)
+    }
+
+    /// Switch the keyboard to `set` (command 0xF0)
+    ///
+    /// If the keyboard refuses set 2, translation (config bit 6) is enabled instead and the
+    /// keyboard path delivers set 1.
+    pub fn select_scancode_set(&mut self, set: ScancodeSet) -> Result<(), DriverError> {
+        // log::trace!("I8042::select_scancode_set({:?})", set);
+
+        let is_port2 = self.keyboard_port().ok_or(DriverError::NoHardware)?;
+        let result = self
+            .without_device_interrupts(|io| {
+                send_device_command_with_retry(
+                    io,
+                    is_port2,
+                    dto::DeviceCommands::ScancodeSet.into(),
+                )?;
+                send_device_command_with_retry(io, is_port2, set as u8)
+            })
+            .and_then(|()| self.detect_scancode_set())
+            .and_then(|active| {
+                if active == set {
+                    Ok(())
+                } else {
+                    Err(DriverError::UnexpectedResponse { byte: active as u8 })
+                }
+            });
+
+        match result {
+            Err(err) if set == ScancodeSet::Set2 && !is_port2 => {
+                log::warn!(
+                    "{}: Keyboard refused set 2 ({}), enabling translation",
+                    I8042::DRIVER_NAME,
+                    err
+                );
+                self.config.set_is_enabled_translation1(true);
+                let config = self.config;
+                without_interrupts(|| set_controller_configuration_byte(&mut self.io, config))?;
+                self.scancode_set = Some(ScancodeSet::Set1);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Whether the keyboard is on the second port, `None` without a keyboard
+    pub fn is_keyboard_on_port2(&self) -> Option<bool> {
+        if self.port1.as_ref().is_some_and(DeviceType::is_keyboard) {
+            Some(false)
+        } else if self.port2.as_ref().is_some_and(DeviceType::is_keyboard) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Next scancode byte from the keyboard
+    pub fn read_key(&mut self) -> Option<u8> {
+        self.buffer(DeviceType::is_keyboard)?.pop()
+    }
+
+    /// Next complete packet from the mouse
+    pub fn poll_mouse(&self) -> Option<MouseEvent> {
+        let buffer = self.buffer(DeviceType::is_mouse)?;
+        let mut packet = self.mouse_packet.get();
+        let mut len = self.mouse_packet_len.get();
+        let mut event = None;
+        while let Some(value) = buffer.pop() {
+            // bit 3 of the first byte is always set, skip bytes until in sync
+            if len == 0 && !value.get_bit(3) {
+                continue;
+            }
+            packet[len] = value;
+            len += 1;
+            if len == self.mouse_packet_size {
+                len = 0;
+                event = Some(MouseEvent::from_packet(&packet[..self.mouse_packet_size]));
+                break;
+            }
+        }
+        self.mouse_packet.set(packet);
+        self.mouse_packet_len.set(len);
+        event
+    }
+
+    /// Next scancode byte from the keyboard, the task sleeps until the keyboard IRQ
+    ///
+    /// Needs [`I8042::enable_interrupts`], fails at once without a keyboard.
+    pub async fn next_key(&self) -> Result<u8, DriverError> {
+        let (buffer, irq) = self
+            .device(DeviceType::is_keyboard)
+            .ok_or(DriverError::NoHardware)?;
+        loop {
+            // before the check, so an IRQ right after it is not missed
+            let irq_fired = wait_irq(irq);
+            if let Some(scancode) = buffer.pop() {
+                return Ok(scancode);
+            }
+            irq_fired.await;
+        }
+    }
+
+    /// Next complete packet from the mouse, the task sleeps until the mouse IRQ
+    ///
+    /// Needs [`I8042::enable_interrupts`], fails at once without a mouse.
+    pub async fn next_mouse(&self) -> Result<MouseEvent, DriverError> {
+        let (_, irq) = self
+            .device(DeviceType::is_mouse)
+            .ok_or(DriverError::NoHardware)?;
+        loop {
+            let irq_fired = wait_irq(irq);
+            if let Some(event) = self.poll_mouse() {
+                return Ok(event);
+            }
+            irq_fired.await;
+        }
+    }
+
+    /// `Some(is_port2)` of the keyboard
+    fn keyboard_port(&self) -> Option<bool> {
+        self.port(DeviceType::is_keyboard)
+    }
+
+    /// `Some(is_port2)` of the mouse
+    fn mouse_port(&self) -> Option<bool> {
+        self.port(DeviceType::is_mouse)
+    }
+
+    fn port(&self, f: fn(&DeviceType) -> bool) -> Option<bool> {
+        if self.port1.as_ref().is_some_and(f) {
+            Some(false)
+        } else if self.port2.as_ref().is_some_and(f) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` with IRQ1/IRQ12 off, so device responses can be polled instead of landing in the buffers
+    fn without_device_interrupts<T>(
+        &mut self,
+        f: impl FnOnce(&mut P) -> Result<T, DriverError>,
+    ) -> Result<T, DriverError> {
+        with_config_polling(&mut self.io, self.config, f)
+    }
+
+    /// Buffer and IRQ of the port with the device
+    fn device(&self, f: fn(&DeviceType) -> bool) -> Option<(&'static RingBuffer<BUFFER_SIZE>, u8)> {
+        let irq = match self.port(f)? {
+            false => IRQ_PORT1.load(Ordering::Relaxed),
+            true => IRQ_PORT2.load(Ordering::Relaxed),
+        };
+        Some((self.buffer(f)?, irq))
+    }
+
+    fn buffer(&self, f: fn(&DeviceType) -> bool) -> Option<&'static RingBuffer<BUFFER_SIZE>> {
+        if self.port1.as_ref().is_some_and(f) {
+            Some(&PORT1_BUFFER)
+        } else if self.port2.as_ref().is_some_and(f) {
+            Some(&PORT2_BUFFER)
+        } else {
+            None
+        }
+    }
+}
+
+impl MouseEvent {
+    /// 3 bytes, or 4 with the wheel
+    fn from_packet(packet: &[u8]) -> Self {
+        let flags = packet[0];
+        // X/Y overflow
+        if flags.get_bit(6) || flags.get_bit(7) {
+            return Self::default();
+        }
+        // 9-bit two's complement with the sign in the first byte
+        let dx = packet[1] as i16 - if flags.get_bit(4) { 0x100 } else { 0 };
+        let dy = packet[2] as i16 - if flags.get_bit(5) { 0x100 } else { 0 };
+        // 4-bit two's complement, positive is down, the 5-button mouse has buttons 4 and 5 above
+        let dz = packet.get(3).map_or(0, |&z| -((z << 4) as i8 >> 4));
+        Self {
+            dx,
+            dy,
+            dz,
+            left: flags.get_bit(0),
+            right: flags.get_bit(1),
+            middle: flags.get_bit(2),
+        }
     }
 }
 
-fn disable_port1() {
-    port_cmd_write(dto::ControllerCommands::DisablePort1);
+/// Take the IRQs from the \_CRS of the keyboard (PNP03xx) and mouse (PNP0Fxx) devices, the
+/// ports are fixed and only checked
+fn check_acpi_resources() {
+    let Some(namespace) = fox_aml::namespace() else {
+        return;
+    };
+
+    for (prefix, irq, name) in [
+        ("PNP03", &IRQ_PORT1, "keyboard"),
+        ("PNP0F", &IRQ_PORT2, "mouse"),
+    ] {
+        let Some(path) = namespace.find_devices(prefix).next() else {
+            log::debug!("{}: No ACPI {} device", I8042::DRIVER_NAME, name);
+            continue;
+        };
+        for resource in namespace.current_resources(path).unwrap_or_default() {
+            match resource {
+                Resource::Io { base, .. }
+                    if base != PORT_DATA_ADDRESS && base != PORT_CMD_ADDRESS =>
+                {
+                    log::warn!(
+                        "{}: ACPI {} uses port {:#x}, not supported",
+                        I8042::DRIVER_NAME,
+                        name,
+                        base
+                    );
+                }
+                Resource::Irq { mask } if mask != 0 => {
+                    irq.store(mask.trailing_zeros() as u8, Ordering::Relaxed);
+                }
+                Resource::Interrupt(gsi) if gsi < 16 => irq.store(gsi as u8, Ordering::Relaxed),
+                _ => {}
+            }
+        }
+        log::info!(
+            "{}: ACPI {} {}, IRQ {}",
+            I8042::DRIVER_NAME,
+            name,
+            PathDisplay(path),
+            irq.load(Ordering::Relaxed)
+        );
+    }
+}
+
+extern "x86-interrupt" fn port1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // the output buffer is full when the IRQ fires
+    let value = Ports.read_data();
+    PORT1_BUFFER.push(value);
+    end_of_interrupt(IRQ_PORT1.load(Ordering::Relaxed));
+}
+
+extern "x86-interrupt" fn port2_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    // the output buffer is full when the IRQ fires
+    let value = Ports.read_data();
+    PORT2_BUFFER.push(value);
+    end_of_interrupt(IRQ_PORT2.load(Ordering::Relaxed));
+}
+
+/// Bytes read at most while flushing in [`probe_controller`]
+const FLUSH_LIMIT: usize = 16;
+
+/// The 8042 flag of the FADT, `None` where it is undefined
+///
+/// IAPC_BOOT_ARCH was added in FADT revision 2 (ACPI 2.0), older or shorter tables have
+/// something else or nothing at its offset.
+fn iapc_boot_arch(fadt: &Fadt) -> Option<IaPcBootArchFlags> {
+    let revision = fadt.header.revision;
+    let length = fadt.header.length as usize;
+    let end = offset_of!(Fadt, iapc_boot_arch) + size_of::<IaPcBootArchFlags>();
+    if revision < 2 || length < end {
+        log::debug!(
+            "{}: FADT revision {}, {} bytes: IAPC_BOOT_ARCH not trusted",
+            I8042::DRIVER_NAME,
+            revision,
+            length
+        );
+        return None;
+    }
+    Some(fadt.iapc_boot_arch)
+}
+
+/// Presence probe when the FADT can't tell: flush the output buffer, the controller self-test,
+/// then a device has to acknowledge a reset
+fn probe_controller(io: &mut impl PortIo) -> Result<(), DriverError> {
+    // log::trace!("probe_controller");
+
+    // without a controller the status port floats to 0xFF, the output buffer never empties
+    if !flush(io) {
+        log::warn!(
+            "{}: No controller found (stuck output buffer)",
+            I8042::DRIVER_NAME
+        );
+        return Err(DriverError::NoHardware);
+    }
+
+    if let Err(err) = test_controller(io) {
+        log::warn!("{}: No controller found: {}", I8042::DRIVER_NAME, err);
+        return Err(DriverError::NoHardware);
+    }
+
+    // the self-test passes on some emulations without anything behind them,
+    // it may also have disabled the first port
+    enable_port1(io);
+    let is_port2 = match probe_reset(io, false) {
+        Ok(()) => false,
+        Err(err) => {
+            log::debug!("{}: No reset ACK on port 1: {}", I8042::DRIVER_NAME, err);
+            if let Err(err) = probe_reset(io, true) {
+                log::warn!(
+                    "{}: No device acknowledged a reset: {}",
+                    I8042::DRIVER_NAME,
+                    err
+                );
+                return Err(DriverError::NoHardware);
+            }
+            true
+        }
+    };
+    log::info!(
+        "{}: Found PS/2 controller by self-test and reset ACK on port {}",
+        I8042::DRIVER_NAME,
+        if is_port2 { 2 } else { 1 }
+    );
+    Ok(())
+}
+
+/// Reset the device and wait for its ACK and self-test, [`Driver::init`] resets it again
+fn probe_reset(io: &mut impl PortIo, is_port2: bool) -> Result<(), DriverError> {
+    send_device_command_with_retry(io, is_port2, dto::DeviceCommands::Reset.into())?;
+    // 0xAA, the ID of a mouse follows
+    let _ = port_data_read(io, TIMEOUT_RESET);
+    flush(io);
+    Ok(())
+}
+
+/// Read the output buffer empty, `false` if it does not drain
+fn flush(io: &mut impl PortIo) -> bool {
+    (0..FLUSH_LIMIT).any(|_| port_data_try_read(io).is_none())
+}
+
+fn disable_port1(io: &mut impl PortIo) {
+    port_cmd_write(io, dto::ControllerCommands::DisablePort1);
     // Response Byte: None
 }
 
-fn disable_port2() {
-    port_cmd_write(dto::ControllerCommands::DisablePort2);
+fn disable_port2(io: &mut impl PortIo) {
+    port_cmd_write(io, dto::ControllerCommands::DisablePort2);
     // Response Byte: None
 }
 
-fn enable_port1() {
-    port_cmd_write(dto::ControllerCommands::EnablePort1);
+fn enable_port1(io: &mut impl PortIo) {
+    port_cmd_write(io, dto::ControllerCommands::EnablePort1);
     // Response Byte: None
 }
 
-fn enable_port2() {
-    port_cmd_write(dto::ControllerCommands::EnablePort2);
+fn enable_port2(io: &mut impl PortIo) {
+    port_cmd_write(io, dto::ControllerCommands::EnablePort2);
     // Response Byte: None
 }
 
-#[allow(clippy::let_and_return)]
-fn get_controller_configuration_byte() -> dto::ControllerConfigurationByte {
-    port_cmd_write(dto::ControllerCommands::ReadByte0);
-    // TODO spinlock
-    let config = dto::ControllerConfigurationByte(unsafe { port_data_read() });
-    // log::trace!("< {:?}", config);
-    config
+fn get_controller_configuration_byte(
+    io: &mut impl PortIo,
+) -> Result<dto::ControllerConfigurationByte, DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::ReadByte0);
+    let config = dto::ControllerConfigurationByte(port_data_read(io, TIMEOUT)?);
+    Ok(config)
 }
 
-fn set_controller_configuration_byte(config: dto::ControllerConfigurationByte) {
-    port_cmd_write(dto::ControllerCommands::WriteByte0);
-    // log::trace!("> {:?}", config);
-    port_data_write(config.into());
+fn set_controller_configuration_byte(
+    io: &mut impl PortIo,
+    config: dto::ControllerConfigurationByte,
+) -> Result<(), DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::WriteByte0);
+    port_data_write(io, config.into(), TIMEOUT)?;
+    CONFIG.store(config.into(), Ordering::Relaxed);
     // Response Byte: None
+    Ok(())
+}
+
+/// Run `f` with IRQ1/IRQ12 masked in the controller, so the handlers can't take the response
+fn with_config_polling<I: PortIo, T>(
+    io: &mut I,
+    config: dto::ControllerConfigurationByte,
+    f: impl FnOnce(&mut I) -> Result<T, DriverError>,
+) -> Result<T, DriverError> {
+    without_interrupts(|| {
+        let mut polling = config;
+        polling.set_is_enable_interrupt1(false);
+        polling.set_is_enable_interrupt2(false);
+        set_controller_configuration_byte(io, polling)?;
+        let result = f(io);
+        set_controller_configuration_byte(io, config)?;
+        result
+    })
 }
 
-fn test_controller() -> Result<(), ()> {
-    port_cmd_write(dto::ControllerCommands::TestController);
-    match unsafe { port_data_read() } {
+/// [`with_config_polling`] for commands without the driver instance, needs [`Driver::init`]
+fn without_controller_interrupts<T>(
+    f: impl FnOnce(&mut Ports) -> Result<T, DriverError>,
+) -> Result<T, DriverError> {
+    if !IS_ACTIVE.load(Ordering::Acquire) {
+        return Err(DriverError::NoHardware);
+    }
+    let config = dto::ControllerConfigurationByte(CONFIG.load(Ordering::Relaxed));
+    with_config_polling(&mut Ports, config, f)
+}
+
+fn read_input_port(io: &mut impl PortIo) -> Result<InputPort, DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::ReadInputPort);
+    Ok(InputPort(port_data_read(io, TIMEOUT)?))
+}
+
+fn read_output_port(io: &mut impl PortIo) -> Result<OutputPort, DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::ReadOutputPort);
+    Ok(OutputPort(port_data_read(io, TIMEOUT)?))
+}
+
+fn write_output_port(io: &mut impl PortIo, value: OutputPort) -> Result<(), DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::WriteOutputPort);
+    port_data_write(io, value.into(), TIMEOUT)
+    // Response Byte: None
+}
+
+/// Output port first, fast A20 if the controller is not there or the bit does not stick
+fn set_a20(is_enabled: bool) -> Result<(), DriverError> {
+    let result = without_controller_interrupts(|io| {
+        let mut output = read_output_port(io)?;
+        // low holds the CPU in reset
+        output.set_system_reset(true);
+        output.set_a20_gate(is_enabled);
+        write_output_port(io, output)?;
+        Ok(read_output_port(io)?.a20_gate() == is_enabled)
+    });
+    match result {
+        Ok(true) => return Ok(()),
+        Ok(false) => log::debug!("{}: A20 ignored by the output port", I8042::DRIVER_NAME),
+        Err(err) => log::debug!("{}: A20 by the output port: {}", I8042::DRIVER_NAME, err),
+    }
+
+    let mut port = PORT_SYSTEM_CONTROL_A;
+    // SAFETY: the fast reset bit is kept clear
+    let is_set = unsafe {
+        port.modify(|mut value| {
+            value.set_bit(SYSTEM_CONTROL_A_A20, is_enabled);
+            value.set_bit(SYSTEM_CONTROL_A_RESET, false);
+            value
+        });
+        port.read().get_bit(SYSTEM_CONTROL_A_A20) == is_enabled
+    };
+    if !is_set {
+        return Err(DriverError::NoHardware);
+    }
+    log::debug!("{}: A20 set by fast A20", I8042::DRIVER_NAME);
+    Ok(())
+}
+
+fn test_controller(io: &mut impl PortIo) -> Result<(), DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::TestController);
+    match port_data_read(io, TIMEOUT)? {
         0x55 => Ok(()),
-        0xFC => Err(()),
-        _ => Err(()),
+        0xFC => Err(DriverError::SelfTestFailed { code: 0xFC }),
+        byte => Err(DriverError::UnexpectedResponse { byte }),
     }
 }
 
-fn test_port1() -> Result<(), ()> {
-    port_cmd_write(dto::ControllerCommands::TestPort1);
-    test_port()
+fn test_port1(io: &mut impl PortIo) -> Result<(), DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::TestPort1);
+    test_port(io, 1)
 }
 
-fn test_port2() -> Result<(), ()> {
-    port_cmd_write(dto::ControllerCommands::TestPort2);
-    test_port()
+fn test_port2(io: &mut impl PortIo) -> Result<(), DriverError> {
+    port_cmd_write(io, dto::ControllerCommands::TestPort2);
+    test_port(io, 2)
 }
 
-fn test_port() -> Result<(), ()> {
-    match unsafe { port_data_read() } {
+fn test_port(io: &mut impl PortIo, port: u8) -> Result<(), DriverError> {
+    match port_data_read(io, TIMEOUT)? {
         0x00 => Ok(()),
-        0x01 => Err(()), // clock line stuck
-        0x02 => Err(()), // clock line stuck high
-        0x03 => Err(()), // data line stuck low
-        0x04 => Err(()), // data line stuck high
-        _ => Err(()),
+        // 0x01 clock line stuck low
+        // 0x02 clock line stuck high
+        // 0x03 data line stuck low
+        // 0x04 data line stuck high
+        code @ 0x01..=0x04 => Err(DriverError::InterfaceTestFailed { port, code }),
+        byte => Err(DriverError::UnexpectedResponse { byte }),
     }
 }
 
 /// Reset Device
-fn reset_dev(is_port2: bool) -> Result<(), ()> {
-    send_to_device(is_port2, dto::DeviceCommands::Reset);
-    let resp1 = unsafe { port_data_read() };
-    let resp2 = unsafe { port_data_read() };
-    match (resp1, resp2) {
-        (0xFA, 0xAA) => Ok(()),
-        _ => Err(()),
+fn reset_dev(io: &mut impl PortIo, is_port2: bool) -> Result<(), DriverError> {
+    send_device_command_with_retry(io, is_port2, dto::DeviceCommands::Reset.into())?;
+    match port_data_read(io, TIMEOUT_RESET)? {
+        // a mouse sends its ID after the self-test, it must not answer the next command
+        0xAA => {
+            let _ = port_data_read(io, TIMEOUT);
+            Ok(())
+        }
+        code => Err(DriverError::SelfTestFailed { code }),
     }
 }
 
 /// Detecting PS/2 Device Types
-pub fn get_dev_type(is_port2: bool) -> Option<DeviceType> {
+pub fn get_dev_type(
+    io: &mut impl PortIo,
+    is_port2: bool,
+) -> Result<Option<DeviceType>, DriverError> {
     // log::trace!("PortDataPort::get_dev_type(is_port2={})", is_port2);
 
-    send_to_device(is_port2, dto::DeviceCommands::DisableScanning);
-    if unsafe { port_data_read() } != 0xFA {
+    let disable_scanning = dto::DeviceCommands::DisableScanning.into();
+    if send_device_command_with_retry(io, is_port2, disable_scanning).is_err() {
         // что-то с первого раза не работает...
-        send_to_device(is_port2, dto::DeviceCommands::DisableScanning);
-        if unsafe { port_data_read() } != 0xFA {
-            return None;
-        }
-    }
-    send_to_device(is_port2, dto::DeviceCommands::Identify);
-    if unsafe { port_data_read() } != 0xFA {
-        return None;
+        send_device_command_with_retry(io, is_port2, disable_scanning)?;
     }
+    send_device_command_with_retry(io, is_port2, dto::DeviceCommands::Identify.into())?;
 
     // Wait for the device to send up to 2 bytes of reply, with a time-out to determine when it's finished (e.g. in case it only sends 1 byte)
-    let resp1 = unsafe { port_data_read() };
-    let resp2 = port_data_try_read(); // TODO timeout
-    let result = match (resp1, resp2) {
-        (0x00, None) => Some(DeviceType::StandardMouse),
-        (0xAB, Some(0x83)) => Some(DeviceType::StandardKeyboard),
-        v => {
-            log::warn!(
-                "{}: Found unknown device {:#02X}, {:?}",
-                I8042::DRIVER_NAME,
-                v.0,
-                v.1
-            );
-            None
-        }
-    };
+    let resp1 = port_data_read(io, TIMEOUT_DEVICE)?;
+    let resp2 = port_data_read(io, TIMEOUT_DEVICE).ok();
+    let result = Some(DeviceType::from_id(resp1, resp2));
 
-    send_to_device(is_port2, dto::DeviceCommands::EnableScanning);
-    if unsafe { port_data_read() } != 0xFA {
-        // return None;
+    let enable_scanning = dto::DeviceCommands::EnableScanning.into();
+    if let Err(err) = send_device_command_with_retry(io, is_port2, enable_scanning) {
+        log::warn!("{}: Enable scanning failed: {}", I8042::DRIVER_NAME, err);
     }
 
-    result
+    Ok(result)
 }
 
-fn send_to_device(is_port2: bool, value: dto::DeviceCommands) {
+/// Send a command or data byte and wait for ACK (0xFA), repeating it on Resend (0xFE)
+///
+/// Gives up after [`RESEND_ATTEMPTS`] resends, an Error (0xFC) fails at once.
+fn send_device_command_with_retry(
+    io: &mut impl PortIo,
+    is_port2: bool,
+    value: u8,
+) -> Result<(), DriverError> {
+    for _ in 0..=RESEND_ATTEMPTS {
+        send_byte_to_device(io, is_port2, value)?;
+        match dto::DeviceResponse::from(port_data_read(io, TIMEOUT_DEVICE)?) {
+            dto::DeviceResponse::Ack => return Ok(()),
+            dto::DeviceResponse::Resend => {
+                // log::trace!("{}: Resend {:#04X}", I8042::DRIVER_NAME, value);
+            }
+            dto::DeviceResponse::Error => return Err(DriverError::Rejected { byte: value }),
+            dto::DeviceResponse::Other(byte) => {
+                return Err(DriverError::UnexpectedResponse { byte });
+            }
+        }
+    }
+    Err(DriverError::Rejected { byte: value })
+}
+
+fn send_byte_to_device(io: &mut impl PortIo, is_port2: bool, value: u8) -> Result<(), DriverError> {
     if is_port2 {
-        port_cmd_write(dto::ControllerCommands::WriteByteInputPort2);
+        port_cmd_write(io, dto::ControllerCommands::WriteByteInputPort2);
     }
-    // log::trace!("> {:?}", value);
-    port_data_write(value.into());
+    port_data_write(io, value, TIMEOUT)
 }
 
 // Ports
 
-const PORT_CMD: PortGeneric<u8, WriteOnlyAccess> = PortGeneric::new(0x0064);
-const PORT_STATUS: PortGeneric<u8, ReadOnlyAccess> = PortGeneric::new(0x0064);
-const PORT_DATA: PortGeneric<u8, ReadWriteAccess> = PortGeneric::new(0x0060);
+const PORT_DATA_ADDRESS: u16 = 0x0060;
+const PORT_CMD_ADDRESS: u16 = 0x0064;
+const PORT_CMD: Pio<u8, WriteOnlyAccess> = Pio::new(PORT_CMD_ADDRESS);
+const PORT_STATUS: Pio<u8, ReadOnlyAccess> = Pio::new(PORT_CMD_ADDRESS);
+const PORT_DATA: Pio<u8, ReadWriteAccess> = Pio::new(PORT_DATA_ADDRESS);
+
+/// System Control Port A of the chipset, fast A20
+///
+/// https://wiki.osdev.org/A20_Line#Fast_A20_Gate
+const PORT_SYSTEM_CONTROL_A: Pio<u8> = Pio::new(0x92);
+const SYSTEM_CONTROL_A_RESET: usize = 0;
+const SYSTEM_CONTROL_A_A20: usize = 1;
+
+/// The controller registers, [`Ports`] on hardware, a mock controller in host tests
+///
+/// The accessors and the `port_*` helpers track their caller: the port trace of
+/// [`crate::fox_port`] shows the driver code doing the access.
+pub trait PortIo {
+    /// Status register (port 0x64)
+    #[track_caller]
+    fn read_status(&mut self) -> u8;
+    /// Command register (port 0x64)
+    #[track_caller]
+    fn write_command(&mut self, value: u8);
+    /// Output buffer (port 0x60)
+    #[track_caller]
+    fn read_data(&mut self) -> u8;
+    /// Input buffer (port 0x60)
+    #[track_caller]
+    fn write_data(&mut self, value: u8);
+}
+
+/// Ports 0x60 and 0x64 of the controller
+#[derive(Copy, Clone, Default, Debug)]
+pub struct Ports;
+
+impl PortIo for Ports {
+    fn read_status(&mut self) -> u8 {
+        let port_status = PORT_STATUS;
+        // SAFETY: trust me
+        let value = unsafe { port_status.read() };
+        trace::record(Access::Status(value));
+        value
+    }
+
+    fn write_command(&mut self, value: u8) {
+        trace::record(Access::Command(value));
+        let mut port_cmd = PORT_CMD;
+        // SAFETY: trust me
+        unsafe { port_cmd.write(value) };
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let port_data = PORT_DATA;
+        // SAFETY: trust me
+        let value = unsafe { port_data.read() };
+        trace::record(Access::ReadData(value));
+        value
+    }
+
+    fn write_data(&mut self, value: u8) {
+        trace::record(Access::WriteData(value));
+        let mut port_data = PORT_DATA;
+        // SAFETY: trust me
+        unsafe { port_data.write(value) };
+    }
+}
 
 // ./cargo-asm asm --target x86_64-unknown-uefi my_uefi_app::drivers::i8042::port_cmd_write | grep port_cmd_write: -A10
 // my_uefi_app::drivers::i8042::port_cmd_write:
@@ -297,69 +1367,54 @@ const PORT_DATA: PortGeneric<u8, ReadWriteAccess> = PortGeneric::new(0x0060);
 //  #NO_APP
 //  ret
 // #[inline(never)]
-fn port_cmd_write(value: dto::ControllerCommands) {
-    let value = value.into();
-    // log::trace!("CMD> {:#02X}", value);
-    let mut port_cmd = PORT_CMD;
-    // SAFETY: trust me
-    unsafe { port_cmd.write(value) };
+#[track_caller]
+fn port_cmd_write(io: &mut impl PortIo, value: impl Into<u8>) {
+    io.write_command(value.into());
 }
 
-#[allow(clippy::let_and_return)]
-fn port_status_read() -> dto::StatusRegister {
-    let mut port_status = PORT_STATUS;
-    // SAFETY: trust me
-    let value = unsafe { port_status.read() };
-    // log::trace!("CMD< {:#02X}", value);
-    dto::StatusRegister(value)
+#[track_caller]
+fn port_status_read(io: &mut impl PortIo) -> dto::StatusRegister {
+    dto::StatusRegister(io.read_status())
 }
 
-unsafe fn port_data_read() -> u8 {
-    // TODO spinlock
-    let mut count = 0;
-    loop {
-        if let Some(value) = port_data_try_read() {
-            return value;
-        }
-        count += 1;
-        if count > 10 {
-            panic!("read_spinlock");
-        }
-    }
+/// Wait up to `timeout` for the output buffer to fill
+///
+/// The status polls are traced here, a closure does not pass the caller on.
+#[track_caller]
+fn port_data_read(io: &mut impl PortIo, timeout: Duration) -> Result<u8, DriverError> {
+    poll_timeout(timeout, || {
+        port_status_read(io).output_buffer_is_full().then_some(())
+    })
+    .ok_or(DriverError::Timeout)?;
+    Ok(io.read_data())
 }
 
-fn port_data_try_read() -> Option<u8> {
+#[track_caller]
+fn port_data_try_read(io: &mut impl PortIo) -> Option<u8> {
     // must be set before attempting to read data from IO port 0x60
-    if port_status_read().output_buffer_is_full() {
-        let mut port_data = PORT_DATA;
-        // SAFETY: trust me
-        let value = unsafe { port_data.read() };
-        // log::trace!("< {:#02X}", value);
-        Some(value)
+    if port_status_read(io).output_buffer_is_full() {
+        Some(io.read_data())
     } else {
         None
     }
 }
 
-fn port_data_write(value: u8) {
-    // TODO spinlock
-    let mut count = 0;
-    loop {
-        if !port_status_read().input_buffer_is_full() {
-            // log::trace!("> {:#02X}", value);
-            let mut port_data = PORT_DATA;
-            // SAFETY: trust me
-            unsafe { port_data.write(value) };
-            break;
-        }
-        count += 1;
-        if count > 10 {
-            panic!("write_spinlock");
-        }
-    }
+/// Wait up to `timeout` for the input buffer to drain
+///
+/// The status polls are traced here, as in [`port_data_read`].
+#[track_caller]
+fn port_data_write(io: &mut impl PortIo, value: u8, timeout: Duration) -> Result<(), DriverError> {
+    poll_timeout(timeout, || {
+        (!port_status_read(io).input_buffer_is_full()).then_some(())
+    })
+    .ok_or(DriverError::Timeout)?;
+    io.write_data(value);
+    Ok(())
 }
 
 mod dto {
+    use crate::bitfields;
+
     #[repr(u8)]
     #[derive(Copy, Clone)]
     pub enum ControllerCommands {
@@ -380,34 +1435,159 @@ mod dto {
         TestController = 0xAA,
         /// Test first PS/2 port
         TestPort1 = 0xAB,
-        // 0xAC Diagnostic dump (read all bytes of internal RAM). Response Byte: Unknown
+        /// Diagnostic dump (read all bytes of internal RAM). Response Byte: Unknown
+        DiagnosticDump = 0xAC,
         /// Disable first PS/2 port
         DisablePort1 = 0xAD,
         /// Enable first PS/2 port
         EnablePort1 = 0xAE,
         // ...
+        /// Read Controller Input Port. Response Byte: Controller Input Port
+        ReadInputPort = 0xC0,
+        // ...
+        /// Read Controller Output Port. Response Byte: Controller Output Port
+        ReadOutputPort = 0xD0,
+        /// Write next byte to Controller Output Port
+        /// Note: Check if output buffer is empty first
+        WriteOutputPort = 0xD1,
+        /// Write next byte to first PS/2 port output buffer (only if 2 PS/2 ports supported)
+        /// (makes it look like the byte written was received from the first PS/2 port)
+        WriteOutputBufferPort1 = 0xD2,
+        /// Write next byte to second PS/2 port output buffer (only if 2 PS/2 ports supported)
+        /// (makes it look like the byte written was received from the second PS/2 port)
+        WriteOutputBufferPort2 = 0xD3,
         /// Write next byte to second PS/2 port input buffer (only if 2 PS/2 ports supported)
         /// (sends next byte to the second PS/2 port)
         WriteByteInputPort2 = 0xD4,
         // ...
+        /// Pulse output line 0 low, the CPU reset line
+        PulseResetLine = 0xFE,
     }
 
     #[repr(u8)]
     #[derive(Copy, Clone, Debug)]
     pub enum DeviceCommands {
+        /// Set LEDs, followed by the LED state byte (keyboard only)
+        SetLeds = 0xED,
+        /// Get/set current scancode set, followed by 0 (get) or the set number (keyboard only)
+        ScancodeSet = 0xF0,
         Identify = 0xF2,
+        /// Set typematic rate and delay, followed by the rate/delay byte (keyboard only)
+        SetTypematic = 0xF3,
         EnableScanning = 0xF4,
         DisableScanning = 0xF5,
         /// Reset command, supported by all PS/2 devices
         Reset = 0xFF,
     }
 
-    /// The Status Register contains various flags that show the state of the PS/2 controller
-    #[derive(Copy, Clone)]
-    pub struct StatusRegister(pub u8);
+    impl DeviceCommands {
+        /// Same byte as [`Self::SetTypematic`], followed by the rate in Hz (mouse only)
+        pub const SET_SAMPLE_RATE: Self = Self::SetTypematic;
+    }
+
+    /// Reply of a device to a command or data byte
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum DeviceResponse {
+        /// 0xFA
+        Ack,
+        /// 0xFE, the last byte has to be sent again
+        Resend,
+        /// 0xFC, the device refuses the byte (also a failed self-test after reset)
+        Error,
+        Other(u8),
+    }
+
+    bitfields! {
+        /// The Status Register contains various flags that show the state of the PS/2 controller
+        pub struct StatusRegister(u8) {
+            /// Output buffer status (0 = empty, 1 = full)
+            /// (must be set before attempting to read data from IO port 0x60)
+            output_buffer_is_full: 0;
+            /// Input buffer status (0 = empty, 1 = full)
+            /// (must be clear before attempting to write data to IO port 0x60 or IO port 0x64)
+            input_buffer_is_full: 1;
+            /// System Flag
+            /// Meant to be cleared on reset and set by firmware (via. PS/2 Controller Configuration Byte) if the system passes self tests (POST)
+            system_flag: 2;
+            /// Command/data (0 = data written to input buffer is data for PS/2 device, 1 = data written to input buffer is data for PS/2 controller command)
+            is_command: 3;
+            // 4 Unknown (chipset specific)
+            // May be "keyboard lock" (more likely unused on modern systems)
+
+            // 5 Unknown (chipset specific)
+            // May be "receive time-out" or "second PS/2 port output buffer full"
+
+            /// Time-out error (0 = no error, 1 = time-out error)
+            is_timeout_error: 6;
+            /// Parity error (0 = no error, 1 = parity error)
+            is_parity_error: 7;
+        }
+    }
 
-    #[derive(Copy, Clone, Default)]
-    pub struct ControllerConfigurationByte(pub u8);
+    bitfields! {
+        /// Controller input port (command 0xC0), the IBM AT meanings, no standard purpose today
+        pub struct InputPort(u8) {
+            /// Keyboard data line as sampled, on controllers that have it wired
+            data1: 0;
+            /// Mouse data line as sampled, on controllers that have it wired
+            data2: 1;
+            // 2-3 Unknown
+
+            /// Base memory above 256 KiB (1 = 512 KiB) on the IBM AT
+            is_extra_ram: 4;
+            /// Manufacturing jumper (0 = installed)
+            manufacturing_jumper: 5;
+            /// Primary display (0 = color, 1 = monochrome)
+            is_monochrome: 6;
+            /// Keyboard lock switch (0 = locked)
+            is_unlocked: 7;
+        }
+    }
+
+    bitfields! {
+        /// Controller output port (commands 0xD0 and 0xD1)
+        pub struct OutputPort(u8) {
+            /// System reset (output)
+            /// WARNING always set to '1'. You need to pulse the reset line (e.g. using command 0xFE),
+            /// and setting this bit to '0' can lock the computer up ("reset forever").
+            system_reset, set_system_reset: 0;
+            /// A20 gate (output)
+            a20_gate, set_a20_gate: 1;
+            /// Second PS/2 port clock (output, only if 2 PS/2 ports supported)
+            clock2: 2;
+            /// Second PS/2 port data (output, only if 2 PS/2 ports supported)
+            data2: 3;
+            /// Output buffer full with byte from first PS/2 port (connected to IRQ1)
+            output_buffer_full1: 4;
+            /// Output buffer full with byte from second PS/2 port (connected to IRQ12, only if 2 PS/2
+            /// ports supported)
+            output_buffer_full2: 5;
+            /// First PS/2 port clock (output)
+            clock1: 6;
+            /// First PS/2 port data (output)
+            data1: 7;
+        }
+    }
+
+    bitfields! {
+        pub struct ControllerConfigurationByte(u8) {
+            /// First PS/2 port interrupt (1 = enabled, 0 = disabled)
+            is_enable_interrupt1, set_is_enable_interrupt1: 0;
+            /// Second PS/2 port interrupt (1 = enabled, 0 = disabled, only if 2 PS/2 ports supported)
+            is_enable_interrupt2, set_is_enable_interrupt2: 1;
+            /// System Flag (1 = system passed POST, 0 = your OS shouldn't be running)
+            system_flag, set_system_flag: 2;
+            // 3 Should be zero
+
+            /// First PS/2 port clock (1 = disabled, 0 = enabled)
+            is_disabled_clock1, set_is_disabled_clock1: 4;
+            /// Second PS/2 port clock (1 = disabled, 0 = enabled, only if 2 PS/2 ports supported)
+            is_disabled_clock2, set_is_disabled_clock2: 5;
+            /// First PS/2 port translation (1 = enabled, 0 = disabled)
+            is_enabled_translation1, set_is_enabled_translation1: 6;
+            // 7 Must be zero
+        }
+    }
 }
 
 impl From<dto::ControllerCommands> for u8 {
@@ -416,160 +1596,286 @@ impl From<dto::ControllerCommands> for u8 {
     }
 }
 
+impl From<u8> for dto::DeviceResponse {
+    fn from(value: u8) -> Self {
+        match value {
+            0xFA => Self::Ack,
+            0xFE => Self::Resend,
+            0xFC => Self::Error,
+            byte => Self::Other(byte),
+        }
+    }
+}
+
 impl From<dto::DeviceCommands> for u8 {
     fn from(value: dto::DeviceCommands) -> Self {
         value as _
     }
 }
 
-impl dto::StatusRegister {
-    /// Output buffer status (0 = empty, 1 = full)
-    /// (must be set before attempting to read data from IO port 0x60)
-    pub fn output_buffer_is_full(&self) -> bool {
-        self.0.get_bit(0)
-    }
+#[cfg(test)]
+mod mock;
 
-    /// Input buffer status (0 = empty, 1 = full)
-    /// (must be clear before attempting to write data to IO port 0x60 or IO port 0x64)
-    pub fn input_buffer_is_full(&self) -> bool {
-        self.0.get_bit(1)
-    }
+#[cfg(test)]
+mod tests {
+    use super::mock::{MockController, MockDevice, Recorder, Replay};
+    use super::*;
+    use crate::fox_time;
 
-    /// System Flag
-    /// Meant to be cleared on reset and set by firmware (via. PS/2 Controller Configuration Byte) if the system passes self tests (POST)
-    pub fn system_flag(&self) -> bool {
-        self.0.get_bit(2)
+    fn driver<P: PortIo>(io: P) -> I8042<P> {
+        fox_time::calibrate_tsc_host();
+        I8042::with_io(io)
     }
 
-    /// Command/data (0 = data written to input buffer is data for PS/2 device, 1 = data written to input buffer is data for PS/2 controller command)
-    pub fn is_command(&self) -> bool {
-        self.0.get_bit(3)
-    }
-
-    // 4 Unknown (chipset specific)
-    // May be "keyboard lock" (more likely unused on modern systems)
-
-    // 5 Unknown (chipset specific)
-    // May be "receive time-out" or "second PS/2 port output buffer full"
-
-    /// Time-out error (0 = no error, 1 = time-out error)
-    pub fn is_timeout_error(&self) -> bool {
-        self.0.get_bit(6)
+    #[test]
+    fn init_keyboard_and_mouse() {
+        let controller =
+            MockController::new(Some(MockDevice::keyboard()), Some(MockDevice::mouse()));
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
+
+        assert_eq!(i8042.port1, Some(DeviceType::StandardKeyboard));
+        assert_eq!(i8042.port2, Some(DeviceType::StandardMouse));
+        assert!(i8042.is_exists_port2);
+        assert_eq!(
+            i8042.firmware_config.map(u8::from),
+            Some(MockController::FIRMWARE_CONFIG)
+        );
+        // polling, no translation, the ports enabled by the commands after the config byte
+        let config = i8042.io.config;
+        assert_eq!(config & 0b0100_0011, 0);
+        assert_eq!(config & 0b0011_0000, 0);
+        assert!(config.get_bit(2));
+        // later config writes must not disable the ports again
+        assert_eq!(u8::from(i8042.config), config);
+        assert!(i8042.io.devices.iter().flatten().all(|i| i.is_scanning));
     }
 
-    /// Parity error (0 = no error, 1 = parity error)
-    pub fn is_parity_error(&self) -> bool {
-        self.0.get_bit(7)
+    #[test]
+    fn config_after_init() {
+        // what `enable_interrupts` writes, the cached byte with the IRQ bits changed
+        let controller =
+            MockController::new(Some(MockDevice::keyboard()), Some(MockDevice::mouse()));
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
+        let mut config = i8042.config;
+        config.set_is_enable_interrupt1(true);
+        config.set_is_enable_interrupt2(true);
+        assert_eq!(
+            set_controller_configuration_byte(&mut i8042.io, config),
+            Ok(())
+        );
+        // both clocks still enabled
+        assert_eq!(i8042.io.config & 0b0011_0000, 0);
+        assert_eq!(i8042.io.config & 0b0000_0011, 0b11);
     }
-}
 
-impl fmt::Debug for dto::StatusRegister {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("StatusRegister")
-            .field("output_buffer_is_full", &self.output_buffer_is_full())
-            .field("input_buffer_is_full", &self.input_buffer_is_full())
-            .field("system_flag", &self.system_flag())
-            .field("is_command", &self.is_command())
-            .field("is_timeout_error", &self.is_timeout_error())
-            .field("is_parity_error", &self.is_parity_error())
-            .finish()
+    #[test]
+    fn init_single_port() {
+        let controller = MockController::new(Some(MockDevice::keyboard()), None);
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
+
+        assert_eq!(i8042.port1, Some(DeviceType::StandardKeyboard));
+        assert_eq!(i8042.port2, None);
+        assert!(!i8042.is_exists_port2);
+        // port 2 is neither tested nor enabled for good
+        assert!(!i8042.io.commands.contains(&0xA9));
+        assert_eq!(i8042.io.commands.iter().filter(|&&i| i == 0xA8).count(), 1);
     }
-}
 
-impl dto::ControllerConfigurationByte {
-    /// First PS/2 port interrupt (1 = enabled, 0 = disabled)
-    pub fn is_enable_interrupt1(&self) -> bool {
-        self.0.get_bit(0)
+    #[test]
+    fn init_controller_self_test_failed() {
+        let mut controller = MockController::new(Some(MockDevice::keyboard()), None);
+        controller.self_test = 0xFC;
+        let mut i8042 = driver(controller);
+        assert_eq!(
+            i8042.init_controller(),
+            Err(DriverError::SelfTestFailed { code: 0xFC })
+        );
     }
 
-    /// Second PS/2 port interrupt (1 = enabled, 0 = disabled, only if 2 PS/2 ports supported)
-    pub fn is_enable_interrupt2(&self) -> bool {
-        self.0.get_bit(1)
+    #[test]
+    fn init_interface_test_failed() {
+        let mut controller = MockController::new(Some(MockDevice::keyboard()), None);
+        controller.port_tests[0] = 0x01;
+        let mut i8042 = driver(controller);
+        assert_eq!(
+            i8042.init_controller(),
+            Err(DriverError::InterfaceTestFailed {
+                port: 1,
+                code: 0x01
+            })
+        );
     }
 
-    /// System Flag (1 = system passed POST, 0 = your OS shouldn't be running)
-    pub fn system_flag(&self) -> bool {
-        self.0.get_bit(2)
+    #[test]
+    fn init_device_self_test_failed() {
+        let mut keyboard = MockDevice::keyboard();
+        keyboard.self_test = 0xFC;
+        let mut i8042 = driver(MockController::new(Some(keyboard), None));
+        assert_eq!(
+            i8042.init_controller(),
+            Err(DriverError::SelfTestFailed { code: 0xFC })
+        );
     }
 
-    // 3 Should be zero
-
-    /// First PS/2 port clock (1 = disabled, 0 = enabled)
-    pub fn is_disabled_clock1(&self) -> bool {
-        self.0.get_bit(4)
+    #[test]
+    fn init_resend() {
+        let mut keyboard = MockDevice::keyboard();
+        keyboard.resends = RESEND_ATTEMPTS;
+        let mut i8042 = driver(MockController::new(Some(keyboard), None));
+        assert_eq!(i8042.init_controller(), Ok(()));
+        assert_eq!(i8042.port1, Some(DeviceType::StandardKeyboard));
+
+        let mut keyboard = MockDevice::keyboard();
+        keyboard.resends = RESEND_ATTEMPTS + 1;
+        let mut i8042 = driver(MockController::new(Some(keyboard), None));
+        assert_eq!(
+            i8042.init_controller(),
+            Err(DriverError::Rejected { byte: 0xFF })
+        );
     }
 
-    /// Second PS/2 port clock (1 = disabled, 0 = enabled, only if 2 PS/2 ports supported)
-    pub fn is_disabled_clock2(&self) -> bool {
-        self.0.get_bit(5)
+    #[test]
+    fn reset_mouse() {
+        // the ID after the self-test must not be taken as the reply to the next command
+        let controller = MockController::new(None, Some(MockDevice::mouse()));
+        let mut i8042 = driver(controller);
+        assert_eq!(reset_dev(&mut i8042.io, true), Ok(()));
+        assert_eq!(port_data_try_read(&mut i8042.io), None);
     }
 
-    /// First PS/2 port translation (1 = enabled, 0 = disabled)
-    pub fn is_enabled_translation1(&self) -> bool {
-        self.0.get_bit(6)
+    #[test]
+    fn init_system_flag_clear() {
+        let mut controller = MockController::new(Some(MockDevice::keyboard()), None);
+        controller.config = MockController::FIRMWARE_CONFIG & !(1 << 2);
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
     }
 
-    // 7 Must be zero
-
-    /// First PS/2 port interrupt (1 = enabled, 0 = disabled)
-    pub fn set_is_enable_interrupt1(&mut self, value: bool) {
-        self.0.set_bit(0, value);
+    #[test]
+    fn init_no_device() {
+        let mut i8042 = driver(MockController::new(None, None));
+        assert_eq!(i8042.init_controller(), Err(DriverError::Timeout));
     }
 
-    /// Second PS/2 port interrupt (1 = enabled, 0 = disabled, only if 2 PS/2 ports supported)
-    pub fn set_is_enable_interrupt2(&mut self, value: bool) {
-        self.0.set_bit(1, value);
+    #[test]
+    fn scancode_set() {
+        let controller = MockController::new(Some(MockDevice::keyboard()), None);
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
+
+        assert_eq!(i8042.detect_scancode_set(), Ok(ScancodeSet::Set2));
+        assert_eq!(i8042.select_scancode_set(ScancodeSet::Set1), Ok(()));
+        assert_eq!(i8042.scancode_set(), Some(ScancodeSet::Set1));
+        assert_eq!(i8042.io.devices[0].as_ref().unwrap().scancode_set, 1);
+        // the IRQs are masked only around the commands
+        assert_eq!(u8::from(i8042.config), i8042.io.config);
     }
 
-    /// System Flag (1 = system passed POST, 0 = your OS shouldn't be running)
-    pub fn set_system_flag(&mut self, value: bool) {
-        self.0.set_bit(2, value);
+    #[test]
+    fn scancode_set_translation() {
+        let mut keyboard = MockDevice::keyboard();
+        keyboard.scancode_sets = &[1, 3];
+        let mut i8042 = driver(MockController::new(Some(keyboard), None));
+        assert_eq!(i8042.init_controller(), Ok(()));
+
+        // refused set 2, the controller translates instead
+        assert_eq!(i8042.select_scancode_set(ScancodeSet::Set2), Ok(()));
+        assert_eq!(i8042.scancode_set(), Some(ScancodeSet::Set1));
+        assert!(i8042.io.config.get_bit(6));
+        // the translated reply still names set 2, the keyboard path delivers set 1
+        assert_eq!(i8042.detect_scancode_set(), Ok(ScancodeSet::Set2));
+        assert_eq!(i8042.scancode_set(), Some(ScancodeSet::Set1));
     }
 
-    /// First PS/2 port clock (1 = disabled, 0 = enabled)
-    pub fn set_is_disabled_clock1(&mut self, value: bool) {
-        self.0.set_bit(4, value);
+    #[test]
+    fn restore_firmware_state() {
+        // the firmware left the mouse clock off
+        const FIRMWARE_CONFIG: u8 = MockController::FIRMWARE_CONFIG | 1 << 5;
+
+        let mut controller =
+            MockController::new(Some(MockDevice::keyboard()), Some(MockDevice::mouse()));
+        controller.config = FIRMWARE_CONFIG;
+        let mut i8042 = driver(controller);
+        assert_eq!(i8042.init_controller(), Ok(()));
+        let config = i8042.firmware_config.take().unwrap();
+
+        assert_eq!(i8042.restore_firmware_state(config), Ok(()));
+        assert_eq!(i8042.io.config, FIRMWARE_CONFIG);
+        let [keyboard, mouse] = &i8042.io.devices;
+        assert!(keyboard.as_ref().unwrap().is_scanning);
+        assert!(!mouse.as_ref().unwrap().is_scanning);
     }
 
-    /// Second PS/2 port clock (1 = disabled, 0 = enabled, only if 2 PS/2 ports supported)
-    pub fn set_is_disabled_clock2(&mut self, value: bool) {
-        self.0.set_bit(5, value);
+    #[test]
+    fn trace_replay() {
+        let controller =
+            MockController::new(Some(MockDevice::keyboard()), Some(MockDevice::mouse()));
+        let mut recorded = driver(Recorder::new(controller));
+        assert_eq!(recorded.init_controller(), Ok(()));
+        let dump = recorded.io.trace.to_string();
+        assert!(dump.contains(" 0 dropped\n"));
+        assert!(dump.contains("cmd> 0xAA\n"));
+
+        let mut replayed = driver(Replay::new(&dump));
+        assert_eq!(replayed.init_controller(), Ok(()));
+        assert!(replayed.io.is_done());
+        assert_eq!(replayed.port1, recorded.port1);
+        assert_eq!(replayed.port2, recorded.port2);
+        assert_eq!(u8::from(replayed.config), u8::from(recorded.config));
     }
 
-    /// First PS/2 port translation (1 = enabled, 0 = disabled)
-    pub fn set_is_enabled_translation1(&mut self, value: bool) {
-        self.0.set_bit(6, value);
+    /// A stale byte in the output buffer, then a failed self-test
+    #[test]
+    fn trace_replay_self_test_failure() {
+        const DUMP: &str = "\
+            # 7 entries, 0 dropped
+               0.812001 st< 0x1D
+               0.812003 dat< 0xFA
+               0.812004 st< 0x1C
+               0.812006 cmd> 0xAA
+               0.812010 st< 0x1C x12
+               0.813101 st< 0x1D
+               0.813102 dat< 0xFC
+        ";
+        let mut replay = Replay::new(DUMP);
+        fox_time::calibrate_tsc_host();
+        assert_eq!(probe_controller(&mut replay), Err(DriverError::NoHardware));
+        assert!(replay.is_done());
     }
-}
 
-impl fmt::Debug for dto::ControllerConfigurationByte {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("ControllerConfigurationByte")
-            .field("is_enable_interrupt1", &self.is_enable_interrupt1())
-            .field("is_enable_interrupt2", &self.is_enable_interrupt2())
-            .field("system_flag", &self.system_flag())
-            .field("is_disabled_clock1", &self.is_disabled_clock1())
-            .field("is_disabled_clock2", &self.is_disabled_clock2())
-            .field("is_enabled_translation1", &self.is_enabled_translation1())
-            .finish()
+    #[test]
+    fn device_types() {
+        assert_eq!(
+            DeviceType::from_id(0xAB, Some(0xC1)),
+            DeviceType::StandardKeyboard
+        );
+        assert_eq!(
+            DeviceType::from_id(0x03, None),
+            DeviceType::ScrollWheelMouse
+        );
+        assert_eq!(
+            DeviceType::from_id(0xAB, None),
+            DeviceType::Unknown(0xAB, None)
+        );
     }
-}
 
-impl From<dto::ControllerConfigurationByte> for u8 {
-    fn from(value: dto::ControllerConfigurationByte) -> Self {
-        value.0
+    #[test]
+    fn mouse_packet() {
+        let event = MouseEvent::from_packet(&[0b0011_1001, 0xFF, 0x02]);
+        assert_eq!((event.dx, event.dy), (-1, -254));
+        assert!(event.left && !event.right && !event.middle);
+
+        // overflow, the movement is dropped
+        let event = MouseEvent::from_packet(&[0b0100_1000, 0x10, 0x10]);
+        assert_eq!((event.dx, event.dy), (0, 0));
+
+        // wheel one click down, button 4 of a 5-button mouse held
+        let event = MouseEvent::from_packet(&[0b0000_1000, 0x00, 0x00, 0x11]);
+        assert_eq!(event.dz, -1);
+        let event = MouseEvent::from_packet(&[0b0000_1000, 0x00, 0x00, 0x0F]);
+        assert_eq!(event.dz, 1);
     }
 }
-
-// // An example interrupt based on https://os.phil-opp.com/hardware-interrupts/. The ps2 mouse is configured to fire
-// // interrupts at PIC offset 12.
-// extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: &mut InterruptStackFrame) {
-//     let mut port = PortReadOnly::new(0x60);
-//     let packet = unsafe { port.read() };
-//     MOUSE.lock().process_packet(packet);
-
-//     unsafe {
-//         PICS.lock()
-//             .notify_end_of_interrupt(InterruptIndex::Mouse.into());
-//     }
-// }
@@ -0,0 +1,370 @@
+//! Mock PS/2 controller for host tests
+//!
+//! Models what the driver relies on: one output buffer fed by the controller and both devices,
+//! the configuration byte, the port enable and test commands. Devices answer resets, Identify
+//! and the keyboard commands at once, the input buffer is never full. Translation only covers
+//! the scancode set replies.
+//!
+//! [`Replay`] plays a dump of the port trace back instead, to reproduce what a machine did.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+
+use super::PortIo;
+use super::trace::{Access, Trace};
+use crate::fox_time::uptime;
+
+const ACK: u8 = 0xFA;
+const RESEND: u8 = 0xFE;
+const ERROR: u8 = 0xFC;
+
+/// An 8042 with up to two devices
+#[derive(Debug)]
+pub struct MockController {
+    /// Configuration byte, as the firmware left it until the driver writes it
+    pub config: u8,
+    /// Without a second port 0xA8 leaves its clock disabled
+    pub has_port2: bool,
+    /// Reply to the controller self-test (0xAA), 0x55 passed
+    pub self_test: u8,
+    /// Replies to the interface tests (0xAB, 0xA9), 0x00 passed
+    pub port_tests: [u8; 2],
+    pub devices: [Option<MockDevice>; 2],
+    /// Every controller command in order
+    pub commands: Vec<u8>,
+    output: VecDeque<u8>,
+    /// Command waiting for its data byte
+    pending: Option<u8>,
+    /// The last write went to the command register
+    is_command: bool,
+}
+
+/// A keyboard or mouse behind a port
+#[derive(Debug)]
+pub struct MockDevice {
+    /// Reply to Identify (0xF2), a mouse also sends it after the self-test
+    pub id: &'static [u8],
+    /// Sent after a reset, 0xAA passed
+    pub self_test: u8,
+    /// Bytes answered with Resend (0xFE) before the device takes one
+    pub resends: usize,
+    /// Scancode sets accepted by 0xF0, others are answered with Error (0xFC)
+    pub scancode_sets: &'static [u8],
+    pub scancode_set: u8,
+    pub is_scanning: bool,
+    /// Command waiting for its argument
+    pending: Option<u8>,
+}
+
+impl MockController {
+    /// Configuration byte of a typical firmware: IRQ1, system flag, translation
+    pub const FIRMWARE_CONFIG: u8 = 0x45;
+
+    pub fn new(port1: Option<MockDevice>, port2: Option<MockDevice>) -> Self {
+        Self {
+            config: Self::FIRMWARE_CONFIG,
+            has_port2: port2.is_some(),
+            self_test: 0x55,
+            port_tests: [0x00; 2],
+            devices: [port1, port2],
+            commands: Vec::new(),
+            output: VecDeque::new(),
+            pending: None,
+            is_command: false,
+        }
+    }
+
+    fn send_to_device(&mut self, port: usize, value: u8) {
+        let is_translated = port == 0 && self.config.get_bit(6);
+        let Some(device) = &mut self.devices[port] else {
+            // nothing answers, the driver times out
+            return;
+        };
+        for byte in device.receive(value) {
+            self.output
+                .push_back(if is_translated { translate(byte) } else { byte });
+        }
+    }
+}
+
+impl PortIo for MockController {
+    fn read_status(&mut self) -> u8 {
+        let mut status = 0u8;
+        status.set_bit(0, !self.output.is_empty());
+        status.set_bit(2, self.config.get_bit(2));
+        status.set_bit(3, self.is_command);
+        status
+    }
+
+    fn write_command(&mut self, value: u8) {
+        self.commands.push(value);
+        self.is_command = true;
+        match value {
+            0x20 => self.output.push_back(self.config),
+            // the rest of the controller RAM
+            0x21..=0x3F => self.output.push_back(0),
+            0x60..=0x7F | 0xD1..=0xD4 => self.pending = Some(value),
+            0xA7 if self.has_port2 => {
+                self.config.set_bit(5, true);
+            }
+            0xA8 if self.has_port2 => {
+                self.config.set_bit(5, false);
+            }
+            0xA9 if self.has_port2 => self.output.push_back(self.port_tests[1]),
+            0xAA => self.output.push_back(self.self_test),
+            0xAB => self.output.push_back(self.port_tests[0]),
+            0xAD => {
+                self.config.set_bit(4, true);
+            }
+            0xAE => {
+                self.config.set_bit(4, false);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_data(&mut self) -> u8 {
+        self.output.pop_front().unwrap_or(0)
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.is_command = false;
+        match self.pending.take() {
+            Some(0x60) => self.config = value,
+            Some(0x61..=0x7F | 0xD1) => {}
+            Some(0xD2 | 0xD3) => self.output.push_back(value),
+            Some(0xD4) => self.send_to_device(1, value),
+            _ => self.send_to_device(0, value),
+        }
+    }
+}
+
+impl MockDevice {
+    /// MF2 keyboard in scancode set 2
+    pub fn keyboard() -> Self {
+        Self {
+            id: &[0xAB, 0x83],
+            self_test: 0xAA,
+            resends: 0,
+            scancode_sets: &[1, 2, 3],
+            scancode_set: 2,
+            is_scanning: true,
+            pending: None,
+        }
+    }
+
+    /// Standard PS/2 mouse
+    pub fn mouse() -> Self {
+        Self {
+            id: &[0x00],
+            scancode_sets: &[],
+            ..Self::keyboard()
+        }
+    }
+
+    fn is_mouse(&self) -> bool {
+        self.id.len() == 1
+    }
+
+    /// Replies to a byte from the host
+    fn receive(&mut self, value: u8) -> Vec<u8> {
+        if self.resends > 0 {
+            self.resends -= 1;
+            return Vec::from([RESEND]);
+        }
+        match (self.pending.take(), value) {
+            (Some(0xF0), 0) => Vec::from([ACK, self.scancode_set]),
+            (Some(0xF0), set) if self.scancode_sets.contains(&set) => {
+                self.scancode_set = set;
+                Vec::from([ACK])
+            }
+            (Some(0xF0), _) => Vec::from([ERROR]),
+            // LEDs, typematic or sample rate
+            (Some(_), _) => Vec::from([ACK]),
+            (None, 0xFF) => {
+                self.is_scanning = true;
+                self.scancode_set = 2;
+                let mut reply = Vec::from([ACK, self.self_test]);
+                if self.is_mouse() {
+                    reply.extend_from_slice(self.id);
+                }
+                reply
+            }
+            (None, 0xF2) => [&[ACK], self.id].concat(),
+            (None, 0xF4 | 0xF5) => {
+                self.is_scanning = value == 0xF4;
+                Vec::from([ACK])
+            }
+            (None, 0xED | 0xF0 | 0xF3) => {
+                self.pending = Some(value);
+                Vec::from([ACK])
+            }
+            (None, _) => Vec::from([RESEND]),
+        }
+    }
+}
+
+/// Set 2 to set 1 for the scancode set replies
+fn translate(byte: u8) -> u8 {
+    match byte {
+        0x01 => 0x43,
+        0x02 => 0x41,
+        0x03 => 0x3F,
+        byte => byte,
+    }
+}
+
+/// Records the accesses to `io` the way [`super::Ports`] does with `kbctrace=on`
+pub struct Recorder<P> {
+    pub io: P,
+    pub trace: Box<Trace>,
+}
+
+impl<P> Recorder<P> {
+    pub fn new(io: P) -> Self {
+        Self {
+            io,
+            trace: Box::new(Trace::new()),
+        }
+    }
+}
+
+impl<P: PortIo> PortIo for Recorder<P> {
+    fn read_status(&mut self) -> u8 {
+        let value = self.io.read_status();
+        self.trace.record(uptime(), Access::Status(value));
+        value
+    }
+
+    fn write_command(&mut self, value: u8) {
+        self.trace.record(uptime(), Access::Command(value));
+        self.io.write_command(value);
+    }
+
+    fn read_data(&mut self) -> u8 {
+        let value = self.io.read_data();
+        self.trace.record(uptime(), Access::ReadData(value));
+        value
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.trace.record(uptime(), Access::WriteData(value));
+        self.io.write_data(value);
+    }
+}
+
+/// Plays back a trace dumped by `kbctrace`, the driver must do the same accesses in order
+///
+/// Times are ignored. A status read takes the next status entries, or repeats the last status if
+/// the driver polls more often than it did on the machine. Past the end, the status shows empty
+/// buffers and data reads give 0, the driver times out as if nothing answered.
+#[derive(Debug)]
+pub struct Replay {
+    /// Accesses and their line in the dump
+    accesses: Vec<(usize, Access)>,
+    position: usize,
+    status: u8,
+}
+
+impl Replay {
+    /// Parse `dump`, panics on a malformed line
+    pub fn new(dump: &str) -> Self {
+        let mut accesses = Vec::new();
+        for (number, line) in (1..).zip(dump.lines()) {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line
+                .split_whitespace()
+                .skip_while(|word| word.contains('.'));
+            let access = match (words.next(), words.next().and_then(parse_hex)) {
+                (Some("cmd>"), Some(value)) => Access::Command(value),
+                (Some("st<"), Some(value)) => Access::Status(value),
+                (Some("dat<"), Some(value)) => Access::ReadData(value),
+                (Some("dat>"), Some(value)) => Access::WriteData(value),
+                _ => panic!("line {}: cannot parse {:?}", number, line),
+            };
+            accesses.push((number, access));
+        }
+        Self {
+            accesses,
+            position: 0,
+            status: 0,
+        }
+    }
+
+    /// Every access of the trace was played
+    pub fn is_done(&self) -> bool {
+        self.position == self.accesses.len()
+    }
+
+    /// Skip the status reads the driver did not repeat, then take the next access
+    fn next(&mut self, expected: &str) -> (usize, Access) {
+        while let Some(&(_, Access::Status(value))) = self.accesses.get(self.position) {
+            self.status = value;
+            self.position += 1;
+        }
+        let Some(&(number, access)) = self.accesses.get(self.position) else {
+            panic!("{} past the end of the trace", expected);
+        };
+        self.position += 1;
+        (number, access)
+    }
+
+    fn expect_write(&mut self, access: Access) {
+        let (number, recorded) = self.next(&access.to_string());
+        assert_eq!(
+            recorded, access,
+            "line {}: the driver wrote {}, the trace has {}",
+            number, access, recorded
+        );
+    }
+}
+
+impl PortIo for Replay {
+    fn read_status(&mut self) -> u8 {
+        if self.position == self.accesses.len() {
+            return 0;
+        }
+        if let Some(&(_, Access::Status(value))) = self.accesses.get(self.position) {
+            self.status = value;
+            self.position += 1;
+        }
+        self.status
+    }
+
+    fn write_command(&mut self, value: u8) {
+        self.expect_write(Access::Command(value));
+    }
+
+    fn read_data(&mut self) -> u8 {
+        if self.position == self.accesses.len() {
+            return 0;
+        }
+        match self.next("dat<") {
+            (_, Access::ReadData(value)) => value,
+            (number, recorded) => {
+                panic!(
+                    "line {}: the driver read data, the trace has {}",
+                    number, recorded
+                )
+            }
+        }
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.expect_write(Access::WriteData(value));
+    }
+}
+
+fn parse_hex(word: &str) -> Option<u8> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))?;
+    u8::from_str_radix(digits, 16).ok()
+}
@@ -0,0 +1,161 @@
+//! Trace of the controller port accesses, for bugs only seen on some machines
+//!
+//! With `kbctrace=on` every access through [`Ports`](super::Ports) is recorded with the uptime:
+//! commands `cmd>`, status reads `st<`, data reads `dat<` and writes `dat>`. A status poll
+//! reads the same value many times, repeats are counted on one entry. The first [`CAPACITY`]
+//! entries are kept, later ones are only counted.
+//!
+//! The dump, one access per line, is what the replay of the host tests reads back.
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use crate::fox_sync::Mutex;
+use crate::fox_time::uptime;
+use crate::fox_vec::FixedVec;
+
+/// Entries of [`TRACE`], an init takes about 200
+pub const CAPACITY: usize = 2048;
+
+/// Record the accesses of [`super::Ports`]
+static IS_ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACE: Mutex<Trace> = Mutex::new(Trace::new());
+
+/// One register access
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Command(u8),
+    Status(u8),
+    ReadData(u8),
+    WriteData(u8),
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Entry {
+    /// Uptime of the first access
+    pub time: Duration,
+    pub access: Access,
+    /// Accesses merged into this entry, only status reads repeat
+    pub count: u32,
+}
+
+/// Accesses in order
+pub struct Trace {
+    entries: FixedVec<Entry, CAPACITY>,
+    /// Accesses that did not fit
+    dropped: usize,
+}
+
+impl Access {
+    /// Direction and register as in the dump
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Command(_) => "cmd>",
+            Self::Status(_) => "st<",
+            Self::ReadData(_) => "dat<",
+            Self::WriteData(_) => "dat>",
+        }
+    }
+
+    pub fn value(self) -> u8 {
+        match self {
+            Self::Command(value)
+            | Self::Status(value)
+            | Self::ReadData(value)
+            | Self::WriteData(value) => value,
+        }
+    }
+}
+
+impl Trace {
+    pub const fn new() -> Self {
+        Self {
+            entries: FixedVec::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, time: Duration, access: Access) {
+        if let Some(last) = self.entries.last_mut()
+            && last.access == access
+            && matches!(access, Access::Status(_))
+        {
+            last.count = last.count.saturating_add(1);
+            return;
+        }
+        let entry = Entry {
+            time,
+            access,
+            count: 1,
+        };
+        if self.entries.push(entry).is_err() {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries = FixedVec::new();
+        self.dropped = 0;
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:#04X}", self.name(), self.value())
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:4}.{:06} {}",
+            self.time.as_secs(),
+            self.time.subsec_micros(),
+            self.access
+        )?;
+        if self.count > 1 {
+            write!(f, " x{}", self.count)?;
+        }
+        Ok(())
+    }
+}
+
+/// A header comment, then one entry per line
+impl fmt::Display for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "# {} entries, {} dropped",
+            self.entries.len(),
+            self.dropped
+        )?;
+        for entry in self.entries.iter() {
+            writeln!(f, "{}", entry)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn set_enabled(is_enabled: bool) {
+    IS_ENABLED.store(is_enabled, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    IS_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Add `access` to the trace if enabled
+pub fn record(access: Access) {
+    if !is_enabled() {
+        return;
+    }
+    let time = uptime();
+    with_trace(|trace| trace.record(time, access));
+}
+
+/// Must not access the ports from `f`, recording would wait for the lock forever
+pub fn with_trace<R>(f: impl FnOnce(&mut Trace) -> R) -> R {
+    f(&mut TRACE.lock())
+}
@@ -0,0 +1,228 @@
+//! Memory-mapped UART from the SPCR
+//!
+//! The PL011 and its SBSA generic UART subset on ARM servers, and the memory-mapped 16550 of
+//! some x86 servers and SoCs. The firmware uses it as its console: the baud rate and line
+//! settings it programmed are kept, the UART clock is rarely known to compute our own.
+//!
+//! https://developer.arm.com/documentation/ddi0183/latest/
+
+use core::fmt;
+
+use super::{Driver, DriverError};
+use crate::fox_acpi::spcr::{Address, Interface};
+use crate::fox_acpi::{self, spcr};
+use crate::fox_io::{self, Space};
+use crate::fox_mem::map_mmio;
+use crate::fox_port::MmioBlock;
+use crate::fox_watchdog;
+use crate::{bitfields, registers};
+
+/// Register space of a PL011
+const PL011_SIZE: u64 = 0x1000;
+
+/// 16550 registers, in units of the stride
+const NS16550_DATA: u64 = 0;
+const NS16550_LINE_STATUS: u64 = 5;
+const NS16550_REGISTER_COUNT: u64 = 8;
+/// Line Status Register: transmitter holding register empty
+const NS16550_THRE: u8 = 0x20;
+
+/// Memory-mapped UART
+#[derive(Copy, Clone, Default, Debug)]
+pub struct MmioUart {
+    kind: Kind,
+    /// Virtual address of the registers, mapped by [`Driver::init`]
+    base: u64,
+    /// From the SPCR, `None` if it keeps the rate programmed
+    baud: Option<u32>,
+}
+
+#[derive(Copy, Clone, Default, Debug, PartialEq, Eq)]
+enum Kind {
+    #[default]
+    Pl011,
+    /// PL011 without the control register
+    Sbsa,
+    /// 16550 with `stride` bytes between registers, accessed 32 bits wide if 4
+    Ns16550 { stride: u8 },
+}
+
+impl MmioUart {
+    /// Busy-wait until the transmitter can accept a byte, or the [`fox_watchdog`] expires
+    pub fn write_byte(&mut self, value: u8) {
+        match self.kind {
+            Kind::Pl011 | Kind::Sbsa => {
+                let registers = Pl011Registers(MmioBlock::new(self.base));
+                // SAFETY: the SPCR UART, mapped uncached by `init`
+                while unsafe { registers.fr().read() }.is_transmit_full()
+                    && !fox_watchdog::is_output_expired()
+                {}
+                // SAFETY: as above
+                unsafe { registers.dr().write(value.into()) };
+            }
+            Kind::Ns16550 { stride } => {
+                while self.ns16550_read(stride, NS16550_LINE_STATUS) & NS16550_THRE == 0
+                    && !fox_watchdog::is_output_expired()
+                {}
+                self.ns16550_write(stride, NS16550_DATA, value);
+            }
+        }
+    }
+
+    fn ns16550_read(&self, stride: u8, register: u64) -> u8 {
+        let registers = MmioBlock::new(self.base);
+        let offset = register * stride as u64;
+        // SAFETY: the SPCR UART, mapped uncached by `init`
+        unsafe {
+            match stride {
+                4 => registers.read::<u32>(offset) as u8,
+                _ => registers.read::<u8>(offset),
+            }
+        }
+    }
+
+    fn ns16550_write(&self, stride: u8, register: u64, value: u8) {
+        let registers = MmioBlock::new(self.base);
+        let offset = register * stride as u64;
+        // SAFETY: the SPCR UART, mapped uncached by `init`
+        unsafe {
+            match stride {
+                4 => registers.write::<u32>(offset, value.into()),
+                _ => registers.write::<u8>(offset, value),
+            }
+        }
+    }
+}
+
+impl Driver for MmioUart {
+    const DRIVER_NAME: &str = "mmio_uart";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("MmioUart::probe()");
+
+        match spcr() {
+            Ok(spcr) if matches!(spcr.address, Address::Mmio { .. }) => Ok(()),
+            Ok(spcr) => {
+                log::info!("{}: SPCR UART at {:X?}", Self::DRIVER_NAME, spcr.address);
+                Err(DriverError::NoHardware)
+            }
+            Err(fox_acpi::Error::NoTable) => Err(DriverError::NoHardware),
+            Err(err) => {
+                log::warn!("{}: SPCR: {}", Self::DRIVER_NAME, err);
+                Err(DriverError::NoHardware)
+            }
+        }
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("MmioUart::init()");
+
+        let spcr = spcr().map_err(|_| DriverError::NoHardware)?;
+        let Address::Mmio { base, stride } = spcr.address else {
+            return Err(DriverError::NoHardware);
+        };
+        self.kind = match spcr.interface {
+            Interface::Pl011 => Kind::Pl011,
+            Interface::Sbsa => Kind::Sbsa,
+            Interface::Ns16550 | Interface::Ns16550Gas => Kind::Ns16550 { stride },
+            Interface::Other(interface_type) => {
+                log::info!(
+                    "{}: unsupported SPCR interface type {:#04X}",
+                    Self::DRIVER_NAME,
+                    interface_type
+                );
+                return Err(DriverError::NoHardware);
+            }
+        };
+        self.baud = spcr.baud;
+
+        let size = match self.kind {
+            Kind::Pl011 | Kind::Sbsa => PL011_SIZE,
+            Kind::Ns16550 { stride } => NS16550_REGISTER_COUNT * stride as u64,
+        };
+        self.base = map_mmio(base, size as usize).map_err(DriverError::Mmio)?;
+
+        if let Kind::Ns16550 { stride } = self.kind {
+            // no device answers with all ones
+            let status = self.ns16550_read(stride, NS16550_LINE_STATUS);
+            if status == 0xFF {
+                return Err(DriverError::UnexpectedResponse { byte: status });
+            }
+        }
+        if self.kind == Kind::Pl011 {
+            let registers = Pl011Registers(MmioBlock::new(self.base));
+            // SAFETY: the SPCR UART, mapped uncached by `init`
+            unsafe {
+                registers.cr().modify(|mut cr| {
+                    cr.set_is_enabled(true);
+                    cr.set_is_transmit_enabled(true);
+                    cr
+                })
+            };
+        }
+        fox_io::reserve(Space::Mmio, base, size, Self::DRIVER_NAME);
+
+        match self.baud {
+            Some(baud) => log::info!(
+                "{}: Found {:?} UART at {:#X}, {} baud",
+                Self::DRIVER_NAME,
+                self.kind,
+                base,
+                baud
+            ),
+            None => log::info!(
+                "{}: Found {:?} UART at {:#X}, baud rate as programmed",
+                Self::DRIVER_NAME,
+                self.kind,
+                base
+            ),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("MmioUart::remove()");
+    }
+}
+
+impl fmt::Write for MmioUart {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for i in s.bytes() {
+            if i == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(i);
+        }
+        Ok(())
+    }
+}
+
+registers! {
+    /// PL011, the SBSA generic UART has DR and FR only
+    struct Pl011Registers {
+        /// Data Register
+        dr: 0x00 => u32, WriteOnlyAccess;
+        /// Flag Register
+        fr: 0x18 => Pl011Flags, ReadOnlyAccess;
+        /// Control Register
+        cr: 0x30 => Pl011Control, ReadWriteAccess;
+    }
+}
+
+bitfields! {
+    /// UARTFR
+    struct Pl011Flags(u32) {
+        /// TXFF, the transmit FIFO is full
+        is_transmit_full: 5;
+    }
+}
+
+bitfields! {
+    /// UARTCR
+    struct Pl011Control(u32) {
+        /// UARTEN
+        is_enabled, set_is_enabled: 0;
+        /// TXE
+        is_transmit_enabled, set_is_transmit_enabled: 8;
+    }
+}
@@ -1,12 +1,404 @@
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+
+use crate::fox_acpi::Table;
+use crate::fox_io::{self, Reservation};
+use crate::fox_mem::MapError;
+use crate::fox_sync::without_interrupts;
+use crate::fox_time::now;
+use crate::{fox_config, fox_watchdog};
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod acpi_ec;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod ahci;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod apic;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod cmos_rtc;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod e1000;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 mod i8042;
+mod mmio_uart;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod nvme;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pc_speaker;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pci;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pic8259;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod pit8254;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod serial16550;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod virtio_net;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod xhci;
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub use i8042::I8042;
+pub use acpi_ec::AcpiEc;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ahci::Ahci;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use apic::Apic;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use cmos_rtc::CmosRtc;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use e1000::E1000;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use i8042::{I8042, MouseEvent, OutputPort, ScancodeSet};
+pub use mmio_uart::MmioUart;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use nvme::Nvme;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use pc_speaker::{PcSpeaker, Signal};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use pci::{Pci, PciAddress};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use pic8259::Pic8259;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use pit8254::Pit8254;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use serial16550::Serial16550;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use virtio_net::VirtioNet;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use xhci::Xhci;
+
+/// Every driver passed to [`load`] or [`Slot::new`] in that order, accessed with interrupts
+/// disabled
+static mut STATUS: Vec<DriverStatus> = Vec::new();
 
 pub trait Driver {
     const DRIVER_NAME: &str;
-    fn probe() -> Result<(), ()>;
-    fn init(&mut self);
+    /// Checked by [`load`] before the probe, [`load_ordered`] loads these drivers first
+    const DEPENDENCIES: &[Dependency] = &[];
+    fn probe() -> Result<(), DriverError>;
+    fn init(&mut self) -> Result<(), DriverError>;
     fn remove(&mut self);
 }
+
+/// What a driver needs to be loaded
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Dependency {
+    /// Another driver loaded without error, by its [`Driver::DRIVER_NAME`]
+    Driver(&'static str),
+    /// An ACPI table found at boot
+    Table(Table),
+}
+
+/// Why probing or initializing a driver failed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DriverError {
+    /// The hardware is not present
+    NoHardware,
+    /// Controller or device self-test returned an error code
+    SelfTestFailed { code: u8 },
+    /// Interface test of a port returned an error code
+    InterfaceTestFailed { port: u8, code: u8 },
+    /// The hardware did not respond in time
+    Timeout,
+    /// The hardware responded with an unexpected byte
+    UnexpectedResponse { byte: u8 },
+    /// The device kept asking to resend a byte or answered it with an error
+    Rejected { byte: u8 },
+    /// The device aborted a command, with its ATA status and error registers
+    DeviceError { status: u8, error: u8 },
+    /// The controller completed a command with an error status
+    CommandFailed { status: u16 },
+    /// A UEFI protocol call failed
+    Firmware(uefi::Status),
+    /// Registers could not be mapped
+    Mmio(MapError),
+    /// The request does not fit the device (buffer size or alignment, block range, read-only)
+    InvalidRequest,
+    /// Turned off in [`fox_config`]
+    Disabled,
+    /// A [`Driver::DEPENDENCIES`] entry is missing or failed
+    Dependency(Dependency),
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoHardware => write!(f, "no hardware found"),
+            Self::SelfTestFailed { code } => write!(f, "self-test failed ({:#04X})", code),
+            Self::InterfaceTestFailed { port, code } => {
+                write!(f, "port {} interface test failed ({:#04X})", port, code)
+            }
+            Self::Timeout => write!(f, "timeout"),
+            Self::UnexpectedResponse { byte } => write!(f, "unexpected response {:#04X}", byte),
+            Self::Rejected { byte } => write!(f, "byte {:#04X} rejected", byte),
+            Self::DeviceError { status, error } => {
+                write!(
+                    f,
+                    "device error (status {:#04X}, error {:#04X})",
+                    status, error
+                )
+            }
+            Self::CommandFailed { status } => write!(f, "command failed ({:#06X})", status),
+            Self::Firmware(status) => write!(f, "firmware error {:?}", status),
+            Self::Mmio(err) => write!(f, "MMIO mapping failed: {}", err),
+            Self::InvalidRequest => write!(f, "invalid request"),
+            Self::Disabled => write!(f, "disabled"),
+            Self::Dependency(dependency) => write!(f, "needs {}", dependency),
+        }
+    }
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Driver(name) => write!(f, "{}", name),
+            Self::Table(table) => write!(f, "{}", table.name()),
+        }
+    }
+}
+
+/// `ahci -> pci (no hardware found)`: the dependency, the one it failed on and so on
+struct DependencyChain(Dependency);
+
+impl fmt::Display for DependencyChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut dependency = self.0;
+        // a cycle fails with "not loaded", the chain ends
+        loop {
+            write!(f, "{}", dependency)?;
+            let name = match dependency {
+                Dependency::Driver(name) => name,
+                Dependency::Table(_) => return write!(f, " (not found)"),
+            };
+            match driver_state(name) {
+                None => return write!(f, " (not loaded)"),
+                Some(State::Failed(DriverError::Dependency(next))) => {
+                    write!(f, " -> ")?;
+                    dependency = next;
+                }
+                Some(State::Initialized) => return Ok(()),
+                Some(state) => return write!(f, " ({})", state),
+            }
+        }
+    }
+}
+
+/// Where a driver is, see [`status`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    /// Waiting in a [`Slot`]
+    NotProbed,
+    /// Skipped by [`load`], disabled by [`fox_config`]
+    Disabled,
+    /// The probe or init found no hardware
+    Absent,
+    Initialized,
+    /// Includes a [`Driver::DEPENDENCIES`] entry not met
+    Failed(DriverError),
+    /// Initialized, then passed to [`remove`]
+    Removed,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotProbed => write!(f, "not probed"),
+            Self::Disabled => write!(f, "disabled"),
+            Self::Absent => write!(f, "absent"),
+            Self::Initialized => write!(f, "initialized"),
+            Self::Failed(err) => write!(f, "failed: {}", err),
+            Self::Removed => write!(f, "removed"),
+        }
+    }
+}
+
+/// A driver known to the registry
+#[derive(Copy, Clone, Debug)]
+pub struct DriverStatus {
+    pub name: &'static str,
+    pub state: State,
+    pub dependencies: &'static [Dependency],
+    /// Probe and init of the last [`load`], zero before
+    pub init_time: Duration,
+}
+
+impl DriverStatus {
+    /// Registers the driver holds in [`fox_io`], released on [`remove`]
+    pub fn resources(&self) -> Vec<Reservation> {
+        fox_io::reservations(self.name)
+    }
+}
+
+/// A driver for [`load_ordered`], holds it once loaded
+pub struct Slot<D> {
+    dev: Option<D>,
+    /// [`load`] ran
+    is_done: bool,
+}
+
+/// A [`Slot`] of any driver
+pub trait Load {
+    fn name(&self) -> &'static str;
+    fn dependencies(&self) -> &'static [Dependency];
+    fn load(&mut self);
+}
+
+impl<D: Driver> Slot<D> {
+    pub fn new(dev: D) -> Self {
+        with_status(|status| {
+            if !status.iter().any(|i| i.name == D::DRIVER_NAME) {
+                status.push(DriverStatus {
+                    name: D::DRIVER_NAME,
+                    state: State::NotProbed,
+                    dependencies: D::DEPENDENCIES,
+                    init_time: Duration::ZERO,
+                });
+            }
+        });
+        Self {
+            dev: Some(dev),
+            is_done: false,
+        }
+    }
+
+    /// The driver if [`load_ordered`] loaded it without error
+    pub fn into_loaded(self) -> Option<D> {
+        self.dev.filter(|_| self.is_done)
+    }
+}
+
+impl<D: Driver> Load for Slot<D> {
+    fn name(&self) -> &'static str {
+        D::DRIVER_NAME
+    }
+
+    fn dependencies(&self) -> &'static [Dependency] {
+        D::DEPENDENCIES
+    }
+
+    fn load(&mut self) {
+        if let Some(dev) = self.dev.take() {
+            self.dev = load(dev);
+            self.is_done = true;
+        }
+    }
+}
+
+/// Probe and initialize `dev`, log failures and record the outcome
+///
+/// Init runs under a [`fox_watchdog`] deadline, drivers disabled by [`fox_config`] are skipped.
+/// Fails without probing if a [`Driver::DEPENDENCIES`] entry is not met.
+pub fn load<D: Driver>(mut dev: D) -> Option<D> {
+    let config = fox_config::config();
+    let start = now();
+    let result = if config.is_driver_enabled(D::DRIVER_NAME) {
+        check_dependencies(D::DRIVER_NAME, D::DEPENDENCIES)
+            .and_then(|()| D::probe())
+            .and_then(|()| {
+                fox_watchdog::run(D::DRIVER_NAME, config.watchdog, || dev.init())
+                    .inspect_err(|err| log::error!("{}: {}", D::DRIVER_NAME, err))
+            })
+    } else {
+        log::info!("{}: disabled by config", D::DRIVER_NAME);
+        Err(DriverError::Disabled)
+    };
+
+    let state = match result {
+        Ok(()) => State::Initialized,
+        Err(DriverError::Disabled) => State::Disabled,
+        Err(DriverError::NoHardware) => State::Absent,
+        Err(err) => State::Failed(err),
+    };
+    let driver_status = DriverStatus {
+        name: D::DRIVER_NAME,
+        state,
+        dependencies: D::DEPENDENCIES,
+        init_time: start.elapsed(),
+    };
+    with_status(
+        |status| match status.iter_mut().find(|i| i.name == D::DRIVER_NAME) {
+            Some(i) => *i = driver_status,
+            None => status.push(driver_status),
+        },
+    );
+
+    result.ok().map(|()| dev)
+}
+
+/// [`Driver::remove`] and record it
+pub fn remove<D: Driver>(dev: &mut D) {
+    dev.remove();
+    with_status(|status| {
+        if let Some(i) = status.iter_mut().find(|i| i.name == D::DRIVER_NAME) {
+            i.state = State::Removed;
+        }
+    });
+}
+
+/// [`load`] every driver after the ones it depends on, otherwise in the given order
+///
+/// Dependencies outside `drivers` must have been loaded before. Drivers in a cycle are loaded
+/// last and fail their dependency check.
+pub fn load_ordered(drivers: &mut [&mut dyn Load]) {
+    // log::trace!("drivers::load_ordered");
+
+    let mut is_done = Vec::from_iter(drivers.iter().map(|_| false));
+    loop {
+        let is_ready = |i: usize| {
+            drivers[i]
+                .dependencies()
+                .iter()
+                .all(|dependency| match dependency {
+                    Dependency::Driver(name) => {
+                        !(0..drivers.len()).any(|j| !is_done[j] && drivers[j].name() == *name)
+                    }
+                    Dependency::Table(_) => true,
+                })
+        };
+        let Some(next) = (0..drivers.len()).find(|&i| !is_done[i] && is_ready(i)) else {
+            break;
+        };
+        drivers[next].load();
+        is_done[next] = true;
+    }
+
+    for i in (0..drivers.len()).filter(|&i| !is_done[i]) {
+        log::error!("{}: dependency cycle", drivers[i].name());
+        drivers[i].load();
+    }
+}
+
+/// Every entry of `dependencies` is loaded or found, the chain of the first one missing is logged
+fn check_dependencies(name: &str, dependencies: &[Dependency]) -> Result<(), DriverError> {
+    for &dependency in dependencies {
+        let is_met = match dependency {
+            Dependency::Driver(name) => driver_state(name) == Some(State::Initialized),
+            Dependency::Table(table) => table.is_found(),
+        };
+        if !is_met {
+            log::warn!("{}: needs {}", name, DependencyChain(dependency));
+            return Err(DriverError::Dependency(dependency));
+        }
+    }
+    Ok(())
+}
+
+fn driver_state(name: &str) -> Option<State> {
+    with_status(|status| status.iter().find(|i| i.name == name).map(|i| i.state))
+}
+
+/// Every driver known to the registry, in the order it was first seen
+pub fn status() -> Vec<DriverStatus> {
+    with_status(|status| status.clone())
+}
+
+fn with_status<R>(f: impl FnOnce(&mut Vec<DriverStatus>) -> R) -> R {
+    without_interrupts(|| {
+        let status = &raw mut STATUS;
+        // SAFETY: interrupts are disabled
+        let status = unsafe { &mut *status };
+        f(status)
+    })
+}
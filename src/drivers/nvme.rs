@@ -0,0 +1,667 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! NVM Express
+//!
+//! One admin and one I/O queue pair, commands are polled one at a time. Memory is
+//! identity-mapped, so addresses of statics and caller buffers are used for DMA as they are.
+//!
+//! https://wiki.osdev.org/NVMe
+
+use core::sync::atomic::{Ordering, compiler_fence};
+use core::time::Duration;
+use core::{fmt, str};
+
+use bit_field::BitField;
+
+use super::pci::{Bar, Pci, PciDevice, PowerState};
+use super::{Dependency, Driver, DriverError};
+use crate::fox_block::{BlockDevice, check_range};
+use crate::fox_mem::map_mmio;
+use crate::fox_port::{Mmio, MmioBlock, WriteOnlyAccess};
+use crate::fox_time::poll_timeout;
+use crate::fox_vec::FixedVec;
+
+const PAGE_SIZE: usize = 4096;
+const QUEUE_SIZE: usize = 64;
+const MAX_NAMESPACES: usize = 16;
+
+/// Upper bound of a transfer, one PRP list page
+const MAX_TRANSFER: usize = PAGE_SIZE / size_of::<u64>() * PAGE_SIZE;
+
+const TIMEOUT_COMMAND: Duration = Duration::from_secs(5);
+
+const ADMIN_QUEUE: u16 = 0;
+const IO_QUEUE: u16 = 1;
+
+/// Queues, PRP list and identify buffer, used by DMA
+static mut MEMORY: NvmeMemory = NvmeMemory::new();
+
+/// NVMe controller
+pub struct Nvme {
+    /// Controller registers (BAR0)
+    bar: MmioBlock,
+    /// Doorbell Stride in bytes
+    doorbell_stride: u64,
+    admin: Queue,
+    io: Queue,
+    command_id: u16,
+    /// Bytes per command, from MDTS
+    max_transfer: usize,
+    model: [u8; 40],
+    namespaces: FixedVec<Namespace, MAX_NAMESPACES>,
+}
+
+/// Active namespace
+#[derive(Copy, Clone, Debug)]
+struct Namespace {
+    id: u32,
+    block_size: usize,
+    block_count: u64,
+}
+
+/// Namespace of a controller as a [`BlockDevice`], see [`Nvme::namespace`]
+pub struct NvmeNamespace<'a> {
+    nvme: &'a mut Nvme,
+    namespace: Namespace,
+}
+
+impl Nvme {
+    /// Model number from Identify Controller
+    pub fn model(&self) -> &str {
+        str::from_utf8(&self.model).map_or("?", |model| model.trim())
+    }
+
+    pub fn namespace_count(&self) -> usize {
+        self.namespaces.len()
+    }
+
+    pub fn namespace(&mut self, index: usize) -> Option<NvmeNamespace<'_>> {
+        let namespace = *self.namespaces.get(index)?;
+        Some(NvmeNamespace {
+            nvme: self,
+            namespace,
+        })
+    }
+}
+
+impl Default for Nvme {
+    fn default() -> Self {
+        Self {
+            bar: MmioBlock::default(),
+            doorbell_stride: 4,
+            admin: Queue::default(),
+            io: Queue::default(),
+            command_id: 0,
+            max_transfer: MAX_TRANSFER,
+            model: [b' '; 40],
+            namespaces: FixedVec::new(),
+        }
+    }
+}
+
+impl Driver for Nvme {
+    const DRIVER_NAME: &str = "nvme";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Driver(Pci::DRIVER_NAME)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Nvme::probe()");
+
+        if controller().is_none() {
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Nvme::init()");
+
+        let (dev, bar, size) = controller().ok_or(DriverError::NoHardware)?;
+        // uncached, whatever the firmware chose
+        let address = map_mmio(bar, size as usize).map_err(DriverError::Mmio)?;
+        self.bar = MmioBlock::new(address);
+        log::info!("{}: {} BAR0 at {:#x}", Self::DRIVER_NAME, dev.address, bar);
+
+        if let Some(pm) = dev.power_management()
+            && pm.state() != PowerState::D0
+        {
+            pm.set_state(PowerState::D0);
+        }
+        dev.enable_bus_master();
+
+        let cap = self.read64(REG_CAP);
+        let version = self.read(REG_VS);
+        log::debug!(
+            "{}: version {}.{}, CAP {:#x}",
+            Self::DRIVER_NAME,
+            version.get_bits(16..32),
+            version.get_bits(8..16),
+            cap
+        );
+        // Memory Page Size Minimum, 4 KiB pages are used
+        if cap.get_bits(48..52) != 0 {
+            log::error!("{}: 4 KiB pages are not supported", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        self.doorbell_stride = 4 << cap.get_bits(32..36);
+        // Maximum Queue Entries Supported, zero-based
+        let queue_size = QUEUE_SIZE.min(cap.get_bits(0..16) as usize + 1);
+        // Timeout, in 500 ms units
+        let timeout_ready = Duration::from_millis(cap.get_bits(24..32).max(1) * 500);
+
+        self.set_enabled(false, timeout_ready)?;
+
+        let memory = &raw mut MEMORY;
+        // SAFETY: only the addresses are taken
+        unsafe {
+            self.admin = Queue::new(
+                ADMIN_QUEUE,
+                queue_size,
+                &raw mut (*memory).admin_sq as u64,
+                &raw mut (*memory).admin_cq as u64,
+            );
+            self.io = Queue::new(
+                IO_QUEUE,
+                queue_size,
+                &raw mut (*memory).io_sq as u64,
+                &raw mut (*memory).io_cq as u64,
+            );
+        }
+        let size = (queue_size - 1) as u32;
+        self.write(REG_AQA, size | size << 16);
+        self.write64(REG_ASQ, self.admin.sq);
+        self.write64(REG_ACQ, self.admin.cq);
+
+        self.set_enabled(true, timeout_ready)?;
+
+        let namespace_count = self.identify_controller()?;
+
+        // Create I/O Completion Queue, then the Submission Queue that posts to it
+        let mut command = Command::new(OPCODE_CREATE_IO_CQ);
+        command.prp1 = self.io.cq;
+        command.cdw10 = (size << 16) | IO_QUEUE as u32;
+        // Physically Contiguous, no interrupts
+        command.cdw11 = 1;
+        self.submit(ADMIN_QUEUE, command)?;
+
+        let mut command = Command::new(OPCODE_CREATE_IO_SQ);
+        command.prp1 = self.io.sq;
+        command.cdw10 = (size << 16) | IO_QUEUE as u32;
+        command.cdw11 = (IO_QUEUE as u32) << 16 | 1;
+        self.submit(ADMIN_QUEUE, command)?;
+
+        self.identify_namespaces(namespace_count)?;
+
+        for i in self.namespaces.iter() {
+            log::info!(
+                "{}: {} namespace {}: {} blocks of {} bytes",
+                Self::DRIVER_NAME,
+                self.model(),
+                i.id,
+                i.block_count,
+                i.block_size
+            );
+        }
+        if self.namespaces.is_empty() {
+            log::info!("{}: No namespaces", Self::DRIVER_NAME);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Nvme::remove()");
+
+        // Shutdown Notification: normal
+        let mut cc = self.read(REG_CC);
+        cc.set_bits(14..16, 0b01);
+        self.write(REG_CC, cc);
+        // Shutdown Status: complete
+        let result = poll_timeout(TIMEOUT_COMMAND, || {
+            (self.read(REG_CSTS).get_bits(2..4) == 0b10).then_some(())
+        });
+        if result.is_none() {
+            log::warn!("{}: shutdown timeout", Self::DRIVER_NAME);
+        }
+        self.namespaces = FixedVec::new();
+    }
+}
+
+impl Nvme {
+    /// Set CC.EN and wait for CSTS.RDY to follow
+    fn set_enabled(&self, enabled: bool, timeout: Duration) -> Result<(), DriverError> {
+        // log::trace!("Nvme::set_enabled()");
+
+        let mut cc = self.read(REG_CC);
+        if enabled {
+            // I/O Completion/Submission Queue Entry Size: 16 and 64 bytes, NVM command set,
+            // 4 KiB pages, round robin
+            cc = 0;
+            cc.set_bits(20..24, 4);
+            cc.set_bits(16..20, 6);
+        }
+        cc.set_bit(0, enabled);
+        self.write(REG_CC, cc);
+
+        poll_timeout(timeout, || {
+            let csts = self.read(REG_CSTS);
+            // Controller Fatal Status
+            if enabled && csts.get_bit(1) {
+                return Some(Err(DriverError::Timeout));
+            }
+            (csts.get_bit(0) == enabled).then_some(Ok(()))
+        })
+        .unwrap_or(Err(DriverError::Timeout))
+        .inspect_err(|_| log::error!("{}: controller not ready", Self::DRIVER_NAME))
+    }
+
+    /// Returns the Number of Namespaces
+    fn identify_controller(&mut self) -> Result<u32, DriverError> {
+        // log::trace!("Nvme::identify_controller()");
+
+        let data = self.identify(CNS_CONTROLLER, 0)?;
+        // Model Number, ASCII padded with spaces
+        self.model.copy_from_slice(&data[24..64]);
+        // Maximum Data Transfer Size, a power of two of pages, 0 is no limit
+        let mdts = data[77];
+        self.max_transfer = match mdts {
+            0 => MAX_TRANSFER,
+            _ => MAX_TRANSFER.min(PAGE_SIZE << mdts.min(16)),
+        };
+        Ok(u32::from_le_bytes(data[516..520].try_into().unwrap()))
+    }
+
+    /// Collect active namespaces up to [`MAX_NAMESPACES`]
+    fn identify_namespaces(&mut self, count: u32) -> Result<(), DriverError> {
+        // log::trace!("Nvme::identify_namespaces()");
+
+        for id in 1..=count.min(MAX_NAMESPACES as u32) {
+            let data = self.identify(CNS_NAMESPACE, id)?;
+            // Namespace Size, 0 if inactive
+            let block_count = u64::from_le_bytes(data[0..8].try_into().unwrap());
+            if block_count == 0 {
+                continue;
+            }
+            // Formatted LBA Size selects an LBA Format, LBA Data Size is a power of two
+            let format = data[26].get_bits(0..4) as usize;
+            let lbaf = u32::from_le_bytes(data[128 + format * 4..][..4].try_into().unwrap());
+            let block_size = 1 << lbaf.get_bits(16..24);
+
+            let _ = self.namespaces.push(Namespace {
+                id,
+                block_size,
+                block_count,
+            });
+        }
+        Ok(())
+    }
+
+    /// Run Identify, the returned page is valid until the next command
+    fn identify(
+        &mut self,
+        cns: u32,
+        namespace: u32,
+    ) -> Result<&'static [u8; PAGE_SIZE], DriverError> {
+        let memory = &raw mut MEMORY;
+        // SAFETY: only the address is taken
+        let buffer = unsafe { &raw mut (*memory).identify };
+
+        let mut command = Command::new(OPCODE_IDENTIFY);
+        command.nsid = namespace;
+        command.prp1 = buffer as u64;
+        command.cdw10 = cns;
+        self.submit(ADMIN_QUEUE, command)?;
+
+        // SAFETY: the controller is done writing
+        Ok(unsafe { &(*buffer).0 })
+    }
+
+    /// Read or write blocks with the I/O queue, at most `max_transfer` bytes at `data`
+    fn transfer(
+        &mut self,
+        opcode: u8,
+        namespace: &Namespace,
+        lba: u64,
+        data: u64,
+        length: usize,
+    ) -> Result<(), DriverError> {
+        let count = length / namespace.block_size;
+        let mut command = Command::new(opcode);
+        command.nsid = namespace.id;
+        (command.prp1, command.prp2) = prp(data, length);
+        command.cdw10 = lba as u32;
+        command.cdw11 = (lba >> 32) as u32;
+        // Number of Logical Blocks, zero-based
+        command.cdw12 = (count - 1) as u32;
+        self.submit(IO_QUEUE, command)
+    }
+
+    /// Post `command` and poll its completion
+    fn submit(&mut self, queue: u16, mut command: Command) -> Result<(), DriverError> {
+        self.command_id = self.command_id.wrapping_add(1);
+        command.cdw0.set_bits(16..32, self.command_id as u32);
+
+        let mut doorbell_sq = self.doorbell(queue, false);
+        let mut doorbell_cq = self.doorbell(queue, true);
+        let queue = match queue {
+            ADMIN_QUEUE => &mut self.admin,
+            _ => &mut self.io,
+        };
+
+        // SAFETY: the slot at the tail belongs to the host
+        unsafe {
+            (queue.sq as *mut Command)
+                .add(queue.sq_tail)
+                .write_volatile(command)
+        };
+        queue.sq_tail = (queue.sq_tail + 1) % queue.size;
+        compiler_fence(Ordering::SeqCst);
+        // SAFETY: the doorbell is in BAR0
+        unsafe { doorbell_sq.write(queue.sq_tail as u32) };
+
+        let completion = poll_timeout(TIMEOUT_COMMAND, || {
+            // SAFETY: the slot at the head is written by the controller
+            let entry = unsafe {
+                (queue.cq as *const Completion)
+                    .add(queue.cq_head)
+                    .read_volatile()
+            };
+            // Phase Tag flips on every pass through the queue
+            (entry.status.get_bit(0) == queue.phase).then_some(entry)
+        });
+        let Some(completion) = completion else {
+            log::error!(
+                "{}: command timeout on queue {}",
+                Self::DRIVER_NAME,
+                queue.id
+            );
+            return Err(DriverError::Timeout);
+        };
+        compiler_fence(Ordering::SeqCst);
+
+        queue.cq_head += 1;
+        if queue.cq_head == queue.size {
+            queue.cq_head = 0;
+            queue.phase = !queue.phase;
+        }
+        // SAFETY: the doorbell is in BAR0
+        unsafe { doorbell_cq.write(queue.cq_head as u32) };
+
+        // Status Code Type and Status Code, without the Phase Tag
+        let status = completion.status >> 1 & 0x7FF;
+        if status != 0 {
+            return Err(DriverError::CommandFailed { status });
+        }
+        Ok(())
+    }
+
+    /// Offset of the tail (submission) or head (completion) doorbell of a queue
+    fn doorbell(&self, queue: u16, is_completion: bool) -> Mmio<u32, WriteOnlyAccess> {
+        let offset = 0x1000 + (2 * queue as u64 + is_completion as u64) * self.doorbell_stride;
+        self.bar.register(offset)
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        // SAFETY: BAR0 comes from the PCI configuration space
+        unsafe { self.bar.read(offset) }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        // SAFETY: BAR0 comes from the PCI configuration space
+        unsafe { self.bar.write(offset, value) }
+    }
+
+    /// 64-bit registers, accessed as two dwords
+    fn read64(&self, offset: u64) -> u64 {
+        self.read(offset) as u64 | (self.read(offset + 4) as u64) << 32
+    }
+
+    fn write64(&self, offset: u64, value: u64) {
+        self.write(offset, value as u32);
+        self.write(offset + 4, (value >> 32) as u32);
+    }
+}
+
+impl fmt::Debug for Nvme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Nvme")
+            .field("bar", &format_args!("{:#x}", self.bar.base()))
+            .field("model", &self.model())
+            .field("namespaces", &self.namespaces)
+            .finish()
+    }
+}
+
+impl BlockDevice for NvmeNamespace<'_> {
+    fn block_size(&self) -> usize {
+        self.namespace.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.namespace.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), DriverError> {
+        // log::trace!("NvmeNamespace::read_blocks()");
+
+        check_range(self, lba, buffer.len())?;
+        // PRP entries must be dword aligned
+        if !(buffer.as_ptr() as usize).is_multiple_of(4) {
+            return Err(DriverError::InvalidRequest);
+        }
+
+        let mut lba = lba;
+        for chunk in buffer.chunks_mut(self.chunk_size()) {
+            let data = chunk.as_mut_ptr() as u64;
+            self.nvme
+                .transfer(OPCODE_READ, &self.namespace, lba, data, chunk.len())?;
+            lba += (chunk.len() / self.namespace.block_size) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), DriverError> {
+        // log::trace!("NvmeNamespace::write_blocks()");
+
+        check_range(self, lba, buffer.len())?;
+        if !(buffer.as_ptr() as usize).is_multiple_of(4) {
+            return Err(DriverError::InvalidRequest);
+        }
+
+        let mut lba = lba;
+        for chunk in buffer.chunks(self.chunk_size()) {
+            let data = chunk.as_ptr() as u64;
+            self.nvme
+                .transfer(OPCODE_WRITE, &self.namespace, lba, data, chunk.len())?;
+            lba += (chunk.len() / self.namespace.block_size) as u64;
+        }
+
+        let mut command = Command::new(OPCODE_FLUSH);
+        command.nsid = self.namespace.id;
+        self.nvme.submit(IO_QUEUE, command)
+    }
+}
+
+impl NvmeNamespace<'_> {
+    /// Whole blocks per command, the first page may be partial so leave room for it
+    fn chunk_size(&self) -> usize {
+        let block_size = self.namespace.block_size;
+        ((self.nvme.max_transfer - PAGE_SIZE) / block_size * block_size).max(block_size)
+    }
+}
+
+impl fmt::Display for NvmeNamespace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} namespace {}", self.nvme.model(), self.namespace.id)
+    }
+}
+
+/// First NVMe controller, its BAR0 and the BAR0 size
+fn controller() -> Option<(&'static PciDevice, u64, u64)> {
+    // prog-if 02: NVM Express
+    Pci::find_class(0x01, 0x08)
+        .filter(|i| i.prog_if == 0x02)
+        .find_map(|dev| match dev.bars[0] {
+            Bar::Memory { address, size, .. } if address != 0 => Some((dev, address, size)),
+            _ => None,
+        })
+}
+
+/// PRP Entry 1 and 2 for a buffer, with a PRP list when it spans more than two pages
+fn prp(start: u64, length: usize) -> (u64, u64) {
+    let end = start + length as u64;
+    let first_page = start & !(PAGE_SIZE as u64 - 1);
+    let next_page = first_page + PAGE_SIZE as u64;
+
+    if end <= next_page {
+        (start, 0)
+    } else if end <= next_page + PAGE_SIZE as u64 {
+        (start, next_page)
+    } else {
+        let memory = &raw mut MEMORY;
+        // SAFETY: the list is only used by the one command in flight
+        let list = unsafe { &mut (*memory).prp_list.0 };
+        for (i, page) in (next_page..end).step_by(PAGE_SIZE).enumerate() {
+            list[i] = page;
+        }
+        (start, list.as_ptr() as u64)
+    }
+}
+
+// Controller registers
+const REG_CAP: u64 = 0x00;
+const REG_VS: u64 = 0x08;
+const REG_CC: u64 = 0x14;
+const REG_CSTS: u64 = 0x1C;
+const REG_AQA: u64 = 0x24;
+const REG_ASQ: u64 = 0x28;
+const REG_ACQ: u64 = 0x30;
+
+// Admin commands
+const OPCODE_CREATE_IO_SQ: u8 = 0x01;
+const OPCODE_CREATE_IO_CQ: u8 = 0x05;
+const OPCODE_IDENTIFY: u8 = 0x06;
+
+// NVM commands
+const OPCODE_FLUSH: u8 = 0x00;
+const OPCODE_WRITE: u8 = 0x01;
+const OPCODE_READ: u8 = 0x02;
+
+// Controller or Namespace Structure of Identify
+const CNS_NAMESPACE: u32 = 0x00;
+const CNS_CONTROLLER: u32 = 0x01;
+
+/// Submission and completion queue of a pair
+#[derive(Default, Debug)]
+struct Queue {
+    id: u16,
+    size: usize,
+    /// Submission queue address
+    sq: u64,
+    /// Completion queue address
+    cq: u64,
+    sq_tail: usize,
+    cq_head: usize,
+    /// Expected Phase Tag of new completions
+    phase: bool,
+}
+
+impl Queue {
+    fn new(id: u16, size: usize, sq: u64, cq: u64) -> Self {
+        Self {
+            id,
+            size,
+            sq,
+            cq,
+            sq_tail: 0,
+            cq_head: 0,
+            phase: true,
+        }
+    }
+}
+
+/// Submission Queue Entry
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Command {
+    /// Opcode, fused operation, PRP or SGL, Command Identifier
+    cdw0: u32,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    prp1: u64,
+    prp2: u64,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+}
+
+impl Command {
+    const fn new(opcode: u8) -> Self {
+        Self {
+            cdw0: opcode as u32,
+            nsid: 0,
+            cdw2: 0,
+            cdw3: 0,
+            metadata: 0,
+            prp1: 0,
+            prp2: 0,
+            cdw10: 0,
+            cdw11: 0,
+            cdw12: 0,
+            cdw13: 0,
+            cdw14: 0,
+            cdw15: 0,
+        }
+    }
+}
+
+/// Completion Queue Entry
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Completion {
+    result: u32,
+    reserved: u32,
+    sq_head: u16,
+    sq_id: u16,
+    command_id: u16,
+    /// Phase Tag and Status Field
+    status: u16,
+}
+
+#[repr(C, align(4096))]
+struct Page<T>(T);
+
+#[repr(C)]
+struct NvmeMemory {
+    admin_sq: Page<[Command; QUEUE_SIZE]>,
+    admin_cq: Page<[Completion; QUEUE_SIZE]>,
+    io_sq: Page<[Command; QUEUE_SIZE]>,
+    io_cq: Page<[Completion; QUEUE_SIZE]>,
+    prp_list: Page<[u64; PAGE_SIZE / size_of::<u64>()]>,
+    identify: Page<[u8; PAGE_SIZE]>,
+}
+
+impl NvmeMemory {
+    const fn new() -> Self {
+        const COMMAND: Command = Command::new(0);
+        const COMPLETION: Completion = Completion {
+            result: 0,
+            reserved: 0,
+            sq_head: 0,
+            sq_id: 0,
+            command_id: 0,
+            status: 0,
+        };
+        Self {
+            admin_sq: Page([COMMAND; QUEUE_SIZE]),
+            admin_cq: Page([COMPLETION; QUEUE_SIZE]),
+            io_sq: Page([COMMAND; QUEUE_SIZE]),
+            io_cq: Page([COMPLETION; QUEUE_SIZE]),
+            prp_list: Page([0; PAGE_SIZE / size_of::<u64>()]),
+            identify: Page([0; PAGE_SIZE]),
+        }
+    }
+}
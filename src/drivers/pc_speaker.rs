@@ -0,0 +1,146 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! PC speaker, driven by PIT channel 2
+//!
+//! Channel 2 runs as a square wave generator, bit 0 of port 0x61 gates it and bit 1 connects
+//! its output to the speaker. A cheap indicator for machines without a screen.
+//!
+//! https://wiki.osdev.org/PC_Speaker
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use super::{Driver, DriverError, Pit8254};
+use crate::fox_io::{self, Space};
+use crate::fox_port::{Pio, WriteOnlyAccess};
+use crate::fox_sync::without_interrupts;
+use crate::fox_time::delay;
+
+/// Set by [`Driver::init`]
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// PIT channel 2 and the gate bits of port 0x61
+#[derive(Debug, Default)]
+pub struct PcSpeaker;
+
+/// Beep patterns, told apart without looking at the machine
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Signal {
+    /// One short high beep
+    Success,
+    /// Three long low beeps
+    Failure,
+}
+
+impl Signal {
+    /// Frequency in Hz and length of each beep
+    fn tones(self) -> &'static [(u32, Duration)] {
+        const SHORT: Duration = Duration::from_millis(100);
+        const LONG: Duration = Duration::from_millis(300);
+        match self {
+            Self::Success => &[(1000, SHORT)],
+            Self::Failure => &[(400, LONG), (400, LONG), (400, LONG)],
+        }
+    }
+}
+
+impl PcSpeaker {
+    /// Between the beeps of a [`Signal`]
+    const PAUSE: Duration = Duration::from_millis(150);
+
+    /// Play `frequency` Hz for `duration`, clamped to what channel 2 can divide
+    pub fn beep(frequency: u32, duration: Duration) -> Result<(), DriverError> {
+        // log::trace!("PcSpeaker::beep({}, {:?})", frequency, duration);
+
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        let frequency = frequency.clamp(
+            Pit8254::BASE_FREQUENCY / 0x10000 + 1,
+            Pit8254::BASE_FREQUENCY,
+        );
+        set_channel2((Pit8254::BASE_FREQUENCY / frequency) as u16);
+        set_gate(true);
+        delay(duration);
+        set_gate(false);
+        Ok(())
+    }
+
+    pub fn signal(signal: Signal) -> Result<(), DriverError> {
+        // log::trace!("PcSpeaker::signal({:?})", signal);
+
+        for (i, &(frequency, duration)) in signal.tones().iter().enumerate() {
+            if i > 0 {
+                delay(Self::PAUSE);
+            }
+            Self::beep(frequency, duration)?;
+        }
+        Ok(())
+    }
+}
+
+impl Driver for PcSpeaker {
+    const DRIVER_NAME: &str = "pc_speaker";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("PcSpeaker::probe()");
+
+        // always present on a PC, if not always connected
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("PcSpeaker::init()");
+
+        set_gate(false);
+        fox_io::reserve(Space::Port, PORT_PIT_CHANNEL2.into(), 1, Self::DRIVER_NAME);
+        IS_ACTIVE.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("PcSpeaker::remove()");
+
+        IS_ACTIVE.store(false, Ordering::Release);
+        set_gate(false);
+        fox_io::release(Self::DRIVER_NAME);
+    }
+}
+
+const PORT_PIT_CHANNEL2: u16 = 0x0042;
+const PORT_PIT_CMD: u16 = 0x0043;
+/// NMI status and control, shared with the NMI and parity enables
+const PORT_SYSTEM_CONTROL_B: u16 = 0x0061;
+
+/// Channel 2, access mode lobyte/hibyte, mode 3 (square wave generator), binary
+fn set_channel2(reload: u16) {
+    let [lo, hi] = reload.to_le_bytes();
+    let mut port_cmd = Pio::<u8, WriteOnlyAccess>::new(PORT_PIT_CMD);
+    let mut port_channel2 = Pio::<u8>::new(PORT_PIT_CHANNEL2);
+    without_interrupts(|| {
+        // SAFETY: trust me
+        unsafe {
+            port_cmd.write(0b1011_0110);
+            port_channel2.write(lo);
+            port_channel2.write(hi);
+        }
+    });
+}
+
+/// Gate channel 2 and connect it to the speaker, or neither
+fn set_gate(is_enabled: bool) {
+    let mut port = Pio::<u8>::new(PORT_SYSTEM_CONTROL_B);
+    without_interrupts(|| {
+        // SAFETY: only the speaker bits change, the upper bits are read-only status
+        unsafe {
+            port.modify(|value| {
+                let value = value & 0x0F;
+                if is_enabled {
+                    value | 0b11
+                } else {
+                    value & !0b11
+                }
+            });
+        }
+    });
+}
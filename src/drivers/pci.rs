@@ -0,0 +1,814 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! PCI configuration space enumeration
+//!
+//! Configuration space is accessed through PCIe ECAM when the MCFG describes the bus, otherwise
+//! through configuration mechanism #1 (ports 0xCF8/0xCFC), which only reaches the first 256 bytes.
+//!
+//! https://wiki.osdev.org/PCI
+//! https://wiki.osdev.org/PCI_Express
+
+use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bit_field::BitField;
+
+use super::{Driver, DriverError};
+use crate::fox_acpi::mcfg;
+use crate::fox_port::Mmio;
+use crate::fox_port::Pio;
+use crate::fox_time::busy_wait_us;
+use crate::fox_vec::FixedVec;
+
+const MAX_DEVICES: usize = 128;
+/// Bridges nested deeper than this are not followed
+const MAX_DEPTH: usize = 8;
+
+/// Legacy (PCI) configuration space size
+const CONFIG_SIZE: u16 = 0x100;
+/// Extended (PCIe) configuration space size
+const EXTENDED_CONFIG_SIZE: u16 = 0x1000;
+
+const CAPABILITY_POWER_MANAGEMENT: u8 = 0x01;
+const CAPABILITY_MSI: u8 = 0x05;
+const CAPABILITY_MSIX: u8 = 0x11;
+
+/// Init [`Driver::init`]
+static DEVICES: AtomicPtr<FixedVec<PciDevice, MAX_DEVICES>> = AtomicPtr::new(null_mut());
+
+/// Storage for [`DEVICES`], written by [`Driver::init`]
+static mut DEVICES_STORAGE: FixedVec<PciDevice, MAX_DEVICES> = FixedVec::new();
+
+/// PCI bus enumerator
+#[derive(Default, Debug)]
+pub struct Pci;
+
+/// PCI Power Management capability
+#[derive(Copy, Clone, Debug)]
+pub struct PowerManagement {
+    address: PciAddress,
+    offset: u16,
+}
+
+/// Device power state
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PowerState {
+    D0,
+    D1,
+    D2,
+    D3Hot,
+}
+
+/// Message Signaled Interrupts capability
+#[derive(Copy, Clone, Debug)]
+pub struct Msi {
+    address: PciAddress,
+    offset: u16,
+    /// 64-bit message address
+    pub is_64: bool,
+    pub per_vector_mask: bool,
+    /// Vectors the function can request
+    pub max_vectors: u8,
+}
+
+/// MSI-X capability
+#[derive(Copy, Clone, Debug)]
+pub struct MsiX {
+    address: PciAddress,
+    offset: u16,
+    pub table_size: u16,
+    /// Vector table in a memory BAR, `None` if the BAR is not assigned
+    pub table_address: Option<u64>,
+}
+
+/// Bus, device, function
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+/// Base Address Register
+#[derive(Copy, Clone, Debug, Default)]
+pub enum Bar {
+    #[default]
+    None,
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    /// Without the multi-function bit: 0 general device, 1 PCI-to-PCI bridge, 2 CardBus bridge
+    pub header_type: u8,
+    pub bars: [Bar; 6],
+    /// Bus behind a PCI-to-PCI bridge
+    pub secondary_bus: Option<u8>,
+}
+
+impl Pci {
+    /// Devices found by [`Driver::init`]
+    pub fn devices() -> &'static [PciDevice] {
+        let ptr = DEVICES.load(Ordering::Acquire);
+        unsafe { ptr.as_ref() }.map_or(&[], |devices| devices.as_slice())
+    }
+
+    /// Functions of a base class and subclass
+    pub fn find_class(class: u8, subclass: u8) -> impl Iterator<Item = &'static PciDevice> {
+        Self::devices()
+            .iter()
+            .filter(move |i| i.class == class && i.subclass == subclass)
+    }
+
+    /// The extended configuration space (offsets 0x100..0x1000) is reachable
+    pub fn has_extended_config(address: PciAddress) -> bool {
+        ecam_base(address).is_some()
+    }
+
+    /// Mask and disable MSI and MSI-X on every function
+    ///
+    /// After exiting boot services, messages enabled by firmware drivers would hit vectors
+    /// that are ours now. INTx stays disabled or enabled as the firmware left it.
+    pub fn disable_msi() {
+        // log::trace!("Pci::disable_msi()");
+
+        for dev in Self::devices() {
+            let (msi, msix) = (dev.msi(), dev.msix());
+            if msi.is_none() && msix.is_none() {
+                continue;
+            }
+            let is_intx_disabled = read_command(dev.address).get_bit(10);
+            if let Some(msi) = msi {
+                if msi.is_enabled() {
+                    log::debug!("{}: {} disable MSI", Self::DRIVER_NAME, dev.address);
+                }
+                for vector in 0..msi.max_vectors {
+                    msi.set_masked(vector, true);
+                }
+                msi.set_enabled(false);
+            }
+            if let Some(msix) = msix {
+                if msix.is_enabled() {
+                    log::debug!("{}: {} disable MSI-X", Self::DRIVER_NAME, dev.address);
+                }
+                msix.set_function_masked(true);
+                for vector in 0..msix.table_size {
+                    msix.set_masked(vector, true);
+                }
+                msix.set_enabled(false);
+            }
+            set_intx_disabled(dev.address, is_intx_disabled);
+        }
+    }
+}
+
+impl Driver for Pci {
+    const DRIVER_NAME: &str = "pci";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Pci::probe()");
+
+        if ecam_base(PciAddress::new(0, 0, 0)).is_some() {
+            log::debug!("{}: Using ECAM", Self::DRIVER_NAME);
+            return Ok(());
+        }
+
+        // the enable bit of CONFIG_ADDRESS reads back only if mechanism #1 exists
+        let mut port_address = Pio::<u32>::new(PORT_CONFIG_ADDRESS);
+        // SAFETY: trust me
+        let value = unsafe {
+            let saved = port_address.read();
+            port_address.write(0x8000_0000);
+            let value = port_address.read();
+            port_address.write(saved);
+            value
+        };
+        if value != 0x8000_0000 {
+            log::warn!("{}: No configuration mechanism #1", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        log::debug!("{}: Using configuration mechanism #1", Self::DRIVER_NAME);
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Pci::init()");
+
+        let devices = &raw mut DEVICES_STORAGE;
+        // SAFETY: `DEVICES` is not published until the scan is done
+        let devices = unsafe { &mut *devices };
+
+        // a multi-function host bridge means one host controller per function and bus
+        let host = PciAddress::new(0, 0, 0);
+        if header_type(host).get_bit(7) {
+            for function in 0..8 {
+                if vendor_id(PciAddress::new(0, 0, function)) != 0xFFFF {
+                    scan_bus(devices, function, 0);
+                }
+            }
+        } else {
+            scan_bus(devices, 0, 0);
+        }
+
+        log::info!("{}: Found {} functions", Self::DRIVER_NAME, devices.len());
+        DEVICES.store(devices, Ordering::Release);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Pci::remove()");
+    }
+}
+
+impl PowerManagement {
+    /// PowerState field of the PMCSR
+    pub fn state(&self) -> PowerState {
+        match read_config(self.address, self.offset + 4).get_bits(0..2) {
+            0 => PowerState::D0,
+            1 => PowerState::D1,
+            2 => PowerState::D2,
+            _ => PowerState::D3Hot,
+        }
+    }
+
+    /// Change the power state, waiting the recovery time of D3hot
+    pub fn set_state(&self, state: PowerState) {
+        let previous = self.state();
+        let mut pmcsr = read_config(self.address, self.offset + 4);
+        // PME_Status is write-1-to-clear
+        pmcsr.set_bit(15, false);
+        pmcsr.set_bits(0..2, state as u32);
+        write_config(self.address, self.offset + 4, pmcsr);
+        if previous == PowerState::D3Hot || state == PowerState::D3Hot {
+            busy_wait_us(10_000);
+        }
+    }
+}
+
+impl Msi {
+    pub fn is_enabled(&self) -> bool {
+        read_config(self.address, self.offset).get_bit(16)
+    }
+
+    /// Also disables INTx while enabled
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut control = read_config(self.address, self.offset);
+        control.set_bit(16, enabled);
+        write_config(self.address, self.offset, control);
+        set_intx_disabled(self.address, enabled);
+    }
+
+    /// Returns `false` without per-vector masking
+    pub fn set_masked(&self, vector: u8, masked: bool) -> bool {
+        if !self.per_vector_mask || vector >= self.max_vectors {
+            return false;
+        }
+        let offset = self.offset + if self.is_64 { 0x10 } else { 0x0C };
+        let mut mask = read_config(self.address, offset);
+        mask.set_bit(vector as usize, masked);
+        write_config(self.address, offset, mask);
+        true
+    }
+}
+
+impl MsiX {
+    pub fn is_enabled(&self) -> bool {
+        read_config(self.address, self.offset).get_bit(31)
+    }
+
+    /// Also disables INTx while enabled
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut control = read_config(self.address, self.offset);
+        control.set_bit(31, enabled);
+        write_config(self.address, self.offset, control);
+        set_intx_disabled(self.address, enabled);
+    }
+
+    /// Function Mask: masks all vectors regardless of their own mask bits
+    pub fn set_function_masked(&self, masked: bool) {
+        let mut control = read_config(self.address, self.offset);
+        control.set_bit(30, masked);
+        write_config(self.address, self.offset, control);
+    }
+
+    /// Returns `false` if the vector table is not mapped
+    pub fn set_masked(&self, vector: u16, masked: bool) -> bool {
+        let Some(table) = self.table_address else {
+            return false;
+        };
+        if vector >= self.table_size {
+            return false;
+        }
+        // struct { u32 address_low; u32 address_high; u32 data; u32 vector_control; }
+        // UEFI identity-maps MMIO
+        let mut control = Mmio::<u32>::new(table + vector as u64 * 16 + 12);
+        // SAFETY: the table lies in a BAR assigned by the firmware
+        unsafe { control.modify(|mut value| *value.set_bit(0, masked)) };
+        true
+    }
+}
+
+impl PciAddress {
+    pub fn new(bus: u8, device: u8, function: u8) -> Self {
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02x}:{:02x}.{:x}",
+            self.bus, self.device, self.function
+        )
+    }
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Self {
+        let class = read_config(address, 0x08);
+        let header_type = header_type(address) & 0x7F;
+
+        let mut device = Self {
+            address,
+            vendor_id: vendor_id(address),
+            device_id: read_config(address, 0x00).get_bits(16..32) as u16,
+            class: class.get_bits(24..32) as u8,
+            subclass: class.get_bits(16..24) as u8,
+            prog_if: class.get_bits(8..16) as u8,
+            revision: class.get_bits(0..8) as u8,
+            header_type,
+            bars: Default::default(),
+            secondary_bus: None,
+        };
+
+        let bar_count = match device.header_type {
+            0x00 => 6,
+            0x01 => 2,
+            _ => 0,
+        };
+        let mut i = 0;
+        while i < bar_count {
+            let (bar, is_64) = read_bar(address, i);
+            device.bars[i] = bar;
+            i += if is_64 { 2 } else { 1 };
+        }
+
+        if device.header_type == 0x01 {
+            device.secondary_bus = Some(read_config(address, 0x18).get_bits(8..16) as u8);
+        }
+        device
+    }
+
+    /// `(id, offset)` of the capabilities in the legacy configuration space
+    pub fn capabilities(&self) -> impl Iterator<Item = (u8, u16)> {
+        let address = self.address;
+        // Capabilities List bit of the Status register
+        let mut offset = if read_config(address, 0x04).get_bit(20) {
+            read_config(address, 0x34) as u16 & 0xFC
+        } else {
+            0
+        };
+        // a broken list must not loop forever
+        let mut remaining = (CONFIG_SIZE - 0x40) / 4;
+        core::iter::from_fn(move || {
+            if offset < 0x40 || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let header = read_config(address, offset);
+            let capability = (header.get_bits(0..8) as u8, offset);
+            offset = header.get_bits(8..16) as u16 & 0xFC;
+            Some(capability)
+        })
+    }
+
+    /// `(id, offset)` of the capabilities in the extended configuration space
+    ///
+    /// Empty without ECAM.
+    pub fn extended_capabilities(&self) -> impl Iterator<Item = (u16, u16)> {
+        let address = self.address;
+        let mut offset = if Pci::has_extended_config(address) {
+            CONFIG_SIZE
+        } else {
+            0
+        };
+        let mut remaining = (EXTENDED_CONFIG_SIZE - CONFIG_SIZE) / 4;
+        core::iter::from_fn(move || {
+            if offset < CONFIG_SIZE || remaining == 0 {
+                return None;
+            }
+            remaining -= 1;
+            let header = read_config(address, offset);
+            // no extended capabilities at all
+            if header == 0 || header == 0xFFFF_FFFF {
+                return None;
+            }
+            let capability = (header.get_bits(0..16) as u16, offset);
+            offset = header.get_bits(20..32) as u16 & 0xFFC;
+            Some(capability)
+        })
+    }
+
+    /// Dword at `offset` of the configuration space, for capabilities of a device class
+    pub fn read_config(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    /// Enable memory space decoding and bus mastering (DMA)
+    pub fn enable_bus_master(&self) {
+        let mut command = read_command(self.address);
+        command.set_bit(1, true);
+        command.set_bit(2, true);
+        write_command(self.address, command);
+    }
+
+    pub fn power_management(&self) -> Option<PowerManagement> {
+        let (_, offset) = self
+            .capabilities()
+            .find(|(id, _)| *id == CAPABILITY_POWER_MANAGEMENT)?;
+        Some(PowerManagement {
+            address: self.address,
+            offset,
+        })
+    }
+
+    pub fn msi(&self) -> Option<Msi> {
+        let (_, offset) = self.capabilities().find(|(id, _)| *id == CAPABILITY_MSI)?;
+        let control = read_config(self.address, offset).get_bits(16..32);
+        Some(Msi {
+            address: self.address,
+            offset,
+            is_64: control.get_bit(7),
+            per_vector_mask: control.get_bit(8),
+            max_vectors: 1 << control.get_bits(1..4).min(5),
+        })
+    }
+
+    pub fn msix(&self) -> Option<MsiX> {
+        let (_, offset) = self.capabilities().find(|(id, _)| *id == CAPABILITY_MSIX)?;
+        let control = read_config(self.address, offset).get_bits(16..32);
+        let table = read_config(self.address, offset + 4);
+        let table_address = match self.bars.get(table.get_bits(0..3) as usize) {
+            Some(Bar::Memory { address, .. }) if *address != 0 => {
+                Some(address + (table & !0b111) as u64)
+            }
+            _ => None,
+        };
+        Some(MsiX {
+            address: self.address,
+            offset,
+            table_size: control.get_bits(0..11) as u16 + 1,
+            table_address,
+        })
+    }
+
+    /// lspci-style description of the class
+    pub fn class_name(&self) -> &'static str {
+        match (self.class, self.subclass) {
+            (0x01, 0x00) => "SCSI storage controller",
+            (0x01, 0x01) => "IDE interface",
+            (0x01, 0x05) => "ATA controller",
+            (0x01, 0x06) => "SATA controller",
+            (0x01, 0x08) => "Non-Volatile memory controller",
+            (0x01, _) => "Mass storage controller",
+            (0x02, 0x00) => "Ethernet controller",
+            (0x02, _) => "Network controller",
+            (0x03, 0x00) => "VGA compatible controller",
+            (0x03, _) => "Display controller",
+            (0x04, _) => "Multimedia controller",
+            (0x05, _) => "Memory controller",
+            (0x06, 0x00) => "Host bridge",
+            (0x06, 0x01) => "ISA bridge",
+            (0x06, 0x04) => "PCI bridge",
+            (0x06, _) => "Bridge",
+            (0x07, _) => "Communication controller",
+            (0x08, _) => "System peripheral",
+            (0x0C, 0x03) => "USB controller",
+            (0x0C, 0x05) => "SMBus",
+            (0x0C, _) => "Serial bus controller",
+            (0x0D, _) => "Wireless controller",
+            _ => "Unclassified device",
+        }
+    }
+}
+
+const PORT_CONFIG_ADDRESS: u16 = 0x0CF8;
+const PORT_CONFIG_DATA: u16 = 0x0CFC;
+
+/// Enumerate a bus, following bridges depth-first
+fn scan_bus(devices: &mut FixedVec<PciDevice, MAX_DEVICES>, bus: u8, depth: usize) {
+    for device in 0..32 {
+        let address = PciAddress::new(bus, device, 0);
+        if vendor_id(address) == 0xFFFF {
+            continue;
+        }
+        let functions = if header_type(address).get_bit(7) {
+            8
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let address = PciAddress::new(bus, device, function);
+            if vendor_id(address) == 0xFFFF {
+                continue;
+            }
+
+            let dev = PciDevice::read(address);
+            log_device(&dev, depth);
+            if devices.push(dev).is_err() {
+                log::warn!(
+                    "{}: too many devices, {} ignored",
+                    Pci::DRIVER_NAME,
+                    address
+                );
+            }
+
+            if let Some(secondary_bus) = dev.secondary_bus {
+                // a bus number not above ours is an unconfigured bridge, not a loop to follow
+                if secondary_bus > bus && depth < MAX_DEPTH {
+                    scan_bus(devices, secondary_bus, depth + 1);
+                }
+            }
+        }
+    }
+}
+
+fn log_device(dev: &PciDevice, depth: usize) {
+    let indent = depth * 2;
+    log::info!(
+        "{}: {:indent$}{} {} [{:02x}{:02x}]: [{:04x}:{:04x}] (rev {:02x}, prog-if {:02x})",
+        Pci::DRIVER_NAME,
+        "",
+        dev.address,
+        dev.class_name(),
+        dev.class,
+        dev.subclass,
+        dev.vendor_id,
+        dev.device_id,
+        dev.revision,
+        dev.prog_if,
+        indent = indent
+    );
+    for (i, bar) in dev.bars.iter().enumerate() {
+        match *bar {
+            Bar::None => {}
+            Bar::Memory {
+                address,
+                size,
+                prefetchable,
+            } => log::debug!(
+                "{}: {:indent$}  BAR{}: memory at {:#x} (size {:#x}{})",
+                Pci::DRIVER_NAME,
+                "",
+                i,
+                address,
+                size,
+                if prefetchable { ", prefetchable" } else { "" },
+                indent = indent
+            ),
+            Bar::Io { port, size } => log::debug!(
+                "{}: {:indent$}  BAR{}: I/O ports at {:#x} (size {:#x})",
+                Pci::DRIVER_NAME,
+                "",
+                i,
+                port,
+                size,
+                indent = indent
+            ),
+        }
+    }
+    if let Some(pm) = dev.power_management() {
+        log::debug!(
+            "{}: {:indent$}  Power Management: {:?}",
+            Pci::DRIVER_NAME,
+            "",
+            pm.state(),
+            indent = indent
+        );
+    }
+    if let Some(msi) = dev.msi() {
+        log::debug!(
+            "{}: {:indent$}  MSI: {} vectors{}{}",
+            Pci::DRIVER_NAME,
+            "",
+            msi.max_vectors,
+            if msi.is_64 { ", 64-bit" } else { "" },
+            if msi.per_vector_mask {
+                ", maskable"
+            } else {
+                ""
+            },
+            indent = indent
+        );
+    }
+    if let Some(msix) = dev.msix() {
+        log::debug!(
+            "{}: {:indent$}  MSI-X: {} vectors, table at {:#x?}",
+            Pci::DRIVER_NAME,
+            "",
+            msix.table_size,
+            msix.table_address,
+            indent = indent
+        );
+    }
+    for (id, offset) in dev.capabilities() {
+        log::debug!(
+            "{}: {:indent$}  Capability {:#04x} at {:#x}",
+            Pci::DRIVER_NAME,
+            "",
+            id,
+            offset,
+            indent = indent
+        );
+    }
+    for (id, offset) in dev.extended_capabilities() {
+        log::debug!(
+            "{}: {:indent$}  Extended capability {:#06x} at {:#x}",
+            Pci::DRIVER_NAME,
+            "",
+            id,
+            offset,
+            indent = indent
+        );
+    }
+    if let Some(secondary_bus) = dev.secondary_bus {
+        log::info!(
+            "{}: {:indent$}  -> bus {:02x}",
+            Pci::DRIVER_NAME,
+            "",
+            secondary_bus,
+            indent = indent
+        );
+    }
+}
+
+fn vendor_id(address: PciAddress) -> u16 {
+    read_config(address, 0x00).get_bits(0..16) as u16
+}
+
+fn header_type(address: PciAddress) -> u8 {
+    read_config(address, 0x0C).get_bits(16..24) as u8
+}
+
+/// Decode and size a BAR, returns whether it takes two slots (64-bit)
+fn read_bar(address: PciAddress, index: usize) -> (Bar, bool) {
+    let offset = 0x10 + index as u16 * 4;
+    let value = read_config(address, offset);
+    if value == 0 {
+        return (Bar::None, false);
+    }
+
+    // decoding must be off while the BAR holds all ones
+    let command = read_command(address);
+    write_command(address, command & !0b11);
+
+    let bar = if value.get_bit(0) {
+        write_config(address, offset, 0xFFFF_FFFF);
+        let mask = read_config(address, offset) & !0b11;
+        write_config(address, offset, value);
+        let size = (!mask).wrapping_add(1) as u16;
+        (
+            Bar::Io {
+                port: (value & !0b11) as u16,
+                size,
+            },
+            false,
+        )
+    } else {
+        let is_64 = value.get_bits(1..3) == 0b10;
+        let high = if is_64 {
+            read_config(address, offset + 4)
+        } else {
+            0
+        };
+
+        write_config(address, offset, 0xFFFF_FFFF);
+        let mask_low = read_config(address, offset) & !0b1111;
+        write_config(address, offset, value);
+        let mask_high = if is_64 {
+            write_config(address, offset + 4, 0xFFFF_FFFF);
+            let mask = read_config(address, offset + 4);
+            write_config(address, offset + 4, high);
+            mask
+        } else {
+            0xFFFF_FFFF
+        };
+
+        let mask = (mask_high as u64) << 32 | mask_low as u64;
+        (
+            Bar::Memory {
+                address: (high as u64) << 32 | (value & !0b1111) as u64,
+                size: (!mask).wrapping_add(1),
+                prefetchable: value.get_bit(3),
+            },
+            is_64,
+        )
+    };
+
+    write_command(address, command);
+    bar
+}
+
+/// Interrupt Disable bit of the Command register
+fn set_intx_disabled(address: PciAddress, disabled: bool) {
+    let mut command = read_command(address);
+    command.set_bit(10, disabled);
+    write_command(address, command);
+}
+
+/// Address of the configuration space of a function, if the MCFG describes its bus
+fn ecam_base(address: PciAddress) -> Option<u64> {
+    let entry = mcfg()?.find(0, address.bus)?;
+    let offset = ((address.bus - entry.start_bus) as u64) << 20
+        | (address.device as u64) << 15
+        | (address.function as u64) << 12;
+    Some(entry.base_address + offset)
+}
+
+fn config_address(address: PciAddress, offset: u16) -> u32 {
+    let mut value = 0u32;
+    value.set_bit(31, true);
+    value.set_bits(16..24, address.bus as u32);
+    value.set_bits(11..16, address.device as u32);
+    value.set_bits(8..11, address.function as u32);
+    value.set_bits(2..8, (offset >> 2) as u32);
+    value
+}
+
+/// Read a dword register, `offset` must be aligned
+///
+/// The extended configuration space reads as all ones without ECAM, like a missing register.
+pub(super) fn read_config(address: PciAddress, offset: u16) -> u32 {
+    if let Some(base) = ecam_base(address) {
+        // UEFI identity-maps MMIO
+        let register = Mmio::<u32>::new(base + offset as u64);
+        // SAFETY: the region is reserved by the firmware for configuration space
+        return unsafe { register.read() };
+    }
+    if offset >= CONFIG_SIZE {
+        return 0xFFFF_FFFF;
+    }
+
+    let mut port_address = Pio::<u32>::new(PORT_CONFIG_ADDRESS);
+    let port_data = Pio::<u32>::new(PORT_CONFIG_DATA);
+    // SAFETY: trust me
+    unsafe {
+        port_address.write(config_address(address, offset));
+        port_data.read()
+    }
+}
+
+/// The Command register, the low half of the dword at 0x04
+fn read_command(address: PciAddress) -> u16 {
+    read_config(address, 0x04) as u16
+}
+
+/// Write the Command register, with zeros in Status: its bits are write-1-to-clear
+fn write_command(address: PciAddress, command: u16) {
+    write_config(address, 0x04, command as u32);
+}
+
+/// Write a dword register, `offset` must be aligned
+///
+/// Writes to the extended configuration space are dropped without ECAM.
+pub(super) fn write_config(address: PciAddress, offset: u16, value: u32) {
+    if let Some(base) = ecam_base(address) {
+        // UEFI identity-maps MMIO
+        let mut register = Mmio::<u32>::new(base + offset as u64);
+        // SAFETY: the region is reserved by the firmware for configuration space
+        unsafe { register.write(value) };
+        return;
+    }
+    if offset >= CONFIG_SIZE {
+        return;
+    }
+
+    let mut port_address = Pio::<u32>::new(PORT_CONFIG_ADDRESS);
+    let mut port_data = Pio::<u32>::new(PORT_CONFIG_DATA);
+    // SAFETY: trust me
+    unsafe {
+        port_address.write(config_address(address, offset));
+        port_data.write(value);
+    }
+}
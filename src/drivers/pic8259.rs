@@ -0,0 +1,127 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! 8259 Programmable Interrupt Controller (master + slave)
+//!
+//! https://wiki.osdev.org/8259_PIC
+
+use super::{Driver, DriverError};
+use crate::fox_acpi::madt;
+use crate::fox_port::{MmioWriteAccess, Pio, WriteOnlyAccess};
+
+/// Legacy PIC pair
+#[derive(Default, Debug)]
+pub struct Pic8259 {
+    /// Masks set by the firmware, restored by [`Driver::remove`]
+    saved_masks: Option<u16>,
+}
+
+impl Pic8259 {
+    /// Vector of IRQ 0 after remapping (IRQ 0-15 -> 0x20-0x2F)
+    pub const OFFSET: u8 = 0x20;
+
+    /// IRQ of the slave PIC on the master
+    const IRQ_CASCADE: u8 = 2;
+
+    /// Disable an IRQ line
+    pub fn mask(irq: u8) {
+        let mask = get_masks() | 1 << irq;
+        set_masks(mask);
+    }
+
+    /// Enable an IRQ line (and the cascade for IRQ 8-15)
+    pub fn unmask(irq: u8) {
+        let mut mask = get_masks() & !(1 << irq);
+        if irq >= 8 {
+            mask &= !(1 << Self::IRQ_CASCADE);
+        }
+        set_masks(mask);
+    }
+
+    /// Must be sent at the end of every IRQ handler
+    pub fn notify_end_of_interrupt(irq: u8) {
+        if irq >= 8 {
+            port_write(PORT_PIC2_CMD, EOI);
+        }
+        port_write(PORT_PIC1_CMD, EOI);
+    }
+}
+
+impl Driver for Pic8259 {
+    const DRIVER_NAME: &str = "pic8259";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Pic8259::probe()");
+
+        // PCAT_COMPAT: the system also has a PC-AT-compatible dual-8259 setup
+        if madt().is_some_and(|madt| !madt.has_8259) {
+            log::warn!("{}: No controller found", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Pic8259::init()");
+
+        self.saved_masks = Some(get_masks());
+
+        // ICW1: initialization, ICW4 needed
+        port_write(PORT_PIC1_CMD, 0x11);
+        port_write(PORT_PIC2_CMD, 0x11);
+        // ICW2: vector offsets
+        port_write(PORT_PIC1_DATA, Self::OFFSET);
+        port_write(PORT_PIC2_DATA, Self::OFFSET + 8);
+        // ICW3: slave PIC at IRQ2, slave cascade identity
+        port_write(PORT_PIC1_DATA, 1 << Self::IRQ_CASCADE);
+        port_write(PORT_PIC2_DATA, Self::IRQ_CASCADE);
+        // ICW4: 8086 mode
+        port_write(PORT_PIC1_DATA, 0x01);
+        port_write(PORT_PIC2_DATA, 0x01);
+
+        // mask everything, drivers unmask what they handle
+        set_masks(0xFFFF);
+
+        log::info!(
+            "{}: Remapped to {:#X}-{:#X}",
+            Self::DRIVER_NAME,
+            Self::OFFSET,
+            Self::OFFSET + 15
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Pic8259::remove()");
+
+        set_masks(self.saved_masks.take().unwrap_or(0xFFFF));
+    }
+}
+
+/// End-of-interrupt command code
+const EOI: u8 = 0x20;
+
+const PORT_PIC1_CMD: Pio<u8, WriteOnlyAccess> = Pio::new(0x0020);
+const PORT_PIC1_DATA: Pio<u8> = Pio::new(0x0021);
+const PORT_PIC2_CMD: Pio<u8, WriteOnlyAccess> = Pio::new(0x00A0);
+const PORT_PIC2_DATA: Pio<u8> = Pio::new(0x00A1);
+
+/// Interrupt Mask Registers of both PICs (slave in the high byte)
+fn get_masks() -> u16 {
+    let pic1_data = PORT_PIC1_DATA;
+    let pic2_data = PORT_PIC2_DATA;
+    // SAFETY: trust me
+    let (lo, hi) = unsafe { (pic1_data.read(), pic2_data.read()) };
+    u16::from_le_bytes([lo, hi])
+}
+
+fn set_masks(mask: u16) {
+    let [lo, hi] = mask.to_le_bytes();
+    port_write(PORT_PIC1_DATA, lo);
+    port_write(PORT_PIC2_DATA, hi);
+}
+
+fn port_write<A: MmioWriteAccess>(port: Pio<u8, A>, value: u8) {
+    let mut port = port;
+    // SAFETY: trust me
+    unsafe { port.write(value) };
+}
@@ -0,0 +1,181 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! 8254 Programmable Interval Timer
+//!
+//! https://wiki.osdev.org/Programmable_Interval_Timer
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::instructions::{hlt, interrupts};
+use x86_64::structures::idt::InterruptStackFrame;
+
+use super::{Driver, DriverError};
+use crate::fox_interrupts;
+use crate::fox_port::{Pio, WriteOnlyAccess};
+use crate::fox_uefi::is_boot_services_active;
+
+/// IRQ 0 count since [`Driver::init`]
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Tick rate in Hz, 0 while channel 0 is not generating interrupts
+static FREQUENCY: AtomicU32 = AtomicU32::new(0);
+
+/// Channel 0 as the system tick
+#[derive(Debug)]
+pub struct Pit8254 {
+    /// Requested tick rate, Hz
+    frequency: u32,
+}
+
+impl Default for Pit8254 {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_FREQUENCY)
+    }
+}
+
+impl Pit8254 {
+    /// PIT input clock, Hz
+    pub const BASE_FREQUENCY: u32 = 1_193_182;
+    /// 1 ms tick
+    pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+    pub const IRQ: u8 = 0;
+
+    /// `frequency` is clamped to what the 16-bit divisor can express (19-1193182 Hz)
+    pub fn new(frequency: u32) -> Self {
+        let frequency = frequency.clamp(Self::BASE_FREQUENCY / 0x10000 + 1, Self::BASE_FREQUENCY);
+        Self { frequency }
+    }
+
+    /// The tick is generating interrupts and they are enabled, so [`Self::ticks`] advances
+    pub fn is_running() -> bool {
+        FREQUENCY.load(Ordering::Acquire) != 0 && interrupts::are_enabled()
+    }
+
+    /// Monotonic tick counter, stays 0 until the driver is initialized
+    pub fn ticks() -> u64 {
+        TICKS.load(Ordering::Relaxed)
+    }
+
+    /// Time since [`Driver::init`]
+    pub fn uptime() -> Duration {
+        let frequency = FREQUENCY.load(Ordering::Acquire);
+        if frequency == 0 {
+            return Duration::ZERO;
+        }
+        let ticks = Self::ticks();
+        Duration::from_secs(ticks / frequency as u64)
+            + Duration::from_nanos(ticks % frequency as u64 * 1_000_000_000 / frequency as u64)
+    }
+
+    /// Halt until enough ticks have passed
+    ///
+    /// Falls back to [`Self::poll_delay`] while the tick is not running or interrupts are disabled.
+    pub fn sleep(duration: Duration) {
+        if !Self::is_running() {
+            Self::poll_delay(duration);
+            return;
+        }
+        let frequency = FREQUENCY.load(Ordering::Acquire);
+
+        // round up, sleeping a bit longer is fine
+        let ticks = (duration.as_nanos() * frequency as u128).div_ceil(1_000_000_000) as u64;
+        let target = Self::ticks() + ticks;
+        while Self::ticks() < target {
+            hlt();
+        }
+    }
+
+    /// Poll channel 0 running as a free rate generator
+    ///
+    /// The firmware timer is gone after exiting boot services, so channel 0 can be reprogrammed.
+    /// A running tick is restored afterwards, the ticks of the delay are not counted.
+    pub fn poll_delay(duration: Duration) {
+        let ticks = duration.as_nanos() * Self::BASE_FREQUENCY as u128 / 1_000_000_000;
+
+        // reload value 0 means 0x10000
+        set_channel0(0);
+
+        let mut last = read_counter();
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            let now = read_counter();
+            // the counter counts down and wraps around
+            elapsed += last.wrapping_sub(now) as u128;
+            last = now;
+        }
+
+        let frequency = FREQUENCY.load(Ordering::Acquire);
+        if let Some(divisor) = Self::BASE_FREQUENCY.checked_div(frequency) {
+            set_channel0(divisor as u16);
+        }
+    }
+}
+
+impl Driver for Pit8254 {
+    const DRIVER_NAME: &str = "pit8254";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Pit8254::probe()");
+
+        // always present on a PC
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Pit8254::init()");
+        assert!(!is_boot_services_active());
+
+        let divisor = (Self::BASE_FREQUENCY / self.frequency) as u16;
+        set_channel0(divisor);
+
+        TICKS.store(0, Ordering::Relaxed);
+        FREQUENCY.store(self.frequency, Ordering::Release);
+        fox_interrupts::set_irq_handler(Self::IRQ, timer_interrupt_handler);
+
+        log::info!("{}: {} Hz tick", Self::DRIVER_NAME, self.frequency);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Pit8254::remove()");
+
+        fox_interrupts::clear_irq_handler(Self::IRQ);
+        FREQUENCY.store(0, Ordering::Release);
+    }
+}
+
+const PORT_PIT_CHANNEL0: u16 = 0x0040;
+const PORT_PIT_CMD: u16 = 0x0043;
+
+/// Channel 0, access mode lobyte/hibyte, mode 2 (rate generator), binary
+fn set_channel0(reload: u16) {
+    let [lo, hi] = reload.to_le_bytes();
+    let mut port_cmd = Pio::<u8, WriteOnlyAccess>::new(PORT_PIT_CMD);
+    let mut port_channel0 = Pio::<u8>::new(PORT_PIT_CHANNEL0);
+    // SAFETY: trust me
+    unsafe {
+        port_cmd.write(0b0011_0100);
+        port_channel0.write(lo);
+        port_channel0.write(hi);
+    }
+}
+
+fn read_counter() -> u16 {
+    let mut port_cmd = Pio::<u8, WriteOnlyAccess>::new(PORT_PIT_CMD);
+    let port_channel0 = Pio::<u8>::new(PORT_PIT_CHANNEL0);
+    // SAFETY: trust me
+    unsafe {
+        // counter latch command for channel 0
+        port_cmd.write(0b0000_0000);
+        let lo = port_channel0.read();
+        let hi = port_channel0.read();
+        u16::from_le_bytes([lo, hi])
+    }
+}
+
+extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    fox_interrupts::end_of_interrupt(Pit8254::IRQ);
+}
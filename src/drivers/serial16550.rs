@@ -0,0 +1,161 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! 16550 UART
+//!
+//! https://wiki.osdev.org/Serial_Ports
+
+use core::fmt;
+
+use super::{Driver, DriverError};
+use crate::fox_port::Pio;
+use crate::fox_watchdog;
+
+/// 16550 UART
+#[derive(Debug)]
+pub struct Serial16550 {
+    base: u16,
+    baud: u32,
+}
+
+impl Serial16550 {
+    pub const COM1: u16 = 0x03F8;
+
+    /// UART input clock divided by 16
+    const MAX_BAUD: u32 = 115200;
+
+    pub fn new(base: u16, baud: u32) -> Self {
+        Self { base, baud }
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    /// Busy-wait until the transmitter can accept a byte, or the [`fox_watchdog`] expires
+    pub fn write_byte(&mut self, value: u8) {
+        while !self.line_status().transmitter_holding_register_empty()
+            && !fox_watchdog::is_output_expired()
+        {}
+        self.port_write(Register::Data, value);
+    }
+
+    fn line_status(&self) -> LineStatus {
+        LineStatus(self.port_read(Register::LineStatus))
+    }
+
+    fn port_read(&self, register: Register) -> u8 {
+        let port = Pio::<u8>::new(self.base + register as u16);
+        // SAFETY: trust me
+        unsafe { port.read() }
+    }
+
+    fn port_write(&self, register: Register, value: u8) {
+        let mut port = Pio::<u8>::new(self.base + register as u16);
+        // SAFETY: trust me
+        unsafe { port.write(value) };
+    }
+}
+
+impl Default for Serial16550 {
+    fn default() -> Self {
+        Self::new(Self::COM1, Self::MAX_BAUD)
+    }
+}
+
+impl Driver for Serial16550 {
+    const DRIVER_NAME: &str = "serial16550";
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Serial16550::probe()");
+
+        // The scratch register keeps the written value only if the UART exists
+        let uart = Self::default();
+        uart.port_write(Register::Scratch, 0xAE);
+        match uart.port_read(Register::Scratch) {
+            0xAE => Ok(()),
+            _ => Err(DriverError::NoHardware),
+        }
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Serial16550::init()");
+
+        let divisor = (Self::MAX_BAUD / self.baud.clamp(1, Self::MAX_BAUD)) as u16;
+        let [divisor_lo, divisor_hi] = divisor.to_le_bytes();
+
+        // Disable all interrupts
+        self.port_write(Register::InterruptEnable, 0x00);
+        // Enable DLAB (set baud rate divisor)
+        self.port_write(Register::LineControl, 0x80);
+        self.port_write(Register::Data, divisor_lo);
+        self.port_write(Register::InterruptEnable, divisor_hi);
+        // 8 bits, no parity, one stop bit (DLAB off)
+        self.port_write(Register::LineControl, 0x03);
+        // Enable FIFO, clear them, with 14-byte threshold
+        self.port_write(Register::FifoControl, 0xC7);
+        // Set in loopback mode, test the serial chip
+        self.port_write(Register::ModemControl, 0x1E);
+        self.port_write(Register::Data, 0xAE);
+        match self.port_read(Register::Data) {
+            0xAE => {}
+            code => return Err(DriverError::SelfTestFailed { code }),
+        }
+        // Normal operation mode (not-loopback with IRQs disabled and OUT#1 and OUT#2 bits enabled)
+        self.port_write(Register::ModemControl, 0x0F);
+
+        log::info!(
+            "{}: Found UART at {:#X}, {} baud",
+            Self::DRIVER_NAME,
+            self.base,
+            Self::MAX_BAUD / divisor as u32
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Serial16550::remove()");
+    }
+}
+
+impl fmt::Write for Serial16550 {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for i in s.bytes() {
+            if i == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(i);
+        }
+        Ok(())
+    }
+}
+
+/// Offsets from the base port
+#[repr(u16)]
+#[derive(Copy, Clone)]
+enum Register {
+    /// Data register (DLAB = 0) or divisor low byte (DLAB = 1)
+    Data = 0,
+    /// Interrupt Enable Register (DLAB = 0) or divisor high byte (DLAB = 1)
+    InterruptEnable = 1,
+    /// FIFO Control Register (write)
+    FifoControl = 2,
+    /// Line Control Register (the most significant bit is the DLAB)
+    LineControl = 3,
+    /// Modem Control Register
+    ModemControl = 4,
+    /// Line Status Register
+    LineStatus = 5,
+    /// Scratch Register
+    Scratch = 7,
+}
+
+/// Line Status Register
+#[derive(Copy, Clone)]
+struct LineStatus(u8);
+
+impl LineStatus {
+    /// Transmitter holding register empty (data can be sent)
+    fn transmitter_holding_register_empty(&self) -> bool {
+        self.0 & 0x20 != 0
+    }
+}
@@ -0,0 +1,559 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! virtio network device, virtio 1.0 PCI transport
+//!
+//! The common, notify, ISR and device configuration structures are found through vendor
+//! capabilities. One receive and one transmit split virtqueue, polled without interrupts, a
+//! frame per descriptor. Memory is identity-mapped, so the queues and buffers in a static are
+//! used for DMA as they are. [`VirtioNet::nic`] hands out the [`Nic`] for [`crate::fox_net`].
+//!
+//! https://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html
+
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering, compiler_fence};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::pci::{Bar, Pci, PciDevice};
+use super::{Dependency, Driver, DriverError};
+use crate::fox_mem::map_mmio;
+use crate::fox_net::{MacAddress, Nic};
+use crate::fox_port::MmioBlock;
+use crate::fox_time::poll_timeout;
+
+const QUEUE_SIZE: usize = 16;
+/// Header and a whole frame
+const BUFFER_SIZE: usize = 2048;
+/// `virtio_net_hdr` with `num_buffers`, always present with VIRTIO_F_VERSION_1
+const NET_HEADER_SIZE: usize = 12;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+const TIMEOUT_RESET: Duration = Duration::from_millis(100);
+/// Until the device has used a transmitted buffer
+const TIMEOUT_TRANSMIT: Duration = Duration::from_millis(100);
+
+/// Set by [`Driver::init`], the [`VirtioNetNic`] fails without it
+static IS_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Virtqueues and packet buffers, used by DMA
+static mut MEMORY: VirtioMemory = VirtioMemory::new();
+
+/// virtio network device
+#[derive(Default)]
+pub struct VirtioNet {
+    registers: Registers,
+    /// Negotiated feature bits
+    features: u64,
+    mac: MacAddress,
+}
+
+/// Frames through an initialized [`VirtioNet`], the ring positions are kept in its memory
+pub struct VirtioNetNic {
+    registers: Registers,
+    mac: MacAddress,
+}
+
+/// Mapped configuration structures
+#[derive(Copy, Clone, Debug, Default)]
+struct Registers {
+    common: MmioBlock,
+    /// Notification address of queue 0
+    notify: MmioBlock,
+    notify_multiplier: u32,
+    isr: MmioBlock,
+    device: MmioBlock,
+}
+
+impl VirtioNet {
+    /// VIRTIO_NET_S_LINK_UP, always up without VIRTIO_NET_F_STATUS
+    pub fn is_link_up(&self) -> bool {
+        // SAFETY: the device configuration is mapped by `init`
+        !self.features.get_bit(FEATURE_STATUS)
+            || unsafe { self.registers.device.read::<u16>(6) }.get_bit(0)
+    }
+
+    /// The device for [`crate::fox_net::set_interface`], fails once the driver is removed
+    pub fn nic(&self) -> VirtioNetNic {
+        VirtioNetNic {
+            registers: self.registers,
+            mac: self.mac,
+        }
+    }
+
+    /// Allocate `queue` in `memory` and tell the device
+    fn setup_queue(&self, index: u16, queue: &mut Virtqueue) -> Result<(), DriverError> {
+        let common = self.registers.common;
+        // SAFETY: the common configuration is mapped by `init`
+        unsafe {
+            common.write(COMMON_QUEUE_SELECT, index);
+            let size = common.read::<u16>(COMMON_QUEUE_SIZE) as usize;
+            if size < QUEUE_SIZE {
+                log::error!(
+                    "{}: queue {} has {} entries",
+                    Self::DRIVER_NAME,
+                    index,
+                    size
+                );
+                return Err(DriverError::NoHardware);
+            }
+            common.write(COMMON_QUEUE_SIZE, QUEUE_SIZE as u16);
+            // no MSI-X vector
+            common.write(COMMON_QUEUE_MSIX_VECTOR, 0xFFFF_u16);
+            write64(
+                common,
+                COMMON_QUEUE_DESC,
+                queue.descriptors.0.as_ptr() as u64,
+            );
+            write64(
+                common,
+                COMMON_QUEUE_DRIVER,
+                &raw const queue.available.0 as u64,
+            );
+            write64(common, COMMON_QUEUE_DEVICE, &raw const queue.used.0 as u64);
+            common.write(COMMON_QUEUE_ENABLE, 1_u16);
+        }
+        Ok(())
+    }
+
+    fn set_status(&self, status: u8) {
+        // SAFETY: the common configuration is mapped by `init`
+        unsafe { self.registers.common.write(COMMON_DEVICE_STATUS, status) };
+    }
+
+    fn status(&self) -> u8 {
+        // SAFETY: the common configuration is mapped by `init`
+        unsafe { self.registers.common.read(COMMON_DEVICE_STATUS) }
+    }
+}
+
+impl Driver for VirtioNet {
+    const DRIVER_NAME: &str = "virtio_net";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Driver(Pci::DRIVER_NAME)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("VirtioNet::probe()");
+
+        if controller().is_none() {
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("VirtioNet::init()");
+
+        let dev = controller().ok_or(DriverError::NoHardware)?;
+        self.registers = Registers::map(dev)?;
+        log::info!(
+            "{}: {:04x} at {}",
+            Self::DRIVER_NAME,
+            dev.device_id,
+            dev.address
+        );
+        dev.enable_bus_master();
+
+        // reset, then the initialization sequence of 3.1.1
+        self.set_status(0);
+        poll_timeout(TIMEOUT_RESET, || (self.status() == 0).then_some(()))
+            .ok_or(DriverError::Timeout)?;
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let offered = self.registers.read_features();
+        if !offered.get_bit(FEATURE_VERSION_1) {
+            log::error!("{}: no VIRTIO_F_VERSION_1", Self::DRIVER_NAME);
+            self.set_status(STATUS_FAILED);
+            return Err(DriverError::NoHardware);
+        }
+        let wanted = 1 << FEATURE_VERSION_1 | 1 << FEATURE_MAC | 1 << FEATURE_STATUS;
+        self.features = offered & wanted;
+        self.registers.write_features(self.features);
+        let status = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+        self.set_status(status);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.set_status(STATUS_FAILED);
+            return Err(DriverError::UnexpectedResponse {
+                byte: self.status(),
+            });
+        }
+
+        let memory = &raw mut MEMORY;
+        // SAFETY: the device is reset and not using the memory
+        let memory = unsafe { &mut *memory };
+        memory.receive.clear();
+        memory.transmit.clear();
+        if let Err(err) = self
+            .setup_queue(RECEIVE_QUEUE, &mut memory.receive)
+            .and_then(|()| self.setup_queue(TRANSMIT_QUEUE, &mut memory.transmit))
+        {
+            self.set_status(STATUS_FAILED);
+            return Err(err);
+        }
+        // every receive buffer goes to the device
+        for (i, buffer) in memory.receive_buffers.iter().enumerate() {
+            memory.receive.descriptors.0[i] = Descriptor {
+                address: buffer.as_ptr() as u64,
+                length: BUFFER_SIZE as u32,
+                flags: DESCRIPTOR_WRITE,
+                next: 0,
+            };
+            memory.receive.available.0.ring[i] = i as u16;
+        }
+        compiler_fence(Ordering::SeqCst);
+        memory.receive.available.0.index = QUEUE_SIZE as u16;
+
+        if self.features.get_bit(FEATURE_MAC) {
+            for (i, byte) in self.mac.0.iter_mut().enumerate() {
+                // SAFETY: the device configuration is mapped
+                *byte = unsafe { self.registers.device.read(i as u64) };
+            }
+        } else {
+            // locally administered, the device takes any
+            self.mac = MacAddress([0x02, 0x00, 0x00, 0x00, 0x00, 0x01]);
+        }
+
+        self.set_status(status | STATUS_DRIVER_OK);
+        // SAFETY: the ISR status is mapped, reading it acknowledges
+        unsafe { self.registers.isr.read::<u8>(0) };
+        self.registers.notify(RECEIVE_QUEUE);
+        IS_ACTIVE.store(true, Ordering::Release);
+
+        let link = if self.is_link_up() {
+            "link up"
+        } else {
+            "no link"
+        };
+        log::info!("{}: {}, {}", Self::DRIVER_NAME, self.mac, link);
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("VirtioNet::remove()");
+
+        IS_ACTIVE.store(false, Ordering::Release);
+        // reset, the device forgets the queues
+        self.set_status(0);
+    }
+}
+
+impl Nic for VirtioNetNic {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), DriverError> {
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        if NET_HEADER_SIZE + frame.len() > BUFFER_SIZE {
+            return Err(DriverError::InvalidRequest);
+        }
+        let memory = &raw mut MEMORY;
+        // SAFETY: the previous frame was used, the device owns no transmit buffer
+        let (queue, buffer) = unsafe { (&mut (*memory).transmit, &mut (*memory).transmit_buffers) };
+
+        // no offloads, the header is all zeros
+        buffer[..NET_HEADER_SIZE].fill(0);
+        buffer[NET_HEADER_SIZE..][..frame.len()].copy_from_slice(frame);
+        let index = queue.available.0.index;
+        let slot = index as usize % QUEUE_SIZE;
+        queue.descriptors.0[slot] = Descriptor {
+            address: buffer.as_ptr() as u64,
+            length: (NET_HEADER_SIZE + frame.len()) as u32,
+            flags: 0,
+            next: 0,
+        };
+        queue.available.0.ring[slot] = slot as u16;
+        compiler_fence(Ordering::SeqCst);
+        queue.available.0.index = index.wrapping_add(1);
+        compiler_fence(Ordering::SeqCst);
+        self.registers.notify(TRANSMIT_QUEUE);
+
+        // one buffer, so wait until the device is done with it
+        let used = &raw const queue.used.0.index;
+        poll_timeout(TIMEOUT_TRANSMIT, || {
+            // SAFETY: written by the device
+            (unsafe { used.read_volatile() } == index.wrapping_add(1)).then_some(())
+        })
+        .ok_or(DriverError::Timeout)?;
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, DriverError> {
+        if !IS_ACTIVE.load(Ordering::Acquire) {
+            return Err(DriverError::NoHardware);
+        }
+        let memory = &raw mut MEMORY;
+        // SAFETY: used buffers belong to us until they are made available again
+        let (queue, buffers) = unsafe { (&mut (*memory).receive, &(*memory).receive_buffers) };
+        let used = &raw const queue.used.0;
+        // SAFETY: written by the device
+        if unsafe { (&raw const (*used).index).read_volatile() } == queue.last_used {
+            return Ok(None);
+        }
+        compiler_fence(Ordering::SeqCst);
+        let slot = queue.last_used as usize % QUEUE_SIZE;
+        // SAFETY: see above
+        let element = unsafe { (&raw const (*used).ring[slot]).read_volatile() };
+        queue.last_used = queue.last_used.wrapping_add(1);
+
+        let id = element.id as usize % QUEUE_SIZE;
+        let length = (element.length as usize).saturating_sub(NET_HEADER_SIZE);
+        let is_valid = length <= buffer.len() && length <= BUFFER_SIZE - NET_HEADER_SIZE;
+        if is_valid {
+            buffer[..length].copy_from_slice(&buffers[id][NET_HEADER_SIZE..][..length]);
+        }
+
+        // back to the device
+        let index = queue.available.0.index;
+        queue.available.0.ring[index as usize % QUEUE_SIZE] = id as u16;
+        compiler_fence(Ordering::SeqCst);
+        queue.available.0.index = index.wrapping_add(1);
+        compiler_fence(Ordering::SeqCst);
+        self.registers.notify(RECEIVE_QUEUE);
+        Ok(is_valid.then_some(length))
+    }
+}
+
+impl fmt::Debug for VirtioNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VirtioNet")
+            .field("registers", &self.registers)
+            .field("mac", &format_args!("{}", self.mac))
+            .finish()
+    }
+}
+
+impl Registers {
+    /// Find and map the structures of the vendor capabilities
+    fn map(dev: &PciDevice) -> Result<Self, DriverError> {
+        let mut registers = Self::default();
+        let capabilities = dev
+            .capabilities()
+            .filter(|(id, _)| *id == CAPABILITY_VENDOR);
+        for (_, offset) in capabilities {
+            let header = dev.read_config(offset);
+            let kind = header.get_bits(24..32) as u8;
+            let bar = dev.read_config(offset + 4).get_bits(0..8) as usize;
+            let start = dev.read_config(offset + 8) as u64;
+            let length = dev.read_config(offset + 12) as usize;
+            let target = match kind {
+                CFG_COMMON => &mut registers.common,
+                CFG_NOTIFY => &mut registers.notify,
+                CFG_ISR => &mut registers.isr,
+                CFG_DEVICE => &mut registers.device,
+                _ => continue,
+            };
+            // the first capability of a type is the preferred one
+            if target.base() != 0 {
+                continue;
+            }
+            let Some(Bar::Memory { address, .. }) = dev.bars.get(bar) else {
+                continue;
+            };
+            let address = map_mmio(address + start, length).map_err(DriverError::Mmio)?;
+            *target = MmioBlock::new(address);
+            if kind == CFG_NOTIFY {
+                registers.notify_multiplier = dev.read_config(offset + 16);
+            }
+        }
+        if registers.common.base() == 0
+            || registers.notify.base() == 0
+            || registers.isr.base() == 0
+            || registers.device.base() == 0
+        {
+            log::error!("{}: legacy device", VirtioNet::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        Ok(registers)
+    }
+
+    /// Feature bits offered by the device, read 32 at a time
+    fn read_features(&self) -> u64 {
+        let mut features = 0;
+        for select in 0..2_u32 {
+            // SAFETY: the common configuration is mapped
+            let value = unsafe {
+                self.common.write(COMMON_DEVICE_FEATURE_SELECT, select);
+                self.common.read::<u32>(COMMON_DEVICE_FEATURE)
+            };
+            features |= (value as u64) << (select * 32);
+        }
+        features
+    }
+
+    fn write_features(&self, features: u64) {
+        for (select, value) in [(0_u32, features as u32), (1, (features >> 32) as u32)] {
+            // SAFETY: the common configuration is mapped
+            unsafe {
+                self.common.write(COMMON_DRIVER_FEATURE_SELECT, select);
+                self.common.write(COMMON_DRIVER_FEATURE, value);
+            }
+        }
+    }
+
+    fn notify(&self, queue: u16) {
+        // SAFETY: the common configuration and notification area are mapped
+        unsafe {
+            self.common.write(COMMON_QUEUE_SELECT, queue);
+            let offset = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as u64;
+            self.notify
+                .write(offset * self.notify_multiplier as u64, queue);
+        }
+    }
+}
+
+fn controller() -> Option<&'static PciDevice> {
+    // transitional and modern network devices
+    Pci::devices()
+        .iter()
+        .find(|i| i.vendor_id == 0x1AF4 && matches!(i.device_id, 0x1000 | 0x1041))
+}
+
+/// Vendor Specific capability, virtio structures
+const CAPABILITY_VENDOR: u8 = 0x09;
+const CFG_COMMON: u8 = 1;
+const CFG_NOTIFY: u8 = 2;
+const CFG_ISR: u8 = 3;
+const CFG_DEVICE: u8 = 4;
+
+// Common configuration structure
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0x00;
+const COMMON_DEVICE_FEATURE: u64 = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 0x08;
+const COMMON_DRIVER_FEATURE: u64 = 0x0C;
+const COMMON_DEVICE_STATUS: u64 = 0x14;
+const COMMON_QUEUE_SELECT: u64 = 0x16;
+const COMMON_QUEUE_SIZE: u64 = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR: u64 = 0x1A;
+const COMMON_QUEUE_ENABLE: u64 = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 0x1E;
+const COMMON_QUEUE_DESC: u64 = 0x20;
+const COMMON_QUEUE_DRIVER: u64 = 0x28;
+const COMMON_QUEUE_DEVICE: u64 = 0x30;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+const FEATURE_MAC: usize = 5;
+const FEATURE_STATUS: usize = 16;
+const FEATURE_VERSION_1: usize = 32;
+
+/// The device writes the buffer
+const DESCRIPTOR_WRITE: u16 = 2;
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Available {
+    flags: u16,
+    index: u16,
+    ring: [u16; QUEUE_SIZE],
+    used_event: u16,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    length: u32,
+}
+
+#[derive(Copy, Clone)]
+#[repr(C)]
+struct Used {
+    flags: u16,
+    index: u16,
+    ring: [UsedElement; QUEUE_SIZE],
+    avail_event: u16,
+}
+
+#[repr(C, align(4096))]
+struct Page<T>(T);
+
+/// Split virtqueue
+#[repr(C)]
+struct Virtqueue {
+    descriptors: Page<[Descriptor; QUEUE_SIZE]>,
+    available: Page<Available>,
+    used: Page<Used>,
+    /// Index of the next used element to process, receive queue only
+    last_used: u16,
+}
+
+impl Virtqueue {
+    const fn new() -> Self {
+        const DESCRIPTOR: Descriptor = Descriptor {
+            address: 0,
+            length: 0,
+            flags: 0,
+            next: 0,
+        };
+        const USED_ELEMENT: UsedElement = UsedElement { id: 0, length: 0 };
+        Self {
+            descriptors: Page([DESCRIPTOR; QUEUE_SIZE]),
+            available: Page(Available {
+                flags: 0,
+                index: 0,
+                ring: [0; QUEUE_SIZE],
+                used_event: 0,
+            }),
+            used: Page(Used {
+                flags: 0,
+                index: 0,
+                ring: [USED_ELEMENT; QUEUE_SIZE],
+                avail_event: 0,
+            }),
+            last_used: 0,
+        }
+    }
+
+    /// Empty again, for a reset device
+    fn clear(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[repr(C)]
+struct VirtioMemory {
+    receive: Virtqueue,
+    transmit: Virtqueue,
+    receive_buffers: [[u8; BUFFER_SIZE]; QUEUE_SIZE],
+    transmit_buffers: [u8; BUFFER_SIZE],
+}
+
+impl VirtioMemory {
+    const fn new() -> Self {
+        Self {
+            receive: Virtqueue::new(),
+            transmit: Virtqueue::new(),
+            receive_buffers: [[0; BUFFER_SIZE]; QUEUE_SIZE],
+            transmit_buffers: [0; BUFFER_SIZE],
+        }
+    }
+}
+
+/// 64-bit fields, written as two dwords
+///
+/// # Safety
+/// See [`MmioBlock::write`].
+unsafe fn write64(registers: MmioBlock, offset: u64, value: u64) {
+    unsafe {
+        registers.write(offset, value as u32);
+        registers.write(offset + 4, (value >> 32) as u32);
+    }
+}
@@ -0,0 +1,1089 @@
+#![cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+
+//! USB eXtensible Host Controller
+//!
+//! Taken over from the firmware through USB Legacy Support, then reset and run without
+//! interrupts, events are polled. Devices on root hub ports are addressed and their
+//! descriptors logged, devices behind hubs are not enumerated. Memory is identity-mapped, so
+//! the rings and contexts in a static are used for DMA as they are.
+//!
+//! https://wiki.osdev.org/EXtensible_Host_Controller_Interface
+
+use alloc::string::String;
+use core::fmt;
+use core::sync::atomic::{Ordering, compiler_fence};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::pci::{Bar, Pci, PciDevice, PowerState};
+use super::{Dependency, Driver, DriverError};
+use crate::fox_mem::{self, map_mmio};
+use crate::fox_port::MmioBlock;
+use crate::fox_time::{delay, poll_timeout};
+use crate::{bitfields, mapped_registers, registers};
+
+const PAGE_SIZE: usize = 4096;
+/// Device slots enabled, the controller may support up to 255
+const MAX_SLOTS: usize = 16;
+/// TRBs per ring, the last one of a command or transfer ring links back to the start
+const RING_SIZE: usize = 32;
+/// Largest descriptor read
+const BUFFER_SIZE: usize = 512;
+/// Dwords of a context with 64-byte contexts, 33 of them in an input context
+const CONTEXT_DWORDS: usize = 16;
+
+const TIMEOUT_HANDOFF: Duration = Duration::from_secs(1);
+const TIMEOUT_RESET: Duration = Duration::from_secs(1);
+const TIMEOUT_COMMAND: Duration = Duration::from_secs(1);
+const TIMEOUT_PORT_RESET: Duration = Duration::from_millis(500);
+/// For the links of powered ports to come up
+const PORT_POWER_DELAY: Duration = Duration::from_millis(100);
+/// For a device to recover from a port reset, TRSTRCY of the USB 2.0 spec
+const RESET_RECOVERY_DELAY: Duration = Duration::from_millis(10);
+
+/// Rings, contexts and the descriptor buffer, used by DMA
+static mut MEMORY: XhciMemory = XhciMemory::new();
+
+/// xHCI controller
+pub struct Xhci {
+    /// Capability registers and the extended capabilities (BAR0)
+    bar: MmioBlock,
+    operational: OperationalRegisters,
+    /// Registers of the primary interrupter, in the runtime registers
+    interrupter: InterrupterRegisters,
+    doorbells: Doorbells,
+    /// Interface Version Number, BCD
+    version: u16,
+    port_count: u8,
+    /// Slots enabled, up to [`MAX_SLOTS`]
+    slot_count: u8,
+    /// Of a slot or endpoint context, 8 or 16
+    context_dwords: usize,
+    commands: Ring,
+    events: EventRing,
+    /// Default control endpoint of each slot
+    transfers: [Ring; MAX_SLOTS],
+    /// Scratchpad buffers and the array after them, address and frame count
+    scratchpad: Option<(u64, usize)>,
+    device_count: usize,
+}
+
+impl Default for Xhci {
+    fn default() -> Self {
+        Self {
+            bar: MmioBlock::default(),
+            operational: OperationalRegisters::default(),
+            interrupter: InterrupterRegisters::default(),
+            doorbells: Doorbells::default(),
+            version: 0,
+            port_count: 0,
+            slot_count: 0,
+            context_dwords: CONTEXT_DWORDS / 2,
+            commands: Ring::default(),
+            events: EventRing::default(),
+            transfers: [Ring::default(); MAX_SLOTS],
+            scratchpad: None,
+            device_count: 0,
+        }
+    }
+}
+
+impl Driver for Xhci {
+    const DRIVER_NAME: &str = "xhci";
+    const DEPENDENCIES: &[Dependency] = &[Dependency::Driver(Pci::DRIVER_NAME)];
+
+    fn probe() -> Result<(), DriverError> {
+        // log::trace!("Xhci::probe()");
+
+        if controller().is_none() {
+            return Err(DriverError::NoHardware);
+        }
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), DriverError> {
+        // log::trace!("Xhci::init()");
+
+        let (dev, bar, size) = controller().ok_or(DriverError::NoHardware)?;
+        let address = map_mmio(bar, size as usize).map_err(DriverError::Mmio)?;
+        self.bar = MmioBlock::new(address);
+        log::info!("{}: {} BAR0 at {:#x}", Self::DRIVER_NAME, dev.address, bar);
+
+        if let Some(pm) = dev.power_management()
+            && pm.state() != PowerState::D0
+        {
+            pm.set_state(PowerState::D0);
+        }
+        dev.enable_bus_master();
+
+        let capability = CapabilityRegisters(self.bar);
+        let caplength = read_register(capability.caplength());
+        self.version = caplength.get_bits(16..32) as u16;
+        self.operational = OperationalRegisters(self.bar.at(caplength.get_bits(0..8) as u64));
+        let rtsoff = read_register(capability.rtsoff()) & !0x1F;
+        self.interrupter = InterrupterRegisters(self.bar.at(rtsoff as u64 + RT_INTERRUPTER));
+        let dboff = read_register(capability.dboff()) & !0x3;
+        self.doorbells = Doorbells(self.bar.at(dboff as u64));
+        let hcsparams1 = read_register(capability.hcsparams1());
+        let hcsparams2 = read_register(capability.hcsparams2());
+        let hccparams1 = read_register(capability.hccparams1());
+        self.port_count = hcsparams1.max_ports() as u8;
+        self.slot_count = (hcsparams1.max_slots() as u8).min(MAX_SLOTS as u8);
+        self.context_dwords = if hccparams1.has_64_byte_contexts() {
+            CONTEXT_DWORDS
+        } else {
+            CONTEXT_DWORDS / 2
+        };
+        log::debug!(
+            "{}: version {:x}.{:02x}, {} ports, {} slots, {:?}",
+            Self::DRIVER_NAME,
+            self.version >> 8,
+            self.version & 0xFF,
+            self.port_count,
+            self.slot_count,
+            hccparams1
+        );
+
+        // in dwords
+        self.take_ownership(hccparams1.extended_capabilities() as u64 * 4);
+        self.reset()?;
+
+        if !read_register(self.operational.pagesize()).get_bit(0) {
+            log::error!("{}: 4 KiB pages are not supported", Self::DRIVER_NAME);
+            return Err(DriverError::NoHardware);
+        }
+        write_register(self.operational.config(), self.slot_count as u32);
+
+        let memory = &raw mut MEMORY;
+        // SAFETY: the controller is reset and does not use the memory
+        let dcbaa = unsafe { &mut (*memory).dcbaa.0 };
+        dcbaa.fill(0);
+        let scratchpad_count =
+            (hcsparams2.max_scratchpad_hi() << 5 | hcsparams2.max_scratchpad_lo()) as usize;
+        if scratchpad_count > 0 {
+            dcbaa[0] = self.alloc_scratchpad(scratchpad_count)?;
+        }
+        compiler_fence(Ordering::SeqCst);
+        write_register64(self.operational.dcbaap(), dcbaa.as_ptr() as u64);
+
+        // SAFETY: only the addresses are taken
+        let (commands, events, erst) = unsafe {
+            (
+                &raw mut (*memory).commands as u64,
+                &raw mut (*memory).events as u64,
+                &mut (*memory).erst.0,
+            )
+        };
+        self.commands = Ring::new(commands);
+        // Ring Cycle State
+        write_register64(self.operational.crcr(), commands | 1);
+
+        self.events = EventRing::new(events);
+        *erst = ErstEntry {
+            address: events,
+            size: RING_SIZE as u32,
+            reserved: 0,
+        };
+        compiler_fence(Ordering::SeqCst);
+        write_register(self.interrupter.erstsz(), 1);
+        write_register64(self.interrupter.erdp(), events);
+        write_register64(self.interrupter.erstba(), erst as *const ErstEntry as u64);
+
+        // Run/Stop, interrupts stay disabled
+        modify_register(self.operational.usbcmd(), |mut usbcmd| {
+            usbcmd.set_is_running(true);
+            usbcmd
+        });
+        poll_timeout(TIMEOUT_RESET, || {
+            (!read_register(self.operational.usbsts()).is_halted()).then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+        .inspect_err(|_| log::error!("{}: controller not running", Self::DRIVER_NAME))?;
+
+        // without Port Power Control the ports are always powered
+        if hccparams1.has_port_power_control() {
+            for port in 1..=self.port_count {
+                let portsc = self.read_port(port);
+                if !portsc.is_powered() {
+                    let mut value = Portsc(portsc.0 & PORTSC_PRESERVE);
+                    value.set_is_powered(true);
+                    self.write_port(port, value);
+                }
+            }
+        }
+        delay(PORT_POWER_DELAY);
+
+        for port in 1..=self.port_count {
+            if !self.read_port(port).is_connected() {
+                continue;
+            }
+            match self.enumerate(port) {
+                Ok(()) => self.device_count += 1,
+                Err(err) => log::warn!("{}: port {}: {}", Self::DRIVER_NAME, port, err),
+            }
+        }
+        log::info!(
+            "{}: {} devices on {} ports",
+            Self::DRIVER_NAME,
+            self.device_count,
+            self.port_count
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self) {
+        // log::trace!("Xhci::remove()");
+
+        // stops all DMA, the firmware gets a clean controller
+        if self.reset().is_err() {
+            log::warn!("{}: reset timeout", Self::DRIVER_NAME);
+        }
+        if let Some((address, count)) = self.scratchpad.take() {
+            // SAFETY: the controller was reset and forgot the scratchpad
+            unsafe { fox_mem::free_frames(address, count) };
+        }
+        self.device_count = 0;
+    }
+}
+
+impl Xhci {
+    /// BIOS/OS handoff through the USB Legacy Support capability, disables its SMIs
+    fn take_ownership(&self, first: u64) {
+        // log::trace!("Xhci::take_ownership()");
+
+        let Some(offset) = self.find_capability(first, CAPABILITY_LEGACY_SUPPORT) else {
+            return;
+        };
+        let legsup = self.read(offset);
+        // HC BIOS Owned Semaphore
+        if legsup.get_bit(16) {
+            // HC OS Owned Semaphore
+            self.write(offset, legsup | 1 << 24);
+            let result = poll_timeout(TIMEOUT_HANDOFF, || {
+                (!self.read(offset).get_bit(16)).then_some(())
+            });
+            match result {
+                Some(()) => log::info!("{}: taken over from the firmware", Self::DRIVER_NAME),
+                None => log::warn!("{}: firmware did not hand over", Self::DRIVER_NAME),
+            }
+        }
+        // USB Legacy Support Control/Status: SMI enables off, SMI events cleared
+        let control = self.read(offset + 4);
+        self.write(offset + 4, control & !0xE011 | 0b111 << 29);
+    }
+
+    /// Offset of the extended capability `id` in the list starting at `first`
+    fn find_capability(&self, first: u64, id: u32) -> Option<u64> {
+        let mut offset = first;
+        // a broken list must not loop forever
+        for _ in 0..256 {
+            if offset == 0 {
+                return None;
+            }
+            let header = self.read(offset);
+            if header.get_bits(0..8) == id {
+                return Some(offset);
+            }
+            // Next xHCI Extended Capability Pointer, in dwords
+            offset = match header.get_bits(8..16) {
+                0 => return None,
+                next => offset + next as u64 * 4,
+            };
+        }
+        None
+    }
+
+    /// Halt and reset the controller
+    fn reset(&self) -> Result<(), DriverError> {
+        // log::trace!("Xhci::reset()");
+
+        modify_register(self.operational.usbcmd(), |mut usbcmd| {
+            usbcmd.set_is_running(false);
+            usbcmd
+        });
+        poll_timeout(TIMEOUT_RESET, || {
+            read_register(self.operational.usbsts())
+                .is_halted()
+                .then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+        .inspect_err(|_| log::error!("{}: controller not halted", Self::DRIVER_NAME))?;
+
+        let mut usbcmd = Usbcmd::default();
+        usbcmd.set_is_resetting(true);
+        write_register(self.operational.usbcmd(), usbcmd);
+        poll_timeout(TIMEOUT_RESET, || {
+            let is_done = !read_register(self.operational.usbcmd()).is_resetting()
+                && !read_register(self.operational.usbsts()).is_not_ready();
+            is_done.then_some(())
+        })
+        .ok_or(DriverError::Timeout)
+        .inspect_err(|_| log::error!("{}: controller not ready", Self::DRIVER_NAME))
+    }
+
+    /// Zeroed scratchpad buffers for the controller, returns the Scratchpad Buffer Array
+    fn alloc_scratchpad(&mut self, count: usize) -> Result<u64, DriverError> {
+        // log::trace!("Xhci::alloc_scratchpad()");
+
+        let array_frames = (count * size_of::<u64>()).div_ceil(PAGE_SIZE);
+        let frames = count + array_frames;
+        let address = fox_mem::alloc_frames(frames, PAGE_SIZE as u64).ok_or_else(|| {
+            log::error!(
+                "{}: no memory for {} scratchpad buffers",
+                Self::DRIVER_NAME,
+                count
+            );
+            DriverError::InvalidRequest
+        })?;
+        self.scratchpad = Some((address, frames));
+
+        // SAFETY: the frames are ours and identity-mapped
+        unsafe { core::ptr::write_bytes(address as *mut u8, 0, frames * PAGE_SIZE) };
+        let array = address + (count * PAGE_SIZE) as u64;
+        for i in 0..count {
+            // SAFETY: see above
+            unsafe {
+                (array as *mut u64)
+                    .add(i)
+                    .write(address + (i * PAGE_SIZE) as u64)
+            };
+        }
+        Ok(array)
+    }
+
+    /// Enable, address and describe the device on a connected root hub port
+    fn enumerate(&mut self, port: u8) -> Result<(), DriverError> {
+        // log::trace!("Xhci::enumerate()");
+
+        if !self.enable_port(port) {
+            return Err(DriverError::Timeout);
+        }
+        let speed = self.read_port(port).speed() as u8;
+
+        let event = self.command(Trb::new(TRB_ENABLE_SLOT))?;
+        let slot = event.control.get_bits(24..32) as usize;
+        if !(1..=MAX_SLOTS).contains(&slot) {
+            return Err(DriverError::InvalidRequest);
+        }
+        // default max packet size of the default control endpoint
+        let max_packet = match speed {
+            SPEED_LOW | SPEED_FULL => 8,
+            SPEED_HIGH => 64,
+            _ => 512,
+        };
+        self.address_device(slot, port, speed, max_packet)?;
+
+        // full-speed devices have a control endpoint of 8 to 64 bytes
+        let header = self.get_descriptor(slot, DESCRIPTOR_DEVICE, 0, 0, 8)?;
+        if speed == SPEED_FULL && header[7] as u32 != max_packet {
+            let max_packet = header[7] as u32;
+            self.evaluate_max_packet(slot, max_packet)?;
+        }
+
+        let device: [u8; 18] = self
+            .get_descriptor(slot, DESCRIPTOR_DEVICE, 0, 0, 18)?
+            .try_into()
+            .unwrap();
+        let vendor = u16::from_le_bytes([device[8], device[9]]);
+        let product = u16::from_le_bytes([device[10], device[11]]);
+        let usb = u16::from_le_bytes([device[2], device[3]]);
+        // iProduct, strings are optional
+        let name = match device[15] {
+            0 => None,
+            index => self.get_string(slot, index).ok(),
+        };
+        log::info!(
+            "{}: port {} {} device {:04X}:{:04X} \"{}\", USB {:x}.{:02x}, class {:02X}",
+            Self::DRIVER_NAME,
+            port,
+            speed_name(speed),
+            vendor,
+            product,
+            name.as_deref().unwrap_or(class_name(device[4])),
+            usb >> 8,
+            usb & 0xFF,
+            device[4]
+        );
+
+        // the first configuration, with its interface and endpoint descriptors
+        let header = self.get_descriptor(slot, DESCRIPTOR_CONFIGURATION, 0, 0, 9)?;
+        let length = u16::from_le_bytes([header[2], header[3]]) as usize;
+        let configuration = self.get_descriptor(slot, DESCRIPTOR_CONFIGURATION, 0, 0, length)?;
+        log_configuration(port, configuration);
+        Ok(())
+    }
+
+    /// Reset a USB2 port, USB3 ports are enabled by link training. Whether it is enabled
+    fn enable_port(&self, port: u8) -> bool {
+        // log::trace!("Xhci::enable_port()");
+
+        let portsc = self.read_port(port);
+        if !portsc.is_enabled() {
+            let mut value = Portsc(portsc.0 & PORTSC_PRESERVE);
+            value.set_is_resetting(true);
+            self.write_port(port, value);
+            let result = poll_timeout(TIMEOUT_PORT_RESET, || {
+                self.read_port(port).is_reset_changed().then_some(())
+            });
+            if result.is_none() {
+                log::debug!("{}: port {} reset timeout", Self::DRIVER_NAME, port);
+            }
+            delay(RESET_RECOVERY_DELAY);
+        }
+        // Connect Status Change, Port Reset Change and the others are write-1-to-clear
+        let portsc = self.read_port(port);
+        self.write_port(port, Portsc(portsc.0 & (PORTSC_PRESERVE | PORTSC_CHANGE)));
+        portsc.is_enabled()
+    }
+
+    /// Address Device with a new transfer ring for the default control endpoint
+    fn address_device(
+        &mut self,
+        slot: usize,
+        port: u8,
+        speed: u8,
+        max_packet: u32,
+    ) -> Result<(), DriverError> {
+        // log::trace!("Xhci::address_device()");
+
+        let memory = &raw mut MEMORY;
+        // SAFETY: the slot is new, the controller does not use its memory yet
+        let (dcbaa, input, device, ring) = unsafe {
+            (
+                &mut (*memory).dcbaa.0,
+                &mut (*memory).input.0,
+                &mut (*memory).devices[slot - 1].0,
+                &raw mut (*memory).transfers[slot - 1] as u64,
+            )
+        };
+        device.fill(0);
+        dcbaa[slot] = device.as_ptr() as u64;
+        self.transfers[slot - 1] = Ring::new(ring);
+
+        let stride = self.context_dwords;
+        input.fill(0);
+        // Input Control Context: Add Context flags of the slot and endpoint 0
+        input[1] = 0b11;
+        // Slot Context: Speed, Context Entries, Root Hub Port Number
+        input[stride] = (speed as u32) << 20 | 1 << 27;
+        input[stride + 1] = (port as u32) << 16;
+        // Endpoint 0 Context: Error Count 3, Control, Dequeue Cycle State 1, Average TRB Length
+        let endpoint = 2 * stride;
+        input[endpoint + 1] = 3 << 1 | ENDPOINT_CONTROL << 3 | max_packet << 16;
+        input[endpoint + 2] = ring as u32 | 1;
+        input[endpoint + 3] = (ring >> 32) as u32;
+        input[endpoint + 4] = 8;
+        compiler_fence(Ordering::SeqCst);
+
+        let mut trb = Trb::new(TRB_ADDRESS_DEVICE);
+        trb.parameter = input.as_ptr() as u64;
+        trb.control.set_bits(24..32, slot as u32);
+        self.command(trb)?;
+        Ok(())
+    }
+
+    /// Evaluate Context with a new max packet size of the default control endpoint
+    fn evaluate_max_packet(&mut self, slot: usize, max_packet: u32) -> Result<(), DriverError> {
+        let memory = &raw mut MEMORY;
+        // SAFETY: the controller reads the input context only during a command
+        let input = unsafe { &mut (*memory).input.0 };
+        let endpoint = 2 * self.context_dwords;
+        input.fill(0);
+        input[1] = 0b10;
+        input[endpoint + 1] = 3 << 1 | ENDPOINT_CONTROL << 3 | max_packet << 16;
+        compiler_fence(Ordering::SeqCst);
+
+        let mut trb = Trb::new(TRB_EVALUATE_CONTEXT);
+        trb.parameter = input.as_ptr() as u64;
+        trb.control.set_bits(24..32, slot as u32);
+        self.command(trb)?;
+        Ok(())
+    }
+
+    /// GET_DESCRIPTOR, the returned bytes are valid until the next transfer
+    fn get_descriptor(
+        &mut self,
+        slot: usize,
+        kind: u8,
+        index: u8,
+        language: u16,
+        length: usize,
+    ) -> Result<&'static [u8], DriverError> {
+        let length = length.min(BUFFER_SIZE);
+        let memory = &raw mut MEMORY;
+        // SAFETY: only the one transfer in flight uses the buffer
+        let buffer = unsafe { &mut (*memory).buffer.0 };
+        buffer.fill(0);
+
+        // bmRequestType device-to-host, standard, device
+        let setup = 0x80
+            | (REQUEST_GET_DESCRIPTOR as u64) << 8
+            | ((kind as u64) << 8 | index as u64) << 16
+            | (language as u64) << 32
+            | (length as u64) << 48;
+        let ring = &mut self.transfers[slot - 1];
+        // Immediate Data, Transfer Type IN
+        let mut trb = Trb::new(TRB_SETUP_STAGE);
+        trb.parameter = setup;
+        trb.status = 8;
+        trb.control |= 1 << 6 | 3 << 16;
+        ring.push(trb);
+        // Direction IN
+        let mut trb = Trb::new(TRB_DATA_STAGE);
+        trb.parameter = buffer.as_ptr() as u64;
+        trb.status = length as u32;
+        trb.control |= 1 << 16;
+        ring.push(trb);
+        // Interrupt On Completion, Direction OUT after an IN data stage
+        let mut trb = Trb::new(TRB_STATUS_STAGE);
+        trb.control |= 1 << 5;
+        let status = ring.push(trb);
+        compiler_fence(Ordering::SeqCst);
+        // Doorbell Target: the default control endpoint
+        self.ring_doorbell(slot, 1);
+
+        // a short data stage may report first
+        loop {
+            let event = self.wait_event(|event| {
+                event.kind() == TRB_TRANSFER_EVENT
+                    && event.control.get_bits(24..32) as usize == slot
+            })?;
+            if event.parameter == status {
+                break;
+            }
+        }
+        compiler_fence(Ordering::SeqCst);
+        Ok(&buffer[..length])
+    }
+
+    /// String descriptor in the first language the device lists
+    fn get_string(&mut self, slot: usize, index: u8) -> Result<String, DriverError> {
+        let languages = self.get_descriptor(slot, DESCRIPTOR_STRING, 0, 0, 4)?;
+        let language = u16::from_le_bytes([languages[2], languages[3]]);
+        let header = self.get_descriptor(slot, DESCRIPTOR_STRING, index, language, 2)?;
+        let length = header[0] as usize;
+        let string = self.get_descriptor(slot, DESCRIPTOR_STRING, index, language, length)?;
+        // bLength, bDescriptorType, then UTF-16LE
+        let units = string
+            .get(2..length)
+            .unwrap_or(&[])
+            .chunks_exact(2)
+            .map(|i| u16::from_le_bytes([i[0], i[1]]));
+        Ok(char::decode_utf16(units)
+            .map(|i| i.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect())
+    }
+
+    /// Post `trb` on the command ring and wait for its completion event
+    fn command(&mut self, trb: Trb) -> Result<Trb, DriverError> {
+        let address = self.commands.push(trb);
+        compiler_fence(Ordering::SeqCst);
+        // Host Controller Command
+        self.ring_doorbell(0, 0);
+        self.wait_event(|event| {
+            event.kind() == TRB_COMMAND_COMPLETION_EVENT && event.parameter == address
+        })
+    }
+
+    /// Next event `f` accepts, failed if its completion code is an error
+    ///
+    /// Other events are dropped.
+    fn wait_event(&mut self, f: impl Fn(&Trb) -> bool) -> Result<Trb, DriverError> {
+        let event = poll_timeout(TIMEOUT_COMMAND, || {
+            while let Some(event) = self.events.pop() {
+                // Event Handler Busy is write-1-to-clear
+                write_register64(
+                    self.interrupter.erdp(),
+                    self.events.dequeue_address() | 1 << 3,
+                );
+                if f(&event) {
+                    return Some(event);
+                }
+                log::debug!("{}: event {} dropped", Self::DRIVER_NAME, event.kind());
+            }
+            None
+        })
+        .ok_or(DriverError::Timeout)?;
+
+        match event.status.get_bits(24..32) as u16 {
+            COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(event),
+            status => Err(DriverError::CommandFailed { status }),
+        }
+    }
+
+    fn ring_doorbell(&self, slot: usize, target: u32) {
+        write_register(self.doorbells.doorbell(slot), target);
+    }
+
+    fn read(&self, offset: u64) -> u32 {
+        // SAFETY: BAR0 comes from the PCI configuration space
+        unsafe { self.bar.read(offset) }
+    }
+
+    fn write(&self, offset: u64, value: u32) {
+        // SAFETY: BAR0 comes from the PCI configuration space
+        unsafe { self.bar.write(offset, value) }
+    }
+
+    /// PORTSC of a port, numbered from 1
+    fn read_port(&self, port: u8) -> Portsc {
+        read_register(self.operational.portsc(port as usize - 1))
+    }
+
+    fn write_port(&self, port: u8, value: Portsc) {
+        write_register(self.operational.portsc(port as usize - 1), value);
+    }
+}
+
+impl fmt::Debug for Xhci {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Xhci")
+            .field("bar", &format_args!("{:#x}", self.bar.base()))
+            .field("version", &format_args!("{:#x}", self.version))
+            .field("ports", &self.port_count)
+            .field("slots", &self.slot_count)
+            .field("devices", &self.device_count)
+            .finish()
+    }
+}
+
+/// First xHCI controller, its BAR0 and the BAR0 size
+fn controller() -> Option<(&'static PciDevice, u64, u64)> {
+    // prog-if 30: XHCI
+    Pci::find_class(0x0C, 0x03)
+        .filter(|i| i.prog_if == 0x30)
+        .find_map(|dev| match dev.bars[0] {
+            Bar::Memory { address, size, .. } if address != 0 => Some((dev, address, size)),
+            _ => None,
+        })
+}
+
+// all registers of the controller are in BAR0, mapped uncached by `Xhci::init`
+mapped_registers!();
+
+/// Interfaces and endpoints of a configuration descriptor with everything after it
+fn log_configuration(port: u8, configuration: &[u8]) {
+    let mut rest = configuration;
+    while let [length, kind, ..] = *rest {
+        let length = length as usize;
+        if length < 2 || length > rest.len() {
+            break;
+        }
+        let descriptor = &rest[..length];
+        match (kind, descriptor) {
+            (DESCRIPTOR_INTERFACE, [_, _, number, _, endpoints, class, subclass, protocol, ..]) => {
+                log::info!(
+                    "{}: port {} interface {}: {} ({:02X}:{:02X}:{:02X}), {} endpoints",
+                    Xhci::DRIVER_NAME,
+                    port,
+                    number,
+                    class_name(*class),
+                    class,
+                    subclass,
+                    protocol,
+                    endpoints
+                );
+            }
+            (
+                DESCRIPTOR_ENDPOINT,
+                [_, _, address, attributes, size_low, size_high, interval, ..],
+            ) => {
+                let kind = match attributes.get_bits(0..2) {
+                    0 => "control",
+                    1 => "isochronous",
+                    2 => "bulk",
+                    _ => "interrupt",
+                };
+                let direction = if address.get_bit(7) { "IN" } else { "OUT" };
+                log::debug!(
+                    "{}: port {} endpoint {:02X}: {} {}, {} bytes, interval {}",
+                    Xhci::DRIVER_NAME,
+                    port,
+                    address,
+                    kind,
+                    direction,
+                    u16::from_le_bytes([*size_low, *size_high]) & 0x7FF,
+                    interval
+                );
+            }
+            _ => {}
+        }
+        rest = &rest[length..];
+    }
+}
+
+fn speed_name(speed: u8) -> &'static str {
+    match speed {
+        SPEED_FULL => "full-speed",
+        SPEED_LOW => "low-speed",
+        SPEED_HIGH => "high-speed",
+        SPEED_SUPER => "SuperSpeed",
+        SPEED_SUPER_PLUS => "SuperSpeedPlus",
+        _ => "unknown speed",
+    }
+}
+
+/// USB class code of a device or interface
+fn class_name(class: u8) -> &'static str {
+    match class {
+        0x00 => "composite",
+        0x01 => "audio",
+        0x02 => "communications",
+        0x03 => "HID",
+        0x05 => "physical",
+        0x06 => "image",
+        0x07 => "printer",
+        0x08 => "mass storage",
+        0x09 => "hub",
+        0x0A => "CDC data",
+        0x0B => "smart card",
+        0x0E => "video",
+        0xDC => "diagnostic",
+        0xE0 => "wireless controller",
+        0xEF => "miscellaneous",
+        0xFE => "application specific",
+        0xFF => "vendor specific",
+        _ => "unknown class",
+    }
+}
+
+registers! {
+    /// At the start of BAR0
+    struct CapabilityRegisters {
+        /// Capability Register Length, and the Interface Version Number in the upper half
+        caplength: 0x00 => u32, ReadOnlyAccess;
+        hcsparams1: 0x04 => Hcsparams1, ReadOnlyAccess;
+        hcsparams2: 0x08 => Hcsparams2, ReadOnlyAccess;
+        hccparams1: 0x10 => Hccparams1, ReadOnlyAccess;
+        /// Doorbell Offset
+        dboff: 0x14 => u32, ReadOnlyAccess;
+        /// Runtime Register Space Offset
+        rtsoff: 0x18 => u32, ReadOnlyAccess;
+    }
+}
+
+registers! {
+    /// At CAPLENGTH
+    struct OperationalRegisters {
+        usbcmd: 0x00 => Usbcmd, ReadWriteAccess;
+        usbsts: 0x04 => Usbsts, ReadWriteAccess;
+        /// Page sizes supported, bit n for 2^(n + 12) bytes
+        pagesize: 0x08 => u32, ReadOnlyAccess;
+        /// Command Ring Control
+        crcr: 0x18 => u64, ReadWriteAccess;
+        /// Device Context Base Address Array Pointer
+        dcbaap: 0x30 => u64, ReadWriteAccess;
+        /// Max Device Slots Enabled
+        config: 0x38 => u32, ReadWriteAccess;
+        /// Port Status and Control, ports numbered from 0
+        portsc[0x10]: 0x400 => Portsc, ReadWriteAccess;
+    }
+}
+
+registers! {
+    /// Of an interrupter, in the runtime registers
+    struct InterrupterRegisters {
+        /// Event Ring Segment Table Size
+        erstsz: 0x08 => u32, ReadWriteAccess;
+        /// Event Ring Segment Table Base Address
+        erstba: 0x10 => u64, ReadWriteAccess;
+        /// Event Ring Dequeue Pointer
+        erdp: 0x18 => u64, ReadWriteAccess;
+    }
+}
+
+registers! {
+    /// At DBOFF
+    struct Doorbells {
+        /// Slot 0 is the Host Controller Command doorbell
+        doorbell[4]: 0x00 => u32, WriteOnlyAccess;
+    }
+}
+
+bitfields! {
+    /// Structural Parameters 1
+    struct Hcsparams1(u32) {
+        max_slots: 0..8;
+        max_interrupters: 8..19;
+        max_ports: 24..32;
+    }
+}
+
+bitfields! {
+    /// Structural Parameters 2
+    struct Hcsparams2(u32) {
+        /// Event Ring Segment Table Max, as a power of 2
+        erst_max: 4..8;
+        max_scratchpad_hi: 21..26;
+        max_scratchpad_lo: 27..32;
+    }
+}
+
+bitfields! {
+    /// Capability Parameters 1
+    struct Hccparams1(u32) {
+        has_64_bit_addressing: 0;
+        has_64_byte_contexts: 2;
+        has_port_power_control: 3;
+        /// xHCI Extended Capabilities Pointer, in dwords from BAR0
+        extended_capabilities: 16..32;
+    }
+}
+
+bitfields! {
+    /// USB Command
+    struct Usbcmd(u32) {
+        /// Run/Stop
+        is_running, set_is_running: 0;
+        /// Host Controller Reset
+        is_resetting, set_is_resetting: 1;
+        /// Interrupter Enable
+        is_interrupt_enabled, set_is_interrupt_enabled: 2;
+    }
+}
+
+bitfields! {
+    /// USB Status
+    struct Usbsts(u32) {
+        /// HCHalted
+        is_halted: 0;
+        /// Controller Not Ready
+        is_not_ready: 11;
+    }
+}
+
+bitfields! {
+    /// Port Status and Control
+    struct Portsc(u32) {
+        /// Current Connect Status
+        is_connected: 0;
+        is_enabled: 1;
+        /// Port Reset
+        is_resetting, set_is_resetting: 4;
+        /// Port Power
+        is_powered, set_is_powered: 9;
+        speed: 10..14;
+        /// Port Reset Change
+        is_reset_changed: 21;
+    }
+}
+
+// Runtime registers
+const RT_INTERRUPTER: u64 = 0x20;
+
+/// Port Power, Port Indicator Control and the Wake on bits, written back as read
+const PORTSC_PRESERVE: u32 = 1 << 9 | 0b11 << 14 | 0b111 << 25;
+/// Connect, Enable, Warm Reset, Over-current, Reset, Link State and Config Error Change
+const PORTSC_CHANGE: u32 = 0x7F << 17;
+
+const CAPABILITY_LEGACY_SUPPORT: u32 = 1;
+
+// Port Speed ID defaults
+const SPEED_FULL: u8 = 1;
+const SPEED_LOW: u8 = 2;
+const SPEED_HIGH: u8 = 3;
+const SPEED_SUPER: u8 = 4;
+const SPEED_SUPER_PLUS: u8 = 5;
+
+// TRB types
+const TRB_SETUP_STAGE: u32 = 2;
+const TRB_DATA_STAGE: u32 = 3;
+const TRB_STATUS_STAGE: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION_EVENT: u32 = 33;
+
+// Completion codes
+const COMPLETION_SUCCESS: u16 = 1;
+const COMPLETION_SHORT_PACKET: u16 = 13;
+
+const ENDPOINT_CONTROL: u32 = 4;
+
+const REQUEST_GET_DESCRIPTOR: u8 = 6;
+
+// Descriptor types
+const DESCRIPTOR_DEVICE: u8 = 1;
+const DESCRIPTOR_CONFIGURATION: u8 = 2;
+const DESCRIPTOR_STRING: u8 = 3;
+const DESCRIPTOR_INTERFACE: u8 = 4;
+const DESCRIPTOR_ENDPOINT: u8 = 5;
+
+/// Transfer Request Block
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(16))]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    /// Cycle bit, TRB Type and flags of the type
+    control: u32,
+}
+
+impl Trb {
+    const fn new(kind: u32) -> Self {
+        Self {
+            parameter: 0,
+            status: 0,
+            control: kind << 10,
+        }
+    }
+
+    fn kind(&self) -> u32 {
+        self.control.get_bits(10..16)
+    }
+}
+
+/// Command or transfer ring, the producer side
+#[derive(Copy, Clone, Debug, Default)]
+struct Ring {
+    address: u64,
+    enqueue: usize,
+    /// Producer Cycle State
+    cycle: bool,
+}
+
+impl Ring {
+    /// Empty ring at `address` with a Link TRB back to the start, it toggles the cycle
+    fn new(address: u64) -> Self {
+        let trbs = address as *mut Trb;
+        for i in 0..RING_SIZE - 1 {
+            // SAFETY: the ring is in MEMORY and not used by the controller
+            unsafe { trbs.add(i).write_volatile(Trb::new(0)) };
+        }
+        let mut link = Trb::new(TRB_LINK);
+        link.parameter = address;
+        link.control.set_bit(1, true);
+        // SAFETY: see above
+        unsafe { trbs.add(RING_SIZE - 1).write_volatile(link) };
+        Self {
+            address,
+            enqueue: 0,
+            cycle: true,
+        }
+    }
+
+    /// Hand `trb` to the controller, returns its address
+    fn push(&mut self, mut trb: Trb) -> u64 {
+        let trbs = self.address as *mut Trb;
+        trb.control.set_bit(0, self.cycle);
+        // SAFETY: the enqueue slot belongs to the producer
+        let slot = unsafe { trbs.add(self.enqueue) };
+        // SAFETY: see above
+        unsafe { slot.write_volatile(trb) };
+
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            // the Link TRB is handed over too, the controller follows it and toggles its cycle
+            // SAFETY: the Link TRB belongs to the producer until its cycle bit is flipped
+            let link = unsafe { trbs.add(RING_SIZE - 1) };
+            // SAFETY: see above
+            let mut trb = unsafe { link.read_volatile() };
+            trb.control.set_bit(0, self.cycle);
+            compiler_fence(Ordering::SeqCst);
+            // SAFETY: see above
+            unsafe { link.write_volatile(trb) };
+            self.enqueue = 0;
+            self.cycle = !self.cycle;
+        }
+        slot as u64
+    }
+}
+
+/// Event ring, the consumer side
+#[derive(Copy, Clone, Debug, Default)]
+struct EventRing {
+    address: u64,
+    dequeue: usize,
+    /// Consumer Cycle State
+    cycle: bool,
+}
+
+impl EventRing {
+    fn new(address: u64) -> Self {
+        let trbs = address as *mut Trb;
+        for i in 0..RING_SIZE {
+            // SAFETY: the ring is in MEMORY and not used by the controller
+            unsafe { trbs.add(i).write_volatile(Trb::new(0)) };
+        }
+        Self {
+            address,
+            dequeue: 0,
+            cycle: true,
+        }
+    }
+
+    /// Next event posted by the controller
+    fn pop(&mut self) -> Option<Trb> {
+        // SAFETY: the dequeue slot is written by the controller
+        let trb = unsafe {
+            (self.address as *const Trb)
+                .add(self.dequeue)
+                .read_volatile()
+        };
+        if trb.control.get_bit(0) != self.cycle {
+            return None;
+        }
+        compiler_fence(Ordering::SeqCst);
+        self.dequeue += 1;
+        if self.dequeue == RING_SIZE {
+            self.dequeue = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    /// For the Event Ring Dequeue Pointer
+    fn dequeue_address(&self) -> u64 {
+        self.address + (self.dequeue * size_of::<Trb>()) as u64
+    }
+}
+
+/// Event Ring Segment Table Entry
+#[derive(Copy, Clone)]
+#[repr(C, align(64))]
+struct ErstEntry {
+    address: u64,
+    size: u32,
+    reserved: u32,
+}
+
+#[repr(C, align(4096))]
+struct Page<T>(T);
+
+#[repr(C)]
+struct XhciMemory {
+    /// Device Context Base Address Array, entry 0 is the scratchpad
+    dcbaa: Page<[u64; MAX_SLOTS + 1]>,
+    commands: Page<[Trb; RING_SIZE]>,
+    events: Page<[Trb; RING_SIZE]>,
+    erst: Page<ErstEntry>,
+    /// Input control context, slot context and 31 endpoint contexts
+    input: Page<[u32; 33 * CONTEXT_DWORDS]>,
+    /// Output device contexts, slot context and 31 endpoint contexts
+    devices: [Page<[u32; 32 * CONTEXT_DWORDS]>; MAX_SLOTS],
+    transfers: [Page<[Trb; RING_SIZE]>; MAX_SLOTS],
+    buffer: Page<[u8; BUFFER_SIZE]>,
+}
+
+impl XhciMemory {
+    const fn new() -> Self {
+        const TRB: Trb = Trb::new(0);
+        Self {
+            dcbaa: Page([0; MAX_SLOTS + 1]),
+            commands: Page([TRB; RING_SIZE]),
+            events: Page([TRB; RING_SIZE]),
+            erst: Page(ErstEntry {
+                address: 0,
+                size: 0,
+                reserved: 0,
+            }),
+            input: Page([0; 33 * CONTEXT_DWORDS]),
+            devices: [const { Page([0; 32 * CONTEXT_DWORDS]) }; MAX_SLOTS],
+            transfers: [const { Page([TRB; RING_SIZE]) }; MAX_SLOTS],
+            buffer: Page([0; BUFFER_SIZE]),
+        }
+    }
+}
@@ -1,115 +1,557 @@
-// use core::iter::Step;
+use core::fmt;
 use core::mem::size_of;
-use core::ptr::{NonNull, null_mut};
-use core::sync::atomic::{AtomicPtr, Ordering};
+use core::ptr::NonNull;
+use core::slice;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
 
+use acpi::AcpiError;
+use acpi::address::{AddressSpace, RawGenericAddress};
 use acpi::fadt::Fadt;
+use acpi::rsdp::Rsdp;
 use acpi::sdt::{SdtHeader, Signature};
-use x86_64::VirtAddr;
+use bit_field::BitField;
 
-use crate::fox_uefi::rsdp_raw;
+use crate::fox_port::Pio;
+use crate::fox_sync::OnceCell;
+use crate::fox_time::poll_timeout;
 
-/// Fixed ACPI Description Table (FADT).
+mod aml;
+mod bgrt;
+mod dump;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod ecdt;
+mod events;
+mod facs;
+mod gas;
+#[cfg(target_arch = "aarch64")]
+mod gtdt;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod iommu;
+mod madt;
+mod mcfg;
+mod numa;
+mod reset;
+mod sleep;
+pub mod spcr;
+
+pub use aml::aml_blocks;
+pub use bgrt::bgrt;
+pub use dump::{dump_table, save_table};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ecdt::ecdt;
+pub use events::event_registers;
+pub use facs::{facs, global_lock};
+#[cfg(target_arch = "aarch64")]
+pub use gtdt::gtdt;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use iommu::IommuInfo;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use madt::IoApic;
+pub use madt::MadtInfo;
+pub use mcfg::McfgInfo;
+pub use numa::NumaTopology;
+pub use reset::reboot;
+pub use sleep::poweroff;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use sleep::suspend;
+pub use spcr::spcr;
+
+#[derive(Debug)]
+pub enum Error {
+    Acpi(AcpiError),
+    /// The firmware has no ACPI configuration table
+    NoRsdp,
+    /// Neither a valid XSDT nor a valid RSDT
+    NoRootTable,
+    NoFadt,
+    /// FIRMWARE_CTRL is 0, or the FACS has a bad signature, length or alignment
+    InvalidFacs,
+    NoDsdt,
+    /// The \_Sx object is missing or could not be parsed
+    NoSleepState,
+    /// SLP_TYPx of the \_Sx object does not fit its 3 bits
+    InvalidSleepType(u8),
+    /// RESET_REG is not supported by the FADT
+    NoResetRegister,
+    UnsupportedAddressSpace(AddressSpace),
+    UnsupportedWidth(u8),
+    Timeout,
+    /// No table with the signature
+    NoTable,
+    Uefi(uefi::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Acpi(err) => write!(f, "{:?}", err),
+            Self::NoRsdp => write!(f, "no RSDP"),
+            Self::NoRootTable => write!(f, "no valid XSDT or RSDT"),
+            Self::NoFadt => write!(f, "no FADT"),
+            Self::InvalidFacs => write!(f, "no valid FACS"),
+            Self::NoDsdt => write!(f, "no DSDT"),
+            Self::NoSleepState => write!(f, "sleep state not found in the AML"),
+            Self::InvalidSleepType(value) => write!(f, "invalid sleep type {}", value),
+            Self::NoResetRegister => write!(f, "no reset register"),
+            Self::UnsupportedAddressSpace(space) => {
+                write!(f, "unsupported address space {:?}", space)
+            }
+            Self::UnsupportedWidth(width) => write!(f, "unsupported register width {}", width),
+            Self::Timeout => write!(f, "timeout"),
+            Self::NoTable => write!(f, "table not found"),
+            Self::Uefi(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Tables the `init_*` functions did not find, one bit per [`Table`]
+static MISSING: AtomicU8 = AtomicU8::new(0);
+
+/// Tables whose absence is recorded, see [`missing_tables`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Table {
+    Rsdp,
+    Fadt,
+    Madt,
+    Mcfg,
+}
+
+impl Table {
+    const ALL: [Self; 4] = [Self::Rsdp, Self::Fadt, Self::Madt, Self::Mcfg];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Rsdp => "RSDP",
+            Self::Fadt => "FADT",
+            Self::Madt => "MADT",
+            Self::Mcfg => "MCFG",
+        }
+    }
+
+    /// Found by its `init_*` function, `false` before it ran
+    pub fn is_found(self) -> bool {
+        match self {
+            Self::Rsdp => rsdp().is_some(),
+            Self::Fadt => fadt().is_some(),
+            Self::Madt => madt().is_some(),
+            Self::Mcfg => mcfg().is_some(),
+        }
+    }
+}
+
+pub fn record_missing(table: Table) {
+    MISSING.fetch_or(1 << table as u8, Ordering::AcqRel);
+}
+
+/// Tables not found or invalid, the drivers fall back to probing the hardware
+pub fn missing_tables() -> impl Iterator<Item = Table> {
+    let missing = MISSING.load(Ordering::Acquire);
+    Table::ALL
+        .into_iter()
+        .filter(move |&table| missing.get_bit(table as usize))
+}
+
+/// The tables and what was parsed from them, each set once by its `init_*` function
 ///
-/// Init [`init_fadt`]
-static FADT: AtomicPtr<Fadt> = AtomicPtr::new(null_mut());
+/// The firmware leaves the tables at their physical address in ACPI reclaim or NVS memory,
+/// which nothing frees or remaps: the references are valid for the rest of the run.
+struct Tables {
+    rsdp: OnceCell<&'static Rsdp>,
+    fadt: OnceCell<&'static Fadt>,
+    madt: OnceCell<&'static MadtInfo>,
+    mcfg: OnceCell<&'static McfgInfo>,
+    numa: OnceCell<&'static NumaTopology>,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    iommu: OnceCell<&'static IommuInfo>,
+}
+
+static TABLES: Tables = Tables {
+    rsdp: OnceCell::new(),
+    fadt: OnceCell::new(),
+    madt: OnceCell::new(),
+    mcfg: OnceCell::new(),
+    numa: OnceCell::new(),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    iommu: OnceCell::new(),
+};
 
-pub fn fadt_raw() -> Option<NonNull<Fadt>> {
-    let ptr = FADT.load(Ordering::Acquire);
-    NonNull::new(ptr)
+/// Root System Description Pointer (RSDP), init [`init_rsdp`]
+pub fn rsdp() -> Option<&'static Rsdp> {
+    TABLES.rsdp.get().copied()
 }
 
-pub fn init_fadt() {
-    // log::trace!("init_fadt");
+/// Fixed ACPI Description Table (FADT), init [`init_fadt`]
+pub fn fadt() -> Option<&'static Fadt> {
+    TABLES.fadt.get().copied()
+}
+
+/// Interrupt controllers from the MADT, init [`init_madt`]
+pub fn madt() -> Option<&'static MadtInfo> {
+    TABLES.madt.get().copied()
+}
+
+/// ECAM regions from the MCFG, init [`init_mcfg`]
+pub fn mcfg() -> Option<&'static McfgInfo> {
+    TABLES.mcfg.get().copied()
+}
+
+/// NUMA nodes from the SRAT and SLIT, init [`init_numa`]
+pub fn numa() -> Option<&'static NumaTopology> {
+    TABLES.numa.get().copied()
+}
+
+/// Remapping units from the DMAR or IVRS, init [`init_iommu`]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn iommu() -> Option<&'static IommuInfo> {
+    TABLES.iommu.get().copied()
+}
+
+/// Set a table found at boot, warns if it was already set
+fn set_table<T>(cell: &OnceCell<&'static T>, value: &'static T, name: &str) {
+    if cell.set(value).is_err() {
+        log::warn!("{} already initialized", name);
+    }
+}
 
-    let rsdp = rsdp_raw().expect("no init ACPI");
+/// Validate the RSDP the firmware points to
+///
+/// On error [`rsdp`] stays `None` and the RSDP is recorded as missing.
+///
+/// # Safety
+/// `rsdp` points to readable memory that stays mapped, as the ACPI configuration table does.
+pub unsafe fn init_rsdp(rsdp: NonNull<Rsdp>) -> Result<(), Error> {
+    // log::trace!("init_rsdp");
+
+    // SAFETY: by the caller
     let rsdp = unsafe { rsdp.as_ref() };
+    if let Err(err) = rsdp.validate() {
+        record_missing(Table::Rsdp);
+        return Err(Error::Acpi(err));
+    }
+    set_table(&TABLES.rsdp, rsdp, "RSDP");
+    Ok(())
+}
 
-    // let rsdt_address = rsdp.rsdt_address() as u64;
-    // let xsdt_address = if rsdp.revision() == 0 {
-    // None
-    // } else {
-    // Some(rsdp.xsdt_address() as u64)
-    // };
-    assert!(rsdp.revision() > 0);
+/// On error [`fadt`] stays `None` and the FADT is recorded as missing
+pub fn init_fadt() -> Result<(), Error> {
+    // log::trace!("init_fadt");
+
+    let result = find_fadt();
+    match result {
+        Ok(fadt) => set_table(&TABLES.fadt, fadt, "FADT"),
+        Err(_) => record_missing(Table::Fadt),
+    }
+    result.map(|_| ())
+}
+
+fn find_fadt() -> Result<&'static Fadt, Error> {
+    rsdp().ok_or(Error::NoRsdp)?;
+    let (root, _) = root_table().ok_or(Error::NoRootTable)?;
+    log::debug!("Using the {}", unsafe { root.as_ref() }.signature);
+
+    let fadt = find_table::<Fadt>(Signature::FADT).ok_or(Error::NoFadt)?;
+    log::debug!("Found FADT");
+
+    // SAFETY: found with a valid checksum, the tables stay mapped
+    let fadt = unsafe { fadt.as_ref() };
+    fadt.validate().map_err(Error::Acpi)?;
+    Ok(fadt)
+}
+
+/// Multiple APIC Description Table (MADT).
+///
+/// Init [`madt`]
+pub fn init_madt() {
+    // log::trace!("init_madt");
+
+    let Some(madt) = find_table::<SdtHeader>(Signature::MADT) else {
+        log::warn!("MADT not found");
+        record_missing(Table::Madt);
+        return;
+    };
+    log::debug!("Found MADT");
+
+    madt::init(table_bytes(madt));
+}
+
+/// PCI Express memory-mapped configuration space (MCFG).
+///
+/// Init [`mcfg`]
+pub fn init_mcfg() {
+    // log::trace!("init_mcfg");
+
+    let Some(mcfg) = find_table::<SdtHeader>(Signature::MCFG) else {
+        log::warn!("MCFG not found");
+        record_missing(Table::Mcfg);
+        return;
+    };
+    log::debug!("Found MCFG");
+
+    mcfg::init(table_bytes(mcfg));
+}
+
+/// IOMMUs from the DMAR on Intel or the IVRS on AMD, absent if the firmware disabled them
+///
+/// Init [`iommu`]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn init_iommu() {
+    // log::trace!("init_iommu");
+
+    if let Some(dmar) = find_table::<SdtHeader>(Signature::DMAR) {
+        log::debug!("Found DMAR");
+        iommu::init_dmar(table_bytes(dmar));
+    } else if let Some(ivrs) = find_table::<SdtHeader>(Signature::IVRS) {
+        log::debug!("Found IVRS");
+        iommu::init_ivrs(table_bytes(ivrs));
+    } else {
+        log::debug!("Neither DMAR nor IVRS found");
+    }
+}
+
+/// NUMA topology from the SRAT and SLIT, absent on most single-socket machines
+///
+/// Init [`numa`]
+pub fn init_numa() {
+    // log::trace!("init_numa");
+
+    let Some(srat) = find_table::<SdtHeader>(Signature::SRAT) else {
+        log::debug!("SRAT not found");
+        return;
+    };
+    log::debug!("Found SRAT");
+
+    numa::init(
+        table_bytes(srat),
+        find_table(Signature::SLIT).map(table_bytes),
+    );
+}
+
+/// Iterate over all System Description Tables referenced by the XSDT, or by the RSDT on
+/// ACPI 1.0 firmware
+///
+/// Empty without ACPI.
+pub fn tables() -> RootEntries {
+    let Some((root, entry_size)) = root_table() else {
+        return RootEntries {
+            root_address: 0,
+            entry_size: size_of::<u64>() as u64,
+            index: 0,
+            entries: 0,
+        };
+    };
+    let length = unsafe { root.as_ref() }.length as u64;
+    let entries = length.saturating_sub(RootEntries::LENGTH_SDT_HEADER) / entry_size;
+    // log::debug!("entries = {}", entries);
+
+    RootEntries {
+        root_address: root.as_ptr() as u64,
+        entry_size,
+        index: 0,
+        entries,
+    }
+}
+
+/// The XSDT with 64-bit entries if present and valid, else the RSDT with 32-bit entries
+fn root_table() -> Option<(NonNull<SdtHeader>, u64)> {
+    let rsdp = rsdp()?;
 
     // If the pointer to the XSDT is valid, the OS MUST use the XSDT.
-    let xsdt_address = VirtAddr::new(rsdp.xsdt_address());
-    log::debug!("Found XSDT");
+    // The XSDT address is only there since ACPI 2.0 (revision 2)
+    if rsdp.revision() > 0 {
+        // System Descriptor tables
+        // struct XSDT {
+        //     struct ACPISDTHeader h;
+        //     uint64_t PointerToOtherSDT[(h.Length - sizeof(h)) / 8];
+        // };
+        let xsdt = NonNull::new(rsdp.xsdt_address() as *mut SdtHeader);
+        if let Some(xsdt) = xsdt
+            && unsafe { xsdt.as_ref() }.validate(Signature::XSDT).is_ok()
+        {
+            return Some((xsdt, size_of::<u64>() as u64));
+        }
+    }
 
-    // System Descriptor tables
-    // struct XSDT {
+    // struct RSDT {
     //     struct ACPISDTHeader h;
-    //     uint64_t PointerToOtherSDT[(h.Length - sizeof(h)) / 8];
+    //     uint32_t PointerToOtherSDT[(h.Length - sizeof(h)) / 4];
     // };
-    let xsdt = xsdt_address.as_u64() as *mut SdtHeader;
-    let xsdt = unsafe { xsdt.as_ref() }.unwrap();
-    xsdt.validate(Signature::XSDT).expect("invalid XSDT");
-    // println!("XSDT = {:?}", xsdt);
+    let rsdt = NonNull::new(rsdp.rsdt_address() as u64 as *mut SdtHeader)?;
+    unsafe { rsdt.as_ref() }.validate(Signature::RSDT).ok()?;
+    Some((rsdt, size_of::<u32>() as u64))
+}
+
+/// The DSDT, referenced by the FADT instead of the XSDT
+///
+/// Not validated, see [`aml_blocks`].
+pub fn dsdt() -> Option<NonNull<SdtHeader>> {
+    let address = fadt()?.dsdt_address().ok()?;
+    NonNull::new(address as *mut SdtHeader)
+}
+
+/// Find a table by signature and validate its checksum
+pub fn find_table<T>(signature: Signature) -> Option<NonNull<T>> {
+    let (_, sdt) = tables().find(|(i, _)| *i == signature)?;
+    let header = unsafe { sdt.as_ref() };
+    if let Err(err) = header.validate(signature) {
+        log::warn!("Invalid {}: {:?}", signature, err);
+        return None;
+    }
+    Some(sdt.cast())
+}
+
+/// The bytes of a table found by [`find_table`], as many as its header says
+fn table_bytes(table: NonNull<SdtHeader>) -> &'static [u8] {
+    // SAFETY: the tables stay mapped, see `Tables`
+    let length = unsafe { table.as_ref() }.length as usize;
+    // SAFETY: as above, `find_table` summed the same bytes
+    unsafe { slice::from_raw_parts(table.as_ptr().cast::<u8>(), length) }
+}
+
+/// Read a packed field of a table or of one of its entries, `None` past the end of `bytes`
+fn read<T: Field>(bytes: &[u8], offset: usize) -> Option<T> {
+    let end = offset.checked_add(size_of::<T>())?;
+    let field = bytes.get(offset..end)?;
+    // SAFETY: in bounds, any bits are a valid `Field`
+    Some(unsafe { field.as_ptr().cast::<T>().read_unaligned() })
+}
 
-    let length = xsdt.length as u64;
+/// What [`read`] takes out of a table
+///
+/// # Safety
+/// Any bits are a valid value: integers, arrays of bytes and packed structs of integers.
+unsafe trait Field: Copy {}
+
+// SAFETY: integers
+unsafe impl Field for u8 {}
+// SAFETY: integers
+unsafe impl Field for u16 {}
+// SAFETY: integers
+unsafe impl Field for u32 {}
+// SAFETY: integers
+unsafe impl Field for u64 {}
+// SAFETY: bytes
+unsafe impl<const N: usize> Field for [u8; N] {}
+// SAFETY: a packed struct of integers
+unsafe impl Field for RawGenericAddress {}
+
+/// Iterator over the entries of the XSDT or RSDT, see [`tables`]
+pub struct RootEntries {
+    root_address: u64,
+    /// 8 bytes in the XSDT, 4 in the RSDT
+    entry_size: u64,
+    index: u64,
+    entries: u64,
+}
+
+impl RootEntries {
     const LENGTH_SDT_HEADER: u64 = size_of::<SdtHeader>() as u64;
-    const LENGTH_U64: usize = size_of::<u64>();
-    // let entries = (length - LENGTH_SDT_HEADER) / LENGTH_U64;
-    // log::debug!("entries = {}", entries);
+}
 
-    let mut fadt_address = None;
-
-    for others_address in
-        (xsdt_address + LENGTH_SDT_HEADER..xsdt_address + length).step_by(LENGTH_U64)
-    {
-        let sdt_address = others_address.as_u64() as *mut u64;
-        let sdt_address = unsafe { sdt_address.as_ref() }.unwrap();
-        let sdt_address = *sdt_address;
-
-        let sdt = sdt_address as *mut SdtHeader;
-        let sdt = unsafe { sdt.as_ref() }.unwrap();
-        match sdt.signature {
-            Signature::FADT => {
-                log::debug!("Found FADT");
-                fadt_address = Some(VirtAddr::new(sdt_address));
-                break;
-            }
-            _ => {
-                // log::debug!("0x{:08x} - found SDT", sdt_address);
-                // sdt.validate(sdt.signature).expect("invalid DST");
-            }
+impl Iterator for RootEntries {
+    type Item = (Signature, NonNull<SdtHeader>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.index < self.entries {
+            let others_address =
+                self.root_address + Self::LENGTH_SDT_HEADER + self.index * self.entry_size;
+            self.index += 1;
+
+            // XSDT entries are only 4-byte aligned
+            let sdt_address = if self.entry_size == size_of::<u64>() as u64 {
+                let sdt_address = others_address as *const u64;
+                unsafe { sdt_address.read_unaligned() }
+            } else {
+                let sdt_address = others_address as *const u32;
+                unsafe { sdt_address.read_unaligned() as u64 }
+            };
+
+            let Some(sdt) = NonNull::new(sdt_address as *mut SdtHeader) else {
+                continue;
+            };
+            let signature = unsafe { sdt.as_ref() }.signature;
+            // log::debug!("0x{:08x} - found {}", sdt_address, signature);
+            return Some((signature, sdt));
         }
+        None
     }
+}
+
+/// How long to wait for SCI_EN after writing ACPI_ENABLE to SMI_CMD
+const ENABLE_TIMEOUT: Duration = Duration::from_secs(3);
 
-    let fadt_address = fadt_address.expect("FADT not found");
-
-    let fadt = fadt_address.as_u64() as *mut Fadt;
-    let fadt = unsafe { fadt.as_ref() }.unwrap();
-    fadt.validate().expect("invalid FADT");
-
-    FADT.store(fadt_address.as_u64() as _, Ordering::Release);
-}
-
-// #[must_use]
-// pub fn is_enable() -> bool {
-//     let fadt = FADT.load(Ordering::Relaxed);
-//     let fadt = unsafe { fadt.as_ref() }.expect("no init FADT");
-
-//     // On some PCs, this is already done for you if...
-//     // the SMI command field in the FADT is 0
-//     // the ACPI enable and ACPI disable fields in the FADT are both 0
-//     // bit 0 (value 1) of the PM1a control block I/O port is set
-//     let t1 = fadt.smi_cmd_port;
-//     log::debug!("{} {} {}", t1, fadt.acpi_enable, fadt.acpi_disable);
-//     let rrr = fadt.pm1a_control_block().unwrap();
-//     let _rrr = rrr.address;
-//     todo!()
-// }
-
-// /// Switching to ACPI Mode
-// pub fn enable() {
-//     let fadt = FADT.load(Ordering::Relaxed);
-//     let fadt = unsafe { fadt.as_ref() }.expect("no init FADT");
-
-//     let rrr = fadt.pm1a_control_block().unwrap();
-//     let _rrr = rrr.address;
-//     todo!()
-//     // outb(fadt->smi_command,fadt->acpi_enable);
-//     // while (inw(fadt->pm1a_control_block) & 1 == 0);
-// }
+/// The system is in ACPI mode (SCI_EN is set)
+pub fn is_enable() -> Result<bool, Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+
+    // On some PCs, this is already done for you if...
+    // the SMI command field in the FADT is 0
+    // the ACPI enable and ACPI disable fields in the FADT are both 0
+    // bit 0 (value 1) of the PM1a control block I/O port is set
+    let smi_cmd_port = fadt.smi_cmd_port;
+    let (acpi_enable, acpi_disable) = (fadt.acpi_enable, fadt.acpi_disable);
+    log::debug!(
+        "ACPI: SMI_CMD={:#X} ACPI_ENABLE={:#X} ACPI_DISABLE={:#X}",
+        smi_cmd_port,
+        acpi_enable,
+        acpi_disable
+    );
+    if smi_cmd_port == 0 || (acpi_enable == 0 && acpi_disable == 0) {
+        // hardware-reduced or ACPI-only platform, there is no legacy mode
+        return Ok(true);
+    }
+
+    let pm1a = fadt.pm1a_control_block().map_err(Error::Acpi)?;
+    Ok(gas::read(&pm1a)?.get_bit(0))
+}
+
+/// Switching to ACPI Mode
+pub fn enable() -> Result<(), Error> {
+    if is_enable()? {
+        log::debug!("ACPI: already enabled");
+        return Ok(());
+    }
+
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+    let pm1a = fadt.pm1a_control_block().map_err(Error::Acpi)?;
+
+    // outb(fadt->smi_command,fadt->acpi_enable);
+    let mut port = Pio::<u8>::new(fadt.smi_cmd_port as u16);
+    // SAFETY: the port comes from the FADT
+    unsafe { port.write(fadt.acpi_enable) };
+
+    // while (inw(fadt->pm1a_control_block) & 1 == 0);
+    let result = poll_timeout(ENABLE_TIMEOUT, || match gas::read(&pm1a) {
+        Ok(value) if !value.get_bit(0) => None,
+        result => Some(result),
+    });
+    let Some(result) = result else {
+        log::warn!("ACPI: SCI_EN was not set after {:?}", ENABLE_TIMEOUT);
+        return Err(Error::Timeout);
+    };
+    result?;
+
+    log::debug!("ACPI: enabled");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_in_bounds() {
+        let bytes = [1, 2, 3, 4, 5];
+        assert_eq!(read::<u8>(&bytes, 4), Some(5));
+        assert_eq!(read::<u32>(&bytes, 1), Some(0x0504_0302));
+        assert_eq!(read::<[u8; 3]>(&bytes, 2), Some([3, 4, 5]));
+    }
+
+    #[test]
+    fn read_past_the_end() {
+        let bytes = [1, 2, 3, 4, 5];
+        assert_eq!(read::<u8>(&bytes, 5), None);
+        assert_eq!(read::<u32>(&bytes, 2), None);
+        assert_eq!(read::<u16>(&bytes, usize::MAX), None);
+    }
+}
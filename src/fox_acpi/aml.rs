@@ -0,0 +1,58 @@
+//! AML definition blocks
+//!
+//! The DSDT, which the FADT points at with X_DSDT or the older 32-bit DSDT field, and the SSDTs
+//! listed in the XSDT. Their AML byte code follows the table header.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#definition-blocks
+
+use core::mem::size_of;
+use core::ptr::NonNull;
+use core::slice;
+
+use acpi::sdt::{SdtHeader, Signature};
+
+use super::{dsdt, tables};
+
+/// Byte code of one DSDT or SSDT
+#[derive(Copy, Clone, Debug)]
+pub struct AmlBlock {
+    pub signature: Signature,
+    /// Address of the table
+    pub address: u64,
+    /// Without the table header
+    pub aml: &'static [u8],
+}
+
+/// The DSDT, then the SSDTs in XSDT order
+///
+/// Tables with a bad checksum are skipped with a warning.
+pub fn aml_blocks() -> impl Iterator<Item = AmlBlock> {
+    let dsdt = dsdt().map(|sdt| (Signature::DSDT, sdt));
+    let ssdts = tables().filter(|(signature, _)| *signature == Signature::SSDT);
+    dsdt.into_iter()
+        .chain(ssdts)
+        .filter_map(|(signature, sdt)| block(signature, sdt))
+}
+
+fn block(signature: Signature, sdt: NonNull<SdtHeader>) -> Option<AmlBlock> {
+    let address = sdt.as_ptr() as u64;
+    // SAFETY: ACPI tables stay mapped
+    let header = unsafe { sdt.as_ref() };
+    let length = header.length as usize;
+    if length < size_of::<SdtHeader>() {
+        log::warn!("Invalid {} at {:#x}: length {}", signature, address, length);
+        return None;
+    }
+    if let Err(err) = header.validate(signature) {
+        log::warn!("Invalid {} at {:#x}: {:?}", signature, address, err);
+        return None;
+    }
+
+    // SAFETY: as above, `length` bytes
+    let bytes = unsafe { slice::from_raw_parts(sdt.as_ptr().cast::<u8>(), length) };
+    Some(AmlBlock {
+        signature,
+        address,
+        aml: &bytes[size_of::<SdtHeader>()..],
+    })
+}
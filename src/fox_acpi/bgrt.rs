@@ -0,0 +1,80 @@
+//! Boot Graphics Resource Table (BGRT): where the firmware drew its logo
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#boot-graphics-resource-table-bgrt
+
+use core::mem::size_of;
+use core::slice;
+
+use acpi::sdt::{SdtHeader, Signature};
+use bit_field::BitField;
+
+use super::{Error, find_table, read, table_bytes};
+
+/// Larger images are taken as a broken table
+const MAX_IMAGE_SIZE: usize = 32 << 20;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Bgrt {
+    /// The logo is on the screen now
+    pub is_displayed: bool,
+    /// Clockwise rotation of the screen the offsets apply to, degrees
+    pub orientation: u16,
+    /// 0 is a BMP
+    pub image_type: u8,
+    /// Physical address of the image
+    pub image_address: u64,
+    /// Upper left corner of the logo on the screen
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Bgrt {
+    /// The BMP file, `None` for other image types
+    ///
+    /// # Safety
+    ///
+    /// The firmware may put the image in boot services memory, it must not have been reused.
+    pub unsafe fn image(&self) -> Option<&'static [u8]> {
+        if self.image_type != 0 || self.image_address == 0 {
+            return None;
+        }
+        let base = self.image_address as *const u8;
+        // BITMAPFILEHEADER: "BM", then the file size
+        let header = unsafe { slice::from_raw_parts(base, 6) };
+        if &header[..2] != b"BM" {
+            return None;
+        }
+        let size = u32::from_le_bytes(header[2..6].try_into().unwrap()) as usize;
+        if size > MAX_IMAGE_SIZE {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(base, size) })
+    }
+}
+
+pub fn bgrt() -> Result<Bgrt, Error> {
+    // log::trace!("bgrt");
+
+    let table = find_table::<SdtHeader>(Signature::BGRT).ok_or(Error::NoTable)?;
+    let table = table_bytes(table);
+
+    // struct BGRT {
+    //     struct ACPISDTHeader h;
+    //     uint16_t version;
+    //     uint8_t status;
+    //     uint8_t image_type;
+    //     uint64_t image_address;
+    //     uint32_t image_offset_x;
+    //     uint32_t image_offset_y;
+    // };
+    const OFFSET: usize = size_of::<SdtHeader>();
+    let status: u8 = read(table, OFFSET + 2).ok_or(Error::NoTable)?;
+    Ok(Bgrt {
+        is_displayed: status.get_bit(0),
+        orientation: status.get_bits(1..3) as u16 * 90,
+        image_type: read(table, OFFSET + 3).ok_or(Error::NoTable)?,
+        image_address: read(table, OFFSET + 4).ok_or(Error::NoTable)?,
+        x: read(table, OFFSET + 12).ok_or(Error::NoTable)?,
+        y: read(table, OFFSET + 16).ok_or(Error::NoTable)?,
+    })
+}
@@ -0,0 +1,119 @@
+//! Hex dumps and binary exports of ACPI tables
+//!
+//! Exported tables are written as they are in memory, `iasl -d DSDT.aml` disassembles them
+//! offline.
+//!
+//! https://wiki.osdev.org/System_Description_Tables
+
+use alloc::format;
+use core::ptr::NonNull;
+use core::{slice, str};
+
+use acpi::sdt::SdtHeader;
+use uefi::{CStr16, Status};
+
+use super::{Error, dsdt, tables};
+use crate::fox_bootlog;
+use crate::fox_log::hexdump;
+use crate::fox_println;
+use crate::fox_uefi::is_boot_services_active;
+
+/// Print the decoded header and a hex dump of every table with signature `name`
+///
+/// The signature is not case-sensitive, the DSDT is found through the FADT.
+pub fn dump_table(name: &str) -> Result<(), Error> {
+    // log::trace!("fox_acpi::dump_table({})", name);
+
+    let mut count = 0;
+    for sdt in find_tables(name) {
+        print_header(sdt);
+        hexdump(sdt.as_ptr() as u64, table_bytes(sdt));
+        count += 1;
+    }
+    if count == 0 {
+        return Err(Error::NoTable);
+    }
+    Ok(())
+}
+
+/// Save every table with signature `name` next to the boot log, returns how many
+///
+/// The first one is `<signature>.aml`, further ones get a number, as the SSDTs do. Needs boot
+/// services.
+pub fn save_table(name: &str) -> Result<usize, Error> {
+    // log::trace!("fox_acpi::save_table({})", name);
+    assert!(is_boot_services_active());
+
+    let mut count = 0;
+    for sdt in find_tables(name) {
+        // SAFETY: ACPI tables stay mapped
+        let signature = unsafe { sdt.as_ref() }.signature;
+        let file_name = if count == 0 {
+            format!("{}.aml", signature)
+        } else {
+            format!("{}{}.aml", signature, count)
+        };
+        let mut buffer = [0; 16];
+        let file_name = CStr16::from_str_with_buf(&file_name, &mut buffer)
+            .map_err(|_| Error::Uefi(Status::INVALID_PARAMETER.into()))?;
+        fox_bootlog::write_file(file_name, table_bytes(sdt)).map_err(Error::Uefi)?;
+        log::debug!("Saved {} ({} bytes)", file_name, table_bytes(sdt).len());
+        count += 1;
+    }
+    if count == 0 {
+        return Err(Error::NoTable);
+    }
+    Ok(count)
+}
+
+/// Tables of the XSDT and the DSDT with signature `name`
+fn find_tables(name: &str) -> impl Iterator<Item = NonNull<SdtHeader>> {
+    tables().map(|(_, sdt)| sdt).chain(dsdt()).filter(|sdt| {
+        // SAFETY: ACPI tables stay mapped
+        let signature = unsafe { sdt.as_ref() }.signature;
+        signature.as_str().eq_ignore_ascii_case(name)
+    })
+}
+
+/// The whole table, header included
+fn table_bytes(sdt: NonNull<SdtHeader>) -> &'static [u8] {
+    // SAFETY: ACPI tables stay mapped, `length` covers the header
+    unsafe {
+        let length = sdt.as_ref().length as usize;
+        slice::from_raw_parts(sdt.as_ptr().cast::<u8>(), length)
+    }
+}
+
+fn print_header(sdt: NonNull<SdtHeader>) {
+    // SAFETY: ACPI tables stay mapped
+    let header = unsafe { sdt.as_ref() };
+    let signature = header.signature;
+    let length = header.length;
+    let revision = header.revision;
+    let checksum = header.checksum;
+    let oem_revision = header.oem_revision;
+    let creator_id = header.creator_id.to_le_bytes();
+    let creator_revision = header.creator_revision;
+    let is_valid = header.validate(signature).is_ok();
+
+    fox_println!(
+        "{} at {:#x}\n  Length {} bytes, revision {}, checksum {:#04x} ({})",
+        signature,
+        sdt.as_ptr() as u64,
+        length,
+        revision,
+        checksum,
+        if is_valid { "valid" } else { "invalid" }
+    );
+    fox_println!(
+        "  OEM ID \"{}\", OEM table ID \"{}\", OEM revision {:#x}",
+        header.oem_id().unwrap_or("?"),
+        header.oem_table_id().unwrap_or("?"),
+        oem_revision
+    );
+    fox_println!(
+        "  Creator ID \"{}\", creator revision {:#x}",
+        str::from_utf8(&creator_id).unwrap_or("?"),
+        creator_revision
+    );
+}
@@ -0,0 +1,71 @@
+//! Embedded Controller Boot Resources Table (ECDT)
+//!
+//! Lets the OS use the embedded controller before the namespace is loaded. Firmware without it
+//! only declares the EC as a PNP0C09 device in the DSDT.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#embedded-controller-boot-resources-table-ecdt
+
+use core::mem::size_of;
+use core::str;
+
+use acpi::address::RawGenericAddress;
+use acpi::sdt::{SdtHeader, Signature};
+
+use super::gas::address_space;
+use super::{Error, find_table, read, table_bytes};
+
+/// Address space ID of system I/O in a GAS
+const SPACE_SYSTEM_IO: u8 = 1;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Ecdt {
+    /// EC_SC, the command and status register
+    pub command_port: u16,
+    /// EC_DATA
+    pub data_port: u16,
+    /// \_UID of the EC device
+    pub uid: u32,
+    /// GPE bit of the EC's SCI
+    pub gpe: u8,
+    /// Namespace path of the EC device, such as `\_SB.PCI0.LPCB.EC0`
+    pub id: &'static str,
+}
+
+/// The ECDT, if its registers are I/O ports
+pub fn ecdt() -> Result<Ecdt, Error> {
+    // log::trace!("ecdt");
+
+    let table = find_table::<SdtHeader>(Signature::ECDT).ok_or(Error::NoTable)?;
+    let table = table_bytes(table);
+
+    // struct ECDT {
+    //     struct ACPISDTHeader h;
+    //     struct GenericAddressStructure ec_control;
+    //     struct GenericAddressStructure ec_data;
+    //     uint32_t uid;
+    //     uint8_t gpe_bit;
+    //     char ec_id[];  // null-terminated
+    // };
+    const OFFSET: usize = size_of::<SdtHeader>();
+    const OFFSET_ID: usize = OFFSET + 29;
+    let id = table.get(OFFSET_ID..).ok_or(Error::NoTable)?;
+    let command_port = port(read(table, OFFSET).ok_or(Error::NoTable)?)?;
+    let data_port = port(read(table, OFFSET + 12).ok_or(Error::NoTable)?)?;
+
+    let id = id.split(|&i| i == 0).next().unwrap_or_default();
+    Ok(Ecdt {
+        command_port,
+        data_port,
+        uid: read(table, OFFSET + 24).ok_or(Error::NoTable)?,
+        gpe: read(table, OFFSET + 28).ok_or(Error::NoTable)?,
+        id: str::from_utf8(id).unwrap_or_default(),
+    })
+}
+
+/// Port of an EC register, the spec allows I/O and memory
+fn port(address: RawGenericAddress) -> Result<u16, Error> {
+    match address.address_space {
+        SPACE_SYSTEM_IO => Ok(address.address as u16),
+        space => Err(Error::UnsupportedAddressSpace(address_space(space)?)),
+    }
+}
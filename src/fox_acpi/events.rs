@@ -0,0 +1,197 @@
+//! Fixed and general purpose event registers
+//!
+//! The PM1 event blocks and the GPE0/GPE1 blocks have a status half followed by an enable half.
+//! An event raises the SCI when its status and enable bits are both set, a status bit set
+//! while sleeping wakes the machine. Status bits are cleared by writing 1, they are only read
+//! here.
+//!
+//! https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#pm1-event-grouping
+//! https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#general-purpose-event-register-blocks
+
+use core::ptr;
+
+use acpi::address::{AccessSize, GenericAddress};
+use bit_field::BitField;
+
+use super::{Error, fadt, gas, is_enable};
+
+/// Registers in each half of a GPE block, GPEx_BLK_LEN is at most 255 bytes
+const MAX_GPE_REGISTERS: usize = 127;
+
+/// Offsets of GPE0_BLK_LEN and GPE1_BLK_LEN in the FADT, the GAS bit width cannot hold them
+const FADT_GPE0_BLK_LEN: usize = 92;
+const FADT_GPE1_BLK_LEN: usize = 93;
+
+/// PM1 status bit, name, how the enable bit at the same position works
+const FIXED_EVENTS: [(usize, &str, Enable); 8] = [
+    (0, "timer carry", Enable::Set),
+    (4, "bus master", Enable::None),
+    (5, "global lock release", Enable::Set),
+    (8, "power button", Enable::Set),
+    (9, "sleep button", Enable::Set),
+    (10, "RTC alarm", Enable::Set),
+    (14, "PCIe wake", Enable::Clear),
+    (15, "wake", Enable::None),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Enable {
+    /// No enable bit, the event never raises the SCI
+    None,
+    /// Enabled when set
+    Set,
+    /// PCIEXP_WAKE_DIS, enabled when clear
+    Clear,
+}
+
+/// PM1a and PM1b ORed together, as the OS is meant to see them
+#[derive(Copy, Clone, Debug)]
+pub struct Pm1Registers {
+    pub status: u16,
+    pub enable: u16,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GpeBlock {
+    /// 0 or 1
+    pub index: u8,
+    /// Number of the first GPE, GPE1_BASE for GPE1
+    pub base: u16,
+    /// Registers in each half
+    registers: usize,
+    /// One bit per GPE, lowest register first
+    status: [u8; MAX_GPE_REGISTERS],
+    enable: [u8; MAX_GPE_REGISTERS],
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct EventRegisters {
+    /// SCI_INT, an ISA IRQ unless overridden in the MADT
+    pub sci_interrupt: u16,
+    /// SCI_EN, events go to the OS instead of SMM firmware
+    pub is_sci_enabled: bool,
+    /// `None` on hardware-reduced platforms
+    pub pm1: Option<Pm1Registers>,
+    /// GPE0 and GPE1, if present
+    pub gpe_blocks: [Option<GpeBlock>; 2],
+}
+
+impl Pm1Registers {
+    /// Fixed events with their status bit set, with whether they raise the SCI
+    pub fn pending(&self) -> impl Iterator<Item = (&'static str, bool)> {
+        let (status, enable) = (self.status, self.enable);
+        FIXED_EVENTS
+            .into_iter()
+            .filter(move |&(bit, _, _)| status.get_bit(bit))
+            .map(move |(bit, name, kind)| {
+                let is_enabled = match kind {
+                    Enable::None => false,
+                    Enable::Set => enable.get_bit(bit),
+                    Enable::Clear => !enable.get_bit(bit),
+                };
+                (name, is_enabled)
+            })
+    }
+}
+
+impl GpeBlock {
+    /// Number of GPEs in the block
+    pub fn count(&self) -> u16 {
+        self.registers as u16 * 8
+    }
+
+    pub fn status(&self) -> &[u8] {
+        &self.status[..self.registers]
+    }
+
+    pub fn enable(&self) -> &[u8] {
+        &self.enable[..self.registers]
+    }
+
+    /// GPEs with their status bit set, with whether they raise the SCI
+    pub fn pending(&self) -> impl Iterator<Item = (u16, bool)> + '_ {
+        (0..self.count()).filter_map(|i| {
+            let register = i as usize / 8;
+            let bit = i as usize % 8;
+            self.status[register]
+                .get_bit(bit)
+                .then(|| (self.base + i, self.enable[register].get_bit(bit)))
+        })
+    }
+}
+
+/// Read the PM1 and GPE status and enable registers named in the FADT
+pub fn event_registers() -> Result<EventRegisters, Error> {
+    // log::trace!("event_registers");
+
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+    let base = ptr::from_ref(fadt).cast::<u8>();
+
+    let pm1a = fadt.pm1a_event_block().map_err(Error::Acpi)?;
+    let pm1b = fadt.pm1b_event_block().map_err(Error::Acpi)?;
+    let mut pm1 = None;
+    for block in [Some(pm1a), pm1b].into_iter().flatten() {
+        if block.address == 0 {
+            continue;
+        }
+        let registers = pm1.get_or_insert(Pm1Registers {
+            status: 0,
+            enable: 0,
+        });
+        // 16-bit status, then 16-bit enable
+        registers.status |= gas::read(&register(&block, 0, AccessSize::WordAccess))? as u16;
+        registers.enable |= gas::read(&register(&block, 2, AccessSize::WordAccess))? as u16;
+    }
+
+    let mut gpe_blocks = [None; 2];
+    let gpe0 = fadt.gpe0_block().map_err(Error::Acpi)?;
+    let gpe1 = fadt.gpe1_block().map_err(Error::Acpi)?;
+    let gpe1_base = fadt.gpe1_base as u16;
+    let blocks = [
+        (0, gpe0, 0, FADT_GPE0_BLK_LEN),
+        (1, gpe1, gpe1_base, FADT_GPE1_BLK_LEN),
+    ];
+    for (index, block, first, len_offset) in blocks {
+        let Some(block) = block.filter(|block| block.address != 0) else {
+            continue;
+        };
+        // SAFETY: both lengths are in the ACPI 1.0 part of the FADT
+        let len = unsafe { base.add(len_offset).read() };
+        let half = len as usize / 2;
+        let mut gpe = GpeBlock {
+            index,
+            base: first,
+            registers: half,
+            status: [0; MAX_GPE_REGISTERS],
+            enable: [0; MAX_GPE_REGISTERS],
+        };
+        for i in 0..half {
+            gpe.status[i] = gas::read(&register(&block, i, AccessSize::ByteAccess))? as u8;
+            gpe.enable[i] = gas::read(&register(&block, half + i, AccessSize::ByteAccess))? as u8;
+        }
+        gpe_blocks[index as usize] = Some(gpe);
+    }
+
+    // packed, copied out
+    let sci_interrupt = fadt.sci_interrupt;
+    Ok(EventRegisters {
+        sci_interrupt,
+        is_sci_enabled: is_enable()?,
+        pm1,
+        gpe_blocks,
+    })
+}
+
+/// The register `offset` bytes into `block`, accessed `size` at a time
+fn register(block: &GenericAddress, offset: usize, size: AccessSize) -> GenericAddress {
+    let bit_width = match size {
+        AccessSize::ByteAccess => 8,
+        _ => 16,
+    };
+    GenericAddress {
+        address: block.address + offset as u64,
+        bit_width,
+        access_size: size,
+        ..*block
+    }
+}
@@ -0,0 +1,161 @@
+//! Firmware ACPI Control Structure (FACS) and the ACPI global lock
+//!
+//! The global lock serializes access to hardware shared with SMM firmware, e.g. the embedded
+//! controller. The lock word has a pending and an owned bit. A waiter sets pending, the owner
+//! then signals the release: the firmware with an SCI, we with GBL_RLS. Without an SCI handler
+//! a waiter polls until the owned bit clears.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#firmware-acpi-control-structure-facs
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#global-lock
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+
+use bit_field::BitField;
+
+use super::{Error, fadt, gas};
+use crate::fox_time::poll_timeout;
+
+/// How long the firmware may hold the lock
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+const GLOBAL_LOCK_PENDING: u32 = 1 << 0;
+const GLOBAL_LOCK_OWNED: u32 = 1 << 1;
+/// GBL_RLS in PM1 control, tells the firmware we released a lock it waits for
+const PM1_GBL_RLS: usize = 2;
+
+/// The fields we use, the structure is not packed
+#[repr(C)]
+struct RawFacs {
+    signature: [u8; 4],
+    length: u32,
+    hardware_signature: u32,
+    firmware_waking_vector: u32,
+    global_lock: AtomicU32,
+    flags: u32,
+    x_firmware_waking_vector: u64,
+    version: u8,
+}
+
+/// A validated FACS
+#[derive(Copy, Clone)]
+pub struct Facs(NonNull<RawFacs>);
+
+/// Held ACPI global lock, released when dropped
+pub struct GlobalLock {
+    facs: Facs,
+}
+
+impl Facs {
+    fn raw(&self) -> &'static RawFacs {
+        // SAFETY: checked by `facs`, the FACS stays mapped
+        unsafe { self.0.as_ref() }
+    }
+
+    /// Changes when the hardware configuration changed, the OS must not resume from S4 then
+    pub fn hardware_signature(&self) -> u32 {
+        self.raw().hardware_signature
+    }
+
+    pub fn version(&self) -> u8 {
+        self.raw().version
+    }
+
+    /// Where the firmware jumps on resume, X_FIRMWARE_WAKING_VECTOR if set
+    pub fn waking_vector(&self) -> u64 {
+        let raw = self.raw();
+        match raw.x_firmware_waking_vector {
+            0 => raw.firmware_waking_vector as u64,
+            vector => vector,
+        }
+    }
+
+    /// Resume from S3 in real mode at `address`, 0 to clear
+    ///
+    /// X_FIRMWARE_WAKING_VECTOR is cleared, the firmware then jumps to CS:IP
+    /// `address >> 4`:`address & 0xF`.
+    pub fn set_waking_vector(&self, address: u32) {
+        let raw = self.0.as_ptr();
+        // SAFETY: checked by `facs`, the firmware only reads these while we run
+        unsafe {
+            (&raw mut (*raw).firmware_waking_vector).write_volatile(address);
+            (&raw mut (*raw).x_firmware_waking_vector).write_volatile(0);
+        }
+    }
+
+    /// S4BIOS_F: the firmware can save memory for S4 itself
+    pub fn has_s4bios(&self) -> bool {
+        self.raw().flags.get_bit(0)
+    }
+
+    /// The lock word: bit 0 pending, bit 1 owned
+    pub fn global_lock_state(&self) -> u32 {
+        self.raw().global_lock.load(Ordering::Acquire)
+    }
+}
+
+/// The FACS pointed to by FADT.X_FIRMWARE_CTRL, else FIRMWARE_CTRL
+///
+/// The FACS has no checksum, its signature and length are checked.
+pub fn facs() -> Result<Facs, Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+    let address = fadt.facs_address().map_err(Error::Acpi)?;
+    let facs = NonNull::new(address as *mut RawFacs).ok_or(Error::InvalidFacs)?;
+    if address % 64 != 0 {
+        // must be 64-byte aligned, the lock is then naturally aligned
+        return Err(Error::InvalidFacs);
+    }
+    let raw = unsafe { facs.as_ref() };
+    if &raw.signature != b"FACS" || raw.length < 64 {
+        return Err(Error::InvalidFacs);
+    }
+    Ok(Facs(facs))
+}
+
+/// Take the ACPI global lock, waiting for the firmware to release it
+pub fn global_lock() -> Result<GlobalLock, Error> {
+    // log::trace!("global_lock");
+
+    let facs = facs()?;
+    let lock = &facs.raw().global_lock;
+    let is_acquired = poll_timeout(LOCK_TIMEOUT, || {
+        // owned if it was free, else pending so the owner signals the release
+        let previous = lock
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |value| {
+                let is_owned = value & GLOBAL_LOCK_OWNED != 0;
+                let pending = if is_owned { GLOBAL_LOCK_PENDING } else { 0 };
+                Some((value & !GLOBAL_LOCK_PENDING) | GLOBAL_LOCK_OWNED | pending)
+            })
+            .unwrap();
+        (previous & GLOBAL_LOCK_OWNED == 0).then_some(())
+    });
+    match is_acquired {
+        Some(()) => Ok(GlobalLock { facs }),
+        None => {
+            log::warn!("ACPI: global lock still owned after {:?}", LOCK_TIMEOUT);
+            Err(Error::Timeout)
+        }
+    }
+}
+
+impl Drop for GlobalLock {
+    fn drop(&mut self) {
+        let lock = &self.facs.raw().global_lock;
+        let previous = lock.fetch_and(!(GLOBAL_LOCK_PENDING | GLOBAL_LOCK_OWNED), Ordering::AcqRel);
+        if previous & GLOBAL_LOCK_PENDING != 0
+            && let Err(err) = signal_release()
+        {
+            log::warn!("ACPI: GBL_RLS failed: {}", err);
+        }
+    }
+}
+
+/// Set GBL_RLS, write-only, the other PM1 control bits are kept
+fn signal_release() -> Result<(), Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+    let pm1a = fadt.pm1a_control_block().map_err(Error::Acpi)?;
+    let mut value = gas::read(&pm1a)?;
+    value.set_bit(PM1_GBL_RLS, true);
+    gas::write(&pm1a, value)
+}
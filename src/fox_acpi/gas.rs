@@ -0,0 +1,177 @@
+//! Generic Address Structure (GAS) register access
+
+use acpi::AcpiError;
+use acpi::address::{AccessSize, AddressSpace, GenericAddress};
+use bit_field::BitField;
+
+use super::Error;
+use crate::fox_port::{Mmio, Pio};
+
+/// Register width in bits
+fn width(address: &GenericAddress) -> u8 {
+    match address.access_size {
+        AccessSize::ByteAccess => 8,
+        AccessSize::WordAccess => 16,
+        AccessSize::DWordAccess => 32,
+        AccessSize::QWordAccess => 64,
+        // legacy FADT fields only know the block length
+        AccessSize::Undefined => address.bit_width,
+    }
+}
+
+/// Address space of the raw ID of a GAS, the IDs in between are reserved
+pub fn address_space(id: u8) -> Result<AddressSpace, Error> {
+    match id {
+        0x00 => Ok(AddressSpace::SystemMemory),
+        0x01 => Ok(AddressSpace::SystemIo),
+        0x02 => Ok(AddressSpace::PciConfigSpace),
+        0x03 => Ok(AddressSpace::EmbeddedController),
+        0x04 => Ok(AddressSpace::SMBus),
+        0x05 => Ok(AddressSpace::SystemCmos),
+        0x06 => Ok(AddressSpace::PciBarTarget),
+        0x07 => Ok(AddressSpace::Ipmi),
+        0x08 => Ok(AddressSpace::GeneralIo),
+        0x09 => Ok(AddressSpace::GenericSerialBus),
+        0x0A => Ok(AddressSpace::PlatformCommunicationsChannel),
+        0x7F => Ok(AddressSpace::FunctionalFixedHardware),
+        0xC0..=0xFF => Ok(AddressSpace::OemDefined(id)),
+        _ => Err(Error::Acpi(AcpiError::InvalidGenericAddress)),
+    }
+}
+
+pub fn read(address: &GenericAddress) -> Result<u64, Error> {
+    match (address.address_space, width(address)) {
+        (AddressSpace::SystemIo, 8) => {
+            let port = Pio::<u8>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (AddressSpace::SystemIo, 16) => {
+            let port = Pio::<u16>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (AddressSpace::SystemIo, 32) => {
+            let port = Pio::<u32>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (AddressSpace::SystemMemory, 8) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            Ok(unsafe { Mmio::<u8>::new(address.address).read() } as u64)
+        }
+        (AddressSpace::SystemMemory, 16) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            Ok(unsafe { Mmio::<u16>::new(address.address).read() } as u64)
+        }
+        (AddressSpace::SystemMemory, 32) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            Ok(unsafe { Mmio::<u32>::new(address.address).read() } as u64)
+        }
+        (AddressSpace::SystemMemory, 64) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            Ok(unsafe { Mmio::<u64>::new(address.address).read() })
+        }
+        (AddressSpace::PciConfigSpace, 8) => {
+            let port = Pio::<u8>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (AddressSpace::PciConfigSpace, 16) => {
+            let port = Pio::<u16>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (AddressSpace::PciConfigSpace, 32) => {
+            let port = Pio::<u32>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            Ok(unsafe { port.read() } as u64)
+        }
+        (
+            AddressSpace::SystemIo | AddressSpace::SystemMemory | AddressSpace::PciConfigSpace,
+            width,
+        ) => Err(Error::UnsupportedWidth(width)),
+        (space, _) => Err(Error::UnsupportedAddressSpace(space)),
+    }
+}
+
+pub fn write(address: &GenericAddress, value: u64) -> Result<(), Error> {
+    match (address.address_space, width(address)) {
+        (AddressSpace::SystemIo, 8) => {
+            let mut port = Pio::<u8>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            unsafe { port.write(value as u8) };
+        }
+        (AddressSpace::SystemIo, 16) => {
+            let mut port = Pio::<u16>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            unsafe { port.write(value as u16) };
+        }
+        (AddressSpace::SystemIo, 32) => {
+            let mut port = Pio::<u32>::new(address.address as u16);
+            // SAFETY: the port comes from the firmware
+            unsafe { port.write(value as u32) };
+        }
+        (AddressSpace::SystemMemory, 8) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            unsafe { Mmio::<u8>::new(address.address).write(value as u8) };
+        }
+        (AddressSpace::SystemMemory, 16) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            unsafe { Mmio::<u16>::new(address.address).write(value as u16) };
+        }
+        (AddressSpace::SystemMemory, 32) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            unsafe { Mmio::<u32>::new(address.address).write(value as u32) };
+        }
+        (AddressSpace::SystemMemory, 64) => {
+            // SAFETY: the address comes from the firmware and is identity mapped
+            unsafe { Mmio::<u64>::new(address.address).write(value) };
+        }
+        (AddressSpace::PciConfigSpace, 8) => {
+            let mut port = Pio::<u8>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            unsafe { port.write(value as u8) };
+        }
+        (AddressSpace::PciConfigSpace, 16) => {
+            let mut port = Pio::<u16>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            unsafe { port.write(value as u16) };
+        }
+        (AddressSpace::PciConfigSpace, 32) => {
+            let mut port = Pio::<u32>::new(pci_config_select(address.address));
+            // SAFETY: the register comes from the firmware
+            unsafe { port.write(value as u32) };
+        }
+        (
+            AddressSpace::SystemIo | AddressSpace::SystemMemory | AddressSpace::PciConfigSpace,
+            width,
+        ) => return Err(Error::UnsupportedWidth(width)),
+        (space, _) => return Err(Error::UnsupportedAddressSpace(space)),
+    }
+    Ok(())
+}
+
+/// Select a register of segment 0, bus 0 with configuration mechanism #1
+///
+/// GAS address: device (bits 32-47), function (bits 16-31), offset (bits 0-15).
+/// Returns the data port of the register.
+fn pci_config_select(address: u64) -> u16 {
+    const PORT_CONFIG_ADDRESS: u16 = 0x0CF8;
+    const PORT_CONFIG_DATA: u16 = 0x0CFC;
+
+    let device = address.get_bits(32..48) as u32;
+    let function = address.get_bits(16..32) as u32;
+    let offset = address.get_bits(0..16) as u32;
+
+    let mut config_address = 0u32;
+    config_address.set_bit(31, true);
+    config_address.set_bits(11..16, device);
+    config_address.set_bits(8..11, function);
+    config_address.set_bits(2..8, offset >> 2);
+
+    let mut port = Pio::<u32>::new(PORT_CONFIG_ADDRESS);
+    // SAFETY: trust me
+    unsafe { port.write(config_address) };
+    PORT_CONFIG_DATA + (offset & 3) as u16
+}
@@ -0,0 +1,55 @@
+//! Generic Timer Description Table (GTDT): interrupts of the ARM generic timer
+//!
+//! The counter frequency is in CNTFRQ_EL0, the table gives the GIC interrupt of each timer and
+//! whether the counter keeps running in low-power states. Platform timers (GT blocks and SBSA
+//! watchdogs) follow the fixed part and are not parsed.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#generic-timer-description-table-gtdt
+
+use core::mem::size_of;
+
+use acpi::sdt::{SdtHeader, Signature};
+use bit_field::BitField;
+
+use super::{Error, find_table, read, table_bytes};
+
+#[derive(Copy, Clone, Debug)]
+pub struct Gtdt {
+    /// GSIV of the EL1 physical timer of the non-secure world, 0 if none
+    pub physical_gsiv: u32,
+    /// GSIV of the EL1 virtual timer, the one of [`crate::fox_arch::counter`]
+    pub virtual_gsiv: u32,
+    /// The counter keeps running in every power state
+    pub is_always_on: bool,
+}
+
+pub fn gtdt() -> Result<Gtdt, Error> {
+    // log::trace!("gtdt");
+
+    let table = find_table::<SdtHeader>(Signature::GTDT).ok_or(Error::NoTable)?;
+    let table = table_bytes(table);
+
+    // struct GTDT {
+    //     struct ACPISDTHeader h;
+    //     uint64_t cnt_control_base;
+    //     uint32_t reserved;
+    //     uint32_t secure_el1_gsiv, secure_el1_flags;
+    //     uint32_t non_secure_el1_gsiv, non_secure_el1_flags;
+    //     uint32_t virtual_el1_gsiv, virtual_el1_flags;
+    //     uint32_t el2_gsiv, el2_flags;
+    //     uint64_t cnt_read_base;
+    //     uint32_t platform_timer_count, platform_timer_offset;
+    // };
+    const OFFSET: usize = size_of::<SdtHeader>();
+    if table.len() < OFFSET + 44 {
+        return Err(Error::NoTable);
+    }
+    Ok(Gtdt {
+        physical_gsiv: read(table, OFFSET + 20).ok_or(Error::NoTable)?,
+        virtual_gsiv: read(table, OFFSET + 28).ok_or(Error::NoTable)?,
+        // in the flags of every timer, ours is the virtual one
+        is_always_on: read::<u32>(table, OFFSET + 32)
+            .ok_or(Error::NoTable)?
+            .get_bit(2),
+    })
+}
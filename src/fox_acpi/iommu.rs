@@ -0,0 +1,427 @@
+//! IOMMUs from the DMA Remapping table (DMAR, Intel VT-d) or the I/O Virtualization Reporting
+//! Structure (IVRS, AMD-Vi)
+//!
+//! Only what shows the configuration is kept: the remapping units, their register base and the
+//! devices they cover. Scope paths through bridges are not resolved, the first hop is shown.
+//!
+//! https://www.intel.com/content/www/us/en/content-details/774206/intel-virtualization-technology-for-directed-i-o-architecture-specification.html (chapter 8)
+//! https://www.amd.com/content/dam/amd/en/documents/processor-tech-docs/specifications/48882_IOMMU.pdf (chapter 5)
+
+use core::fmt;
+use core::mem::size_of;
+
+use acpi::sdt::SdtHeader;
+use bit_field::BitField;
+
+use super::{TABLES, read, set_table};
+use crate::drivers::PciAddress;
+use crate::fox_vec::FixedVec;
+
+const MAX_UNITS: usize = 32;
+const MAX_SCOPES: usize = 256;
+const MAX_RESERVED: usize = 32;
+
+/// Storage for [`super::iommu`], written once by [`init_dmar`] or [`init_ivrs`]
+static mut IOMMU_INFO: IommuInfo = IommuInfo::new();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Intel VT-d, from the DMAR
+    Vtd,
+    /// AMD-Vi, from the IVRS
+    AmdVi,
+}
+
+/// Remapping units, the devices they cover and the memory kept identity-mapped
+#[derive(Debug)]
+pub struct IommuInfo {
+    pub kind: Kind,
+    /// DMA address width in bits (DMAR), or virtual address size (IVRS)
+    pub address_width: u8,
+    pub units: FixedVec<Unit, MAX_UNITS>,
+    pub scopes: FixedVec<DeviceScope, MAX_SCOPES>,
+    /// Reserved Memory Region Reporting (DMAR only)
+    pub reserved: FixedVec<ReservedRegion, MAX_RESERVED>,
+}
+
+/// DMA Remapping Hardware Unit Definition (DRHD) or I/O Virtualization Hardware Definition (IVHD)
+#[derive(Copy, Clone, Debug)]
+pub struct Unit {
+    pub segment: u16,
+    /// Physical address of the registers
+    pub base_address: u64,
+    /// Covers every device of the segment not claimed by another unit (DRHD INCLUDE_PCI_ALL)
+    pub is_catch_all: bool,
+    /// The IOMMU's own PCI function (IVHD only)
+    pub address: Option<PciAddress>,
+}
+
+/// Device Scope (DMAR) or IVHD device entry, which devices a unit translates for
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceScope {
+    /// Index into [`IommuInfo::units`]
+    pub unit: usize,
+    pub kind: ScopeKind,
+    pub address: PciAddress,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ScopeKind {
+    Endpoint,
+    /// A bridge and everything behind it
+    Bridge,
+    /// I/O APIC with its APIC ID
+    IoApic(u8),
+    /// HPET with its number
+    Hpet(u8),
+    /// ACPI namespace device with its enumeration ID
+    AcpiDevice(u8),
+    /// Every device (IVHD)
+    All,
+    /// Devices from this one up to the address (IVHD)
+    Range(PciAddress),
+}
+
+/// Memory a device keeps using for DMA, e.g. USB legacy emulation buffers
+#[derive(Copy, Clone, Debug)]
+pub struct ReservedRegion {
+    pub segment: u16,
+    pub base: u64,
+    /// Last byte
+    pub limit: u64,
+}
+
+impl IommuInfo {
+    const fn new() -> Self {
+        Self {
+            kind: Kind::Vtd,
+            address_width: 0,
+            units: FixedVec::new(),
+            scopes: FixedVec::new(),
+            reserved: FixedVec::new(),
+        }
+    }
+
+    /// Add a scope to the last unit
+    fn push_scope(&mut self, kind: ScopeKind, address: PciAddress) {
+        let scope = DeviceScope {
+            unit: self.units.len() - 1,
+            kind,
+            address,
+        };
+        if self.scopes.push(scope).is_err() {
+            log::warn!("{}: too many device scopes", self.kind.table());
+        }
+    }
+}
+
+impl Kind {
+    fn table(self) -> &'static str {
+        match self {
+            Self::Vtd => "DMAR",
+            Self::AmdVi => "IVRS",
+        }
+    }
+}
+
+/// Parse the remapping structures of a validated DMAR
+pub(super) fn init_dmar(dmar: &[u8]) {
+    // log::trace!("iommu::init_dmar");
+
+    let info = &raw mut IOMMU_INFO;
+    // SAFETY: called once during boot, before anyone reads `TABLES.iommu`
+    let info = unsafe { &mut *info };
+    info.kind = Kind::Vtd;
+
+    // struct DMAR {
+    //     struct ACPISDTHeader h;
+    //     uint8_t host_address_width;  // N-1
+    //     uint8_t flags;
+    //     uint8_t reserved[10];
+    //     remapping structures...
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 12;
+    let Some(width) = read::<u8>(dmar, size_of::<SdtHeader>()) else {
+        log::warn!("DMAR: truncated");
+        return;
+    };
+    info.address_width = width + 1;
+
+    let mut offset = OFFSET_ENTRIES;
+    while let (Some(entry_type), Some(entry_length)) =
+        (read::<u16>(dmar, offset), read::<u16>(dmar, offset + 2))
+    {
+        let entry_length = entry_length as usize;
+        if entry_length < 4 || offset + entry_length > dmar.len() {
+            log::warn!("DMAR: invalid entry length {} at {}", entry_length, offset);
+            break;
+        }
+        let entry = &dmar[offset..offset + entry_length];
+
+        match entry_type {
+            // DRHD
+            0 => match read_drhd(entry) {
+                None => log::warn!("DMAR: DRHD at {} too short", offset),
+                Some(unit) if info.units.push(unit).is_err() => {
+                    log::warn!("DMAR: too many remapping units")
+                }
+                Some(_) => dmar_scopes(info, entry.get(16..).unwrap_or_default()),
+            },
+            // RMRR, its scopes name the devices using the region
+            1 => match read_rmrr(entry) {
+                None => log::warn!("DMAR: RMRR at {} too short", offset),
+                Some(region) if info.reserved.push(region).is_err() => {
+                    log::warn!("DMAR: too many reserved regions")
+                }
+                Some(_) => {}
+            },
+            _ => {
+                // log::debug!("DMAR: skip structure type {}", entry_type);
+            }
+        }
+
+        offset += entry_length;
+    }
+
+    log_info(info);
+    set_table(&TABLES.iommu, info, "IOMMU");
+}
+
+/// DMA Remapping Hardware Unit Definition, without its device scopes
+fn read_drhd(entry: &[u8]) -> Option<Unit> {
+    Some(Unit {
+        is_catch_all: read::<u8>(entry, 4)?.get_bit(0),
+        segment: read(entry, 6)?,
+        base_address: read(entry, 8)?,
+        address: None,
+    })
+}
+
+/// Reserved Memory Region Reporting
+fn read_rmrr(entry: &[u8]) -> Option<ReservedRegion> {
+    Some(ReservedRegion {
+        segment: read(entry, 6)?,
+        base: read(entry, 8)?,
+        limit: read(entry, 16)?,
+    })
+}
+
+/// Device scopes of a DRHD
+fn dmar_scopes(info: &mut IommuInfo, scopes: &[u8]) {
+    // struct DeviceScope {
+    //     uint8_t type;
+    //     uint8_t length;
+    //     uint16_t reserved;
+    //     uint8_t enumeration_id;
+    //     uint8_t start_bus;
+    //     struct { uint8_t device; uint8_t function; } path[];
+    // };
+    let mut offset = 0;
+    while offset + 8 <= scopes.len() {
+        let scope_type = scopes[offset];
+        let scope_length = scopes[offset + 1] as usize;
+        if scope_length < 8 || offset + scope_length > scopes.len() {
+            log::warn!("DMAR: invalid device scope length {}", scope_length);
+            return;
+        }
+        let scope = &scopes[offset..offset + scope_length];
+        let id = scope[4];
+        let address = PciAddress {
+            bus: scope[5],
+            device: scope[6],
+            function: scope[7],
+        };
+        let kind = match scope_type {
+            1 => Some(ScopeKind::Endpoint),
+            2 => Some(ScopeKind::Bridge),
+            3 => Some(ScopeKind::IoApic(id)),
+            4 => Some(ScopeKind::Hpet(id)),
+            5 => Some(ScopeKind::AcpiDevice(id)),
+            _ => None,
+        };
+        if let Some(kind) = kind {
+            info.push_scope(kind, address);
+        }
+        offset += scope_length;
+    }
+}
+
+/// Parse the IVHD blocks of a validated IVRS
+pub(super) fn init_ivrs(ivrs: &[u8]) {
+    // log::trace!("iommu::init_ivrs");
+
+    let info = &raw mut IOMMU_INFO;
+    // SAFETY: called once during boot, before anyone reads `TABLES.iommu`
+    let info = unsafe { &mut *info };
+    info.kind = Kind::AmdVi;
+
+    // struct IVRS {
+    //     struct ACPISDTHeader h;
+    //     uint32_t iv_info;  // bits 15-21: virtual address size
+    //     uint64_t reserved;
+    //     IVHD and IVMD blocks...
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 12;
+    let Some(iv_info) = read::<u32>(ivrs, size_of::<SdtHeader>()) else {
+        log::warn!("IVRS: truncated");
+        return;
+    };
+    info.address_width = iv_info.get_bits(15..22) as u8;
+
+    let mut offset = OFFSET_ENTRIES;
+    while let (Some(entry_type), Some(entry_length)) =
+        (read::<u8>(ivrs, offset), read::<u16>(ivrs, offset + 2))
+    {
+        let entry_length = entry_length as usize;
+        if entry_length < 4 || offset + entry_length > ivrs.len() {
+            log::warn!("IVRS: invalid block length {} at {}", entry_length, offset);
+            break;
+        }
+        let entry = &ivrs[offset..offset + entry_length];
+
+        // types 0x11 and 0x40 describe the same IOMMU with more features than 0x10, the firmware
+        // lists it once per type
+        if entry_type == 0x10 {
+            match read_ivhd(entry) {
+                None => log::warn!("IVRS: IVHD at {} too short", offset),
+                Some(unit) if info.units.push(unit).is_err() => {
+                    log::warn!("IVRS: too many IOMMUs")
+                }
+                Some(_) => {
+                    if ivhd_entries(info, entry.get(24..).unwrap_or_default()).is_none() {
+                        log::warn!("IVRS: truncated device entry in the IVHD at {}", offset);
+                    }
+                }
+            }
+        }
+
+        offset += entry_length;
+    }
+
+    log_info(info);
+    set_table(&TABLES.iommu, info, "IOMMU");
+}
+
+/// I/O Virtualization Hardware Definition, without its device entries
+fn read_ivhd(entry: &[u8]) -> Option<Unit> {
+    Some(Unit {
+        segment: read(entry, 16)?,
+        base_address: read(entry, 8)?,
+        is_catch_all: false,
+        address: Some(pci_address(read(entry, 4)?)),
+    })
+}
+
+/// Device entries of an IVHD, `None` at one past the end
+fn ivhd_entries(info: &mut IommuInfo, entries: &[u8]) -> Option<()> {
+    let mut range_start = None;
+    let mut offset = 0;
+    while offset + 4 <= entries.len() {
+        let entry_type = entries[offset];
+        let device = pci_address(read(entries, offset + 1)?);
+        // the size is in the type: 4 bytes below 64, 8 bytes up to 127, variable for ACPI HIDs
+        let entry_length = match entry_type {
+            0..64 => 4,
+            64..128 => 8,
+            0xF0 => 22 + read::<u8>(entries, offset + 21)? as usize,
+            _ => {
+                log::warn!("IVRS: unknown device entry type {:#x}", entry_type);
+                return Some(());
+            }
+        };
+        let entry = entries.get(offset..offset + entry_length)?;
+
+        match entry_type {
+            1 => info.push_scope(ScopeKind::All, device),
+            // select, alias select, extended select
+            2 | 0x42 | 0x46 => info.push_scope(ScopeKind::Endpoint, device),
+            // start of a range, plain, alias or extended
+            3 | 0x43 | 0x47 => range_start = Some(device),
+            4 => {
+                if let Some(start) = range_start.take() {
+                    info.push_scope(ScopeKind::Range(device), start);
+                }
+            }
+            // special device: handle, device ID, variety
+            0x48 => {
+                let handle = entry[4];
+                let device = pci_address(read(entry, 5)?);
+                match entry[7] {
+                    1 => info.push_scope(ScopeKind::IoApic(handle), device),
+                    2 => info.push_scope(ScopeKind::Hpet(handle), device),
+                    _ => {}
+                }
+            }
+            0xF0 => info.push_scope(ScopeKind::AcpiDevice(0), device),
+            _ => {}
+        }
+        offset += entry_length;
+    }
+    Some(())
+}
+
+/// IVRS device ID: bus, device, function in 8, 5 and 3 bits
+fn pci_address(id: u16) -> PciAddress {
+    PciAddress {
+        bus: id.get_bits(8..16) as u8,
+        device: id.get_bits(3..8) as u8,
+        function: id.get_bits(0..3) as u8,
+    }
+}
+
+fn log_info(info: &IommuInfo) {
+    let table = info.kind.table();
+    log::debug!("{}: {}-bit addresses", table, info.address_width);
+    for (index, unit) in info.units.iter().enumerate() {
+        let catch_all = if unit.is_catch_all {
+            ", all others"
+        } else {
+            ""
+        };
+        match unit.address {
+            Some(address) => log::debug!(
+                "{}: IOMMU {} at {:#X}, segment {}, function {}{}",
+                table,
+                index,
+                unit.base_address,
+                unit.segment,
+                address,
+                catch_all
+            ),
+            None => log::debug!(
+                "{}: IOMMU {} at {:#X}, segment {}{}",
+                table,
+                index,
+                unit.base_address,
+                unit.segment,
+                catch_all
+            ),
+        }
+        for scope in info.scopes.iter().filter(|i| i.unit == index) {
+            log::debug!("{}:   {}", table, scope);
+        }
+    }
+    for region in info.reserved.iter() {
+        log::debug!(
+            "{}: reserved memory {:#X}-{:#X}, segment {}",
+            table,
+            region.base,
+            region.limit,
+            region.segment
+        );
+    }
+}
+
+impl fmt::Display for DeviceScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ScopeKind::Endpoint => write!(f, "endpoint {}", self.address),
+            ScopeKind::Bridge => write!(f, "bridge {} and below", self.address),
+            ScopeKind::IoApic(id) => write!(f, "I/O APIC {} as {}", id, self.address),
+            ScopeKind::Hpet(id) => write!(f, "HPET {} as {}", id, self.address),
+            ScopeKind::AcpiDevice(id) => write!(f, "ACPI device {} as {}", id, self.address),
+            ScopeKind::All => write!(f, "all devices"),
+            ScopeKind::Range(end) => write!(f, "devices {} to {}", self.address, end),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+//! Multiple APIC Description Table (MADT)
+//!
+//! https://wiki.osdev.org/MADT
+
+use core::mem::size_of;
+
+use acpi::sdt::SdtHeader;
+use bit_field::BitField;
+
+use super::{TABLES, read, set_table};
+use crate::fox_vec::FixedVec;
+
+const MAX_LOCAL_APICS: usize = 256;
+const MAX_IO_APICS: usize = 16;
+const MAX_OVERRIDES: usize = 32;
+const MAX_NMI_SOURCES: usize = 16;
+const MAX_LOCAL_APIC_NMIS: usize = 64;
+
+/// Storage for [`super::madt`], written once by [`init`]
+static mut MADT_INFO: MadtInfo = MadtInfo::new();
+
+/// Interrupt topology collected from the MADT
+#[derive(Debug)]
+pub struct MadtInfo {
+    /// Physical address of the local APIC (may be overridden by entry type 5)
+    pub local_apic_address: u64,
+    /// The system also has a PC-AT-compatible dual-8259 setup
+    pub has_8259: bool,
+    pub local_apics: FixedVec<LocalApic, MAX_LOCAL_APICS>,
+    pub io_apics: FixedVec<IoApic, MAX_IO_APICS>,
+    pub overrides: FixedVec<InterruptSourceOverride, MAX_OVERRIDES>,
+    pub nmi_sources: FixedVec<NmiSource, MAX_NMI_SOURCES>,
+    pub local_apic_nmis: FixedVec<LocalApicNmi, MAX_LOCAL_APIC_NMIS>,
+}
+
+/// Processor Local APIC (entry type 0) or Processor Local x2APIC (entry type 9)
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApic {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub flags: u32,
+}
+
+/// I/O APIC (entry type 1)
+#[derive(Copy, Clone, Debug)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    /// The first global system interrupt number that this I/O APIC handles
+    pub gsi_base: u32,
+}
+
+/// Interrupt Source Override (entry type 2)
+#[derive(Copy, Clone, Debug)]
+pub struct InterruptSourceOverride {
+    pub bus: u8,
+    /// ISA IRQ
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// Non-maskable Interrupt Source (entry type 3)
+#[derive(Copy, Clone, Debug)]
+pub struct NmiSource {
+    pub flags: u16,
+    pub gsi: u32,
+}
+
+/// Local APIC Non-maskable Interrupts (entry type 4) or Local x2APIC NMI (entry type 0xA)
+#[derive(Copy, Clone, Debug)]
+pub struct LocalApicNmi {
+    /// 0xFF (0xFFFFFFFF for x2APIC) means all processors
+    pub processor_uid: u32,
+    pub flags: u16,
+    /// LINT# (0 or 1)
+    pub lint: u8,
+}
+
+impl MadtInfo {
+    const fn new() -> Self {
+        Self {
+            local_apic_address: 0,
+            has_8259: false,
+            local_apics: FixedVec::new(),
+            io_apics: FixedVec::new(),
+            overrides: FixedVec::new(),
+            nmi_sources: FixedVec::new(),
+            local_apic_nmis: FixedVec::new(),
+        }
+    }
+}
+
+impl MadtInfo {
+    /// Override for an ISA IRQ, if the firmware routes it to a different GSI
+    pub fn isa_override(&self, irq: u8) -> Option<&InterruptSourceOverride> {
+        self.overrides
+            .iter()
+            .find(|i| i.bus == 0 && i.source == irq)
+    }
+}
+
+impl LocalApic {
+    /// Processor Enabled
+    pub fn is_enabled(&self) -> bool {
+        self.flags.get_bit(0)
+    }
+}
+
+/// Parse entries of a validated MADT
+pub(super) fn init(madt: &[u8]) {
+    // log::trace!("madt::init");
+
+    let info = &raw mut MADT_INFO;
+    // SAFETY: called once during boot, before anyone reads `TABLES.madt`
+    let info = unsafe { &mut *info };
+
+    // struct MADT {
+    //     struct ACPISDTHeader h;
+    //     uint32_t local_apic_address;
+    //     uint32_t flags;
+    //     entries...
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 8;
+    let (Some(address), Some(flags)) = (
+        read::<u32>(madt, size_of::<SdtHeader>()),
+        read::<u32>(madt, size_of::<SdtHeader>() + 4),
+    ) else {
+        log::warn!("MADT: truncated");
+        return;
+    };
+    info.local_apic_address = address as u64;
+    info.has_8259 = flags.get_bit(0);
+
+    let mut offset = OFFSET_ENTRIES;
+    while offset + 2 <= madt.len() {
+        let entry_type = madt[offset];
+        let entry_length = madt[offset + 1] as usize;
+        if entry_length < 2 || offset + entry_length > madt.len() {
+            log::warn!("MADT: invalid entry length {} at {}", entry_length, offset);
+            break;
+        }
+        let entry = &madt[offset..offset + entry_length];
+
+        match push_entry(info, entry_type, entry) {
+            Some(true) => {}
+            Some(false) => log::warn!("MADT: too many entries of type {}", entry_type),
+            None => log::warn!("MADT: entry type {} at {} too short", entry_type, offset),
+        }
+
+        offset += entry_length;
+    }
+
+    for i in info.local_apics.iter() {
+        log::debug!(
+            "MADT: CPU {}: APIC ID {} (flags {:#X})",
+            i.processor_uid,
+            i.apic_id,
+            i.flags
+        );
+    }
+    for i in info.io_apics.iter() {
+        log::debug!(
+            "MADT: IO APIC {} at {:#X}, GSI base {}",
+            i.id,
+            i.address,
+            i.gsi_base
+        );
+    }
+    for i in info.overrides.iter() {
+        log::debug!(
+            "MADT: bus {} IRQ {} -> GSI {} (flags {:#X})",
+            i.bus,
+            i.source,
+            i.gsi,
+            i.flags
+        );
+    }
+    for i in info.nmi_sources.iter() {
+        log::debug!("MADT: NMI GSI {} (flags {:#X})", i.gsi, i.flags);
+    }
+    for i in info.local_apic_nmis.iter() {
+        log::debug!(
+            "MADT: LINT{} NMI for CPU {:#X} (flags {:#X})",
+            i.lint,
+            i.processor_uid,
+            i.flags
+        );
+    }
+    log::debug!(
+        "MADT: {} local APICs, {} IO APICs, {} overrides, {} NMI sources",
+        info.local_apics.len(),
+        info.io_apics.len(),
+        info.overrides.len(),
+        info.nmi_sources.len()
+    );
+
+    set_table(&TABLES.madt, info, "MADT");
+}
+
+/// Keep an entry, `Some(false)` if there is no room for it, `None` if it is too short
+fn push_entry(info: &mut MadtInfo, entry_type: u8, entry: &[u8]) -> Option<bool> {
+    let is_pushed = match entry_type {
+        0 => info
+            .local_apics
+            .push(LocalApic {
+                processor_uid: read::<u8>(entry, 2)? as u32,
+                apic_id: read::<u8>(entry, 3)? as u32,
+                flags: read(entry, 4)?,
+            })
+            .is_ok(),
+        1 => info
+            .io_apics
+            .push(IoApic {
+                id: read(entry, 2)?,
+                address: read(entry, 4)?,
+                gsi_base: read(entry, 8)?,
+            })
+            .is_ok(),
+        2 => info
+            .overrides
+            .push(InterruptSourceOverride {
+                bus: read(entry, 2)?,
+                source: read(entry, 3)?,
+                gsi: read(entry, 4)?,
+                flags: read(entry, 8)?,
+            })
+            .is_ok(),
+        3 => info
+            .nmi_sources
+            .push(NmiSource {
+                flags: read(entry, 2)?,
+                gsi: read(entry, 4)?,
+            })
+            .is_ok(),
+        4 => info
+            .local_apic_nmis
+            .push(LocalApicNmi {
+                processor_uid: read::<u8>(entry, 2)? as u32,
+                flags: read(entry, 3)?,
+                lint: read(entry, 5)?,
+            })
+            .is_ok(),
+        5 => {
+            info.local_apic_address = read(entry, 4)?;
+            true
+        }
+        9 => info
+            .local_apics
+            .push(LocalApic {
+                apic_id: read(entry, 4)?,
+                flags: read(entry, 8)?,
+                processor_uid: read(entry, 12)?,
+            })
+            .is_ok(),
+        0xA => info
+            .local_apic_nmis
+            .push(LocalApicNmi {
+                flags: read(entry, 2)?,
+                processor_uid: read(entry, 4)?,
+                lint: read(entry, 8)?,
+            })
+            .is_ok(),
+        _ => {
+            // log::debug!("MADT: skip entry type {}", entry_type);
+            true
+        }
+    };
+    Some(is_pushed)
+}
@@ -0,0 +1,97 @@
+//! PCI Express Memory-mapped Configuration Space base address description table (MCFG)
+//!
+//! https://wiki.osdev.org/PCI_Express
+
+use core::mem::size_of;
+
+use acpi::sdt::SdtHeader;
+
+use super::{TABLES, read, set_table};
+use crate::fox_vec::FixedVec;
+
+const MAX_ENTRIES: usize = 16;
+
+/// Storage for [`super::mcfg`], written once by [`init`]
+static mut MCFG_INFO: McfgInfo = McfgInfo::new();
+
+/// ECAM regions collected from the MCFG
+#[derive(Debug)]
+pub struct McfgInfo {
+    pub entries: FixedVec<McfgEntry, MAX_ENTRIES>,
+}
+
+/// Configuration Space Base Address Allocation Structure
+#[derive(Copy, Clone, Debug)]
+pub struct McfgEntry {
+    /// Physical address of the configuration space of `start_bus`
+    pub base_address: u64,
+    /// PCI Segment Group Number
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+impl McfgInfo {
+    const fn new() -> Self {
+        Self {
+            entries: FixedVec::new(),
+        }
+    }
+
+    /// Region covering `bus` of `segment`
+    pub fn find(&self, segment: u16, bus: u8) -> Option<&McfgEntry> {
+        self.entries
+            .iter()
+            .find(|i| i.segment == segment && (i.start_bus..=i.end_bus).contains(&bus))
+    }
+}
+
+/// Parse entries of a validated MCFG
+pub(super) fn init(mcfg: &[u8]) {
+    // log::trace!("mcfg::init");
+
+    let info = &raw mut MCFG_INFO;
+    // SAFETY: called once during boot, before anyone reads `TABLES.mcfg`
+    let info = unsafe { &mut *info };
+
+    // struct MCFG {
+    //     struct ACPISDTHeader h;
+    //     uint64_t reserved;
+    //     struct {
+    //         uint64_t base_address;
+    //         uint16_t segment;
+    //         uint8_t start_bus;
+    //         uint8_t end_bus;
+    //         uint32_t reserved;
+    //     } entries[];
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 8;
+    const LENGTH_ENTRY: usize = 16;
+
+    let entries = mcfg.get(OFFSET_ENTRIES..).unwrap_or_default();
+    for entry in entries.chunks_exact(LENGTH_ENTRY).filter_map(read_entry) {
+        log::debug!(
+            "MCFG: segment {} buses {:02x}-{:02x} at {:#X}",
+            entry.segment,
+            entry.start_bus,
+            entry.end_bus,
+            entry.base_address
+        );
+        if info.entries.push(entry).is_err() {
+            log::warn!("MCFG: too many entries");
+            break;
+        }
+    }
+
+    set_table(&TABLES.mcfg, info, "MCFG");
+}
+
+/// A Configuration Space Base Address Allocation Structure
+fn read_entry(entry: &[u8]) -> Option<McfgEntry> {
+    Some(McfgEntry {
+        base_address: read(entry, 0)?,
+        segment: read(entry, 8)?,
+        start_bus: read(entry, 10)?,
+        end_bus: read(entry, 11)?,
+    })
+}
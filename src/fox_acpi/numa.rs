@@ -0,0 +1,265 @@
+//! System Resource Affinity Table (SRAT) and System Locality Information Table (SLIT)
+//!
+//! The SRAT puts processors and memory ranges into proximity domains, the NUMA nodes. The SLIT
+//! gives the relative distance between them, 10 meaning local.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#system-resource-affinity-table-srat
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#system-locality-information-table-slit
+
+use core::mem::size_of;
+
+use acpi::sdt::SdtHeader;
+use bit_field::BitField;
+
+use super::{TABLES, read, set_table};
+use crate::fox_vec::FixedVec;
+
+const MAX_PROCESSORS: usize = 256;
+const MAX_MEMORY_RANGES: usize = 64;
+const MAX_NODES: usize = 16;
+
+/// Storage for [`super::numa`], written once by [`init`]
+static mut NUMA_TOPOLOGY: NumaTopology = NumaTopology::new();
+
+/// Nodes collected from the SRAT and their distances from the SLIT
+#[derive(Debug)]
+pub struct NumaTopology {
+    /// Proximity domains in order of appearance
+    pub nodes: FixedVec<u32, MAX_NODES>,
+    pub processors: FixedVec<ProcessorAffinity, MAX_PROCESSORS>,
+    pub memory: FixedVec<MemoryAffinity, MAX_MEMORY_RANGES>,
+    /// Localities of the SLIT, 0 without one
+    localities: usize,
+    /// `localities` x `localities` matrix
+    distances: FixedVec<u8, { MAX_NODES * MAX_NODES }>,
+}
+
+/// Processor Local APIC/SAPIC Affinity (type 0) or Processor Local x2APIC Affinity (type 2)
+#[derive(Copy, Clone, Debug)]
+pub struct ProcessorAffinity {
+    pub domain: u32,
+    pub apic_id: u32,
+    pub flags: u32,
+}
+
+/// Memory Affinity (type 1)
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryAffinity {
+    pub domain: u32,
+    pub base: u64,
+    pub length: u64,
+    pub flags: u32,
+}
+
+impl NumaTopology {
+    const fn new() -> Self {
+        Self {
+            nodes: FixedVec::new(),
+            processors: FixedVec::new(),
+            memory: FixedVec::new(),
+            localities: 0,
+            distances: FixedVec::new(),
+        }
+    }
+
+    /// Relative distance from the SLIT, 10 within a node
+    pub fn distance(&self, from: u32, to: u32) -> Option<u8> {
+        let (from, to) = (from as usize, to as usize);
+        if from >= self.localities || to >= self.localities {
+            return None;
+        }
+        self.distances.get(from * self.localities + to).copied()
+    }
+}
+
+impl ProcessorAffinity {
+    pub fn is_enabled(&self) -> bool {
+        self.flags.get_bit(0)
+    }
+}
+
+impl MemoryAffinity {
+    pub fn is_enabled(&self) -> bool {
+        self.flags.get_bit(0)
+    }
+
+    pub fn is_hot_pluggable(&self) -> bool {
+        self.flags.get_bit(1)
+    }
+
+    pub fn is_non_volatile(&self) -> bool {
+        self.flags.get_bit(2)
+    }
+}
+
+/// Parse a validated SRAT and, if there is one, SLIT
+pub(super) fn init(srat: &[u8], slit: Option<&[u8]>) {
+    // log::trace!("numa::init");
+
+    let info = &raw mut NUMA_TOPOLOGY;
+    // SAFETY: called once during boot, before anyone reads `TABLES.numa`
+    let info = unsafe { &mut *info };
+
+    // struct SRAT {
+    //     struct ACPISDTHeader h;
+    //     uint32_t reserved1;  // 1 for compatibility
+    //     uint64_t reserved2;
+    //     entries...
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 12;
+
+    let mut offset = OFFSET_ENTRIES;
+    while offset + 2 <= srat.len() {
+        let entry_type = srat[offset];
+        let entry_length = srat[offset + 1] as usize;
+        if entry_length < 2 || offset + entry_length > srat.len() {
+            log::warn!("SRAT: invalid entry length {} at {}", entry_length, offset);
+            break;
+        }
+        let entry = &srat[offset..offset + entry_length];
+
+        match push_entry(info, entry_type, entry) {
+            Some(true) => {}
+            Some(false) => log::warn!("SRAT: too many entries of type {}", entry_type),
+            None => log::warn!("SRAT: entry type {} at {} too short", entry_type, offset),
+        }
+
+        offset += entry_length;
+    }
+
+    for i in info.processors.iter() {
+        log::debug!(
+            "SRAT: APIC ID {} in domain {} (flags {:#X})",
+            i.apic_id,
+            i.domain,
+            i.flags
+        );
+    }
+
+    let processor_domains = info.processors.iter().filter(|i| i.is_enabled());
+    let memory_domains = info.memory.iter().filter(|i| i.is_enabled());
+    for domain in processor_domains
+        .map(|i| i.domain)
+        .chain(memory_domains.map(|i| i.domain))
+    {
+        if !info.nodes.contains(&domain) && info.nodes.push(domain).is_err() {
+            log::warn!("SRAT: too many proximity domains");
+            break;
+        }
+    }
+
+    if let Some(slit) = slit {
+        init_slit(info, slit);
+    }
+
+    for &node in info.nodes.iter() {
+        let cpus = info
+            .processors
+            .iter()
+            .filter(|i| i.is_enabled() && i.domain == node)
+            .count();
+        log::debug!("NUMA: node {}: {} CPUs", node, cpus);
+        let memory = info.memory.iter();
+        for i in memory.filter(|i| i.is_enabled() && i.domain == node) {
+            let hot_pluggable = if i.is_hot_pluggable() {
+                " hot-pluggable"
+            } else {
+                ""
+            };
+            let non_volatile = if i.is_non_volatile() {
+                " non-volatile"
+            } else {
+                ""
+            };
+            log::debug!(
+                "NUMA: node {}: memory {:#X}-{:#X} ({} MiB){}{}",
+                node,
+                i.base,
+                i.base.saturating_add(i.length.saturating_sub(1)),
+                i.length >> 20,
+                hot_pluggable,
+                non_volatile
+            );
+        }
+    }
+
+    set_table(&TABLES.numa, info, "NUMA");
+}
+
+fn init_slit(info: &mut NumaTopology, slit: &[u8]) {
+    // struct SLIT {
+    //     struct ACPISDTHeader h;
+    //     uint64_t localities;
+    //     uint8_t entries[localities][localities];
+    // };
+    const OFFSET_ENTRIES: usize = size_of::<SdtHeader>() + 8;
+    let Some(localities) = read::<u64>(slit, size_of::<SdtHeader>()) else {
+        log::warn!("SLIT: truncated");
+        return;
+    };
+    let localities = localities as usize;
+    if localities > MAX_NODES {
+        log::warn!(
+            "SLIT: {} localities, only {} supported",
+            localities,
+            MAX_NODES
+        );
+        return;
+    }
+    let Some(distances) = slit.get(OFFSET_ENTRIES..OFFSET_ENTRIES + localities * localities) else {
+        log::warn!("SLIT: truncated");
+        return;
+    };
+    for &distance in distances {
+        let _ = info.distances.push(distance);
+    }
+    info.localities = localities;
+
+    for from in 0..localities as u32 {
+        let mut row = [0; MAX_NODES];
+        for to in 0..localities as u32 {
+            row[to as usize] = info.distance(from, to).unwrap_or(0);
+        }
+        log::debug!("SLIT: {}: {:?}", from, &row[..localities]);
+    }
+}
+
+/// Keep an entry, `Some(false)` if there is no room for it, `None` if it is too short
+fn push_entry(info: &mut NumaTopology, entry_type: u8, entry: &[u8]) -> Option<bool> {
+    let is_pushed = match entry_type {
+        0 => {
+            // proximity domain bits 0-7, then 8-31 after the SAPIC EID
+            let low = read::<u8>(entry, 2)? as u32;
+            let high: [u8; 3] = read(entry, 9)?;
+            info.processors
+                .push(ProcessorAffinity {
+                    domain: u32::from_le_bytes([0, high[0], high[1], high[2]]) | low,
+                    apic_id: read::<u8>(entry, 3)? as u32,
+                    flags: read(entry, 4)?,
+                })
+                .is_ok()
+        }
+        1 => info
+            .memory
+            .push(MemoryAffinity {
+                domain: read(entry, 2)?,
+                base: read(entry, 8)?,
+                length: read(entry, 16)?,
+                flags: read(entry, 28)?,
+            })
+            .is_ok(),
+        2 => info
+            .processors
+            .push(ProcessorAffinity {
+                domain: read(entry, 4)?,
+                apic_id: read(entry, 8)?,
+                flags: read(entry, 12)?,
+            })
+            .is_ok(),
+        _ => {
+            // log::debug!("SRAT: skip entry type {}", entry_type);
+            true
+        }
+    };
+    Some(is_pushed)
+}
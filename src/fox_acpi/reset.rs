@@ -0,0 +1,83 @@
+//! System reset
+//!
+//! https://wiki.osdev.org/Reboot
+
+use core::convert::Infallible;
+use core::time::Duration;
+
+use super::{Error, fadt, gas};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::I8042;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_port::Pio;
+use crate::fox_time::delay;
+
+/// Time for a reset method to take effect before trying the next one
+const RESET_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Returns if the reset did not happen right away
+type ResetMethod = fn() -> Result<(), Error>;
+
+/// Most preferred first, the chipset ones only exist on PCs
+const METHODS: &[(&str, ResetMethod)] = &[
+    ("FADT reset register", reset_register),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    ("port 0xCF9", reset_control_register),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    ("i8042", i8042_reset),
+];
+
+/// Restart the machine
+///
+/// Tries the FADT reset register, then on x86 the reset control register at port 0xCF9 and
+/// the reset line of the i8042.
+pub fn reboot() -> Result<Infallible, Error> {
+    // log::trace!("reboot");
+
+    for &(name, method) in METHODS {
+        log::info!("ACPI: reset via {}", name);
+        match method() {
+            Ok(()) => delay(RESET_TIMEOUT),
+            Err(err) => log::warn!("ACPI: reset via {} failed: {}", name, err),
+        }
+    }
+
+    Err(Error::Timeout)
+}
+
+/// Write RESET_VALUE into RESET_REG
+fn reset_register() -> Result<(), Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+
+    let reset_reg = fadt.reset_register().map_err(Error::Acpi)?;
+    // ACPI 1.0 FADTs have no such field
+    if reset_reg.address == 0 {
+        return Err(Error::NoResetRegister);
+    }
+    let reset_value = fadt.reset_value;
+    log::debug!(
+        "ACPI: {:?} {:#X} = {:#04X}",
+        reset_reg.address_space,
+        reset_reg.address,
+        reset_value
+    );
+    gas::write(&reset_reg, reset_value as u64)
+}
+
+/// Intel chipsets: hard reset (bit 1), then start the reset (bit 2)
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn reset_control_register() -> Result<(), Error> {
+    let mut port = Pio::<u8>::new(0x0CF9);
+    // SAFETY: trust me
+    unsafe {
+        port.write(0x02);
+        port.write(0x06);
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn i8042_reset() -> Result<(), Error> {
+    // the only error is a full input buffer
+    I8042::cpu_reset().map_err(|_| Error::Timeout)
+}
@@ -0,0 +1,193 @@
+//! Sleeping states (\_Sx)
+//!
+//! https://wiki.osdev.org/Shutdown
+//! https://forum.osdev.org/viewtopic.php?t=16990
+
+use core::arch::asm;
+use core::convert::Infallible;
+use core::time::Duration;
+
+use acpi::address::{AccessSize, GenericAddress};
+use bit_field::BitField;
+
+use super::{Error, aml_blocks, fadt, gas};
+use crate::fox_time::delay;
+
+/// AML NameOp
+const NAME_OP: u8 = 0x08;
+/// AML PackageOp
+const PACKAGE_OP: u8 = 0x12;
+/// AML BytePrefix
+const BYTE_PREFIX: u8 = 0x0A;
+/// AML ZeroOp
+const ZERO_OP: u8 = 0x00;
+/// AML OneOp
+const ONE_OP: u8 = 0x01;
+
+/// PM1 control register: SLP_TYPx (bits 10-12)
+const SLP_TYP: core::ops::Range<usize> = 10..13;
+/// PM1 control register: SLP_EN (bit 13)
+const SLP_EN: usize = 13;
+/// PM1 status register: WAK_STS (bit 15), write 1 to clear
+const WAK_STS: usize = 15;
+
+/// Put the machine into the soft off state (S5)
+pub fn poweroff() -> Result<Infallible, Error> {
+    // log::trace!("poweroff");
+
+    let (slp_typa, slp_typb) = sleep_type(b"_S5_")?;
+    log::info!(
+        "ACPI: poweroff (SLP_TYPa={}, SLP_TYPb={})",
+        slp_typa,
+        slp_typb
+    );
+
+    enter_sleep_state(slp_typa, slp_typb)?;
+
+    // the write should never return, give the hardware a moment
+    delay(Duration::from_secs(1));
+    Err(Error::Timeout)
+}
+
+/// Put the machine to sleep in S3, the caller has set the waking vector in the FACS
+///
+/// On success the firmware resumes at the waking vector, this only returns if the machine did
+/// not go to sleep. \_PTS is not evaluated, platforms that need it may not wake up.
+pub fn suspend() -> Result<Infallible, Error> {
+    // log::trace!("suspend");
+
+    let (slp_typa, slp_typb) = sleep_type(b"_S3_")?;
+    log::info!(
+        "ACPI: suspend to RAM (SLP_TYPa={}, SLP_TYPb={})",
+        slp_typa,
+        slp_typb
+    );
+
+    clear_wake_status()?;
+    // the caches lose their contents in S3
+    // SAFETY: only writes back and invalidates the caches
+    unsafe { asm!("wbinvd", options(nostack, preserves_flags)) };
+    enter_sleep_state(slp_typa, slp_typb)?;
+
+    delay(Duration::from_secs(1));
+    Err(Error::Timeout)
+}
+
+/// Clear WAK_STS in the PM1 status registers, the first half of the PM1 event blocks
+fn clear_wake_status() -> Result<(), Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+
+    let pm1a = fadt.pm1a_event_block().map_err(Error::Acpi)?;
+    let pm1b = fadt.pm1b_event_block().map_err(Error::Acpi)?;
+    for block in [Some(pm1a), pm1b].into_iter().flatten() {
+        let status = GenericAddress {
+            bit_width: 16,
+            access_size: AccessSize::WordAccess,
+            ..block
+        };
+        let mut value = 0u64;
+        value.set_bit(WAK_STS, true);
+        gas::write(&status, value)?;
+    }
+    Ok(())
+}
+
+/// Write SLP_TYPx | SLP_EN into the PM1a/PM1b control blocks
+pub(super) fn enter_sleep_state(slp_typa: u8, slp_typb: u8) -> Result<(), Error> {
+    let fadt = fadt().ok_or(Error::NoFadt)?;
+
+    let pm1a = fadt.pm1a_control_block().map_err(Error::Acpi)?;
+    let pm1b = fadt.pm1b_control_block().map_err(Error::Acpi)?;
+
+    let mut value = gas::read(&pm1a)?;
+    value.set_bits(SLP_TYP, slp_typa as u64);
+    value.set_bit(SLP_EN, true);
+    gas::write(&pm1a, value)?;
+
+    if let Some(pm1b) = pm1b {
+        let mut value = gas::read(&pm1b)?;
+        value.set_bits(SLP_TYP, slp_typb as u64);
+        value.set_bit(SLP_EN, true);
+        gas::write(&pm1b, value)?;
+    }
+
+    Ok(())
+}
+
+/// Find the `Name(\_Sx, Package() { SLP_TYPa, SLP_TYPb, ... })` object in the DSDT or an SSDT
+///
+/// The values must fit [`SLP_TYP`].
+pub(super) fn sleep_type(name: &[u8; 4]) -> Result<(u8, u8), Error> {
+    let mut blocks = aml_blocks().peekable();
+    if blocks.peek().is_none() {
+        return Err(Error::NoDsdt);
+    }
+    let (slp_typa, slp_typb) = blocks
+        .find_map(|block| find_sleep_package(block.aml, name))
+        .ok_or(Error::NoSleepState)?;
+    let max = (1 << SLP_TYP.len()) - 1;
+    if let Some(value) = [slp_typa, slp_typb].into_iter().find(|&i| i > max) {
+        return Err(Error::InvalidSleepType(value));
+    }
+    Ok((slp_typa, slp_typb))
+}
+
+/// Minimal scan of the AML stream instead of a full interpreter
+fn find_sleep_package(aml: &[u8], name: &[u8; 4]) -> Option<(u8, u8)> {
+    let mut start = 0;
+    while let Some(pos) = aml[start..].windows(4).position(|i| i == name) {
+        let pos = start + pos;
+        start = pos + 1;
+
+        // NameOp, optionally followed by the root prefix
+        let is_name = (pos >= 1 && aml[pos - 1] == NAME_OP)
+            || (pos >= 2 && aml[pos - 2] == NAME_OP && aml[pos - 1] == b'\\');
+        if !is_name {
+            continue;
+        }
+
+        let mut i = pos + 4;
+        if *aml.get(i)? != PACKAGE_OP {
+            continue;
+        }
+        i += 1;
+
+        // PkgLength: bits 6-7 of the lead byte is the count of following bytes
+        let lead = *aml.get(i)?;
+        i += 1 + lead.get_bits(6..8) as usize;
+
+        // NumElements
+        i += 1;
+
+        let slp_typa = parse_byte(aml, &mut i)?;
+        let slp_typb = parse_byte(aml, &mut i)?;
+        return Some((slp_typa, slp_typb));
+    }
+    None
+}
+
+/// ByteConst, ZeroOp or OneOp
+fn parse_byte(aml: &[u8], i: &mut usize) -> Option<u8> {
+    match *aml.get(*i)? {
+        BYTE_PREFIX => {
+            let value = *aml.get(*i + 1)?;
+            *i += 2;
+            Some(value)
+        }
+        ZERO_OP => {
+            *i += 1;
+            Some(0)
+        }
+        ONE_OP => {
+            *i += 1;
+            Some(1)
+        }
+        value => {
+            log::warn!(
+                "ACPI: unexpected AML opcode {:#04X} in sleep package",
+                value
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Serial Port Console Redirection table (SPCR): the UART the firmware uses as its console
+//!
+//! Required on ARM servers, where there is no COM1 to assume. The interface types are those of
+//! the DBG2 table.
+//!
+//! https://learn.microsoft.com/en-us/windows-hardware/drivers/serports/serial-port-console-redirection-table
+
+use core::mem::size_of;
+
+use acpi::address::RawGenericAddress;
+use acpi::sdt::{SdtHeader, Signature};
+
+use super::gas::address_space;
+use super::{Error, find_table, read, table_bytes};
+
+/// Address space IDs of a GAS
+const SPACE_SYSTEM_MEMORY: u8 = 0;
+const SPACE_SYSTEM_IO: u8 = 1;
+
+/// Access size of a GAS for 32-bit accesses
+const ACCESS_DWORD: u8 = 3;
+
+/// UART register interface, the DBG2 port subtype
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Interface {
+    /// 16550 or a subset, registers 1 byte apart
+    Ns16550,
+    /// 16550 with the register width and stride of the GAS
+    Ns16550Gas,
+    /// ARM PL011
+    Pl011,
+    /// ARM SBSA generic UART, a PL011 subset without baud rate or line control
+    Sbsa,
+    Other(u8),
+}
+
+impl Interface {
+    fn from_type(interface_type: u8) -> Self {
+        match interface_type {
+            0x00 | 0x01 => Self::Ns16550,
+            0x12 => Self::Ns16550Gas,
+            0x03 => Self::Pl011,
+            0x0D | 0x0E => Self::Sbsa,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// Where the registers of the UART are
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Address {
+    Port(u16),
+    /// `stride` bytes between registers, accessed 32 bits wide if 4
+    Mmio {
+        base: u64,
+        stride: u8,
+    },
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Spcr {
+    pub interface: Interface,
+    pub address: Address,
+    /// `None` to keep the rate the firmware programmed
+    pub baud: Option<u32>,
+}
+
+pub fn spcr() -> Result<Spcr, Error> {
+    // log::trace!("spcr");
+
+    let table = find_table::<SdtHeader>(Signature::SPCR).ok_or(Error::NoTable)?;
+    let table = table_bytes(table);
+
+    // struct SPCR {
+    //     struct ACPISDTHeader h;
+    //     uint8_t interface_type;
+    //     uint8_t reserved[3];
+    //     struct GenericAddressStructure base_address;
+    //     uint8_t interrupt_type;
+    //     uint8_t irq;
+    //     uint32_t gsi;
+    //     uint8_t configured_baud_rate;
+    //     ...
+    //     uint32_t uart_clock_frequency;  // revision 3
+    //     uint32_t precise_baud_rate;  // revision 4
+    // };
+    const OFFSET: usize = size_of::<SdtHeader>();
+    const OFFSET_PRECISE_BAUD: usize = OFFSET + 44;
+    // h.revision
+    const OFFSET_REVISION: usize = 8;
+    let revision: u8 = read(table, OFFSET_REVISION).ok_or(Error::NoTable)?;
+    let interface = Interface::from_type(read(table, OFFSET).ok_or(Error::NoTable)?);
+    let address = address(interface, read(table, OFFSET + 4).ok_or(Error::NoTable)?)?;
+    let precise_baud: u32 = match revision {
+        4.. => read(table, OFFSET_PRECISE_BAUD).unwrap_or(0),
+        _ => 0,
+    };
+    let baud = match precise_baud {
+        0 => baud(read(table, OFFSET + 22).ok_or(Error::NoTable)?),
+        baud => Some(baud),
+    };
+    Ok(Spcr {
+        interface,
+        address,
+        baud,
+    })
+}
+
+fn address(interface: Interface, address: RawGenericAddress) -> Result<Address, Error> {
+    match address.address_space {
+        SPACE_SYSTEM_IO => Ok(Address::Port(address.address as u16)),
+        SPACE_SYSTEM_MEMORY => {
+            let stride = match interface {
+                Interface::Ns16550Gas if address.access_size == ACCESS_DWORD => 4,
+                _ => 1,
+            };
+            Ok(Address::Mmio {
+                base: address.address,
+                stride,
+            })
+        }
+        space => Err(Error::UnsupportedAddressSpace(address_space(space)?)),
+    }
+}
+
+/// Configured baud rate code, 0 keeps the rate as is
+fn baud(code: u8) -> Option<u32> {
+    match code {
+        3 => Some(9600),
+        4 => Some(19200),
+        6 => Some(57600),
+        7 => Some(115200),
+        _ => None,
+    }
+}
@@ -0,0 +1,285 @@
+//! ACPI namespace from the AML of the DSDT and SSDTs
+//!
+//! No interpreter: the declarations are read straight from the byte code, so objects created by
+//! methods or under `If` are missing. Enough to find the devices and their \_HID and \_CRS.
+//!
+//! https://uefi.org/specs/ACPI/6.5/05_ACPI_Software_Programming_Model.html#acpi-namespace
+//! https://wiki.osdev.org/AML
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::ptr::null_mut;
+use core::str;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use crate::fox_acpi::aml_blocks;
+use crate::fox_println;
+
+mod parser;
+mod resources;
+
+use parser::Parser;
+pub use resources::Resource;
+
+/// Init [`init`]
+static NAMESPACE: AtomicPtr<Namespace> = AtomicPtr::new(null_mut());
+
+/// Storage for [`NAMESPACE`], written once by [`init`]
+static mut NAMESPACE_STORAGE: Namespace = Namespace::new();
+
+/// Four characters, padded with `_`
+pub type NameSeg = [u8; 4];
+
+/// Absolute, the root is empty
+pub type Path = Vec<NameSeg>;
+
+/// Objects by path, parents sort before their children
+#[derive(Debug)]
+pub struct Namespace {
+    nodes: BTreeMap<Path, NodeKind>,
+}
+
+#[derive(Clone, Debug)]
+pub enum NodeKind {
+    /// Predefined scope such as `\_SB`, only seen through `Scope()`
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+    Method {
+        arg_count: u8,
+    },
+    Name(Value),
+    Alias(Path),
+    Mutex,
+    Event,
+    OperationRegion {
+        space: u8,
+    },
+}
+
+/// Value of a `Name()`
+#[derive(Copy, Clone, Debug)]
+pub enum Value {
+    Integer(u64),
+    String(&'static str),
+    /// Initializer, the declared size may be larger
+    Buffer(&'static [u8]),
+    Package,
+}
+
+/// Formats a path as `\_SB_.PCI0`
+pub struct PathDisplay<'a>(pub &'a [NameSeg]);
+
+impl Namespace {
+    const fn new() -> Self {
+        Self {
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// The first declaration of a path wins, `Scope()` never replaces an object
+    fn add(&mut self, path: Path, kind: NodeKind) {
+        self.nodes.entry(path).or_insert(kind);
+    }
+
+    /// All objects in tree order
+    pub fn iter(&self) -> impl Iterator<Item = (&Path, &NodeKind)> {
+        self.nodes.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Object `name` right below `path`
+    pub fn child(&self, path: &[NameSeg], name: &NameSeg) -> Option<&NodeKind> {
+        let mut child = path.to_vec();
+        child.push(*name);
+        self.nodes.get(&child)
+    }
+
+    pub fn devices(&self) -> impl Iterator<Item = &Path> {
+        self.iter()
+            .filter(|(_, kind)| matches!(kind, NodeKind::Device))
+            .map(|(path, _)| path)
+    }
+
+    pub fn thermal_zones(&self) -> impl Iterator<Item = &Path> {
+        self.iter()
+            .filter(|(_, kind)| matches!(kind, NodeKind::ThermalZone))
+            .map(|(path, _)| path)
+    }
+
+    /// \_HID of a device, EISA IDs decoded, `None` if missing or computed by a method
+    pub fn hardware_id(&self, path: &[NameSeg]) -> Option<String> {
+        match *self.child(path, b"_HID")? {
+            NodeKind::Name(Value::String(id)) => Some(String::from(id)),
+            NodeKind::Name(Value::Integer(id)) => Some(eisa_id(u32::try_from(id).ok()?)),
+            _ => None,
+        }
+    }
+
+    /// Devices whose \_HID starts with `prefix`, such as `PNP03` for keyboards
+    pub fn find_devices(&self, prefix: &str) -> impl Iterator<Item = &Path> {
+        self.devices().filter(move |path| {
+            self.hardware_id(path)
+                .is_some_and(|hid| hid.starts_with(prefix))
+        })
+    }
+
+    /// Decoded \_CRS of a device, `None` if missing or computed by a method
+    pub fn current_resources(&self, path: &[NameSeg]) -> Option<Vec<Resource>> {
+        match self.child(path, b"_CRS")? {
+            NodeKind::Name(Value::Buffer(bytes)) => Some(resources::parse(bytes)),
+            _ => None,
+        }
+    }
+}
+
+/// The namespace, `None` before [`init`]
+pub fn namespace() -> Option<&'static Namespace> {
+    let ptr = NAMESPACE.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Read the declarations of every valid DSDT and SSDT
+pub fn init() {
+    // log::trace!("fox_aml::init");
+
+    let namespace = &raw mut NAMESPACE_STORAGE;
+    // SAFETY: called once during boot, before anyone reads `NAMESPACE`
+    let namespace = unsafe { &mut *namespace };
+
+    let mut parser = Parser::new(namespace);
+    let mut blocks = 0;
+    for block in aml_blocks() {
+        parser.parse(block.aml);
+        blocks += 1;
+    }
+    let skipped = parser.skipped;
+
+    log::info!(
+        "AML: {} objects, {} devices from {} blocks",
+        namespace.len(),
+        namespace.devices().count(),
+        blocks
+    );
+    if skipped > 0 {
+        log::debug!("AML: {} term lists not fully parsed", skipped);
+    }
+
+    NAMESPACE.store(namespace, Ordering::Release);
+}
+
+/// Log every device with its hardware ID and current resources
+pub fn log_devices() {
+    let Some(namespace) = namespace() else {
+        return;
+    };
+
+    log::debug!("ACPI devices:");
+    for path in namespace.devices() {
+        let hid = match namespace.child(path, b"_HID") {
+            Some(NodeKind::Method { .. }) => String::from("(method)"),
+            _ => namespace.hardware_id(path).unwrap_or_default(),
+        };
+        log::debug!("  {} {}", PathDisplay(path), hid);
+        match namespace.current_resources(path) {
+            Some(resources) => {
+                for resource in resources {
+                    log::debug!("    {}", resource);
+                }
+            }
+            None if namespace.child(path, b"_CRS").is_some() => {
+                log::debug!("    _CRS (method)");
+            }
+            None => {}
+        }
+    }
+}
+
+/// Print the namespace as an indented tree
+pub fn print_tree() {
+    let Some(namespace) = namespace() else {
+        return;
+    };
+
+    for (path, kind) in namespace.iter() {
+        let Some(name) = path.last() else {
+            continue;
+        };
+        let indent = 2 * path.len();
+        fox_println!(
+            "{:indent$}{} {}",
+            "",
+            str::from_utf8(name).unwrap_or("????"),
+            kind,
+            indent = indent
+        );
+    }
+}
+
+impl fmt::Display for PathDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "\\")?;
+        for (i, seg) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ".")?;
+            }
+            // name segments are ASCII
+            for &c in seg {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for NodeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Scope => write!(f, "Scope"),
+            Self::Device => write!(f, "Device"),
+            Self::Processor => write!(f, "Processor"),
+            Self::PowerResource => write!(f, "PowerResource"),
+            Self::ThermalZone => write!(f, "ThermalZone"),
+            Self::Method { arg_count } => write!(f, "Method({})", arg_count),
+            Self::Name(value) => write!(f, "Name = {}", value),
+            Self::Alias(path) => write!(f, "Alias of {}", PathDisplay(path)),
+            Self::Mutex => write!(f, "Mutex"),
+            Self::Event => write!(f, "Event"),
+            Self::OperationRegion { space } => write!(f, "OperationRegion({:#04x})", space),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer(value) => write!(f, "{:#x}", value),
+            Self::String(value) => write!(f, "\"{}\"", value),
+            Self::Buffer(bytes) => write!(f, "Buffer({} bytes)", bytes.len()),
+            Self::Package => write!(f, "Package"),
+        }
+    }
+}
+
+/// Compressed EISA ID: three 5-bit letters and four hex digits, stored big-endian
+fn eisa_id(id: u32) -> String {
+    let id = id.swap_bytes();
+    let letter = |shift: u32| (b'@' + ((id >> shift) & 0x1F) as u8) as char;
+    let mut string = String::new();
+    let _ = write!(
+        string,
+        "{}{}{}{:04X}",
+        letter(26),
+        letter(21),
+        letter(16),
+        id & 0xFFFF
+    );
+    string
+}
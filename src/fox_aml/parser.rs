@@ -0,0 +1,342 @@
+//! AML byte code to namespace objects
+//!
+//! Only declarations are parsed: method bodies, `If`/`Else` blocks and field lists are skipped
+//! by their package length. A term list with an opcode the parser does not know is given up on
+//! from there, the enclosing package length tells where to go on.
+//!
+//! https://uefi.org/specs/ACPI/6.5/20_AML_Specification.html
+
+use alloc::vec::Vec;
+use core::str;
+
+use bit_field::BitField;
+
+use super::{NameSeg, Namespace, NodeKind, Path, Value};
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const ALIAS_OP: u8 = 0x06;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0A;
+const WORD_PREFIX: u8 = 0x0B;
+const DWORD_PREFIX: u8 = 0x0C;
+const STRING_PREFIX: u8 = 0x0D;
+const QWORD_PREFIX: u8 = 0x0E;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const VAR_PACKAGE_OP: u8 = 0x13;
+const METHOD_OP: u8 = 0x14;
+const EXTERNAL_OP: u8 = 0x15;
+const DUAL_NAME_PREFIX: u8 = 0x2E;
+const MULTI_NAME_PREFIX: u8 = 0x2F;
+const EXT_OP_PREFIX: u8 = 0x5B;
+const ROOT_CHAR: u8 = b'\\';
+const PARENT_PREFIX_CHAR: u8 = b'^';
+const LOCAL0_OP: u8 = 0x60;
+const ARG6_OP: u8 = 0x6E;
+const IF_OP: u8 = 0xA0;
+const ELSE_OP: u8 = 0xA1;
+const WHILE_OP: u8 = 0xA2;
+const ONES_OP: u8 = 0xFF;
+
+// after EXT_OP_PREFIX
+const MUTEX_OP: u8 = 0x01;
+const EVENT_OP: u8 = 0x02;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const PROCESSOR_OP: u8 = 0x83;
+const POWER_RES_OP: u8 = 0x84;
+const THERMAL_ZONE_OP: u8 = 0x85;
+const INDEX_FIELD_OP: u8 = 0x86;
+const BANK_FIELD_OP: u8 = 0x87;
+
+pub struct Parser<'a> {
+    namespace: &'a mut Namespace,
+    /// Term lists given up on
+    pub skipped: usize,
+}
+
+/// Position in the byte code of one definition block
+#[derive(Copy, Clone)]
+struct Cursor {
+    aml: &'static [u8],
+    pos: usize,
+}
+
+/// A NameString before it is resolved against the current scope
+struct NameString {
+    is_root: bool,
+    parents: usize,
+    segments: Vec<NameSeg>,
+}
+
+impl<'a> Parser<'a> {
+    pub fn new(namespace: &'a mut Namespace) -> Self {
+        Self {
+            namespace,
+            skipped: 0,
+        }
+    }
+
+    /// Add the objects declared by a DSDT or SSDT
+    pub fn parse(&mut self, aml: &'static [u8]) {
+        let mut cursor = Cursor { aml, pos: 0 };
+        self.term_list(&mut cursor, aml.len(), &Vec::new());
+    }
+
+    fn term_list(&mut self, cursor: &mut Cursor, end: usize, scope: &Path) {
+        while cursor.pos < end {
+            if self.term(cursor, scope).is_none() || cursor.pos > end {
+                // log::trace!("AML: skipped from {:#x}", cursor.pos);
+                self.skipped += 1;
+                break;
+            }
+        }
+        cursor.pos = end;
+    }
+
+    fn term(&mut self, cursor: &mut Cursor, scope: &Path) -> Option<()> {
+        match cursor.byte()? {
+            SCOPE_OP => {
+                let end = cursor.pkg_length()?;
+                let path = cursor.name_string()?.resolve(scope)?;
+                self.namespace.add(path.clone(), NodeKind::Scope);
+                self.term_list(cursor, end, &path);
+            }
+            NAME_OP => {
+                let path = cursor.name_string()?.resolve(scope)?;
+                let value = cursor.data_object()?;
+                self.namespace.add(path, NodeKind::Name(value));
+            }
+            METHOD_OP => {
+                let end = cursor.pkg_length()?;
+                let path = cursor.name_string()?.resolve(scope)?;
+                let flags = cursor.byte()?;
+                let arg_count = flags.get_bits(0..3);
+                self.namespace.add(path, NodeKind::Method { arg_count });
+                cursor.pos = end;
+            }
+            ALIAS_OP => {
+                let source = cursor.name_string()?.resolve(scope)?;
+                let alias = cursor.name_string()?.resolve(scope)?;
+                self.namespace.add(alias, NodeKind::Alias(source));
+            }
+            EXTERNAL_OP => {
+                // declared in another block, object type and argument count follow
+                cursor.name_string()?;
+                cursor.bytes(2)?;
+            }
+            IF_OP | ELSE_OP | WHILE_OP => cursor.pos = cursor.pkg_length()?,
+            EXT_OP_PREFIX => self.ext_term(cursor, scope)?,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn ext_term(&mut self, cursor: &mut Cursor, scope: &Path) -> Option<()> {
+        let op = cursor.byte()?;
+        match op {
+            DEVICE_OP | PROCESSOR_OP | POWER_RES_OP | THERMAL_ZONE_OP => {
+                let end = cursor.pkg_length()?;
+                let path = cursor.name_string()?.resolve(scope)?;
+                let kind = match op {
+                    DEVICE_OP => NodeKind::Device,
+                    PROCESSOR_OP => {
+                        // ProcID, PblkAddr and PblkLen
+                        cursor.bytes(6)?;
+                        NodeKind::Processor
+                    }
+                    POWER_RES_OP => {
+                        // SystemLevel and ResourceOrder
+                        cursor.bytes(3)?;
+                        NodeKind::PowerResource
+                    }
+                    _ => NodeKind::ThermalZone,
+                };
+                self.namespace.add(path.clone(), kind);
+                self.term_list(cursor, end, &path);
+            }
+            MUTEX_OP => {
+                let path = cursor.name_string()?.resolve(scope)?;
+                // SyncFlags
+                cursor.byte()?;
+                self.namespace.add(path, NodeKind::Mutex);
+            }
+            EVENT_OP => {
+                let path = cursor.name_string()?.resolve(scope)?;
+                self.namespace.add(path, NodeKind::Event);
+            }
+            OP_REGION_OP => {
+                let path = cursor.name_string()?.resolve(scope)?;
+                let space = cursor.byte()?;
+                // RegionOffset and RegionLen
+                cursor.term_arg()?;
+                cursor.term_arg()?;
+                self.namespace
+                    .add(path, NodeKind::OperationRegion { space });
+            }
+            FIELD_OP | INDEX_FIELD_OP | BANK_FIELD_OP => cursor.pos = cursor.pkg_length()?,
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+impl Cursor {
+    fn byte(&mut self) -> Option<u8> {
+        let value = *self.aml.get(self.pos)?;
+        self.pos += 1;
+        Some(value)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.aml.get(self.pos).copied()
+    }
+
+    fn bytes(&mut self, count: usize) -> Option<&'static [u8]> {
+        let bytes = self.aml.get(self.pos..self.pos + count)?;
+        self.pos += count;
+        Some(bytes)
+    }
+
+    fn integer(&mut self, size: usize) -> Option<u64> {
+        let bytes = self.bytes(size)?;
+        Some(
+            bytes
+                .iter()
+                .rev()
+                .fold(0, |value, &byte| (value << 8) | byte as u64),
+        )
+    }
+
+    /// PkgLength, returns where the package ends
+    fn pkg_length(&mut self) -> Option<usize> {
+        let start = self.pos;
+        let lead = self.byte()?;
+        // bits 6-7 are the count of bytes that follow
+        let count = lead.get_bits(6..8) as usize;
+        let length = if count == 0 {
+            lead.get_bits(0..6) as usize
+        } else {
+            let mut length = lead.get_bits(0..4) as usize;
+            for i in 0..count {
+                length |= (self.byte()? as usize) << (4 + 8 * i);
+            }
+            length
+        };
+        let end = start + length;
+        (end <= self.aml.len()).then_some(end)
+    }
+
+    fn name_seg(&mut self) -> Option<NameSeg> {
+        let seg: NameSeg = self.bytes(4)?.try_into().ok()?;
+        let is_valid = matches!(seg[0], b'A'..=b'Z' | b'_')
+            && seg[1..]
+                .iter()
+                .all(|i| matches!(i, b'A'..=b'Z' | b'0'..=b'9' | b'_'));
+        is_valid.then_some(seg)
+    }
+
+    fn name_string(&mut self) -> Option<NameString> {
+        let mut name = NameString {
+            is_root: false,
+            parents: 0,
+            segments: Vec::new(),
+        };
+        if self.peek()? == ROOT_CHAR {
+            self.pos += 1;
+            name.is_root = true;
+        } else {
+            while self.peek()? == PARENT_PREFIX_CHAR {
+                self.pos += 1;
+                name.parents += 1;
+            }
+        }
+
+        let count = match self.peek()? {
+            ZERO_OP => {
+                self.pos += 1;
+                0
+            }
+            DUAL_NAME_PREFIX => {
+                self.pos += 1;
+                2
+            }
+            MULTI_NAME_PREFIX => {
+                self.pos += 1;
+                self.byte()? as usize
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segments.push(self.name_seg()?);
+        }
+        Some(name)
+    }
+
+    /// DataRefObject of a Name
+    fn data_object(&mut self) -> Option<Value> {
+        let value = match self.byte()? {
+            ZERO_OP => Value::Integer(0),
+            ONE_OP => Value::Integer(1),
+            ONES_OP => Value::Integer(u64::MAX),
+            BYTE_PREFIX => Value::Integer(self.integer(1)?),
+            WORD_PREFIX => Value::Integer(self.integer(2)?),
+            DWORD_PREFIX => Value::Integer(self.integer(4)?),
+            QWORD_PREFIX => Value::Integer(self.integer(8)?),
+            STRING_PREFIX => {
+                let length = self.aml[self.pos..].iter().position(|&i| i == 0)?;
+                let bytes = self.bytes(length)?;
+                self.pos += 1;
+                Value::String(str::from_utf8(bytes).ok()?)
+            }
+            BUFFER_OP => {
+                let end = self.pkg_length()?;
+                // BufferSize, the initializer may be shorter
+                self.term_arg()?;
+                let bytes = self.aml.get(self.pos..end)?;
+                self.pos = end;
+                Value::Buffer(bytes)
+            }
+            PACKAGE_OP | VAR_PACKAGE_OP => {
+                self.pos = self.pkg_length()?;
+                Value::Package
+            }
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Skip a TermArg made of a constant, a name or a local or argument
+    fn term_arg(&mut self) -> Option<()> {
+        match self.peek()? {
+            LOCAL0_OP..=ARG6_OP => self.pos += 1,
+            ROOT_CHAR | PARENT_PREFIX_CHAR | DUAL_NAME_PREFIX | MULTI_NAME_PREFIX => {
+                self.name_string()?;
+            }
+            b'A'..=b'Z' | b'_' => {
+                self.name_string()?;
+            }
+            _ => {
+                self.data_object()?;
+            }
+        }
+        Some(())
+    }
+}
+
+impl NameString {
+    /// Absolute path of a declaration in `scope`, no search rules apply
+    fn resolve(self, scope: &Path) -> Option<Path> {
+        let mut path = if self.is_root {
+            Vec::new()
+        } else {
+            let depth = scope.len().checked_sub(self.parents)?;
+            scope[..depth].to_vec()
+        };
+        path.extend(self.segments);
+        Some(path)
+    }
+}
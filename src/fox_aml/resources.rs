@@ -0,0 +1,199 @@
+//! Resource templates of \_CRS
+//!
+//! Only the descriptors for I/O, memory, interrupts, DMA and bus numbers are decoded, others
+//! are skipped by their length.
+//!
+//! https://uefi.org/specs/ACPI/6.5/06_Device_Configuration.html#resource-data-types-for-acpi
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use bit_field::BitField;
+
+// small resource items, by bits 3-6 of the tag
+const SMALL_IRQ: u8 = 0x04;
+const SMALL_DMA: u8 = 0x05;
+const SMALL_IO: u8 = 0x08;
+const SMALL_FIXED_IO: u8 = 0x09;
+const SMALL_END_TAG: u8 = 0x0F;
+
+// large resource items, by bits 0-6 of the tag
+const LARGE_MEMORY24: u8 = 0x01;
+const LARGE_MEMORY32: u8 = 0x05;
+const LARGE_FIXED_MEMORY32: u8 = 0x06;
+const LARGE_DWORD_ADDRESS: u8 = 0x07;
+const LARGE_WORD_ADDRESS: u8 = 0x08;
+const LARGE_EXTENDED_IRQ: u8 = 0x09;
+const LARGE_QWORD_ADDRESS: u8 = 0x0A;
+
+// resource type of an address space descriptor
+const ADDRESS_IO: u8 = 1;
+const ADDRESS_BUS: u8 = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resource {
+    Io {
+        base: u16,
+        length: u16,
+    },
+    Memory {
+        base: u64,
+        length: u64,
+    },
+    /// Legacy IRQs, one bit each
+    Irq {
+        mask: u16,
+    },
+    /// Global system interrupt of an extended interrupt descriptor
+    Interrupt(u32),
+    /// DMA channels, one bit each
+    Dma {
+        mask: u8,
+    },
+    BusNumber {
+        base: u16,
+        length: u16,
+    },
+}
+
+/// Decode a resource template up to its end tag
+///
+/// A truncated descriptor ends the list with a warning.
+pub fn parse(bytes: &[u8]) -> Vec<Resource> {
+    let mut resources = Vec::new();
+    let mut pos = 0;
+    while let Some(&tag) = bytes.get(pos) {
+        let (item, header, length) = if tag.get_bit(7) {
+            let Some(length) = bytes.get(pos + 1..pos + 3) else {
+                break;
+            };
+            (
+                tag.get_bits(0..7),
+                3,
+                u16::from_le_bytes([length[0], length[1]]) as usize,
+            )
+        } else {
+            (tag.get_bits(3..7), 1, tag.get_bits(0..3) as usize)
+        };
+        let Some(data) = bytes.get(pos + header..pos + header + length) else {
+            log::warn!("AML: truncated resource descriptor {:#04x}", tag);
+            break;
+        };
+        pos += header + length;
+
+        if tag.get_bit(7) {
+            parse_large(item, data, &mut resources);
+        } else if item == SMALL_END_TAG {
+            break;
+        } else {
+            parse_small(item, data, &mut resources);
+        }
+    }
+    resources
+}
+
+fn parse_small(item: u8, data: &[u8], resources: &mut Vec<Resource>) {
+    let resource = match item {
+        SMALL_IRQ if data.len() >= 2 => Resource::Irq {
+            mask: u16_at(data, 0),
+        },
+        SMALL_DMA if !data.is_empty() => Resource::Dma { mask: data[0] },
+        // the minimum base, fixed in a _CRS
+        SMALL_IO if data.len() >= 7 => Resource::Io {
+            base: u16_at(data, 1),
+            length: data[6] as u16,
+        },
+        SMALL_FIXED_IO if data.len() >= 3 => Resource::Io {
+            base: u16_at(data, 0) & 0x3FF,
+            length: data[2] as u16,
+        },
+        _ => return,
+    };
+    resources.push(resource);
+}
+
+fn parse_large(item: u8, data: &[u8], resources: &mut Vec<Resource>) {
+    let resource = match item {
+        LARGE_MEMORY24 if data.len() >= 9 => Resource::Memory {
+            base: (u16_at(data, 1) as u64) << 8,
+            length: (u16_at(data, 7) as u64) << 8,
+        },
+        LARGE_MEMORY32 if data.len() >= 17 => Resource::Memory {
+            base: u32_at(data, 1) as u64,
+            length: u32_at(data, 13) as u64,
+        },
+        LARGE_FIXED_MEMORY32 if data.len() >= 9 => Resource::Memory {
+            base: u32_at(data, 1) as u64,
+            length: u32_at(data, 5) as u64,
+        },
+        LARGE_EXTENDED_IRQ if data.len() >= 2 => {
+            let count = data[1] as usize;
+            for i in 0..count {
+                if data.len() >= 2 + 4 * (i + 1) {
+                    resources.push(Resource::Interrupt(u32_at(data, 2 + 4 * i)));
+                }
+            }
+            return;
+        }
+        // type, flags, type flags, then granularity, minimum, maximum, translation, length
+        LARGE_WORD_ADDRESS if data.len() >= 13 => {
+            address(data[0], u16_at(data, 5) as u64, u16_at(data, 11) as u64)
+        }
+        LARGE_DWORD_ADDRESS if data.len() >= 23 => {
+            address(data[0], u32_at(data, 7) as u64, u32_at(data, 19) as u64)
+        }
+        LARGE_QWORD_ADDRESS if data.len() >= 43 => {
+            address(data[0], u64_at(data, 11), u64_at(data, 35))
+        }
+        _ => return,
+    };
+    resources.push(resource);
+}
+
+fn address(kind: u8, base: u64, length: u64) -> Resource {
+    match kind {
+        ADDRESS_IO => Resource::Io {
+            base: base as u16,
+            length: length as u16,
+        },
+        ADDRESS_BUS => Resource::BusNumber {
+            base: base as u16,
+            length: length as u16,
+        },
+        // 0 is memory, vendor defined types are taken as memory too
+        _ => Resource::Memory { base, length },
+    }
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Io { base, length } => write!(f, "I/O {:#06x} ({} ports)", base, length),
+            Self::Memory { base, length } => {
+                write!(f, "memory {:#x} ({:#x} bytes)", base, length)
+            }
+            Self::Irq { mask } => {
+                write!(f, "IRQ")?;
+                for irq in (0..16).filter(|&i| mask.get_bit(i)) {
+                    write!(f, " {}", irq)?;
+                }
+                Ok(())
+            }
+            Self::Interrupt(gsi) => write!(f, "GSI {}", gsi),
+            Self::Dma { mask } => write!(f, "DMA mask {:#04x}", mask),
+            Self::BusNumber { base, length } => write!(f, "bus {:#04x} ({} buses)", base, length),
+        }
+    }
+}
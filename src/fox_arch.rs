@@ -0,0 +1,18 @@
+//! The few CPU operations the core of the app needs, for each architecture we build for
+//!
+//! Masking interrupts, halting until the next one and the free-running cycle counter behind
+//! [`crate::fox_time`]. Everything else architecture-specific stays in its subsystem behind a
+//! `target_arch` cfg: port I/O in [`crate::fox_port`], the legacy PC drivers, paging, the IDT.
+//!
+//! On aarch64 the interrupt controller and the generic timer are not driven yet, a halt is only
+//! woken by what the firmware left enabled.
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use x86::*;
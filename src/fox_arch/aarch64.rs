@@ -0,0 +1,48 @@
+//! The I bit of DAIF, WFI and the virtual count of the generic timer
+
+use core::arch::asm;
+
+/// DAIF.I, IRQs masked
+const DAIF_I: u64 = 1 << 7;
+
+pub fn are_interrupts_enabled() -> bool {
+    let daif: u64;
+    // SAFETY: reads the interrupt mask
+    unsafe { asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags)) };
+    daif & DAIF_I == 0
+}
+
+pub fn disable_interrupts() {
+    // SAFETY: masks IRQs, no memory is touched
+    unsafe { asm!("msr daifset, #2", options(nomem, nostack, preserves_flags)) };
+}
+
+pub fn enable_interrupts() {
+    // SAFETY: unmasks IRQs, no memory is touched
+    unsafe { asm!("msr daifclr, #2", options(nomem, nostack, preserves_flags)) };
+}
+
+/// WFI wakes on a pending IRQ even while masked, it is taken once unmasked
+pub fn enable_interrupts_and_halt() {
+    // SAFETY: as above
+    unsafe {
+        asm!(
+            "wfi",
+            "msr daifclr, #2",
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+}
+
+pub fn halt() {
+    // SAFETY: waits for an interrupt, no memory is touched
+    unsafe { asm!("wfi", options(nomem, nostack, preserves_flags)) };
+}
+
+/// CNTVCT_EL0, after the instructions before it
+pub fn counter() -> u64 {
+    let count: u64;
+    // SAFETY: EL0 access to the virtual count is enabled by the firmware for UEFI
+    unsafe { asm!("isb", "mrs {}, cntvct_el0", out(reg) count, options(nomem, nostack)) };
+    count
+}
@@ -0,0 +1,36 @@
+//! IF of RFLAGS, HLT and the TSC
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::_rdtsc;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::_rdtsc;
+
+use x86_64::instructions::{hlt, interrupts};
+
+pub fn are_interrupts_enabled() -> bool {
+    interrupts::are_enabled()
+}
+
+pub fn disable_interrupts() {
+    interrupts::disable();
+}
+
+pub fn enable_interrupts() {
+    interrupts::enable();
+}
+
+/// STI delays interrupts by one instruction, one pending since [`disable_interrupts`] wakes the
+/// HLT instead of being taken before it
+pub fn enable_interrupts_and_halt() {
+    interrupts::enable_and_hlt();
+}
+
+pub fn halt() {
+    hlt();
+}
+
+/// Time Stamp Counter
+pub fn counter() -> u64 {
+    // SAFETY: the TSC is available on every x86_64 CPU
+    unsafe { _rdtsc() }
+}
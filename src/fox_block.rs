@@ -0,0 +1,234 @@
+//! Block devices and their partitions
+//!
+//! [`BlockDevice`] is implemented by the AHCI and NVMe drivers and by [`UefiBlockIo`] for any
+//! disk the firmware knows. [`partitions`] reads the GPT or MBR of a disk, and [`Partition`]
+//! exposes one of its entries as a device of its own.
+
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+
+use uefi::Guid;
+
+use crate::drivers::DriverError;
+use crate::fox_vec::FixedVec;
+
+mod block_io;
+mod gpt;
+mod mbr;
+
+pub use block_io::UefiBlockIo;
+
+/// Largest block size the partition parsers handle
+pub const MAX_BLOCK_SIZE: usize = 4096;
+const MAX_PARTITIONS: usize = 128;
+
+/// Partition table entries, see [`partitions`]
+pub type Partitions = FixedVec<PartitionInfo, MAX_PARTITIONS>;
+
+/// Buffer for one block of up to [`MAX_BLOCK_SIZE`] bytes
+///
+/// Page-aligned, devices reject buffers below their I/O alignment (IoAlign of the Block I/O
+/// media, DMA of the drivers), which is at most a page.
+#[repr(C, align(4096))]
+pub struct BlockBuffer([u8; MAX_BLOCK_SIZE]);
+
+/// Storage addressed in fixed-size blocks
+pub trait BlockDevice {
+    /// Bytes per block
+    fn block_size(&self) -> usize;
+    fn block_count(&self) -> u64;
+    /// Read whole blocks starting at `lba`, the length of `buffer` is a multiple of the block size
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), DriverError>;
+    /// Write whole blocks starting at `lba` and flush them to the medium
+    // nothing writes disks yet, the file system is read-only
+    #[allow(dead_code)]
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), DriverError>;
+}
+
+/// Partition table entry
+#[derive(Copy, Clone, Debug)]
+pub struct PartitionInfo {
+    /// 1-based, logical MBR partitions start at 5
+    pub number: u32,
+    pub first_lba: u64,
+    pub block_count: u64,
+    pub kind: PartitionKind,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum PartitionKind {
+    Mbr {
+        system_id: u8,
+        bootable: bool,
+    },
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        /// UTF-16, zero-padded
+        name: [u16; 36],
+    },
+}
+
+/// One partition of a disk as a [`BlockDevice`]
+pub struct Partition<'a, D: BlockDevice + ?Sized> {
+    disk: &'a mut D,
+    info: PartitionInfo,
+}
+
+/// Check that a request covers whole blocks inside the device
+pub fn check_range(
+    dev: &(impl BlockDevice + ?Sized),
+    lba: u64,
+    length: usize,
+) -> Result<(), DriverError> {
+    if !length.is_multiple_of(dev.block_size()) {
+        return Err(DriverError::InvalidRequest);
+    }
+    let count = (length / dev.block_size()) as u64;
+    if lba
+        .checked_add(count)
+        .is_none_or(|end| end > dev.block_count())
+    {
+        return Err(DriverError::InvalidRequest);
+    }
+    Ok(())
+}
+
+/// Partitions of a disk from its GPT, or from its MBR when the MBR is not protective
+///
+/// Empty if the disk has no partition table.
+pub fn partitions(disk: &mut (impl BlockDevice + ?Sized)) -> Result<Partitions, DriverError> {
+    // log::trace!("fox_block::partitions");
+
+    if disk.block_size() > MAX_BLOCK_SIZE || disk.block_size() < 512 {
+        return Err(DriverError::InvalidRequest);
+    }
+
+    let mut partitions = Partitions::new();
+    let is_protective = mbr::read(disk, &mut partitions)?;
+    if is_protective {
+        partitions = Partitions::new();
+        gpt::read(disk, &mut partitions)?;
+    }
+    Ok(partitions)
+}
+
+/// Log the partition table of a disk
+pub fn log_partitions(disk: &mut (impl BlockDevice + fmt::Display)) {
+    log::info!(
+        "{}: {} blocks of {} bytes",
+        disk,
+        disk.block_count(),
+        disk.block_size()
+    );
+
+    let partitions = match partitions(disk) {
+        Ok(partitions) => partitions,
+        Err(err) => {
+            log::warn!("{}: partition table: {}", disk, err);
+            return;
+        }
+    };
+    if partitions.is_empty() {
+        log::info!("{}: no partition table", disk);
+    }
+    for info in partitions.iter() {
+        let mut partition = Partition::new(disk, *info);
+        // a volume boot record ends like an MBR
+        let mut block = BlockBuffer::new();
+        let block = &mut block[..partition.block_size()];
+        let has_boot_record = partition.block_count() > 0
+            && partition.read_blocks(0, block).is_ok()
+            && block[510..512] == [0x55, 0xAA];
+        log::info!(
+            "  {}{}",
+            info,
+            if has_boot_record { ", boot record" } else { "" }
+        );
+    }
+}
+
+impl BlockBuffer {
+    pub const fn new() -> Self {
+        Self([0; MAX_BLOCK_SIZE])
+    }
+}
+
+impl Deref for BlockBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for BlockBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl<'a, D: BlockDevice + ?Sized> Partition<'a, D> {
+    pub fn new(disk: &'a mut D, info: PartitionInfo) -> Self {
+        Self { disk, info }
+    }
+}
+
+impl<D: BlockDevice + ?Sized> BlockDevice for Partition<'_, D> {
+    fn block_size(&self) -> usize {
+        self.disk.block_size()
+    }
+
+    fn block_count(&self) -> u64 {
+        self.info.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), DriverError> {
+        check_range(self, lba, buffer.len())?;
+        self.disk.read_blocks(self.info.first_lba + lba, buffer)
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), DriverError> {
+        check_range(self, lba, buffer.len())?;
+        self.disk.write_blocks(self.info.first_lba + lba, buffer)
+    }
+}
+
+impl<D: BlockDevice + fmt::Display + ?Sized> fmt::Display for Partition<'_, D> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} partition {}", self.disk, self.info.number)
+    }
+}
+
+impl fmt::Display for PartitionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: LBA {} + {}",
+            self.number, self.first_lba, self.block_count
+        )?;
+        match self.kind {
+            PartitionKind::Mbr {
+                system_id,
+                bootable,
+            } => write!(
+                f,
+                ", type {:#04x}{}",
+                system_id,
+                if bootable { ", bootable" } else { "" }
+            ),
+            PartitionKind::Gpt {
+                type_guid,
+                unique_guid,
+                name,
+            } => {
+                write!(f, ", type {}, PARTUUID {} \"", type_guid, unique_guid)?;
+                let name = name.iter().copied().take_while(|&i| i != 0);
+                for c in char::decode_utf16(name) {
+                    write!(f, "{}", c.unwrap_or(char::REPLACEMENT_CHARACTER))?;
+                }
+                write!(f, "\"")
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! UEFI Block I/O protocol as a [`BlockDevice`]
+//!
+//! Only while boot services are active.
+
+use core::fmt;
+
+use uefi::Handle;
+use uefi::boot::{
+    HandleBuffer, OpenProtocolAttributes, OpenProtocolParams, ScopedProtocol, SearchType,
+    image_handle, locate_handle_buffer, open_protocol,
+};
+use uefi::proto::media::block::BlockIO;
+
+use super::{BlockDevice, check_range};
+use crate::drivers::DriverError;
+
+/// Disk or partition exposed by the firmware
+pub struct UefiBlockIo {
+    handle: Handle,
+    protocol: ScopedProtocol<BlockIO>,
+    media_id: u32,
+    block_size: usize,
+    block_count: u64,
+    io_align: usize,
+    is_read_only: bool,
+    is_partition: bool,
+}
+
+impl UefiBlockIo {
+    /// Handles supporting the Block I/O protocol
+    pub fn handles() -> uefi::Result<HandleBuffer> {
+        locate_handle_buffer(SearchType::from_proto::<BlockIO>())
+    }
+
+    /// Open the protocol non-exclusively, the firmware drivers keep using the device
+    pub fn open(handle: Handle) -> uefi::Result<Self> {
+        // log::trace!("UefiBlockIo::open()");
+
+        let params = OpenProtocolParams {
+            handle,
+            agent: image_handle(),
+            controller: None,
+        };
+        // SAFETY: the disk is only read, and written through the same protocol as everyone else
+        let protocol =
+            unsafe { open_protocol::<BlockIO>(params, OpenProtocolAttributes::GetProtocol) }?;
+
+        let media = protocol.media();
+        let block_count = if media.is_media_present() {
+            media.last_block() + 1
+        } else {
+            0
+        };
+        Ok(Self {
+            handle,
+            media_id: media.media_id(),
+            block_size: media.block_size() as usize,
+            block_count,
+            io_align: (media.io_align() as usize).max(1),
+            is_read_only: media.is_read_only(),
+            is_partition: media.is_logical_partition(),
+            protocol,
+        })
+    }
+
+    /// The firmware already split this out of a disk
+    pub fn is_partition(&self) -> bool {
+        self.is_partition
+    }
+
+    fn check_align(&self, buffer: &[u8]) -> Result<(), DriverError> {
+        if !(buffer.as_ptr() as usize).is_multiple_of(self.io_align) {
+            return Err(DriverError::InvalidRequest);
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for UefiBlockIo {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, lba: u64, buffer: &mut [u8]) -> Result<(), DriverError> {
+        check_range(self, lba, buffer.len())?;
+        self.check_align(buffer)?;
+        self.protocol
+            .read_blocks(self.media_id, lba, buffer)
+            .map_err(|err| DriverError::Firmware(err.status()))
+    }
+
+    fn write_blocks(&mut self, lba: u64, buffer: &[u8]) -> Result<(), DriverError> {
+        check_range(self, lba, buffer.len())?;
+        self.check_align(buffer)?;
+        if self.is_read_only {
+            return Err(DriverError::InvalidRequest);
+        }
+        self.protocol
+            .write_blocks(self.media_id, lba, buffer)
+            .and_then(|()| self.protocol.flush_blocks())
+            .map_err(|err| DriverError::Firmware(err.status()))
+    }
+}
+
+impl fmt::Display for UefiBlockIo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Block IO {:?}", self.handle)
+    }
+}
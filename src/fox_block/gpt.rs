@@ -0,0 +1,150 @@
+//! GUID Partition Table
+//!
+//! https://wiki.osdev.org/GPT
+
+use uefi::Guid;
+
+use super::{BlockBuffer, BlockDevice, PartitionInfo, PartitionKind, Partitions};
+use crate::drivers::DriverError;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+
+/// Partitions from the primary GPT, or from the backup one if the primary is damaged
+pub(super) fn read(
+    disk: &mut (impl BlockDevice + ?Sized),
+    partitions: &mut Partitions,
+) -> Result<(), DriverError> {
+    // log::trace!("gpt::read");
+
+    let backup_lba = disk.block_count() - 1;
+    let header = match read_header(disk, 1) {
+        Some(header) => header,
+        None => {
+            log::warn!("GPT: invalid primary header, trying the backup");
+            read_header(disk, backup_lba).ok_or(DriverError::InvalidRequest)?
+        }
+    };
+    read_entries(disk, &header, partitions)
+}
+
+struct Header {
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: usize,
+    entries_crc: u32,
+}
+
+/// Header at `lba` if its signature and checksum are valid
+fn read_header(disk: &mut (impl BlockDevice + ?Sized), lba: u64) -> Option<Header> {
+    let mut block = BlockBuffer::new();
+    let block = &mut block[..disk.block_size()];
+    disk.read_blocks(lba, block).ok()?;
+
+    if &block[0..8] != SIGNATURE {
+        return None;
+    }
+    let header_size = u32::from_le_bytes(block[12..16].try_into().unwrap()) as usize;
+    if !(MIN_HEADER_SIZE..=block.len()).contains(&header_size) {
+        return None;
+    }
+    // computed with the CRC field zeroed
+    let header_crc = u32::from_le_bytes(block[16..20].try_into().unwrap());
+    block[16..20].fill(0);
+    if crc32(!0, &block[..header_size]) != !header_crc {
+        return None;
+    }
+
+    let header = Header {
+        entries_lba: u64::from_le_bytes(block[72..80].try_into().unwrap()),
+        entry_count: u32::from_le_bytes(block[80..84].try_into().unwrap()),
+        entry_size: u32::from_le_bytes(block[84..88].try_into().unwrap()) as usize,
+        entries_crc: u32::from_le_bytes(block[88..92].try_into().unwrap()),
+    };
+    // a power of two of at least 128 bytes, so entries never straddle blocks
+    if header.entry_size < MIN_ENTRY_SIZE
+        || !header.entry_size.is_power_of_two()
+        || header.entry_size > block.len()
+    {
+        return None;
+    }
+    Some(header)
+}
+
+fn read_entries(
+    disk: &mut (impl BlockDevice + ?Sized),
+    header: &Header,
+    partitions: &mut Partitions,
+) -> Result<(), DriverError> {
+    let mut block = BlockBuffer::new();
+    let block = &mut block[..disk.block_size()];
+    let entries_per_block = block.len() / header.entry_size;
+
+    let mut crc = !0;
+    let mut lba = header.entries_lba;
+    let mut index = 0;
+    while index < header.entry_count {
+        disk.read_blocks(lba, block)?;
+        lba += 1;
+
+        for entry in block.chunks(header.entry_size).take(entries_per_block) {
+            if index == header.entry_count {
+                break;
+            }
+            index += 1;
+            crc = crc32(crc, entry);
+
+            let type_guid = guid(&entry[0..16]);
+            if type_guid == Guid::ZERO {
+                continue;
+            }
+            let first_lba = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last_lba = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            let mut name = [0u16; 36];
+            for (i, c) in entry[56..128].chunks(2).enumerate() {
+                name[i] = u16::from_le_bytes([c[0], c[1]]);
+            }
+
+            let info = PartitionInfo {
+                number: index,
+                first_lba,
+                block_count: (last_lba + 1).saturating_sub(first_lba),
+                kind: PartitionKind::Gpt {
+                    type_guid,
+                    unique_guid: guid(&entry[16..32]),
+                    name,
+                },
+            };
+            if partitions.push(info).is_err() {
+                log::warn!("GPT: too many partitions, {} ignored", index);
+            }
+        }
+    }
+
+    if !crc != header.entries_crc {
+        log::warn!("GPT: partition entry array checksum mismatch");
+    }
+    Ok(())
+}
+
+/// GUIDs are stored in the mixed-endian layout of `EFI_GUID`
+fn guid(bytes: &[u8]) -> Guid {
+    Guid::from_bytes(bytes.try_into().unwrap())
+}
+
+/// CRC-32 (IEEE 802.3), continue from `crc`, start with `!0` and invert the result
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,119 @@
+//! Master Boot Record
+//!
+//! https://wiki.osdev.org/MBR_(x86)
+
+use super::{BlockBuffer, BlockDevice, PartitionInfo, PartitionKind, Partitions};
+use crate::drivers::DriverError;
+
+const OFFSET_ENTRIES: usize = 446;
+const LENGTH_ENTRY: usize = 16;
+
+const SYSTEM_ID_PROTECTIVE: u8 = 0xEE;
+const SYSTEM_IDS_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+
+/// Extended boot records followed before giving up on a looping chain
+const MAX_LOGICAL: usize = 64;
+
+/// Primary and logical partitions, returns whether the MBR protects a GPT instead
+pub(super) fn read(
+    disk: &mut (impl BlockDevice + ?Sized),
+    partitions: &mut Partitions,
+) -> Result<bool, DriverError> {
+    // log::trace!("mbr::read");
+
+    let mut block = BlockBuffer::new();
+    let block = &mut block[..disk.block_size()];
+    disk.read_blocks(0, block)?;
+    if block[510..512] != [0x55, 0xAA] {
+        return Ok(false);
+    }
+
+    let entries = entries(block);
+    if entries
+        .iter()
+        .any(|i| i.is_some_and(|i| i.system_id == SYSTEM_ID_PROTECTIVE))
+    {
+        return Ok(true);
+    }
+
+    for (i, entry) in entries.iter().enumerate() {
+        let Some(entry) = entry else {
+            continue;
+        };
+        if SYSTEM_IDS_EXTENDED.contains(&entry.system_id) {
+            read_logical(disk, entry.lba as u64, partitions)?;
+        } else {
+            push(partitions, entry, i as u32 + 1, 0);
+        }
+    }
+    Ok(false)
+}
+
+/// Walk the chain of extended boot records of an extended partition
+fn read_logical(
+    disk: &mut (impl BlockDevice + ?Sized),
+    extended_lba: u64,
+    partitions: &mut Partitions,
+) -> Result<(), DriverError> {
+    let mut block = BlockBuffer::new();
+    let block = &mut block[..disk.block_size()];
+
+    let mut ebr_lba = extended_lba;
+    for number in 5..5 + MAX_LOGICAL as u32 {
+        disk.read_blocks(ebr_lba, block)?;
+        if block[510..512] != [0x55, 0xAA] {
+            break;
+        }
+        let [logical, next, ..] = entries(block);
+        // the logical partition starts relative to its EBR
+        if let Some(logical) = logical {
+            push(partitions, &logical, number, ebr_lba);
+        }
+        // the next EBR starts relative to the extended partition
+        match next {
+            Some(next) if SYSTEM_IDS_EXTENDED.contains(&next.system_id) => {
+                ebr_lba = extended_lba + next.lba as u64;
+            }
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    bootable: bool,
+    system_id: u8,
+    lba: u32,
+    count: u32,
+}
+
+/// The four entries of an MBR or EBR, `None` for unused ones
+fn entries(block: &[u8]) -> [Option<Entry>; 4] {
+    core::array::from_fn(|i| {
+        let entry = &block[OFFSET_ENTRIES + i * LENGTH_ENTRY..][..LENGTH_ENTRY];
+        let system_id = entry[4];
+        let count = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        (system_id != 0 && count != 0).then(|| Entry {
+            bootable: entry[0] == 0x80,
+            system_id,
+            lba: u32::from_le_bytes(entry[8..12].try_into().unwrap()),
+            count,
+        })
+    })
+}
+
+fn push(partitions: &mut Partitions, entry: &Entry, number: u32, base_lba: u64) {
+    let info = PartitionInfo {
+        number,
+        first_lba: base_lba + entry.lba as u64,
+        block_count: entry.count as u64,
+        kind: PartitionKind::Mbr {
+            system_id: entry.system_id,
+            bootable: entry.bootable,
+        },
+    };
+    if partitions.push(info).is_err() {
+        log::warn!("MBR: too many partitions, {} ignored", number);
+    }
+}
@@ -0,0 +1,116 @@
+//! Boot log on the EFI System Partition
+//!
+//! For real hardware without a serial port: the driver probe results and the captured log are
+//! written to `\EFI\my-uefi-app\boot.log` on the volume the app was loaded from. Other exports
+//! go to the same directory with [`write_file`].
+
+use core::fmt::{self, Write};
+
+use uefi::boot::{get_image_file_system, image_handle};
+use uefi::proto::media::file::{Directory, File, FileAttribute, FileMode, RegularFile};
+use uefi::{CStr16, ResultExt, Status, cstr16};
+
+use crate::drivers;
+use crate::fox_acpi::missing_tables;
+use crate::fox_log::with_buffer;
+use crate::fox_uefi::is_boot_services_active;
+
+const FILE_NAME: &CStr16 = cstr16!("boot.log");
+
+/// Write the boot log, replacing the previous one
+///
+/// Needs boot services.
+pub fn save() -> uefi::Result {
+    // log::trace!("fox_bootlog::save");
+    assert!(is_boot_services_active());
+
+    let mut file = create_file(FILE_NAME)?;
+    let mut writer = FileWriter {
+        file: &mut file,
+        error: None,
+    };
+    if write_summary(&mut writer).is_err() {
+        return Err(writer.error.unwrap_or(Status::ABORTED.into()));
+    }
+
+    // the firmware's file I/O wants its timer interrupt, the buffer is copied out first
+    let log = with_buffer(|older, newer| [older, newer].concat());
+    file.write(&log).discard_errdata()?;
+
+    file.flush()?;
+    file.close();
+    Ok(())
+}
+
+/// Driver states and missing tables, before the log
+fn write_summary(writer: &mut impl Write) -> fmt::Result {
+    writeln!(writer, "Drivers:")?;
+    for i in drivers::status() {
+        writeln!(writer, "  {}: {}", i.name, i.state)?;
+    }
+    let mut missing = missing_tables().peekable();
+    if missing.peek().is_some() {
+        write!(writer, "\nMissing ACPI tables:")?;
+        for table in missing {
+            write!(writer, " {}", table.name())?;
+        }
+        writeln!(writer)?;
+    }
+    writeln!(writer, "\nLog:")
+}
+
+/// Write `data` to the file `name` next to the boot log, replacing the previous one
+///
+/// Needs boot services.
+pub fn write_file(name: &CStr16, data: &[u8]) -> uefi::Result {
+    // log::trace!("fox_bootlog::write_file");
+    assert!(is_boot_services_active());
+
+    let mut file = create_file(name)?;
+    file.write(data).discard_errdata()?;
+    file.flush()?;
+    file.close();
+    Ok(())
+}
+
+/// Create an empty file in `\EFI\my-uefi-app`
+fn create_file(name: &CStr16) -> uefi::Result<RegularFile> {
+    let mut fs = get_image_file_system(image_handle())?;
+    let mut root = fs.open_volume()?;
+    let mut dir = open_dir(&mut root, cstr16!("EFI"))?;
+    let mut dir = open_dir(&mut dir, cstr16!("my-uefi-app"))?;
+
+    // there is no truncate, delete the previous file instead
+    if let Ok(file) = dir.open(name, FileMode::ReadWrite, FileAttribute::empty()) {
+        file.delete()?;
+    }
+    let file = dir.open(name, FileMode::CreateReadWrite, FileAttribute::empty())?;
+    file.into_regular_file()
+        .ok_or(uefi::Error::from(Status::INVALID_PARAMETER))
+}
+
+/// Open or create a subdirectory
+fn open_dir(parent: &mut Directory, name: &CStr16) -> uefi::Result<Directory> {
+    parent
+        .open(name, FileMode::CreateReadWrite, FileAttribute::DIRECTORY)?
+        .into_directory()
+        .ok_or(Status::INVALID_PARAMETER.into())
+}
+
+/// `fmt::Write` for a file, keeping the UEFI error
+struct FileWriter<'a> {
+    file: &'a mut RegularFile,
+    error: Option<uefi::Error>,
+}
+
+impl Write for FileWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.file
+            .write(s.as_bytes())
+            .discard_errdata()
+            .map_err(|err| {
+                self.error = Some(err);
+                fmt::Error
+            })
+    }
+}
@@ -0,0 +1,486 @@
+//! App configuration, stored in a UEFI variable of ours
+//!
+//! The variable holds `key=value` lines, so it reads fine in the `var` shell command and a
+//! firmware shell. Unknown keys are skipped with a warning, a missing variable means defaults.
+//!
+//! Load options override the stored configuration for one run: `--key=value` for any key,
+//! `--<driver>` and `--no-<driver>` to enable or disable a driver, e.g.
+//! `my-uefi-app.efi --no-i8042 --log=trace --serial`. `--selftest` runs [`crate::fox_test`]
+//! instead of the shell and `--tftp=<server>:<file>` fetches a file to the ESP at boot, they
+//! are never stored.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::time::Duration;
+
+use log::LevelFilter;
+use uefi::Status;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::{
+    AcpiEc, Ahci, Apic, CmosRtc, E1000, I8042, Nvme, PcSpeaker, Pci, Pic8259, Pit8254, Serial16550,
+    VirtioNet, Xhci,
+};
+use crate::drivers::{Driver, MmioUart};
+use crate::fox_input;
+use crate::fox_log::{self, Sink};
+use crate::fox_net::Ipv4Cidr;
+use crate::fox_port;
+use crate::fox_sync::without_interrupts;
+use crate::fox_uefi::{load_options, vars};
+
+/// Name of the variable under [`vars::APP_VENDOR`]
+const VARIABLE: &str = "Config";
+
+/// Drivers that can be disabled, bit `i` of [`Config::disabled_drivers`] is entry `i`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const DRIVERS: [&str; 15] = [
+    Serial16550::DRIVER_NAME,
+    CmosRtc::DRIVER_NAME,
+    Pci::DRIVER_NAME,
+    I8042::DRIVER_NAME,
+    Ahci::DRIVER_NAME,
+    Nvme::DRIVER_NAME,
+    Pic8259::DRIVER_NAME,
+    Apic::DRIVER_NAME,
+    Pit8254::DRIVER_NAME,
+    AcpiEc::DRIVER_NAME,
+    PcSpeaker::DRIVER_NAME,
+    E1000::DRIVER_NAME,
+    VirtioNet::DRIVER_NAME,
+    Xhci::DRIVER_NAME,
+    MmioUart::DRIVER_NAME,
+];
+
+/// The MMIO UART is the only driver outside x86
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+const DRIVERS: [&str; 1] = [MmioUart::DRIVER_NAME];
+
+/// Of `netconsole=` without a port
+const SYSLOG_PORT: u16 = 514;
+/// Longest `delay=`, seconds
+const MAX_BOOT_DELAY: u64 = 60 * 60;
+/// Longest `idle=`, seconds
+const MAX_IDLE_TIMEOUT: u64 = 24 * 60 * 60;
+
+/// Written by [`init`] and [`set`], with the load options applied
+static mut CONFIG: Config = Config::DEFAULT;
+/// As in the variable, without the load options
+static mut STORED: Config = Config::DEFAULT;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Config {
+    /// Default level of [`fox_log`]
+    pub log_level: LevelFilter,
+    /// Where [`crate::fox_print!`] output goes, [`Sink`] bits
+    print_sinks: u8,
+    /// Log records colored by level
+    color: bool,
+    /// Bits by [`DRIVERS`]
+    disabled_drivers: u16,
+    /// Longest driver init, see [`crate::fox_watchdog`], zero for no limit
+    pub watchdog: Duration,
+    /// Pause before exiting boot services, to read the screen
+    pub boot_delay: Duration,
+    /// The event loop quits after this long, nobody may be at the keyboard
+    pub idle_timeout: Duration,
+    /// Name of the keyboard layout
+    pub layout: &'static str,
+    /// Extend a TPM PCR with this configuration, see [`crate::fox_tpm`]
+    pub measure_config: bool,
+    /// Record the i8042 port accesses from boot, see [`I8042::set_trace`]
+    kbc_trace: bool,
+    /// Log every port and MMIO access from boot, see [`fox_port::set_tracing`]
+    port_trace: bool,
+    /// Our address and subnet, see [`crate::fox_net`]
+    pub ip: Option<Ipv4Cidr>,
+    /// Router for addresses outside the subnet of `ip`
+    pub gateway: Option<Ipv4Addr>,
+    /// Log records go there over UDP, see [`crate::fox_net::send_log`]
+    pub netconsole: Option<SocketAddrV4>,
+    /// Run the self-tests instead of the shell, only from the load options
+    pub selftest: bool,
+    /// Server and file to fetch at boot, only from the load options
+    pub tftp: Option<(Ipv4Addr, &'static str)>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    UnknownKey,
+    InvalidValue,
+    Uefi(Status),
+}
+
+impl Config {
+    pub const DEFAULT: Self = Self {
+        log_level: LevelFilter::Debug,
+        print_sinks: Sink::Console as u8 | Sink::Serial as u8,
+        color: true,
+        disabled_drivers: 0,
+        watchdog: Duration::from_secs(5),
+        boot_delay: Duration::from_secs(10),
+        idle_timeout: Duration::from_secs(600),
+        layout: "us",
+        measure_config: false,
+        kbc_trace: false,
+        port_trace: false,
+        ip: None,
+        gateway: None,
+        netconsole: None,
+        selftest: false,
+        tftp: None,
+    };
+
+    /// Unknown names are enabled
+    pub fn is_driver_enabled(&self, name: &str) -> bool {
+        driver_index(name).is_none_or(|i| self.disabled_drivers & (1 << i) == 0)
+    }
+
+    fn set_driver_enabled(&mut self, index: usize, is_enabled: bool) {
+        if is_enabled {
+            self.disabled_drivers &= !(1 << index);
+        } else {
+            self.disabled_drivers |= 1 << index;
+        }
+    }
+
+    /// Set `key` from its text form, as in the variable
+    fn set(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let seconds = |max: u64| {
+            value
+                .parse()
+                .ok()
+                .filter(|&i| i <= max)
+                .map(Duration::from_secs)
+                .ok_or(Error::InvalidValue)
+        };
+        match key {
+            "log" => self.log_level = value.parse().map_err(|_| Error::InvalidValue)?,
+            "print" => {
+                let mut sinks = 0;
+                for name in value.split(',').filter(|i| !i.is_empty() && *i != "none") {
+                    let sink = Sink::ALL.into_iter().find(|i| i.name() == name);
+                    sinks |= sink.ok_or(Error::InvalidValue)? as u8;
+                }
+                self.print_sinks = sinks;
+            }
+            "disable" => {
+                let mut disabled = 0;
+                for name in value.split(',').filter(|i| !i.is_empty() && *i != "none") {
+                    disabled |= 1 << driver_index(name).ok_or(Error::InvalidValue)?;
+                }
+                self.disabled_drivers = disabled;
+            }
+            "watchdog" => {
+                let ms = value.parse().map_err(|_| Error::InvalidValue)?;
+                self.watchdog = Duration::from_millis(ms);
+            }
+            "delay" => self.boot_delay = seconds(MAX_BOOT_DELAY)?,
+            "idle" => self.idle_timeout = seconds(MAX_IDLE_TIMEOUT)?,
+            "layout" => {
+                let layout = fox_input::layouts().iter().find(|i| i.name == value);
+                self.layout = layout.ok_or(Error::InvalidValue)?.name;
+            }
+            "color" => {
+                self.color = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidValue),
+                }
+            }
+            "measure" => {
+                self.measure_config = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidValue),
+                }
+            }
+            "kbctrace" => {
+                self.kbc_trace = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidValue),
+                }
+            }
+            "porttrace" => {
+                self.port_trace = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => return Err(Error::InvalidValue),
+                }
+            }
+            "ip" => {
+                self.ip = match value {
+                    "none" => None,
+                    _ => Some(value.parse().map_err(|_| Error::InvalidValue)?),
+                }
+            }
+            "gateway" => {
+                self.gateway = match value {
+                    "none" => None,
+                    _ => Some(value.parse().map_err(|_| Error::InvalidValue)?),
+                }
+            }
+            "netconsole" => {
+                self.netconsole = match value {
+                    "none" => None,
+                    _ if value.contains(':') => {
+                        Some(value.parse().map_err(|_| Error::InvalidValue)?)
+                    }
+                    _ => {
+                        let address = value.parse().map_err(|_| Error::InvalidValue)?;
+                        Some(SocketAddrV4::new(address, SYSLOG_PORT))
+                    }
+                }
+            }
+            _ => return Err(Error::UnknownKey),
+        }
+        Ok(())
+    }
+
+    /// Defaults overridden by the `key=value` lines of `text`
+    fn parse(text: &str) -> Self {
+        let mut config = Self::DEFAULT;
+        for line in text.lines().filter(|i| !i.trim().is_empty()) {
+            let result = match line.split_once('=') {
+                Some((key, value)) => config.set(key.trim(), value.trim()),
+                None => Err(Error::UnknownKey),
+            };
+            if let Err(err) = result {
+                log::warn!("Config: {}: {}", line, err);
+            }
+        }
+        config
+    }
+
+    /// Override settings by command line arguments, others than `--` options are skipped
+    fn apply_args(&mut self, args: &str) {
+        for arg in args.split_whitespace() {
+            let Some(option) = arg.strip_prefix("--") else {
+                // the image name
+                continue;
+            };
+            let result = match option.split_once('=') {
+                Some(("tftp", value)) => match value.split_once(':') {
+                    Some((server, file)) if !file.is_empty() => match server.parse() {
+                        // load options are parsed once per boot
+                        Ok(server) => {
+                            self.tftp = Some((server, String::from(file).leak()));
+                            Ok(())
+                        }
+                        Err(_) => Err(Error::InvalidValue),
+                    },
+                    _ => Err(Error::InvalidValue),
+                },
+                Some((key, value)) => self.set(key, value),
+                None if option == "selftest" => {
+                    self.selftest = true;
+                    Ok(())
+                }
+                None => match option.strip_prefix("no-") {
+                    Some(driver) => {
+                        driver_by_prefix(driver).map(|i| self.set_driver_enabled(i, false))
+                    }
+                    None => driver_by_prefix(option).map(|i| self.set_driver_enabled(i, true)),
+                },
+            };
+            if let Err(err) = result {
+                log::warn!("Config: {}: {}", arg, err);
+            }
+        }
+    }
+
+    /// Make the settings that are not read on use take effect
+    fn apply(&self) {
+        fox_log::set_level(self.log_level);
+        fox_log::set_print_sinks(self.print_sinks);
+        fox_log::set_color(self.color);
+        fox_log::set_sink(Sink::Net, self.netconsole.is_some());
+        fox_input::set_layout(self.layout);
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        I8042::set_trace(self.kbc_trace);
+        fox_port::set_tracing(self.port_trace);
+    }
+}
+
+fn driver_index(name: &str) -> Option<usize> {
+    DRIVERS.iter().position(|i| *i == name)
+}
+
+/// `serial` for `serial16550`, the name must be unambiguous
+fn driver_by_prefix(prefix: &str) -> Result<usize, Error> {
+    let mut matches = (0..DRIVERS.len()).filter(|&i| DRIVERS[i].starts_with(prefix));
+    match (matches.next(), matches.next()) {
+        (Some(index), None) if !prefix.is_empty() => Ok(index),
+        _ => Err(Error::UnknownKey),
+    }
+}
+
+/// The current configuration
+pub fn config() -> Config {
+    without_interrupts(|| {
+        let config = &raw const CONFIG;
+        // SAFETY: interrupts are disabled
+        unsafe { *config }
+    })
+}
+
+/// The configuration of the next boots, as far as it is stored
+pub fn stored() -> Config {
+    without_interrupts(|| {
+        let stored = &raw const STORED;
+        // SAFETY: interrupts are disabled
+        unsafe { *stored }
+    })
+}
+
+/// Run `f` on the current and the stored configuration
+fn update(mut f: impl FnMut(&mut Config) -> Result<(), Error>) -> Result<(), Error> {
+    let config = without_interrupts(|| {
+        let current = &raw mut CONFIG;
+        let stored = &raw mut STORED;
+        // SAFETY: interrupts are disabled
+        let (current, stored) = unsafe { (&mut *current, &mut *stored) };
+        // check on a copy, so a bad value changes neither
+        let mut new_stored = *stored;
+        f(&mut new_stored)?;
+        f(current)?;
+        *stored = new_stored;
+        Ok(*current)
+    })?;
+    config.apply();
+    Ok(())
+}
+
+/// Load the stored configuration, defaults if there is none, and apply the load options
+///
+/// Must be called while boot services are active.
+pub fn init() {
+    // log::trace!("fox_config::init");
+
+    let stored = match vars::read(VARIABLE, &vars::APP_VENDOR) {
+        Ok((data, _)) => match core::str::from_utf8(&data) {
+            Ok(text) => Config::parse(text),
+            Err(_) => {
+                log::warn!("Config: not UTF-8, using defaults");
+                Config::DEFAULT
+            }
+        },
+        Err(err) if err.status() == Status::NOT_FOUND => Config::DEFAULT,
+        Err(err) => {
+            log::warn!("Config: {}, using defaults", err.status());
+            Config::DEFAULT
+        }
+    };
+    let mut config = stored;
+    match load_options() {
+        Ok(args) => {
+            if !args.is_empty() {
+                log::info!("Load options: {}", args);
+            }
+            config.apply_args(&args);
+        }
+        Err(err) => log::warn!("Config: no load options: {}", err.status()),
+    }
+
+    without_interrupts(|| {
+        let current = &raw mut CONFIG;
+        let stored_storage = &raw mut STORED;
+        // SAFETY: interrupts are disabled
+        unsafe {
+            *current = config;
+            *stored_storage = stored;
+        }
+    });
+    config.apply();
+}
+
+/// Set `key` for this run and the next boots, the load options stay in effect otherwise
+pub fn set(key: &str, value: &str) -> Result<(), Error> {
+    update(|config| config.set(key, value))?;
+    let mut text = String::new();
+    let _ = write!(text, "{}", stored());
+    vars::write(VARIABLE, text.as_bytes()).map_err(|err| Error::Uefi(err.status()))
+}
+
+/// Back to defaults, the variable is deleted
+pub fn reset() -> Result<(), Error> {
+    update(|config| {
+        *config = Config::DEFAULT;
+        Ok(())
+    })?;
+    match vars::delete(VARIABLE) {
+        Err(err) if err.status() != Status::NOT_FOUND => Err(Error::Uefi(err.status())),
+        _ => Ok(()),
+    }
+}
+
+/// The variable contents, one `key=value` per line
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "log={}", self.log_level)?;
+        write!(f, "print=")?;
+        let sinks = Sink::ALL
+            .into_iter()
+            .filter(|&i| self.print_sinks & i as u8 != 0);
+        let mut is_empty = true;
+        for sink in sinks {
+            if !is_empty {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", sink.name())?;
+            is_empty = false;
+        }
+        if is_empty {
+            write!(f, "none")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "color={}", if self.color { "on" } else { "off" })?;
+        write!(f, "disable=")?;
+        let disabled = (0..DRIVERS.len()).filter(|i| self.disabled_drivers & (1 << i) != 0);
+        let mut is_empty = true;
+        for i in disabled {
+            if !is_empty {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", DRIVERS[i])?;
+            is_empty = false;
+        }
+        if is_empty {
+            write!(f, "none")?;
+        }
+        writeln!(f)?;
+        writeln!(f, "watchdog={}", self.watchdog.as_millis())?;
+        writeln!(f, "delay={}", self.boot_delay.as_secs())?;
+        writeln!(f, "idle={}", self.idle_timeout.as_secs())?;
+        writeln!(f, "layout={}", self.layout)?;
+        let measure = if self.measure_config { "on" } else { "off" };
+        writeln!(f, "measure={}", measure)?;
+        writeln!(f, "kbctrace={}", if self.kbc_trace { "on" } else { "off" })?;
+        let port_trace = if self.port_trace { "on" } else { "off" };
+        writeln!(f, "porttrace={}", port_trace)?;
+        match self.ip {
+            Some(ip) => writeln!(f, "ip={}", ip)?,
+            None => writeln!(f, "ip=none")?,
+        }
+        match self.gateway {
+            Some(gateway) => writeln!(f, "gateway={}", gateway)?,
+            None => writeln!(f, "gateway=none")?,
+        }
+        match self.netconsole {
+            Some(netconsole) => writeln!(f, "netconsole={}", netconsole),
+            None => writeln!(f, "netconsole=none"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownKey => write!(f, "unknown key"),
+            Self::InvalidValue => write!(f, "invalid value"),
+            Self::Uefi(status) => write!(f, "UEFI variable: {}", status),
+        }
+    }
+}
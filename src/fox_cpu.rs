@@ -0,0 +1,187 @@
+//! CPUID: vendor, brand string, family/model/stepping and the features we care about
+//!
+//! https://www.intel.com/content/www/us/en/developer/articles/technical/intel-sdm.html (CPUID)
+//! https://wiki.osdev.org/CPUID
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{__cpuid, __cpuid_count};
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::str;
+
+use bit_field::BitField;
+
+pub mod msr;
+
+/// First extended leaf, returns the highest extended leaf
+const EXTENDED: u32 = 0x8000_0000;
+/// Leaves of the 48-byte brand string
+const BRAND_LEAVES: [u32; 3] = [0x8000_0002, 0x8000_0003, 0x8000_0004];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Feature {
+    /// On-chip local APIC
+    Apic,
+    /// Local APIC timer one-shot at a TSC value
+    TscDeadline,
+    X2Apic,
+    Rdrand,
+    Rdseed,
+    /// The TSC runs at a constant rate in every P-, C- and T-state
+    InvariantTsc,
+    /// Core temperature in IA32_THERM_STATUS, Intel only
+    DigitalThermalSensor,
+    /// Running in a virtual machine, always clear on real hardware
+    Hypervisor,
+}
+
+#[derive(Copy, Clone)]
+enum Register {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Identification of the CPU we run on
+pub struct Cpu {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    /// With the extended family added
+    pub family: u32,
+    /// With the extended model added
+    pub model: u32,
+    pub stepping: u32,
+}
+
+impl Feature {
+    pub const ALL: [Self; 8] = [
+        Self::Apic,
+        Self::TscDeadline,
+        Self::X2Apic,
+        Self::Rdrand,
+        Self::Rdseed,
+        Self::InvariantTsc,
+        Self::DigitalThermalSensor,
+        Self::Hypervisor,
+    ];
+
+    /// Leaf, register and bit
+    fn location(self) -> (u32, Register, usize) {
+        match self {
+            Self::Apic => (1, Register::Edx, 9),
+            Self::TscDeadline => (1, Register::Ecx, 24),
+            Self::X2Apic => (1, Register::Ecx, 21),
+            Self::Rdrand => (1, Register::Ecx, 30),
+            Self::Rdseed => (7, Register::Ebx, 18),
+            Self::InvariantTsc => (0x8000_0007, Register::Edx, 8),
+            Self::DigitalThermalSensor => (6, Register::Eax, 0),
+            Self::Hypervisor => (1, Register::Ecx, 31),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Apic => "APIC",
+            Self::TscDeadline => "TSC-deadline",
+            Self::X2Apic => "x2APIC",
+            Self::Rdrand => "RDRAND",
+            Self::Rdseed => "RDSEED",
+            Self::InvariantTsc => "invariant TSC",
+            Self::DigitalThermalSensor => "digital thermal sensor",
+            Self::Hypervisor => "hypervisor",
+        }
+    }
+}
+
+impl Cpu {
+    pub fn read() -> Self {
+        // log::trace!("Cpu::read");
+
+        let leaf0 = __cpuid(0);
+        let mut vendor = [0; 12];
+        // EBX, EDX, ECX: "GenuineIntel", "AuthenticAMD"
+        for (i, register) in [leaf0.ebx, leaf0.edx, leaf0.ecx].into_iter().enumerate() {
+            vendor[4 * i..4 * i + 4].copy_from_slice(&register.to_le_bytes());
+        }
+
+        let mut brand = [0; 48];
+        if max_leaf(EXTENDED) >= BRAND_LEAVES[2] {
+            for (i, leaf) in BRAND_LEAVES.into_iter().enumerate() {
+                let result = __cpuid(leaf);
+                for (j, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                    .into_iter()
+                    .enumerate()
+                {
+                    let start = 16 * i + 4 * j;
+                    brand[start..start + 4].copy_from_slice(&register.to_le_bytes());
+                }
+            }
+        }
+
+        let signature = __cpuid(1).eax;
+        let mut family = signature.get_bits(8..12);
+        let mut model = signature.get_bits(4..8);
+        if family == 0x6 || family == 0xF {
+            model += signature.get_bits(16..20) << 4;
+        }
+        if family == 0xF {
+            family += signature.get_bits(20..28);
+        }
+        Self {
+            vendor,
+            brand,
+            family,
+            model,
+            stepping: signature.get_bits(0..4),
+        }
+    }
+
+    pub fn vendor(&self) -> &str {
+        str::from_utf8(&self.vendor).unwrap_or("?")
+    }
+
+    /// Empty on CPUs without a brand string
+    pub fn brand(&self) -> &str {
+        let brand = str::from_utf8(&self.brand).unwrap_or("?");
+        brand.trim_end_matches('\0').trim()
+    }
+}
+
+/// Highest leaf of the range starting at `base`, 0 or [`EXTENDED`]
+fn max_leaf(base: u32) -> u32 {
+    __cpuid(base).eax
+}
+
+pub fn has_feature(feature: Feature) -> bool {
+    let (leaf, register, bit) = feature.location();
+    let base = if leaf >= EXTENDED { EXTENDED } else { 0 };
+    if leaf > max_leaf(base) {
+        return false;
+    }
+    let result = __cpuid_count(leaf, 0);
+    let value = match register {
+        Register::Eax => result.eax,
+        Register::Ebx => result.ebx,
+        Register::Ecx => result.ecx,
+        Register::Edx => result.edx,
+    };
+    value.get_bit(bit)
+}
+
+/// Log the identification and which features are there
+pub fn log_summary() {
+    let cpu = Cpu::read();
+    log::info!(
+        "CPU: {} \"{}\" family {:#x} model {:#x} stepping {}",
+        cpu.vendor(),
+        cpu.brand(),
+        cpu.family,
+        cpu.model,
+        cpu.stepping
+    );
+    for feature in Feature::ALL {
+        let state = if has_feature(feature) { "yes" } else { "no" };
+        log::debug!("CPU: {} {}", feature.name(), state);
+    }
+}
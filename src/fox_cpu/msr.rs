@@ -0,0 +1,163 @@
+//! Model-specific registers: RDMSR/WRMSR and a decoded view of the ones we know
+//!
+//! An MSR the CPU does not implement raises #GP, which panics. [`is_present`] tells for the
+//! known ones, anything else is the caller's risk.
+//!
+//! https://www.intel.com/content/www/us/en/developer/articles/technical/intel-sdm.html (Vol. 4)
+
+use core::fmt;
+
+use bit_field::BitField;
+use x86_64::registers::model_specific::Msr;
+
+use super::{Cpu, Feature, has_feature};
+
+pub const IA32_APIC_BASE: u32 = 0x1B;
+/// Intel only, ratios of the bus clock
+pub const MSR_PLATFORM_INFO: u32 = 0xCE;
+/// Core temperature as degrees below TjMax
+pub const IA32_THERM_STATUS: u32 = 0x19C;
+/// Intel only, TjMax
+pub const MSR_TEMPERATURE_TARGET: u32 = 0x1A2;
+pub const IA32_PAT: u32 = 0x277;
+pub const IA32_EFER: u32 = 0xC000_0080;
+
+/// Bus clock the [`MSR_PLATFORM_INFO`] ratios are multiples of, MHz
+const BUS_CLOCK_MHZ: u64 = 100;
+
+/// An MSR value, [`fmt::Display`] decodes the known registers
+#[derive(Copy, Clone, Debug)]
+pub struct Value {
+    pub address: u32,
+    pub value: u64,
+}
+
+/// Read an MSR
+///
+/// # Safety
+///
+/// The MSR must exist, see [`is_present`].
+pub unsafe fn read(address: u32) -> u64 {
+    // SAFETY: by the caller
+    unsafe { Msr::new(address).read() }
+}
+
+/// Write an MSR
+///
+/// # Safety
+///
+/// The MSR must exist and accept `value`, which may change how the CPU runs.
+pub unsafe fn write(address: u32, value: u64) {
+    // SAFETY: by the caller
+    unsafe { Msr::new(address).write(value) }
+}
+
+/// Read a known MSR, `None` if it is unknown or the CPU lacks it
+pub fn read_known(address: u32) -> Option<Value> {
+    if !is_present(address) {
+        return None;
+    }
+    // SAFETY: checked above
+    let value = unsafe { read(address) };
+    Some(Value { address, value })
+}
+
+/// Writing it could change what memory means to the running code: long mode and NX, the memory
+/// types of PAT and the MTRRs, where the APIC registers are, the FS and GS bases
+pub fn is_critical(address: u32) -> bool {
+    matches!(
+        address,
+        IA32_APIC_BASE | IA32_PAT | IA32_EFER | 0x0200..=0x02FF | 0xC000_0100..=0xC000_0102
+    )
+}
+
+/// The MSR is one of ours and this CPU has it
+pub fn is_present(address: u32) -> bool {
+    match address {
+        IA32_APIC_BASE => has_feature(Feature::Apic),
+        MSR_PLATFORM_INFO => Cpu::read().vendor() == "GenuineIntel",
+        IA32_THERM_STATUS => has_feature(Feature::DigitalThermalSensor),
+        MSR_TEMPERATURE_TARGET => {
+            Cpu::read().vendor() == "GenuineIntel" && has_feature(Feature::DigitalThermalSensor)
+        }
+        // every x86_64 CPU has them
+        IA32_PAT | IA32_EFER => true,
+        _ => false,
+    }
+}
+
+pub fn name(address: u32) -> Option<&'static str> {
+    match address {
+        IA32_APIC_BASE => Some("IA32_APIC_BASE"),
+        MSR_PLATFORM_INFO => Some("MSR_PLATFORM_INFO"),
+        IA32_THERM_STATUS => Some("IA32_THERM_STATUS"),
+        MSR_TEMPERATURE_TARGET => Some("MSR_TEMPERATURE_TARGET"),
+        IA32_PAT => Some("IA32_PAT"),
+        IA32_EFER => Some("IA32_EFER"),
+        _ => None,
+    }
+}
+
+/// Memory type of a PAT entry
+fn pat_type(entry: u64) -> &'static str {
+    match entry {
+        0 => "UC",
+        1 => "WC",
+        4 => "WT",
+        5 => "WP",
+        6 => "WB",
+        7 => "UC-",
+        _ => "reserved",
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.value;
+        write!(f, "{:#x} = {:#018x}", self.address, value)?;
+        let Some(name) = name(self.address) else {
+            return Ok(());
+        };
+        write!(f, " {}:", name)?;
+        let flag = |bit: usize| if value.get_bit(bit) { "on" } else { "off" };
+        match self.address {
+            IA32_APIC_BASE => write!(
+                f,
+                " base {:#x}, BSP {}, x2APIC {}, enabled {}",
+                value.get_bits(12..52) << 12,
+                flag(8),
+                flag(10),
+                flag(11)
+            ),
+            MSR_PLATFORM_INFO => write!(
+                f,
+                " max non-turbo {} MHz, max efficiency {} MHz",
+                value.get_bits(8..16) * BUS_CLOCK_MHZ,
+                value.get_bits(40..48) * BUS_CLOCK_MHZ
+            ),
+            IA32_THERM_STATUS => write!(
+                f,
+                " {} C below TjMax, valid {}, throttling {}",
+                value.get_bits(16..23),
+                flag(31),
+                flag(0)
+            ),
+            MSR_TEMPERATURE_TARGET => write!(f, " TjMax {} C", value.get_bits(16..24)),
+            IA32_PAT => {
+                for i in 0..8 {
+                    write!(f, " PA{}={}", i, pat_type(value.get_bits(8 * i..8 * i + 3)))?;
+                }
+                Ok(())
+            }
+            IA32_EFER => write!(
+                f,
+                " SCE {}, LME {}, LMA {}, NXE {}",
+                flag(0),
+                flag(8),
+                flag(10),
+                flag(11)
+            ),
+            _ => Ok(()),
+        }
+    }
+}
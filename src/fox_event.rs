@@ -0,0 +1,236 @@
+//! Cooperative async executor and event loop
+//!
+//! A task is polled when its waker was called. [`sleep`] and, on x86, [`wait_irq`] leave their
+//! wakers here, each round wakes the ones whose deadline passed or whose IRQ fired, then polls
+//! the woken tasks and dispatches queued [`Event`]s to the handlers. With nothing to do it halts
+//! until the next interrupt, the timer tick or a device IRQ. Only usable after exiting boot
+//! services.
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::hint::spin_loop;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+
+use uefi::Status;
+
+use crate::fox_arch;
+use crate::fox_input::InputEvent;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_interrupts::irq_count;
+use crate::fox_net;
+use crate::fox_sync::Mutex;
+use crate::fox_time::{is_ticking, uptime};
+
+static QUEUE: Mutex<VecDeque<Event>> = Mutex::new(VecDeque::new());
+/// Wakers of pending [`Sleep`]s with their deadline
+static TIMERS: Mutex<Vec<(Duration, Waker)>> = Mutex::new(Vec::new());
+/// Wakers of pending [`WaitIrq`]s with the IRQ and its count when they were left
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static IRQ_WAITERS: Mutex<Vec<(u8, u32, Waker)>> = Mutex::new(Vec::new());
+
+#[derive(Copy, Clone, Debug)]
+pub enum Event {
+    /// Published by [`crate::fox_input::publish`]
+    Input(InputEvent),
+    /// Leave [`EventLoop::run`]
+    Quit(Status),
+}
+
+struct Task<'a> {
+    future: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    waker: Arc<TaskWaker>,
+}
+
+/// Marks the task for the next round, safe to call from IRQ handlers
+struct TaskWaker {
+    is_woken: AtomicBool,
+}
+
+type Handler<'a> = Box<dyn FnMut(&Event) + 'a>;
+
+#[derive(Default)]
+pub struct EventLoop<'a> {
+    tasks: Vec<Task<'a>>,
+    handlers: Vec<Handler<'a>>,
+}
+
+/// Queue an event for the handlers, also from IRQ handlers
+pub fn post(event: Event) {
+    QUEUE.lock().push_back(event);
+}
+
+fn next_event() -> Option<Event> {
+    QUEUE.lock().pop_front()
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.is_woken.store(true, Ordering::Release);
+    }
+}
+
+impl<'a> EventLoop<'a> {
+    /// Run `task` until it completes, it is first polled in the next round
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'a) {
+        self.tasks.push(Task {
+            future: Box::pin(task),
+            waker: Arc::new(TaskWaker {
+                is_woken: AtomicBool::new(true),
+            }),
+        });
+    }
+
+    /// Call `handler` for every event but [`Event::Quit`]
+    pub fn add_handler(&mut self, handler: impl FnMut(&Event) + 'a) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Run until an [`Event::Quit`]
+    pub fn run(&mut self) -> Status {
+        // log::trace!("EventLoop::run()");
+
+        loop {
+            self.tasks.retain_mut(|task| {
+                if !task.waker.is_woken.swap(false, Ordering::Acquire) {
+                    return true;
+                }
+                let waker = Waker::from(task.waker.clone());
+                let mut context = Context::from_waker(&waker);
+                task.future.as_mut().poll(&mut context).is_pending()
+            });
+
+            while let Some(event) = next_event() {
+                if let Event::Quit(status) = event {
+                    return status;
+                }
+                for handler in &mut self.handlers {
+                    handler(&event);
+                }
+            }
+            // the records of this round, not from inside a handler or an IRQ
+            fox_net::flush_log();
+
+            // an IRQ between the check and the halt would be missed, STI delays it past HLT
+            fox_arch::disable_interrupts();
+            wake_ready();
+            let is_idle = self.is_idle();
+            // without the timer nothing may wake us up
+            if is_idle && is_ticking() {
+                fox_arch::enable_interrupts_and_halt();
+            } else {
+                fox_arch::enable_interrupts();
+                if is_idle {
+                    spin_loop();
+                }
+            }
+        }
+    }
+
+    /// No task woken and no event queued, interrupts must be disabled
+    fn is_idle(&self) -> bool {
+        QUEUE.lock().is_empty()
+            && !self
+                .tasks
+                .iter()
+                .any(|task| task.waker.is_woken.load(Ordering::Acquire))
+    }
+}
+
+/// Wake the sleeps past their deadline and the waits whose IRQ fired
+fn wake_ready() {
+    let now = uptime();
+    TIMERS.lock().retain(|(deadline, waker)| {
+        let is_expired = *deadline <= now;
+        if is_expired {
+            waker.wake_by_ref();
+        }
+        !is_expired
+    });
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    IRQ_WAITERS.lock().retain(|(irq, count, waker)| {
+        let is_fired = irq_count(*irq) != *count;
+        if is_fired {
+            waker.wake_by_ref();
+        }
+        !is_fired
+    });
+}
+
+/// Complete after `duration`
+pub fn sleep(duration: Duration) -> Sleep {
+    Sleep {
+        deadline: uptime().saturating_add(duration),
+        is_registered: false,
+    }
+}
+
+/// Complete on the next interrupt of a legacy IRQ
+///
+/// The count is taken here, so an IRQ between the call and the first poll completes it.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn wait_irq(irq: u8) -> WaitIrq {
+    WaitIrq {
+        irq,
+        count: irq_count(irq),
+        is_registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: Duration,
+    /// Our task waker was left in [`TIMERS`], it stays the same for a task
+    is_registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if uptime() >= self.deadline {
+            return Poll::Ready(());
+        }
+        if !self.is_registered {
+            let entry = (self.deadline, context.waker().clone());
+            TIMERS.lock().push(entry);
+            self.is_registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct WaitIrq {
+    irq: u8,
+    count: u32,
+    /// Our task waker was left in [`IRQ_WAITERS`]
+    is_registered: bool,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Future for WaitIrq {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<()> {
+        if irq_count(self.irq) != self.count {
+            return Poll::Ready(());
+        }
+        if !self.is_registered {
+            let entry = (self.irq, self.count, context.waker().clone());
+            IRQ_WAITERS.lock().push(entry);
+            self.is_registered = true;
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,266 @@
+//! Linear framebuffer of the UEFI Graphics Output Protocol
+//!
+//! The mode is taken from GOP while boot services last, the framebuffer stays where it is
+//! afterwards. Modes with no framebuffer (BltOnly) are not supported.
+//!
+//! The firmware logo of the BGRT is copied while boot services last, so it can be drawn again.
+//!
+//! https://uefi.org/specs/UEFI/2.10/12_Protocols_Console_Support.html#graphics-output-protocol
+//! https://wiki.osdev.org/GOP
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use uefi::Status;
+use uefi::boot::{
+    OpenProtocolAttributes, OpenProtocolParams, get_handle_for_protocol, image_handle,
+    open_protocol,
+};
+use uefi::proto::console::gop::{GraphicsOutput, PixelBitmask, PixelFormat};
+
+use crate::fox_acpi;
+use crate::fox_uefi::is_boot_services_active;
+
+mod bmp;
+mod cursor;
+
+use bmp::Bmp;
+pub use cursor::Cursor;
+
+/// Init [`init`]
+static FRAMEBUFFER: AtomicPtr<Framebuffer> = AtomicPtr::new(null_mut());
+
+/// Storage for [`FRAMEBUFFER`], written once by [`init`]
+static mut FRAMEBUFFER_STORAGE: Framebuffer = Framebuffer {
+    base: null_mut(),
+    width: 0,
+    height: 0,
+    stride: 0,
+    format: Format::Rgb,
+};
+
+/// Init [`save_logo`]
+static LOGO: AtomicPtr<Logo> = AtomicPtr::new(null_mut());
+
+/// Storage for [`LOGO`], written once by [`save_logo`]
+static mut LOGO_STORAGE: Logo = Logo {
+    data: Vec::new(),
+    x: 0,
+    y: 0,
+};
+
+/// 32 bits per pixel, `stride` pixels per line
+pub struct Framebuffer {
+    base: *mut u32,
+    width: usize,
+    height: usize,
+    stride: usize,
+    format: Format,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Format {
+    /// Red in the lowest byte
+    Rgb,
+    /// Blue in the lowest byte
+    Bgr,
+    Bitmask(PixelBitmask),
+}
+
+/// BMP file of the firmware logo and where it was drawn
+struct Logo {
+    data: Vec<u8>,
+    x: usize,
+    y: usize,
+}
+
+#[derive(Debug)]
+pub enum LogoError {
+    Acpi(fox_acpi::Error),
+    /// Not an uncompressed 24- or 32-bit BMP
+    UnsupportedImage,
+    /// [`save_logo`] did not succeed
+    NoLogo,
+    NoFramebuffer,
+}
+
+/// The framebuffer, `None` before [`init`] or without GOP
+pub fn framebuffer() -> Option<&'static Framebuffer> {
+    let ptr = FRAMEBUFFER.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Take the current GOP mode, needs boot services
+pub fn init() -> uefi::Result {
+    // log::trace!("fox_fb::init");
+    assert!(is_boot_services_active());
+
+    let handle = get_handle_for_protocol::<GraphicsOutput>()?;
+    let params = OpenProtocolParams {
+        handle,
+        agent: image_handle(),
+        controller: None,
+    };
+    // SAFETY: the console driver keeps drawing through the same mode
+    let mut gop =
+        unsafe { open_protocol::<GraphicsOutput>(params, OpenProtocolAttributes::GetProtocol) }?;
+
+    let mode = gop.current_mode_info();
+    let format = match mode.pixel_format() {
+        PixelFormat::Rgb => Format::Rgb,
+        PixelFormat::Bgr => Format::Bgr,
+        PixelFormat::Bitmask => Format::Bitmask(mode.pixel_bitmask().ok_or(Status::UNSUPPORTED)?),
+        PixelFormat::BltOnly => return Err(Status::UNSUPPORTED.into()),
+    };
+    let (width, height) = mode.resolution();
+
+    let framebuffer = &raw mut FRAMEBUFFER_STORAGE;
+    // SAFETY: called once during boot, before anyone reads `FRAMEBUFFER`
+    let framebuffer = unsafe { &mut *framebuffer };
+    *framebuffer = Framebuffer {
+        base: gop.frame_buffer().as_mut_ptr().cast(),
+        width,
+        height,
+        stride: mode.stride(),
+        format,
+    };
+    log::info!("Framebuffer: {}", framebuffer);
+
+    FRAMEBUFFER.store(framebuffer, Ordering::Release);
+    Ok(())
+}
+
+/// Copy the logo of the BGRT, needs boot services
+pub fn save_logo() -> Result<(), LogoError> {
+    // log::trace!("fox_fb::save_logo");
+    assert!(is_boot_services_active());
+
+    let bgrt = fox_acpi::bgrt().map_err(LogoError::Acpi)?;
+    // SAFETY: boot services memory is not reused yet
+    let image = unsafe { bgrt.image() }.ok_or(LogoError::UnsupportedImage)?;
+    let bmp = Bmp::parse(image).ok_or(LogoError::UnsupportedImage)?;
+    if bgrt.orientation != 0 {
+        log::warn!(
+            "Logo: placed for a screen rotated by {} degrees",
+            bgrt.orientation
+        );
+    }
+    log::info!(
+        "Logo: {}x{} at {},{}{}",
+        bmp.width(),
+        bmp.height(),
+        bgrt.x,
+        bgrt.y,
+        if bgrt.is_displayed {
+            ""
+        } else {
+            ", not displayed"
+        }
+    );
+
+    let logo = &raw mut LOGO_STORAGE;
+    // SAFETY: called once during boot, before anyone reads `LOGO`
+    let logo = unsafe { &mut *logo };
+    *logo = Logo {
+        data: image.to_vec(),
+        x: bgrt.x as usize,
+        y: bgrt.y as usize,
+    };
+    LOGO.store(logo, Ordering::Release);
+    Ok(())
+}
+
+/// Draw the logo saved by [`save_logo`] where the firmware had it
+pub fn draw_logo() -> Result<(), LogoError> {
+    let logo = LOGO.load(Ordering::Acquire);
+    let logo = unsafe { logo.as_ref() }.ok_or(LogoError::NoLogo)?;
+    let framebuffer = framebuffer().ok_or(LogoError::NoFramebuffer)?;
+    let bmp = Bmp::parse(&logo.data).ok_or(LogoError::UnsupportedImage)?;
+    framebuffer.draw_bmp(&bmp, logo.x, logo.y);
+    Ok(())
+}
+
+impl Framebuffer {
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Pixel value of a color in this format
+    pub fn color(&self, red: u8, green: u8, blue: u8) -> u32 {
+        match self.format {
+            Format::Rgb => u32::from_le_bytes([red, green, blue, 0]),
+            Format::Bgr => u32::from_le_bytes([blue, green, red, 0]),
+            Format::Bitmask(mask) => {
+                channel(red, mask.red) | channel(green, mask.green) | channel(blue, mask.blue)
+            }
+        }
+    }
+
+    /// 0 outside the screen
+    pub fn read(&self, x: usize, y: usize) -> u32 {
+        match self.offset(x, y) {
+            // SAFETY: inside the framebuffer
+            Some(offset) => unsafe { self.base.add(offset).read_volatile() },
+            None => 0,
+        }
+    }
+
+    /// Ignored outside the screen
+    pub fn write(&self, x: usize, y: usize, value: u32) {
+        if let Some(offset) = self.offset(x, y) {
+            // SAFETY: inside the framebuffer
+            unsafe { self.base.add(offset).write_volatile(value) };
+        }
+    }
+
+    /// Clipped at the edges of the screen
+    fn draw_bmp(&self, bmp: &Bmp, x: usize, y: usize) {
+        for row in 0..bmp.height() {
+            for column in 0..bmp.width() {
+                let (red, green, blue) = bmp.pixel(column, row);
+                self.write(x + column, y + row, self.color(red, green, blue));
+            }
+        }
+    }
+
+    fn offset(&self, x: usize, y: usize) -> Option<usize> {
+        (x < self.width && y < self.height).then_some(y * self.stride + x)
+    }
+}
+
+/// An 8-bit channel scaled into the bits of `mask`
+fn channel(value: u8, mask: u32) -> u32 {
+    if mask == 0 {
+        return 0;
+    }
+    let shift = mask.trailing_zeros();
+    let bits = (mask >> shift).count_ones().min(8);
+    ((value as u32) >> (8 - bits)) << shift
+}
+
+impl fmt::Display for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} at {:#x}, stride {}, {:?}",
+            self.width, self.height, self.base as u64, self.stride, self.format
+        )
+    }
+}
+
+impl fmt::Display for LogoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Acpi(err) => write!(f, "BGRT: {}", err),
+            Self::UnsupportedImage => write!(f, "unsupported image"),
+            Self::NoLogo => write!(f, "no logo saved"),
+            Self::NoFramebuffer => write!(f, "no framebuffer"),
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Uncompressed 24- and 32-bit BMP files, as firmware logos are
+//!
+//! https://en.wikipedia.org/wiki/BMP_file_format
+
+/// A BMP file whose header has been checked
+pub struct Bmp<'a> {
+    data: &'a [u8],
+    /// Offset of the pixel array
+    pixels: usize,
+    width: usize,
+    height: usize,
+    /// Rows are stored bottom row first
+    is_bottom_up: bool,
+    bytes_per_pixel: usize,
+    /// Bytes per row, padded to 4
+    row_size: usize,
+}
+
+impl<'a> Bmp<'a> {
+    /// `None` if the file is not a BMP we can draw
+    pub fn parse(data: &'a [u8]) -> Option<Self> {
+        let u16_at = |offset: usize| {
+            Some(u16::from_le_bytes(
+                data.get(offset..offset + 2)?.try_into().ok()?,
+            ))
+        };
+        let u32_at = |offset: usize| {
+            Some(u32::from_le_bytes(
+                data.get(offset..offset + 4)?.try_into().ok()?,
+            ))
+        };
+
+        // BITMAPFILEHEADER, then a BITMAPINFOHEADER or a later version of it
+        if data.get(..2)? != b"BM" || u32_at(14)? < 40 {
+            return None;
+        }
+        let pixels = u32_at(10)? as usize;
+        let width = u32_at(18)? as i32;
+        let height = u32_at(22)? as i32;
+        let bits_per_pixel = u16_at(28)?;
+        // BI_RGB
+        if u32_at(30)? != 0 || !matches!(bits_per_pixel, 24 | 32) || width <= 0 || height == 0 {
+            return None;
+        }
+
+        let width = width as usize;
+        let bytes_per_pixel = bits_per_pixel as usize / 8;
+        let row_size = (width * bytes_per_pixel).div_ceil(4) * 4;
+        let bmp = Self {
+            data,
+            pixels,
+            width,
+            height: height.unsigned_abs() as usize,
+            is_bottom_up: height > 0,
+            bytes_per_pixel,
+            row_size,
+        };
+        let end = bmp
+            .pixels
+            .checked_add(bmp.row_size.checked_mul(bmp.height)?)?;
+        (end <= data.len()).then_some(bmp)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Red, green and blue of a pixel, `(0, 0)` is top left
+    pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+        let row = if self.is_bottom_up {
+            self.height - 1 - y
+        } else {
+            y
+        };
+        let offset = self.pixels + row * self.row_size + x * self.bytes_per_pixel;
+        // stored blue first
+        (
+            self.data[offset + 2],
+            self.data[offset + 1],
+            self.data[offset],
+        )
+    }
+}
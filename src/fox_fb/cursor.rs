@@ -0,0 +1,158 @@
+//! Mouse pointer drawn on the framebuffer
+//!
+//! The pixels under the sprite are saved before it is drawn and put back before it moves, so
+//! whatever else is on the screen survives.
+
+use super::Framebuffer;
+use crate::fox_input::{InputEvent, MouseButton};
+
+const WIDTH: usize = 12;
+const HEIGHT: usize = 19;
+
+/// Arrow with the hot spot top left: `X` outline, `.` fill, the rest transparent
+#[rustfmt::skip]
+const SPRITE: [&[u8; WIDTH]; HEIGHT] = [
+    b"X           ",
+    b"XX          ",
+    b"X.X         ",
+    b"X..X        ",
+    b"X...X       ",
+    b"X....X      ",
+    b"X.....X     ",
+    b"X......X    ",
+    b"X.......X   ",
+    b"X........X  ",
+    b"X.........X ",
+    b"X..........X",
+    b"X......XXXXX",
+    b"X...X..X    ",
+    b"X..XX..X    ",
+    b"X.X  X..X   ",
+    b"XX   X..X   ",
+    b"X     X..X  ",
+    b"      XXXX  ",
+];
+
+/// Position of the hot spot and the buttons held
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pointer {
+    pub x: usize,
+    pub y: usize,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+pub struct Cursor {
+    framebuffer: &'static Framebuffer,
+    pointer: Pointer,
+    /// Percent of a pixel per mouse count
+    sensitivity: i32,
+    /// Movement below a pixel, in percent of a pixel
+    remainder: (i32, i32),
+    /// The pixels under the sprite while it is drawn
+    save_under: Option<[u32; WIDTH * HEIGHT]>,
+}
+
+impl Cursor {
+    /// Hidden in the middle of the screen
+    pub fn new(framebuffer: &'static Framebuffer) -> Self {
+        Self {
+            framebuffer,
+            pointer: Pointer {
+                x: framebuffer.width() / 2,
+                y: framebuffer.height() / 2,
+                ..Pointer::default()
+            },
+            sensitivity: 100,
+            remainder: (0, 0),
+            save_under: None,
+        }
+    }
+
+    /// 100 moves one pixel per mouse count
+    pub fn set_sensitivity(&mut self, percent: u16) {
+        self.sensitivity = percent.into();
+    }
+
+    pub fn show(&mut self) {
+        if self.save_under.is_some() {
+            return;
+        }
+        let fb = self.framebuffer;
+        let black = fb.color(0, 0, 0);
+        let white = fb.color(0xFF, 0xFF, 0xFF);
+        let mut save_under = [0; WIDTH * HEIGHT];
+        for (row, line) in SPRITE.iter().enumerate() {
+            for (column, &pixel) in line.iter().enumerate() {
+                let (x, y) = (self.pointer.x + column, self.pointer.y + row);
+                save_under[row * WIDTH + column] = fb.read(x, y);
+                match pixel {
+                    b'X' => fb.write(x, y, black),
+                    b'.' => fb.write(x, y, white),
+                    _ => {}
+                }
+            }
+        }
+        self.save_under = Some(save_under);
+    }
+
+    pub fn hide(&mut self) {
+        let Some(save_under) = self.save_under.take() else {
+            return;
+        };
+        for (row, line) in SPRITE.iter().enumerate() {
+            for (column, &pixel) in line.iter().enumerate() {
+                if pixel != b' ' {
+                    let (x, y) = (self.pointer.x + column, self.pointer.y + row);
+                    self.framebuffer
+                        .write(x, y, save_under[row * WIDTH + column]);
+                }
+            }
+        }
+    }
+
+    /// Follow a mouse move or button, clamped to the screen
+    pub fn apply(&mut self, event: &InputEvent) -> Pointer {
+        match *event {
+            InputEvent::MouseMove { dx, dy } => self.move_by(dx, dy),
+            InputEvent::MouseButton { button, is_pressed } => match button {
+                MouseButton::Left => self.pointer.left = is_pressed,
+                MouseButton::Right => self.pointer.right = is_pressed,
+                MouseButton::Middle => self.pointer.middle = is_pressed,
+            },
+            InputEvent::Key(_) | InputEvent::Wheel(_) => {}
+        }
+        self.pointer
+    }
+
+    fn move_by(&mut self, dx: i16, dy: i16) {
+        // screen y grows downwards
+        let dx = self.remainder.0 + dx as i32 * self.sensitivity;
+        let dy = self.remainder.1 - dy as i32 * self.sensitivity;
+        self.remainder = (dx % 100, dy % 100);
+
+        let x = clamp(self.pointer.x, dx / 100, self.framebuffer.width());
+        let y = clamp(self.pointer.y, dy / 100, self.framebuffer.height());
+        if (x, y) != (self.pointer.x, self.pointer.y) {
+            let is_shown = self.save_under.is_some();
+            self.hide();
+            self.pointer.x = x;
+            self.pointer.y = y;
+            if is_shown {
+                self.show();
+            }
+        }
+    }
+}
+
+impl Drop for Cursor {
+    fn drop(&mut self) {
+        self.hide();
+    }
+}
+
+fn clamp(position: usize, delta: i32, size: usize) -> usize {
+    let max = size.saturating_sub(1) as i64;
+    (position as i64 + delta as i64).clamp(0, max) as usize
+}
@@ -0,0 +1,100 @@
+//! File systems on a [`BlockDevice`](crate::fox_block::BlockDevice)
+//!
+//! Independent of the UEFI SimpleFileSystem protocol, so volumes stay readable after exiting
+//! boot services.
+
+use core::fmt;
+
+use crate::drivers::DriverError;
+use crate::fox_block::{self, BlockDevice, Partition};
+
+mod fat;
+
+pub use fat::FatFs;
+
+/// Boot log saved by an earlier boot, see [`fox_bootlog`](crate::fox_bootlog)
+const BOOT_LOG_PATH: &str = "/EFI/my-uefi-app/boot.log";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    Device(DriverError),
+    /// The volume does not hold this file system
+    NotFat,
+    /// Broken cluster chain or directory
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl From<DriverError> for Error {
+    fn from(err: DriverError) -> Self {
+        Self::Device(err)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Device(err) => write!(f, "{}", err),
+            Self::NotFat => write!(f, "not a FAT volume"),
+            Self::Corrupt => write!(f, "corrupt file system"),
+            Self::NotFound => write!(f, "not found"),
+            Self::NotADirectory => write!(f, "not a directory"),
+            Self::IsADirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+/// Log the root directory of every FAT partition of a disk
+pub fn log_volumes(disk: &mut (impl BlockDevice + fmt::Display)) {
+    let Ok(partitions) = fox_block::partitions(disk) else {
+        return;
+    };
+    for info in partitions.iter() {
+        let mut fs = match FatFs::mount(Partition::new(&mut *disk, *info)) {
+            Ok(fs) => fs,
+            Err(Error::NotFat) => continue,
+            Err(err) => {
+                log::warn!("{} partition {}: {}", disk, info.number, err);
+                continue;
+            }
+        };
+        log::info!("{}: {:?} \"{}\"", fs.disk(), fs.fat_type(), fs.label());
+
+        let root = fs.root();
+        for entry in fs.read_dir(root) {
+            match entry {
+                Ok(entry) if entry.is_dir() => log::info!("  {}/", entry.name()),
+                Ok(entry) => log::info!("  {} {} bytes", entry.name(), entry.size()),
+                Err(err) => {
+                    log::warn!("  {}", err);
+                    break;
+                }
+            }
+        }
+
+        // read through the file to exercise the cluster chain
+        match fs.open(BOOT_LOG_PATH) {
+            Ok(mut file) => {
+                let mut buffer = [0u8; 512];
+                let mut lines = 0;
+                loop {
+                    match fs.read(&mut file, &mut buffer) {
+                        Ok(0) => break,
+                        Ok(length) => {
+                            lines += buffer[..length].iter().filter(|&&i| i == b'\n').count()
+                        }
+                        Err(err) => {
+                            log::warn!("  {}: {}", BOOT_LOG_PATH, err);
+                            break;
+                        }
+                    }
+                }
+                log::info!("  {}: {} lines from an earlier boot", BOOT_LOG_PATH, lines);
+            }
+            Err(Error::NotFound) => {}
+            Err(err) => log::warn!("  {}: {}", BOOT_LOG_PATH, err),
+        }
+    }
+}
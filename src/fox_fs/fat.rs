@@ -0,0 +1,513 @@
+//! FAT12/16/32, read-only
+//!
+//! https://wiki.osdev.org/FAT
+
+use core::str;
+
+use super::Error;
+use crate::fox_block::{BlockBuffer, BlockDevice, MAX_BLOCK_SIZE};
+
+/// Sectors fit a [`BlockBuffer`]
+const MAX_SECTOR_SIZE: usize = MAX_BLOCK_SIZE;
+const LENGTH_ENTRY: usize = 32;
+/// UTF-16 code units of a long name
+const MAX_LONG_NAME: usize = 255;
+/// Long name as UTF-8
+const MAX_NAME: usize = MAX_LONG_NAME * 3;
+
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+/// Mounted FAT volume
+pub struct FatFs<D: BlockDevice> {
+    disk: D,
+    fat_type: FatType,
+    bytes_per_sector: usize,
+    sectors_per_cluster: u32,
+    /// First sector of the first FAT
+    fat_start: u64,
+    /// First sector of the FAT12/16 root directory
+    root_start: u64,
+    root_sectors: u32,
+    /// First sector of cluster 2
+    data_start: u64,
+    cluster_count: u32,
+    /// First cluster of the FAT32 root directory
+    root_cluster: u32,
+    label: [u8; 11],
+    cache: BlockBuffer,
+    cached_sector: Option<u64>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// Directory to list with [`FatFs::read_dir`]
+#[derive(Copy, Clone, Debug)]
+pub struct Dir {
+    /// 0 is the FAT12/16 root directory region
+    cluster: u32,
+}
+
+/// Entry of a directory
+#[derive(Clone)]
+pub struct DirEntry {
+    name: [u8; MAX_NAME],
+    name_length: usize,
+    attributes: u8,
+    size: u32,
+    cluster: u32,
+}
+
+/// File opened with [`FatFs::open`], read with [`FatFs::read`]
+#[derive(Copy, Clone, Debug)]
+pub struct File {
+    size: u32,
+    position: u32,
+    /// Cluster holding `position`, and its index in the chain
+    cluster: u32,
+    cluster_index: u32,
+}
+
+/// Iterator over the entries of a directory, see [`FatFs::read_dir`]
+pub struct ReadDir<'a, D: BlockDevice> {
+    fs: &'a mut FatFs<D>,
+    cluster: u32,
+    /// Sector in the cluster or the root directory region
+    sector: u32,
+    entry: usize,
+    is_done: bool,
+    long_name: [u16; MAX_LONG_NAME + 5],
+    /// Checksum of the short name the long name belongs to
+    long_name_checksum: Option<u8>,
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Check the BIOS Parameter Block of the volume
+    pub fn mount(mut disk: D) -> Result<Self, Error> {
+        // log::trace!("FatFs::mount()");
+
+        let block_size = disk.block_size();
+        if !(512..=MAX_SECTOR_SIZE).contains(&block_size) {
+            return Err(Error::NotFat);
+        }
+        let mut boot = BlockBuffer::new();
+        disk.read_blocks(0, &mut boot[..block_size])?;
+
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(boot[offset..offset + 4].try_into().unwrap());
+
+        let bytes_per_sector = u16_at(11) as usize;
+        let sectors_per_cluster = boot[13] as u32;
+        let reserved_sectors = u16_at(14) as u64;
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(17) as u64;
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32) as u64,
+            count => count as u64,
+        };
+        let fat_size = match u16_at(22) {
+            0 => u32_at(36) as u64,
+            size => size as u64,
+        };
+
+        let is_valid = matches!(boot[0], 0xEB | 0xE9)
+            && boot[510..512] == [0x55, 0xAA]
+            && bytes_per_sector.is_power_of_two()
+            && (block_size..=MAX_SECTOR_SIZE).contains(&bytes_per_sector)
+            && sectors_per_cluster.is_power_of_two()
+            && reserved_sectors != 0
+            && fat_count != 0
+            && fat_size != 0;
+        if !is_valid {
+            return Err(Error::NotFat);
+        }
+
+        let root_sectors = (root_entries * LENGTH_ENTRY as u64).div_ceil(bytes_per_sector as u64);
+        let root_start = reserved_sectors + fat_count * fat_size;
+        let data_start = root_start + root_sectors;
+        let cluster_count = total_sectors.checked_sub(data_start).ok_or(Error::NotFat)?
+            / sectors_per_cluster as u64;
+
+        // the type is decided by the cluster count alone
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        let (root_cluster, label_offset) = match fat_type {
+            FatType::Fat32 => (u32_at(44), 71),
+            _ => (0, 43),
+        };
+        let mut label = [0; 11];
+        label.copy_from_slice(&boot[label_offset..label_offset + 11]);
+
+        Ok(Self {
+            disk,
+            fat_type,
+            bytes_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            root_start,
+            root_sectors: root_sectors as u32,
+            data_start,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            label,
+            cache: BlockBuffer::new(),
+            cached_sector: None,
+        })
+    }
+
+    pub fn disk(&self) -> &D {
+        &self.disk
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    /// Volume label of the boot sector
+    pub fn label(&self) -> &str {
+        str::from_utf8(&self.label).map_or("?", |label| label.trim_end())
+    }
+
+    pub fn root(&self) -> Dir {
+        Dir {
+            cluster: self.root_cluster,
+        }
+    }
+
+    pub fn read_dir(&mut self, dir: Dir) -> ReadDir<'_, D> {
+        ReadDir {
+            fs: self,
+            cluster: dir.cluster,
+            sector: 0,
+            entry: 0,
+            is_done: false,
+            long_name: [0; MAX_LONG_NAME + 5],
+            long_name_checksum: None,
+        }
+    }
+
+    /// Look up an absolute path, `/` or `\` separated, ignoring ASCII case
+    pub fn find(&mut self, path: &str) -> Result<DirEntry, Error> {
+        // log::trace!("FatFs::find()");
+
+        let mut components = path.split(['/', '\\']).filter(|i| !i.is_empty()).peekable();
+        let mut dir = self.root();
+        while let Some(component) = components.next() {
+            let entry = self
+                .read_dir(dir)
+                .find(|i| {
+                    i.as_ref()
+                        .map_or(true, |i| i.name().eq_ignore_ascii_case(component))
+                })
+                .ok_or(Error::NotFound)??;
+            if components.peek().is_none() {
+                return Ok(entry);
+            }
+            dir = entry.to_dir(self).ok_or(Error::NotADirectory)?;
+        }
+        // the root itself has no entry
+        Err(Error::IsADirectory)
+    }
+
+    pub fn open(&mut self, path: &str) -> Result<File, Error> {
+        let entry = self.find(path)?;
+        if entry.is_dir() {
+            return Err(Error::IsADirectory);
+        }
+        Ok(File {
+            size: entry.size,
+            position: 0,
+            cluster: entry.cluster,
+            cluster_index: 0,
+        })
+    }
+
+    /// Read sequentially from the current position, returns 0 at the end of the file
+    pub fn read(&mut self, file: &mut File, buffer: &mut [u8]) -> Result<usize, Error> {
+        let cluster_size = self.bytes_per_sector as u32 * self.sectors_per_cluster;
+
+        let mut done = 0;
+        while done < buffer.len() && file.position < file.size {
+            // follow the chain to the cluster of the position
+            let index = file.position / cluster_size;
+            while file.cluster_index < index {
+                file.cluster = self.next_cluster(file.cluster)?.ok_or(Error::Corrupt)?;
+                file.cluster_index += 1;
+            }
+
+            let offset = (file.position % cluster_size) as usize;
+            let sector =
+                self.cluster_sector(file.cluster)? + (offset / self.bytes_per_sector) as u64;
+            self.read_sector(sector)?;
+
+            let start = offset % self.bytes_per_sector;
+            let length = (self.bytes_per_sector - start)
+                .min(buffer.len() - done)
+                .min((file.size - file.position) as usize);
+            buffer[done..done + length].copy_from_slice(&self.cache[start..start + length]);
+            done += length;
+            file.position += length as u32;
+        }
+        Ok(done)
+    }
+
+    /// Next cluster of a chain, `None` at its end
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error> {
+        let (value, end) = match self.fat_type {
+            FatType::Fat12 => {
+                // 12-bit entries, packed in pairs
+                let offset = cluster as u64 + cluster as u64 / 2;
+                let low = self.read_fat(offset, 1)?;
+                let high = self.read_fat(offset + 1, 1)?;
+                let pair = low | high << 8;
+                let value = if cluster % 2 == 1 {
+                    pair >> 4
+                } else {
+                    pair & 0xFFF
+                };
+                (value, 0xFF8)
+            }
+            FatType::Fat16 => (self.read_fat(cluster as u64 * 2, 2)?, 0xFFF8),
+            FatType::Fat32 => (
+                self.read_fat(cluster as u64 * 4, 4)? & 0x0FFF_FFFF,
+                0x0FFF_FFF8,
+            ),
+        };
+
+        if value >= end {
+            Ok(None)
+        } else if value < 2 || value - 2 >= self.cluster_count {
+            Err(Error::Corrupt)
+        } else {
+            Ok(Some(value))
+        }
+    }
+
+    /// Little-endian value of `size` bytes at `offset` in the first FAT
+    fn read_fat(&mut self, offset: u64, size: usize) -> Result<u32, Error> {
+        let sector = self.fat_start + offset / self.bytes_per_sector as u64;
+        self.read_sector(sector)?;
+        let start = (offset % self.bytes_per_sector as u64) as usize;
+        let mut bytes = [0; 4];
+        bytes[..size].copy_from_slice(&self.cache[start..start + size]);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> Result<u64, Error> {
+        if cluster < 2 || cluster - 2 >= self.cluster_count {
+            return Err(Error::Corrupt);
+        }
+        Ok(self.data_start + (cluster - 2) as u64 * self.sectors_per_cluster as u64)
+    }
+
+    /// Load a sector into the cache
+    fn read_sector(&mut self, sector: u64) -> Result<(), Error> {
+        if self.cached_sector == Some(sector) {
+            return Ok(());
+        }
+        self.cached_sector = None;
+        let blocks_per_sector = (self.bytes_per_sector / self.disk.block_size()) as u64;
+        self.disk.read_blocks(
+            sector * blocks_per_sector,
+            &mut self.cache[..self.bytes_per_sector],
+        )?;
+        self.cached_sector = Some(sector);
+        Ok(())
+    }
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    /// Next raw 32-byte entry, `None` at the end of the directory
+    fn next_raw(&mut self) -> Result<Option<[u8; LENGTH_ENTRY]>, Error> {
+        if self.is_done {
+            return Ok(None);
+        }
+        let sector = if self.cluster == 0 {
+            if self.sector >= self.fs.root_sectors {
+                self.is_done = true;
+                return Ok(None);
+            }
+            self.fs.root_start + self.sector as u64
+        } else {
+            self.fs.cluster_sector(self.cluster)? + self.sector as u64
+        };
+
+        self.fs.read_sector(sector)?;
+        let offset = self.entry * LENGTH_ENTRY;
+        let mut raw = [0; LENGTH_ENTRY];
+        raw.copy_from_slice(&self.fs.cache[offset..offset + LENGTH_ENTRY]);
+
+        self.entry += 1;
+        if self.entry == self.fs.bytes_per_sector / LENGTH_ENTRY {
+            self.entry = 0;
+            self.sector += 1;
+            if self.cluster != 0 && self.sector == self.fs.sectors_per_cluster {
+                self.sector = 0;
+                match self.fs.next_cluster(self.cluster)? {
+                    Some(cluster) => self.cluster = cluster,
+                    None => self.is_done = true,
+                }
+            }
+        }
+        Ok(Some(raw))
+    }
+
+    /// Collect a long name entry, they come last part first
+    fn push_long_name(&mut self, raw: &[u8; LENGTH_ENTRY]) {
+        let order = raw[0];
+        let index = (order & 0x3F) as usize;
+        if index == 0 || index * 13 > self.long_name.len() {
+            self.long_name_checksum = None;
+            return;
+        }
+        // the last part starts a new name
+        if order & 0x40 != 0 {
+            self.long_name = [0; MAX_LONG_NAME + 5];
+            self.long_name_checksum = Some(raw[13]);
+        } else if self.long_name_checksum != Some(raw[13]) {
+            self.long_name_checksum = None;
+            return;
+        }
+
+        let units = raw[1..11]
+            .chunks(2)
+            .chain(raw[14..26].chunks(2))
+            .chain(raw[28..32].chunks(2))
+            .map(|i| u16::from_le_bytes([i[0], i[1]]));
+        for (i, unit) in units.enumerate() {
+            self.long_name[(index - 1) * 13 + i] = unit;
+        }
+    }
+
+    fn entry(&mut self, raw: &[u8; LENGTH_ENTRY]) -> DirEntry {
+        let mut entry = DirEntry {
+            name: [0; MAX_NAME],
+            name_length: 0,
+            attributes: raw[11],
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            cluster: (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                | u16::from_le_bytes([raw[26], raw[27]]) as u32,
+        };
+
+        let checksum = raw[0..11]
+            .iter()
+            .fold(0u8, |sum, &i| sum.rotate_right(1).wrapping_add(i));
+        if self.long_name_checksum.take() == Some(checksum) {
+            let units = self
+                .long_name
+                .iter()
+                .copied()
+                .take_while(|&i| i != 0x0000 && i != 0xFFFF);
+            for c in char::decode_utf16(units) {
+                entry.push_name(c.unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+        } else {
+            // 8.3, NT flags for a lowercase base name and extension
+            let case = raw[12];
+            let base = raw[0..8].trim_ascii_end();
+            let extension = raw[8..11].trim_ascii_end();
+            for (i, &byte) in base.iter().enumerate() {
+                // 0x05 stands for a leading 0xE5
+                let byte = if i == 0 && byte == 0x05 { 0xE5 } else { byte };
+                entry.push_short(byte, case & 0x08 != 0);
+            }
+            if !extension.is_empty() {
+                entry.push_name('.');
+                for &byte in extension {
+                    entry.push_short(byte, case & 0x10 != 0);
+                }
+            }
+        }
+        entry
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let raw = match self.next_raw() {
+                Ok(Some(raw)) => raw,
+                Ok(None) => return None,
+                Err(err) => {
+                    self.is_done = true;
+                    return Some(Err(err));
+                }
+            };
+            match raw[0] {
+                // no more entries
+                0x00 => {
+                    self.is_done = true;
+                    return None;
+                }
+                // deleted
+                0xE5 => self.long_name_checksum = None,
+                _ if raw[11] & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME => {
+                    self.push_long_name(&raw)
+                }
+                _ if raw[11] & ATTRIBUTE_VOLUME_ID != 0 => self.long_name_checksum = None,
+                _ => return Some(Ok(self.entry(&raw))),
+            }
+        }
+    }
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &str {
+        // only whole characters are pushed
+        str::from_utf8(&self.name[..self.name_length]).unwrap_or("?")
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attributes & ATTRIBUTE_DIRECTORY != 0
+    }
+
+    /// Size in bytes, 0 for directories
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn to_dir<D: BlockDevice>(&self, fs: &FatFs<D>) -> Option<Dir> {
+        if !self.is_dir() {
+            return None;
+        }
+        // `..` of a top-level directory points at cluster 0
+        Some(match self.cluster {
+            0 => fs.root(),
+            cluster => Dir { cluster },
+        })
+    }
+
+    fn push_name(&mut self, c: char) {
+        let length = c.len_utf8();
+        if self.name_length + length <= MAX_NAME {
+            c.encode_utf8(&mut self.name[self.name_length..]);
+            self.name_length += length;
+        }
+    }
+
+    fn push_short(&mut self, byte: u8, is_lowercase: bool) {
+        let byte = if is_lowercase {
+            byte.to_ascii_lowercase()
+        } else {
+            byte
+        };
+        // OEM code page, anything outside ASCII is unknown
+        self.push_name(if byte.is_ascii() {
+            byte as char
+        } else {
+            char::REPLACEMENT_CHARACTER
+        });
+    }
+}
@@ -0,0 +1,228 @@
+//! Interrupt Descriptor Table and CPU exception handlers
+//!
+//! Faults are logged with their address and error code, then handed to the panic handler. A
+//! double fault runs on its own stack, from the TSS of our GDT, so a stack overflow still gets
+//! reported instead of ending in a triple fault.
+//!
+//! https://wiki.osdev.org/Exceptions
+//! https://os.phil-opp.com/double-fault-exceptions/
+
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::segmentation::{CS, DS, ES, SS, Segment};
+use x86_64::instructions::tables::{load_tss, sgdt};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::idt::{
+    Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+};
+use x86_64::structures::tss::TaskStateSegment;
+
+use crate::fox_uefi::is_boot_services_active;
+
+const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 8 * 4096;
+
+/// Init [`init`]
+static mut IDT: InterruptDescriptorTable = InterruptDescriptorTable::new();
+
+/// Replaces the GDT of the firmware, which has no TSS
+static mut GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// Written once by [`init_gdt`], for [`reload`]
+static mut SELECTORS: Selectors = Selectors {
+    code: SegmentSelector(0),
+    data: SegmentSelector(0),
+    tss: SegmentSelector(0),
+};
+
+#[derive(Copy, Clone)]
+struct Selectors {
+    code: SegmentSelector,
+    data: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+/// Stack of the double fault handler, the panic handler runs on it too
+///
+/// The CPU aligns it to 16 bytes when switching to it.
+static mut DOUBLE_FAULT_STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+/// Load our GDT and TSS, then our IDT with the exception handlers
+///
+/// Interrupts must be disabled: IRQ handlers are installed later by
+/// [`crate::fox_interrupts::init`].
+pub fn init() {
+    // log::trace!("fox_idt::init");
+    assert!(!is_boot_services_active());
+    assert!(!interrupts::are_enabled());
+
+    init_gdt();
+
+    let idt = &raw mut IDT;
+    // SAFETY: interrupts are disabled
+    let idt = unsafe { &mut *idt };
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    // SAFETY: the IST entry is set up by `init_gdt`
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.load();
+
+    log::debug!("IDT loaded");
+}
+
+/// Install the handler of an interrupt vector
+pub fn set_handler(vector: u8, handler: HandlerFunc) {
+    interrupts::without_interrupts(|| {
+        let idt = &raw mut IDT;
+        // SAFETY: interrupts are disabled
+        let idt = unsafe { &mut *idt };
+        idt[vector].set_handler_fn(handler);
+    });
+}
+
+/// Remove the handler of an interrupt vector
+pub fn clear_handler(vector: u8) {
+    interrupts::without_interrupts(|| {
+        let idt = &raw mut IDT;
+        // SAFETY: interrupts are disabled
+        let idt = unsafe { &mut *idt };
+        idt[vector] = Entry::missing();
+    });
+}
+
+/// Flat code and data segments plus the TSS
+fn init_gdt() {
+    let tss = &raw mut TSS;
+    // SAFETY: called once, before the TSS is loaded
+    let tss = unsafe { &mut *tss };
+    let stack = &raw const DOUBLE_FAULT_STACK;
+    // the stack grows down from its end
+    let stack_end = VirtAddr::from_ptr(stack) + DOUBLE_FAULT_STACK_SIZE as u64;
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack_end;
+    // never written again
+    let tss: &'static TaskStateSegment = tss;
+
+    let gdt = &raw mut GDT;
+    // SAFETY: called once, before the GDT is loaded
+    let gdt = unsafe { &mut *gdt };
+    let selectors = Selectors {
+        code: gdt.append(Descriptor::kernel_code_segment()),
+        data: gdt.append(Descriptor::kernel_data_segment()),
+        tss: gdt.append(Descriptor::tss_segment(tss)),
+    };
+    // SAFETY: called once, nothing reads it yet
+    unsafe { SELECTORS = selectors };
+    load_gdt(selectors);
+}
+
+/// Load our GDT, segment registers and TSS
+fn load_gdt(selectors: Selectors) {
+    load_gdt_table();
+
+    // SAFETY: the selectors point at the descriptors just loaded
+    unsafe {
+        CS::set_reg(selectors.code);
+        DS::set_reg(selectors.data);
+        ES::set_reg(selectors.data);
+        SS::set_reg(selectors.data);
+        load_tss(selectors.tss);
+    }
+}
+
+fn load_gdt_table() {
+    let gdt = &raw const GDT;
+    // SAFETY: only written by `init_gdt` and the busy bit in `reload`
+    let gdt: &'static GlobalDescriptorTable = unsafe { &*gdt };
+    gdt.load();
+}
+
+/// Load the GDT, TSS and IDT again after the CPU lost them, on resume from S3
+///
+/// Interrupts must be disabled. The TSS descriptor is still busy from [`init`], `ltr` wants it
+/// available.
+pub fn reload() {
+    // log::trace!("fox_idt::reload");
+    assert!(!interrupts::are_enabled());
+
+    // SAFETY: written once by `init_gdt`
+    let selectors = unsafe { SELECTORS };
+    load_gdt_table();
+    // type 0xB busy TSS to 0x9 available, bit 41 of the descriptor
+    let table = sgdt().base.as_mut_ptr::<u64>();
+    let entry = table.wrapping_add(selectors.tss.index() as usize);
+    // SAFETY: the low half of the TSS descriptor in our GDT, interrupts are disabled
+    unsafe { *entry &= !(1 << 41) };
+    load_gdt(selectors);
+
+    let idt = &raw const IDT;
+    // SAFETY: only changed with interrupts disabled
+    let idt: &'static InterruptDescriptorTable = unsafe { &*idt };
+    idt.load();
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    log_frame(&stack_frame);
+    panic!("#DE divide error at {:#x}", stack_frame.instruction_pointer);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    log_frame(&stack_frame);
+    panic!(
+        "#UD invalid opcode at {:#x}",
+        stack_frame.instruction_pointer
+    );
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    log_frame(&stack_frame);
+    // non-zero for a faulting segment selector or IDT entry
+    panic!(
+        "#GP general protection fault at {:#x}, error code {:#x}",
+        stack_frame.instruction_pointer, error_code
+    );
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    log_frame(&stack_frame);
+    panic!(
+        "#PF page fault at {:#x} accessing {:#x}, {:?}",
+        stack_frame.instruction_pointer,
+        Cr2::read_raw(),
+        error_code
+    );
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    log_frame(&stack_frame);
+    panic!("#DF double fault at {:#x}", stack_frame.instruction_pointer);
+}
+
+fn log_frame(stack_frame: &InterruptStackFrame) {
+    log::error!(
+        "RIP {:#x} CS {:#x} RFLAGS {:#x} RSP {:#x} SS {:#x}",
+        stack_frame.instruction_pointer,
+        stack_frame.code_segment.0,
+        stack_frame.cpu_flags.bits(),
+        stack_frame.stack_pointer,
+        stack_frame.stack_segment.0
+    );
+}
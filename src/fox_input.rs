@@ -0,0 +1,182 @@
+//! Input event bus: keys as the UEFI Simple Text Input protocol reports them and mouse events
+//!
+//! Producers [`publish`] [`InputEvent`]s and consumers [`subscribe`] to all of them, neither
+//! knows the other. [`Input`] is the producer for the firmware ConIn while boot services last,
+//! or for our i8042 driver in passive mode: the driver only buffers the scancodes and packets,
+//! [`Keymap`] decodes them here. Its tasks sleep until the device IRQs. The events travel
+//! through the [`fox_event`] queue, so producers may publish from IRQ handlers.
+//!
+//! https://uefi.org/specs/UEFI/2.10/12_Protocols_Console_Support.html#simple-text-input-protocol
+
+use core::time::Duration;
+
+use uefi::proto::console::text::Key;
+use uefi::system::with_stdin;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::{I8042, MouseEvent};
+use crate::fox_event::{self, Event, EventLoop};
+use crate::fox_uefi::is_boot_services_active;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod keymap;
+mod layout;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use keymap::Keymap;
+pub use layout::{layout, layouts, set_layout};
+
+/// Printable characters with a control meaning
+pub const CHAR_BACKSPACE: char = '\u{08}';
+pub const CHAR_CARRIAGE_RETURN: char = '\r';
+/// Ctrl+C as our keymap reports it, firmwares may not
+pub const CHAR_CTRL_C: char = '\u{03}';
+
+/// Of ConIn, it has no IRQ of ours
+const FIRMWARE_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What a keyboard or mouse reported, whichever device it was
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    Key(Key),
+    /// Device counts, positive `dy` is up
+    MouseMove {
+        dx: i16,
+        dy: i16,
+    },
+    MouseButton {
+        button: MouseButton,
+        is_pressed: bool,
+    },
+    /// Wheel clicks, positive is up
+    Wheel(i8),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MouseButton {
+    Left,
+    Right,
+    Middle,
+}
+
+/// Producer of the keyboard and mouse the app reads
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum Input<'a> {
+    /// ConIn, needs boot services
+    Firmware,
+    /// Our driver in passive mode, no keymap for set 3 or without a keyboard
+    I8042 {
+        i8042: &'a mut I8042,
+        keymap: Option<Keymap>,
+    },
+}
+
+/// Producer of the keys the app reads, there is no driver of ours outside x86
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub enum Input {
+    /// ConIn, needs boot services
+    Firmware,
+}
+
+/// Queue `event` for every subscriber, also from IRQ handlers
+pub fn publish(event: InputEvent) {
+    fox_event::post(Event::Input(event));
+}
+
+/// Call `handler` with every [`InputEvent`] published while `events` runs
+pub fn subscribe<'a>(events: &mut EventLoop<'a>, mut handler: impl FnMut(&InputEvent) + 'a) {
+    events.add_handler(move |event| {
+        if let Event::Input(event) = event {
+            handler(event);
+        }
+    });
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl<'a> Input<'a> {
+    /// The i8042 if loaded, else ConIn while boot services last
+    pub fn new(i8042: Option<&'a mut I8042>) -> Option<Self> {
+        match i8042 {
+            Some(i8042) => {
+                let keymap = i8042.scancode_set().and_then(Keymap::new);
+                Some(Self::I8042 { i8042, keymap })
+            }
+            None => is_boot_services_active().then_some(Self::Firmware),
+        }
+    }
+
+    /// Spawn the tasks that [`publish`] what the devices report
+    pub fn spawn(self, events: &mut EventLoop<'a>) {
+        match self {
+            Self::Firmware => spawn_firmware(events),
+            Self::I8042 { i8042, keymap } => {
+                let i8042: &'a I8042 = i8042;
+                if let Some(mut keymap) = keymap {
+                    events.spawn(async move {
+                        while let Ok(scancode) = i8042.next_key().await {
+                            if let Some(key) = keymap.translate(scancode) {
+                                publish(InputEvent::Key(key));
+                            }
+                        }
+                    });
+                }
+                events.spawn(async move {
+                    // left, right and middle as of the last packet
+                    let mut buttons = [false; 3];
+                    while let Ok(event) = i8042.next_mouse().await {
+                        publish_mouse(event, &mut buttons);
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+impl Input {
+    /// ConIn while boot services last
+    pub fn new() -> Option<Self> {
+        is_boot_services_active().then_some(Self::Firmware)
+    }
+
+    /// Spawn the task that [`publish`]es the keys of ConIn
+    pub fn spawn(self, events: &mut EventLoop) {
+        match self {
+            Self::Firmware => spawn_firmware(events),
+        }
+    }
+}
+
+/// Poll ConIn every [`FIRMWARE_POLL_INTERVAL`]
+fn spawn_firmware(events: &mut EventLoop) {
+    events.spawn(async {
+        loop {
+            while let Some(key) = with_stdin(|stdin| stdin.read_key()).ok().flatten() {
+                publish(InputEvent::Key(key));
+            }
+            fox_event::sleep(FIRMWARE_POLL_INTERVAL).await;
+        }
+    });
+}
+
+/// Movement, then the buttons that changed since `buttons`, then the wheel
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn publish_mouse(event: MouseEvent, buttons: &mut [bool; 3]) {
+    if event.dx != 0 || event.dy != 0 {
+        publish(InputEvent::MouseMove {
+            dx: event.dx,
+            dy: event.dy,
+        });
+    }
+    let pressed = [event.left, event.right, event.middle];
+    let names = [MouseButton::Left, MouseButton::Right, MouseButton::Middle];
+    for ((button, is_pressed), was_pressed) in names.into_iter().zip(pressed).zip(buttons.iter()) {
+        if is_pressed != *was_pressed {
+            publish(InputEvent::MouseButton { button, is_pressed });
+        }
+    }
+    *buttons = pressed;
+    if event.dz != 0 {
+        publish(InputEvent::Wheel(event.dz));
+    }
+}
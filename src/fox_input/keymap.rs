@@ -0,0 +1,271 @@
+//! Scancodes to UEFI keys, characters from the selected [`super::layout`]
+//!
+//! Set 2 make codes are turned into set 1 ones first, so one table serves both sets.
+//!
+//! https://wiki.osdev.org/PS/2_Keyboard#Scan_Code_Sets
+
+use core::mem;
+
+use uefi::Char16;
+use uefi::proto::console::text::{Key, ScanCode};
+
+use super::layout::layout;
+use super::{CHAR_BACKSPACE, CHAR_CARRIAGE_RETURN, CHAR_CTRL_C};
+use crate::drivers::ScancodeSet;
+
+const ESCAPE: u8 = 0x01;
+const BACKSPACE: u8 = 0x0E;
+const ENTER: u8 = 0x1C;
+const CTRL: u8 = 0x1D;
+const LEFT_SHIFT: u8 = 0x2A;
+/// Ctrl+C is taken by position, the Russian layout has a Cyrillic `с` there
+const KEY_C: u8 = 0x2E;
+const RIGHT_SHIFT: u8 = 0x36;
+/// AltGr after the extended prefix
+const ALT: u8 = 0x38;
+const CAPS_LOCK: u8 = 0x3A;
+const F1: u8 = 0x3B;
+const F10: u8 = 0x44;
+const F11: u8 = 0x57;
+const F12: u8 = 0x58;
+/// Keypad `/` after the extended prefix
+const KEYPAD_SLASH: u8 = 0x35;
+
+/// Prefix of keys added after the original XT keyboard
+const EXTENDED: u8 = 0xE0;
+/// Prefix of the Pause key, which has no break code
+const PAUSE: u8 = 0xE1;
+/// Set 2 prefix of a break code
+const BREAK: u8 = 0xF0;
+
+/// Keeps the modifier state between scancodes
+pub struct Keymap {
+    set: ScancodeSet,
+    left_shift: bool,
+    right_shift: bool,
+    ctrl: bool,
+    altgr: bool,
+    caps_lock: bool,
+    is_extended: bool,
+    is_break: bool,
+    /// Codes left of a Pause sequence
+    skip: u8,
+}
+
+impl Keymap {
+    /// `None` for set 3
+    pub fn new(set: ScancodeSet) -> Option<Self> {
+        if set == ScancodeSet::Set3 {
+            return None;
+        }
+        Some(Self {
+            set,
+            left_shift: false,
+            right_shift: false,
+            ctrl: false,
+            altgr: false,
+            caps_lock: false,
+            is_extended: false,
+            is_break: false,
+            skip: 0,
+        })
+    }
+
+    /// Feed one scancode byte, returns the key once a make code is complete
+    pub fn translate(&mut self, scancode: u8) -> Option<Key> {
+        match scancode {
+            EXTENDED => {
+                self.is_extended = true;
+                return None;
+            }
+            PAUSE => {
+                // E1 1D 45 in set 1, E1 14 77 in set 2, the same again for the break
+                self.skip = 2;
+                return None;
+            }
+            BREAK if self.set == ScancodeSet::Set2 => {
+                self.is_break = true;
+                return None;
+            }
+            _ => {}
+        }
+
+        let (code, is_break) = if self.set == ScancodeSet::Set1 {
+            (scancode & 0x7F, scancode & 0x80 != 0)
+        } else {
+            (set2_to_set1(scancode), mem::take(&mut self.is_break))
+        };
+        let is_extended = mem::take(&mut self.is_extended);
+        if self.skip > 0 {
+            self.skip -= 1;
+            return None;
+        }
+
+        match code {
+            // E0 2A and E0 36 are fake shifts around some extended keys
+            LEFT_SHIFT if !is_extended => self.left_shift = !is_break,
+            RIGHT_SHIFT if !is_extended => self.right_shift = !is_break,
+            CTRL => self.ctrl = !is_break,
+            ALT if is_extended => self.altgr = !is_break,
+            CAPS_LOCK if !is_break => self.caps_lock = !self.caps_lock,
+            _ if is_break => {}
+            ESCAPE => return Some(Key::Special(ScanCode::ESCAPE)),
+            BACKSPACE if !is_extended => return printable(CHAR_BACKSPACE),
+            // keypad Enter is extended
+            ENTER => return printable(CHAR_CARRIAGE_RETURN),
+            KEYPAD_SLASH if is_extended => return printable('/'),
+            // arrows, Home, Delete and the like share their codes with the keypad
+            _ if is_extended => return special(code).map(Key::Special),
+            F1..=F10 | F11 | F12 => return special(code).map(Key::Special),
+            _ => return self.char(code),
+        }
+        None
+    }
+
+    fn char(&self, code: u8) -> Option<Key> {
+        if self.ctrl {
+            return printable(CHAR_CTRL_C).filter(|_| code == KEY_C);
+        }
+        let layout = layout();
+        if self.altgr {
+            return printable(layout.altgr(code)?);
+        }
+        let normal = layout.normal(code)?;
+        let shifted = layout.shifted(code);
+        let mut is_shifted = self.left_shift || self.right_shift;
+        // Caps Lock inverts Shift on letters only
+        if self.caps_lock && normal.is_alphabetic() && shifted.is_some_and(char::is_alphabetic) {
+            is_shifted = !is_shifted;
+        }
+        if is_shifted {
+            printable(shifted?)
+        } else {
+            printable(normal)
+        }
+    }
+}
+
+fn printable(c: char) -> Option<Key> {
+    Char16::try_from(c).ok().map(Key::Printable)
+}
+
+/// UEFI scan code of a set 1 make code, navigation keys are the extended keypad codes
+fn special(code: u8) -> Option<ScanCode> {
+    let scan_code = match code {
+        0x48 => ScanCode::UP,
+        0x50 => ScanCode::DOWN,
+        0x4D => ScanCode::RIGHT,
+        0x4B => ScanCode::LEFT,
+        0x47 => ScanCode::HOME,
+        0x4F => ScanCode::END,
+        0x52 => ScanCode::INSERT,
+        0x53 => ScanCode::DELETE,
+        0x49 => ScanCode::PAGE_UP,
+        0x51 => ScanCode::PAGE_DOWN,
+        0x3B => ScanCode::FUNCTION_1,
+        0x3C => ScanCode::FUNCTION_2,
+        0x3D => ScanCode::FUNCTION_3,
+        0x3E => ScanCode::FUNCTION_4,
+        0x3F => ScanCode::FUNCTION_5,
+        0x40 => ScanCode::FUNCTION_6,
+        0x41 => ScanCode::FUNCTION_7,
+        0x42 => ScanCode::FUNCTION_8,
+        0x43 => ScanCode::FUNCTION_9,
+        0x44 => ScanCode::FUNCTION_10,
+        0x57 => ScanCode::FUNCTION_11,
+        0x58 => ScanCode::FUNCTION_12,
+        _ => return None,
+    };
+    Some(scan_code)
+}
+
+/// Make code of set 2 as in set 1, 0 if the key has no UEFI key or modifier
+fn set2_to_set1(code: u8) -> u8 {
+    match code {
+        0x76 => 0x01,
+        0x16 => 0x02,
+        0x1E => 0x03,
+        0x26 => 0x04,
+        0x25 => 0x05,
+        0x2E => 0x06,
+        0x36 => 0x07,
+        0x3D => 0x08,
+        0x3E => 0x09,
+        0x46 => 0x0A,
+        0x45 => 0x0B,
+        0x4E => 0x0C,
+        0x55 => 0x0D,
+        0x66 => 0x0E,
+        0x0D => 0x0F,
+        0x15 => 0x10,
+        0x1D => 0x11,
+        0x24 => 0x12,
+        0x2D => 0x13,
+        0x2C => 0x14,
+        0x35 => 0x15,
+        0x3C => 0x16,
+        0x43 => 0x17,
+        0x44 => 0x18,
+        0x4D => 0x19,
+        0x54 => 0x1A,
+        0x5B => 0x1B,
+        0x5A => 0x1C,
+        0x14 => 0x1D,
+        0x11 => 0x38,
+        0x1C => 0x1E,
+        0x1B => 0x1F,
+        0x23 => 0x20,
+        0x2B => 0x21,
+        0x34 => 0x22,
+        0x33 => 0x23,
+        0x3B => 0x24,
+        0x42 => 0x25,
+        0x4B => 0x26,
+        0x4C => 0x27,
+        0x52 => 0x28,
+        0x0E => 0x29,
+        0x12 => 0x2A,
+        0x5D => 0x2B,
+        0x1A => 0x2C,
+        0x22 => 0x2D,
+        0x21 => 0x2E,
+        0x2A => 0x2F,
+        0x32 => 0x30,
+        0x31 => 0x31,
+        0x3A => 0x32,
+        0x41 => 0x33,
+        0x49 => 0x34,
+        0x4A => 0x35,
+        0x59 => 0x36,
+        0x7C => 0x37,
+        0x29 => 0x39,
+        0x58 => 0x3A,
+        0x05 => 0x3B,
+        0x06 => 0x3C,
+        0x04 => 0x3D,
+        0x0C => 0x3E,
+        0x03 => 0x3F,
+        0x0B => 0x40,
+        0x83 => 0x41,
+        0x0A => 0x42,
+        0x01 => 0x43,
+        0x09 => 0x44,
+        0x6C => 0x47,
+        0x75 => 0x48,
+        0x7D => 0x49,
+        0x7B => 0x4A,
+        0x6B => 0x4B,
+        0x73 => 0x4C,
+        0x74 => 0x4D,
+        0x79 => 0x4E,
+        0x69 => 0x4F,
+        0x72 => 0x50,
+        0x7A => 0x51,
+        0x70 => 0x52,
+        0x71 => 0x53,
+        0x61 => 0x56,
+        0x78 => 0x57,
+        0x07 => 0x58,
+        _ => 0,
+    }
+}
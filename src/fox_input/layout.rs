@@ -0,0 +1,121 @@
+//! Keyboard layouts, characters by set 1 make code
+//!
+//! Dead keys are not supported: the accents of the German layout come out as they are.
+//!
+//! https://kbdlayout.info/
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub struct Layout {
+    pub name: &'static str,
+    /// Codes 0x00 to 0x56, the keypad as with NumLock on, `\0` where there is no character
+    normal: &'static str,
+    shifted: &'static str,
+    /// With the right Alt, shorter where the rest has none
+    altgr: &'static str,
+}
+
+#[rustfmt::skip]
+const US: Layout = Layout {
+    name: "us",
+    normal: concat!(
+        "\x00\x001234567890-=\x00",
+        "\x00qwertyuiop[]\x00\x00",
+        "asdfghjkl;'`\x00\\",
+        "zxcvbnm,./\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00\\",
+    ),
+    shifted: concat!(
+        "\x00\x00!@#$%^&*()_+\x00",
+        "\x00QWERTYUIOP{}\x00\x00",
+        "ASDFGHJKL:\"~\x00|",
+        "ZXCVBNM<>?\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00|",
+    ),
+    altgr: "",
+};
+
+#[rustfmt::skip]
+const DE: Layout = Layout {
+    name: "de",
+    normal: concat!(
+        "\x00\x001234567890ß´\x00",
+        "\x00qwertzuiopü+\x00\x00",
+        "asdfghjklöä^\x00#",
+        "yxcvbnm,.-\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00<",
+    ),
+    shifted: concat!(
+        "\x00\x00!\"§$%&/()=?`\x00",
+        "\x00QWERTZUIOPÜ*\x00\x00",
+        "ASDFGHJKLÖÄ°\x00'",
+        "YXCVBNM;:_\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00>",
+    ),
+    altgr: concat!(
+        "\x00\x00\x00²³\x00\x00\x00{[]}\\\x00\x00",
+        "\x00@\x00€\x00\x00\x00\x00\x00\x00\x00\x00~\x00\x00",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        "\x00\x00\x00\x00\x00\x00µ\x00\x00\x00\x00\x00\x00\x00",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00|",
+    ),
+};
+
+#[rustfmt::skip]
+const RU: Layout = Layout {
+    name: "ru",
+    normal: concat!(
+        "\x00\x001234567890-=\x00",
+        "\x00йцукенгшщзхъ\x00\x00",
+        "фывапролджэё\x00\\",
+        "ячсмитьбю.\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00\\",
+    ),
+    shifted: concat!(
+        "\x00\x00!\"№;%:?*()_+\x00",
+        "\x00ЙЦУКЕНГШЩЗХЪ\x00\x00",
+        "ФЫВАПРОЛДЖЭЁ\x00/",
+        "ЯЧСМИТЬБЮ,\x00*\x00 ",
+        "\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00789-456+1230.\x00\x00/",
+    ),
+    altgr: "",
+};
+
+static LAYOUTS: [Layout; 3] = [US, DE, RU];
+
+/// Index into [`LAYOUTS`], US by default
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+impl Layout {
+    pub fn normal(&self, code: u8) -> Option<char> {
+        lookup(self.normal, code)
+    }
+
+    pub fn shifted(&self, code: u8) -> Option<char> {
+        lookup(self.shifted, code)
+    }
+
+    pub fn altgr(&self, code: u8) -> Option<char> {
+        lookup(self.altgr, code)
+    }
+}
+
+fn lookup(table: &str, code: u8) -> Option<char> {
+    table.chars().nth(code as usize).filter(|&c| c != '\0')
+}
+
+pub fn layouts() -> &'static [Layout] {
+    &LAYOUTS
+}
+
+/// The layout every [`super::Keymap`] uses
+pub fn layout() -> &'static Layout {
+    &LAYOUTS[CURRENT.load(Ordering::Relaxed)]
+}
+
+/// Switch all keymaps to the layout `name`, `None` if there is none
+pub fn set_layout(name: &str) -> Option<&'static Layout> {
+    let index = LAYOUTS.iter().position(|i| i.name == name)?;
+    CURRENT.store(index, Ordering::Relaxed);
+    Some(&LAYOUTS[index])
+}
@@ -0,0 +1,72 @@
+//! IRQ routing
+//!
+//! Only usable after exiting boot services: until then the firmware owns the IDT and the PIC.
+//! Handlers go into the IDT of [`fox_idt`].
+//! IRQs go through the I/O APIC when the [`Apic`] driver is initialized, through the PIC otherwise.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+use crate::drivers::{Apic, Pic8259};
+use crate::fox_idt;
+use crate::fox_uefi::is_boot_services_active;
+
+/// Handled interrupts per legacy IRQ, counted by [`end_of_interrupt`]
+static IRQ_COUNTS: [AtomicU32; 16] = [const { AtomicU32::new(0) }; 16];
+
+/// Prepare IRQ handling
+///
+/// [`fox_idt::init`] and the [`Pic8259`] driver (and the [`Apic`] driver, if used) must be
+/// initialized first.
+pub fn init() {
+    // log::trace!("fox_interrupts::init");
+    assert!(!is_boot_services_active());
+
+    interrupts::disable();
+
+    fox_idt::set_handler(Apic::SPURIOUS_VECTOR, spurious_interrupt_handler);
+}
+
+/// Install a handler for a legacy IRQ and unmask it
+pub fn set_irq_handler(irq: u8, handler: HandlerFunc) {
+    interrupts::without_interrupts(|| {
+        fox_idt::set_handler(Pic8259::OFFSET + irq, handler);
+        if Apic::is_active() {
+            Apic::route_irq(irq, Pic8259::OFFSET + irq);
+        } else {
+            Pic8259::unmask(irq);
+        }
+    });
+}
+
+/// Mask a legacy IRQ and remove its handler
+pub fn clear_irq_handler(irq: u8) {
+    interrupts::without_interrupts(|| {
+        if Apic::is_active() {
+            Apic::mask_irq(irq);
+        } else {
+            Pic8259::mask(irq);
+        }
+        fox_idt::clear_handler(Pic8259::OFFSET + irq);
+    });
+}
+
+/// Must be called at the end of every IRQ handler
+pub fn end_of_interrupt(irq: u8) {
+    IRQ_COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    if Apic::is_active() {
+        Apic::send_eoi();
+    } else {
+        Pic8259::notify_end_of_interrupt(irq);
+    }
+}
+
+/// Interrupts of a legacy IRQ handled so far, wraps around
+pub fn irq_count(irq: u8) -> u32 {
+    IRQ_COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
+/// Spurious interrupts of the local APIC must not be acknowledged
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
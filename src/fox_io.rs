@@ -0,0 +1,250 @@
+//! Raw port and MMIO access for exploring the hardware
+//!
+//! Drivers [`reserve`] the registers whose state they track, [`peek`] and [`poke`] refuse to
+//! touch them: even reading the i8042 data port takes a byte away from the driver.
+//!
+//! https://wiki.osdev.org/I/O_Ports
+
+use alloc::vec::Vec;
+use core::fmt;
+
+use uefi::mem::memory_map::MemoryType;
+
+use crate::fox_mem::{self, MapError};
+use crate::fox_port::{Mmio, Pio};
+use crate::fox_sync::without_interrupts;
+
+/// Size of the I/O port space
+const PORT_COUNT: u64 = 0x1_0000;
+
+/// Accessed with interrupts disabled
+static mut RESERVED: Vec<Reservation> = Vec::new();
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Space {
+    Port,
+    Mmio,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// Owned by a driver, see [`reserve`]
+    Reserved(&'static str),
+    /// Outside the address space
+    InvalidAddress,
+    /// MMIO not aligned to the width
+    Unaligned,
+    /// Does not fit the width
+    InvalidValue,
+    Map(MapError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Reserved(owner) => write!(f, "reserved by {}", owner),
+            Self::InvalidAddress => write!(f, "invalid address"),
+            Self::Unaligned => write!(f, "unaligned address"),
+            Self::InvalidValue => write!(f, "value too large"),
+            Self::Map(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Registers of a driver, see [`reservations`]
+#[derive(Copy, Clone, Debug)]
+pub struct Reservation {
+    space: Space,
+    start: u64,
+    end: u64,
+    owner: &'static str,
+}
+
+/// `port 0x60` or `MMIO 0xfed00000-0xfed003ff`
+impl fmt::Display for Reservation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let space = match self.space {
+            Space::Port => "port",
+            Space::Mmio => "MMIO",
+        };
+        write!(f, "{} {:#x}", space, self.start)?;
+        if self.end - self.start > 1 {
+            write!(f, "-{:#x}", self.end - 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl Width {
+    pub fn bytes(self) -> u64 {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+
+    pub fn max(self) -> u32 {
+        u32::MAX >> (32 - 8 * self.bytes())
+    }
+}
+
+/// Keep [`peek`] and [`poke`] away from `length` bytes at `start`
+pub fn reserve(space: Space, start: u64, length: u64, owner: &'static str) {
+    with_reserved(|reserved| {
+        reserved.push(Reservation {
+            space,
+            start,
+            end: start + length,
+            owner,
+        })
+    });
+}
+
+/// What `owner` reserved, in order
+pub fn reservations(owner: &str) -> Vec<Reservation> {
+    with_reserved(|reserved| {
+        reserved
+            .iter()
+            .filter(|i| i.owner == owner)
+            .copied()
+            .collect()
+    })
+}
+
+/// Drop every reservation of `owner`
+pub fn release(owner: &'static str) {
+    with_reserved(|reserved| reserved.retain(|i| i.owner != owner));
+}
+
+/// The driver reserving any byte of the access
+pub fn owner(space: Space, address: u64, width: Width) -> Option<&'static str> {
+    let end = address.saturating_add(width.bytes());
+    with_reserved(|reserved| {
+        reserved
+            .iter()
+            .find(|i| i.space == space && i.start < end && address < i.end)
+            .map(|i| i.owner)
+    })
+}
+
+fn with_reserved<R>(f: impl FnOnce(&mut Vec<Reservation>) -> R) -> R {
+    without_interrupts(|| {
+        let reserved = &raw mut RESERVED;
+        // SAFETY: interrupts are disabled
+        let reserved = unsafe { &mut *reserved };
+        f(reserved)
+    })
+}
+
+/// Read a port or MMIO register
+///
+/// MMIO outside RAM is mapped uncached first.
+///
+/// # Safety
+/// Reads may have side effects on the device.
+pub unsafe fn peek(space: Space, address: u64, width: Width) -> Result<u32, Error> {
+    // log::trace!("fox_io::peek({:?}, {:#x}, {:?})", space, address, width);
+
+    check(space, address, width)?;
+    let value = match space {
+        // SAFETY: forwarded to the caller
+        Space::Port => unsafe {
+            let port = address as u16;
+            match width {
+                Width::U8 => Pio::<u8>::new(port).read() as u32,
+                Width::U16 => Pio::<u16>::new(port).read() as u32,
+                Width::U32 => Pio::<u32>::new(port).read(),
+            }
+        },
+        Space::Mmio => {
+            let address = map(address)?;
+            // SAFETY: mapped and aligned, the rest is forwarded to the caller
+            unsafe {
+                match width {
+                    Width::U8 => Mmio::<u8>::new(address).read() as u32,
+                    Width::U16 => Mmio::<u16>::new(address).read() as u32,
+                    Width::U32 => Mmio::<u32>::new(address).read(),
+                }
+            }
+        }
+    };
+    Ok(value)
+}
+
+/// Write a port or MMIO register
+///
+/// MMIO outside RAM is mapped uncached first.
+///
+/// # Safety
+/// The write must not break the device, or the memory of anyone else.
+pub unsafe fn poke(space: Space, address: u64, width: Width, value: u32) -> Result<(), Error> {
+    // log::trace!("fox_io::poke({:?}, {:#x}, {:?}, {:#x})", space, address, width, value);
+
+    check(space, address, width)?;
+    if value > width.max() {
+        return Err(Error::InvalidValue);
+    }
+    match space {
+        // SAFETY: forwarded to the caller
+        Space::Port => unsafe {
+            let port = address as u16;
+            match width {
+                Width::U8 => Pio::<u8>::new(port).write(value as u8),
+                Width::U16 => Pio::<u16>::new(port).write(value as u16),
+                Width::U32 => Pio::<u32>::new(port).write(value),
+            }
+        },
+        Space::Mmio => {
+            let address = map(address)?;
+            // SAFETY: mapped and aligned, the rest is forwarded to the caller
+            unsafe {
+                match width {
+                    Width::U8 => Mmio::<u8>::new(address).write(value as u8),
+                    Width::U16 => Mmio::<u16>::new(address).write(value as u16),
+                    Width::U32 => Mmio::<u32>::new(address).write(value),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn check(space: Space, address: u64, width: Width) -> Result<(), Error> {
+    match space {
+        Space::Port if address.saturating_add(width.bytes()) > PORT_COUNT => {
+            return Err(Error::InvalidAddress);
+        }
+        Space::Mmio if !address.is_multiple_of(width.bytes()) => return Err(Error::Unaligned),
+        _ => {}
+    }
+    match owner(space, address, width) {
+        Some(owner) => Err(Error::Reserved(owner)),
+        None => Ok(()),
+    }
+}
+
+/// `address` is in a memory map region of RAM, ours, the firmware's or free
+pub fn is_ram(address: u64) -> bool {
+    fox_mem::regions().is_some_and(|regions| {
+        regions.iter().any(|i| {
+            (i.start..i.end()).contains(&address)
+                && !matches!(i.ty, MemoryType::MMIO | MemoryType::RESERVED)
+        })
+    })
+}
+
+/// RAM is identity-mapped already, uncached mapping would alias it
+fn map(address: u64) -> Result<u64, Error> {
+    if is_ram(address) {
+        return Ok(address);
+    }
+    fox_mem::map_mmio(address, 1).map_err(Error::Map)
+}
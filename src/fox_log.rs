@@ -0,0 +1,447 @@
+//! Structured logger that keeps working after exiting boot services
+//!
+//! Replaces the `uefi::helpers` logger. Records are prefixed with the uptime, level and module
+//! path and written to every enabled [`Sink`], over the network with a syslog priority. Levels
+//! can be changed per module at runtime. Errors and warnings are colored and trace records
+//! dimmed, with console attributes on the UEFI console and ANSI escapes on the serial port.
+//!
+//! Command output goes through [`fox_print!`] and [`fox_println!`], unfiltered and to its own
+//! set of sinks, so it can be redirected as a whole.
+
+use alloc::string::String;
+use core::fmt::{self, Write};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use core::sync::atomic::AtomicU16;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use core::time::Duration;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use uefi::proto::console::text::Color;
+
+use crate::drivers::MmioUart;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::Serial16550;
+use crate::fox_net;
+use crate::fox_sync::{OnceCell, without_interrupts};
+use crate::fox_time::uptime;
+use crate::fox_uefi::is_boot_services_active;
+use crate::fox_vec::FixedVec;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const MAX_FILTERS: usize = 16;
+
+/// Colors of the UEFI console outside of colored records
+const CONSOLE_FOREGROUND: Color = Color::LightGray;
+const CONSOLE_BACKGROUND: Color = Color::Black;
+/// SGR reset, ends a colored record on the serial port
+const ANSI_RESET: &str = "\x1b[0m";
+/// Syslog tag of [`Sink::Net`] records
+const SYSLOG_TAG: &str = "my-uefi-app";
+/// Syslog facility local0
+const SYSLOG_FACILITY: u8 = 16;
+
+static LOGGER: Logger = Logger;
+
+/// Base port of the initialized UART, 0 if none
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+static SERIAL: AtomicU16 = AtomicU16::new(0);
+/// Initialized memory-mapped UART, used instead of the one at a port
+static MMIO_SERIAL: OnceCell<MmioUart> = OnceCell::new();
+
+/// Enabled [`Sink`]s
+static SINKS: AtomicU8 = AtomicU8::new(Sink::Console as u8 | Sink::Memory as u8);
+
+/// Records colored by level, see [`set_color`]
+static IS_COLOR: AtomicBool = AtomicBool::new(true);
+
+/// [`Sink`]s of [`print`]
+static PRINT_SINKS: AtomicU8 = AtomicU8::new(Sink::Console as u8 | Sink::Serial as u8);
+
+/// Accessed with interrupts disabled
+static mut FILTERS: Filters = Filters::new();
+
+/// Storage of [`Sink::Memory`], accessed with interrupts disabled
+static mut BUFFER: LogBuffer = LogBuffer::new();
+
+/// Where log records go
+#[derive(Copy, Clone, Debug)]
+pub enum Sink {
+    /// UEFI console, only while boot services are active
+    Console = 1 << 0,
+    /// UART set by [`set_serial`] or [`set_mmio_serial`]
+    Serial = 1 << 1,
+    /// In-memory ring buffer, see [`with_buffer`]
+    Memory = 1 << 2,
+    /// UDP to the `netconsole=` host, see [`fox_net::send_log`]
+    Net = 1 << 3,
+}
+
+impl Sink {
+    pub const ALL: [Self; 4] = [Self::Console, Self::Serial, Self::Memory, Self::Net];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Console => "console",
+            Self::Serial => "serial",
+            Self::Memory => "memory",
+            Self::Net => "net",
+        }
+    }
+}
+
+/// Formatted output to the sinks set by [`set_print_sinks`]
+#[macro_export]
+macro_rules! fox_print {
+    ($($arg:tt)*) => {
+        $crate::fox_log::print(format_args!($($arg)*))
+    };
+}
+
+/// [`fox_print!`] with a newline
+#[macro_export]
+macro_rules! fox_println {
+    () => {
+        $crate::fox_log::print(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::fox_log::print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger already set");
+    // filtering is done by `Logger::enabled`
+    log::set_max_level(log::STATIC_MAX_LEVEL);
+}
+
+/// Mirror log records to an initialized UART
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn set_serial(serial: &Serial16550) {
+    SERIAL.store(serial.base(), Ordering::Release);
+    set_sink(Sink::Serial, true);
+}
+
+/// Mirror log records to an initialized memory-mapped UART, the SPCR console
+pub fn set_mmio_serial(serial: &MmioUart) {
+    if MMIO_SERIAL.set(*serial).is_err() {
+        log::warn!("fox_log: MMIO UART already set");
+        return;
+    }
+    set_sink(Sink::Serial, true);
+}
+
+pub fn set_sink(sink: Sink, enabled: bool) {
+    if enabled {
+        SINKS.fetch_or(sink as u8, Ordering::AcqRel);
+    } else {
+        SINKS.fetch_and(!(sink as u8), Ordering::AcqRel);
+    }
+}
+
+/// Where [`print`] writes, [`Sink`] bits, the console and the serial port by default
+pub fn set_print_sinks(sinks: u8) {
+    PRINT_SINKS.store(sinks, Ordering::Release);
+}
+
+/// Color errors, warnings and trace records, for terminals that show escapes as text
+pub fn set_color(enabled: bool) {
+    IS_COLOR.store(enabled, Ordering::Relaxed);
+}
+
+/// Level of modules without their own filter
+pub fn set_level(level: LevelFilter) {
+    with_filters(|filters| filters.default = level);
+}
+
+/// Level of `module` and its submodules, e.g. `my_uefi_app::drivers`
+pub fn set_module_level(module: &'static str, level: LevelFilter) {
+    let is_full = with_filters(|filters| {
+        if let Some(filter) = filters.modules.iter_mut().find(|i| i.0 == module) {
+            filter.1 = level;
+            false
+        } else {
+            filters.modules.push((module, level)).is_err()
+        }
+    });
+    // not from the closure: logging reads the filters
+    if is_full {
+        log::warn!("fox_log: too many module filters, {} ignored", module);
+    }
+}
+
+/// Write unfiltered text to the sinks set by [`set_print_sinks`], see [`fox_print!`]
+pub fn print(args: fmt::Arguments) {
+    let sinks = PRINT_SINKS.load(Ordering::Acquire);
+
+    if sinks & Sink::Console as u8 != 0 && is_boot_services_active() {
+        uefi::system::with_stdout(|stdout| {
+            let _ = stdout.write_fmt(args);
+        });
+    }
+
+    without_interrupts(|| {
+        if sinks & Sink::Serial as u8 != 0 {
+            with_serial(|serial| {
+                let _ = serial.write_fmt(args);
+            });
+        }
+
+        if sinks & Sink::Memory as u8 != 0 {
+            let buffer = &raw mut BUFFER;
+            // SAFETY: interrupts are disabled
+            let buffer = unsafe { &mut *buffer };
+            let _ = buffer.write_fmt(args);
+        }
+    });
+
+    if sinks & Sink::Net as u8 != 0 {
+        let mut text = String::new();
+        let _ = text.write_fmt(args);
+        fox_net::send_log(&text);
+    }
+}
+
+/// [`print`] `bytes` at `address`, 16 per line with their address and as ASCII
+pub fn hexdump(address: u64, bytes: &[u8]) {
+    let mut line = String::new();
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        line.clear();
+        let _ = write!(line, "{:016x} ", address + i as u64 * 16);
+        for j in 0..16 {
+            match chunk.get(j) {
+                Some(byte) => {
+                    let _ = write!(line, " {:02x}", byte);
+                }
+                None => line.push_str("   "),
+            }
+        }
+        line.push_str("  ");
+        for &byte in chunk {
+            let is_printable = byte.is_ascii_graphic() || byte == b' ';
+            line.push(if is_printable { byte as char } else { '.' });
+        }
+        fox_println!("{}", line);
+    }
+}
+
+/// Records captured by [`Sink::Memory`], the oldest part first
+///
+/// Must not log from `f`.
+pub fn with_buffer<R>(f: impl FnOnce(&[u8], &[u8]) -> R) -> R {
+    without_interrupts(|| {
+        let buffer = &raw const BUFFER;
+        // SAFETY: interrupts are disabled and `f` does not log
+        let buffer = unsafe { &*buffer };
+        let (older, newer) = buffer.as_slices();
+        f(older, newer)
+    })
+}
+
+/// Write `record` to [`Sink::Memory`] only and unfiltered, for traces that would flood the
+/// other sinks
+pub fn log_to_buffer(record: &Record) {
+    let uptime = uptime();
+    without_interrupts(|| {
+        let buffer = &raw mut BUFFER;
+        // SAFETY: interrupts are disabled
+        let buffer = unsafe { &mut *buffer };
+        let _ = write_record(buffer, record, uptime, None);
+    });
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = with_filters(|filters| filters.level(metadata.target()));
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let uptime = uptime();
+        let sinks = SINKS.load(Ordering::Acquire);
+        let is_color = IS_COLOR.load(Ordering::Relaxed);
+        let color = if is_color {
+            level_color(record.level())
+        } else {
+            None
+        };
+
+        if sinks & Sink::Console as u8 != 0 && is_boot_services_active() {
+            uefi::system::with_stdout(|stdout| {
+                if let Some((foreground, _)) = color {
+                    let _ = stdout.set_color(foreground, CONSOLE_BACKGROUND);
+                }
+                let _ = write_record(stdout, record, uptime, None);
+                if color.is_some() {
+                    let _ = stdout.set_color(CONSOLE_FOREGROUND, CONSOLE_BACKGROUND);
+                }
+            });
+        }
+
+        // a record from an IRQ handler must not interleave with the one it interrupted
+        without_interrupts(|| {
+            if sinks & Sink::Serial as u8 != 0 {
+                with_serial(|serial| {
+                    let _ = write_record(serial, record, uptime, color.map(|(_, ansi)| ansi));
+                });
+            }
+
+            if sinks & Sink::Memory as u8 != 0 {
+                let buffer = &raw mut BUFFER;
+                // SAFETY: interrupts are disabled
+                let buffer = unsafe { &mut *buffer };
+                let _ = write_record(buffer, record, uptime, None);
+            }
+        });
+
+        if sinks & Sink::Net as u8 != 0 {
+            let mut line = String::new();
+            let priority = SYSLOG_FACILITY * 8 + syslog_severity(record.level());
+            let _ = write!(line, "<{}>{}: ", priority, SYSLOG_TAG);
+            let _ = write_record(&mut line, record, uptime, None);
+            fox_net::send_log(&line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Console color and ANSI escape of records at `level`, `None` to keep the default
+fn level_color(level: Level) -> Option<(Color, &'static str)> {
+    match level {
+        Level::Error => Some((Color::LightRed, "\x1b[31m")),
+        Level::Warn => Some((Color::Yellow, "\x1b[33m")),
+        Level::Info | Level::Debug => None,
+        // dim
+        Level::Trace => Some((Color::DarkGray, "\x1b[2m")),
+    }
+}
+
+/// RFC 5424 severity, trace records are debug too
+fn syslog_severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Run `f` on the UART of [`Sink::Serial`] if one is set, with interrupts disabled
+fn with_serial(f: impl FnOnce(&mut dyn Write)) {
+    if let Some(serial) = MMIO_SERIAL.get() {
+        let mut serial = *serial;
+        f(&mut serial);
+    } else {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            let base = SERIAL.load(Ordering::Acquire);
+            if base != 0 {
+                // baud rate is already programmed by `Driver::init`
+                f(&mut Serial16550::new(base, 0));
+            }
+        }
+    }
+}
+
+/// `[    1.234567]  INFO my_uefi_app::drivers::i8042: message`
+///
+/// `ansi` starts the line, reset before the newline.
+fn write_record(
+    w: &mut dyn Write,
+    record: &Record,
+    uptime: Duration,
+    ansi: Option<&str>,
+) -> fmt::Result {
+    let (start, end) = match ansi {
+        Some(ansi) => (ansi, ANSI_RESET),
+        None => ("", ""),
+    };
+    writeln!(
+        w,
+        "{}[{:>5}.{:06}] {:>5} {}: {}{}",
+        start,
+        uptime.as_secs(),
+        uptime.subsec_micros(),
+        record.level(),
+        record.module_path().unwrap_or(record.target()),
+        record.args(),
+        end
+    )
+}
+
+fn with_filters<R>(f: impl FnOnce(&mut Filters) -> R) -> R {
+    without_interrupts(|| {
+        let filters = &raw mut FILTERS;
+        // SAFETY: interrupts are disabled
+        let filters = unsafe { &mut *filters };
+        f(filters)
+    })
+}
+
+struct Filters {
+    default: LevelFilter,
+    modules: FixedVec<(&'static str, LevelFilter), MAX_FILTERS>,
+}
+
+impl Filters {
+    const fn new() -> Self {
+        Self {
+            default: LevelFilter::Trace,
+            modules: FixedVec::new(),
+        }
+    }
+
+    /// Level of the most specific matching module filter
+    fn level(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(module, _)| {
+                target
+                    .strip_prefix(module)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+}
+
+/// Last [`BUFFER_SIZE`] bytes of log output, the oldest bytes are overwritten
+struct LogBuffer {
+    data: [u8; BUFFER_SIZE],
+    /// Total bytes written
+    written: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; BUFFER_SIZE],
+            written: 0,
+        }
+    }
+
+    fn as_slices(&self) -> (&[u8], &[u8]) {
+        if self.written <= BUFFER_SIZE {
+            (&self.data[..self.written], &[])
+        } else {
+            let position = self.written % BUFFER_SIZE;
+            (&self.data[position..], &self.data[..position])
+        }
+    }
+}
+
+impl Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.written % BUFFER_SIZE] = byte;
+            self.written += 1;
+        }
+        Ok(())
+    }
+}
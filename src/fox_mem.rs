@@ -0,0 +1,318 @@
+//! Physical memory map
+//!
+//! A sorted, merged copy of the final UEFI memory map, and the physical frame allocator built
+//! on it.
+//!
+//! https://wiki.osdev.org/Detecting_Memory_(x86)#Getting_an_UEFI_Memory_Map
+
+use core::fmt;
+use core::ptr::{NonNull, null_mut};
+use core::slice;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use uefi::boot::{self, AllocateType};
+use uefi::mem::memory_map::{MemoryMap, MemoryType};
+
+use crate::fox_sync::without_interrupts;
+use crate::fox_uefi::is_boot_services_active;
+use crate::fox_vec::FixedVec;
+
+mod frames;
+mod heap;
+mod paging;
+
+use frames::FrameBitmap;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use paging::map_executable;
+pub use paging::{MapError, map_mmio};
+
+pub const PAGE_SIZE: u64 = 4096;
+/// Real-mode memory, kept for the firmware and legacy structures
+const LOW_MEMORY: u64 = 0x10_0000;
+const MAX_REGIONS: usize = 256;
+
+pub type Regions = FixedVec<MemoryRegion, MAX_REGIONS>;
+
+/// Init [`init`]
+static REGIONS: AtomicPtr<Regions> = AtomicPtr::new(null_mut());
+
+/// Storage for [`REGIONS`], written once by [`init`]
+static mut REGIONS_STORAGE: Regions = FixedVec::new();
+
+/// Frame allocator after exiting boot services, set up by [`init`]
+static mut FRAMES: Option<FrameBitmap<'static>> = None;
+
+/// Physically contiguous pages of one memory type
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryRegion {
+    pub ty: MemoryType,
+    pub start: u64,
+    pub page_count: u64,
+}
+
+impl MemoryRegion {
+    pub fn end(&self) -> u64 {
+        self.start + self.page_count * PAGE_SIZE
+    }
+
+    pub fn size(&self) -> u64 {
+        self.page_count * PAGE_SIZE
+    }
+
+    /// Free for the OS once boot services are gone
+    pub fn is_usable(&self) -> bool {
+        matches!(
+            self.ty,
+            MemoryType::CONVENTIONAL
+                | MemoryType::BOOT_SERVICES_CODE
+                | MemoryType::BOOT_SERVICES_DATA
+        )
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {:>10} KiB {}",
+            self.start,
+            self.end() - 1,
+            self.size() >> 10,
+            type_name(self.ty)
+        )
+    }
+}
+
+/// Regions sorted by address, `None` before [`init`]
+pub fn regions() -> Option<&'static Regions> {
+    let ptr = REGIONS.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Copy the memory map, sorted by address, adjacent regions of the same type merged, and hand
+/// its conventional memory to the frame allocator
+///
+/// Call with the final map returned when exiting boot services.
+pub fn init(memory_map: &impl MemoryMap) {
+    // log::trace!("fox_mem::init");
+
+    let mut sorted = FixedVec::<MemoryRegion, MAX_REGIONS>::new();
+    for descriptor in memory_map.entries() {
+        let region = MemoryRegion {
+            ty: descriptor.ty,
+            start: descriptor.phys_start,
+            page_count: descriptor.page_count,
+        };
+        if sorted.push(region).is_err() {
+            log::warn!("Memory map: too many regions, {:#x} ignored", region.start);
+        }
+    }
+    sorted.sort_unstable_by_key(|i| i.start);
+
+    let regions = &raw mut REGIONS_STORAGE;
+    // SAFETY: called once during boot, before anyone reads `REGIONS`
+    let regions = unsafe { &mut *regions };
+
+    for region in sorted.iter() {
+        if let Some(last) = regions.last_mut()
+            && last.ty == region.ty
+            && last.end() == region.start
+        {
+            last.page_count += region.page_count;
+            continue;
+        }
+        // never fails, merging only shrinks the list
+        let _ = regions.push(*region);
+    }
+
+    REGIONS.store(regions, Ordering::Release);
+
+    // boot services memory still holds the stack, it stays reserved
+    let frame_count = FrameBitmap::frame_count(regions);
+    let words = FrameBitmap::words(frame_count);
+    let pages = (words * size_of::<u64>()).div_ceil(PAGE_SIZE as usize) as u64;
+    let Some(storage) = regions.iter().find(|i| {
+        i.ty == MemoryType::CONVENTIONAL && i.start >= LOW_MEMORY && i.page_count >= pages
+    }) else {
+        log::error!(
+            "Frame allocator: no room for a bitmap of {} frames",
+            frame_count
+        );
+        return;
+    };
+    // SAFETY: conventional memory is unused after exiting boot services, UEFI identity-maps it
+    let bits = unsafe { slice::from_raw_parts_mut(storage.start as *mut u64, words) };
+    let mut bitmap = FrameBitmap::new(regions, bits);
+    bitmap.reserve(storage.start / PAGE_SIZE, pages);
+    // keep null pointers invalid
+    bitmap.reserve(0, 1);
+
+    without_interrupts(|| {
+        let frames = &raw mut FRAMES;
+        // SAFETY: interrupts are disabled
+        let frames = unsafe { &mut *frames };
+        *frames = Some(bitmap);
+    });
+}
+
+/// Allocate `count` contiguous frames, the first aligned to `align` bytes
+///
+/// From the firmware while boot services are active, from the bitmap after [`init`].
+pub fn alloc_frames(count: usize, align: u64) -> Option<u64> {
+    let align = align.max(PAGE_SIZE);
+    if count == 0 || !align.is_power_of_two() {
+        return None;
+    }
+    if is_boot_services_active() {
+        return alloc_firmware_frames(count, align);
+    }
+    with_frames(|frames| frames.alloc(count as u64, align / PAGE_SIZE)).map(|i| i * PAGE_SIZE)
+}
+
+/// Allocate one frame below 1 MiB, for code that starts in real mode
+pub fn alloc_low_frame() -> Option<u64> {
+    if is_boot_services_active() {
+        let ptr = boot::allocate_pages(
+            AllocateType::MaxAddress(LOW_MEMORY - 1),
+            MemoryType::LOADER_DATA,
+            1,
+        )
+        .inspect_err(|err| log::warn!("Frame allocator: low page: {}", err))
+        .ok()?;
+        return Some(ptr.as_ptr() as u64);
+    }
+    with_frames(|frames| frames.alloc_below(1, 1, LOW_MEMORY / PAGE_SIZE)).map(|i| i * PAGE_SIZE)
+}
+
+/// Return frames from [`alloc_frames`]
+///
+/// Frames the firmware handed out are loader data in the final map, freeing them after exiting
+/// boot services gives them to the bitmap.
+///
+/// # Safety
+/// Nothing uses the frames anymore.
+pub unsafe fn free_frames(address: u64, count: usize) {
+    if is_boot_services_active() {
+        if let Some(ptr) = NonNull::new(address as *mut u8)
+            && let Err(err) = unsafe { boot::free_pages(ptr, count) }
+        {
+            log::warn!("Frame allocator: free {:#x}: {}", address, err);
+        }
+        return;
+    }
+    with_frames(|frames| {
+        frames.free(address / PAGE_SIZE, count as u64);
+        Some(())
+    });
+}
+
+/// Free frames of the bitmap, `None` before [`init`]
+pub fn free_frame_count() -> Option<u64> {
+    with_frames(|frames| Some(frames.free_count()))
+}
+
+fn with_frames<R>(f: impl FnOnce(&mut FrameBitmap<'static>) -> Option<R>) -> Option<R> {
+    without_interrupts(|| {
+        let frames = &raw mut FRAMES;
+        // SAFETY: interrupts are disabled
+        let frames = unsafe { &mut *frames };
+        frames.as_mut().and_then(f)
+    })
+}
+
+/// Over-allocate pages and give back the unaligned head and the tail
+fn alloc_firmware_frames(count: usize, align: u64) -> Option<u64> {
+    let extra = (align / PAGE_SIZE) as usize - 1;
+    let ptr = boot::allocate_pages(
+        AllocateType::AnyPages,
+        MemoryType::LOADER_DATA,
+        count + extra,
+    )
+    .inspect_err(|err| log::warn!("Frame allocator: {} pages: {}", count + extra, err))
+    .ok()?;
+
+    let start = ptr.as_ptr() as u64;
+    let aligned = start.next_multiple_of(align);
+    let head = ((aligned - start) / PAGE_SIZE) as usize;
+    let tail = extra - head;
+    // SAFETY: the pages were just allocated and are not handed out
+    unsafe {
+        if head > 0 {
+            free_frames(start, head);
+        }
+        if tail > 0 {
+            free_frames(aligned + (count as u64) * PAGE_SIZE, tail);
+        }
+    }
+    Some(aligned)
+}
+
+/// Log the regions and the totals per kind of memory
+pub fn log_memory_map() {
+    let Some(regions) = regions() else {
+        return;
+    };
+
+    log::debug!("Memory map ({} regions):", regions.len());
+    for region in regions.iter() {
+        log::debug!("  {}", region);
+    }
+
+    let total = |filter: &dyn Fn(&MemoryRegion) -> bool| -> u64 {
+        regions.iter().filter(|i| filter(i)).map(|i| i.size()).sum()
+    };
+    let usable = total(&|i| i.is_usable());
+    let loader = total(&|i| matches!(i.ty, MemoryType::LOADER_CODE | MemoryType::LOADER_DATA));
+    let runtime = total(&|i| {
+        matches!(
+            i.ty,
+            MemoryType::RUNTIME_SERVICES_CODE | MemoryType::RUNTIME_SERVICES_DATA
+        )
+    });
+    let acpi = total(&|i| {
+        matches!(
+            i.ty,
+            MemoryType::ACPI_RECLAIM | MemoryType::ACPI_NON_VOLATILE
+        )
+    });
+    let reserved = total(&|i| {
+        matches!(
+            i.ty,
+            MemoryType::RESERVED | MemoryType::UNUSABLE | MemoryType::PAL_CODE
+        )
+    });
+
+    log::info!(
+        "Memory: {} MiB usable, {} MiB loader, {} MiB runtime, {} KiB ACPI, {} MiB reserved",
+        usable >> 20,
+        loader >> 20,
+        runtime >> 20,
+        acpi >> 10,
+        reserved >> 20
+    );
+    if let Some(free) = free_frame_count() {
+        log::info!("Frame allocator: {} MiB free", (free * PAGE_SIZE) >> 20);
+    }
+}
+
+fn type_name(ty: MemoryType) -> &'static str {
+    match ty {
+        MemoryType::RESERVED => "reserved",
+        MemoryType::LOADER_CODE => "loader code",
+        MemoryType::LOADER_DATA => "loader data",
+        MemoryType::BOOT_SERVICES_CODE => "boot services code",
+        MemoryType::BOOT_SERVICES_DATA => "boot services data",
+        MemoryType::RUNTIME_SERVICES_CODE => "runtime services code",
+        MemoryType::RUNTIME_SERVICES_DATA => "runtime services data",
+        MemoryType::CONVENTIONAL => "conventional",
+        MemoryType::UNUSABLE => "unusable",
+        MemoryType::ACPI_RECLAIM => "ACPI reclaimable",
+        MemoryType::ACPI_NON_VOLATILE => "ACPI NVS",
+        MemoryType::MMIO => "MMIO",
+        MemoryType::MMIO_PORT_SPACE => "MMIO port space",
+        MemoryType::PAL_CODE => "PAL code",
+        MemoryType::PERSISTENT_MEMORY => "persistent",
+        MemoryType::UNACCEPTED => "unaccepted",
+        _ => "OEM/OS defined",
+    }
+}
@@ -0,0 +1,280 @@
+//! Bitmap of physical frames
+//!
+//! One bit per frame below the end of the last conventional region, set while the frame is in
+//! use. Independent of UEFI, it only needs the regions and storage for the bits.
+
+use uefi::mem::memory_map::MemoryType;
+
+use super::{MemoryRegion, PAGE_SIZE};
+
+pub struct FrameBitmap<'a> {
+    bits: &'a mut [u64],
+    frame_count: u64,
+    free_count: u64,
+    /// No frame below this one is free
+    lowest_free: u64,
+}
+
+impl<'a> FrameBitmap<'a> {
+    /// Frames to track for `regions`
+    pub fn frame_count(regions: &[MemoryRegion]) -> u64 {
+        regions
+            .iter()
+            .filter(|i| i.ty == MemoryType::CONVENTIONAL)
+            .map(|i| i.end() / PAGE_SIZE)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Words of storage for `frame_count` frames
+    pub fn words(frame_count: u64) -> usize {
+        frame_count.div_ceil(u64::BITS as u64) as usize
+    }
+
+    /// Only the conventional memory of `regions` is free
+    pub fn new(regions: &[MemoryRegion], bits: &'a mut [u64]) -> Self {
+        let frame_count = Self::frame_count(regions).min(bits.len() as u64 * u64::BITS as u64);
+        bits.fill(!0);
+
+        let mut bitmap = Self {
+            bits,
+            frame_count,
+            free_count: 0,
+            lowest_free: 0,
+        };
+        for region in regions.iter().filter(|i| i.ty == MemoryType::CONVENTIONAL) {
+            let start = region.start / PAGE_SIZE;
+            let end = (region.end() / PAGE_SIZE).min(frame_count);
+            if start < end {
+                bitmap.set_range(start, end - start, false);
+            }
+        }
+        bitmap
+    }
+
+    pub fn free_count(&self) -> u64 {
+        self.free_count
+    }
+
+    /// Mark frames as used whatever their state
+    pub fn reserve(&mut self, frame: u64, count: u64) {
+        let end = (frame + count).min(self.frame_count);
+        if frame < end {
+            self.set_range(frame, end - frame, true);
+        }
+    }
+
+    /// First fit of `count` free frames, the first one a multiple of `align` frames
+    pub fn alloc(&mut self, count: u64, align: u64) -> Option<u64> {
+        self.alloc_below(count, align, self.frame_count)
+    }
+
+    /// Like [`Self::alloc`], with every frame below `limit`
+    pub fn alloc_below(&mut self, count: u64, align: u64, limit: u64) -> Option<u64> {
+        if count == 0 || !align.is_power_of_two() {
+            return None;
+        }
+
+        let mut frame = self.lowest_free.next_multiple_of(align);
+        while frame + count <= limit.min(self.frame_count) {
+            match self.find_used(frame, frame + count) {
+                Some(used) => frame = (used + 1).next_multiple_of(align),
+                None => {
+                    self.set_range(frame, count, true);
+                    if frame == self.lowest_free {
+                        self.lowest_free = frame + count;
+                    }
+                    return Some(frame);
+                }
+            }
+        }
+        None
+    }
+
+    /// Frames past the tracked range are ignored, they were never free
+    pub fn free(&mut self, frame: u64, count: u64) {
+        let end = (frame + count).min(self.frame_count);
+        if frame >= end {
+            return;
+        }
+        let freed = self.set_range(frame, end - frame, false);
+        if freed != end - frame {
+            log::warn!(
+                "Frame allocator: {} frames at {:#x} already free",
+                end - frame - freed,
+                frame * PAGE_SIZE
+            );
+        }
+        self.lowest_free = self.lowest_free.min(frame);
+    }
+
+    /// First used frame in `start..end`
+    fn find_used(&self, start: u64, end: u64) -> Option<u64> {
+        let mut frame = start;
+        while frame < end {
+            let bit = frame % u64::BITS as u64;
+            let word = self.bits[(frame / u64::BITS as u64) as usize] >> bit;
+            if word != 0 {
+                let used = frame + word.trailing_zeros() as u64;
+                return (used < end).then_some(used);
+            }
+            frame += u64::BITS as u64 - bit;
+        }
+        None
+    }
+
+    /// Returns the number of frames that changed state
+    fn set_range(&mut self, start: u64, count: u64, is_used: bool) -> u64 {
+        let end = start + count;
+        let mut changed = 0;
+        let mut frame = start;
+        while frame < end {
+            let bit = frame % u64::BITS as u64;
+            let length = (u64::BITS as u64 - bit).min(end - frame);
+            let mask = (!0u64 >> (u64::BITS as u64 - length)) << bit;
+
+            let word = &mut self.bits[(frame / u64::BITS as u64) as usize];
+            let old = *word;
+            *word = if is_used { old | mask } else { old & !mask };
+            changed += (old ^ *word).count_ones() as u64;
+            frame += length;
+        }
+
+        if is_used {
+            self.free_count -= changed;
+        } else {
+            self.free_count += changed;
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    fn region(ty: MemoryType, frame: u64, count: u64) -> MemoryRegion {
+        MemoryRegion {
+            ty,
+            start: frame * PAGE_SIZE,
+            page_count: count,
+        }
+    }
+
+    /// Free: 1-3, 6-11 from two overlapping entries, 16-19, the rest reserved or unlisted
+    fn regions() -> Vec<MemoryRegion> {
+        vec![
+            region(MemoryType::CONVENTIONAL, 1, 3),
+            region(MemoryType::RESERVED, 4, 2),
+            region(MemoryType::CONVENTIONAL, 6, 4),
+            region(MemoryType::CONVENTIONAL, 8, 4),
+            region(MemoryType::LOADER_DATA, 12, 1),
+            region(MemoryType::CONVENTIONAL, 16, 4),
+        ]
+    }
+
+    fn storage(regions: &[MemoryRegion]) -> Vec<u64> {
+        vec![0; FrameBitmap::words(FrameBitmap::frame_count(regions))]
+    }
+
+    #[test]
+    fn holes_and_overlaps() {
+        let regions = regions();
+        let mut bits = storage(&regions);
+        let mut bitmap = FrameBitmap::new(&regions, &mut bits);
+
+        assert_eq!(bitmap.frame_count, 20);
+        // the overlap counts once
+        assert_eq!(bitmap.free_count(), 13);
+        // first fit skips the reserved frames and the holes
+        assert_eq!(bitmap.alloc(1, 1), Some(1));
+        assert_eq!(bitmap.alloc(3, 1), Some(6));
+        assert_eq!(bitmap.alloc(3, 1), Some(9));
+        assert_eq!(bitmap.alloc(2, 1), Some(2));
+        assert_eq!(bitmap.alloc(4, 4), Some(16));
+        assert_eq!(bitmap.free_count(), 0);
+    }
+
+    #[test]
+    fn storage_limits_frames() {
+        let regions = [region(MemoryType::CONVENTIONAL, 0, 200)];
+        let mut bits = [0; 2];
+        let mut bitmap = FrameBitmap::new(&regions, &mut bits);
+
+        assert_eq!(bitmap.frame_count, 128);
+        assert_eq!(bitmap.free_count(), 128);
+        assert_eq!(bitmap.alloc(129, 1), None);
+        assert_eq!(bitmap.alloc(128, 1), Some(0));
+    }
+
+    #[test]
+    fn alloc_free_round_trip() {
+        let regions = regions();
+        let mut bits = storage(&regions);
+        let mut bitmap = FrameBitmap::new(&regions, &mut bits);
+
+        let frame = bitmap.alloc(4, 2).unwrap();
+        assert_eq!(frame, 6);
+        assert_eq!(bitmap.free_count(), 9);
+        bitmap.free(frame, 4);
+        assert_eq!(bitmap.free_count(), 13);
+        assert_eq!(bitmap.alloc(4, 2), Some(frame));
+
+        // freeing twice and past the end changes nothing
+        bitmap.free(frame, 4);
+        bitmap.free(frame, 4);
+        bitmap.free(19, 100);
+        assert_eq!(bitmap.free_count(), 13);
+
+        // reserved frames are not handed out until freed
+        bitmap.reserve(1, 3);
+        assert_eq!(bitmap.free_count(), 10);
+        assert_eq!(bitmap.alloc(1, 1), Some(6));
+        bitmap.free(1, 1);
+        assert_eq!(bitmap.alloc(1, 1), Some(1));
+    }
+
+    #[test]
+    fn out_of_frames() {
+        let regions = regions();
+        let mut bits = storage(&regions);
+        let mut bitmap = FrameBitmap::new(&regions, &mut bits);
+
+        // 6 frames, but not contiguous
+        assert_eq!(bitmap.alloc(7, 1), None);
+        assert_eq!(bitmap.alloc(0, 1), None);
+        assert_eq!(bitmap.alloc(1, 3), None);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = bitmap.alloc(1, 1) {
+            frames.push(frame);
+        }
+        assert_eq!(frames, [1, 2, 3, 6, 7, 8, 9, 10, 11, 16, 17, 18, 19]);
+        assert_eq!(bitmap.free_count(), 0);
+        assert_eq!(bitmap.alloc(1, 1), None);
+
+        bitmap.free(17, 1);
+        assert_eq!(bitmap.alloc(1, 1), Some(17));
+        assert_eq!(bitmap.alloc(1, 1), None);
+    }
+
+    #[test]
+    fn alloc_below() {
+        let regions = regions();
+        let mut bits = storage(&regions);
+        let mut bitmap = FrameBitmap::new(&regions, &mut bits);
+
+        // the whole allocation must fit below the limit
+        assert_eq!(bitmap.alloc_below(4, 1, 9), None);
+        assert_eq!(bitmap.alloc_below(3, 1, 9), Some(1));
+        assert_eq!(bitmap.alloc_below(3, 1, 9), Some(6));
+        assert_eq!(bitmap.alloc_below(1, 1, 9), None);
+        // frames above the limit stay free
+        assert_eq!(bitmap.alloc(1, 1), Some(9));
+        // a limit past the tracked frames is the end of them
+        assert_eq!(bitmap.alloc_below(4, 1, u64::MAX), Some(16));
+    }
+}
@@ -0,0 +1,246 @@
+//! Global allocator
+//!
+//! UEFI pool memory while boot services are active, afterwards a first-fit free list on frames
+//! from [`alloc_frames`]. Pool blocks freed after exiting boot services are leaked, the firmware
+//! can no longer take them back.
+//!
+//! https://os.phil-opp.com/allocator-designs/#linked-list-allocator
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::mem::size_of;
+use core::ptr::{NonNull, null_mut};
+
+use uefi::boot;
+use uefi::mem::memory_map::MemoryType;
+
+use super::{PAGE_SIZE, alloc_frames};
+use crate::fox_sync::without_interrupts;
+use crate::fox_uefi::is_boot_services_active;
+use crate::fox_vec::FixedVec;
+
+/// Pool memory is 8-byte aligned
+const POOL_ALIGN: usize = 8;
+/// Granularity of the free list, large enough for a [`FreeBlock`]
+const BLOCK_ALIGN: usize = 16;
+/// Frames added at once when the heap runs out
+const GROW_FRAMES: usize = 256;
+const MAX_CHUNKS: usize = 64;
+
+// host tests use the std allocator
+#[cfg_attr(not(test), global_allocator)]
+#[cfg_attr(test, allow(dead_code))]
+static ALLOCATOR: Allocator = Allocator;
+
+/// Accessed with interrupts disabled
+static mut HEAP: Heap = Heap::new();
+
+struct Allocator;
+
+/// Free list sorted by address, adjacent blocks merged
+struct Heap {
+    head: *mut FreeBlock,
+    /// Frames owned by the heap, start and length
+    chunks: FixedVec<(usize, usize), MAX_CHUNKS>,
+}
+
+#[repr(C)]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if is_boot_services_active() {
+            return pool_alloc(layout);
+        }
+        with_heap(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let is_heap = with_heap(|heap| {
+            let is_heap = heap.contains(ptr);
+            if is_heap {
+                // SAFETY: the block came from `Heap::alloc` with this layout
+                unsafe { heap.dealloc(ptr, layout) };
+            }
+            is_heap
+        });
+        if !is_heap && is_boot_services_active() {
+            // SAFETY: the block came from `pool_alloc` with this layout
+            unsafe { pool_free(ptr, layout) };
+        }
+    }
+}
+
+fn with_heap<R>(f: impl FnOnce(&mut Heap) -> R) -> R {
+    without_interrupts(|| {
+        let heap = &raw mut HEAP;
+        // SAFETY: interrupts are disabled
+        let heap = unsafe { &mut *heap };
+        f(heap)
+    })
+}
+
+/// Over-allocate for larger alignments, the pool pointer is stored right before the block
+fn pool_alloc(layout: Layout) -> *mut u8 {
+    if layout.align() <= POOL_ALIGN {
+        return boot::allocate_pool(MemoryType::LOADER_DATA, layout.size())
+            .map_or(null_mut(), |i| i.as_ptr());
+    }
+    let Ok(ptr) = boot::allocate_pool(MemoryType::LOADER_DATA, layout.size() + layout.align())
+    else {
+        return null_mut();
+    };
+    let ptr = ptr.as_ptr();
+    // at least POOL_ALIGN bytes in, there is room for the pointer
+    let offset = layout.align() - ptr as usize % layout.align();
+    // SAFETY: `offset` is at most `align`, inside the allocation
+    unsafe {
+        let block = ptr.add(offset);
+        block.cast::<*mut u8>().sub(1).write(ptr);
+        block
+    }
+}
+
+/// # Safety
+/// `ptr` came from [`pool_alloc`] with `layout`.
+unsafe fn pool_free(ptr: *mut u8, layout: Layout) {
+    let ptr = if layout.align() <= POOL_ALIGN {
+        ptr
+    } else {
+        unsafe { ptr.cast::<*mut u8>().sub(1).read() }
+    };
+    if let Some(ptr) = NonNull::new(ptr) {
+        // SAFETY: allocated from the pool and no longer used
+        let _ = unsafe { boot::free_pool(ptr) };
+    }
+}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            head: null_mut(),
+            chunks: FixedVec::new(),
+        }
+    }
+
+    fn contains(&self, ptr: *mut u8) -> bool {
+        let address = ptr as usize;
+        self.chunks
+            .iter()
+            .any(|&(start, length)| (start..start + length).contains(&address))
+    }
+
+    fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = block_layout(layout);
+        if let Some(ptr) = self.take(size, align) {
+            return ptr;
+        }
+        if self.grow(size + align) {
+            return self.take(size, align).unwrap_or(null_mut());
+        }
+        null_mut()
+    }
+
+    /// # Safety
+    /// `ptr` came from [`Heap::alloc`] with `layout`.
+    unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = block_layout(layout);
+        // SAFETY: the block is ours again
+        unsafe { self.insert(ptr as usize, size) };
+    }
+
+    /// Carve `size` bytes aligned to `align` out of the first block they fit in
+    fn take(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut link: *mut *mut FreeBlock = &raw mut self.head;
+        // SAFETY: the free list only links free blocks of the heap
+        unsafe {
+            while !(*link).is_null() {
+                let block = *link;
+                let start = block as usize;
+                let end = start + (*block).size;
+                let aligned = start.next_multiple_of(align);
+
+                if aligned + size <= end {
+                    // what is left on either side is a multiple of BLOCK_ALIGN, a block of its own
+                    let mut next = (*block).next;
+                    if aligned + size < end {
+                        let tail = (aligned + size) as *mut FreeBlock;
+                        tail.write(FreeBlock {
+                            size: end - aligned - size,
+                            next,
+                        });
+                        next = tail;
+                    }
+                    if aligned > start {
+                        (*block).size = aligned - start;
+                        (*block).next = next;
+                    } else {
+                        *link = next;
+                    }
+                    return Some(aligned as *mut u8);
+                }
+                link = &raw mut (*block).next;
+            }
+        }
+        None
+    }
+
+    /// Add frames for at least `size` bytes
+    fn grow(&mut self, size: usize) -> bool {
+        let frames = GROW_FRAMES.max(size.div_ceil(PAGE_SIZE as usize));
+        let Some(address) = alloc_frames(frames, PAGE_SIZE) else {
+            return false;
+        };
+        let length = frames * PAGE_SIZE as usize;
+        if self.chunks.push((address as usize, length)).is_err() {
+            // SAFETY: just allocated
+            unsafe { super::free_frames(address, frames) };
+            return false;
+        }
+        // SAFETY: the frames are owned by the heap now, UEFI identity-maps them
+        unsafe { self.insert(address as usize, length) };
+        true
+    }
+
+    /// Return a block to the free list, merged with its neighbours
+    ///
+    /// # Safety
+    /// The block is in a chunk of the heap and not in use.
+    unsafe fn insert(&mut self, start: usize, size: usize) {
+        unsafe {
+            let mut previous: *mut FreeBlock = null_mut();
+            let mut next = self.head;
+            while !next.is_null() && (next as usize) < start {
+                previous = next;
+                next = (*next).next;
+            }
+
+            let block = start as *mut FreeBlock;
+            block.write(FreeBlock { size, next });
+            if !next.is_null() && start + size == next as usize {
+                (*block).size += (*next).size;
+                (*block).next = (*next).next;
+            }
+
+            if previous.is_null() {
+                self.head = block;
+            } else if previous as usize + (*previous).size == start {
+                (*previous).size += (*block).size;
+                (*previous).next = (*block).next;
+            } else {
+                (*previous).next = block;
+            }
+        }
+    }
+}
+
+/// Size and alignment of a request as blocks of the free list
+fn block_layout(layout: Layout) -> (usize, usize) {
+    const _: () = assert!(size_of::<FreeBlock>() <= BLOCK_ALIGN);
+    (
+        layout.size().max(1).next_multiple_of(BLOCK_ALIGN),
+        layout.align().max(BLOCK_ALIGN),
+    )
+}
@@ -0,0 +1,40 @@
+//! Identity mapping of MMIO registers and code, for each architecture we build for
+//!
+//! The firmware leaves memory identity-mapped, device registers outside its memory map may be
+//! missing from its page tables or mapped cached.
+
+use core::fmt;
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+#[cfg(target_arch = "aarch64")]
+pub use aarch64::*;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use x86::*;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MapError {
+    /// No frame for a page table
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    OutOfFrames,
+    /// Not in the lower canonical half
+    InvalidAddress,
+    /// 5-level paging
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Unsupported,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::OutOfFrames => write!(f, "out of frames for page tables"),
+            Self::InvalidAddress => write!(f, "invalid address"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Unsupported => write!(f, "5-level paging is not supported"),
+        }
+    }
+}
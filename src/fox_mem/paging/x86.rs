@@ -0,0 +1,179 @@
+//! 4-level page tables
+//!
+//! UEFI leaves 4-level paging on with memory identity-mapped. The tables of the firmware are
+//! edited in place and reached through that identity mapping, new tables come from
+//! [`alloc_frames`].
+//!
+//! https://wiki.osdev.org/Paging
+
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::MapError;
+use crate::fox_mem::{PAGE_SIZE, alloc_frames};
+use crate::fox_sync::without_interrupts;
+
+/// End of the lower canonical half
+const MAX_ADDRESS: u64 = 1 << 47;
+const SIZE_2M: u64 = 1 << 21;
+const SIZE_1G: u64 = 1 << 30;
+/// PAT bit of a huge page entry, part of [`PageTableEntry::addr`]
+const HUGE_PAT: u64 = 1 << 12;
+
+/// Identity-map `length` bytes at `address` writable and uncached
+///
+/// Returns the virtual address of `address`. Huge pages of the firmware over the range are split,
+/// memory next to it keeps its caching.
+pub fn map_mmio(address: u64, length: usize) -> Result<u64, MapError> {
+    // log::trace!("fox_mem::map_mmio");
+
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        return Err(MapError::Unsupported);
+    }
+    let end = address
+        .checked_add(length as u64)
+        .filter(|&i| i <= MAX_ADDRESS)
+        .ok_or(MapError::InvalidAddress)?;
+
+    let mut flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH;
+    // reserved without NXE
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    map_range(address, end, flags)?;
+    Ok(address)
+}
+
+/// Identity-map `length` bytes at `address` writable and executable, cached as RAM
+///
+/// The firmware may map its data pages no-execute.
+pub fn map_executable(address: u64, length: usize) -> Result<(), MapError> {
+    // log::trace!("fox_mem::map_executable");
+
+    if Cr4::read().contains(Cr4Flags::L5_PAGING) {
+        return Err(MapError::Unsupported);
+    }
+    let end = address
+        .checked_add(length as u64)
+        .filter(|&i| i <= MAX_ADDRESS)
+        .ok_or(MapError::InvalidAddress)?;
+    map_range(
+        address,
+        end,
+        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+    )
+}
+
+/// Map the pages from `address` up to `end` with `flags`
+fn map_range(address: u64, end: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    without_interrupts(|| {
+        let cr0 = Cr0::read();
+        // SAFETY: the firmware may map its page tables read-only, restored right after
+        unsafe { Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT) };
+        let start = address & !(PAGE_SIZE - 1);
+        let result = (start..end)
+            .step_by(PAGE_SIZE as usize)
+            .try_for_each(|page| map_page(page, flags));
+        // SAFETY: as it was
+        unsafe { Cr0::write(cr0) };
+        result
+    })
+}
+
+/// Identity-map one 4 KiB page
+fn map_page(address: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    let page = VirtAddr::new(address);
+    let (pml4, _) = Cr3::read();
+    let mut table = pml4.start_address().as_u64() as *mut PageTable;
+    let mut is_changed = false;
+
+    for (index, huge_size) in [
+        (page.p4_index(), 0),
+        (page.p3_index(), SIZE_1G),
+        (page.p2_index(), SIZE_2M),
+    ] {
+        // SAFETY: page tables are identity-mapped, interrupts are disabled
+        let entry = unsafe { &mut (&mut *table)[index] };
+        if entry.is_unused() {
+            let frame = new_table()?;
+            entry.set_addr(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            split(entry, huge_size)?;
+        }
+        // the most restrictive level wins, NX on any level makes the page no-execute
+        let mut upper = entry.flags() | PageTableFlags::WRITABLE;
+        if !flags.contains(PageTableFlags::NO_EXECUTE) {
+            upper -= PageTableFlags::NO_EXECUTE;
+        }
+        if upper != entry.flags() {
+            entry.set_flags(upper);
+            is_changed = true;
+        }
+        table = entry.addr().as_u64() as *mut PageTable;
+    }
+
+    // SAFETY: as above
+    let entry = unsafe { &mut (&mut *table)[page.p1_index()] };
+    if entry.addr().as_u64() != address || entry.flags() != flags {
+        entry.set_addr(PhysAddr::new(address), flags);
+        is_changed = true;
+    }
+    if is_changed {
+        tlb::flush(page);
+    }
+    Ok(())
+}
+
+/// Replace a huge page with a table mapping the same memory with the same flags
+///
+/// NX stays on the children only, the parent entry must not make all of them no-execute.
+fn split(entry: &mut PageTableEntry, huge_size: u64) -> Result<(), MapError> {
+    let frame = new_table()?;
+    // SAFETY: just allocated and identity-mapped
+    let table = unsafe { &mut *(frame.as_u64() as *mut PageTable) };
+
+    let flags = entry.flags();
+    let base = entry.addr().as_u64() & !(huge_size - 1);
+    let is_pat = entry.addr().as_u64() & HUGE_PAT != 0;
+    for (i, child) in table.iter_mut().enumerate() {
+        if huge_size == SIZE_1G {
+            let address = base + i as u64 * SIZE_2M;
+            let pat = if is_pat { HUGE_PAT } else { 0 };
+            child.set_addr(PhysAddr::new(address | pat), flags);
+        } else {
+            // bit 7 is PAT in a 4 KiB entry
+            let mut flags = flags - PageTableFlags::HUGE_PAGE;
+            if is_pat {
+                flags |= PageTableFlags::HUGE_PAGE;
+            }
+            child.set_addr(PhysAddr::new(base + i as u64 * PAGE_SIZE), flags);
+        }
+    }
+
+    entry.set_addr(
+        frame,
+        flags
+            - PageTableFlags::HUGE_PAGE
+            - PageTableFlags::GLOBAL
+            - PageTableFlags::DIRTY
+            - PageTableFlags::NO_EXECUTE,
+    );
+    // every page of the huge page changes its entry
+    tlb::flush_all();
+    Ok(())
+}
+
+/// Zeroed frame for a page table
+fn new_table() -> Result<PhysAddr, MapError> {
+    let address = alloc_frames(1, PAGE_SIZE).ok_or(MapError::OutOfFrames)?;
+    // SAFETY: a fresh frame, identity-mapped
+    unsafe { (address as *mut PageTable).write(PageTable::new()) };
+    Ok(PhysAddr::new(address))
+}
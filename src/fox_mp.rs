@@ -0,0 +1,212 @@
+//! The other CPUs through EFI_MP_SERVICES_PROTOCOL
+//!
+//! The firmware has started the application processors (APs) and keeps them waiting for work.
+//! [`init`] has each run [`ap_sample`], which reads its TSC and on x86 its CPUID identification,
+//! and keeps what it found: the protocol is gone after exiting boot services.
+//!
+//! An AP runs [`ap_sample`] while the BSP waits in the blocking call, its TSC must fall between
+//! the BSP's readings before and after. A TSC outside is off by at least the distance, skews
+//! smaller than the round trip of the call go unnoticed.
+//!
+//! https://uefi.org/specs/PI/1.8/V2_DXE_Boot_Services_Protocols.html#efi-mp-services-protocol
+
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::time::Duration;
+
+use uefi::boot::{
+    OpenProtocolAttributes, OpenProtocolParams, get_handle_for_protocol, image_handle,
+    open_protocol,
+};
+use uefi::proto::pi::mp::MpServices;
+
+use crate::fox_arch::counter;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::Cpu;
+use crate::fox_println;
+use crate::fox_sync::OnceCell;
+use crate::fox_uefi::is_boot_services_active;
+
+/// Longest [`ap_sample`] on an AP, it takes microseconds
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Set by [`init`]
+static CPUS: OnceCell<Vec<CpuInfo>> = OnceCell::new();
+
+/// A CPU as [`init`] found it
+pub struct CpuInfo {
+    /// Processor number of the protocol
+    pub number: usize,
+    /// Local APIC ID
+    pub apic_id: u64,
+    pub is_bsp: bool,
+    /// Disabled APs do not run [`ap_sample`]
+    pub is_enabled: bool,
+    /// Passed the firmware's built-in self-test
+    pub is_healthy: bool,
+    pub package: u32,
+    pub core: u32,
+    pub thread: u32,
+    /// Read on the CPU itself, `None` if it did not run [`ap_sample`]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub cpu: Option<Cpu>,
+    /// TSC cycles outside the BSP's readings around the call, 0 in sync
+    pub tsc_skew: i64,
+}
+
+/// What [`ap_sample`] reads on a CPU
+#[derive(Default)]
+struct Sample {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    cpu: Option<Cpu>,
+    tsc: u64,
+}
+
+/// The CPUs found by [`init`], empty before or without the protocol
+pub fn cpus() -> &'static [CpuInfo] {
+    CPUS.get().map_or(&[], Vec::as_slice)
+}
+
+/// Run [`ap_sample`] on every enabled CPU, one at a time
+///
+/// Needs boot services, `NOT_FOUND` without the protocol.
+pub fn init() -> uefi::Result {
+    // log::trace!("fox_mp::init");
+    assert!(is_boot_services_active());
+
+    let handle = get_handle_for_protocol::<MpServices>()?;
+    let params = OpenProtocolParams {
+        handle,
+        agent: image_handle(),
+        controller: None,
+    };
+    // SAFETY: the firmware keeps using the protocol, the APs are only given work while we wait
+    let mp = unsafe { open_protocol::<MpServices>(params, OpenProtocolAttributes::GetProtocol) }?;
+    let count = mp.get_number_of_processors()?;
+    let bsp = mp.who_am_i()?;
+
+    let mut cpus = Vec::with_capacity(count.total);
+    for number in 0..count.total {
+        let info = mp.get_processor_info(number)?;
+        let mut sample = Sample::default();
+        let before = counter();
+        let is_sampled = if number == bsp {
+            read_sample(&mut sample);
+            true
+        } else if info.is_enabled() {
+            let argument = (&raw mut sample).cast::<c_void>();
+            match mp.startup_this_ap(number, ap_sample, argument, None, Some(TIMEOUT)) {
+                Ok(()) => true,
+                Err(err) => {
+                    log::warn!("MP: CPU {} did not run: {}", number, err.status());
+                    false
+                }
+            }
+        } else {
+            false
+        };
+        let after = counter();
+
+        let tsc_skew = match sample.tsc {
+            _ if !is_sampled => 0,
+            tsc if tsc < before => -((before - tsc) as i64),
+            tsc if tsc > after => (tsc - after) as i64,
+            _ => 0,
+        };
+        cpus.push(CpuInfo {
+            number,
+            apic_id: info.processor_id,
+            is_bsp: info.is_bsp(),
+            is_enabled: info.is_enabled(),
+            is_healthy: info.is_healthy(),
+            package: info.location.package,
+            core: info.location.core,
+            thread: info.location.thread,
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            cpu: sample.cpu,
+            tsc_skew,
+        });
+    }
+
+    let skewed = cpus.iter().filter(|i| i.tsc_skew != 0).count();
+    log::info!(
+        "MP: {} CPUs, {} enabled, {}",
+        count.total,
+        count.enabled,
+        match skewed {
+            0 => "TSC in sync",
+            _ => "TSC out of sync",
+        }
+    );
+    for cpu in cpus.iter().filter(|i| i.tsc_skew != 0 || !i.is_healthy) {
+        log::warn!(
+            "MP: CPU {} (APIC {}) TSC skew {} cycles{}",
+            cpu.number,
+            cpu.apic_id,
+            cpu.tsc_skew,
+            if cpu.is_healthy {
+                ""
+            } else {
+                ", failed self-test"
+            }
+        );
+    }
+    if CPUS.set(cpus).is_err() {
+        log::warn!("fox_mp already initialized");
+    }
+    Ok(())
+}
+
+/// One line per CPU
+pub fn print_cpus() {
+    if CPUS.get().is_none() {
+        fox_println!("no MP services");
+        return;
+    }
+    for info in cpus() {
+        let role = if info.is_bsp { "BSP" } else { "AP" };
+        let state = match (info.is_enabled, info.is_healthy) {
+            (false, _) => "disabled",
+            (true, false) => "unhealthy",
+            (true, true) => "ok",
+        };
+        fox_println!(
+            "  {:3} {:3} APIC {:4} package {} core {} thread {}, {}, TSC skew {}",
+            info.number,
+            role,
+            info.apic_id,
+            info.package,
+            info.core,
+            info.thread,
+            state,
+            info.tsc_skew
+        );
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if let Some(cpu) = &info.cpu {
+            fox_println!(
+                "      {} family {:#x} model {:#x} stepping {}",
+                cpu.vendor(),
+                cpu.family,
+                cpu.model,
+                cpu.stepping
+            );
+        }
+    }
+}
+
+/// Procedure of the APs, `argument` is a [`Sample`]
+///
+/// Runs on another CPU: no logging, no boot services, no locks the BSP may hold.
+extern "efiapi" fn ap_sample(argument: *mut c_void) {
+    // SAFETY: `init` passes its `Sample` and waits for us to return
+    let sample = unsafe { &mut *argument.cast::<Sample>() };
+    read_sample(sample);
+}
+
+fn read_sample(sample: &mut Sample) {
+    sample.tsc = counter();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        sample.cpu = Some(Cpu::read());
+    }
+}
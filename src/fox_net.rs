@@ -0,0 +1,572 @@
+//! IPv4 networking, just enough to check that a NIC works
+//!
+//! [`Nic`] sends and receives Ethernet frames. It is implemented by [`UefiSnp`] for any NIC the
+//! firmware drives, only while boot services are active, and by the e1000 and virtio-net
+//! drivers after that. The [`Interface`] on top answers ARP and pings, [`ping`] sends ICMP echo
+//! requests, [`send_log`] queues log records that [`flush_log`] sends over UDP and [`tftp::get`]
+//! fetches files. The addresses come from `ip=`, `gateway=` and `netconsole=` in [`fox_config`],
+//! there is no DHCP.
+//!
+//! https://www.rfc-editor.org/rfc/rfc826 (ARP)
+//! https://www.rfc-editor.org/rfc/rfc791 (IPv4)
+//! https://www.rfc-editor.org/rfc/rfc792 (ICMP)
+//! https://www.rfc-editor.org/rfc/rfc768 (UDP)
+//! https://www.rfc-editor.org/rfc/rfc1350 (TFTP)
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::time::Duration;
+
+use crate::drivers::DriverError;
+use crate::fox_config;
+use crate::fox_sync::Mutex;
+use crate::fox_time::{now, poll_timeout};
+
+mod arp;
+mod ethernet;
+mod icmp;
+mod ipv4;
+mod snp;
+pub mod tftp;
+mod udp;
+
+pub use ethernet::MacAddress;
+pub use snp::UefiSnp;
+
+/// ARP requests sent by [`resolve`] before giving up
+const ARP_ATTEMPTS: u32 = 3;
+const ARP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Identifier of our echo requests, replies to others are dropped
+const ECHO_ID: u16 = 0xF0C5;
+
+/// Source port of [`send_log`] datagrams, the one Linux netconsole uses
+const NETCONSOLE_PORT: u16 = 6666;
+/// Records [`send_log`] keeps until [`flush_log`], newer ones are dropped
+const LOG_QUEUE_SIZE: usize = 256;
+/// First of the dynamic ports handed out by [`ephemeral_port`]
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// Set by [`init`] or a NIC driver, accessed by whoever set [`IS_BUSY`]
+static mut INTERFACE: Option<Interface> = None;
+/// Owns [`INTERFACE`], a record logged while it is set is not sent
+///
+/// A flag and not a [`Mutex`](crate::fox_sync::Mutex): SNP calls need the firmware timer
+/// interrupt, and an IRQ handler that finds the interface taken gives up instead of spinning.
+static IS_BUSY: AtomicBool = AtomicBool::new(false);
+/// Next [`ephemeral_port`], an offset from [`EPHEMERAL_PORTS`]
+static NEXT_PORT: AtomicU16 = AtomicU16::new(0);
+/// Records of [`send_log`] waiting for [`flush_log`], a `Mutex` as IRQ handlers log too
+static LOG_QUEUE: Mutex<LogQueue> = Mutex::new(LogQueue {
+    records: VecDeque::new(),
+    dropped: 0,
+});
+
+/// A network card, whole Ethernet frames including the header but not the FCS
+pub trait Nic {
+    fn mac(&self) -> MacAddress;
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), DriverError>;
+    /// One frame into `buffer`, `None` if nothing arrived
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, DriverError>;
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// No NIC, or the firmware one is gone with boot services
+    NoInterface,
+    /// The interface is in use further up the stack, e.g. by the code an IRQ handler interrupted
+    Busy,
+    /// `ip=` is not set
+    NoAddress,
+    /// Not on our subnet and `gateway=` is not set
+    NoRoute,
+    /// No ARP reply
+    Unreachable(Ipv4Addr),
+    Timeout,
+    /// Error packet from the TFTP server, with its code
+    Tftp(u16),
+    /// More than a fetched file may take
+    TooLarge,
+    /// Writing a fetched file to the ESP failed
+    Save(uefi::Status),
+    Nic(DriverError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoInterface => write!(f, "no network interface"),
+            Self::Busy => write!(f, "network interface in use"),
+            Self::NoAddress => write!(f, "no IP address, set ip=<address>/<prefix>"),
+            Self::NoRoute => write!(f, "no route, set gateway=<address>"),
+            Self::Unreachable(address) => write!(f, "{} does not answer ARP", address),
+            Self::Timeout => write!(f, "timeout"),
+            Self::Tftp(code) => write!(f, "TFTP: {}", tftp::error_name(*code)),
+            Self::TooLarge => write!(f, "file too large"),
+            Self::Save(status) => write!(f, "saving: {}", status),
+            Self::Nic(err) => write!(f, "NIC: {}", err),
+        }
+    }
+}
+
+impl From<DriverError> for Error {
+    fn from(err: DriverError) -> Self {
+        Self::Nic(err)
+    }
+}
+
+/// Our address on a subnet
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Ipv4Cidr {
+    pub address: Ipv4Addr,
+    pub prefix: u8,
+}
+
+impl Ipv4Cidr {
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        u32::from(self.address) & mask == u32::from(address) & mask
+    }
+
+    /// Directed broadcast address of the subnet
+    pub fn broadcast(&self) -> Ipv4Addr {
+        let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+        Ipv4Addr::from(u32::from(self.address) | !mask)
+    }
+}
+
+/// `10.0.2.15/24`
+impl fmt::Display for Ipv4Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix)
+    }
+}
+
+impl core::str::FromStr for Ipv4Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        let (address, prefix) = s.split_once('/').ok_or(())?;
+        let address = address.parse().map_err(|_| ())?;
+        let prefix = prefix.parse().map_err(|_| ())?;
+        if prefix > 32 {
+            return Err(());
+        }
+        Ok(Self { address, prefix })
+    }
+}
+
+/// See [`LOG_QUEUE`]
+struct LogQueue {
+    records: VecDeque<Vec<u8>>,
+    /// Records that found the queue full since the last [`flush_log`]
+    dropped: u32,
+}
+
+/// An IPv4 packet for us that the interface does not handle itself
+struct Packet {
+    source: Ipv4Addr,
+    protocol: u8,
+    payload: Vec<u8>,
+}
+
+/// A [`Nic`] with an address
+pub struct Interface {
+    nic: Box<dyn Nic>,
+    mac: MacAddress,
+    cidr: Option<Ipv4Cidr>,
+    gateway: Option<Ipv4Addr>,
+    arp: arp::Cache,
+    /// Identification of the next IPv4 packet
+    next_id: u16,
+}
+
+impl Interface {
+    /// Addresses from [`fox_config`]
+    pub fn new(nic: Box<dyn Nic>) -> Self {
+        let config = fox_config::config();
+        Self {
+            mac: nic.mac(),
+            nic,
+            cidr: config.ip,
+            gateway: config.gateway,
+            arp: arp::Cache::default(),
+            next_id: 0,
+        }
+    }
+
+    fn address(&self) -> Result<Ipv4Addr, Error> {
+        self.cidr.map(|i| i.address).ok_or(Error::NoAddress)
+    }
+
+    /// Where to send a packet for `destination`
+    fn next_hop(&self, destination: Ipv4Addr) -> Result<Ipv4Addr, Error> {
+        let cidr = self.cidr.ok_or(Error::NoAddress)?;
+        if cidr.contains(destination) || destination.is_broadcast() {
+            Ok(destination)
+        } else {
+            self.gateway.ok_or(Error::NoRoute)
+        }
+    }
+
+    /// The MAC of `next_hop` if known, broadcasts need no ARP
+    fn lookup(&self, next_hop: Ipv4Addr) -> Option<MacAddress> {
+        let is_broadcast = next_hop.is_broadcast()
+            || self
+                .cidr
+                .is_some_and(|i| i.prefix < 31 && i.broadcast() == next_hop);
+        if is_broadcast {
+            Some(MacAddress::BROADCAST)
+        } else {
+            self.arp.lookup(next_hop)
+        }
+    }
+
+    fn send_frame(
+        &mut self,
+        destination: MacAddress,
+        ethertype: u16,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let mut frame = Vec::with_capacity(ethernet::HEADER_SIZE + payload.len());
+        ethernet::write_header(&mut frame, destination, self.mac, ethertype);
+        frame.extend_from_slice(payload);
+        if frame.len() < ethernet::MIN_FRAME_SIZE {
+            frame.resize(ethernet::MIN_FRAME_SIZE, 0);
+        }
+        self.nic.transmit(&frame)?;
+        Ok(())
+    }
+
+    fn send_arp_request(&mut self, target: Ipv4Addr) -> Result<(), Error> {
+        let request = arp::Packet {
+            operation: arp::REQUEST,
+            sender_mac: self.mac,
+            sender_ip: self.address()?,
+            target_mac: MacAddress::ZERO,
+            target_ip: target,
+        };
+        self.send_frame(
+            MacAddress::BROADCAST,
+            ethernet::ETHERTYPE_ARP,
+            &request.to_bytes(),
+        )
+    }
+
+    /// The next hop must be in the ARP cache
+    fn send_ipv4(
+        &mut self,
+        destination: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let next_hop = self.next_hop(destination)?;
+        let mac = self.lookup(next_hop).ok_or(Error::Unreachable(next_hop))?;
+        let header = ipv4::Header {
+            id: self.next_id,
+            protocol,
+            source: self.address()?,
+            destination,
+        };
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = Vec::with_capacity(ipv4::HEADER_SIZE + payload.len());
+        header.write(&mut packet, payload.len());
+        packet.extend_from_slice(payload);
+        self.send_frame(mac, ethernet::ETHERTYPE_IPV4, &packet)
+    }
+
+    /// The next hop must be in the ARP cache
+    fn send_udp(
+        &mut self,
+        destination: SocketAddrV4,
+        source_port: u16,
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        let header = udp::Header {
+            source: SocketAddrV4::new(self.address()?, source_port),
+            destination,
+        };
+        let mut datagram = Vec::with_capacity(udp::HEADER_SIZE + payload.len());
+        header.write(&mut datagram, payload);
+        self.send_ipv4(*destination.ip(), ipv4::PROTOCOL_UDP, &datagram)
+    }
+
+    /// Handle one received frame, ARP and echo requests are answered here
+    fn poll(&mut self) -> Result<Option<Packet>, Error> {
+        let mut buffer = [0; ethernet::MAX_FRAME_SIZE];
+        let Some(length) = self.nic.receive(&mut buffer)? else {
+            return Ok(None);
+        };
+        let Some((ethertype, payload)) = ethernet::parse(&buffer[..length]) else {
+            return Ok(None);
+        };
+        match ethertype {
+            ethernet::ETHERTYPE_ARP => {
+                if let Some(packet) = arp::Packet::parse(payload) {
+                    self.handle_arp(packet)?;
+                }
+                Ok(None)
+            }
+            ethernet::ETHERTYPE_IPV4 => {
+                let Some((header, payload)) = ipv4::parse(payload) else {
+                    return Ok(None);
+                };
+                if self.cidr.is_none_or(|i| i.address != header.destination) {
+                    return Ok(None);
+                }
+                if header.protocol == ipv4::PROTOCOL_ICMP
+                    && let Some(reply) = icmp::echo_reply(payload)
+                {
+                    self.send_ipv4(header.source, ipv4::PROTOCOL_ICMP, &reply)?;
+                    return Ok(None);
+                }
+                Ok(Some(Packet {
+                    source: header.source,
+                    protocol: header.protocol,
+                    payload: Vec::from(payload),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn handle_arp(&mut self, packet: arp::Packet) -> Result<(), Error> {
+        let Some(cidr) = self.cidr else {
+            return Ok(());
+        };
+        if packet.target_ip != cidr.address {
+            return Ok(());
+        }
+        self.arp.insert(packet.sender_ip, packet.sender_mac);
+        if packet.operation == arp::REQUEST {
+            let reply = arp::Packet {
+                operation: arp::REPLY,
+                sender_mac: self.mac,
+                sender_ip: cidr.address,
+                target_mac: packet.sender_mac,
+                target_ip: packet.sender_ip,
+            };
+            self.send_frame(
+                packet.sender_mac,
+                ethernet::ETHERTYPE_ARP,
+                &reply.to_bytes(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Bring up the first firmware NIC with a link, needs boot services
+pub fn init() -> Result<(), Error> {
+    // log::trace!("fox_net::init");
+
+    let handles = UefiSnp::handles().map_err(|err| DriverError::Firmware(err.status()))?;
+    let mut last_error = Error::NoInterface;
+    for &handle in handles.iter() {
+        match UefiSnp::open(handle) {
+            Ok(snp) if snp.is_media_present() => {
+                log::info!("Net: {} {}", snp, snp.mac());
+                set_interface(Interface::new(Box::new(snp)));
+                return Ok(());
+            }
+            Ok(snp) => log::debug!("Net: {} has no link", snp),
+            Err(err) => {
+                log::debug!("Net: SNP {:?}: {}", handle, err);
+                last_error = Error::Nic(DriverError::Firmware(err.status()));
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Take over the network with `interface`, the previous one is dropped
+///
+/// Resolves the `netconsole=` host, [`send_log`] does not wait for ARP.
+pub fn set_interface(interface: Interface) {
+    let cidr = interface.cidr;
+    if let Err(err) = with_raw_interface(|current| *current = Some(interface)) {
+        log::warn!("Net: {}", err);
+        return;
+    }
+    match cidr {
+        Some(cidr) => log::info!("Net: {}", cidr),
+        None => log::info!("Net: no address, set ip= in the configuration"),
+    }
+    if cidr.is_some()
+        && let Some(netconsole) = fox_config::config().netconsole
+        && let Err(err) = resolve(*netconsole.ip())
+    {
+        log::warn!("Net: netconsole {}: {}", netconsole, err);
+    }
+}
+
+/// Drop the interface, before its NIC goes away
+pub fn shutdown() {
+    // log::trace!("fox_net::shutdown");
+
+    flush_log();
+    match with_raw_interface(|current| current.take()) {
+        Ok(interface) => drop(interface),
+        Err(err) => log::warn!("Net: {}", err),
+    }
+}
+
+/// The MAC of the next hop to `address`, from the cache or by ARP
+pub fn resolve(address: Ipv4Addr) -> Result<MacAddress, Error> {
+    // log::trace!("fox_net::resolve({})", address);
+
+    let next_hop = with_interface(|interface| interface.next_hop(address))?;
+    for _ in 0..ARP_ATTEMPTS {
+        let mac = with_interface(|interface| {
+            if let Some(mac) = interface.lookup(next_hop) {
+                return Ok(Some(mac));
+            }
+            interface.send_arp_request(next_hop).map(|()| None)
+        })?;
+        if let Some(mac) = mac {
+            return Ok(mac);
+        }
+        let mac = poll_timeout(ARP_TIMEOUT, || {
+            with_interface(|interface| {
+                interface.poll()?;
+                Ok(interface.arp.lookup(next_hop))
+            })
+            .transpose()
+        });
+        if let Some(mac) = mac {
+            return mac;
+        }
+    }
+    Err(Error::Unreachable(next_hop))
+}
+
+/// Send an echo request with `sequence`, the round trip time of the reply
+pub fn ping(address: Ipv4Addr, sequence: u16, timeout: Duration) -> Result<Duration, Error> {
+    // log::trace!("fox_net::ping({}, {})", address, sequence);
+
+    resolve(address)?;
+    let request = icmp::echo_request(ECHO_ID, sequence, b"my-uefi-app ping");
+    let start = now();
+    with_interface(|interface| interface.send_ipv4(address, ipv4::PROTOCOL_ICMP, &request))?;
+    let reply = poll_timeout(timeout, || {
+        with_interface(|interface| {
+            let Some(packet) = interface.poll()? else {
+                return Ok(None);
+            };
+            let is_reply = packet.source == address
+                && packet.protocol == ipv4::PROTOCOL_ICMP
+                && icmp::parse_echo_reply(&packet.payload) == Some((ECHO_ID, sequence));
+            Ok(is_reply.then_some(()))
+        })
+        .transpose()
+    });
+    match reply {
+        Some(result) => result.map(|()| start.elapsed()),
+        None => Err(Error::Timeout),
+    }
+}
+
+/// Send a datagram from our `source_port`, resolving the next hop first
+pub fn send_udp(destination: SocketAddrV4, source_port: u16, payload: &[u8]) -> Result<(), Error> {
+    resolve(*destination.ip())?;
+    with_interface(|interface| interface.send_udp(destination, source_port, payload))
+}
+
+/// The next datagram to our `port` and where it came from, others are dropped
+pub fn receive_udp(port: u16, timeout: Duration) -> Result<(SocketAddrV4, Vec<u8>), Error> {
+    let datagram = poll_timeout(timeout, || {
+        with_interface(|interface| {
+            let Some(packet) = interface.poll()? else {
+                return Ok(None);
+            };
+            if packet.protocol != ipv4::PROTOCOL_UDP {
+                return Ok(None);
+            }
+            let address = interface.address()?;
+            let Some((header, payload)) = udp::parse(packet.source, address, &packet.payload)
+            else {
+                return Ok(None);
+            };
+            if header.destination.port() != port {
+                return Ok(None);
+            }
+            Ok(Some((header.source, Vec::from(payload))))
+        })
+        .transpose()
+    });
+    datagram.unwrap_or(Err(Error::Timeout))
+}
+
+/// A local port for a new exchange, not reused until the others have been handed out
+pub fn ephemeral_port() -> u16 {
+    let offset = NEXT_PORT.fetch_add(1, Ordering::Relaxed) % (u16::MAX - EPHEMERAL_PORTS);
+    EPHEMERAL_PORTS + offset
+}
+
+/// Queue `text` for the `netconsole=` host, [`flush_log`] sends it
+///
+/// Called by [`crate::fox_log`] for every record, so it must not log. Sending right away would
+/// run the NIC from wherever the record was logged, IRQ handlers and the NIC drivers included.
+pub fn send_log(text: &str) {
+    if fox_config::config().netconsole.is_none() {
+        return;
+    }
+    let text = &text.as_bytes()[..text.len().min(udp::MAX_PAYLOAD_SIZE)];
+    let mut queue = LOG_QUEUE.lock();
+    if queue.records.len() < LOG_QUEUE_SIZE {
+        queue.records.push_back(Vec::from(text));
+    } else {
+        queue.dropped += 1;
+    }
+}
+
+/// Send the records queued by [`send_log`], those that cannot go out right away are dropped
+///
+/// Called by the event loop every round and before the interface is shut down.
+pub fn flush_log() {
+    let Some(destination) = fox_config::config().netconsole else {
+        return;
+    };
+    loop {
+        // not held while sending, the NIC may log and interrupts stay enabled
+        let record = LOG_QUEUE.lock().records.pop_front();
+        let Some(record) = record else {
+            break;
+        };
+        let _ =
+            with_interface(|interface| interface.send_udp(destination, NETCONSOLE_PORT, &record));
+    }
+    let dropped = mem::take(&mut LOG_QUEUE.lock().dropped);
+    if dropped > 0 {
+        log::warn!("Net: {} log records dropped, the queue was full", dropped);
+    }
+}
+
+/// Run `f` on the interface, must not be called from `f`
+fn with_interface<R>(f: impl FnOnce(&mut Interface) -> Result<R, Error>) -> Result<R, Error> {
+    with_raw_interface(|current| match current {
+        Some(interface) => f(interface),
+        None => Err(Error::NoInterface),
+    })?
+}
+
+/// Run `f` on [`INTERFACE`] with interrupts left as they are, [`Error::Busy`] if it is taken
+fn with_raw_interface<R>(f: impl FnOnce(&mut Option<Interface>) -> R) -> Result<R, Error> {
+    if IS_BUSY
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        return Err(Error::Busy);
+    }
+    let interface = &raw mut INTERFACE;
+    // SAFETY: we own `IS_BUSY`, so nobody else has a reference
+    let interface = unsafe { &mut *interface };
+    let result = f(interface);
+    IS_BUSY.store(false, Ordering::Release);
+    Ok(result)
+}
@@ -0,0 +1,97 @@
+//! ARP for IPv4 over Ethernet, and the cache of its answers
+
+use core::net::Ipv4Addr;
+
+use super::MacAddress;
+use crate::fox_vec::FixedVec;
+
+pub const REQUEST: u16 = 1;
+pub const REPLY: u16 = 2;
+
+const HARDWARE_ETHERNET: u16 = 1;
+const PACKET_SIZE: usize = 28;
+/// Entries in the cache, then the oldest is replaced
+const CACHE_SIZE: usize = 16;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Packet {
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl Packet {
+    /// `None` if not for IPv4 over Ethernet
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; PACKET_SIZE] = bytes.get(..PACKET_SIZE)?.try_into().ok()?;
+        let hardware = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let protocol = u16::from_be_bytes([bytes[2], bytes[3]]);
+        if hardware != HARDWARE_ETHERNET
+            || protocol != super::ethernet::ETHERTYPE_IPV4
+            || bytes[4] != 6
+            || bytes[5] != 4
+        {
+            return None;
+        }
+        let mac = |offset: usize| MacAddress(bytes[offset..offset + 6].try_into().unwrap());
+        let ip = |offset: usize| {
+            Ipv4Addr::new(
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            )
+        };
+        Some(Self {
+            operation: u16::from_be_bytes([bytes[6], bytes[7]]),
+            sender_mac: mac(8),
+            sender_ip: ip(14),
+            target_mac: mac(18),
+            target_ip: ip(24),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; PACKET_SIZE] {
+        let mut bytes = [0; PACKET_SIZE];
+        bytes[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        bytes[2..4].copy_from_slice(&super::ethernet::ETHERTYPE_IPV4.to_be_bytes());
+        bytes[4] = 6;
+        bytes[5] = 4;
+        bytes[6..8].copy_from_slice(&self.operation.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac.0);
+        bytes[14..18].copy_from_slice(&self.sender_ip.octets());
+        bytes[18..24].copy_from_slice(&self.target_mac.0);
+        bytes[24..28].copy_from_slice(&self.target_ip.octets());
+        bytes
+    }
+}
+
+/// Addresses learned from ARP packets sent to us, never expire
+#[derive(Default)]
+pub struct Cache {
+    entries: FixedVec<(Ipv4Addr, MacAddress), CACHE_SIZE>,
+    /// Replaced next once full
+    oldest: usize,
+}
+
+impl Cache {
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddress> {
+        self.entries
+            .iter()
+            .find(|(i, _)| *i == ip)
+            .map(|&(_, mac)| mac)
+    }
+
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddress) {
+        if let Some(entry) = self.entries.iter_mut().find(|(i, _)| *i == ip) {
+            entry.1 = mac;
+            return;
+        }
+        if let Err(entry) = self.entries.push((ip, mac)) {
+            self.entries[self.oldest] = entry;
+            self.oldest = (self.oldest + 1) % CACHE_SIZE;
+        }
+    }
+}
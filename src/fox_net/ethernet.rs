@@ -0,0 +1,51 @@
+//! Ethernet II frames, without the FCS which the NIC adds and strips
+
+use alloc::vec::Vec;
+use core::fmt;
+
+pub const HEADER_SIZE: usize = 14;
+/// Shorter frames are padded with zeros
+pub const MIN_FRAME_SIZE: usize = 60;
+/// 1500 bytes MTU plus the header
+pub const MAX_FRAME_SIZE: usize = 1514;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const ZERO: Self = Self([0; 6]);
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+}
+
+/// `52:54:00:12:34:56`
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ":")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn write_header(
+    frame: &mut Vec<u8>,
+    destination: MacAddress,
+    source: MacAddress,
+    ethertype: u16,
+) {
+    frame.extend_from_slice(&destination.0);
+    frame.extend_from_slice(&source.0);
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+}
+
+/// EtherType and payload, `None` if too short
+pub fn parse(frame: &[u8]) -> Option<(u16, &[u8])> {
+    let (header, payload) = frame.split_at_checked(HEADER_SIZE)?;
+    Some((u16::from_be_bytes([header[12], header[13]]), payload))
+}
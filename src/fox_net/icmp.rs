@@ -0,0 +1,47 @@
+//! ICMP echo request and reply, nothing else
+
+use alloc::vec::Vec;
+
+use super::ipv4::checksum;
+
+const TYPE_ECHO_REPLY: u8 = 0;
+const TYPE_ECHO_REQUEST: u8 = 8;
+const HEADER_SIZE: usize = 8;
+
+pub fn echo_request(id: u16, sequence: u16, data: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + data.len());
+    message.extend_from_slice(&[TYPE_ECHO_REQUEST, 0, 0, 0]);
+    message.extend_from_slice(&id.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(data);
+    set_checksum(&mut message);
+    message
+}
+
+/// The reply to `message` if it is a valid echo request
+pub fn echo_reply(message: &[u8]) -> Option<Vec<u8>> {
+    if message.len() < HEADER_SIZE || message[0] != TYPE_ECHO_REQUEST || checksum(message) != 0 {
+        return None;
+    }
+    let mut reply = Vec::from(message);
+    reply[0] = TYPE_ECHO_REPLY;
+    set_checksum(&mut reply);
+    Some(reply)
+}
+
+/// Identifier and sequence number of a valid echo reply
+pub fn parse_echo_reply(message: &[u8]) -> Option<(u16, u16)> {
+    if message.len() < HEADER_SIZE || message[0] != TYPE_ECHO_REPLY || checksum(message) != 0 {
+        return None;
+    }
+    Some((
+        u16::from_be_bytes([message[4], message[5]]),
+        u16::from_be_bytes([message[6], message[7]]),
+    ))
+}
+
+fn set_checksum(message: &mut [u8]) {
+    message[2..4].fill(0);
+    let checksum = checksum(message);
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
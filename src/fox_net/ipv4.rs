@@ -0,0 +1,80 @@
+//! IPv4 headers without options, fragments are dropped
+
+use alloc::vec::Vec;
+use core::net::Ipv4Addr;
+
+pub const HEADER_SIZE: usize = 20;
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_UDP: u8 = 17;
+
+/// Version 4, 5 words of header
+const VERSION_IHL: u8 = 0x45;
+const FLAG_DONT_FRAGMENT: u16 = 1 << 14;
+const FLAG_MORE_FRAGMENTS: u16 = 1 << 13;
+const TTL: u8 = 64;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub id: u16,
+    pub protocol: u8,
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+}
+
+impl Header {
+    /// Append the header of a packet with `payload_size` bytes after it
+    pub fn write(&self, packet: &mut Vec<u8>, payload_size: usize) {
+        let mut header = [0; HEADER_SIZE];
+        header[0] = VERSION_IHL;
+        header[2..4].copy_from_slice(&((HEADER_SIZE + payload_size) as u16).to_be_bytes());
+        header[4..6].copy_from_slice(&self.id.to_be_bytes());
+        header[6..8].copy_from_slice(&FLAG_DONT_FRAGMENT.to_be_bytes());
+        header[8] = TTL;
+        header[9] = self.protocol;
+        header[12..16].copy_from_slice(&self.source.octets());
+        header[16..20].copy_from_slice(&self.destination.octets());
+        let checksum = checksum(&header);
+        header[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(&header);
+    }
+}
+
+/// Header and payload of a valid, unfragmented packet
+pub fn parse(packet: &[u8]) -> Option<(Header, &[u8])> {
+    let header = packet.get(..HEADER_SIZE)?;
+    if header[0] >> 4 != 4 {
+        return None;
+    }
+    let header_size = (header[0] & 0x0F) as usize * 4;
+    let total_size = u16::from_be_bytes([header[2], header[3]]) as usize;
+    let flags = u16::from_be_bytes([header[6], header[7]]);
+    if header_size < HEADER_SIZE
+        || total_size < header_size
+        || total_size > packet.len()
+        || checksum(&packet[..header_size]) != 0
+        || flags & FLAG_MORE_FRAGMENTS != 0
+        || flags & 0x1FFF != 0
+    {
+        return None;
+    }
+    let header = Header {
+        id: u16::from_be_bytes([header[4], header[5]]),
+        protocol: header[9],
+        source: Ipv4Addr::new(header[12], header[13], header[14], header[15]),
+        destination: Ipv4Addr::new(header[16], header[17], header[18], header[19]),
+    };
+    Some((header, &packet[header_size..total_size]))
+}
+
+/// Internet checksum (RFC 1071), zero when run over data including a valid checksum
+pub fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)]) as u32)
+        .sum::<u32>();
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
@@ -0,0 +1,111 @@
+//! UEFI Simple Network protocol as a [`Nic`]
+//!
+//! Only while boot services are active.
+
+use core::fmt;
+use core::time::Duration;
+
+use uefi::boot::{
+    HandleBuffer, ScopedProtocol, SearchType, locate_handle_buffer, open_protocol_exclusive,
+};
+use uefi::proto::network::snp::{NetworkState, ReceiveFlags, SimpleNetwork};
+use uefi::{Handle, Status};
+
+use super::{MacAddress, Nic};
+use crate::drivers::DriverError;
+use crate::fox_time::poll_timeout;
+
+/// Until the NIC hands back a transmitted buffer
+const TRANSMIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// NIC driven by the firmware
+pub struct UefiSnp {
+    handle: Handle,
+    protocol: ScopedProtocol<SimpleNetwork>,
+    mac: MacAddress,
+}
+
+impl UefiSnp {
+    /// Handles supporting the Simple Network protocol
+    pub fn handles() -> uefi::Result<HandleBuffer> {
+        locate_handle_buffer(SearchType::from_proto::<SimpleNetwork>())
+    }
+
+    /// Open the protocol exclusively, the firmware network stack would take our packets
+    pub fn open(handle: Handle) -> uefi::Result<Self> {
+        // log::trace!("UefiSnp::open()");
+
+        let protocol = open_protocol_exclusive::<SimpleNetwork>(handle)?;
+        if protocol.mode().state == NetworkState::STOPPED {
+            protocol.start()?;
+        }
+        if protocol.mode().state == NetworkState::STARTED {
+            protocol.initialize(0, 0)?;
+        }
+        protocol.receive_filters(
+            ReceiveFlags::UNICAST | ReceiveFlags::BROADCAST,
+            ReceiveFlags::empty(),
+            false,
+            None,
+        )?;
+
+        let mut mac = MacAddress::ZERO;
+        mac.0
+            .copy_from_slice(&protocol.mode().current_address.0[..6]);
+        Ok(Self {
+            handle,
+            protocol,
+            mac,
+        })
+    }
+
+    /// A cable is plugged in, or the NIC cannot tell
+    pub fn is_media_present(&self) -> bool {
+        let mode = self.protocol.mode();
+        !mode.media_present_supported || mode.media_present
+    }
+}
+
+impl Nic for UefiSnp {
+    fn mac(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), DriverError> {
+        self.protocol
+            .transmit(0, frame, None, None, None)
+            .map_err(|err| DriverError::Firmware(err.status()))?;
+        // the frame must stay put until the NIC is done with it
+        poll_timeout(TRANSMIT_TIMEOUT, || {
+            match self.protocol.get_recycled_transmit_buffer_status() {
+                Ok(Some(_)) => Some(Ok(())),
+                Ok(None) => None,
+                Err(err) => Some(Err(DriverError::Firmware(err.status()))),
+            }
+        })
+        .unwrap_or(Err(DriverError::Timeout))
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, DriverError> {
+        match self.protocol.receive(buffer, None, None, None, None) {
+            Ok(length) => Ok(Some(length)),
+            Err(err) if err.status() == Status::NOT_READY => Ok(None),
+            Err(err) => Err(DriverError::Firmware(err.status())),
+        }
+    }
+}
+
+impl Drop for UefiSnp {
+    fn drop(&mut self) {
+        // log::trace!("UefiSnp::drop()");
+
+        let _ = self.protocol.shutdown();
+        let _ = self.protocol.stop();
+    }
+}
+
+impl fmt::Display for UefiSnp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SNP {:?}", self.handle)
+    }
+}
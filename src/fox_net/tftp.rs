@@ -0,0 +1,139 @@
+//! TFTP client, read requests in octet mode only
+//!
+//! The server answers from a port of its own, the transfer ID, and every data block is
+//! acknowledged before the next one is sent. A lost packet is sent again after a timeout.
+
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+use core::time::Duration;
+
+use uefi::{CStr16, Status};
+
+use super::{Error, ephemeral_port, receive_udp, send_udp};
+use crate::fox_bootlog;
+
+const SERVER_PORT: u16 = 69;
+const BLOCK_SIZE: usize = 512;
+/// Files are kept in memory
+const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+/// Retransmissions of one packet before giving up
+const RETRIES: u32 = 5;
+
+const OPCODE_RRQ: u16 = 1;
+const OPCODE_DATA: u16 = 3;
+const OPCODE_ACK: u16 = 4;
+const OPCODE_ERROR: u16 = 5;
+
+/// Our error code for the server when giving up on a file
+const ERROR_DISK_FULL: u16 = 3;
+
+/// Fetch `file` from `server`
+pub fn get(server: Ipv4Addr, file: &str) -> Result<Vec<u8>, Error> {
+    // log::trace!("tftp::get({}, {})", server, file);
+
+    let port = ephemeral_port();
+    let mut request = Vec::with_capacity(file.len() + 9);
+    request.extend_from_slice(&OPCODE_RRQ.to_be_bytes());
+    request.extend_from_slice(file.as_bytes());
+    request.extend_from_slice(b"\0octet\0");
+
+    let mut data = Vec::new();
+    // the server's transfer ID is unknown until the first block
+    let mut peer = SocketAddrV4::new(server, SERVER_PORT);
+    let mut is_connected = false;
+    let mut last_sent = request;
+    let mut block: u16 = 1;
+    let mut retries = 0;
+    send_udp(peer, port, &last_sent)?;
+    loop {
+        let (source, packet) = match receive_udp(port, TIMEOUT) {
+            Err(Error::Timeout) if retries < RETRIES => {
+                retries += 1;
+                send_udp(peer, port, &last_sent)?;
+                continue;
+            }
+            result => result?,
+        };
+        if *source.ip() != server || (is_connected && source != peer) || packet.len() < 4 {
+            continue;
+        }
+        let opcode = u16::from_be_bytes([packet[0], packet[1]]);
+        let number = u16::from_be_bytes([packet[2], packet[3]]);
+        match opcode {
+            OPCODE_DATA if number == block => {
+                peer = source;
+                is_connected = true;
+                let payload = &packet[4..];
+                if data.len() + payload.len() > MAX_FILE_SIZE {
+                    let _ = send_udp(peer, port, &error_packet(ERROR_DISK_FULL, "too large"));
+                    return Err(Error::TooLarge);
+                }
+                data.extend_from_slice(payload);
+                last_sent = ack_packet(block);
+                send_udp(peer, port, &last_sent)?;
+                if payload.len() < BLOCK_SIZE {
+                    return Ok(data);
+                }
+                block = block.wrapping_add(1);
+                retries = 0;
+            }
+            // our ACK was lost, the server sent the block again
+            OPCODE_DATA if number == block.wrapping_sub(1) => send_udp(peer, port, &last_sent)?,
+            OPCODE_ERROR => {
+                let message = packet[4..].split(|&i| i == 0).next().unwrap_or(&[]);
+                log::debug!(
+                    "TFTP {}: {}",
+                    server,
+                    core::str::from_utf8(message).unwrap_or("?")
+                );
+                return Err(Error::Tftp(number));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Fetch `file` from `server` and write it next to the boot log, its size
+///
+/// The name on the ESP is the last component of `file`. Needs boot services.
+pub fn save(server: Ipv4Addr, file: &str) -> Result<usize, Error> {
+    let name = file.rsplit(['/', '\\']).next().unwrap_or(file);
+    let mut buffer = [0; 64];
+    let name = CStr16::from_str_with_buf(name, &mut buffer)
+        .map_err(|_| Error::Save(Status::INVALID_PARAMETER))?;
+    let data = get(server, file)?;
+    fox_bootlog::write_file(name, &data).map_err(|err| Error::Save(err.status()))?;
+    Ok(data.len())
+}
+
+/// Meaning of an error code, from RFC 1350
+pub fn error_name(code: u16) -> &'static str {
+    match code {
+        1 => "file not found",
+        2 => "access violation",
+        3 => "disk full or allocation exceeded",
+        4 => "illegal TFTP operation",
+        5 => "unknown transfer ID",
+        6 => "file already exists",
+        7 => "no such user",
+        _ => "not defined",
+    }
+}
+
+fn ack_packet(block: u16) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(4);
+    packet.extend_from_slice(&OPCODE_ACK.to_be_bytes());
+    packet.extend_from_slice(&block.to_be_bytes());
+    packet
+}
+
+fn error_packet(code: u16, message: &str) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(message.len() + 5);
+    packet.extend_from_slice(&OPCODE_ERROR.to_be_bytes());
+    packet.extend_from_slice(&code.to_be_bytes());
+    packet.extend_from_slice(message.as_bytes());
+    packet.push(0);
+    packet
+}
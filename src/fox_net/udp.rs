@@ -0,0 +1,71 @@
+//! UDP datagrams, checksummed over the IPv4 pseudo header
+
+use alloc::vec::Vec;
+use core::net::{Ipv4Addr, SocketAddrV4};
+
+use super::{ethernet, ipv4};
+
+pub const HEADER_SIZE: usize = 8;
+/// Largest payload that fits one unfragmented packet
+pub const MAX_PAYLOAD_SIZE: usize =
+    ethernet::MAX_FRAME_SIZE - ethernet::HEADER_SIZE - ipv4::HEADER_SIZE - HEADER_SIZE;
+
+#[derive(Copy, Clone, Debug)]
+pub struct Header {
+    pub source: SocketAddrV4,
+    pub destination: SocketAddrV4,
+}
+
+impl Header {
+    /// Append the header and `payload`
+    pub fn write(&self, datagram: &mut Vec<u8>, payload: &[u8]) {
+        let length = (HEADER_SIZE + payload.len()) as u16;
+        let start = datagram.len();
+        datagram.extend_from_slice(&self.source.port().to_be_bytes());
+        datagram.extend_from_slice(&self.destination.port().to_be_bytes());
+        datagram.extend_from_slice(&length.to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(payload);
+
+        // zero means no checksum
+        let checksum = match checksum(
+            *self.source.ip(),
+            *self.destination.ip(),
+            &datagram[start..],
+        ) {
+            0 => 0xFFFF,
+            checksum => checksum,
+        };
+        datagram[start + 6..start + 8].copy_from_slice(&checksum.to_be_bytes());
+    }
+}
+
+/// Header and payload of a valid datagram from `source` to `destination`
+pub fn parse(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> Option<(Header, &[u8])> {
+    let header = datagram.get(..HEADER_SIZE)?;
+    let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+    if length < HEADER_SIZE || length > datagram.len() {
+        return None;
+    }
+    let datagram = &datagram[..length];
+    let is_checksummed = header[6..8] != [0, 0];
+    if is_checksummed && checksum(source, destination, datagram) != 0 {
+        return None;
+    }
+    let header = Header {
+        source: SocketAddrV4::new(source, u16::from_be_bytes([header[0], header[1]])),
+        destination: SocketAddrV4::new(destination, u16::from_be_bytes([header[2], header[3]])),
+    };
+    Some((header, &datagram[HEADER_SIZE..]))
+}
+
+/// Internet checksum of the pseudo header and `datagram`
+fn checksum(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut pseudo = Vec::with_capacity(12 + datagram.len());
+    pseudo.extend_from_slice(&source.octets());
+    pseudo.extend_from_slice(&destination.octets());
+    pseudo.extend_from_slice(&[0, ipv4::PROTOCOL_UDP]);
+    pseudo.extend_from_slice(&(datagram.len() as u16).to_be_bytes());
+    pseudo.extend_from_slice(datagram);
+    ipv4::checksum(&pseudo)
+}
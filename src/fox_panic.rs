@@ -0,0 +1,176 @@
+//! Panic handler: location, log tail and registers, then a reboot
+//!
+//! Replaces the `uefi` panic handler, which halts forever. Under QEMU with isa-debug-exit the
+//! VM exits instead, see [`fox_qemu`](crate::fox_qemu).
+
+use core::arch::asm;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use uefi::Status;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use x86_64::registers::rflags;
+
+use crate::fox_arch;
+use crate::fox_bootlog;
+use crate::fox_log::{print, with_buffer};
+use crate::fox_power::{self, Reset};
+use crate::fox_qemu::{self, ExitCode};
+use crate::fox_time::delay;
+use crate::fox_uefi::is_boot_services_active;
+
+/// Bytes of the log buffer printed after the panic message
+const LOG_TAIL: usize = 2048;
+/// Dump the stack and frame pointers, flags and control registers
+///
+/// Not the other general-purpose registers: in the handler they hold its own values, not those
+/// of the panicking code.
+const DUMP_REGISTERS: bool = true;
+/// Without boot services there is no keyboard to wait for
+const REBOOT_DELAY: Duration = Duration::from_secs(10);
+
+/// A panic inside the handler must not recurse
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// host tests use the std panic handler
+#[cfg_attr(not(test), panic_handler)]
+#[cfg_attr(test, allow(dead_code))]
+fn panic(info: &PanicInfo) -> ! {
+    // the firmware keyboard needs its timer interrupt
+    if !is_boot_services_active() {
+        fox_arch::disable_interrupts();
+    }
+    if PANICKING.swap(true, Ordering::AcqRel) {
+        loop {
+            fox_arch::halt();
+        }
+    }
+
+    match info.location() {
+        Some(location) => log::error!(
+            "PANIC at {}:{}:{}: {}",
+            location.file(),
+            location.line(),
+            location.column(),
+            info.message()
+        ),
+        None => log::error!("PANIC: {}", info.message()),
+    }
+
+    print_log_tail();
+    if DUMP_REGISTERS {
+        print_registers();
+    }
+
+    if is_boot_services_active()
+        && let Err(err) = fox_bootlog::save()
+    {
+        log::error!("Saving the boot log failed: {}", err);
+    }
+    // an automated run, nobody waits for the reboot
+    fox_qemu::exit(ExitCode::Panic);
+
+    if is_boot_services_active() {
+        print(format_args!("\nPress any key to reboot\n"));
+        wait_for_key();
+    } else {
+        print(format_args!("\nRebooting in {:?}\n", REBOOT_DELAY));
+        delay(REBOOT_DELAY);
+    }
+
+    fox_power::reset(Reset::Cold, Status::ABORTED, "panic")
+}
+
+fn print_log_tail() {
+    with_buffer(|older, newer| {
+        let skip = (older.len() + newer.len()).saturating_sub(LOG_TAIL);
+        let (older, newer) = if skip < older.len() {
+            (&older[skip..], newer)
+        } else {
+            (&[][..], &newer[skip - older.len()..])
+        };
+
+        print(format_args!(
+            "\n--- last {} bytes of log ---\n",
+            older.len() + newer.len()
+        ));
+        // the cut may split a line, start at the next one
+        let mut in_cut_line = skip > 0;
+        for part in [older, newer] {
+            let mut part = part;
+            if in_cut_line {
+                let Some(pos) = part.iter().position(|&i| i == b'\n') else {
+                    continue;
+                };
+                part = &part[pos + 1..];
+                in_cut_line = false;
+            }
+            // the ring buffer may also split a UTF-8 sequence
+            for chunk in part.utf8_chunks() {
+                print(format_args!("{}", chunk.valid()));
+            }
+        }
+        print(format_args!("--- end of log ---\n"));
+    });
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn print_registers() {
+    let (rsp, rbp): (u64, u64);
+    // SAFETY: only copies registers
+    unsafe {
+        asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    let (cr3, cr3_flags) = Cr3::read_raw();
+
+    print(format_args!(
+        "RSP={:#018X} RBP={:#018X} RFLAGS={:#018X}\n",
+        rsp,
+        rbp,
+        rflags::read_raw()
+    ));
+    print(format_args!(
+        "CR0={:#018X} CR2={:#018X} CR3={:#018X} CR4={:#018X}\n",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        cr3.start_address().as_u64() | cr3_flags as u64,
+        Cr4::read_raw()
+    ));
+}
+
+#[cfg(target_arch = "aarch64")]
+fn print_registers() {
+    let (sp, fp, lr, daif, current_el): (u64, u64, u64, u64, u64);
+    // SAFETY: only copies registers, DAIF and CurrentEL are readable at EL1 and above
+    unsafe {
+        asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+        asm!("mov {}, x30", out(reg) lr, options(nomem, nostack, preserves_flags));
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+        asm!("mrs {}, currentel", out(reg) current_el, options(nomem, nostack, preserves_flags));
+    }
+
+    print(format_args!(
+        "SP={:#018X} FP={:#018X} LR={:#018X}\n",
+        sp, fp, lr
+    ));
+    print(format_args!(
+        "DAIF={:#X} EL{}\n",
+        daif,
+        (current_el >> 2) & 0b11
+    ));
+}
+
+fn wait_for_key() {
+    loop {
+        let key = uefi::system::with_stdin(|stdin| stdin.read_key());
+        if let Ok(Some(_)) = key {
+            return;
+        }
+        delay(Duration::from_millis(10));
+    }
+}
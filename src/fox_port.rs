@@ -0,0 +1,698 @@
+//! I/O ports and memory-mapped registers, every driver access goes through here
+//!
+//! A [`Pio`] port or an [`Mmio`] register has its width and access in its type, reads and
+//! writes are `unsafe`, MMIO ones volatile. Drivers keep an [`MmioBlock`] per register block,
+//! BAR or part of one, and access the registers by their offset, or declare the layout with
+//! [`registers!`] and the bits of a register with [`bitfields!`].
+//!
+//! With [`set_tracing`] every access is logged to the memory sink of [`fox_log`], with the
+//! driver code doing it. A busy poll would push the rest of the log out, past
+//! [`TRACE_LIMIT`] accesses a second they are only counted.
+//!
+//! Only x86 has an I/O space, elsewhere a [`Pio`] reads all ones and ignores writes.
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+use log::{Level, Record};
+
+use crate::fox_io::Space;
+use crate::fox_log;
+use crate::fox_sync::Mutex;
+use crate::fox_time::uptime;
+
+/// Accesses traced per [`TRACE_WINDOW`]
+const TRACE_LIMIT: u32 = 1000;
+const TRACE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Log every access, see [`set_tracing`]
+static IS_TRACING: AtomicBool = AtomicBool::new(false);
+static TRACE_BUDGET: Mutex<TraceBudget> = Mutex::new(TraceBudget::new());
+
+/// Widths a register can be accessed with
+pub trait MmioValue: Copy {
+    /// The value as traced
+    fn bits(self) -> u64;
+}
+
+impl MmioValue for u8 {
+    fn bits(self) -> u64 {
+        self.into()
+    }
+}
+
+impl MmioValue for u16 {
+    fn bits(self) -> u64 {
+        self.into()
+    }
+}
+
+impl MmioValue for u32 {
+    fn bits(self) -> u64 {
+        self.into()
+    }
+}
+
+impl MmioValue for u64 {
+    fn bits(self) -> u64 {
+        self
+    }
+}
+
+/// Widths an I/O port can be accessed with
+pub trait PioValue: MmioValue {
+    /// # Safety
+    /// See [`Pio::read`].
+    unsafe fn read_port(port: u16) -> Self;
+
+    /// # Safety
+    /// See [`Pio::write`].
+    unsafe fn write_port(port: u16, value: Self);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PioValue for u8 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u8;
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "in al, dx",
+                out("al") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "out dx, al",
+                in("dx") port,
+                in("al") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PioValue for u16 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u16;
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "in ax, dx",
+                out("ax") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "out dx, ax",
+                in("dx") port,
+                in("ax") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PioValue for u32 {
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u32;
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "in eax, dx",
+                out("eax") value,
+                in("dx") port,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+        value
+    }
+
+    unsafe fn write_port(port: u16, value: Self) {
+        // SAFETY: by the caller
+        unsafe {
+            asm!(
+                "out dx, eax",
+                in("dx") port,
+                in("eax") value,
+                options(nomem, nostack, preserves_flags)
+            );
+        }
+    }
+}
+
+/// There is no I/O space, reads float high like an absent device and writes go nowhere
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+macro_rules! impl_pio_value_absent {
+    ($($ty:ty),*) => {$(
+        impl PioValue for $ty {
+            unsafe fn read_port(_port: u16) -> Self {
+                <$ty>::MAX
+            }
+
+            unsafe fn write_port(_port: u16, _value: Self) {}
+        }
+    )*};
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+impl_pio_value_absent!(u8, u16, u32);
+
+pub trait MmioReadAccess {}
+pub trait MmioWriteAccess {}
+
+pub struct ReadOnlyAccess;
+pub struct WriteOnlyAccess;
+pub struct ReadWriteAccess;
+
+impl MmioReadAccess for ReadOnlyAccess {}
+impl MmioWriteAccess for WriteOnlyAccess {}
+impl MmioReadAccess for ReadWriteAccess {}
+impl MmioWriteAccess for ReadWriteAccess {}
+
+/// I/O port of width `T`
+pub struct Pio<T, A = ReadWriteAccess> {
+    port: u16,
+    phantom: PhantomData<(T, A)>,
+}
+
+/// Register of width `T` at a virtual address
+pub struct Mmio<T, A = ReadWriteAccess> {
+    address: u64,
+    phantom: PhantomData<(T, A)>,
+}
+
+/// Registers at offsets from a mapped base, no access is checked against its size
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MmioBlock {
+    base: u64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// Rate limit of [`trace`]
+struct TraceBudget {
+    /// Start of the current [`TRACE_WINDOW`]
+    start: Duration,
+    traced: u32,
+    /// Accesses over [`TRACE_LIMIT`] in the current window
+    dropped: u32,
+}
+
+impl<T, A> Pio<T, A> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: PioValue, A: MmioReadAccess> Pio<T, A> {
+    /// # Safety
+    /// Reading the port has no side effect the caller does not expect.
+    #[track_caller]
+    pub unsafe fn read(&self) -> T {
+        // SAFETY: by the caller
+        let value = unsafe { T::read_port(self.port) };
+        trace(
+            Space::Port,
+            Direction::Read,
+            self.port.into(),
+            size_of::<T>(),
+            value.bits(),
+        );
+        value
+    }
+}
+
+impl<T: PioValue, A: MmioWriteAccess> Pio<T, A> {
+    /// # Safety
+    /// The value is one the device accepts.
+    #[track_caller]
+    pub unsafe fn write(&mut self, value: T) {
+        trace(
+            Space::Port,
+            Direction::Write,
+            self.port.into(),
+            size_of::<T>(),
+            value.bits(),
+        );
+        // SAFETY: by the caller
+        unsafe { T::write_port(self.port, value) }
+    }
+}
+
+impl<T: PioValue> Pio<T, ReadWriteAccess> {
+    /// Read, change and write back, not atomic
+    ///
+    /// # Safety
+    /// See [`Self::read`] and [`Self::write`].
+    #[track_caller]
+    pub unsafe fn modify(&mut self, f: impl FnOnce(T) -> T) {
+        // SAFETY: by the caller
+        unsafe {
+            let value = self.read();
+            self.write(f(value));
+        }
+    }
+}
+
+impl<T, A> Mmio<T, A> {
+    pub const fn new(address: u64) -> Self {
+        Self {
+            address,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: MmioValue, A: MmioReadAccess> Mmio<T, A> {
+    /// # Safety
+    /// The register is mapped uncached, reading it has no side effect the caller does not expect.
+    #[track_caller]
+    pub unsafe fn read(&self) -> T {
+        // SAFETY: by the caller
+        let value = unsafe { (self.address as *const T).read_volatile() };
+        trace(
+            Space::Mmio,
+            Direction::Read,
+            self.address,
+            size_of::<T>(),
+            value.bits(),
+        );
+        value
+    }
+}
+
+impl<T: MmioValue, A: MmioWriteAccess> Mmio<T, A> {
+    /// # Safety
+    /// The register is mapped uncached, the value is one the device accepts.
+    #[track_caller]
+    pub unsafe fn write(&mut self, value: T) {
+        trace(
+            Space::Mmio,
+            Direction::Write,
+            self.address,
+            size_of::<T>(),
+            value.bits(),
+        );
+        // SAFETY: by the caller
+        unsafe { (self.address as *mut T).write_volatile(value) }
+    }
+}
+
+impl<A: MmioWriteAccess> Mmio<u64, A> {
+    /// Low dword first, for devices that only take 32-bit accesses
+    ///
+    /// # Safety
+    /// See [`Self::write`].
+    #[track_caller]
+    pub unsafe fn write_dwords(&mut self, value: u64) {
+        let mut low = Mmio::<u32, A>::new(self.address);
+        let mut high = Mmio::<u32, A>::new(self.address + 4);
+        // SAFETY: by the caller
+        unsafe {
+            low.write(value as u32);
+            high.write((value >> 32) as u32);
+        }
+    }
+}
+
+impl<T: MmioValue> Mmio<T, ReadWriteAccess> {
+    /// Read, change and write back, not atomic
+    ///
+    /// # Safety
+    /// See [`Self::read`] and [`Self::write`].
+    #[track_caller]
+    pub unsafe fn modify(&mut self, f: impl FnOnce(T) -> T) {
+        // SAFETY: by the caller
+        unsafe {
+            let value = self.read();
+            self.write(f(value));
+        }
+    }
+}
+
+impl MmioBlock {
+    pub const fn new(base: u64) -> Self {
+        Self { base }
+    }
+
+    pub const fn base(self) -> u64 {
+        self.base
+    }
+
+    /// The block starting at `offset` in this one
+    pub const fn at(self, offset: u64) -> Self {
+        Self::new(self.base + offset)
+    }
+
+    pub const fn register<T, A>(self, offset: u64) -> Mmio<T, A> {
+        Mmio::new(self.base + offset)
+    }
+
+    /// # Safety
+    /// See [`Mmio::read`].
+    #[track_caller]
+    pub unsafe fn read<T: MmioValue>(self, offset: u64) -> T {
+        // SAFETY: by the caller
+        unsafe { self.register::<T, ReadOnlyAccess>(offset).read() }
+    }
+
+    /// # Safety
+    /// See [`Mmio::write`].
+    #[track_caller]
+    pub unsafe fn write<T: MmioValue>(self, offset: u64, value: T) {
+        // SAFETY: by the caller
+        unsafe { self.register::<T, WriteOnlyAccess>(offset).write(value) }
+    }
+}
+
+impl TraceBudget {
+    const fn new() -> Self {
+        Self {
+            start: Duration::ZERO,
+            traced: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Whether an access at `now` is traced, and the accesses dropped in the window it ended
+    fn take(&mut self, now: Duration) -> (bool, u32) {
+        let mut dropped = 0;
+        if now.saturating_sub(self.start) >= TRACE_WINDOW {
+            dropped = self.dropped;
+            *self = Self {
+                start: now,
+                ..Self::new()
+            };
+        }
+        if self.traced < TRACE_LIMIT {
+            self.traced += 1;
+            (true, dropped)
+        } else {
+            self.dropped += 1;
+            (false, dropped)
+        }
+    }
+}
+
+/// Trace the accesses from now on, or stop
+pub fn set_tracing(is_enabled: bool) {
+    // log::trace!("fox_port::set_tracing({})", is_enabled);
+
+    if is_enabled {
+        *TRACE_BUDGET.lock() = TraceBudget::new();
+    }
+    IS_TRACING.store(is_enabled, Ordering::Relaxed);
+}
+
+pub fn is_tracing() -> bool {
+    IS_TRACING.load(Ordering::Relaxed)
+}
+
+/// Log an access of `width` bytes if tracing, with the location it was done from
+///
+/// `MMIO write 0xfebf1000 = 0x00000001 (src/drivers/xhci.rs:871)`
+#[track_caller]
+fn trace(space: Space, direction: Direction, address: u64, width: usize, value: u64) {
+    if !is_tracing() {
+        return;
+    }
+    let caller = Location::caller();
+    let (is_traced, dropped) = TRACE_BUDGET.lock().take(uptime());
+
+    if dropped > 0 {
+        log_trace(format_args!("{} accesses not traced", dropped));
+    }
+    if is_traced {
+        let space = match space {
+            Space::Port => "port",
+            Space::Mmio => "MMIO",
+        };
+        let direction = match direction {
+            Direction::Read => "read",
+            Direction::Write => "write",
+        };
+        log_trace(format_args!(
+            "{} {} {:#x} = {:#0digits$x} ({}:{})",
+            space,
+            direction,
+            address,
+            value,
+            caller.file(),
+            caller.line(),
+            digits = 2 * width + 2
+        ));
+    }
+}
+
+fn log_trace(args: fmt::Arguments) {
+    fox_log::log_to_buffer(
+        &Record::builder()
+            .args(args)
+            .level(Level::Trace)
+            .target(module_path!())
+            .module_path_static(Some(module_path!()))
+            .build(),
+    );
+}
+
+/// A register value with named bits: getters, setters and a `Debug` listing the getters
+///
+/// A field is one bit, read as `bool`, or a range of bits, read as the register type. A setter
+/// name after the getter makes it writable:
+///
+/// ```ignore
+/// bitfields! {
+///     /// USB Command
+///     struct Usbcmd(u32) {
+///         /// Run/Stop
+///         is_running, set_is_running: 0;
+///         /// Interrupter Target
+///         target: 22..32;
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! bitfields {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident($ty:ty) {
+            $(
+                $(#[$field_attr:meta])*
+                $getter:ident $(, $setter:ident)?: $first:literal $(.. $end:literal)?;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Default, PartialEq, Eq)]
+        #[repr(transparent)]
+        $vis struct $name(pub $ty);
+
+        // a layout names the bits whether the driver uses them or not
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $crate::bitfields!(
+                    @field $ty; $(#[$field_attr])* $getter $(, $setter)?: $first $(.. $end)?
+                );
+            )*
+        }
+
+        impl core::fmt::Debug for $name {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_struct(stringify!($name))
+                    $(.field(stringify!($getter), &self.$getter()))*
+                    .finish()
+            }
+        }
+
+        impl From<$name> for $ty {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl $crate::fox_port::MmioValue for $name {
+            fn bits(self) -> u64 {
+                self.0.into()
+            }
+        }
+    };
+    (@field $ty:ty; $(#[$attr:meta])* $getter:ident, $setter:ident: $($bits:tt)+) => {
+        $crate::bitfields!(@field $ty; $(#[$attr])* $getter: $($bits)+);
+        $crate::bitfields!(@setter $ty; $(#[$attr])* $setter: $($bits)+);
+    };
+    (@field $ty:ty; $(#[$attr:meta])* $getter:ident: $bit:literal) => {
+        $(#[$attr])*
+        pub fn $getter(&self) -> bool {
+            ::bit_field::BitField::get_bit(&self.0, $bit)
+        }
+    };
+    (@field $ty:ty; $(#[$attr:meta])* $getter:ident: $start:literal .. $end:literal) => {
+        $(#[$attr])*
+        pub fn $getter(&self) -> $ty {
+            ::bit_field::BitField::get_bits(&self.0, $start..$end)
+        }
+    };
+    (@setter $ty:ty; $(#[$attr:meta])* $setter:ident: $bit:literal) => {
+        $(#[$attr])*
+        pub fn $setter(&mut self, value: bool) {
+            ::bit_field::BitField::set_bit(&mut self.0, $bit, value);
+        }
+    };
+    (@setter $ty:ty; $(#[$attr:meta])* $setter:ident: $start:literal .. $end:literal) => {
+        $(#[$attr])*
+        pub fn $setter(&mut self, value: $ty) {
+            ::bit_field::BitField::set_bits(&mut self.0, $start..$end, value);
+        }
+    };
+}
+
+/// The registers of a block at offsets in an [`MmioBlock`], one [`Mmio`] getter each
+///
+/// A register has an offset, a type, an integer or a [`bitfields!`] value, and an access. An
+/// array of registers has the stride between them, its getter takes the index:
+///
+/// ```ignore
+/// registers! {
+///     /// Operational registers
+///     struct OperationalRegisters {
+///         /// USB Command
+///         usbcmd: 0x00 => Usbcmd, ReadWriteAccess;
+///         /// Port Status and Control, ports numbered from 0
+///         portsc[0x10]: 0x400 => Portsc, ReadWriteAccess;
+///     }
+/// }
+/// ```
+#[macro_export]
+macro_rules! registers {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$register_attr:meta])*
+                $register:ident $([$stride:literal])?: $offset:literal => $ty:ty, $access:ident;
+            )*
+        }
+    ) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+        $vis struct $name(pub $crate::fox_port::MmioBlock);
+
+        // a layout names the registers whether the driver uses them or not
+        #[allow(dead_code)]
+        impl $name {
+            $(
+                $crate::registers!(
+                    @register $(#[$register_attr])* $register $([$stride])?: $offset => $ty, $access
+                );
+            )*
+        }
+    };
+    (@register $(#[$attr:meta])* $register:ident: $offset:literal => $ty:ty, $access:ident) => {
+        $(#[$attr])*
+        pub const fn $register(self) -> $crate::fox_port::Mmio<$ty, $crate::fox_port::$access> {
+            self.0.register($offset)
+        }
+    };
+    (
+        @register $(#[$attr:meta])* $register:ident [$stride:literal]:
+        $offset:literal => $ty:ty, $access:ident
+    ) => {
+        $(#[$attr])*
+        pub const fn $register(
+            self,
+            index: usize,
+        ) -> $crate::fox_port::Mmio<$ty, $crate::fox_port::$access> {
+            self.0.register($offset + index as u64 * $stride)
+        }
+    };
+}
+
+/// `read_register`, `write_register`, `modify_register` and `write_register64` for a driver
+/// whose registers are all in blocks it mapped uncached
+///
+/// The functions are private to the module that expands the macro, the mapping is the safety
+/// argument of each access: a comment at the expansion says where it is done. Accesses are
+/// traced at the caller.
+///
+/// ```ignore
+/// // all registers are in ABAR, mapped uncached by `Ahci::init`
+/// mapped_registers!();
+/// ```
+#[macro_export]
+macro_rules! mapped_registers {
+    () => {
+        #[allow(dead_code)]
+        #[track_caller]
+        fn read_register<T, A>(register: $crate::fox_port::Mmio<T, A>) -> T
+        where
+            T: $crate::fox_port::MmioValue,
+            A: $crate::fox_port::MmioReadAccess,
+        {
+            // SAFETY: mapped uncached, see the expansion of `mapped_registers!`
+            unsafe { register.read() }
+        }
+
+        #[allow(dead_code)]
+        #[track_caller]
+        fn write_register<T, A>(mut register: $crate::fox_port::Mmio<T, A>, value: T)
+        where
+            T: $crate::fox_port::MmioValue,
+            A: $crate::fox_port::MmioWriteAccess,
+        {
+            // SAFETY: see `read_register()`
+            unsafe { register.write(value) }
+        }
+
+        #[allow(dead_code)]
+        #[track_caller]
+        fn modify_register<T: $crate::fox_port::MmioValue>(
+            mut register: $crate::fox_port::Mmio<T>,
+            f: impl FnOnce(T) -> T,
+        ) {
+            // SAFETY: see `read_register()`
+            unsafe { register.modify(f) }
+        }
+
+        /// 64-bit registers, written as two dwords
+        #[allow(dead_code)]
+        #[track_caller]
+        fn write_register64<A: $crate::fox_port::MmioWriteAccess>(
+            mut register: $crate::fox_port::Mmio<u64, A>,
+            value: u64,
+        ) {
+            // SAFETY: see `read_register()`
+            unsafe { register.write_dwords(value) }
+        }
+    };
+}
@@ -0,0 +1,76 @@
+//! Reset and shutdown through the UEFI ResetSystem runtime service
+//!
+//! Runtime services stay available after exiting boot services. The reason goes to the firmware
+//! as ResetData, a UCS-2 string some firmwares log.
+//!
+//! https://uefi.org/specs/UEFI/2.10/08_Services_Runtime_Services.html#resetsystem
+
+use core::fmt;
+
+use uefi::runtime::ResetType;
+use uefi::{Guid, Status};
+
+pub mod battery;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod s3;
+pub mod thermal;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use s3::sleep_s3;
+pub use thermal::temperatures;
+
+/// Characters of the reason passed to the firmware, longer reasons are cut
+const REASON_LEN: usize = 63;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Reset {
+    /// Every circuit reset, as after power on
+    Cold,
+    /// The processors reset, memory may be kept
+    Warm,
+    /// Power off (ACPI S5)
+    Shutdown,
+    /// Reset of a kind the platform defines, identified by a GUID
+    PlatformSpecific(Guid),
+}
+
+/// Reset the machine, log `reason` and pass it to the firmware
+///
+/// `status` is [`Status::SUCCESS`] for an expected reset, an error status otherwise.
+pub fn reset(kind: Reset, status: Status, reason: &str) -> ! {
+    // log::trace!("fox_power::reset({:?}, {:?}, {})", kind, status, reason);
+
+    log::info!("{} ({:?}): {}", kind, status, reason);
+
+    // null-terminated UCS-2, the GUID of a platform specific reset follows
+    let mut data = [0u8; 2 * (REASON_LEN + 1) + size_of::<Guid>()];
+    let mut len = 0;
+    for c in reason.encode_utf16().take(REASON_LEN) {
+        data[len..len + 2].copy_from_slice(&c.to_le_bytes());
+        len += 2;
+    }
+    len += 2;
+
+    let reset_type = match kind {
+        Reset::Cold => ResetType::COLD,
+        Reset::Warm => ResetType::WARM,
+        Reset::Shutdown => ResetType::SHUTDOWN,
+        Reset::PlatformSpecific(guid) => {
+            data[len..len + size_of::<Guid>()].copy_from_slice(&guid.to_bytes());
+            len += size_of::<Guid>();
+            ResetType::PLATFORM_SPECIFIC
+        }
+    };
+    uefi::runtime::reset(reset_type, status, Some(&data[..len]))
+}
+
+impl fmt::Display for Reset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cold => write!(f, "Cold reset"),
+            Self::Warm => write!(f, "Warm reset"),
+            Self::Shutdown => write!(f, "Shutdown"),
+            Self::PlatformSpecific(guid) => write!(f, "Platform specific reset {}", guid),
+        }
+    }
+}
@@ -0,0 +1,262 @@
+//! Battery and AC adapter status
+//!
+//! Without an AML interpreter the \_BST and \_PSR methods of a control method battery (PNP0C0A)
+//! and AC adapter (ACPI0003) cannot be evaluated, their EC fields are vendor specific. A Smart
+//! Battery System (ACPI0002) is read directly instead: its battery and charger answer standard
+//! SMBus commands, sent through the SMBus host controller (ACPI0001) inside the EC.
+//!
+//! https://uefi.org/specs/ACPI/6.5/10_Power_Source_and_Power_Meter_Devices.html
+//! https://uefi.org/specs/ACPI/6.5/12_ACPI_Embedded_Controller_Interface_Specification.html#smbus-host-controller-interface-via-embedded-controller
+//! http://sbs-forum.org/specs/sbdat110.pdf
+
+use core::fmt;
+use core::time::Duration;
+
+use bit_field::BitField;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::AcpiEc;
+use crate::drivers::DriverError;
+use crate::fox_aml::{self, NodeKind, Value};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_time::poll_timeout;
+
+/// Hardware IDs
+const HID_CONTROL_METHOD_BATTERY: &str = "PNP0C0A";
+const HID_AC_ADAPTER: &str = "ACPI0003";
+const HID_SMART_BATTERY_SYSTEM: &str = "ACPI0002";
+const HID_SMBUS_HOST_CONTROLLER: &str = "ACPI0001";
+
+/// SMBus transactions take a few ms, the EC firmware may be slower
+const TIMEOUT_SMBUS: Duration = Duration::from_millis(100);
+
+// SMBus host controller registers, from the offset in \_EC
+const SMB_PRTCL: u8 = 0x00;
+const SMB_STS: u8 = 0x01;
+const SMB_ADDR: u8 = 0x02;
+const SMB_CMD: u8 = 0x03;
+const SMB_DATA: u8 = 0x04;
+/// Registers up to SMB_ALRM_DATA
+const SMB_SIZE: u8 = 0x28;
+
+/// SMB_PRTCL of a Read Word transaction
+const PROTOCOL_READ_WORD: u8 = 0x09;
+/// SMB_STS: transaction done, bits 0-4 the error code
+const STATUS_DONE: usize = 7;
+
+// SMBus addresses
+const ADDRESS_CHARGER: u8 = 0x09;
+const ADDRESS_BATTERY: u8 = 0x0B;
+
+// Smart Battery Data commands
+const SBD_BATTERY_MODE: u8 = 0x03;
+const SBD_VOLTAGE: u8 = 0x09;
+const SBD_CURRENT: u8 = 0x0A;
+const SBD_RELATIVE_STATE_OF_CHARGE: u8 = 0x0D;
+const SBD_REMAINING_CAPACITY: u8 = 0x0F;
+const SBD_FULL_CHARGE_CAPACITY: u8 = 0x10;
+const SBD_BATTERY_STATUS: u8 = 0x16;
+/// Smart Battery Charger command
+const SBC_CHARGER_STATUS: u8 = 0x13;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// No battery device in the namespace
+    NoBattery,
+    /// Only control method batteries, their methods need an AML interpreter
+    NeedsInterpreter,
+    /// The EC is not in use or did not answer
+    Ec(DriverError),
+    /// The SMBus transaction failed, with the SMB_STS error code
+    Smbus(u8),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoBattery => write!(f, "no battery"),
+            Self::NeedsInterpreter => {
+                write!(f, "control method battery, _BST needs an AML interpreter")
+            }
+            Self::Ec(err) => write!(f, "EC: {}", err),
+            Self::Smbus(code) => write!(f, "SMBus error {:#04X}", code),
+        }
+    }
+}
+
+impl From<DriverError> for Error {
+    fn from(err: DriverError) -> Self {
+        Self::Ec(err)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Unit {
+    MilliampHours,
+    MilliwattHours,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum State {
+    Charging,
+    Discharging,
+    Full,
+    /// Neither charging nor discharging, e.g. held below 100% by the charger
+    Idle,
+}
+
+/// One reading of the battery and the charger
+#[derive(Copy, Clone, Debug)]
+pub struct PowerStatus {
+    pub state: State,
+    /// Relative state of charge, percent
+    pub charge: u8,
+    pub remaining_capacity: u32,
+    pub full_capacity: u32,
+    pub unit: Unit,
+    /// Positive while charging, mA
+    pub current: i16,
+    /// mV
+    pub voltage: u16,
+    /// `None` if the charger did not answer
+    pub is_ac_online: Option<bool>,
+}
+
+/// Battery and AC adapter devices declared in the namespace, for the log
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Devices {
+    pub batteries: usize,
+    pub ac_adapters: usize,
+    pub smart_battery_systems: usize,
+}
+
+pub fn devices() -> Devices {
+    let Some(namespace) = fox_aml::namespace() else {
+        return Devices::default();
+    };
+    Devices {
+        batteries: namespace.find_devices(HID_CONTROL_METHOD_BATTERY).count(),
+        ac_adapters: namespace.find_devices(HID_AC_ADAPTER).count(),
+        smart_battery_systems: namespace.find_devices(HID_SMART_BATTERY_SYSTEM).count(),
+    }
+}
+
+/// Read the smart battery and charger through the EC
+pub fn read() -> Result<PowerStatus, Error> {
+    // log::trace!("battery::read");
+
+    let devices = devices();
+    let smbus = smbus_base();
+    let smbus = match smbus {
+        Some(smbus) if devices.smart_battery_systems > 0 => smbus,
+        _ if devices.batteries > 0 => return Err(Error::NeedsInterpreter),
+        _ => return Err(Error::NoBattery),
+    };
+
+    let mode = read_word(smbus, ADDRESS_BATTERY, SBD_BATTERY_MODE)?;
+    let status = read_word(smbus, ADDRESS_BATTERY, SBD_BATTERY_STATUS)?;
+    let current = read_word(smbus, ADDRESS_BATTERY, SBD_CURRENT)? as i16;
+    // CAPACITY_MODE: capacities in 10 mWh
+    let (unit, scale) = if mode.get_bit(15) {
+        (Unit::MilliwattHours, 10)
+    } else {
+        (Unit::MilliampHours, 1)
+    };
+    let state = if status.get_bit(5) {
+        State::Full
+    } else if status.get_bit(6) {
+        State::Discharging
+    } else if current > 0 {
+        State::Charging
+    } else {
+        State::Idle
+    };
+    // AC_PRESENT, the charger is optional
+    let is_ac_online = match read_word(smbus, ADDRESS_CHARGER, SBC_CHARGER_STATUS) {
+        Ok(status) => Some(status.get_bit(15)),
+        Err(err) => {
+            log::debug!("Battery: charger: {}", err);
+            None
+        }
+    };
+
+    Ok(PowerStatus {
+        state,
+        charge: read_word(smbus, ADDRESS_BATTERY, SBD_RELATIVE_STATE_OF_CHARGE)?.min(100) as u8,
+        remaining_capacity: read_word(smbus, ADDRESS_BATTERY, SBD_REMAINING_CAPACITY)? as u32
+            * scale,
+        full_capacity: read_word(smbus, ADDRESS_BATTERY, SBD_FULL_CHARGE_CAPACITY)? as u32 * scale,
+        unit,
+        current,
+        voltage: read_word(smbus, ADDRESS_BATTERY, SBD_VOLTAGE)?,
+        is_ac_online,
+    })
+}
+
+/// Offset of the SMBus host controller in the EC space, byte 1 of its \_EC
+fn smbus_base() -> Option<u8> {
+    let namespace = fox_aml::namespace()?;
+    let device = namespace.find_devices(HID_SMBUS_HOST_CONTROLLER).next()?;
+    match namespace.child(device, b"_EC_")? {
+        NodeKind::Name(Value::Integer(value)) => {
+            let base = (value >> 8) as u8;
+            (base <= u8::MAX - SMB_SIZE).then_some(base)
+        }
+        _ => None,
+    }
+}
+
+/// SMBus Read Word through the host controller at `base`
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn read_word(base: u8, address: u8, command: u8) -> Result<u16, Error> {
+    // log::trace!("battery::read_word({:#x}, {:#x})", address, command);
+
+    AcpiEc::write(base + SMB_ADDR, address << 1)?;
+    AcpiEc::write(base + SMB_CMD, command)?;
+    // writing the protocol starts the transaction, the EC clears it when done
+    AcpiEc::write(base + SMB_PRTCL, PROTOCOL_READ_WORD)?;
+    let status = poll_timeout(TIMEOUT_SMBUS, || match AcpiEc::read(base + SMB_PRTCL) {
+        Ok(0) => AcpiEc::read(base + SMB_STS).ok(),
+        _ => None,
+    })
+    .ok_or(Error::Ec(DriverError::Timeout))?;
+    let code = status.get_bits(0..5);
+    if !status.get_bit(STATUS_DONE) || code != 0 {
+        return Err(Error::Smbus(code));
+    }
+
+    let mut data = [0; 2];
+    AcpiEc::read_block(base + SMB_DATA, &mut data)?;
+    Ok(u16::from_le_bytes(data))
+}
+
+/// The EC is only driven on x86
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn read_word(_base: u8, _address: u8, _command: u8) -> Result<u16, Error> {
+    Err(Error::Ec(DriverError::NoHardware))
+}
+
+impl fmt::Display for PowerStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = match self.unit {
+            Unit::MilliampHours => "mAh",
+            Unit::MilliwattHours => "mWh",
+        };
+        match self.is_ac_online {
+            Some(true) => write!(f, "AC online, ")?,
+            Some(false) => write!(f, "AC offline, ")?,
+            None => {}
+        }
+        write!(
+            f,
+            "{}% {:?}, {}/{} {}, {} mA at {} mV",
+            self.charge,
+            self.state,
+            self.remaining_capacity,
+            self.full_capacity,
+            unit,
+            self.current,
+            self.voltage
+        )
+    }
+}
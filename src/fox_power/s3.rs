@@ -0,0 +1,303 @@
+//! Suspend to RAM (ACPI S3)
+//!
+//! The CPU loses its state in S3, the firmware resumes at the waking vector of the FACS in real
+//! mode. A trampoline in a page below 1 MiB loads a temporary GDT, enables long mode with our
+//! page tables and calls [`resumed`] on the stack [`sleep_s3`] was on. Nothing returns into the
+//! frames there: [`resumed`] loads the GDT, TSS and IDT again and hands over to the resume
+//! function of the caller, which never returns.
+//!
+//! An experiment: \_PTS and \_WAK are not evaluated, devices, timers and interrupt controllers
+//! are not restored.
+//!
+//! https://uefi.org/specs/ACPI/6.5/16_Waking_and_Sleeping.html
+//! https://wiki.osdev.org/ACPI#Switching_to_ACPI_Mode
+
+use core::arch::{asm, global_asm};
+use core::convert::Infallible;
+use core::fmt;
+use core::mem::offset_of;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::registers::xcontrol::XCr0;
+
+use crate::fox_mem::{self, MapError, PAGE_SIZE};
+use crate::fox_uefi::is_boot_services_active;
+use crate::{fox_acpi, fox_arch, fox_idt, fox_time};
+
+/// Where [`WakeData`] starts in the trampoline page, the code comes first
+const WAKE_DATA: usize = 0x800;
+
+const GDT_NULL: u64 = 0;
+/// 64-bit code, DPL 0
+const GDT_CODE: u64 = 0x00AF_9A00_0000_FFFF;
+/// Data, DPL 0
+const GDT_DATA: u64 = 0x00CF_9200_0000_FFFF;
+const SELECTOR_CODE: u16 = 0x08;
+const SELECTOR_DATA: u16 = 0x10;
+
+/// The trampoline page, 0 until the first sleep
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+
+/// What [`resumed`] needs, only touched with interrupts disabled
+static mut CONTEXT: Context = Context {
+    rsp: 0,
+    uptime: Duration::ZERO,
+    resume: None,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    /// Boot services still own the machine
+    BootServices,
+    /// No free page below 1 MiB for the trampoline
+    NoLowMemory,
+    /// The page tables are above 4 GiB, out of reach of the 32-bit CR3 of the trampoline
+    HighPageTables,
+    /// The trampoline could not be mapped executable
+    Map(MapError),
+    Acpi(fox_acpi::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BootServices => write!(f, "boot services are active"),
+            Self::NoLowMemory => write!(f, "no memory below 1 MiB"),
+            Self::HighPageTables => write!(f, "page tables above 4 GiB"),
+            Self::Map(err) => write!(f, "trampoline: {}", err),
+            Self::Acpi(err) => write!(f, "ACPI: {}", err),
+        }
+    }
+}
+
+/// Written by [`sleep_s3`], read by [`fox_s3_resume`] and [`resumed`]
+#[repr(C)]
+struct Context {
+    /// Stack pointer of [`sleep_s3`] before sleeping, the frames above it are dead on wake
+    rsp: u64,
+    /// [`fox_time::uptime`] before sleeping
+    uptime: Duration,
+    resume: Option<fn() -> !>,
+}
+
+/// Read by the trampoline at [`WAKE_DATA`], real mode fields first
+#[repr(C, packed)]
+struct WakeData {
+    gdt: [u64; 3],
+    /// GDTR: limit and 32-bit base
+    gdt_limit: u16,
+    gdt_base: u32,
+    _reserved0: u16,
+    cr3: u32,
+    /// CR4 without the bits that need long mode or CR0.WP
+    cr4_low: u32,
+    /// EFER without LMA, the CPU sets it
+    efer: u64,
+    cr0: u32,
+    /// m16:32 pointer of the far jump into long mode
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    _reserved1: [u8; 6],
+    cr4: u64,
+    /// 0 if XSAVE is not enabled
+    xcr0: u64,
+    context: u64,
+    resume: u64,
+}
+
+unsafe extern "C" {
+    /// Start of the code copied into the trampoline page
+    static fox_s3_trampoline: u8;
+    /// First instruction in long mode
+    static fox_s3_trampoline_64: u8;
+    static fox_s3_trampoline_end: u8;
+    /// Switch to the stack of a [`Context`] and call [`resumed`]
+    static fox_s3_resume: u8;
+}
+
+global_asm!(
+    ".global fox_s3_resume",
+    "fox_s3_resume:",
+    "mov rsp, [rdi + {rsp}]",
+    "and rsp, -16",
+    "call {resumed}",
+    "ud2",
+    "",
+    // entered in real mode at CS:0, CS the page address >> 4
+    ".global fox_s3_trampoline",
+    "fox_s3_trampoline:",
+    ".code16",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [{gdtr}]",
+    "mov eax, dword ptr [{cr4_low}]",
+    "mov cr4, eax",
+    "mov eax, dword ptr [{cr3}]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "mov eax, dword ptr [{efer}]",
+    "mov edx, dword ptr [{efer} + 4]",
+    "wrmsr",
+    "mov eax, dword ptr [{cr0}]",
+    "mov cr0, eax",
+    // jmp far dword ptr [long_mode_offset]
+    ".byte 0x66, 0xFF, 0x2E",
+    ".2byte {far}",
+    ".code64",
+    ".global fox_s3_trampoline_64",
+    "fox_s3_trampoline_64:",
+    "mov eax, {selector_data}",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov ss, ax",
+    "lea rsi, [rip + fox_s3_trampoline + {wake_data}]",
+    "mov rax, [rsi + {cr4}]",
+    "mov cr4, rax",
+    "mov rax, [rsi + {xcr0}]",
+    "test rax, rax",
+    "jz 2f",
+    "mov rdx, rax",
+    "shr rdx, 32",
+    "xor ecx, ecx",
+    "xsetbv",
+    "2:",
+    "mov rdi, [rsi + {context}]",
+    "jmp qword ptr [rsi + {resume}]",
+    ".global fox_s3_trampoline_end",
+    "fox_s3_trampoline_end:",
+    rsp = const offset_of!(Context, rsp),
+    resumed = sym resumed,
+    gdtr = const WAKE_DATA + offset_of!(WakeData, gdt_limit),
+    cr4_low = const WAKE_DATA + offset_of!(WakeData, cr4_low),
+    cr3 = const WAKE_DATA + offset_of!(WakeData, cr3),
+    efer = const WAKE_DATA + offset_of!(WakeData, efer),
+    cr0 = const WAKE_DATA + offset_of!(WakeData, cr0),
+    far = const WAKE_DATA + offset_of!(WakeData, long_mode_offset),
+    selector_data = const SELECTOR_DATA,
+    wake_data = const WAKE_DATA,
+    cr4 = const offset_of!(WakeData, cr4),
+    xcr0 = const offset_of!(WakeData, xcr0),
+    context = const offset_of!(WakeData, context),
+    resume = const offset_of!(WakeData, resume),
+);
+
+/// Suspend to RAM, `resume` runs once the machine woke up
+///
+/// Only returns on error. Boot services must have been exited. `resume` runs on the stack of the
+/// caller, whose frames are gone, with interrupts disabled: their controllers lost their state.
+pub fn sleep_s3(resume: fn() -> !) -> Result<Infallible, Error> {
+    // log::trace!("sleep_s3");
+
+    if is_boot_services_active() {
+        return Err(Error::BootServices);
+    }
+    let facs = fox_acpi::facs().map_err(Error::Acpi)?;
+    let page = trampoline()?;
+
+    let were_enabled = fox_arch::are_interrupts_enabled();
+    fox_arch::disable_interrupts();
+    let result = write_wake_data(page).and_then(|()| {
+        let rsp: u64;
+        // SAFETY: only reads the stack pointer
+        unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+        let context = &raw mut CONTEXT;
+        // SAFETY: interrupts are disabled, `resumed` reads it after waking
+        unsafe {
+            *context = Context {
+                rsp,
+                uptime: fox_time::uptime(),
+                resume: Some(resume),
+            }
+        };
+        facs.set_waking_vector(page as u32);
+        fox_acpi::suspend().map_err(Error::Acpi)
+    });
+    facs.set_waking_vector(0);
+    if were_enabled {
+        fox_arch::enable_interrupts();
+    }
+    result
+}
+
+/// Entered from the trampoline with interrupts disabled, see [`Context`]
+extern "sysv64" fn resumed() -> ! {
+    // before anything can fault
+    fox_idt::reload();
+    let context = &raw const CONTEXT;
+    // SAFETY: written before sleeping, interrupts are still disabled
+    let context = unsafe { &*context };
+    if let Ok(facs) = fox_acpi::facs() {
+        facs.set_waking_vector(0);
+    }
+    fox_time::resume(context.uptime);
+    log::info!("S3: resumed");
+    let resume = context
+        .resume
+        .expect("S3: woke up without a resume function");
+    resume()
+}
+
+/// The page below 1 MiB with the trampoline code, allocated and mapped once
+fn trampoline() -> Result<u64, Error> {
+    let page = TRAMPOLINE.load(Ordering::Acquire);
+    if page != 0 {
+        return Ok(page);
+    }
+    let page = fox_mem::alloc_low_frame().ok_or(Error::NoLowMemory)?;
+    fox_mem::map_executable(page, PAGE_SIZE as usize).map_err(Error::Map)?;
+
+    let start = &raw const fox_s3_trampoline;
+    let end = &raw const fox_s3_trampoline_end;
+    let len = end as usize - start as usize;
+    assert!(len <= WAKE_DATA);
+    // SAFETY: the code is position independent, the page is ours and mapped
+    unsafe { ptr::copy_nonoverlapping(start, page as *mut u8, len) };
+    TRAMPOLINE.store(page, Ordering::Release);
+    Ok(page)
+}
+
+/// Our control registers, GDT and context for the trampoline at `page`
+fn write_wake_data(page: u64) -> Result<(), Error> {
+    let (frame, cr3_flags) = Cr3::read_raw();
+    let cr3 = frame.start_address().as_u64() | cr3_flags as u64;
+    let cr3 = u32::try_from(cr3).map_err(|_| Error::HighPageTables)?;
+    let cr4 = Cr4::read_raw();
+    // PCIDE needs long mode, CET needs CR0.WP
+    let cr4_low = cr4 & !(Cr4Flags::PCID | Cr4Flags::CONTROL_FLOW_ENFORCEMENT).bits();
+    let xcr0 = if Cr4Flags::from_bits_truncate(cr4).contains(Cr4Flags::OSXSAVE) {
+        XCr0::read_raw()
+    } else {
+        0
+    };
+    let start = &raw const fox_s3_trampoline;
+    let long_mode = &raw const fox_s3_trampoline_64;
+
+    let data = WakeData {
+        gdt: [GDT_NULL, GDT_CODE, GDT_DATA],
+        gdt_limit: (3 * size_of::<u64>() - 1) as u16,
+        gdt_base: (page as usize + WAKE_DATA) as u32,
+        _reserved0: 0,
+        cr3,
+        cr4_low: cr4_low as u32,
+        efer: Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits(),
+        cr0: Cr0::read_raw() as u32,
+        long_mode_offset: (page as usize + (long_mode as usize - start as usize)) as u32,
+        long_mode_selector: SELECTOR_CODE,
+        _reserved1: [0; 6],
+        cr4,
+        xcr0,
+        context: &raw mut CONTEXT as u64,
+        resume: &raw const fox_s3_resume as u64,
+    };
+    // SAFETY: the second half of our trampoline page, nothing runs from it now
+    unsafe { ptr::write_unaligned((page as usize + WAKE_DATA) as *mut WakeData, data) };
+    Ok(())
+}
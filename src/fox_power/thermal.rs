@@ -0,0 +1,176 @@
+//! Thermal zone temperatures and trip points
+//!
+//! ACPI reports temperatures in tenths of a Kelvin. A thermal zone's \_TMP and trip points are
+//! only read when they are plain `Name()` integers, most firmwares compute them in methods
+//! from vendor specific EC fields. The digital thermal sensor of the CPU is read directly.
+//!
+//! https://uefi.org/specs/ACPI/6.5/11_Thermal_Management.html
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use bit_field::BitField;
+
+use crate::fox_aml::{self, NameSeg, NodeKind, PathDisplay, Value};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::msr;
+
+/// 0 °C in tenths of a Kelvin
+const ZERO_CELSIUS: u32 = 2732;
+
+/// Names of the active cooling trip points, hottest first
+const ACTIVE: [NameSeg; 10] = [
+    *b"_AC0", *b"_AC1", *b"_AC2", *b"_AC3", *b"_AC4", *b"_AC5", *b"_AC6", *b"_AC7", *b"_AC8",
+    *b"_AC9",
+];
+
+/// Tenths of a Kelvin, as in ACPI
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Temperature(pub u32);
+
+/// Where the firmware acts on a temperature
+#[derive(Copy, Clone, Debug, Default)]
+pub struct TripPoints {
+    /// \_CRT, shutdown
+    pub critical: Option<Temperature>,
+    /// \_HOT, sleep to disk
+    pub hot: Option<Temperature>,
+    /// \_PSV, throttling
+    pub passive: Option<Temperature>,
+    /// \_AC0 to \_AC9, fans on
+    pub active: [Option<Temperature>; 10],
+}
+
+/// A thermal zone or the CPU sensor
+#[derive(Clone, Debug)]
+pub struct Zone {
+    pub name: String,
+    /// `None` if \_TMP is a method or missing
+    pub temperature: Option<Temperature>,
+    pub trip_points: TripPoints,
+}
+
+impl Temperature {
+    pub fn from_celsius(celsius: u32) -> Self {
+        Self(celsius * 10 + ZERO_CELSIUS)
+    }
+
+    /// Tenths of a degree Celsius
+    pub fn deci_celsius(self) -> i32 {
+        self.0 as i32 - ZERO_CELSIUS as i32
+    }
+}
+
+/// Every thermal zone of the namespace, then the CPU's digital thermal sensor
+pub fn temperatures() -> Vec<Zone> {
+    // log::trace!("temperatures");
+
+    let mut zones = Vec::new();
+    if let Some(namespace) = fox_aml::namespace() {
+        for path in namespace.thermal_zones() {
+            let value = |name: &NameSeg| match namespace.child(path, name) {
+                // 0 is a placeholder of unpopulated zones
+                Some(NodeKind::Name(Value::Integer(value))) if *value != 0 => {
+                    u32::try_from(*value).ok().map(Temperature)
+                }
+                _ => None,
+            };
+            zones.push(Zone {
+                name: format!("{}", PathDisplay(path)),
+                temperature: value(b"_TMP"),
+                trip_points: TripPoints {
+                    critical: value(b"_CRT"),
+                    hot: value(b"_HOT"),
+                    passive: value(b"_PSV"),
+                    active: ACTIVE.map(|name| value(&name)),
+                },
+            });
+        }
+    }
+    if let Some(zone) = cpu_sensor() {
+        zones.push(zone);
+    }
+    zones
+}
+
+/// Core temperature of the CPU we run on, `None` without a valid reading
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_sensor() -> Option<Zone> {
+    let status = msr::read_known(msr::IA32_THERM_STATUS)?.value;
+    // TjMax, 100 °C if the CPU does not tell
+    let target = match msr::read_known(msr::MSR_TEMPERATURE_TARGET) {
+        Some(target) => target.value.get_bits(16..24) as u32,
+        None => 100,
+    };
+    // reading valid
+    if !status.get_bit(31) {
+        return None;
+    }
+    let below_target = status.get_bits(16..23) as u32;
+    Some(Zone {
+        name: String::from("CPU"),
+        temperature: Some(Temperature::from_celsius(
+            target.saturating_sub(below_target),
+        )),
+        trip_points: TripPoints {
+            passive: Some(Temperature::from_celsius(target)),
+            ..TripPoints::default()
+        },
+    })
+}
+
+/// The thermal sensors of ARM cores are SoC specific
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn cpu_sensor() -> Option<Zone> {
+    None
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = self.deci_celsius();
+        let sign = if value < 0 { "-" } else { "" };
+        let value = value.unsigned_abs();
+        write!(f, "{}{}.{} C", sign, value / 10, value % 10)
+    }
+}
+
+impl fmt::Display for TripPoints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let named = [
+            ("critical", self.critical),
+            ("hot", self.hot),
+            ("passive", self.passive),
+        ];
+        let active = (0..).zip(self.active).filter_map(|(i, t)| Some((i, t?)));
+        let mut separator = "";
+        for (name, temperature) in named {
+            if let Some(temperature) = temperature {
+                write!(f, "{}{} {}", separator, name, temperature)?;
+                separator = ", ";
+            }
+        }
+        for (i, temperature) in active {
+            write!(f, "{}active{} {}", separator, i, temperature)?;
+            separator = ", ";
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Zone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: ", self.name)?;
+        match self.temperature {
+            Some(temperature) => write!(f, "{}", temperature)?,
+            None => write!(f, "unknown")?,
+        }
+        let trip_points = format!("{}", self.trip_points);
+        if !trip_points.is_empty() {
+            write!(f, " ({})", trip_points)?;
+        }
+        Ok(())
+    }
+}
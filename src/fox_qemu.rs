@@ -0,0 +1,125 @@
+//! QEMU detection through fw_cfg and exit through the isa-debug-exit device
+//!
+//! fw_cfg is QEMU's firmware configuration interface: a 16-bit key written to the selector
+//! port selects an item, its bytes are then read one at a time from the data port. Item 0 is
+//! the signature `QEMU`. The ports are only probed when CPUID reports a hypervisor.
+//!
+//! isa-debug-exit ends QEMU with status `(code << 1) | 1` when `code` is written to its port,
+//! so an automated run gets a result instead of waiting for its timeout. It is only there with
+//! `-device isa-debug-exit,iobase=0xf4,iosize=0x04` and cannot be detected, without it the
+//! write is ignored and [`exit`] returns.
+//!
+//! https://www.qemu.org/docs/master/specs/fw_cfg.html
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use bit_field::BitField;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::{Feature, has_feature};
+use crate::fox_io::{self, Space};
+use crate::fox_port::Pio;
+
+const FW_CFG_PORT_SELECTOR: u16 = 0x510;
+const FW_CFG_PORT_DATA: u16 = 0x511;
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+/// Bit 0 traditional interface, bit 1 DMA
+const FW_CFG_ID: u16 = 0x0001;
+const FW_CFG_ID_DMA: usize = 1;
+
+const DEBUG_EXIT_PORT: u16 = 0xF4;
+
+/// Owner of the fw_cfg and isa-debug-exit ports in [`fox_io`]
+const IO_OWNER: &str = "QEMU";
+
+/// [`detect`] did not run yet
+const STATE_UNKNOWN: u8 = 0;
+const STATE_QEMU: u8 = 1;
+const STATE_OTHER: u8 = 2;
+
+/// Result of [`detect`]
+static STATE: AtomicU8 = AtomicU8::new(STATE_UNKNOWN);
+
+/// Written to isa-debug-exit, QEMU exits with `2 * code + 1`
+///
+/// 0 would give 1, which QEMU also returns for its own errors.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ExitCode {
+    /// QEMU exits with 33
+    Success = 0x10,
+    /// QEMU exits with 35
+    Failure = 0x11,
+    /// QEMU exits with 37
+    Panic = 0x12,
+}
+
+/// Look for the fw_cfg signature, reserve the QEMU ports if found
+pub fn detect() -> bool {
+    // log::trace!("fox_qemu::detect");
+
+    if !is_guest() {
+        STATE.store(STATE_OTHER, Ordering::Relaxed);
+        return false;
+    }
+    let mut signature = [0; 4];
+    read_item(FW_CFG_SIGNATURE, &mut signature);
+    if &signature != b"QEMU" {
+        STATE.store(STATE_OTHER, Ordering::Relaxed);
+        log::debug!("QEMU: no fw_cfg ({:x?})", signature);
+        return false;
+    }
+
+    let mut id = [0; 4];
+    read_item(FW_CFG_ID, &mut id);
+    let has_dma = u32::from_le_bytes(id).get_bit(FW_CFG_ID_DMA);
+    fox_io::reserve(Space::Port, FW_CFG_PORT_SELECTOR.into(), 2, IO_OWNER);
+    fox_io::reserve(Space::Port, DEBUG_EXIT_PORT.into(), 4, IO_OWNER);
+    STATE.store(STATE_QEMU, Ordering::Relaxed);
+    log::info!("QEMU: fw_cfg (DMA {})", if has_dma { "yes" } else { "no" });
+    true
+}
+
+/// CPUID reports a hypervisor
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn is_guest() -> bool {
+    has_feature(Feature::Hypervisor)
+}
+
+/// The fw_cfg of QEMU's ARM machines is MMIO, there are no ports to probe
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn is_guest() -> bool {
+    false
+}
+
+/// Running under QEMU, [`detect`] must have run
+pub fn is_qemu() -> bool {
+    STATE.load(Ordering::Relaxed) == STATE_QEMU
+}
+
+/// End QEMU with `code`, returns if not under QEMU or without isa-debug-exit
+pub fn exit(code: ExitCode) {
+    // log::trace!("fox_qemu::exit({:?})", code);
+
+    if !is_qemu() {
+        return;
+    }
+    log::info!("QEMU: exit with {:?} ({})", code, 2 * code as u32 + 1);
+    let mut port = Pio::<u32>::new(DEBUG_EXIT_PORT);
+    // SAFETY: QEMU, the port is isa-debug-exit or unused
+    unsafe { port.write(code as u32) };
+    log::debug!("QEMU: no isa-debug-exit at {:#x}", DEBUG_EXIT_PORT);
+}
+
+/// Select `key` and read the first bytes of its item
+fn read_item(key: u16, buf: &mut [u8]) {
+    let mut selector = Pio::<u16>::new(FW_CFG_PORT_SELECTOR);
+    let data = Pio::<u8>::new(FW_CFG_PORT_DATA);
+    // SAFETY: in a virtual machine, fw_cfg under QEMU, else ports of no PC device
+    unsafe {
+        selector.write(key);
+        for byte in buf {
+            *byte = data.read();
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Random numbers: EFI_RNG_PROTOCOL, RDSEED or RDRAND, and a seedable PRNG
+//!
+//! [`fill_bytes`] takes the firmware RNG while boot services are active, the CPU instructions
+//! otherwise. [`Prng`] is xoshiro256**, not for secrets but reproducible from a seed, e.g. to
+//! repeat a self-test.
+//!
+//! https://uefi.org/specs/UEFI/2.10/37_Secure_Technologies.html#efi-rng-protocol
+//! https://www.intel.com/content/www/us/en/developer/articles/guide/intel-digital-random-number-generator-drng-software-implementation-guide.html
+//! https://prng.di.unimi.it/
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::_rdseed64_step;
+use core::fmt;
+
+use uefi::boot::{get_handle_for_protocol, open_protocol_exclusive};
+use uefi::proto::rng::Rng;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use x86_64::instructions::random::RdRand;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::{Feature, has_feature};
+use crate::fox_uefi::is_boot_services_active;
+
+/// Tries per 64 bits, RDRAND and RDSEED fail now and then when drained
+const RETRIES: usize = 10;
+
+/// The next 64 bits of a CPU source, `None` if it failed this time
+type NextFn = fn() -> Option<u64>;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Source {
+    /// EFI_RNG_PROTOCOL with the firmware's default algorithm
+    Uefi,
+    /// Entropy straight from the CPU's conditioner
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Rdseed,
+    /// The CPU's DRBG, reseeded by the conditioner
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Rdrand,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// No RNG protocol and neither instruction
+    NoSource,
+    /// The source kept failing
+    Failed(Source),
+}
+
+/// Fill `buf` from the best source there is, which is returned
+pub fn fill_bytes(buf: &mut [u8]) -> Result<Source, Error> {
+    // log::trace!("fox_rand::fill_bytes({} bytes)", buf.len());
+
+    if is_boot_services_active() && fill_uefi(buf).is_ok() {
+        return Ok(Source::Uefi);
+    }
+    let (source, next) = cpu_source().ok_or(Error::NoSource)?;
+    for chunk in buf.chunks_mut(8) {
+        let value = (0..RETRIES)
+            .find_map(|_| next())
+            .ok_or(Error::Failed(source))?;
+        chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+    }
+    Ok(source)
+}
+
+fn fill_uefi(buf: &mut [u8]) -> uefi::Result {
+    let handle = get_handle_for_protocol::<Rng>()?;
+    let mut rng = open_protocol_exclusive::<Rng>(handle)?;
+    rng.get_rng(None, buf)
+}
+
+/// RDSEED if the CPU has it, RDRAND otherwise
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpu_source() -> Option<(Source, NextFn)> {
+    if has_feature(Feature::Rdseed) {
+        Some((Source::Rdseed, rdseed))
+    } else if has_feature(Feature::Rdrand) {
+        Some((Source::Rdrand, rdrand))
+    } else {
+        None
+    }
+}
+
+/// The RNDR of ARMv8.5 is not used yet
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn cpu_source() -> Option<(Source, NextFn)> {
+    None
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn rdseed() -> Option<u64> {
+    let mut value = 0;
+    // SAFETY: only called after checking `Feature::Rdseed`
+    let is_ok = unsafe { _rdseed64_step(&mut value) } == 1;
+    is_ok.then_some(value)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn rdrand() -> Option<u64> {
+    RdRand::new()?.get_u64()
+}
+
+/// xoshiro256**, the state is spread from a 64-bit seed by SplitMix64
+pub struct Prng {
+    state: [u64; 4],
+}
+
+impl Prng {
+    pub fn new(seed: u64) -> Self {
+        let mut seed = seed;
+        let mut splitmix = || {
+            seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            z ^ (z >> 31)
+        };
+        Self {
+            state: [splitmix(), splitmix(), splitmix(), splitmix()],
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_le_bytes()[..chunk.len()]);
+        }
+    }
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Uefi => write!(f, "EFI_RNG_PROTOCOL"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Rdseed => write!(f, "RDSEED"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Rdrand => write!(f, "RDRAND"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSource => write!(f, "no random source"),
+            Self::Failed(source) => write!(f, "{} failed", source),
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Lock-free single-producer single-consumer ring buffer
+//!
+//! Interrupt handlers push, the main loop pops.
+
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+pub struct RingBuffer<const N: usize> {
+    buffer: [AtomicU8; N],
+    /// Next slot to write (producer)
+    head: AtomicUsize,
+    /// Next slot to read (consumer)
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [const { AtomicU8::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns `false` if the buffer is full and the byte was dropped
+    pub fn push(&self, value: u8) -> bool {
+        let head = self.head.load(Ordering::Relaxed);
+        let next = (head + 1) % N;
+        if next == self.tail.load(Ordering::Acquire) {
+            return false;
+        }
+        self.buffer[head].store(value, Ordering::Relaxed);
+        self.head.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail == self.head.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = self.buffer[tail].load(Ordering::Relaxed);
+        self.tail.store((tail + 1) % N, Ordering::Release);
+        Some(value)
+    }
+}
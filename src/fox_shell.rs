@@ -0,0 +1,1049 @@
+//! Interactive debug shell
+//!
+//! Reads lines from the keyboard and runs commands to look at the hardware. Output goes through
+//! [`fox_print!`], to the serial port once boot services are gone.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem;
+use core::time::Duration;
+
+use uefi::proto::console::text::Key;
+use uefi::runtime::VariableVendor;
+use uefi::{Guid, Status};
+
+use crate::drivers::{self, DriverError};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::{AcpiEc, I8042, OutputPort, PcSpeaker, Pci};
+use crate::fox_config;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::msr;
+use crate::fox_event::{self, Event};
+use crate::fox_input::{self, CHAR_BACKSPACE, CHAR_CARRIAGE_RETURN, CHAR_CTRL_C};
+use crate::fox_io::{self, Space, Width};
+use crate::fox_mem::{self, PAGE_SIZE};
+use crate::fox_net;
+use crate::fox_port;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_power::s3;
+use crate::fox_power::{self, Reset, battery};
+use crate::fox_rand::{self, Prng};
+use crate::fox_time::delay;
+use crate::fox_tpm;
+use crate::fox_uefi::vars::{self, ValueDisplay};
+use crate::fox_uefi::{is_boot_services_active, secure_boot};
+use crate::{fox_acpi, fox_aml, fox_fb, fox_log, fox_mp, fox_print, fox_println};
+
+const PROMPT: &str = "fox> ";
+const MAX_LINE: usize = 256;
+
+#[derive(Default)]
+pub struct Shell {
+    line: String,
+}
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str]) -> Result<(), Error>,
+}
+
+#[derive(Debug)]
+enum Error {
+    /// Wrong arguments, the usage is printed
+    Usage,
+    /// Could crash or change how the machine runs, `--yes` was not given
+    NotConfirmed,
+    InvalidNumber,
+    UnknownLayout,
+    InvalidAddress,
+    /// `poke` only writes MMIO, RAM may hold anything of ours or the firmware's
+    Ram,
+    /// `wrmsr` refuses the MSRs of paging, caching and the APIC
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    CriticalMsr,
+    Io(fox_io::Error),
+    Acpi(fox_acpi::Error),
+    Driver(DriverError),
+    Uefi(uefi::Error),
+    Config(fox_config::Error),
+    Logo(fox_fb::LogoError),
+    Rand(fox_rand::Error),
+    Battery(battery::Error),
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Sleep(s3::Error),
+    Net(fox_net::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Usage => write!(f, "invalid arguments"),
+            Self::NotConfirmed => write!(f, "refused, pass --yes to do it anyway"),
+            Self::InvalidNumber => write!(f, "invalid number"),
+            Self::UnknownLayout => write!(f, "unknown keyboard layout"),
+            Self::InvalidAddress => write!(f, "invalid IPv4 address"),
+            Self::Ram => write!(f, "RAM, only MMIO can be written"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::CriticalMsr => write!(f, "the running code depends on this MSR"),
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Acpi(err) => write!(f, "ACPI: {}", err),
+            Self::Driver(err) => write!(f, "{}", err),
+            Self::Uefi(err) => write!(f, "UEFI: {}", err.status()),
+            Self::Config(err) => write!(f, "config: {}", err),
+            Self::Logo(err) => write!(f, "logo: {}", err),
+            Self::Rand(err) => write!(f, "{}", err),
+            Self::Battery(err) => write!(f, "battery: {}", err),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            Self::Sleep(err) => write!(f, "S3: {}", err),
+            Self::Net(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<fox_io::Error> for Error {
+    fn from(err: fox_io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<uefi::Error> for Error {
+    fn from(err: uefi::Error) -> Self {
+        Self::Uefi(err)
+    }
+}
+
+impl From<DriverError> for Error {
+    fn from(err: DriverError) -> Self {
+        Self::Driver(err)
+    }
+}
+
+impl From<fox_net::Error> for Error {
+    fn from(err: fox_net::Error) -> Self {
+        Self::Net(err)
+    }
+}
+
+#[rustfmt::skip]
+const COMMANDS: &[Command] = &[
+    Command { name: "help", usage: "", help: "list the commands", run: help },
+    Command { name: "lsacpi", usage: "", help: "list the ACPI tables", run: lsacpi },
+    Command { name: "dump", usage: "<table>", help: "hex dump an ACPI table", run: dump },
+    Command { name: "ns", usage: "", help: "print the ACPI namespace", run: ns },
+    Command { name: "facs", usage: "[lock]", help: "show the FACS, take and release the global lock", run: facs },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "lspci", usage: "", help: "list the PCI functions", run: lspci },
+    Command { name: "cpus", usage: "", help: "list the CPUs, their CPUID and TSC skew", run: cpus },
+    Command { name: "lsdrv", usage: "", help: "list the drivers, their state and resources", run: lsdrv },
+    Command { name: "mem", usage: "", help: "show the memory map", run: mem },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "inb", usage: "<port>", help: "read an 8-bit I/O port", run: inb },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "inw", usage: "<port>", help: "read a 16-bit I/O port", run: inw },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "inl", usage: "<port>", help: "read a 32-bit I/O port", run: inl },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "outb", usage: "<port> <value>", help: "write an 8-bit I/O port", run: outb },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "outw", usage: "<port> <value>", help: "write a 16-bit I/O port", run: outw },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "outl", usage: "<port> <value>", help: "write a 32-bit I/O port", run: outl },
+    Command { name: "peek", usage: "<address> [8|16|32]", help: "read memory or MMIO", run: peek },
+    Command { name: "poke", usage: "<address> <value> [8|16|32]", help: "write MMIO", run: poke },
+    Command { name: "porttrace", usage: "[on|off]", help: "log port and MMIO accesses to the memory log", run: porttrace },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "kbcram", usage: "[<index> <value>]", help: "show or write the i8042 RAM", run: kbcram },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "kbcdump", usage: "", help: "i8042 diagnostic dump (0xAC)", run: kbcdump },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "kbcport", usage: "[out <byte>]", help: "i8042 input and output ports, reset and A20 stay high", run: kbcport },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "kbcfake", usage: "<1|2> <byte>", help: "i8042 byte as if from a port", run: kbcfake },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "kbctrace", usage: "[on|off|clear]", help: "i8042 port access trace", run: kbctrace },
+    Command { name: "gpe", usage: "", help: "PM1 and GPE registers, pending events", run: gpe },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "ec", usage: "[query|<address> [<value> --yes]]", help: "dump, read or write the embedded controller", run: ec },
+    Command { name: "battery", usage: "", help: "battery charge and AC adapter status", run: battery },
+    Command { name: "temp", usage: "", help: "thermal zone temperatures and trip points", run: temp },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "rdmsr", usage: "<msr> [--yes]", help: "read an MSR, --yes for unknown ones", run: rdmsr },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "wrmsr", usage: "<msr> <value> --yes", help: "write an MSR", run: wrmsr },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "beep", usage: "[<hz> [<ms>]]", help: "play a tone on the PC speaker, up to 10 s", run: beep },
+    Command { name: "ping", usage: "<ip> [count]", help: "send ICMP echo requests", run: ping },
+    Command { name: "tftp", usage: "get <server> <file>", help: "fetch a file, saved to the ESP before exiting boot services", run: tftp },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "a20", usage: "[on|off --yes]", help: "show or set the A20 gate", run: a20 },
+    Command { name: "layout", usage: "[name]", help: "show or select the keyboard layout", run: layout },
+    Command { name: "logo", usage: "", help: "draw the firmware logo again", run: logo },
+    Command { name: "vars", usage: "", help: "list UEFI variables", run: vars },
+    Command { name: "var", usage: "<name> [guid]", help: "show a UEFI variable, global or ours", run: var },
+    Command { name: "setvar", usage: "<name> <text>", help: "create or replace a variable of ours", run: setvar },
+    Command { name: "delvar", usage: "<name>", help: "delete a variable of ours", run: delvar },
+    Command { name: "secureboot", usage: "", help: "Secure Boot state and key databases", run: secureboot },
+    Command { name: "tpm", usage: "[log]", help: "TPM PCRs or event log, as read at boot", run: tpm },
+    Command { name: "rand", usage: "[count] [seed]", help: "random bytes, from the PRNG with a seed", run: rand },
+    Command { name: "config", usage: "[<key> <value>|reset]", help: "show or change the stored configuration", run: config },
+    Command { name: "reboot", usage: "", help: "reset the machine", run: reboot },
+    Command { name: "poweroff", usage: "", help: "power the machine off", run: poweroff },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Command { name: "s3", usage: "--yes", help: "suspend to RAM, then power off", run: s3 },
+    Command { name: "reset", usage: "<cold|warm|shutdown|platform <guid>> [reason]", help: "UEFI ResetSystem", run: reset },
+    Command { name: "exit", usage: "", help: "leave the event loop", run: exit },
+];
+
+impl Shell {
+    pub fn prompt(&self) {
+        fox_print!("{}", PROMPT);
+    }
+
+    /// Feed one key from [`crate::fox_input`]
+    pub fn handle_key(&mut self, key: Key) {
+        let Key::Printable(c) = key else {
+            return;
+        };
+        match char::from(c) {
+            CHAR_BACKSPACE if !self.line.is_empty() => {
+                self.line.pop();
+                fox_print!("\x08 \x08");
+            }
+            CHAR_CARRIAGE_RETURN => {
+                fox_println!();
+                let line = mem::take(&mut self.line);
+                execute(&line);
+                self.prompt();
+            }
+            CHAR_CTRL_C => {
+                fox_println!("^C");
+                self.line.clear();
+                self.prompt();
+            }
+            c if !c.is_control() && self.line.len() < MAX_LINE => {
+                self.line.push(c);
+                fox_print!("{}", c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn execute(line: &str) {
+    // log::trace!("fox_shell::execute({})", line);
+
+    let mut words = line.split_ascii_whitespace();
+    let Some(name) = words.next() else {
+        return;
+    };
+    let Some(command) = COMMANDS.iter().find(|i| i.name == name) else {
+        fox_println!("{}: command not found, try help", name);
+        return;
+    };
+    let args: Vec<&str> = words.collect();
+    match (command.run)(&args) {
+        Ok(()) => {}
+        Err(Error::Usage) => fox_println!("usage: {} {}", command.name, command.usage),
+        Err(err) => fox_println!("{}: {}", command.name, err),
+    }
+}
+
+/// `0x` prefixed hex or decimal
+fn parse_number(s: &str) -> Result<u64, Error> {
+    let result = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| Error::InvalidNumber)
+}
+
+fn parse_value(s: &str) -> Result<u32, Error> {
+    u32::try_from(parse_number(s)?).map_err(|_| Error::InvalidNumber)
+}
+
+fn parse_width(s: &str) -> Result<Width, Error> {
+    match s {
+        "8" => Ok(Width::U8),
+        "16" => Ok(Width::U16),
+        "32" => Ok(Width::U32),
+        _ => Err(Error::Usage),
+    }
+}
+
+fn help(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    for command in COMMANDS {
+        fox_println!(
+            "  {:<8} {:<28} {}",
+            command.name,
+            command.usage,
+            command.help
+        );
+    }
+    fox_println!("Numbers are decimal or 0x hex, Escape quits too");
+    Ok(())
+}
+
+fn lsacpi(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let tables = fox_acpi::tables().map(|(_, sdt)| sdt);
+    for sdt in tables.chain(fox_acpi::dsdt()) {
+        // SAFETY: ACPI tables stay mapped
+        let header = unsafe { sdt.as_ref() };
+        let signature = header.signature;
+        let length = header.length;
+        let revision = header.revision;
+        fox_println!(
+            "  {} at {:#012x}, {:>6} bytes, rev {}, OEM {}",
+            signature,
+            sdt.as_ptr() as u64,
+            length,
+            revision,
+            header.oem_id().unwrap_or("?")
+        );
+    }
+    Ok(())
+}
+
+fn dump(args: &[&str]) -> Result<(), Error> {
+    let [name] = args else {
+        return Err(Error::Usage);
+    };
+    fox_acpi::dump_table(name).map_err(Error::Acpi)
+}
+
+fn ns(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    fox_aml::print_tree();
+    Ok(())
+}
+
+fn facs(args: &[&str]) -> Result<(), Error> {
+    let facs = fox_acpi::facs().map_err(Error::Acpi)?;
+    match args {
+        [] => {
+            fox_println!("version {}", facs.version());
+            fox_println!("hardware signature {:#010x}", facs.hardware_signature());
+            fox_println!("waking vector {:#x}", facs.waking_vector());
+            fox_println!("S4BIOS {}", facs.has_s4bios());
+            fox_println!("global lock {:#x}", facs.global_lock_state());
+        }
+        ["lock"] => {
+            let lock = fox_acpi::global_lock().map_err(Error::Acpi)?;
+            fox_println!("global lock {:#x}", facs.global_lock_state());
+            drop(lock);
+            fox_println!("released, {:#x}", facs.global_lock_state());
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn cpus(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    fox_mp::print_cpus();
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn lspci(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    for dev in Pci::devices() {
+        fox_println!(
+            "  {} {} [{:02x}{:02x}]: [{:04x}:{:04x}] (rev {:02x})",
+            dev.address,
+            dev.class_name(),
+            dev.class,
+            dev.subclass,
+            dev.vendor_id,
+            dev.device_id,
+            dev.revision
+        );
+    }
+    Ok(())
+}
+
+fn lsdrv(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    for driver in drivers::status() {
+        fox_println!(
+            "  {:<12} {:>10.1?}  {}",
+            driver.name,
+            driver.init_time,
+            driver.state
+        );
+        if !driver.dependencies.is_empty() {
+            fox_print!("    needs:");
+            for dependency in driver.dependencies {
+                fox_print!(" {}", dependency);
+            }
+            fox_println!();
+        }
+        let resources = driver.resources();
+        if !resources.is_empty() {
+            fox_print!("    uses:");
+            for resource in resources {
+                fox_print!(" {}", resource);
+            }
+            fox_println!();
+        }
+    }
+    Ok(())
+}
+
+fn mem(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    if let Some(regions) = fox_mem::regions() {
+        for region in regions.iter() {
+            fox_println!("  {}", region);
+        }
+    }
+    if let Some(free) = fox_mem::free_frame_count() {
+        fox_println!("{} MiB free", (free * PAGE_SIZE) >> 20);
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inb(args: &[&str]) -> Result<(), Error> {
+    port_in(args, Width::U8)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inw(args: &[&str]) -> Result<(), Error> {
+    port_in(args, Width::U16)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn inl(args: &[&str]) -> Result<(), Error> {
+    port_in(args, Width::U32)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn outb(args: &[&str]) -> Result<(), Error> {
+    port_out(args, Width::U8)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn outw(args: &[&str]) -> Result<(), Error> {
+    port_out(args, Width::U16)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn outl(args: &[&str]) -> Result<(), Error> {
+    port_out(args, Width::U32)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn port_in(args: &[&str], width: Width) -> Result<(), Error> {
+    let [port] = args else {
+        return Err(Error::Usage);
+    };
+    let port = parse_number(port)?;
+    // SAFETY: `peek` refuses the ports our drivers reserved, a side effect of the read can only
+    // reach a device none of them drives
+    let value = unsafe { fox_io::peek(Space::Port, port, width) }?;
+    print_value(port, value, width);
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn port_out(args: &[&str], width: Width) -> Result<(), Error> {
+    let [port, value] = args else {
+        return Err(Error::Usage);
+    };
+    let port = parse_number(port)?;
+    let value = parse_value(value)?;
+    // SAFETY: a port write does not touch memory, and `poke` refuses the ports our drivers
+    // reserved
+    unsafe { fox_io::poke(Space::Port, port, width, value) }?;
+    Ok(())
+}
+
+fn peek(args: &[&str]) -> Result<(), Error> {
+    let (address, width) = match args {
+        [address] => (address, Width::U32),
+        [address, width] => (address, parse_width(width)?),
+        _ => return Err(Error::Usage),
+    };
+    let address = parse_number(address)?;
+    // SAFETY: RAM reads have no side effects, `peek` refuses the MMIO our drivers reserved
+    let value = unsafe { fox_io::peek(Space::Mmio, address, width) }?;
+    print_value(address, value, width);
+    Ok(())
+}
+
+fn poke(args: &[&str]) -> Result<(), Error> {
+    let (address, value, width) = match args {
+        [address, value] => (address, value, Width::U32),
+        [address, value, width] => (address, value, parse_width(width)?),
+        _ => return Err(Error::Usage),
+    };
+    let address = parse_number(address)?;
+    let value = parse_value(value)?;
+    if fox_io::is_ram(address) {
+        return Err(Error::Ram);
+    }
+    // SAFETY: not RAM, checked above, and `poke` refuses the MMIO our drivers reserved
+    unsafe { fox_io::poke(Space::Mmio, address, width, value) }?;
+    Ok(())
+}
+
+fn print_value(address: u64, value: u32, width: Width) {
+    let digits = 2 * width.bytes() as usize;
+    fox_println!("{:#x}: {:#0width$x}", address, value, width = digits + 2);
+}
+
+fn porttrace(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {
+            let state = if fox_port::is_tracing() { "on" } else { "off" };
+            fox_println!("tracing {}", state);
+        }
+        ["on"] => fox_port::set_tracing(true),
+        ["off"] => fox_port::set_tracing(false),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kbcram(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {
+            let mut ram = [0; I8042::RAM_SIZE];
+            for (index, byte) in (0..).zip(ram.iter_mut()) {
+                *byte = I8042::read_ram(index)?;
+            }
+            fox_log::hexdump(0, &ram);
+        }
+        [index, value] => {
+            let index = u8::try_from(parse_number(index)?).map_err(|_| Error::InvalidNumber)?;
+            let value = u8::try_from(parse_number(value)?).map_err(|_| Error::InvalidNumber)?;
+            I8042::write_ram(index, value)?;
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kbcdump(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let bytes = I8042::diagnostic_dump()?;
+    if bytes.is_empty() {
+        fox_println!("no response");
+    } else {
+        fox_log::hexdump(0, &bytes);
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kbcport(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {}
+        ["out", value] => {
+            let value = u8::try_from(parse_number(value)?).map_err(|_| Error::InvalidNumber)?;
+            // low would hold the CPU in reset, closing A20 is what `a20 off --yes` is for
+            let mut port = OutputPort(value);
+            port.set_system_reset(true);
+            port.set_a20_gate(true);
+            if port.0 != value {
+                fox_println!("writing {:#04X}, reset and A20 stay high", port.0);
+            }
+            // SAFETY: reset and A20 are high, the other bits are PS/2 lines and IRQ outputs
+            unsafe { I8042::set_output_port(port) }?;
+        }
+        _ => return Err(Error::Usage),
+    }
+    fox_println!("{:#?}", I8042::input_port()?);
+    fox_println!("{:#?}", I8042::output_port()?);
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kbcfake(args: &[&str]) -> Result<(), Error> {
+    let [port, value] = args else {
+        return Err(Error::Usage);
+    };
+    let is_port2 = match *port {
+        "1" => false,
+        "2" => true,
+        _ => return Err(Error::Usage),
+    };
+    let value = u8::try_from(parse_number(value)?).map_err(|_| Error::InvalidNumber)?;
+    I8042::fake_input(is_port2, value)?;
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn kbctrace(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {
+            let state = if I8042::is_tracing() { "on" } else { "off" };
+            fox_println!("# tracing {}", state);
+            fox_print!("{}", I8042::trace_dump());
+        }
+        ["on"] => I8042::set_trace(true),
+        ["off"] => I8042::set_trace(false),
+        ["clear"] => I8042::clear_trace(),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn parse_msr(s: &str) -> Result<u32, Error> {
+    u32::try_from(parse_number(s)?).map_err(|_| Error::InvalidNumber)
+}
+
+fn gpe(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let events = fox_acpi::event_registers().map_err(Error::Acpi)?;
+    let sci = if events.is_sci_enabled {
+        "ACPI mode"
+    } else {
+        "legacy mode"
+    };
+    fox_println!("SCI: IRQ {}, {}", events.sci_interrupt, sci);
+    let enabled = |is_enabled: bool| if is_enabled { " (enabled)" } else { "" };
+    match events.pm1 {
+        Some(pm1) => {
+            fox_println!(
+                "PM1: status {:#06X}, enable {:#06X}",
+                pm1.status,
+                pm1.enable
+            );
+            for (name, is_enabled) in pm1.pending() {
+                fox_println!("  {}{}", name, enabled(is_enabled));
+            }
+        }
+        None => fox_println!("PM1: none"),
+    }
+    for block in events.gpe_blocks.iter().flatten() {
+        fox_println!(
+            "GPE{}: GPEs {:#04X}-{:#04X}",
+            block.index,
+            block.base,
+            block.base + block.count().saturating_sub(1)
+        );
+        fox_print!("  status:");
+        for value in block.status() {
+            fox_print!(" {:02X}", value);
+        }
+        fox_print!("\n  enable:");
+        for value in block.enable() {
+            fox_print!(" {:02X}", value);
+        }
+        fox_println!();
+        for (number, is_enabled) in block.pending() {
+            fox_println!("  GPE {:#04X}{}", number, enabled(is_enabled));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn ec(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {
+            fox_println!("status {:#04X}", AcpiEc::status()?);
+            let mut space = [0; AcpiEc::SPACE_SIZE];
+            AcpiEc::read_block(0, &mut space)?;
+            fox_log::hexdump(0, &space);
+        }
+        ["query"] => match AcpiEc::query()? {
+            Some(event) => fox_println!("event _Q{:02X}", event),
+            None => fox_println!("no event"),
+        },
+        [address] => {
+            let address = u8::try_from(parse_number(address)?).map_err(|_| Error::InvalidNumber)?;
+            fox_println!("{:#04X}: {:#04X}", address, AcpiEc::read(address)?);
+        }
+        // the EC runs the fans and the charger
+        [address, value, "--yes"] => {
+            let address = u8::try_from(parse_number(address)?).map_err(|_| Error::InvalidNumber)?;
+            let value = u8::try_from(parse_number(value)?).map_err(|_| Error::InvalidNumber)?;
+            AcpiEc::write(address, value)?;
+        }
+        [_, _] => return Err(Error::NotConfirmed),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn battery(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let devices = battery::devices();
+    fox_println!(
+        "{} batteries, {} AC adapters, {} smart battery systems",
+        devices.batteries,
+        devices.ac_adapters,
+        devices.smart_battery_systems
+    );
+    fox_println!("{}", battery::read().map_err(Error::Battery)?);
+    Ok(())
+}
+
+fn temp(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let zones = fox_power::temperatures();
+    if zones.is_empty() {
+        fox_println!("no thermal zones");
+    }
+    for zone in zones {
+        fox_println!("{}", zone);
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn rdmsr(args: &[&str]) -> Result<(), Error> {
+    let value = match args {
+        [address] => msr::read_known(parse_msr(address)?).ok_or(Error::NotConfirmed)?,
+        [address, "--yes"] => {
+            let address = parse_msr(address)?;
+            // SAFETY: an MSR the CPU lacks raises #GP, which ends in a panic or the firmware's
+            // exception handler, no code runs on with a made-up value
+            let value = unsafe { msr::read(address) };
+            msr::Value { address, value }
+        }
+        _ => return Err(Error::Usage),
+    };
+    fox_println!("{}", value);
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn wrmsr(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [address, value, "--yes"] => {
+            let address = parse_msr(address)?;
+            let value = parse_number(value)?;
+            if msr::is_critical(address) {
+                return Err(Error::CriticalMsr);
+            }
+            // SAFETY: the MSRs that change what memory means are refused above, a missing one
+            // raises #GP like in `rdmsr`
+            unsafe { msr::write(address, value) };
+            fox_println!("{}", msr::Value { address, value });
+        }
+        [_, _] => return Err(Error::NotConfirmed),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn a20(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {}
+        ["on"] => I8042::enable_a20()?,
+        // SAFETY: the CPU ignores A20M# in long mode, and the real mode S3 trampoline stays below
+        // 1 MiB where bit 20 is 0 anyway
+        ["off", "--yes"] => unsafe { I8042::disable_a20() }?,
+        ["off"] => return Err(Error::NotConfirmed),
+        _ => return Err(Error::Usage),
+    }
+    let state = if I8042::is_a20_enabled()? {
+        "on"
+    } else {
+        "off"
+    };
+    fox_println!("A20 {}", state);
+    Ok(())
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn beep(args: &[&str]) -> Result<(), Error> {
+    const FREQUENCY: u64 = 880;
+    const DURATION: u64 = 200;
+    /// The tone blocks the shell
+    const MAX_DURATION: u64 = 10_000;
+
+    let (frequency, ms) = match args {
+        [] => (FREQUENCY, DURATION),
+        [frequency] => (parse_number(frequency)?, DURATION),
+        [frequency, ms] => (parse_number(frequency)?, parse_number(ms)?),
+        _ => return Err(Error::Usage),
+    };
+    if ms > MAX_DURATION {
+        return Err(Error::InvalidNumber);
+    }
+    let frequency = u32::try_from(frequency).map_err(|_| Error::InvalidNumber)?;
+    PcSpeaker::beep(frequency, Duration::from_millis(ms))?;
+    Ok(())
+}
+
+fn ping(args: &[&str]) -> Result<(), Error> {
+    const COUNT: u64 = 4;
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    let (address, count) = match args {
+        [address] => (address, COUNT),
+        [address, count] => (address, parse_number(count)?),
+        _ => return Err(Error::Usage),
+    };
+    let address = address.parse().map_err(|_| Error::InvalidAddress)?;
+    let mut received = 0;
+    for sequence in 0..count {
+        if sequence > 0 {
+            delay(INTERVAL);
+        }
+        match fox_net::ping(address, sequence as u16, INTERVAL) {
+            Ok(time) => {
+                fox_println!("{}: seq={} time={:?}", address, sequence, time);
+                received += 1;
+            }
+            Err(fox_net::Error::Timeout) => fox_println!("{}: seq={} timeout", address, sequence),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    fox_println!("{} sent, {} received", count, received);
+    Ok(())
+}
+
+fn tftp(args: &[&str]) -> Result<(), Error> {
+    let ["get", server, file] = args else {
+        return Err(Error::Usage);
+    };
+    let server = server.parse().map_err(|_| Error::InvalidAddress)?;
+    if is_boot_services_active() {
+        let size = fox_net::tftp::save(server, file)?;
+        fox_println!("{}: saved, {} bytes", file, size);
+    } else {
+        let size = fox_net::tftp::get(server, file)?.len();
+        fox_println!("{}: {} bytes, not saved without boot services", file, size);
+        fox_println!("load the app with --tftp={}:{} to save it", server, file);
+    }
+    Ok(())
+}
+
+fn layout(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {
+            let current = fox_input::layout().name;
+            for layout in fox_input::layouts() {
+                let mark = if layout.name == current { '*' } else { ' ' };
+                fox_println!("{} {}", mark, layout.name);
+            }
+        }
+        [name] => {
+            let layout = fox_input::set_layout(name).ok_or(Error::UnknownLayout)?;
+            fox_println!("layout {}", layout.name);
+        }
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn logo(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    fox_fb::draw_logo().map_err(Error::Logo)
+}
+
+fn vars(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    for variable in vars::variables()? {
+        fox_println!("{}", variable);
+    }
+    Ok(())
+}
+
+fn var(args: &[&str]) -> Result<(), Error> {
+    let (name, vendor) = match args {
+        [name] => {
+            // ours first, the global one otherwise
+            let is_ours = vars::read(name, &vars::APP_VENDOR).is_ok();
+            let vendor = if is_ours {
+                vars::APP_VENDOR
+            } else {
+                VariableVendor::GLOBAL_VARIABLE
+            };
+            (*name, vendor)
+        }
+        [name, guid] => {
+            let guid = Guid::try_parse(guid).map_err(|_| Error::Usage)?;
+            (*name, VariableVendor(guid))
+        }
+        _ => return Err(Error::Usage),
+    };
+    let (data, _) = vars::read(name, &vendor)?;
+    fox_println!(
+        "{}",
+        ValueDisplay {
+            name,
+            vendor,
+            data: &data
+        }
+    );
+    fox_log::hexdump(0, &data);
+    Ok(())
+}
+
+fn setvar(args: &[&str]) -> Result<(), Error> {
+    let [name, text @ ..] = args else {
+        return Err(Error::Usage);
+    };
+    if text.is_empty() {
+        return Err(Error::Usage);
+    }
+    vars::write(name, text.join(" ").as_bytes())?;
+    Ok(())
+}
+
+fn delvar(args: &[&str]) -> Result<(), Error> {
+    let [name] = args else {
+        return Err(Error::Usage);
+    };
+    vars::delete(name)?;
+    Ok(())
+}
+
+fn secureboot(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    secure_boot::print_report();
+    Ok(())
+}
+
+fn tpm(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => fox_tpm::print_pcrs(),
+        ["log"] => fox_tpm::print_event_log(),
+        _ => return Err(Error::Usage),
+    }
+    Ok(())
+}
+
+fn rand(args: &[&str]) -> Result<(), Error> {
+    const MAX_COUNT: usize = 4096;
+
+    let count = match args.first() {
+        Some(count) => usize::try_from(parse_number(count)?).map_err(|_| Error::InvalidNumber)?,
+        None => 16,
+    };
+    if count > MAX_COUNT {
+        return Err(Error::InvalidNumber);
+    }
+    let mut bytes = vec![0; count];
+    match args {
+        [] | [_] => {
+            let source = fox_rand::fill_bytes(&mut bytes).map_err(Error::Rand)?;
+            fox_println!("from {}", source);
+        }
+        [_, seed] => Prng::new(parse_number(seed)?).fill_bytes(&mut bytes),
+        _ => return Err(Error::Usage),
+    }
+    fox_log::hexdump(0, &bytes);
+    Ok(())
+}
+
+fn config(args: &[&str]) -> Result<(), Error> {
+    match args {
+        [] => {}
+        ["reset"] => fox_config::reset().map_err(Error::Config)?,
+        [key, value] => fox_config::set(key, value).map_err(Error::Config)?,
+        _ => return Err(Error::Usage),
+    }
+    fox_print!("{}", fox_config::stored());
+    Ok(())
+}
+
+fn reboot(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    let Err(err) = fox_acpi::reboot();
+    Err(Error::Acpi(err))
+}
+
+fn poweroff(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    fox_acpi::enable().map_err(Error::Acpi)?;
+    let Err(err) = fox_acpi::poweroff();
+    Err(Error::Acpi(err))
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn s3(args: &[&str]) -> Result<(), Error> {
+    match args {
+        ["--yes"] => {}
+        [] => return Err(Error::NotConfirmed),
+        _ => return Err(Error::Usage),
+    }
+    fox_acpi::enable().map_err(Error::Acpi)?;
+    let Err(err) = fox_power::sleep_s3(resumed);
+    Err(Error::Sleep(err))
+}
+
+/// After [`s3`], on a stack whose shell and event loop are gone
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn resumed() -> ! {
+    fox_println!("resumed");
+    // timers, interrupt controllers and drivers were not restored
+    let Err(err) = fox_acpi::poweroff();
+    log::error!("ACPI: poweroff failed: {}", err);
+    fox_power::reset(Reset::Shutdown, Status::SUCCESS, "resumed from S3")
+}
+
+fn reset(args: &[&str]) -> Result<(), Error> {
+    let (kind, reason) = match args {
+        ["cold", reason @ ..] => (Reset::Cold, reason),
+        ["warm", reason @ ..] => (Reset::Warm, reason),
+        ["shutdown", reason @ ..] => (Reset::Shutdown, reason),
+        ["platform", guid, reason @ ..] => {
+            let guid = Guid::try_parse(guid).map_err(|_| Error::Usage)?;
+            (Reset::PlatformSpecific(guid), reason)
+        }
+        _ => return Err(Error::Usage),
+    };
+    let reason = if reason.is_empty() {
+        String::from("shell")
+    } else {
+        reason.join(" ")
+    };
+    fox_power::reset(kind, Status::SUCCESS, &reason)
+}
+
+fn exit(args: &[&str]) -> Result<(), Error> {
+    if !args.is_empty() {
+        return Err(Error::Usage);
+    }
+    fox_event::post(Event::Quit(Status::SUCCESS));
+    Ok(())
+}
@@ -0,0 +1,541 @@
+//! System Management BIOS (SMBIOS)
+//!
+//! https://wiki.osdev.org/System_Management_BIOS
+//! https://www.dmtf.org/standards/smbios
+
+use core::ptr::null_mut;
+use core::slice;
+use core::str;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use uefi::Guid;
+use uefi::system::with_config_table;
+use uefi::table::cfg::ConfigTableEntry;
+
+const ANCHOR_2: &[u8; 4] = b"_SM_";
+const ANCHOR_3: &[u8; 5] = b"_SM3_";
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_BASEBOARD: u8 = 2;
+const TYPE_PROCESSOR: u8 = 4;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END_OF_TABLE: u8 = 127;
+
+/// Init [`init`]
+static SMBIOS: AtomicPtr<SmbiosInfo> = AtomicPtr::new(null_mut());
+
+/// Storage for [`SMBIOS`], written once by [`init`]
+static mut SMBIOS_INFO: SmbiosInfo = SmbiosInfo::new();
+
+/// Structure table located through the entry point
+#[derive(Debug)]
+pub struct SmbiosInfo {
+    pub major: u8,
+    pub minor: u8,
+    /// Firmware memory, still valid after exiting boot services
+    table: &'static [u8],
+}
+
+/// One structure of the table, with its strings
+#[derive(Copy, Clone, Debug)]
+pub struct Structure {
+    pub kind: u8,
+    /// Formatted area, starting with the header
+    data: &'static [u8],
+    /// String-set, each string null-terminated
+    strings: &'static [u8],
+}
+
+/// BIOS Information (Type 0)
+#[derive(Copy, Clone, Debug)]
+pub struct BiosInfo {
+    pub vendor: &'static str,
+    pub version: &'static str,
+    pub release_date: &'static str,
+    /// Bytes
+    pub rom_size: u64,
+    /// Major and minor release, SMBIOS 2.4
+    pub release: Option<(u8, u8)>,
+}
+
+/// System Information (Type 1)
+#[derive(Copy, Clone, Debug)]
+pub struct SystemInfo {
+    pub manufacturer: &'static str,
+    pub product_name: &'static str,
+    pub version: &'static str,
+    pub serial_number: &'static str,
+    /// SMBIOS 2.1
+    pub uuid: Option<Guid>,
+    /// SMBIOS 2.4
+    pub family: &'static str,
+}
+
+/// Baseboard Information (Type 2)
+#[derive(Copy, Clone, Debug)]
+pub struct Baseboard {
+    pub manufacturer: &'static str,
+    pub product: &'static str,
+    pub version: &'static str,
+    pub serial_number: &'static str,
+}
+
+/// Processor Information (Type 4)
+#[derive(Copy, Clone, Debug)]
+pub struct Processor {
+    pub socket: &'static str,
+    pub manufacturer: &'static str,
+    pub version: &'static str,
+    /// MHz, 0 if unknown
+    pub max_speed: u16,
+    pub current_speed: u16,
+    /// The socket holds a processor
+    pub is_populated: bool,
+    /// SMBIOS 2.5
+    pub core_count: Option<u16>,
+    pub thread_count: Option<u16>,
+}
+
+/// Memory Device (Type 17)
+#[derive(Copy, Clone, Debug)]
+pub struct MemoryDevice {
+    pub device_locator: &'static str,
+    pub bank_locator: &'static str,
+    /// Bytes, 0 if the slot is empty, `None` if unknown
+    pub size: Option<u64>,
+    /// SMBIOS 2.3
+    pub memory_type: Option<u8>,
+    /// MT/s, 0 if unknown
+    pub speed: u16,
+    pub manufacturer: &'static str,
+    pub part_number: &'static str,
+}
+
+/// Iterator over the structures of the table, see [`SmbiosInfo::structures`]
+pub struct Structures {
+    table: &'static [u8],
+    offset: usize,
+}
+
+impl SmbiosInfo {
+    const fn new() -> Self {
+        Self {
+            major: 0,
+            minor: 0,
+            table: &[],
+        }
+    }
+
+    pub fn structures(&self) -> Structures {
+        Structures {
+            table: self.table,
+            offset: 0,
+        }
+    }
+
+    pub fn bios(&self) -> Option<BiosInfo> {
+        self.structures().find_map(BiosInfo::decode)
+    }
+
+    pub fn system(&self) -> Option<SystemInfo> {
+        self.structures().find_map(SystemInfo::decode)
+    }
+
+    pub fn baseboard(&self) -> Option<Baseboard> {
+        self.structures().find_map(Baseboard::decode)
+    }
+
+    pub fn processors(&self) -> impl Iterator<Item = Processor> {
+        self.structures().filter_map(Processor::decode)
+    }
+
+    pub fn memory_devices(&self) -> impl Iterator<Item = MemoryDevice> {
+        self.structures().filter_map(MemoryDevice::decode)
+    }
+}
+
+pub fn smbios_info() -> Option<&'static SmbiosInfo> {
+    let ptr = SMBIOS.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Find the entry point in the UEFI configuration table, preferring SMBIOS 3
+pub fn init() {
+    // log::trace!("fox_smbios::init");
+
+    let (smbios2, smbios3) = with_config_table(|slice: &[ConfigTableEntry]| {
+        let find = |guid| {
+            slice
+                .iter()
+                .find(|i| i.guid == guid)
+                .map(|i| i.address as *const u8)
+        };
+        (
+            find(ConfigTableEntry::SMBIOS_GUID),
+            find(ConfigTableEntry::SMBIOS3_GUID),
+        )
+    });
+
+    let info = &raw mut SMBIOS_INFO;
+    // SAFETY: called once during boot, before anyone reads `SMBIOS`
+    let info = unsafe { &mut *info };
+
+    // SAFETY: the firmware points at an entry point structure
+    let found = smbios3.is_some_and(|ptr| unsafe { parse_entry_point_3(ptr, info) })
+        || smbios2.is_some_and(|ptr| unsafe { parse_entry_point_2(ptr, info) });
+    if !found {
+        log::warn!("SMBIOS not found");
+        return;
+    }
+    log::debug!(
+        "Found SMBIOS {}.{}, {} structures",
+        info.major,
+        info.minor,
+        info.structures().count()
+    );
+
+    SMBIOS.store(info, Ordering::Release);
+}
+
+/// Log the BIOS, system, processors and memory
+pub fn log_summary() {
+    let Some(smbios) = smbios_info() else {
+        return;
+    };
+
+    if let Some(bios) = smbios.bios() {
+        log::info!(
+            "BIOS: {} {} ({})",
+            bios.vendor,
+            bios.version,
+            bios.release_date
+        );
+        let (major, minor) = bios.release.unwrap_or_default();
+        log::debug!(
+            "BIOS release {}.{}, {} KiB ROM",
+            major,
+            minor,
+            bios.rom_size >> 10
+        );
+    }
+    if let Some(system) = smbios.system() {
+        log::info!(
+            "System: {} {} {}",
+            system.manufacturer,
+            system.product_name,
+            system.version
+        );
+        if let Some(uuid) = system.uuid {
+            log::debug!("System UUID: {}, family {}", uuid, system.family);
+        }
+    }
+    if let Some(board) = smbios.baseboard() {
+        log::info!(
+            "Baseboard: {} {} {}",
+            board.manufacturer,
+            board.product,
+            board.version
+        );
+        log::debug!(
+            "Serial numbers: system {}, baseboard {}",
+            smbios.system().map_or("", |i| i.serial_number),
+            board.serial_number
+        );
+    }
+    for cpu in smbios.processors().filter(|i| i.is_populated) {
+        log::info!(
+            "{}: {} {}, {} cores, {} threads, {}/{} MHz",
+            cpu.socket,
+            cpu.manufacturer,
+            cpu.version,
+            cpu.core_count.unwrap_or(0),
+            cpu.thread_count.unwrap_or(0),
+            cpu.current_speed,
+            cpu.max_speed
+        );
+    }
+
+    let mut total = 0;
+    for memory in smbios.memory_devices() {
+        match memory.size {
+            Some(0) => continue,
+            Some(size) => total += size,
+            None => {}
+        }
+        log::info!(
+            "{} {}: {} MiB {} at {} MT/s, {} {}",
+            memory.bank_locator,
+            memory.device_locator,
+            memory.size.unwrap_or(0) >> 20,
+            memory_type_name(memory.memory_type.unwrap_or(0x02)),
+            memory.speed,
+            memory.manufacturer,
+            memory.part_number
+        );
+    }
+    log::info!("Installed memory: {} MiB", total >> 20);
+}
+
+/// 64-bit entry point, SMBIOS 3.0
+///
+/// # Safety
+/// `ptr` points at readable memory
+unsafe fn parse_entry_point_3(ptr: *const u8, info: &mut SmbiosInfo) -> bool {
+    // struct {
+    //     char anchor[5];
+    //     uint8_t checksum;
+    //     uint8_t length;
+    //     uint8_t major, minor, docrev;
+    //     uint8_t revision;
+    //     uint8_t reserved;
+    //     uint32_t table_max_size;
+    //     uint64_t table_address;
+    // };
+    let header = unsafe { slice::from_raw_parts(ptr, 0x18) };
+    if &header[0..5] != ANCHOR_3 {
+        return false;
+    }
+    let length = (header[6] as usize).max(0x18);
+    if !is_checksum_valid(unsafe { slice::from_raw_parts(ptr, length) }) {
+        log::warn!("SMBIOS 3: invalid entry point checksum");
+        return false;
+    }
+
+    let size = u32::from_le_bytes(header[0x0C..0x10].try_into().unwrap()) as usize;
+    let address = u64::from_le_bytes(header[0x10..0x18].try_into().unwrap());
+    info.major = header[7];
+    info.minor = header[8];
+    // SAFETY: the table stays in firmware reserved memory, UEFI identity-maps it
+    info.table = unsafe { slice::from_raw_parts(address as *const u8, size) };
+    true
+}
+
+/// 32-bit entry point, SMBIOS 2.1
+///
+/// # Safety
+/// `ptr` points at readable memory
+unsafe fn parse_entry_point_2(ptr: *const u8, info: &mut SmbiosInfo) -> bool {
+    // struct {
+    //     char anchor[4];
+    //     uint8_t checksum;
+    //     uint8_t length;
+    //     uint8_t major, minor;
+    //     uint16_t max_structure_size;
+    //     uint8_t revision;
+    //     uint8_t formatted[5];
+    //     char intermediate_anchor[5];
+    //     uint8_t intermediate_checksum;
+    //     uint16_t table_length;
+    //     uint32_t table_address;
+    //     uint16_t structure_count;
+    //     uint8_t bcd_revision;
+    // };
+    let header = unsafe { slice::from_raw_parts(ptr, 0x1F) };
+    if &header[0..4] != ANCHOR_2 {
+        return false;
+    }
+    let length = (header[5] as usize).max(0x1F);
+    if !is_checksum_valid(unsafe { slice::from_raw_parts(ptr, length) }) {
+        log::warn!("SMBIOS: invalid entry point checksum");
+        return false;
+    }
+
+    let size = u16::from_le_bytes([header[0x16], header[0x17]]) as usize;
+    let address = u32::from_le_bytes(header[0x18..0x1C].try_into().unwrap());
+    info.major = header[6];
+    info.minor = header[7];
+    // SAFETY: the table stays in firmware reserved memory, UEFI identity-maps it
+    info.table = unsafe { slice::from_raw_parts(address as usize as *const u8, size) };
+    true
+}
+
+/// Bytes sum to zero
+fn is_checksum_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &i| sum.wrapping_add(i)) == 0
+}
+
+fn memory_type_name(memory_type: u8) -> &'static str {
+    match memory_type {
+        0x12 => "DDR",
+        0x13 => "DDR2",
+        0x18 => "DDR3",
+        0x1A => "DDR4",
+        0x1B => "LPDDR",
+        0x1C => "LPDDR2",
+        0x1D => "LPDDR3",
+        0x1E => "LPDDR4",
+        0x22 => "DDR5",
+        0x23 => "LPDDR5",
+        0x07 => "RAM",
+        0x09 => "NVRAM",
+        _ => "unknown type",
+    }
+}
+
+impl Iterator for Structures {
+    type Item = Structure;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let rest = self.table.get(self.offset..)?;
+        if rest.len() < 4 {
+            return None;
+        }
+        let length = rest[1] as usize;
+        if length < 4 || length > rest.len() {
+            log::warn!("SMBIOS: invalid structure length at {}", self.offset);
+            self.table = &[];
+            return None;
+        }
+
+        // the string-set ends with two nulls, an empty one is just the two nulls
+        let strings_end = rest[length..]
+            .windows(2)
+            .position(|i| i == [0, 0])
+            .map(|i| length + i + 2)?;
+        let structure = Structure {
+            kind: rest[0],
+            data: &rest[..length],
+            strings: &rest[length..strings_end],
+        };
+
+        if structure.kind == TYPE_END_OF_TABLE {
+            self.table = &[];
+        } else {
+            self.offset += strings_end;
+        }
+        Some(structure)
+    }
+}
+
+impl Structure {
+    fn byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).copied()
+    }
+
+    fn word(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset + 2)?;
+        Some(u16::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn dword(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// String referenced by the byte at `offset`, empty if there is none
+    fn string(&self, offset: usize) -> &'static str {
+        let index = match self.byte(offset) {
+            Some(0) | None => return "",
+            Some(index) => index as usize,
+        };
+        self.strings
+            .split(|&i| i == 0)
+            .nth(index - 1)
+            .map_or("", |i| str::from_utf8(i).unwrap_or("?").trim())
+    }
+}
+
+impl BiosInfo {
+    fn decode(s: Structure) -> Option<Self> {
+        if s.kind != TYPE_BIOS {
+            return None;
+        }
+        // 0xFF means the extended size, in MiB or GiB
+        let rom_size = match s.byte(0x09)? {
+            0xFF => match s.word(0x18).unwrap_or(0) {
+                size if size >> 14 == 1 => ((size & 0x3FFF) as u64) << 30,
+                size => ((size & 0x3FFF) as u64) << 20,
+            },
+            size => (size as u64 + 1) << 16,
+        };
+        Some(Self {
+            vendor: s.string(0x04),
+            version: s.string(0x05),
+            release_date: s.string(0x08),
+            rom_size,
+            release: s.byte(0x14).zip(s.byte(0x15)),
+        })
+    }
+}
+
+impl SystemInfo {
+    fn decode(s: Structure) -> Option<Self> {
+        if s.kind != TYPE_SYSTEM {
+            return None;
+        }
+        let uuid = s
+            .data
+            .get(0x08..0x18)
+            .map(|i| Guid::from_bytes(i.try_into().unwrap()));
+        Some(Self {
+            manufacturer: s.string(0x04),
+            product_name: s.string(0x05),
+            version: s.string(0x06),
+            serial_number: s.string(0x07),
+            uuid,
+            family: s.string(0x1A),
+        })
+    }
+}
+
+impl Baseboard {
+    fn decode(s: Structure) -> Option<Self> {
+        if s.kind != TYPE_BASEBOARD {
+            return None;
+        }
+        Some(Self {
+            manufacturer: s.string(0x04),
+            product: s.string(0x05),
+            version: s.string(0x06),
+            serial_number: s.string(0x07),
+        })
+    }
+}
+
+impl Processor {
+    fn decode(s: Structure) -> Option<Self> {
+        if s.kind != TYPE_PROCESSOR {
+            return None;
+        }
+        // 0xFF in the byte counts defers to the 3.0 word counts
+        let count = |byte, word| match s.byte(byte)? {
+            0xFF => s.word(word),
+            count => Some(count as u16),
+        };
+        Some(Self {
+            socket: s.string(0x04),
+            manufacturer: s.string(0x07),
+            version: s.string(0x10),
+            max_speed: s.word(0x14).unwrap_or(0),
+            current_speed: s.word(0x16).unwrap_or(0),
+            is_populated: s.byte(0x18).is_some_and(|i| i & 0x40 != 0),
+            core_count: count(0x23, 0x2A),
+            thread_count: count(0x25, 0x2E),
+        })
+    }
+}
+
+impl MemoryDevice {
+    fn decode(s: Structure) -> Option<Self> {
+        if s.kind != TYPE_MEMORY_DEVICE {
+            return None;
+        }
+        let size = match s.word(0x0C)? {
+            0xFFFF => None,
+            // the extended size is in MiB
+            0x7FFF => s.dword(0x1C).map(|i| ((i & 0x7FFF_FFFF) as u64) << 20),
+            // bit 15 set means KiB granularity
+            size if size & 0x8000 != 0 => Some(((size & 0x7FFF) as u64) << 10),
+            size => Some((size as u64) << 20),
+        };
+        Some(Self {
+            device_locator: s.string(0x10),
+            bank_locator: s.string(0x11),
+            size,
+            memory_type: s.byte(0x12),
+            speed: s.word(0x15).unwrap_or(0),
+            manufacturer: s.string(0x17),
+            part_number: s.string(0x1A),
+        })
+    }
+}
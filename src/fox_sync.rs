@@ -0,0 +1,205 @@
+//! Locks and one-time initialization for statics
+//!
+//! [`Mutex`] is what statics shared with IRQ handlers use: it disables interrupts before
+//! taking its [`SpinLock`], a handler can't spin on a lock held by the code it interrupted.
+//! [`OnceCell`] holds what is found once during boot and only read afterwards.
+
+use core::cell::UnsafeCell;
+use core::hint::spin_loop;
+use core::mem::MaybeUninit;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU8, AtomicU32, Ordering};
+
+#[cfg(not(test))]
+use crate::fox_arch;
+
+/// Ticket lock, the waiters get the lock in the order they asked for it
+pub struct SpinLock<T> {
+    /// Ticket of the next [`SpinLock::lock`]
+    next_ticket: AtomicU32,
+    /// Ticket holding the lock
+    now_serving: AtomicU32,
+    value: UnsafeCell<T>,
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+/// [`SpinLock`] taken with interrupts disabled, for data also used by IRQ handlers
+pub struct Mutex<T> {
+    lock: SpinLock<T>,
+}
+
+pub struct MutexGuard<'a, T> {
+    // dropped in order: unlock, then restore interrupts
+    guard: SpinLockGuard<'a, T>,
+    _interrupts: InterruptGuard,
+}
+
+/// Interrupts disabled until dropped, then restored as they were
+pub struct InterruptGuard {
+    #[cfg(not(test))]
+    was_enabled: bool,
+}
+
+/// Set once, then read only
+pub struct OnceCell<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+const EMPTY: u8 = 0;
+/// A [`OnceCell::set`] is writing the value
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+// SAFETY: the lock hands out one reference at a time
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+// SAFETY: the lock hands out one reference at a time
+unsafe impl<T: Send> Sync for Mutex<T> {}
+// SAFETY: the value is written once before any reference to it is handed out
+unsafe impl<T: Send + Sync> Sync for OnceCell<T> {}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Spin until our ticket is served, never returns if the holder was interrupted and the
+    /// handler locks too, use a [`Mutex`] there
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+        SpinLockGuard { lock: self }
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: we hold the lock
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: we hold the lock
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            lock: SpinLock::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let interrupts = InterruptGuard::new();
+        MutexGuard {
+            guard: self.lock.lock(),
+            _interrupts: interrupts,
+        }
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl InterruptGuard {
+    #[cfg(not(test))]
+    pub fn new() -> Self {
+        let was_enabled = fox_arch::are_interrupts_enabled();
+        fox_arch::disable_interrupts();
+        Self { was_enabled }
+    }
+
+    /// CLI and STI fault outside ring 0, a host test has no interrupts to mask
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        #[cfg(not(test))]
+        if self.was_enabled {
+            fox_arch::enable_interrupts();
+        }
+    }
+}
+
+/// Run `f` with interrupts disabled, nests
+pub fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let _interrupts = InterruptGuard::new();
+    f()
+}
+
+impl<T> OnceCell<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) != READY {
+            return None;
+        }
+        // SAFETY: READY, the value was written and is never written again
+        Some(unsafe { (*self.value.get()).assume_init_ref() })
+    }
+
+    /// Returns `value` back if the cell was already set
+    pub fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(value);
+        }
+        // SAFETY: WRITING, no one else writes or reads the value
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(READY, Ordering::Release);
+        Ok(())
+    }
+}
+
+impl<T> Drop for OnceCell<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == READY {
+            // SAFETY: READY, the value was written
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
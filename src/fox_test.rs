@@ -0,0 +1,313 @@
+//! Self-tests on the target, run instead of the shell with the `--selftest` load option
+//!
+//! Results are printed in the Test Anything Protocol, the exit status tells an automated run
+//! whether every test passed. Tests of hardware that is missing are skipped. They run after
+//! exiting boot services with the drivers loaded and interrupts enabled.
+//!
+//! https://testanything.org/tap-version-13-specification.html
+
+use alloc::alloc::{Layout, alloc, dealloc};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use core::time::Duration;
+
+use uefi::Status;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::I8042;
+use crate::fox_io::{self, Space, Width};
+use crate::fox_mem::{self, PAGE_SIZE};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_time::poll_timeout;
+use crate::{fox_acpi, fox_println};
+
+/// Owner of the reservation made by [`io_reservations`]
+const IO_OWNER: &str = "fox_test";
+/// First of the ports reserved by [`io_reservations`], unused by PC hardware
+const IO_PORT: u64 = 0xFFF0;
+const IO_PORT_COUNT: u64 = 8;
+
+/// Written to the i8042 output buffer by [`i8042_loopback`]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const LOOPBACK_VALUE: u8 = 0x5A;
+/// The byte comes back through the keyboard IRQ
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const LOOPBACK_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Words pushed by [`heap`], 1 MiB
+const HEAP_WORDS: u32 = 256 * 1024;
+
+/// Hardware the tests may use, `None` if its driver is not loaded
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct Devices<'a> {
+    pub i8042: Option<&'a mut I8042>,
+}
+
+/// There is no driver of ours outside x86
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+pub struct Devices {}
+
+enum Failure {
+    /// Does not apply to this machine, e.g. the hardware is missing
+    Skip(&'static str),
+    Fail(String),
+}
+
+type TestResult = Result<(), Failure>;
+
+struct Test {
+    name: &'static str,
+    run: fn(&mut Devices) -> TestResult,
+}
+
+/// Fail with a formatted message unless `condition` holds
+macro_rules! ensure {
+    ($condition:expr, $($arg:tt)*) => {
+        if !$condition {
+            return Err(Failure::Fail(format!($($arg)*)));
+        }
+    };
+}
+
+#[rustfmt::skip]
+const TESTS: &[Test] = &[
+    Test { name: "acpi_checksums", run: acpi_checksums },
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Test { name: "i8042_loopback", run: i8042_loopback },
+    Test { name: "io_reservations", run: io_reservations },
+    Test { name: "io_mmio_ram", run: io_mmio_ram },
+    Test { name: "frame_allocator", run: frame_allocator },
+    Test { name: "heap", run: heap },
+];
+
+/// Run every test, [`Status::SUCCESS`] if none failed, [`Status::ABORTED`] otherwise
+pub fn run(devices: &mut Devices) -> Status {
+    // log::trace!("fox_test::run");
+
+    fox_println!("TAP version 13");
+    fox_println!("1..{}", TESTS.len());
+    let mut failed = 0;
+    for (i, test) in (1..).zip(TESTS) {
+        match (test.run)(devices) {
+            Ok(()) => fox_println!("ok {} - {}", i, test.name),
+            Err(Failure::Skip(reason)) => {
+                fox_println!("ok {} - {} # SKIP {}", i, test.name, reason);
+            }
+            Err(Failure::Fail(message)) => {
+                failed += 1;
+                fox_println!("not ok {} - {}", i, test.name);
+                fox_println!("# {}", message);
+            }
+        }
+    }
+
+    log::info!("Self-test: {} of {} failed", failed, TESTS.len());
+    if failed == 0 {
+        Status::SUCCESS
+    } else {
+        Status::ABORTED
+    }
+}
+
+/// Every table of the XSDT or RSDT and the DSDT has a valid checksum
+fn acpi_checksums(_: &mut Devices) -> TestResult {
+    let mut count = 0;
+    for sdt in fox_acpi::tables()
+        .map(|(_, sdt)| sdt)
+        .chain(fox_acpi::dsdt())
+    {
+        // SAFETY: ACPI tables stay mapped
+        let header = unsafe { sdt.as_ref() };
+        let signature = header.signature;
+        ensure!(
+            header.validate(signature).is_ok(),
+            "{} at {:#x} is invalid",
+            signature,
+            sdt.as_ptr() as u64
+        );
+        count += 1;
+    }
+    if count == 0 {
+        return Err(Failure::Skip("no ACPI tables"));
+    }
+    Ok(())
+}
+
+/// A byte written to the output buffer with command 0xD2 comes back as a key
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn i8042_loopback(devices: &mut Devices) -> TestResult {
+    let Some(i8042) = devices.i8042.as_deref_mut() else {
+        return Err(Failure::Skip("no i8042"));
+    };
+    let Some(is_port2) = i8042.is_keyboard_on_port2() else {
+        return Err(Failure::Skip("no keyboard"));
+    };
+    // keys typed before
+    while i8042.read_key().is_some() {}
+
+    I8042::fake_input(is_port2, LOOPBACK_VALUE)
+        .map_err(|err| Failure::Fail(format!("0xD2 failed: {}", err)))?;
+    let value = poll_timeout(LOOPBACK_TIMEOUT, || i8042.read_key());
+    ensure!(
+        value == Some(LOOPBACK_VALUE),
+        "wrote {:#04x}, read {:x?}",
+        LOOPBACK_VALUE,
+        value
+    );
+    Ok(())
+}
+
+/// Reserved ports are refused until released, bad addresses and values are refused
+fn io_reservations(_: &mut Devices) -> TestResult {
+    fox_io::reserve(Space::Port, IO_PORT, IO_PORT_COUNT, IO_OWNER);
+    let inside = fox_io::owner(Space::Port, IO_PORT + 4, Width::U16);
+    let straddling = fox_io::owner(Space::Port, IO_PORT - 1, Width::U16);
+    let below = fox_io::owner(Space::Port, IO_PORT - 1, Width::U8);
+    let other_space = fox_io::owner(Space::Mmio, IO_PORT, Width::U8);
+    // SAFETY: refused before the port is read
+    let peek = unsafe { fox_io::peek(Space::Port, IO_PORT, Width::U8) };
+    fox_io::release(IO_OWNER);
+    let released = fox_io::owner(Space::Port, IO_PORT, Width::U8);
+
+    ensure!(inside == Some(IO_OWNER), "inside: {:?}", inside);
+    ensure!(straddling == Some(IO_OWNER), "straddling: {:?}", straddling);
+    ensure!(below.is_none(), "below: {:?}", below);
+    ensure!(other_space.is_none(), "MMIO: {:?}", other_space);
+    ensure!(
+        peek == Err(fox_io::Error::Reserved(IO_OWNER)),
+        "peek: {:?}",
+        peek
+    );
+    ensure!(released.is_none(), "released: {:?}", released);
+
+    // SAFETY: refused, past the end of the port space
+    let past_end = unsafe { fox_io::peek(Space::Port, 0xFFFF, Width::U16) };
+    ensure!(
+        past_end == Err(fox_io::Error::InvalidAddress),
+        "past the end: {:?}",
+        past_end
+    );
+    // SAFETY: refused before the port is written
+    let too_large = unsafe { fox_io::poke(Space::Port, IO_PORT, Width::U8, 0x100) };
+    ensure!(
+        too_large == Err(fox_io::Error::InvalidValue),
+        "value too large: {:?}",
+        too_large
+    );
+    Ok(())
+}
+
+/// MMIO accesses of each width to a RAM frame read back what they wrote
+fn io_mmio_ram(_: &mut Devices) -> TestResult {
+    let frame = fox_mem::alloc_frames(1, PAGE_SIZE)
+        .ok_or_else(|| Failure::Fail(String::from("out of memory")))?;
+    let accesses = [
+        (Width::U8, 0xA5),
+        (Width::U16, 0xBEEF),
+        (Width::U32, 0xDEAD_BEEF),
+    ];
+    let mut results = Vec::new();
+    for (i, (width, value)) in (0..).zip(accesses) {
+        let address = frame + 8 * i;
+        // SAFETY: our frame, identity-mapped RAM
+        let read = unsafe {
+            fox_io::poke(Space::Mmio, address, width, value)
+                .and_then(|()| fox_io::peek(Space::Mmio, address, width))
+        };
+        results.push((width, value, read));
+    }
+    // SAFETY: refused before the frame is read
+    let unaligned = unsafe { fox_io::peek(Space::Mmio, frame + 1, Width::U32) };
+    // SAFETY: only used above
+    unsafe { fox_mem::free_frames(frame, 1) };
+
+    for (width, value, read) in results {
+        ensure!(
+            read == Ok(value),
+            "{:?}: wrote {:#x}, read {:x?}",
+            width,
+            value,
+            read
+        );
+    }
+    ensure!(
+        unaligned == Err(fox_io::Error::Unaligned),
+        "unaligned: {:?}",
+        unaligned
+    );
+    Ok(())
+}
+
+/// Aligned runs of frames do not overlap and come back when freed
+fn frame_allocator(_: &mut Devices) -> TestResult {
+    const COUNT: usize = 4;
+    let align = COUNT as u64 * PAGE_SIZE;
+
+    let Some(free) = fox_mem::free_frame_count() else {
+        return Err(Failure::Skip("no frame bitmap"));
+    };
+    let first = fox_mem::alloc_frames(COUNT, align);
+    let second = fox_mem::alloc_frames(COUNT, align);
+    let used = fox_mem::free_frame_count();
+    for address in [first, second].into_iter().flatten() {
+        // SAFETY: only counted
+        unsafe { fox_mem::free_frames(address, COUNT) };
+    }
+    let after = fox_mem::free_frame_count();
+
+    let (Some(first), Some(second)) = (first, second) else {
+        return Err(Failure::Fail(String::from("out of memory")));
+    };
+    ensure!(
+        first.is_multiple_of(align) && second.is_multiple_of(align),
+        "not aligned to {:#x}: {:#x}, {:#x}",
+        align,
+        first,
+        second
+    );
+    ensure!(
+        first.abs_diff(second) >= align,
+        "overlap: {:#x}, {:#x}",
+        first,
+        second
+    );
+    let expected = free - 2 * COUNT as u64;
+    ensure!(
+        used == Some(expected),
+        "{:?} free, expected {}",
+        used,
+        expected
+    );
+    ensure!(
+        after == Some(free),
+        "{:?} free after freeing, was {}",
+        after,
+        free
+    );
+    Ok(())
+}
+
+/// A growing vector keeps its contents, page-aligned allocations are aligned
+fn heap(_: &mut Devices) -> TestResult {
+    let mut words = Vec::new();
+    for i in 0..HEAP_WORDS {
+        words.push(i);
+    }
+    let mismatch = (0..).zip(words).find(|&(i, word)| word != i);
+    ensure!(mismatch.is_none(), "mismatch at {:?}", mismatch);
+
+    let layout = Layout::from_size_align(PAGE_SIZE as usize, PAGE_SIZE as usize).unwrap();
+    // SAFETY: the layout has a non-zero size
+    let ptr = unsafe { alloc(layout) };
+    ensure!(!ptr.is_null(), "page-aligned allocation failed");
+    // SAFETY: allocated above with this layout
+    unsafe { dealloc(ptr, layout) };
+    ensure!(
+        (ptr as usize).is_multiple_of(PAGE_SIZE as usize),
+        "not page-aligned: {:p}",
+        ptr
+    );
+    Ok(())
+}
@@ -0,0 +1,226 @@
+//! Delays that work before and after exiting boot services, TSC uptime and busy waits
+//!
+//! On aarch64 the TSC is the virtual count of the [`generic_timer`], which has no tick yet.
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use uefi::boot::stall;
+
+#[cfg(target_arch = "aarch64")]
+use self::generic_timer::GenericTimer as TickTimer;
+
+/// The tick [`delay`] halts on, the last timer to poll once boot services are gone
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::Pit8254 as TickTimer;
+use crate::fox_arch::counter;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_cpu::{Feature, has_feature};
+use crate::fox_sync::without_interrupts;
+use crate::fox_uefi::is_boot_services_active;
+use crate::fox_watchdog;
+
+#[cfg(target_arch = "aarch64")]
+pub mod generic_timer;
+pub mod pm_timer;
+
+/// TSC ticks per second, 0 until [`calibrate_tsc`]
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// TSC at the last [`calibrate_tsc`]
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+/// [`uptime`] at the last [`calibrate_tsc`], nanoseconds
+static UPTIME_BASE: AtomicU64 = AtomicU64::new(0);
+/// The TSC is not invariant, busy waits count [`pm_timer`] ticks instead
+static IS_TSC_UNSTABLE: AtomicBool = AtomicBool::new(false);
+
+/// A point in time, see [`now`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(Duration);
+
+impl Instant {
+    pub fn elapsed(self) -> Duration {
+        uptime().saturating_sub(self.0)
+    }
+}
+
+/// Measure the TSC against the UEFI stall, or after exiting boot services against the
+/// [`pm_timer`] or, without one, the PIT
+///
+/// Called again after exiting boot services to check the first result, the uptime carries on.
+/// Uptime assumes an invariant TSC, busy waits fall back to the [`pm_timer`] without one.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub fn calibrate_tsc() {
+    // log::trace!("fox_time::calibrate_tsc");
+
+    const CALIBRATION: Duration = Duration::from_millis(10);
+
+    let is_invariant = has_feature(Feature::InvariantTsc);
+    if !is_calibrated() && !is_invariant {
+        log::warn!("TSC: not invariant, uptime may drift");
+    }
+    IS_TSC_UNSTABLE.store(!is_invariant && pm_timer::is_available(), Ordering::Relaxed);
+
+    let (reference, wait): (&str, fn(Duration)) = if is_boot_services_active() {
+        ("UEFI stall", stall)
+    } else if pm_timer::is_available() {
+        ("PM timer", pm_timer::poll_delay)
+    } else {
+        // the firmware timer is gone, channel 0 is free until the driver is loaded
+        ("PIT", TickTimer::poll_delay)
+    };
+    let start = counter();
+    wait(CALIBRATION);
+    let end = counter();
+
+    let frequency =
+        (end - start) * (Duration::from_secs(1).as_micros() / CALIBRATION.as_micros()) as u64;
+    without_interrupts(|| {
+        UPTIME_BASE.store(uptime().as_nanos() as u64, Ordering::Relaxed);
+        TSC_START.store(end, Ordering::Relaxed);
+        TSC_FREQUENCY.store(frequency, Ordering::Release);
+    });
+    log::debug!(
+        "TSC: {} MHz against the {}",
+        frequency / 1_000_000,
+        reference
+    );
+}
+
+/// Take the counter rate from CNTFRQ_EL0, nothing to measure
+///
+/// The rate never changes, a second call keeps the uptime as is.
+#[cfg(target_arch = "aarch64")]
+pub fn calibrate_tsc() {
+    // log::trace!("fox_time::calibrate_tsc");
+
+    if is_calibrated() {
+        return;
+    }
+    let frequency = generic_timer::frequency();
+    if frequency == 0 {
+        log::warn!("Generic timer: CNTFRQ is not set, no uptime");
+        return;
+    }
+    TSC_START.store(counter(), Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Release);
+    log::debug!("Generic timer: {} MHz", frequency / 1_000_000);
+}
+
+/// Measure the TSC against the host clock, host tests have no firmware or timers to wait on
+#[cfg(test)]
+pub fn calibrate_tsc_host() {
+    const CALIBRATION: Duration = Duration::from_millis(10);
+
+    if is_calibrated() {
+        return;
+    }
+    let start = counter();
+    std::thread::sleep(CALIBRATION);
+    let end = counter();
+    let frequency =
+        (end - start) * (Duration::from_secs(1).as_micros() / CALIBRATION.as_micros()) as u64;
+    TSC_START.store(end, Ordering::Relaxed);
+    TSC_FREQUENCY.store(frequency, Ordering::Release);
+}
+
+/// Carry the uptime on from `uptime` after the TSC restarted from 0, on resume from S3
+///
+/// The time asleep is not counted.
+pub fn resume(uptime: Duration) {
+    without_interrupts(|| {
+        UPTIME_BASE.store(uptime.as_nanos() as u64, Ordering::Relaxed);
+        TSC_START.store(counter(), Ordering::Relaxed);
+    });
+}
+
+fn is_calibrated() -> bool {
+    TSC_FREQUENCY.load(Ordering::Acquire) != 0
+}
+
+/// Time since the first [`calibrate_tsc`], zero before
+pub fn uptime() -> Duration {
+    let frequency = TSC_FREQUENCY.load(Ordering::Acquire);
+    if frequency == 0 {
+        return Duration::ZERO;
+    }
+    let ticks = counter() - TSC_START.load(Ordering::Relaxed);
+    Duration::from_nanos(UPTIME_BASE.load(Ordering::Relaxed))
+        + Duration::from_secs(ticks / frequency)
+        + Duration::from_nanos(ticks % frequency * 1_000_000_000 / frequency)
+}
+
+/// A periodic interrupt advances the time, [`delay`] and the idle event loop halt on it
+pub fn is_ticking() -> bool {
+    TickTimer::is_running()
+}
+
+pub fn now() -> Instant {
+    Instant(uptime())
+}
+
+/// Spin on the TSC for `us` microseconds, or on the [`pm_timer`] if the TSC is not invariant
+///
+/// Does not halt or reprogram a timer, so it works with interrupts disabled and leaves the
+/// [`TickTimer`] tick alone.
+pub fn busy_wait_us(us: u64) {
+    busy_wait(Duration::from_micros(us));
+}
+
+fn busy_wait(duration: Duration) {
+    if !is_calibrated() || IS_TSC_UNSTABLE.load(Ordering::Relaxed) {
+        if is_boot_services_active() {
+            stall(duration);
+        } else if pm_timer::is_available() {
+            pm_timer::poll_delay(duration);
+        } else {
+            TickTimer::poll_delay(duration);
+        }
+        return;
+    }
+    let start = now();
+    while start.elapsed() < duration {
+        spin_loop();
+    }
+}
+
+/// Wait for `duration`
+///
+/// Uses the UEFI stall while boot services are available, halts on the [`TickTimer`] tick when it
+/// runs and spins on the TSC otherwise.
+pub fn delay(duration: Duration) {
+    if is_boot_services_active() {
+        stall(duration);
+    } else if is_ticking() {
+        TickTimer::sleep(duration);
+    } else {
+        busy_wait(duration);
+    }
+}
+
+/// Call `f` until it returns `Some` or `timeout` passes
+///
+/// Time is measured with the TSC, by counting delays before it is calibrated.
+/// Also gives up once the [`fox_watchdog`] deadline has passed.
+pub fn poll_timeout<T>(timeout: Duration, mut f: impl FnMut() -> Option<T>) -> Option<T> {
+    /// Delay between attempts before the TSC is calibrated
+    const POLL_INTERVAL: Duration = Duration::from_micros(10);
+
+    let start = now();
+    let mut elapsed = Duration::ZERO;
+    loop {
+        if let Some(value) = f() {
+            return Some(value);
+        }
+        if elapsed >= timeout || fox_watchdog::is_expired() {
+            return None;
+        }
+        if is_calibrated() {
+            spin_loop();
+            elapsed = start.elapsed();
+        } else {
+            busy_wait(POLL_INTERVAL);
+            elapsed += POLL_INTERVAL;
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! ARM generic timer
+//!
+//! The virtual count CNTVCT_EL0, the [`counter`] of aarch64, runs at the CNTFRQ_EL0 rate the
+//! firmware programmed, the same on every core. Its interrupts are in the GTDT, they go through
+//! the GIC which is not driven yet: there is no tick, delays spin on the count.
+//!
+//! https://developer.arm.com/documentation/102379/latest/
+
+use core::arch::asm;
+use core::hint::spin_loop;
+use core::time::Duration;
+
+use crate::fox_acpi::{self, gtdt};
+use crate::fox_arch::counter;
+
+/// The generic timer as the tick of [`super::delay`], what the PIT is on x86
+pub struct GenericTimer;
+
+/// Counter rate in Hz, 0 if the firmware left CNTFRQ_EL0 unset
+pub fn frequency() -> u64 {
+    let frequency: u64;
+    // SAFETY: reads a register readable at EL0 and above
+    unsafe {
+        asm!(
+            "mrs {}, cntfrq_el0",
+            out(reg) frequency,
+            options(nomem, nostack, preserves_flags)
+        )
+    };
+    frequency
+}
+
+/// Log the timer interrupts of the GTDT
+pub fn init() {
+    // log::trace!("generic_timer::init");
+
+    match gtdt() {
+        Ok(gtdt) => log::debug!(
+            "Generic timer: virtual GSIV {}, physical GSIV {}{}",
+            gtdt.virtual_gsiv,
+            gtdt.physical_gsiv,
+            if gtdt.is_always_on { ", always on" } else { "" }
+        ),
+        Err(fox_acpi::Error::NoTable) => log::debug!("Generic timer: no GTDT"),
+        Err(err) => log::warn!("GTDT: {}", err),
+    }
+}
+
+impl GenericTimer {
+    /// No tick until the GIC is driven
+    pub fn is_running() -> bool {
+        false
+    }
+
+    /// Spins, there is no tick to halt on yet
+    pub fn sleep(duration: Duration) {
+        Self::poll_delay(duration);
+    }
+
+    /// Spin on the virtual count, returns at once without a rate
+    pub fn poll_delay(duration: Duration) {
+        let frequency = frequency();
+        let ticks = (duration.as_nanos() * frequency as u128 / 1_000_000_000) as u64;
+        let start = counter();
+        while counter().wrapping_sub(start) < ticks {
+            spin_loop();
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! ACPI power management timer (PM_TMR)
+//!
+//! A free-running 3.579545 MHz counter in the PM_TMR_BLK of the FADT, 24 bits wide unless the
+//! FADT says 32. It never stops or changes rate, so it calibrates the TSC and times delays when
+//! the TSC cannot be trusted. Delays read it often enough to see every rollover.
+//!
+//! https://uefi.org/specs/ACPI/6.5/04_ACPI_Hardware_Specification.html#power-management-timer
+
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use core::time::Duration;
+
+use acpi::address::AddressSpace;
+
+use crate::fox_acpi::fadt;
+use crate::fox_port::Pio;
+
+/// Counter rate, Hz
+pub const FREQUENCY: u64 = 3_579_545;
+/// Reads in a row without a tick before the timer is taken as stuck, a port read takes longer
+/// than a tick
+const MAX_UNCHANGED_READS: u32 = 10_000;
+
+/// Port of the counter, 0 without one, set by [`init`]
+static PORT: AtomicU16 = AtomicU16::new(0);
+/// Bits that count, 24 or 32
+static MASK: AtomicU32 = AtomicU32::new(0);
+
+/// Find the timer in the FADT
+pub fn init() {
+    // log::trace!("pm_timer::init");
+
+    let Some(fadt) = fadt() else {
+        return;
+    };
+    let block = match fadt.pm_timer_block() {
+        Ok(Some(block)) => block,
+        Ok(None) => {
+            log::debug!("PM timer: none");
+            return;
+        }
+        Err(err) => {
+            log::warn!("PM timer: {:?}", err);
+            return;
+        }
+    };
+    if block.address_space != AddressSpace::SystemIo || block.address == 0 {
+        log::warn!("PM timer: {:?} not supported", block.address_space);
+        return;
+    }
+    // packed, copied out
+    let flags = fadt.flags;
+    let bits = if flags.pm_timer_is_32_bit() { 32 } else { 24 };
+    MASK.store(u32::MAX >> (32 - bits), Ordering::Relaxed);
+    PORT.store(block.address as u16, Ordering::Release);
+    log::debug!("PM timer: {} bits at port {:#x}", bits, block.address);
+}
+
+pub fn is_available() -> bool {
+    PORT.load(Ordering::Acquire) != 0
+}
+
+/// The counter, 0 without a timer
+fn read() -> u32 {
+    let port = PORT.load(Ordering::Acquire);
+    if port == 0 {
+        return 0;
+    }
+    let port = Pio::<u32>::new(port);
+    // SAFETY: PM_TMR_BLK from the FADT, reading has no side effects
+    let value = unsafe { port.read() };
+    value & MASK.load(Ordering::Relaxed)
+}
+
+/// Ticks from `from` to `to`, across one rollover
+fn ticks_between(from: u32, to: u32) -> u64 {
+    (to.wrapping_sub(from) & MASK.load(Ordering::Relaxed)) as u64
+}
+
+/// Spin for `duration`, returns at once without a timer
+///
+/// A counter that stops is dropped: the delay returns early and [`is_available`] is then false.
+pub fn poll_delay(duration: Duration) {
+    if !is_available() {
+        return;
+    }
+    let target = (duration.as_nanos() * FREQUENCY as u128 / 1_000_000_000) as u64;
+    let mut last = read();
+    let mut elapsed = 0;
+    let mut unchanged = 0;
+    while elapsed < target {
+        spin_loop();
+        let now = read();
+        if now == last {
+            unchanged += 1;
+            if unchanged == MAX_UNCHANGED_READS {
+                log::warn!("PM timer: stuck at {:#x}, not used any more", now);
+                PORT.store(0, Ordering::Release);
+                return;
+            }
+            continue;
+        }
+        unchanged = 0;
+        elapsed += ticks_between(last, now);
+        last = now;
+    }
+}
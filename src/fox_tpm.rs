@@ -0,0 +1,327 @@
+//! TPM 2.0 through the EFI_TCG2 protocol
+//!
+//! The protocol is gone after exiting boot services, so [`init`] copies the event log and reads
+//! the SHA1 and SHA256 PCR banks while it is there. With `measure=on` in [`fox_config`] the
+//! configuration is measured into [`CONFIG_PCR`] first.
+//!
+//! https://trustedcomputinggroup.org/resource/tcg-efi-protocol-specification/
+//! https://trustedcomputinggroup.org/resource/tpm-library-specification/ (TPM2_PCR_Read)
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use uefi::Status;
+use uefi::boot::{
+    OpenProtocolAttributes, OpenProtocolParams, get_handle_for_protocol, image_handle,
+    open_protocol,
+};
+use uefi::proto::tcg::v2::{
+    EventLogFormat, HashAlgorithm, HashLogExtendEventFlags, PcrEventInputs, Tcg,
+};
+use uefi::proto::tcg::{EventType, PcrIndex};
+
+use crate::fox_config;
+use crate::fox_uefi::is_boot_services_active;
+use crate::{fox_print, fox_println};
+
+/// PCR of boot loader configuration, also used by GRUB for its commands
+pub const CONFIG_PCR: u32 = 8;
+/// PCRs of a PC client TPM
+const PCR_COUNT: u32 = 24;
+
+const TPM_ST_NO_SESSIONS: u16 = 0x8001;
+const TPM_CC_PCR_READ: u32 = 0x0000_017E;
+const TPM_RC_SUCCESS: u32 = 0;
+/// Command and response header: tag, size, command code or response code
+const TPM_HEADER: usize = 10;
+
+/// Init [`init`]
+static TPM: AtomicPtr<Tpm> = AtomicPtr::new(null_mut());
+
+/// Storage for [`TPM`], written once by [`init`]
+static mut TPM_STORAGE: Tpm = Tpm::new();
+
+/// What [`init`] found
+pub struct Tpm {
+    /// Four ASCII characters, e.g. `IFX` or `MSFT`
+    pub manufacturer: u32,
+    pub active_banks: HashAlgorithm,
+    pub events: Vec<Event>,
+    /// The firmware ran out of space for the event log
+    pub is_truncated: bool,
+    pub pcrs: Vec<Pcr>,
+}
+
+/// Entry of the TCG event log
+pub struct Event {
+    pub pcr: u32,
+    pub event_type: EventType,
+    pub data: Vec<u8>,
+}
+
+pub struct Pcr {
+    pub bank: Bank,
+    pub index: u32,
+    pub digest: Vec<u8>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Bank {
+    Sha1,
+    Sha256,
+}
+
+impl Tpm {
+    const fn new() -> Self {
+        Self {
+            manufacturer: 0,
+            active_banks: HashAlgorithm::SHA1,
+            events: Vec::new(),
+            is_truncated: false,
+            pcrs: Vec::new(),
+        }
+    }
+}
+
+impl Bank {
+    /// TPM_ALG_ID
+    fn algorithm(self) -> u16 {
+        match self {
+            Self::Sha1 => 0x0004,
+            Self::Sha256 => 0x000B,
+        }
+    }
+
+    fn hash_algorithm(self) -> HashAlgorithm {
+        match self {
+            Self::Sha1 => HashAlgorithm::SHA1,
+            Self::Sha256 => HashAlgorithm::SHA256,
+        }
+    }
+}
+
+/// The TPM state read by [`init`], `None` before or without a TPM
+pub fn tpm() -> Option<&'static Tpm> {
+    let ptr = TPM.load(Ordering::Acquire);
+    unsafe { ptr.as_ref() }
+}
+
+/// Measure the configuration if asked to, then copy the event log and the PCRs
+///
+/// Needs boot services, `NOT_FOUND` without a TPM 2.0.
+pub fn init() -> uefi::Result {
+    // log::trace!("fox_tpm::init");
+    assert!(is_boot_services_active());
+
+    let handle = get_handle_for_protocol::<Tcg>()?;
+    let params = OpenProtocolParams {
+        handle,
+        agent: image_handle(),
+        controller: None,
+    };
+    // SAFETY: the firmware keeps using the TPM, only stateless calls are made
+    let mut tcg = unsafe { open_protocol::<Tcg>(params, OpenProtocolAttributes::GetProtocol) }?;
+    let capability = tcg.get_capability()?;
+    if !capability.tpm_present() {
+        return Err(Status::NOT_FOUND.into());
+    }
+
+    let config = fox_config::config();
+    if config.measure_config {
+        let mut text = String::new();
+        let _ = write!(text, "{}", config);
+        match measure(&mut tcg, CONFIG_PCR, b"my-uefi-app config", text.as_bytes()) {
+            Ok(()) => log::info!("TPM: configuration measured into PCR {}", CONFIG_PCR),
+            Err(err) => log::warn!("TPM: measuring the configuration failed: {}", err.status()),
+        }
+    }
+
+    let tpm = &raw mut TPM_STORAGE;
+    // SAFETY: called once during boot, before anyone reads `TPM`
+    let tpm = unsafe { &mut *tpm };
+    tpm.manufacturer = capability.manufacturer_id;
+    tpm.active_banks = capability.active_pcr_banks;
+
+    let log = tcg.get_event_log_v2(EventLogFormat::TCG_2)?;
+    tpm.is_truncated = log.is_truncated();
+    tpm.events = log
+        .events()
+        .map(|event| Event {
+            pcr: event.pcr_index().0,
+            event_type: event.event_type(),
+            data: event.event_data().to_vec(),
+        })
+        .collect();
+
+    for bank in [Bank::Sha1, Bank::Sha256] {
+        if !tpm.active_banks.contains(bank.hash_algorithm()) {
+            continue;
+        }
+        match read_pcrs(&mut tcg, bank) {
+            Ok(pcrs) => tpm.pcrs.extend(pcrs),
+            Err(err) => log::warn!("TPM: reading {:?} PCRs failed: {}", bank, err.status()),
+        }
+    }
+
+    log::info!(
+        "TPM: {}, {} events, {} PCRs",
+        Manufacturer(tpm.manufacturer),
+        tpm.events.len(),
+        tpm.pcrs.len()
+    );
+    TPM.store(tpm, Ordering::Release);
+    Ok(())
+}
+
+/// Hash `data` into `pcr` and log it as an IPL event described by `description`
+fn measure(tcg: &mut Tcg, pcr: u32, description: &[u8], data: &[u8]) -> uefi::Result {
+    let mut buffer = [MaybeUninit::uninit(); 128];
+    let event =
+        PcrEventInputs::new_in_buffer(&mut buffer, PcrIndex(pcr), EventType::IPL, description)
+            .map_err(|err| err.status())?;
+    tcg.hash_log_extend_event(HashLogExtendEventFlags::empty(), data, event)
+}
+
+/// TPM2_PCR_Read of every PCR of `bank`, the TPM returns at most 8 digests per command
+fn read_pcrs(tcg: &mut Tcg, bank: Bank) -> uefi::Result<Vec<Pcr>> {
+    let mut pcrs = Vec::new();
+    for byte in 0..PCR_COUNT / 8 {
+        let mut select = [0u8; 3];
+        select[byte as usize] = 0xFF;
+
+        let mut command = Vec::with_capacity(TPM_HEADER + 10);
+        command.extend(TPM_ST_NO_SESSIONS.to_be_bytes());
+        command.extend(0u32.to_be_bytes());
+        command.extend(TPM_CC_PCR_READ.to_be_bytes());
+        // TPML_PCR_SELECTION with one TPMS_PCR_SELECTION
+        command.extend(1u32.to_be_bytes());
+        command.extend(bank.algorithm().to_be_bytes());
+        command.push(select.len() as u8);
+        command.extend(select);
+        let size = command.len() as u32;
+        command[2..6].copy_from_slice(&size.to_be_bytes());
+
+        let mut response = [0u8; 512];
+        tcg.submit_command(&command, &mut response)?;
+        let digests = parse_pcr_read(&response).ok_or(Status::DEVICE_ERROR)?;
+        pcrs.extend(digests.into_iter().map(|(index, digest)| Pcr {
+            bank,
+            index,
+            digest,
+        }));
+    }
+    Ok(pcrs)
+}
+
+/// PCR indices and digests of a TPM2_PCR_Read response
+fn parse_pcr_read(response: &[u8]) -> Option<Vec<(u32, Vec<u8>)>> {
+    let mut reader = Reader(response);
+    reader.bytes(6)?;
+    if reader.u32()? != TPM_RC_SUCCESS {
+        return None;
+    }
+    // pcrUpdateCounter
+    reader.u32()?;
+    // pcrSelectionOut, which PCRs the digests belong to
+    let mut indices = Vec::new();
+    for _ in 0..reader.u32()? {
+        reader.bytes(2)?;
+        let size = reader.bytes(1)?[0] as usize;
+        for (i, &bits) in reader.bytes(size)?.iter().enumerate() {
+            indices.extend(
+                (0..8)
+                    .filter(|bit| bits & (1 << bit) != 0)
+                    .map(|bit| 8 * i as u32 + bit),
+            );
+        }
+    }
+    // pcrValues
+    // the count comes from the TPM, only as many digests as selected PCRs are taken
+    let count = (reader.u32()? as usize).min(indices.len());
+    let mut digests = Vec::with_capacity(count);
+    for index in indices.into_iter().take(count) {
+        let size = reader.u16()? as usize;
+        digests.push((index, reader.bytes(size)?.to_vec()));
+    }
+    Some(digests)
+}
+
+/// Big-endian fields of a TPM response
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.0.get(..count)?;
+        self.0 = &self.0[count..];
+        Some(bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+}
+
+/// Manufacturer ID as its ASCII characters
+struct Manufacturer(u32);
+
+impl core::fmt::Display for Manufacturer {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for c in self.0.to_be_bytes() {
+            if c.is_ascii_graphic() {
+                write!(f, "{}", c as char)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Print the event log, one line per event
+pub fn print_event_log() {
+    let Some(tpm) = tpm() else {
+        fox_println!("no TPM");
+        return;
+    };
+    for event in &tpm.events {
+        fox_print!(
+            "PCR {:2} {:#010x} {} bytes",
+            event.pcr,
+            event.event_type.0,
+            event.data.len()
+        );
+        // EV_EFI_ACTION and the like are text
+        if let Ok(text) = core::str::from_utf8(&event.data)
+            && !text.is_empty()
+            && text.chars().all(|c| !c.is_control() || c == '\0')
+        {
+            fox_print!(" \"{}\"", text.trim_end_matches('\0'));
+        }
+        fox_println!();
+    }
+    if tpm.is_truncated {
+        fox_println!("(truncated)");
+    }
+}
+
+/// Print the PCRs of every bank read
+pub fn print_pcrs() {
+    let Some(tpm) = tpm() else {
+        fox_println!("no TPM");
+        return;
+    };
+    fox_println!("Manufacturer {}", Manufacturer(tpm.manufacturer));
+    for pcr in &tpm.pcrs {
+        fox_print!("{:?} {:2} ", pcr.bank, pcr.index);
+        for byte in &pcr.digest {
+            fox_print!("{:02x}", byte);
+        }
+        fox_println!();
+    }
+}
@@ -1,20 +1,25 @@
-use core::ptr::{NonNull, null_mut};
-use core::sync::atomic::{AtomicPtr, Ordering};
+use alloc::string::String;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use acpi::rsdp::Rsdp;
+use uefi::boot::{image_handle, open_protocol_exclusive};
+use uefi::mem::memory_map::{MemoryMap, MemoryMapOwned};
+use uefi::proto::loaded_image::LoadedImage;
 use uefi::system::with_config_table;
 use uefi::table::cfg::ConfigTableEntry;
-use x86_64::VirtAddr;
 
-/// Init [`init_rsdp`]
-static ACPI: AtomicPtr<Rsdp> = AtomicPtr::new(null_mut());
+use crate::fox_acpi::{Error, Table, init_rsdp, record_missing};
+use crate::fox_arch;
 
-pub fn rsdp_raw() -> Option<NonNull<Rsdp>> {
-    let ptr = ACPI.load(Ordering::Acquire);
-    NonNull::new(ptr)
-}
+pub mod secure_boot;
+pub mod vars;
+
+/// Cleared by [`exit_boot_services`]
+static BOOT_SERVICES: AtomicBool = AtomicBool::new(true);
 
-pub fn init_acpi() {
+/// Find the RSDP in the configuration table, see [`init_rsdp`]
+pub fn init_acpi() -> Result<(), Error> {
     // log::trace!("init_acpi");
 
     let acpi_address = with_config_table(|slice: &[ConfigTableEntry]| {
@@ -24,12 +29,12 @@ pub fn init_acpi() {
                 ConfigTableEntry::ACPI_GUID => {
                     log::debug!("Found ACPI1");
                     if acpi_address.is_none() {
-                        acpi_address = Some(VirtAddr::from_ptr(i.address));
+                        acpi_address = Some(i.address);
                     }
                 }
                 ConfigTableEntry::ACPI2_GUID => {
                     log::debug!("Found ACPI2");
-                    acpi_address = Some(VirtAddr::from_ptr(i.address));
+                    acpi_address = Some(i.address);
                     break;
                 }
                 _ => {
@@ -40,14 +45,56 @@ pub fn init_acpi() {
         acpi_address
     });
 
-    let acpi_address = acpi_address.expect("ACPI not found");
+    let Some(rsdp) =
+        acpi_address.and_then(|address| NonNull::new(address.cast::<Rsdp>().cast_mut()))
+    else {
+        record_missing(Table::Rsdp);
+        return Err(Error::NoRsdp);
+    };
 
     log::debug!("Found RSDP");
 
-    let rsdp = acpi_address.as_u64() as *mut Rsdp;
-    let rsdp = unsafe { rsdp.as_ref() }.unwrap();
-    rsdp.validate().expect("invalid RSDP");
-    // println!("RSDP = {:?}", rsdp);
+    // SAFETY: the firmware keeps its ACPI tables mapped
+    unsafe { init_rsdp(rsdp) }
+}
+
+/// Load options of our image as text, empty if there are none
+///
+/// The UEFI shell passes the command line, the image name first. Boot entries may pass anything,
+/// options that are not UCS-2 are taken as empty.
+pub fn load_options() -> uefi::Result<String> {
+    // log::trace!("load_options");
+
+    let image = open_protocol_exclusive::<LoadedImage>(image_handle())?;
+    let Some(bytes) = image.load_options_as_bytes() else {
+        return Ok(String::new());
+    };
+    let ucs2 = bytes
+        .chunks_exact(2)
+        .map(|i| u16::from_le_bytes([i[0], i[1]]))
+        .take_while(|&c| c != 0);
+    Ok(char::decode_utf16(ucs2)
+        .collect::<Result<String, _>>()
+        .unwrap_or_default())
+}
+
+pub fn is_boot_services_active() -> bool {
+    BOOT_SERVICES.load(Ordering::Acquire)
+}
+
+/// Leave the firmware: after this only runtime services, ACPI tables and our own drivers are left
+///
+/// Returns the final memory map.
+pub fn exit_boot_services() -> MemoryMapOwned {
+    log::info!("Exit boot services");
+
+    // SAFETY: nothing obtained from boot services is used afterwards
+    let memory_map = unsafe { uefi::boot::exit_boot_services(None) };
+    BOOT_SERVICES.store(false, Ordering::Release);
+
+    // the firmware interrupt handlers are no longer valid
+    fox_arch::disable_interrupts();
 
-    ACPI.store(acpi_address.as_u64() as _, Ordering::Release);
+    log::info!("Exited boot services ({} memory regions)", memory_map.len());
+    memory_map
 }
@@ -0,0 +1,217 @@
+//! Secure Boot state and key databases
+//!
+//! PK, KEK, db and dbx hold EFI_SIGNATURE_LISTs. Certificates are shown by the CN and O of
+//! their subject, hashes are only counted.
+//!
+//! https://uefi.org/specs/UEFI/2.10/32_Secure_Boot_and_Driver_Signing.html#signature-database
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::str;
+
+use uefi::runtime::VariableVendor;
+use uefi::{Guid, guid};
+
+use super::vars;
+use crate::fox_println;
+
+const CERT_SHA1: Guid = guid!("826ca512-cf10-4ac9-b187-be01496631bd");
+const CERT_SHA256: Guid = guid!("c1c41626-504c-4092-aca9-41f936934328");
+const CERT_SHA384: Guid = guid!("ff3e5307-9fd0-48c9-85f1-8ad56c701e01");
+const CERT_SHA512: Guid = guid!("093e0fae-a6c4-4f50-9f1b-d41b2b89c19a");
+const CERT_RSA2048: Guid = guid!("3c5766e8-269c-4e34-aa14-ed776e85b3b6");
+const CERT_X509: Guid = guid!("a5c059a1-94e4-4aa7-87b5-ab155c2bf072");
+const CERT_X509_SHA256: Guid = guid!("3bd2a492-96c0-4079-b420-fcf98ef103ed");
+
+/// Key databases with their vendor, in the order of the chain of trust
+const DATABASES: [(&str, VariableVendor); 4] = [
+    ("PK", VariableVendor::GLOBAL_VARIABLE),
+    ("KEK", VariableVendor::GLOBAL_VARIABLE),
+    ("db", VariableVendor::IMAGE_SECURITY_DATABASE),
+    ("dbx", VariableVendor::IMAGE_SECURITY_DATABASE),
+];
+
+// DER tags
+const DER_OID: u8 = 0x06;
+const DER_SEQUENCE: u8 = 0x30;
+const DER_SET: u8 = 0x31;
+/// `[0]` explicit, the version of a certificate
+const DER_CONTEXT_0: u8 = 0xA0;
+
+// attribute types of a Name
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_ORGANIZATION: &[u8] = &[0x55, 0x04, 0x0A];
+
+/// `None` if the variable is missing
+#[derive(Copy, Clone, Debug)]
+pub struct State {
+    /// Images are verified
+    pub secure_boot: Option<bool>,
+    /// No PK is enrolled, the key databases can be written without authentication
+    pub setup_mode: Option<bool>,
+}
+
+/// One EFI_SIGNATURE_LIST: signatures of the same type and size
+pub struct SignatureList<'a> {
+    pub kind: Guid,
+    /// SignatureData of each entry, without the owner GUID
+    pub signatures: Vec<&'a [u8]>,
+}
+
+pub fn state() -> State {
+    let flag = |name| match vars::read(name, &VariableVendor::GLOBAL_VARIABLE) {
+        Ok((data, _)) => data.first().map(|&i| i == 1),
+        Err(_) => None,
+    };
+    State {
+        secure_boot: flag("SecureBoot"),
+        setup_mode: flag("SetupMode"),
+    }
+}
+
+/// Split a signature database, a truncated list ends it
+pub fn signature_lists(data: &[u8]) -> Vec<SignatureList<'_>> {
+    // SignatureType, SignatureListSize, SignatureHeaderSize, SignatureSize
+    const HEADER: usize = 16 + 3 * 4;
+    const OWNER: usize = 16;
+
+    let mut lists = Vec::new();
+    let mut rest = data;
+    while rest.len() >= HEADER {
+        let u32_at = |offset: usize| {
+            u32::from_le_bytes(rest[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        let kind = Guid::from_bytes(rest[..16].try_into().unwrap());
+        let (list_size, header_size, signature_size) = (u32_at(16), u32_at(20), u32_at(24));
+        let Some(list) = rest.get(..list_size) else {
+            log::warn!("Secure Boot: truncated signature list");
+            break;
+        };
+        if list_size < HEADER || signature_size <= OWNER {
+            log::warn!("Secure Boot: invalid signature list");
+            break;
+        }
+        let signatures = list
+            .get(HEADER + header_size..)
+            .unwrap_or_default()
+            .chunks_exact(signature_size)
+            .map(|i| &i[OWNER..])
+            .collect();
+        lists.push(SignatureList { kind, signatures });
+        rest = &rest[list_size..];
+    }
+    lists
+}
+
+/// Print the state and every key database
+pub fn print_report() {
+    let state = state();
+    let on_off = |flag: Option<bool>| match flag {
+        Some(true) => "on",
+        Some(false) => "off",
+        None => "unknown",
+    };
+    fox_println!(
+        "SecureBoot {}, SetupMode {}",
+        on_off(state.secure_boot),
+        on_off(state.setup_mode)
+    );
+
+    for (name, vendor) in DATABASES {
+        let data = match vars::read(name, &vendor) {
+            Ok((data, _)) => data,
+            Err(err) => {
+                fox_println!("{}: {}", name, err.status());
+                continue;
+            }
+        };
+        fox_println!("{}: {} bytes", name, data.len());
+        for list in signature_lists(&data) {
+            if list.kind == CERT_X509 {
+                for cert in &list.signatures {
+                    let subject = subject(cert).unwrap_or_else(|| String::from("(unparsed)"));
+                    fox_println!("  X509 {}", subject);
+                }
+            } else {
+                fox_println!("  {} x{}", kind_name(&list.kind), list.signatures.len());
+            }
+        }
+    }
+}
+
+fn kind_name(kind: &Guid) -> &'static str {
+    match *kind {
+        CERT_SHA1 => "SHA1",
+        CERT_SHA256 => "SHA256",
+        CERT_SHA384 => "SHA384",
+        CERT_SHA512 => "SHA512",
+        CERT_RSA2048 => "RSA2048",
+        CERT_X509 => "X509",
+        CERT_X509_SHA256 => "X509 SHA256",
+        _ => "unknown type",
+    }
+}
+
+/// `CN=..., O=...` of the subject of a DER certificate
+fn subject(cert: &[u8]) -> Option<String> {
+    let (_, certificate, _) = der(cert, DER_SEQUENCE)?;
+    let (_, tbs, _) = der(certificate, DER_SEQUENCE)?;
+    // version, then serialNumber, signature, issuer, validity, subject
+    let mut rest = tbs;
+    if rest.first() == Some(&DER_CONTEXT_0) {
+        rest = der_any(rest)?.1;
+    }
+    for _ in 0..4 {
+        rest = der_any(rest)?.1;
+    }
+    let (_, mut name, _) = der(rest, DER_SEQUENCE)?;
+
+    let mut subject = String::new();
+    // a SET of SEQUENCE { type, value } per relative distinguished name
+    while !name.is_empty() {
+        let (_, set, next) = der(name, DER_SET)?;
+        name = next;
+        let (_, attribute, _) = der(set, DER_SEQUENCE)?;
+        let (_, oid, value) = der(attribute, DER_OID)?;
+        let label = match oid {
+            OID_COMMON_NAME => "CN",
+            OID_ORGANIZATION => "O",
+            _ => continue,
+        };
+        // any of the string types
+        let (_, value, _) = der_any(value)?;
+        if !subject.is_empty() {
+            subject.push_str(", ");
+        }
+        subject.push_str(label);
+        subject.push('=');
+        subject.push_str(str::from_utf8(value).unwrap_or("?"));
+    }
+    Some(subject)
+}
+
+/// Element with tag `tag`: the tag, its contents and what follows
+fn der(bytes: &[u8], tag: u8) -> Option<(u8, &[u8], &[u8])> {
+    der_any(bytes).filter(|(i, _, _)| *i == tag)
+}
+
+fn der_any(bytes: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = bytes.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        // long form, the low bits count the length bytes
+        let count = (first & 0x7F) as usize;
+        if count > size_of::<usize>() {
+            return None;
+        }
+        let len_bytes = rest.get(..count)?;
+        let len = len_bytes
+            .iter()
+            .fold(0, |len, &byte| (len << 8) | byte as usize);
+        (len, &rest[count..])
+    };
+    let contents = rest.get(..len)?;
+    Some((tag, contents, &rest[len..]))
+}
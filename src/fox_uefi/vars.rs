@@ -0,0 +1,236 @@
+//! UEFI variables: list them, decode well-known ones, keep our own
+//!
+//! Our variables live under [`APP_VENDOR`], non-volatile and accessible at runtime, so they
+//! survive a reboot and can be changed after exiting boot services.
+//!
+//! https://uefi.org/specs/UEFI/2.10/08_Services_Runtime_Services.html#variable-services
+//! https://uefi.org/specs/UEFI/2.10/03_Boot_Manager.html#globally-defined-variables
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use uefi::runtime::{self, VariableAttributes, VariableVendor};
+use uefi::{CStr16, Status, guid};
+
+/// Vendor GUID of the variables of this app
+pub const APP_VENDOR: VariableVendor =
+    VariableVendor(guid!("6f0b3a2e-9d41-4c7a-8e55-0a1d7c3f5b94"));
+
+/// Attributes of the variables of this app
+const APP_ATTRIBUTES: VariableAttributes = VariableAttributes::NON_VOLATILE
+    .union(VariableAttributes::BOOTSERVICE_ACCESS)
+    .union(VariableAttributes::RUNTIME_ACCESS);
+
+/// UCS-2 characters of a variable name we create or look up, with the terminating null
+const NAME_LEN: usize = 128;
+
+/// A variable as listed by [`variables`]
+pub struct Variable {
+    pub name: String,
+    pub vendor: VariableVendor,
+    pub attributes: VariableAttributes,
+    pub size: usize,
+}
+
+/// Formats the value of a variable, well-known ones decoded, others as a byte count
+pub struct ValueDisplay<'a> {
+    pub name: &'a str,
+    pub vendor: VariableVendor,
+    pub data: &'a [u8],
+}
+
+/// Every variable the firmware lets us see, in firmware order
+pub fn variables() -> uefi::Result<Vec<Variable>> {
+    // log::trace!("vars::variables");
+
+    let mut variables = Vec::new();
+    let mut name = vec![0u16; NAME_LEN];
+    let mut vendor = VariableVendor(uefi::Guid::ZERO);
+    loop {
+        match runtime::get_next_variable_key(&mut name, &mut vendor) {
+            Ok(()) => {}
+            Err(err) if err.status() == Status::NOT_FOUND => break,
+            Err(err) => match *err.data() {
+                // the size is in bytes, the name so far must be kept
+                Some(size) if err.status() == Status::BUFFER_TOO_SMALL => {
+                    name.resize(size.div_ceil(2), 0);
+                    continue;
+                }
+                _ => return Err(err.status().into()),
+            },
+        }
+        let Ok(cstr) = CStr16::from_u16_with_nul(&name[..=nul_position(&name)]) else {
+            continue;
+        };
+        // listed even if it cannot be read
+        let (data, attributes) =
+            read_cstr(cstr, &vendor).unwrap_or((Vec::new(), VariableAttributes::empty()));
+        variables.push(Variable {
+            name: decode(&name),
+            vendor,
+            attributes,
+            size: data.len(),
+        });
+    }
+    Ok(variables)
+}
+
+/// Value and attributes of a variable
+pub fn read(name: &str, vendor: &VariableVendor) -> uefi::Result<(Vec<u8>, VariableAttributes)> {
+    let mut buf = [0; NAME_LEN];
+    read_cstr(encode(name, &mut buf)?, vendor)
+}
+
+/// Create or replace a variable of this app
+pub fn write(name: &str, data: &[u8]) -> uefi::Result {
+    // log::trace!("vars::write({}, {} bytes)", name, data.len());
+    let mut buf = [0; NAME_LEN];
+    runtime::set_variable(encode(name, &mut buf)?, &APP_VENDOR, APP_ATTRIBUTES, data)
+}
+
+/// Delete a variable of this app
+pub fn delete(name: &str) -> uefi::Result {
+    // log::trace!("vars::delete({})", name);
+    let mut buf = [0; NAME_LEN];
+    runtime::delete_variable(encode(name, &mut buf)?, &APP_VENDOR)
+}
+
+fn read_cstr(
+    name: &CStr16,
+    vendor: &VariableVendor,
+) -> uefi::Result<(Vec<u8>, VariableAttributes)> {
+    let size = match runtime::get_variable(name, vendor, &mut []) {
+        Ok((_, attributes)) => return Ok((Vec::new(), attributes)),
+        Err(err) => match *err.data() {
+            Some(size) if err.status() == Status::BUFFER_TOO_SMALL => size,
+            _ => return Err(err.status().into()),
+        },
+    };
+    let mut data = vec![0; size];
+    let (value, attributes) =
+        runtime::get_variable(name, vendor, &mut data).map_err(|err| err.status())?;
+    let len = value.len();
+    data.truncate(len);
+    Ok((data, attributes))
+}
+
+fn encode<'a>(name: &str, buf: &'a mut [u16]) -> uefi::Result<&'a CStr16> {
+    CStr16::from_str_with_buf(name, buf).map_err(|_| Status::INVALID_PARAMETER.into())
+}
+
+fn nul_position(name: &[u16]) -> usize {
+    name.iter().position(|&c| c == 0).unwrap_or(name.len() - 1)
+}
+
+/// UCS-2 up to the first null
+fn decode(ucs2: &[u16]) -> String {
+    let len = ucs2.iter().position(|&c| c == 0).unwrap_or(ucs2.len());
+    char::decode_utf16(ucs2[..len].iter().copied())
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+
+/// `Boot0001` and the like
+fn is_boot_option(name: &str) -> bool {
+    name.len() == 8 && name.starts_with("Boot") && name[4..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+fn u16_list(data: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    data.chunks_exact(2)
+        .map(|i| u16::from_le_bytes([i[0], i[1]]))
+}
+
+/// EFI_LOAD_OPTION: attributes, device path length, description, device path, optional data
+fn fmt_load_option(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    const LOAD_OPTION_ACTIVE: u32 = 0x1;
+    const LOAD_OPTION_HIDDEN: u32 = 0x8;
+
+    let Some(header) = data.get(..6) else {
+        return write!(f, "truncated load option");
+    };
+    let attributes = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let path_len = u16::from_le_bytes([header[4], header[5]]) as usize;
+    let description: Vec<u16> = u16_list(&data[6..]).take_while(|&c| c != 0).collect();
+    let optional_start = 6 + 2 * (description.len() + 1) + path_len;
+
+    write!(f, "\"{}\"", decode(&description))?;
+    if attributes & LOAD_OPTION_ACTIVE != 0 {
+        write!(f, " active")?;
+    }
+    if attributes & LOAD_OPTION_HIDDEN != 0 {
+        write!(f, " hidden")?;
+    }
+    write!(f, ", device path {} bytes", path_len)?;
+    let optional = data.len().saturating_sub(optional_start);
+    if optional > 0 {
+        write!(f, ", optional data {} bytes", optional)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for ValueDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data = self.data;
+        if self.vendor == APP_VENDOR {
+            // ours are text
+            return match core::str::from_utf8(data) {
+                Ok(text) => write!(f, "\"{}\"", text),
+                Err(_) => write!(f, "{} bytes", data.len()),
+            };
+        }
+        if self.vendor != VariableVendor::GLOBAL_VARIABLE {
+            return write!(f, "{} bytes", data.len());
+        }
+
+        match (self.name, data) {
+            ("BootOrder" | "DriverOrder", _) => {
+                let prefix = self.name.trim_end_matches("Order");
+                for (i, option) in u16_list(data).enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}{:04X}", prefix, option)?;
+                }
+                Ok(())
+            }
+            ("BootCurrent" | "BootNext", [low, high]) => {
+                write!(f, "Boot{:04X}", u16::from_le_bytes([*low, *high]))
+            }
+            ("Timeout", [low, high]) => write!(f, "{} s", u16::from_le_bytes([*low, *high])),
+            ("SecureBoot" | "SetupMode" | "AuditMode" | "DeployedMode", [value]) => {
+                write!(f, "{}", if *value == 1 { "on" } else { "off" })
+            }
+            (name, _) if is_boot_option(name) => fmt_load_option(f, data),
+            _ => write!(f, "{} bytes", data.len()),
+        }
+    }
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attributes = self.attributes;
+        let flag = |attribute: VariableAttributes, c: char| {
+            if attributes.contains(attribute) {
+                c
+            } else {
+                '-'
+            }
+        };
+        write!(
+            f,
+            "{} {}{}{}{} {:5} {}",
+            self.vendor.0,
+            flag(VariableAttributes::NON_VOLATILE, 'N'),
+            flag(VariableAttributes::BOOTSERVICE_ACCESS, 'B'),
+            flag(VariableAttributes::RUNTIME_ACCESS, 'R'),
+            flag(
+                VariableAttributes::TIME_BASED_AUTHENTICATED_WRITE_ACCESS,
+                'A'
+            ),
+            self.size,
+            self.name
+        )
+    }
+}
@@ -0,0 +1,74 @@
+//! Deadline around driver init
+//!
+//! Polling loops check [`is_expired`] and give up, so a device that never answers fails its
+//! driver with a timeout instead of hanging the boot. The UEFI watchdog is not used: it resets
+//! the machine rather than letting the boot go on, and it is gone after exiting boot services.
+//!
+//! Time comes from [`uptime`], the deadline never expires before the TSC is calibrated.
+//!
+//! A UART stops waiting for its transmitter too, see [`is_output_expired`].
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::drivers::DriverError;
+use crate::fox_time::uptime;
+
+/// Uptime in nanoseconds when the running [`run`] expires, 0 if none
+static DEADLINE: AtomicU64 = AtomicU64::new(0);
+/// A UART dropped output since the deadline of the running [`run`] passed
+static IS_OUTPUT_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Run `f` with a deadline `timeout` from now, a zero `timeout` sets none
+///
+/// An error of `f` after the deadline is logged as a hang of `name` and becomes
+/// [`DriverError::Timeout`]. Nested calls keep the earlier deadline.
+pub fn run<T>(
+    name: &str,
+    timeout: Duration,
+    f: impl FnOnce() -> Result<T, DriverError>,
+) -> Result<T, DriverError> {
+    // log::trace!("fox_watchdog::run({}, {:?})", name, timeout);
+
+    if timeout.is_zero() {
+        return f();
+    }
+    let deadline = (uptime() + timeout).as_nanos() as u64;
+    let previous = DEADLINE.load(Ordering::Relaxed);
+    if previous == 0 || deadline < previous {
+        DEADLINE.store(deadline, Ordering::Relaxed);
+    }
+    let result = f();
+    let is_expired = is_expired();
+    DEADLINE.store(previous, Ordering::Relaxed);
+
+    // logged here, a record from inside the UART would interleave with the one it is sending
+    if IS_OUTPUT_DROPPED.swap(false, Ordering::Relaxed) {
+        log::warn!("{}: serial output dropped after the watchdog expired", name);
+    }
+
+    match result {
+        Err(err) if is_expired => {
+            log::error!("{}: watchdog expired after {:?} ({})", name, timeout, err);
+            Err(DriverError::Timeout)
+        }
+        result => result,
+    }
+}
+
+/// The deadline of the running [`run`] has passed, `false` outside of it
+pub fn is_expired() -> bool {
+    let deadline = DEADLINE.load(Ordering::Relaxed);
+    deadline != 0 && uptime().as_nanos() as u64 >= deadline
+}
+
+/// [`is_expired`] for a UART waiting for its transmitter
+///
+/// The UART sends anyway and overwrites a byte, [`run`] logs it once.
+pub fn is_output_expired() -> bool {
+    let is_expired = is_expired();
+    if is_expired {
+        IS_OUTPUT_DROPPED.store(true, Ordering::Relaxed);
+    }
+    is_expired
+}
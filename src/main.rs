@@ -1,35 +1,606 @@
 // #![feature(step_trait)]
-#![no_main]
-#![no_std]
+#![feature(abi_x86_interrupt)]
+// host tests (`make test`) run with std and the test harness
+#![cfg_attr(not(test), no_main)]
+#![cfg_attr(not(test), no_std)]
+// the ARM build has none of the PC drivers, which use much of the shared code
+#![cfg_attr(
+    not(any(target_arch = "x86", target_arch = "x86_64")),
+    allow(dead_code)
+)]
 
+extern crate alloc;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use alloc::boxed::Box;
 use core::time::Duration;
 
-use uefi::boot::stall;
+use log::LevelFilter;
 use uefi::helpers::init;
-use uefi::{Status, entry, println};
+use uefi::proto::console::text::{Key, ScanCode};
+use uefi::{Status, entry};
 
-use crate::drivers::{Driver, I8042};
-use crate::fox_acpi::init_fadt;
-use crate::fox_uefi::init_acpi;
+use crate::drivers::MmioUart;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::drivers::{
+    AcpiEc, Ahci, Apic, CmosRtc, Driver, E1000, I8042, Nvme, PcSpeaker, Pci, Pic8259, Pit8254,
+    ScancodeSet, Serial16550, Signal, Slot, State, VirtioNet, Xhci,
+};
+use crate::fox_acpi::{init_fadt, init_madt, init_mcfg, init_numa, madt, numa};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_acpi::{init_iommu, iommu};
+use crate::fox_block::UefiBlockIo;
+use crate::fox_event::{Event, EventLoop};
+use crate::fox_fb::{Cursor, LogoError};
+use crate::fox_input::{Input, InputEvent};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::fox_net::Interface;
+use crate::fox_power::{Reset, battery};
+use crate::fox_qemu::ExitCode;
+use crate::fox_shell::Shell;
+use crate::fox_test::Devices;
+use crate::fox_time::delay;
+use crate::fox_uefi::{exit_boot_services, init_acpi};
 
 mod drivers;
 mod fox_acpi;
+mod fox_aml;
+mod fox_arch;
+mod fox_block;
+mod fox_bootlog;
+mod fox_config;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod fox_cpu;
+mod fox_event;
+mod fox_fb;
+mod fox_fs;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod fox_idt;
+mod fox_input;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod fox_interrupts;
+mod fox_io;
+mod fox_log;
+mod fox_mem;
+mod fox_mp;
+mod fox_net;
+mod fox_panic;
+mod fox_port;
+mod fox_power;
+mod fox_qemu;
+mod fox_rand;
+mod fox_ring;
+mod fox_shell;
+mod fox_smbios;
+mod fox_sync;
+mod fox_test;
+mod fox_time;
+mod fox_tpm;
 mod fox_uefi;
+mod fox_vec;
+mod fox_watchdog;
+
+/// Percent of a pixel per mouse count, PS/2 counts are coarse for large screens
+const MOUSE_SENSITIVITY: u16 = 150;
+/// Battery readings in the log while the shell runs
+const BATTERY_LOG_INTERVAL: Duration = Duration::from_secs(300);
 
 #[entry]
 fn main() -> Status {
     init().unwrap();
-    println!();
-    init_acpi();
-    init_fadt();
+    fox_log::init();
+    fox_time::calibrate_tsc();
+    // sets the log level
+    fox_config::init();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fox_cpu::log_summary();
+    // the MADT dump is long
+    fox_log::set_module_level("my_uefi_app::fox_acpi::madt", LevelFilter::Info);
 
-    if I8042::probe().is_ok() {
-        let mut i8042 = I8042::default();
-        i8042.init();
-        log::debug!("{:?}", i8042);
-        i8042.remove();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if let Some(serial) = drivers::load(Serial16550::default()) {
+        fox_log::set_serial(&serial);
+    }
+
+    fox_println!();
+    if let Err(err) = init_acpi() {
+        log::warn!("ACPI: {}", err);
+    }
+    if let Err(err) = init_fadt() {
+        log::warn!("FADT: {}", err);
+    }
+    // the SPCR console of ARM servers, where there is no COM1
+    if let Some(uart) = drivers::load(MmioUart::default()) {
+        fox_log::set_mmio_serial(&uart);
+    }
+    fox_time::pm_timer::init();
+    #[cfg(target_arch = "aarch64")]
+    fox_time::generic_timer::init();
+    fox_qemu::detect();
+    init_madt();
+    init_mcfg();
+    init_numa();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    init_iommu();
+    for block in fox_acpi::aml_blocks() {
+        log::debug!(
+            "{} at {:#x}: {} bytes of AML",
+            block.signature,
+            block.address,
+            block.aml.len()
+        );
+    }
+    fox_aml::init();
+    fox_aml::log_devices();
+    fox_smbios::init();
+    fox_smbios::log_summary();
+    if let Some(madt) = madt() {
+        log::info!(
+            "Found {} CPUs, {} IO APICs",
+            madt.local_apics.iter().filter(|i| i.is_enabled()).count(),
+            madt.io_apics.len()
+        );
+    }
+    if let Some(numa) = numa() {
+        log::info!("Found {} NUMA nodes", numa.nodes.len());
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if let Some(iommu) = iommu() {
+        log::info!("Found {} IOMMUs ({:?})", iommu.units.len(), iommu.kind);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let mut pc = PcDrivers::load_boot();
+
+    // disks as the firmware sees them, partitions are listed from the whole disks
+    match UefiBlockIo::handles() {
+        Ok(handles) => {
+            for &handle in handles.iter() {
+                match UefiBlockIo::open(handle) {
+                    Ok(disk) if disk.is_partition() => {}
+                    Ok(mut disk) => fox_block::log_partitions(&mut disk),
+                    Err(err) => log::warn!("Block IO {:?}: {}", handle, err),
+                }
+            }
+        }
+        Err(err) => log::warn!("No Block IO handles: {}", err),
+    }
+
+    if let Err(err) = fox_fb::init() {
+        log::warn!("No framebuffer: {}", err);
+    }
+    match fox_fb::save_logo() {
+        Ok(()) => {}
+        Err(LogoError::Acpi(fox_acpi::Error::NoTable)) => log::debug!("No BGRT"),
+        Err(err) => log::warn!("Logo: {}", err),
+    }
+    match fox_tpm::init() {
+        Ok(()) => {}
+        Err(err) if err.status() == Status::NOT_FOUND => log::info!("TPM: none"),
+        Err(err) => log::warn!("TPM: {}", err),
+    }
+    match fox_mp::init() {
+        Ok(()) => {}
+        Err(err) if err.status() == Status::NOT_FOUND => log::info!("MP: no MP services"),
+        Err(err) => log::warn!("MP: {}", err),
+    }
+    match fox_net::init() {
+        Ok(()) => {
+            if let Some(gateway) = fox_config::config().gateway {
+                match fox_net::ping(gateway, 0, Duration::from_secs(1)) {
+                    Ok(time) => log::info!("Net: gateway {} answered in {:?}", gateway, time),
+                    Err(err) => log::warn!("Net: ping {}: {}", gateway, err),
+                }
+            }
+        }
+        Err(fox_net::Error::NoInterface) => log::info!("Net: no interface with a link"),
+        Err(err) => log::warn!("Net: {}", err),
+    }
+    // the ESP is only writable through boot services
+    if let Some((server, file)) = fox_config::config().tftp {
+        match fox_net::tftp::save(server, file) {
+            Ok(size) => log::info!("TFTP: saved {} from {} ({} bytes)", file, server, size),
+            Err(err) => log::error!("TFTP: {} from {}: {}", file, server, err),
+        }
+    }
+
+    delay(fox_config::config().boot_delay);
+
+    // for iasl, the file system is gone after exiting boot services
+    for name in ["DSDT", "SSDT"] {
+        match fox_acpi::save_table(name) {
+            Ok(count) => log::info!("Saved {} {} table(s)", count, name),
+            Err(err) => log::warn!("Saving {} failed: {}", name, err),
+        }
+    }
+    if let Err(err) = fox_bootlog::save() {
+        log::error!("Saving the boot log failed: {}", err);
+    }
+
+    // the firmware NIC goes away with boot services
+    fox_net::shutdown();
+    // boot services memory is free from here on
+    let memory_map = exit_boot_services();
+    fox_time::calibrate_tsc();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fox_idt::init();
+    fox_mem::init(&memory_map);
+    fox_mem::log_memory_map();
+    match fox_mem::alloc_frames(16, 0x1_0000) {
+        Some(address) => {
+            log::debug!("Frame allocator: 16 frames at {:#x}", address);
+            // SAFETY: never used
+            unsafe { fox_mem::free_frames(address, 16) };
+        }
+        None => log::error!("Frame allocator: out of memory"),
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pc.load_runtime();
+
+    let devices = battery::devices();
+    if devices.batteries + devices.smart_battery_systems > 0 {
+        log::info!(
+            "Found {} batteries, {} smart battery systems, {} AC adapters",
+            devices.batteries,
+            devices.smart_battery_systems,
+            devices.ac_adapters
+        );
+    }
+    let has_battery = match battery::read() {
+        Ok(status) => {
+            log::info!("Battery: {}", status);
+            true
+        }
+        Err(battery::Error::NoBattery) => false,
+        Err(err) => {
+            log::warn!("Battery: {}", err);
+            false
+        }
+    };
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pc.enable_interrupts();
+    fox_arch::enable_interrupts();
+    // headless machines tell how the boot went
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pc.signal_boot();
+
+    let status = if fox_config::config().selftest {
+        fox_test::run(&mut Devices {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            i8042: pc.i8042.as_mut(),
+        })
+    } else {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let input = Input::new(pc.i8042.as_mut());
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        let input = Input::new();
+        run_shell(input, has_battery)
     };
+    log::info!("Event loop done: {:?}", status);
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pc.disable_interrupts();
+    fox_arch::disable_interrupts();
+    fox_net::shutdown();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pc.remove();
+
+    // ResetSystem passes the result on, an ACPI poweroff would lose it
+    if fox_config::config().selftest {
+        fox_qemu::exit(if status.is_success() {
+            ExitCode::Success
+        } else {
+            ExitCode::Failure
+        });
+        fox_power::reset(Reset::Shutdown, status, "self-test done");
+    }
+
+    if let Err(err) = fox_acpi::enable() {
+        log::error!("ACPI: enable failed: {}", err);
+    }
+    if let Err(err) = fox_acpi::poweroff() {
+        log::error!("ACPI: poweroff failed: {}", err);
+        if let Err(err) = fox_acpi::reboot() {
+            log::error!("ACPI: reboot failed: {}", err);
+        }
+    }
+
+    // there is no firmware to return to
+    fox_power::reset(Reset::Shutdown, Status::SUCCESS, "ACPI poweroff failed")
+}
+
+/// Shell until Escape or exit, with the periodic log tasks
+fn run_shell(input: Option<Input>, has_battery: bool) -> Status {
+    let mut events = EventLoop::default();
+    if let Some(input) = input {
+        input.spawn(&mut events);
+
+        let mut shell = Shell::default();
+        shell.prompt();
+        fox_input::subscribe(&mut events, move |event| match event {
+            InputEvent::Key(Key::Special(ScanCode::ESCAPE)) => {
+                fox_event::post(Event::Quit(Status::SUCCESS));
+            }
+            InputEvent::Key(key) => shell.handle_key(*key),
+            _ => {}
+        });
+
+        if let Some(mut cursor) = fox_fb::framebuffer().map(Cursor::new) {
+            cursor.set_sensitivity(MOUSE_SENSITIVITY);
+            cursor.show();
+            fox_input::subscribe(&mut events, move |event| {
+                let pointer = cursor.apply(event);
+                // only clicks, moves are too many
+                if let InputEvent::MouseButton { .. } = event {
+                    log::info!(
+                        "pointer at {},{} left={} right={} middle={}",
+                        pointer.x,
+                        pointer.y,
+                        pointer.left,
+                        pointer.right,
+                        pointer.middle
+                    );
+                }
+            });
+        }
+
+        fox_input::subscribe(&mut events, |event| {
+            if !matches!(event, InputEvent::MouseMove { .. }) {
+                log::debug!("input {:?}", event);
+            }
+        });
+    }
+    // the TSC keeps time, the IRQ shows the timer still interrupts
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    if fox_time::is_ticking() {
+        events.spawn(async {
+            loop {
+                fox_event::sleep(Duration::from_secs(60)).await;
+                fox_event::wait_irq(Pit8254::IRQ).await;
+                log::info!("Uptime {:?}", Pit8254::uptime());
+            }
+        });
+    }
+    if has_battery {
+        events.spawn(async {
+            loop {
+                fox_event::sleep(BATTERY_LOG_INTERVAL).await;
+                match battery::read() {
+                    Ok(status) => log::info!("Battery: {}", status),
+                    Err(err) => log::warn!("Battery: {}", err),
+                }
+            }
+        });
+    }
+    // nobody may be at the keyboard
+    events.spawn(async {
+        fox_event::sleep(fox_config::config().idle_timeout).await;
+        fox_event::post(Event::Quit(Status::TIMEOUT));
+    });
+    events.run()
+}
+
+/// The drivers of PC hardware, `None` where not loaded
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+#[derive(Default)]
+struct PcDrivers {
+    rtc: Option<CmosRtc>,
+    i8042: Option<I8042>,
+    ahci: Option<Ahci>,
+    nvme: Option<Nvme>,
+    ec: Option<AcpiEc>,
+    pic: Option<Pic8259>,
+    apic: Option<Apic>,
+    e1000: Option<E1000>,
+    virtio_net: Option<VirtioNet>,
+    xhci: Option<Xhci>,
+    pit: Option<Pit8254>,
+    speaker: Option<PcSpeaker>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PcDrivers {
+    /// The RTC, PCI and the i8042, while boot services are active
+    fn load_boot() -> Self {
+        let mut rtc = Slot::new(CmosRtc::default());
+        let mut pci = Slot::new(Pci);
+        let mut i8042 = Slot::new(I8042::default());
+        drivers::load_ordered(&mut [&mut rtc, &mut pci, &mut i8042]);
+        if pci.into_loaded().is_some() {
+            log::info!("Found {} PCI functions", Pci::devices().len());
+        }
+
+        let mut i8042 = i8042.into_loaded();
+        log::debug!("{:?}", i8042);
+        if let Some(i8042) = &mut i8042 {
+            // NumLock on, 10.9 Hz repeat after 500 ms, mouse wheel on at 100 samples/s
+            if let Err(err) = i8042.set_leds(false, true, false) {
+                log::warn!("{}: Set LEDs failed: {}", I8042::DRIVER_NAME, err);
+            }
+            if let Err(err) = i8042.set_typematic(0x0B, 1) {
+                log::warn!("{}: Set typematic failed: {}", I8042::DRIVER_NAME, err);
+            }
+            match i8042.enable_wheel() {
+                Ok(is_enabled) => log::debug!("{}: Wheel {}", I8042::DRIVER_NAME, is_enabled),
+                Err(err) => log::debug!("{}: Enable wheel failed: {}", I8042::DRIVER_NAME, err),
+            }
+            if let Err(err) = i8042.set_sample_rate(100) {
+                log::debug!("{}: Set sample rate failed: {}", I8042::DRIVER_NAME, err);
+            }
+            if let Err(err) = i8042.select_scancode_set(ScancodeSet::Set2) {
+                log::warn!(
+                    "{}: Select scancode set failed: {}",
+                    I8042::DRIVER_NAME,
+                    err
+                );
+            }
+            log::info!(
+                "{}: Scancodes {:?}",
+                I8042::DRIVER_NAME,
+                i8042.scancode_set()
+            );
+            match I8042::is_a20_enabled() {
+                Ok(true) => log::info!("{}: A20 enabled", I8042::DRIVER_NAME),
+                // the firmware runs above 1 MiB, this should not happen
+                Ok(false) => log::warn!("{}: A20 disabled", I8042::DRIVER_NAME),
+                Err(err) => log::warn!("{}: A20 unknown: {}", I8042::DRIVER_NAME, err),
+            }
+        }
+
+        Self {
+            rtc: rtc.into_loaded(),
+            i8042,
+            ..Self::default()
+        }
+    }
+
+    /// Storage, interrupt controllers, NICs and USB, once the firmware drivers are gone
+    fn load_runtime(&mut self) {
+        Pci::disable_msi();
+        // the firmware's own AHCI driver is gone now
+        let mut ahci = Slot::new(Ahci::default());
+        let mut nvme = Slot::new(Nvme::default());
+        // shared with SMM firmware, the global lock guards it where needed
+        let mut ec = Slot::new(AcpiEc::default());
+        let mut pic = Slot::new(Pic8259::default());
+        // the PIC stays remapped and masked when the APIC takes over
+        let mut apic = Slot::new(Apic::default());
+        // the firmware NIC was stopped by `fox_net::shutdown`
+        let mut e1000 = Slot::new(E1000::default());
+        let mut virtio_net = Slot::new(VirtioNet::default());
+        // the firmware drives USB for its console until here
+        let mut xhci = Slot::new(Xhci::default());
+        drivers::load_ordered(&mut [
+            &mut ahci,
+            &mut nvme,
+            &mut ec,
+            &mut pic,
+            &mut apic,
+            &mut e1000,
+            &mut virtio_net,
+            &mut xhci,
+        ]);
+        self.ahci = ahci.into_loaded();
+        self.nvme = nvme.into_loaded();
+        self.ec = ec.into_loaded();
+        self.pic = pic.into_loaded();
+        self.apic = apic.into_loaded();
+        self.e1000 = e1000.into_loaded();
+        self.virtio_net = virtio_net.into_loaded();
+        self.xhci = xhci.into_loaded();
+
+        if let Some(ahci) = &self.ahci {
+            for disk in ahci.disks() {
+                let mut disk = *disk;
+                fox_block::log_partitions(&mut disk);
+                fox_fs::log_volumes(&mut disk);
+            }
+        }
+        if let Some(nvme) = &mut self.nvme {
+            for i in 0..nvme.namespace_count() {
+                if let Some(mut namespace) = nvme.namespace(i) {
+                    fox_block::log_partitions(&mut namespace);
+                    fox_fs::log_volumes(&mut namespace);
+                }
+            }
+        }
+
+        // virtio-net if it has a link, the e1000 otherwise
+        if let Some(virtio_net) = self.virtio_net.as_ref().filter(|i| i.is_link_up()) {
+            fox_net::set_interface(Interface::new(Box::new(virtio_net.nic())));
+        } else if let Some(e1000) = &self.e1000 {
+            fox_net::set_interface(Interface::new(Box::new(e1000.nic())));
+        }
+    }
+
+    /// IRQ routing, the PIT tick and the i8042 IRQs, the caller enables interrupts
+    fn enable_interrupts(&mut self) {
+        fox_interrupts::init();
+        self.pit = drivers::load(Pit8254::default());
+        if let Some(i8042) = &mut self.i8042
+            && let Err(err) = i8042.enable_interrupts()
+        {
+            log::error!("{}: {}", I8042::DRIVER_NAME, err);
+        }
+    }
+
+    /// Beep whether a driver failed, for headless machines
+    fn signal_boot(&mut self) {
+        self.speaker = drivers::load(PcSpeaker);
+        if self.speaker.is_none() {
+            return;
+        }
+        let is_failed = drivers::status()
+            .iter()
+            .any(|i| matches!(i.state, State::Failed(_)));
+        let signal = if is_failed {
+            Signal::Failure
+        } else {
+            Signal::Success
+        };
+        if let Err(err) = PcSpeaker::signal(signal) {
+            log::warn!("{}: {}", PcSpeaker::DRIVER_NAME, err);
+        }
+    }
+
+    /// The i8042 IRQs, the caller disables interrupts
+    fn disable_interrupts(&mut self) {
+        if let Some(i8042) = &mut self.i8042
+            && let Err(err) = i8042.disable_interrupts()
+        {
+            log::error!("{}: {}", I8042::DRIVER_NAME, err);
+        }
+    }
+
+    /// Remove every driver, the network interface must be shut down first
+    fn remove(&mut self) {
+        if let Some(speaker) = &mut self.speaker {
+            drivers::remove(speaker);
+        }
+        if let Some(i8042) = &mut self.i8042 {
+            drivers::remove(i8042);
+        }
+        if let Some(pit) = &mut self.pit {
+            drivers::remove(pit);
+            log::info!(
+                "Uptime {:?} ({} ticks)",
+                Pit8254::uptime(),
+                Pit8254::ticks()
+            );
+        }
+        if let Some(apic) = &mut self.apic {
+            drivers::remove(apic);
+        }
+        if let Some(pic) = &mut self.pic {
+            drivers::remove(pic);
+        }
+        if let Some(ec) = &mut self.ec {
+            drivers::remove(ec);
+        }
+        if let Some(xhci) = &mut self.xhci {
+            drivers::remove(xhci);
+        }
+        if let Some(virtio_net) = &mut self.virtio_net {
+            drivers::remove(virtio_net);
+        }
+        if let Some(e1000) = &mut self.e1000 {
+            drivers::remove(e1000);
+        }
+        if let Some(nvme) = &mut self.nvme {
+            drivers::remove(nvme);
+        }
+        if let Some(ahci) = &mut self.ahci {
+            drivers::remove(ahci);
+        }
 
-    stall(Duration::from_secs(600));
-    Status::SUCCESS
+        if let Some(rtc) = &mut self.rtc {
+            match rtc.now() {
+                Ok(now) => log::info!("Powering off at {}", now),
+                Err(err) => log::error!("{}: {}", CmosRtc::DRIVER_NAME, err),
+            }
+            drivers::remove(rtc);
+        }
+    }
 }